@@ -0,0 +1,190 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Run with `cargo bench`, from the repository root (the assembler lives in tools/)
+
+#![allow(clippy::needless_return)]
+
+use base64::{engine::general_purpose, Engine as _};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use msp430_rust::{Computer, elf, image::ProgramImage, utils};
+
+/// Repeats `instruction` many times in a loop so nearly every step executes it
+fn looped(setup: &str, instruction: &str) -> Computer {
+    let body: String = format!("{}\n", instruction).repeat(64);
+    let assembled = utils::assemble(&format!("mov #0x4400 sp\nmov #0x5a80 &0x0120\nmov #0x0200 r4\n{}\nloop:\n{}jmp loop\n",
+                                             setup, body));
+    let mut computer = Computer::new();
+    utils::execute(&mut computer, assembled.trim(), 3 + setup.lines().count() as u64);
+    return computer;
+}
+
+/// Instruction words go through the table build.rs generates (src/decode.rs). Against the shift
+/// and branch chain it replaced (`--save-baseline` before, `--baseline` after) jumps and double
+/// operand instructions got 24-30% faster, single operand ones didn't change measurably.
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, instruction) in [
+        ("single_operand", "rra r5"),
+        ("jump", "jn loop"), // N is clear, so not taken
+        ("double_operand", "add r5 r6"),
+    ] {
+        let mut computer = looped("", instruction);
+        group.bench_function(name, |b| b.iter(|| computer.step()));
+    }
+    group.finish();
+}
+
+fn bench_addressing_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("addressing");
+    for (name, instruction) in [
+        ("src_register", "mov r5 r6"),
+        ("src_indexed", "mov 2(r4) r6"),
+        ("src_indirect", "mov @r4 r6"),
+        ("src_autoincrement", "mov @r7+ r6"),
+        ("src_immediate", "mov #0x1234 r6"),
+        ("src_constant_generator", "mov #4 r6"),
+        ("src_absolute", "mov &0x0200 r6"),
+        ("dst_indexed", "mov r5 2(r4)"),
+        ("dst_absolute", "mov r5 &0x0200"),
+        ("byte_indexed", "add.b 1(r4) 3(r4)"),
+    ] {
+        let mut computer = looped("mov #0x0200 r7", instruction);
+        group.bench_function(name, |b| b.iter(|| computer.step()));
+    }
+    group.finish();
+}
+
+/// Instructions per second on a mix of typical firmware code, what the interpreter is tuned for.
+/// The target is 20 M instructions/s (a 20 MHz part at one instruction per cycle) on a desktop machine.
+/// Returning operand write targets by value instead of boxed, one SR write per flag update and
+/// dropping RETI's prints took it from about 11.5 M/s to 25 M/s. The device and debugging work
+/// since brought it down to about 20 M/s there, 15.6 M/s on a slower machine; decoding once per
+/// step and skipping the per-instruction hooks when none is attached brought that machine back to
+/// 17 M/s.
+fn bench_throughput(c: &mut Criterion) {
+    const STEPS: u64 = 10_000;
+    let assembled = utils::assemble("
+mov #0x4400 sp
+mov #0x5a80 &0x0120 ; WDTPW | WDTHOLD
+mov #0x0200 r4
+loop:
+mov #16 r5
+inner:
+mov @r4+ r6
+add r6 r7
+xor r7 r8
+bit #1 r8
+jz skip
+push r8
+pop r9
+skip:
+mov.b r7 0(r4)
+cmp #0x0220 r4
+jlo next
+mov #0x0200 r4
+next:
+call #leaf
+sub #1 r5
+jnz inner
+jmp loop
+leaf:
+rla r7
+ret
+");
+    let computer: &mut Computer = &mut Computer::new();
+    utils::execute(computer, assembled.trim(), 3);
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(STEPS));
+    group.bench_function("mixed_firmware", |b| b.iter(|| {
+        for _ in 0..STEPS {
+            computer.step();
+        }
+    }));
+    group.finish();
+}
+
+fn bench_interrupt_dispatch(c: &mut Criterion) {
+    let assembled = utils::assemble("
+mov #0x4400 sp
+mov #0x5a80 &0x0120 ; WDTPW | WDTHOLD
+eint
+loop:
+jmp loop
+
+handler:
+reti
+
+.interrupt 0xffe4 handler
+");
+    let computer: &mut Computer = &mut Computer::new();
+    utils::execute(computer, assembled.trim(), 3);
+    c.bench_function("interrupt_dispatch", |b| b.iter(|| {
+        computer.interrupt(black_box(0xffe4));
+        computer.step(); // reti
+    }));
+}
+
+fn bench_fork(c: &mut Criterion) {
+    let computer = looped("", "add r5 &0x0200");
+    c.bench_function("fork_and_step", |b| b.iter(|| {
+        let mut fork = computer.fork();
+        fork.step(); // copies the one page it writes
+        fork
+    }));
+}
+
+/// Smallest ELF the loader accepts: one PT_LOAD segment, no sections
+fn minimal_elf(load_address: u32, code: &[u8]) -> Vec<u8> {
+    let mut elf: Vec<u8> = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    elf.extend_from_slice(&2u16.to_le_bytes());   // e_type EXEC
+    elf.extend_from_slice(&105u16.to_le_bytes()); // e_machine MSP430
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&load_address.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&52u32.to_le_bytes());  // e_phoff
+    elf.extend_from_slice(&0u32.to_le_bytes());   // e_shoff
+    elf.extend_from_slice(&0u32.to_le_bytes());   // e_flags
+    for v in [52u16, 32, 1, 40, 0, 0] { // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in [1u32, 84, load_address, load_address, code.len() as u32, code.len() as u32, 5, 2] {
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    elf.extend_from_slice(code);
+    return elf;
+}
+
+fn bench_loaders(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loaders");
+    let program: String = "add r5 r6\nmov #0x1234 &0x0200\n".repeat(512);
+    let segmented: Vec<u8> = general_purpose::STANDARD.decode(utils::assemble(&program).trim())
+        .expect("Assembler output is base64");
+    group.bench_function("segmented_parse", |b| b.iter(|| ProgramImage::from_segmented(black_box(&segmented))));
+
+    let elf_data: Vec<u8> = minimal_elf(0xc000, &[0x35, 0x40, 0x34, 0x12].repeat(1024));
+    group.bench_function("elf_parse", |b| b.iter(|| elf::parse_elf(black_box(&elf_data))));
+
+    let image = elf::parse_elf(&elf_data).expect("ELF should parse");
+    // loading only overwrites memory, so the same computer can be reused
+    let computer: &mut Computer = &mut Computer::new();
+    group.bench_function("image_load", |b| b.iter(|| image.load(computer)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_throughput, bench_decode, bench_addressing_modes, bench_interrupt_dispatch, bench_fork, bench_loaders);
+criterion_main!(benches);
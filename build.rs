@@ -0,0 +1,59 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Generates the instruction decode table, see src/decode.rs for the entry layout
+
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+
+const KIND_NONE: u8 = 0;
+const KIND_SINGLE: u8 = 1;
+const KIND_JUMP: u8 = 2;
+const KIND_DOUBLE: u8 = 3;
+
+fn entry(instruction: u16) -> [u8; 4] {
+    if instruction >> 10 == 4 { // 0b000100
+        let opcode: u8 = ((instruction >> 7) & 0x7) as u8;
+        let reg_as: u8 = (instruction & 0xf) as u8 | (((instruction >> 4) & 0x3) as u8) << 4;
+        let bw: u8 = ((instruction >> 6) & 0x1) as u8;
+        return [KIND_SINGLE | opcode << 4, reg_as, bw << 5, 0];
+    } else if instruction >> 13 == 1 { // 0b001
+        let mut offset: i32 = (instruction & 0x3ff) as i32;
+        if offset >= 512 {
+            offset -= 1024;
+        }
+        let condition: u8 = ((instruction >> 10) & 0x7) as u8;
+        let [low, high] = ((offset * 2) as i16).to_le_bytes();
+        return [KIND_JUMP | condition << 4, low, high, 0];
+    } else if instruction >> 12 >= 4 {
+        let opcode: u8 = (instruction >> 12) as u8 - 4;
+        let src_as: u8 = ((instruction >> 8) & 0xf) as u8 | (((instruction >> 4) & 0x3) as u8) << 4;
+        let dst_ad_bw: u8 = (instruction & 0xf) as u8 | (((instruction >> 7) & 0x1) as u8) << 4
+            | (((instruction >> 6) & 0x1) as u8) << 5;
+        return [KIND_DOUBLE | opcode << 4, src_as, dst_ad_bw, 0];
+    }
+    return [KIND_NONE, 0, 0, 0];
+}
+
+fn main() {
+    let table: Vec<u8> = (0..=0xffffu16).flat_map(entry).collect();
+    let out_dir: String = std::env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    std::fs::write(Path::new(&out_dir).join("decode_table.bin"), table).expect("Failed to write the decode table");
+    println!("cargo:rerun-if-changed=build.rs");
+}
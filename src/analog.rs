@@ -0,0 +1,159 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::devices::comparator::{ComparatorDevice, CHANNELS};
+
+/// Full scale of WAV samples, the lowest sample is 0 V and the highest this
+pub(crate) const WAV_FULL_SCALE_MV: u32 = 3300;
+
+/// `CA0:FILE`, a waveform played into a comparator input (`run --analog`)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct AnalogSpec {
+    pub(crate) channel: usize,
+    pub(crate) path: String,
+}
+
+impl FromStr for AnalogSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channel, path) = s.split_once(':').ok_or(format!("'{}' is not an analog input (expected e.g. CA0:wave.csv)", s))?;
+        let channel: usize = channel.trim().to_ascii_uppercase().strip_prefix("CA").and_then(|n| n.parse().ok())
+            .filter(|n| *n < CHANNELS)
+            .ok_or(format!("'{}' is not a comparator input (CA0 - CA{})", channel, CHANNELS - 1))?;
+        return Ok(AnalogSpec { channel, path: path.to_string() });
+    }
+}
+
+impl fmt::Display for AnalogSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "CA{}:{}", self.channel, self.path);
+    }
+}
+
+/// Voltage samples replayed into a comparator input as emulated time passes, each one holds
+/// until the next and the last one holds forever
+#[derive(Clone)]
+pub(crate) struct Waveform {
+    channel: usize,
+    /// (cycle, millivolts), sorted by cycle
+    samples: Vec<(u64, u16)>,
+    /// first sample not applied yet
+    next: usize,
+}
+
+impl Waveform {
+    pub(crate) fn new(channel: usize, mut samples: Vec<(u64, u16)>) -> Waveform {
+        samples.sort_by_key(|s| s.0);
+        return Waveform { channel, samples, next: 0 };
+    }
+
+    /// `cycle,millivolts` lines, e.g. `1000,1650`. Blank lines, `#` comments and a header are skipped.
+    pub(crate) fn from_csv(channel: usize, text: &str) -> Result<Waveform, String> {
+        let mut samples: Vec<(u64, u16)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() || (number == 0 && line.starts_with("cycle")) {
+                continue;
+            }
+            let (cycle, millivolts) = line.split_once(',').ok_or(format!("Line {}: expected cycle,millivolts", number + 1))?;
+            let cycle: u64 = cycle.trim().parse().map_err(|e| format!("Line {}: bad cycle: {}", number + 1, e))?;
+            let millivolts: u16 = millivolts.trim().parse().map_err(|e| format!("Line {}: bad millivolts: {}", number + 1, e))?;
+            samples.push((cycle, millivolts));
+        }
+        return Ok(Waveform::new(channel, samples));
+    }
+
+    /// PCM WAV with 8 or 16 bit samples, only the first channel is used. Samples are spread over
+    /// emulated time at the file's sample rate and span 0 V to `WAV_FULL_SCALE_MV`.
+    pub(crate) fn from_wav(channel: usize, bytes: &[u8], mclk_hz: u64) -> Result<Waveform, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err("Not a WAV file".to_string());
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        // (channels, sample rate, bits per sample)
+        let mut format: Option<(usize, u64, usize)> = None;
+        let mut data: Option<&[u8]> = None;
+        let mut offset: usize = 12;
+        while offset + 8 <= bytes.len() {
+            let size: usize = u32_at(offset + 4) as usize;
+            let body: &[u8] = &bytes[offset + 8..(offset + 8 + size).min(bytes.len())];
+            match &bytes[offset..offset + 4] {
+                b"fmt " if body.len() >= 16 => {
+                    if u16_at(offset + 8) != 1 {
+                        return Err("Only PCM WAV files are supported".to_string());
+                    }
+                    format = Some((u16_at(offset + 10) as usize, u32_at(offset + 12) as u64, u16_at(offset + 22) as usize));
+                },
+                b"data" => data = Some(body),
+                _ => {},
+            }
+            offset += 8 + size + size % 2; // chunks are padded to an even size
+        }
+        let (channels, rate, bits) = format.ok_or("Missing fmt chunk")?;
+        let data: &[u8] = data.ok_or("Missing data chunk")?;
+        if channels == 0 || rate == 0 || (bits != 8 && bits != 16) {
+            return Err(format!("Unsupported WAV format ({} channels, {} Hz, {} bit)", channels, rate, bits));
+        }
+        let frame: usize = channels * bits / 8;
+        let samples: Vec<(u64, u16)> = data.chunks_exact(frame).enumerate().map(|(i, frame)| {
+            // as an unsigned 16 bit level, 8 bit WAV samples are unsigned, 16 bit ones signed
+            let level: u32 = if bits == 8 {
+                (frame[0] as u32) << 8
+            } else {
+                (i16::from_le_bytes([frame[0], frame[1]]) as i32 + 0x8000) as u32
+            };
+            return (i as u64 * mclk_hz / rate, (level * WAV_FULL_SCALE_MV / 0xffff) as u16);
+        }).collect();
+        return Ok(Waveform::new(channel, samples));
+    }
+
+    /// WAV if the file starts with a RIFF header, CSV otherwise
+    pub(crate) fn load(spec: &AnalogSpec, mclk_hz: u64) -> Result<Waveform, String> {
+        let bytes: Vec<u8> = std::fs::read(&spec.path).map_err(|e| e.to_string())?;
+        if bytes.starts_with(b"RIFF") {
+            return Waveform::from_wav(spec.channel, &bytes, mclk_hz);
+        }
+        let text: String = String::from_utf8(bytes).map_err(|_| "Neither a WAV nor a CSV file".to_string())?;
+        return Waveform::from_csv(spec.channel, &text);
+    }
+
+    /// Replay from the start, for when the computer is reset
+    pub(crate) fn rewind(&mut self) {
+        self.next = 0;
+    }
+
+    /// Apply the latest sample due by `cycle`
+    #[inline]
+    pub(crate) fn apply(&mut self, cycle: u64, comparator: &mut ComparatorDevice) {
+        let mut latest: Option<u16> = None;
+        while let Some(&(at, millivolts)) = self.samples.get(self.next) {
+            if at > cycle {
+                break;
+            }
+            latest = Some(millivolts);
+            self.next += 1;
+        }
+        if let Some(millivolts) = latest {
+            comparator.set_input(self.channel, millivolts);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 4] = b"MSPB";
+const VERSION: u8 = 1;
+
+const KIND_MASK: u8 = 0x07;
+const FLAG_TAKEN: u8 = 0x08;
+
+/// What moved the PC somewhere other than the next instruction
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum BranchKind {
+    /// conditional or unconditional jump, recorded taken or not
+    Jump,
+    Call,
+    /// RET or RETI
+    Return,
+    /// an interrupt was entered, the source is where the CPU returns to
+    Interrupt,
+    /// any other instruction writing the PC (`br`, `mov @r5+ pc`)
+    PcWrite,
+    /// the CPU restarted from the reset vector
+    Reset,
+}
+
+impl BranchKind {
+    fn id(&self) -> u8 {
+        return match self {
+            BranchKind::Jump => 0,
+            BranchKind::Call => 1,
+            BranchKind::Return => 2,
+            BranchKind::Interrupt => 3,
+            BranchKind::PcWrite => 4,
+            BranchKind::Reset => 5,
+        };
+    }
+
+    fn from_id(id: u8) -> Option<BranchKind> {
+        return match id {
+            0 => Some(BranchKind::Jump),
+            1 => Some(BranchKind::Call),
+            2 => Some(BranchKind::Return),
+            3 => Some(BranchKind::Interrupt),
+            4 => Some(BranchKind::PcWrite),
+            5 => Some(BranchKind::Reset),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            BranchKind::Jump => "jump",
+            BranchKind::Call => "call",
+            BranchKind::Return => "return",
+            BranchKind::Interrupt => "interrupt",
+            BranchKind::PcWrite => "pc write",
+            BranchKind::Reset => "reset",
+        };
+    }
+}
+
+/// One control-flow change, everything between the previous target and `source` ran in sequence
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct BranchRecord {
+    pub(crate) kind: BranchKind,
+    pub(crate) source: u16,
+    /// where a jump goes when taken, even when it wasn't
+    pub(crate) target: u16,
+    pub(crate) taken: bool,
+}
+
+/// Appends control-flow changes to a binary trace: a "MSPB" + version header followed by
+/// variable-length entries of a flags byte (kind in bits 0-2, bit 3 taken), the source as an
+/// unsigned LEB128 distance from the previous entry's target and the target as a zigzag LEB128
+/// distance from the source. Most entries take 3 bytes.
+pub(crate) struct BranchTrace {
+    out: BufWriter<Box<dyn Write>>,
+    last_target: u16,
+}
+
+impl BranchTrace {
+    pub(crate) fn new(out: Box<dyn Write>) -> io::Result<BranchTrace> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        return Ok(BranchTrace { out, last_target: 0 });
+    }
+
+    pub(crate) fn create(path: &str) -> io::Result<BranchTrace> {
+        return BranchTrace::new(Box::new(std::fs::File::create(path)?));
+    }
+
+    pub(crate) fn record(&mut self, record: &BranchRecord) -> io::Result<()> {
+        let mut entry: Vec<u8> = vec![record.kind.id() | if record.taken {FLAG_TAKEN} else {0}];
+        write_leb128(&mut entry, record.source.wrapping_sub(self.last_target) as u32);
+        write_leb128(&mut entry, zigzag(record.target.wrapping_sub(record.source) as i16));
+        self.last_target = if record.taken {record.target} else {record.source};
+        return self.out.write_all(&entry);
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+fn zigzag(value: i16) -> u32 {
+    return ((value << 1) ^ (value >> 15)) as u16 as u32;
+}
+
+fn unzigzag(value: u32) -> i16 {
+    let value: u16 = value as u16;
+    return (value >> 1) as i16 ^ -((value & 1) as i16);
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_leb128(data: &[u8], at: &mut usize) -> Option<u32> {
+    let mut value: u32 = 0;
+    for shift in (0..21).step_by(7) {
+        let byte: u8 = *data.get(*at)?;
+        *at += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    return None;
+}
+
+pub(crate) fn read_trace<R: Read>(mut input: R) -> io::Result<Vec<BranchRecord>> {
+    let mut header: [u8; 5] = [0; 5];
+    input.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a branch trace"));
+    }
+    if header[4] != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Unsupported branch trace version {}", header[4])));
+    }
+    let mut data: Vec<u8> = Vec::new();
+    input.read_to_end(&mut data)?;
+    let mut records: Vec<BranchRecord> = Vec::new();
+    let mut last_target: u16 = 0;
+    let mut at: usize = 0;
+    // a run killed mid-write can leave a partial entry at the end, it ends the trace
+    while at < data.len() {
+        let flags: u8 = data[at];
+        at += 1;
+        let kind: BranchKind = BranchKind::from_id(flags & KIND_MASK)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Unknown branch kind {}", flags & KIND_MASK)))?;
+        let (Some(source), Some(target)) = (read_leb128(&data, &mut at), read_leb128(&data, &mut at)) else {
+            break;
+        };
+        let source: u16 = last_target.wrapping_add(source as u16);
+        let target: u16 = source.wrapping_add(unzigzag(target) as u16);
+        let taken: bool = flags & FLAG_TAKEN != 0;
+        last_target = if taken {target} else {source};
+        records.push(BranchRecord { kind, source, target, taken });
+    }
+    return Ok(records);
+}
+
+pub(crate) fn to_text<W: Write>(records: &[BranchRecord], mut out: W) -> io::Result<()> {
+    for r in records {
+        writeln!(out, "0x{:04x} -> 0x{:04x} {}{}", r.source, r.target, r.kind.name(), if r.taken {""} else {" (not taken)"})?;
+    }
+    return out.flush();
+}
@@ -0,0 +1,111 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::Computer;
+use crate::diagnostics::{CheckSpec, Diagnostics, EmulationMode};
+use crate::profile::{DeviceProfile, Profile, RegisterReset, StartupState};
+
+/// Sets up a `Computer` for a part: its memory layout and what the CPU starts with after a reset.
+/// Without a profile the computer has no restrictions, like `run --profile generic`.
+#[derive(Debug, Clone)]
+pub struct ComputerBuilder {
+    profile: DeviceProfile,
+    reset_sp: Option<u16>,
+    /// r4 - r15
+    registers: [u16; 12],
+    ram_fill: u8,
+    emulation: EmulationMode,
+    /// severities overriding the emulation mode's (`run --check`)
+    checks: Vec<CheckSpec>,
+}
+
+impl ComputerBuilder {
+    pub fn new() -> ComputerBuilder {
+        return ComputerBuilder { profile: Profile::Generic.device(), reset_sp: None, registers: [0; 12], ram_fill: 0,
+                                emulation: EmulationMode::Permissive, checks: Vec::new() };
+    }
+
+    pub fn profile(mut self, profile: DeviceProfile) -> ComputerBuilder {
+        self.profile = profile;
+        return self;
+    }
+
+    /// Stack pointer after a reset instead of the top of the profile's RAM
+    pub fn reset_sp(mut self, sp: u16) -> ComputerBuilder {
+        self.reset_sp = Some(sp);
+        return self;
+    }
+
+    /// Value of one of r4 - r15 after a reset instead of 0
+    pub fn reset_register(mut self, reset: RegisterReset) -> ComputerBuilder {
+        self.registers[reset.register as usize - 4] = reset.value;
+        return self;
+    }
+
+    /// Byte the profile's RAM holds at power-up instead of 0
+    pub fn ram_fill(mut self, fill: u8) -> ComputerBuilder {
+        self.ram_fill = fill;
+        return self;
+    }
+
+    /// Strict emulation faults the CPU on anything that would behave differently on hardware
+    /// (odd word addresses, flash writes, unimplemented peripherals, writes to r3, ...), permissive
+    /// (the default) lets it all pass
+    pub fn emulation(mut self, mode: EmulationMode) -> ComputerBuilder {
+        self.emulation = mode;
+        return self;
+    }
+
+    pub(crate) fn check(mut self, check: CheckSpec) -> ComputerBuilder {
+        self.checks.push(check);
+        return self;
+    }
+
+    pub(crate) fn startup(&self) -> StartupState {
+        let profile: StartupState = self.profile.startup();
+        return StartupState { sp: self.reset_sp.unwrap_or(profile.sp), registers: self.registers, ram_fill: self.ram_fill };
+    }
+
+    /// Set up `c` this way and reset it
+    pub(crate) fn apply(&self, c: &mut Computer) {
+        c.no_execute = self.profile.no_execute.clone();
+        c.ram = self.profile.ram.clone();
+        c.retained = self.profile.backup_memory.clone();
+        c.peripherals = self.profile.peripherals.clone();
+        c.flash = self.profile.flash.clone();
+        c.read_only = self.profile.read_only.clone();
+        c.map_bus();
+        c.diagnostics = Diagnostics::new(self.emulation, &self.checks);
+        c.startup = self.startup();
+        c.devices.wdt.set_armed(!self.profile.watchdog_held);
+        c.devices.vectors = self.profile.vector_remap();
+        c.reset();
+    }
+
+    pub fn build(&self) -> Computer {
+        let mut c: Computer = Computer::new();
+        self.apply(&mut c);
+        return c;
+    }
+}
+
+impl Default for ComputerBuilder {
+    fn default() -> ComputerBuilder {
+        return ComputerBuilder::new();
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::rc::Rc;
+use crate::clock::Clock;
+use crate::devices::{DeviceId, Devices};
+use crate::devices::gpio::GpioDevice;
+use crate::profile::Region;
+
+/// What answers an address on the data bus
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Handler {
+    /// nothing is mapped here, plain memory as on the generic part
+    Unmapped,
+    /// cleared by a power cycle unless retained, otherwise plain memory
+    Ram,
+    /// written through the flash controller only (`--check flash-write`)
+    Flash,
+    /// peripheral space no emulated device answers, backed by memory (`--check unimplemented`)
+    Peripheral,
+    /// ROM, data writes are dropped (`--check read-only-write`)
+    ReadOnly,
+    /// a register of an emulated device, never backed by memory
+    Device(DeviceId),
+}
+
+/// What a device may need while it handles an access besides its own registers
+pub(crate) struct BusAccess<'a> {
+    pub(crate) clock: &'a Clock,
+    /// address of the instruction performing the access
+    pub(crate) pc: u16,
+    /// the ports, for devices that drive or sense pins (`None` while the ports handle an access)
+    pub(crate) gpio: Option<&'a mut GpioDevice>,
+}
+
+/// A device on the data bus. `claims` says which bytes are its registers, the bus map asks it once
+/// when the map is made and routes every access to one of those bytes (or to the word holding one)
+/// to `read`/`write`. A word access passes the even address and the word with its high byte at
+/// that address, a byte access passes the byte's own address and the byte in the low 8 bits.
+pub(crate) trait BusDevice {
+    fn claims(&self, address: u16) -> bool;
+    fn read(&mut self, address: u16, word: bool, bus: &mut BusAccess) -> u16;
+    fn write(&mut self, address: u16, value: u16, word: bool, bus: &mut BusAccess);
+}
+
+/// `read` for devices with byte registers, a word is the byte pair
+pub(crate) fn read_bytes(address: u16, word: bool, mut read: impl FnMut(u16) -> u8) -> u16 {
+    if !word {
+        return read(address) as u16;
+    }
+    return ((read(address) as u16) << 8) | read(address + 1) as u16;
+}
+
+/// `write` for devices with byte registers, a word is the byte pair
+pub(crate) fn write_bytes(address: u16, value: u16, word: bool, mut write: impl FnMut(u16, u8)) {
+    if !word {
+        write(address, value as u8);
+        return;
+    }
+    write(address, (value >> 8) as u8);
+    write(address + 1, (value & 0xff) as u8);
+}
+
+/// `read` for devices with word registers, a byte read returns the low byte of the register
+#[inline]
+pub(crate) fn word_register(value: u16, word: bool) -> u16 {
+    return if word {value} else {value & 0xff};
+}
+
+/// Which handler claims each of the 64K addresses, so an access looks up its target once instead
+/// of asking every device and scanning the profile's regions. Device registers are mapped when
+/// the map is made and win over any region, regions mapped later win over earlier ones. Forked
+/// computers share the table.
+#[derive(Clone)]
+pub(crate) struct BusMap {
+    handlers: Rc<[Handler; 0x10000]>,
+}
+
+impl BusMap {
+    /// Every device's registers and nothing mapped everywhere else. A byte no device claims that
+    /// shares a word with a device's register belongs to that device as well, the word is its
+    /// register.
+    pub(crate) fn new(devices: &Devices) -> BusMap {
+        let mut handlers: Box<[Handler; 0x10000]> = vec![Handler::Unmapped; 0x10000].into_boxed_slice()
+            .try_into().unwrap();
+        for id in DeviceId::ALL {
+            let device: &dyn BusDevice = devices.device(id);
+            for address in 0..=0xffff_u16 {
+                if handlers[address as usize] == Handler::Unmapped && device.claims(address) {
+                    handlers[address as usize] = Handler::Device(id);
+                }
+            }
+        }
+        for word in (0..0x10000).step_by(2) {
+            match (handlers[word], handlers[word + 1]) {
+                (Handler::Device(id), Handler::Unmapped) => handlers[word + 1] = Handler::Device(id),
+                (Handler::Unmapped, Handler::Device(id)) => handlers[word] = Handler::Device(id),
+                _ => {}
+            }
+        }
+        return BusMap { handlers: Rc::from(handlers) };
+    }
+
+    /// The profile's layout: RAM, then flash, then peripheral space, with the retained regions inside
+    /// peripheral space as RAM (backup memory), then the read-only regions over all of them
+    pub(crate) fn with_regions(devices: &Devices, ram: &[Region], flash: &[Region], peripherals: &[Region],
+                               retained: &[Region], read_only: &[Region]) -> BusMap {
+        let mut map: BusMap = BusMap::new(devices);
+        for (regions, handler) in [(ram, Handler::Ram), (flash, Handler::Flash), (peripherals, Handler::Peripheral)] {
+            for region in regions {
+                map.map(*region, handler);
+            }
+        }
+        let handlers = Rc::make_mut(&mut map.handlers);
+        for region in retained {
+            for address in region.start..=region.end {
+                if handlers[address as usize] == Handler::Peripheral {
+                    handlers[address as usize] = Handler::Ram;
+                }
+            }
+        }
+        for region in read_only {
+            map.map(*region, Handler::ReadOnly);
+        }
+        return map;
+    }
+
+    /// Hand `region` to `handler`, device registers inside it stay with their devices
+    pub(crate) fn map(&mut self, region: Region, handler: Handler) {
+        let handlers = Rc::make_mut(&mut self.handlers);
+        for address in region.start..=region.end {
+            if !matches!(handlers[address as usize], Handler::Device(_)) {
+                handlers[address as usize] = handler;
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn handler(&self, address: u16) -> Handler {
+        return self.handlers[address as usize];
+    }
+
+    /// Whether a data write of the word (or byte) at `address` has to be dropped
+    #[inline]
+    pub(crate) fn read_only(&self, address: u16, word: bool) -> bool {
+        if !word {
+            return self.handlers[address as usize] == Handler::ReadOnly;
+        }
+        let word: usize = (address & 0xfffe) as usize;
+        return self.handlers[word] == Handler::ReadOnly || self.handlers[word + 1] == Handler::ReadOnly;
+    }
+
+    /// The device whose register the byte at `address` is
+    #[inline]
+    pub(crate) fn device(&self, address: u16) -> Option<DeviceId> {
+        return match self.handlers[address as usize] {
+            Handler::Device(id) => Some(id),
+            _ => None,
+        };
+    }
+}
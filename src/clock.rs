@@ -0,0 +1,287 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::Instant;
+use crate::devices::rng::split_mix;
+
+pub(crate) const DEFAULT_MCLK_HZ: u64 = 1_000_000;
+pub(crate) const ACLK_HZ: u64 = 32_768;
+/// How long the DCO keeps one frequency before drifting to the next
+pub(crate) const DRIFT_SEGMENT_CYCLES: u64 = 100_000;
+/// Largest drift between two segments, as a fraction of the tolerance
+const DRIFT_STEP_DIVISOR: i64 = 10;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum TimeSource {
+    /// Time is derived from executed cycles, runs are bit-identical
+    Emulated,
+    /// Time follows the host's wall clock, for interactive use
+    Host,
+}
+
+/// Frequency error of the DCO that MCLK comes from (`run --dco-tolerance`). It starts somewhere
+/// within the tolerance and wanders by up to a tenth of it every `DRIFT_SEGMENT_CYCLES`, never
+/// leaving it. Every value is drawn from the seed, so the same seed drifts the same way.
+#[derive(Clone)]
+struct Dco {
+    seed: u64,
+    tolerance_ppm: i64,
+    state: u64,
+    error_ppm: i64,
+    hz: u64,
+    /// cycle the current segment started at, and the emulated time then
+    segment_cycles: u64,
+    segment_nanos: u128,
+}
+
+impl Dco {
+    fn new(seed: u64, tolerance_ppm: i64) -> Dco {
+        let mut dco = Dco { seed, tolerance_ppm, state: seed ^ 0xdc0d_c0dc_0dc0_dc0d, error_ppm: 0, hz: DEFAULT_MCLK_HZ,
+                            segment_cycles: 0, segment_nanos: 0 };
+        dco.error_ppm = dco.draw(tolerance_ppm);
+        dco.hz = dco.frequency();
+        return dco;
+    }
+
+    /// Uniform in -`range`..=`range`
+    fn draw(&mut self, range: i64) -> i64 {
+        if range == 0 {
+            return 0;
+        }
+        return (split_mix(&mut self.state) % (2 * range as u64 + 1)) as i64 - range;
+    }
+
+    fn frequency(&self) -> u64 {
+        return (DEFAULT_MCLK_HZ as i64 + DEFAULT_MCLK_HZ as i64 * self.error_ppm / 1_000_000) as u64;
+    }
+
+    fn nanos_at(&self, cycles: u64) -> u128 {
+        return self.segment_nanos + (cycles - self.segment_cycles) as u128 * 1_000_000_000 / self.hz as u128;
+    }
+
+    /// Move on to the segment starting at `cycles`
+    fn next_segment(&mut self) {
+        let cycles: u64 = self.segment_cycles + DRIFT_SEGMENT_CYCLES;
+        self.segment_nanos = self.nanos_at(cycles);
+        self.segment_cycles = cycles;
+        let step: i64 = self.draw(self.tolerance_ppm / DRIFT_STEP_DIVISOR);
+        self.error_ppm = (self.error_ppm + step).clamp(-self.tolerance_ppm, self.tolerance_ppm);
+        self.hz = self.frequency();
+    }
+}
+
+/// Keeps track of emulated cycles and answers "how much time has passed" for anything that
+/// models real time, so those devices don't need to know which time source is in use
+#[derive(Clone)]
+pub(crate) struct Clock {
+    source: TimeSource,
+    mclk_hz: u64,
+    cycles: u64,
+    host_start: Instant,
+    /// when host time stopped counting, while a control command is handled
+    frozen_at: Option<Instant>,
+    dco: Option<Dco>,
+    /// cycle the DCO drifts next, never without one
+    next_drift: u64,
+    /// time ACLK lost while OSCOFF stopped it, and when the current stop began
+    aclk_lost_nanos: u128,
+    aclk_off_at: Option<u128>,
+    /// cycles SMCLK lost while SCG1 stopped it, and the cycle the current stop began
+    smclk_lost: u64,
+    smclk_off_at: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl Clock {
+    pub(crate) fn new(source: TimeSource) -> Clock {
+        return Clock {
+            source,
+            mclk_hz: DEFAULT_MCLK_HZ,
+            cycles: 0,
+            host_start: Instant::now(),
+            frozen_at: None,
+            dco: None,
+            next_drift: u64::MAX,
+            aclk_lost_nanos: 0,
+            aclk_off_at: None,
+            smclk_lost: 0,
+            smclk_off_at: None,
+        };
+    }
+
+    /// The DCO starts drifting the same way again
+    pub(crate) fn reset(&mut self) {
+        self.cycles = 0;
+        self.host_start = Instant::now();
+        if self.frozen_at.is_some() {
+            self.frozen_at = Some(self.host_start);
+        }
+        self.aclk_lost_nanos = 0;
+        self.aclk_off_at = None;
+        self.smclk_lost = 0;
+        self.smclk_off_at = None;
+        if let Some(dco) = &self.dco {
+            self.set_dco_tolerance(dco.tolerance_ppm as u32, dco.seed);
+        }
+    }
+
+    /// Let MCLK drift within `tolerance_ppm` of its nominal frequency, 0 keeps it exact
+    pub(crate) fn set_dco_tolerance(&mut self, tolerance_ppm: u32, seed: u64) {
+        if tolerance_ppm == 0 {
+            self.dco = None;
+            self.mclk_hz = DEFAULT_MCLK_HZ;
+            self.next_drift = u64::MAX;
+            return;
+        }
+        let dco: Dco = Dco::new(seed, tolerance_ppm as i64);
+        self.mclk_hz = dco.hz;
+        self.next_drift = DRIFT_SEGMENT_CYCLES;
+        self.dco = Some(dco);
+        self.drift();
+    }
+
+    /// Catch up with the segments `cycles` went past
+    fn drift(&mut self) {
+        if let Some(dco) = &mut self.dco {
+            while self.cycles >= dco.segment_cycles + DRIFT_SEGMENT_CYCLES {
+                dco.next_segment();
+            }
+            self.mclk_hz = dco.hz;
+            self.next_drift = dco.segment_cycles + DRIFT_SEGMENT_CYCLES;
+        }
+    }
+
+    pub(crate) fn source(&self) -> TimeSource {
+        return self.source;
+    }
+
+    pub(crate) fn set_source(&mut self, source: TimeSource) {
+        self.source = source;
+    }
+
+    /// The frequency MCLK actually runs at right now, the nominal one unless the DCO drifts
+    pub(crate) fn mclk_hz(&self) -> u64 {
+        return self.mclk_hz;
+    }
+
+    pub(crate) fn cycles(&self) -> u64 {
+        return self.cycles;
+    }
+
+    #[inline]
+    pub(crate) fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if self.cycles >= self.next_drift {
+            self.drift();
+        }
+    }
+
+    /// Continue from a snapshot, the DCO replays its drift up to `cycles`
+    pub(crate) fn restore_cycles(&mut self, cycles: u64) {
+        self.reset();
+        self.advance(cycles);
+    }
+
+    /// Stop host time until `thaw`, the emulated machine doesn't see the host handle a command.
+    /// Emulated time only moves with cycles anyway.
+    pub(crate) fn freeze(&mut self) {
+        self.frozen_at.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn thaw(&mut self) {
+        if let Some(at) = self.frozen_at.take() {
+            self.host_start += at.elapsed();
+        }
+    }
+
+    /// Time since reset according to the active source
+    pub(crate) fn elapsed_nanos(&self) -> u128 {
+        return match self.source {
+            TimeSource::Emulated => match &self.dco {
+                Some(dco) => dco.nanos_at(self.cycles),
+                None => (self.cycles as u128) * 1_000_000_000 / (self.mclk_hz as u128),
+            },
+            TimeSource::Host => self.frozen_at.unwrap_or_else(Instant::now).duration_since(self.host_start).as_nanos(),
+        };
+    }
+
+    /// Number of ticks of a clock running at `hz` since reset
+    pub(crate) fn ticks(&self, hz: u64) -> u64 {
+        return (self.elapsed_nanos() * (hz as u128) / 1_000_000_000) as u64;
+    }
+
+    /// Stop ACLK while `off` (OSCOFF in the SR), what it drives doesn't count the time it was stopped
+    #[inline]
+    pub(crate) fn gate_aclk(&mut self, off: bool) {
+        if off == self.aclk_off_at.is_some() {
+            return;
+        }
+        let now: u128 = self.elapsed_nanos();
+        match self.aclk_off_at.take() {
+            Some(since) => self.aclk_lost_nanos += now.saturating_sub(since),
+            None => self.aclk_off_at = Some(now),
+        }
+    }
+
+    /// Number of ticks of ACLK at `hz` since reset, less the time OSCOFF stopped it
+    pub(crate) fn aclk_ticks(&self, hz: u64) -> u64 {
+        let now: u128 = self.aclk_off_at.unwrap_or_else(|| self.elapsed_nanos());
+        return (now.saturating_sub(self.aclk_lost_nanos) * (hz as u128) / 1_000_000_000) as u64;
+    }
+
+    /// How far ACLK is behind the other clocks, for snapshots
+    pub(crate) fn aclk_lag_nanos(&self) -> u64 {
+        let now: u128 = self.elapsed_nanos();
+        return now.saturating_sub(self.aclk_off_at.unwrap_or(now)).saturating_add(self.aclk_lost_nanos) as u64;
+    }
+
+    /// Continue from a snapshot's `aclk_lag_nanos`, the CPU's OSCOFF stops ACLK again on the next step
+    pub(crate) fn restore_aclk_lag(&mut self, lag_nanos: u64) {
+        self.aclk_lost_nanos = lag_nanos as u128;
+        self.aclk_off_at = None;
+    }
+
+    /// Stop SMCLK while `off` (SCG1 in the SR)
+    #[inline]
+    pub(crate) fn gate_smclk(&mut self, off: bool) {
+        if off == self.smclk_off_at.is_some() {
+            return;
+        }
+        match self.smclk_off_at.take() {
+            Some(since) => self.smclk_lost += self.cycles.saturating_sub(since),
+            None => self.smclk_off_at = Some(self.cycles),
+        }
+    }
+
+    /// Number of SMCLK ticks since reset, it runs from the DCO like MCLK so a tick is a cycle, less
+    /// the cycles SCG1 stopped it
+    pub(crate) fn smclk_ticks(&self) -> u64 {
+        return self.smclk_off_at.unwrap_or(self.cycles).saturating_sub(self.smclk_lost);
+    }
+
+    /// How many cycles SMCLK is behind MCLK, for snapshots
+    pub(crate) fn smclk_lag(&self) -> u64 {
+        return self.cycles - self.smclk_ticks();
+    }
+
+    /// Continue from a snapshot's `smclk_lag`, the CPU's SCG1 stops SMCLK again on the next step
+    pub(crate) fn restore_smclk_lag(&mut self, lag: u64) {
+        self.smclk_lost = lag;
+        self.smclk_off_at = None;
+    }
+}
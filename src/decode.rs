@@ -0,0 +1,76 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Instruction words are decoded through a table build.rs generates, 4 bytes for every word:
+//   byte 0  bits 0-3 kind (0 = nothing to execute, 1 = single operand, 2 = jump, 3 = double operand)
+//           bits 4-7 opcode (single and double operand, double counts from MOV = 0) or jump condition
+//   jump:   bytes 1-2 signed offset in bytes, little-endian
+//   others: byte 1 bits 0-3 source register, bits 4-5 As
+//           byte 2 bits 0-3 destination register, bit 4 Ad, bit 5 B/W
+
+static DECODE_TABLE: &[u8; 0x40000] = include_bytes!(concat!(env!("OUT_DIR"), "/decode_table.bin"));
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Decoded {
+    /// nonexistent opcodes (and 0x0000) do nothing
+    None,
+    Single { opcode: u8, reg: u8, as_: u8, bw: bool },
+    Jump { condition: u8, offset: i16 },
+    Double { opcode: u8, src_reg: u8, as_: u8, ad: u8, bw: bool, dst_reg: u8 },
+}
+
+#[inline(always)]
+pub(crate) fn decode(instruction: u16) -> Decoded {
+    let index: usize = instruction as usize * 4;
+    let [head, first, second, _] = [DECODE_TABLE[index], DECODE_TABLE[index + 1], DECODE_TABLE[index + 2], DECODE_TABLE[index + 3]];
+    let opcode: u8 = head >> 4;
+    return match head & 0xf {
+        1 => Decoded::Single { opcode, reg: first & 0xf, as_: first >> 4, bw: second & 0x20 != 0 },
+        2 => Decoded::Jump { condition: opcode, offset: i16::from_le_bytes([first, second]) },
+        3 => Decoded::Double {
+            opcode, src_reg: first & 0xf, as_: first >> 4, ad: (second >> 4) & 1, bw: second & 0x20 != 0, dst_reg: second & 0xf,
+        },
+        _ => Decoded::None,
+    };
+}
+
+/// Words the MSP430 CPU doesn't define: the MSP430X address and extension instructions (below
+/// 0x1000, 0x1380-0x1fff), RETI with operand bits and byte-mode SWPB, SXT and CALL. `decoded` is
+/// what `decode` made of `instruction`.
+pub(crate) fn illegal(instruction: u16, decoded: Decoded) -> bool {
+    return match decoded {
+        Decoded::None => true,
+        Decoded::Single { opcode: 7, .. } => true,
+        Decoded::Single { opcode: 6, .. } => instruction != crate::stepping::RETI,
+        Decoded::Single { opcode: 1 | 3 | 5, bw, .. } => bw,
+        _ => false,
+    };
+}
+
+/// The instruction word `decode` turns into `decoded`, extension words aren't included
+pub(crate) fn encode(decoded: Decoded) -> u16 {
+    return match decoded {
+        Decoded::None => 0,
+        Decoded::Single { opcode, reg, as_, bw } =>
+            0x1000 | (opcode as u16 & 0x7) << 7 | (bw as u16) << 6 | (as_ as u16 & 0x3) << 4 | reg as u16 & 0xf,
+        Decoded::Jump { condition, offset } => 0x2000 | (condition as u16 & 0x7) << 10 | (offset / 2) as u16 & 0x3ff,
+        Decoded::Double { opcode, src_reg, as_, ad, bw, dst_reg } =>
+            (opcode as u16 + 4) << 12 | (src_reg as u16 & 0xf) << 8 | (ad as u16 & 0x1) << 7 | (bw as u16) << 6
+                | (as_ as u16 & 0x3) << 4 | dst_reg as u16 & 0xf,
+    };
+}
@@ -0,0 +1,240 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::{pins, volts, RegisterView};
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const CACTL1: u16 = 0x0059;
+pub(crate) const CACTL2: u16 = 0x005a;
+pub(crate) const CAPD: u16 = 0x005b;
+
+// CACTL1
+pub(crate) const CAIFG: u8 = 0x01;
+pub(crate) const CAIE: u8 = 0x02;
+/// 0 = CAOUT rising edge sets CAIFG, 1 = falling edge
+pub(crate) const CAIES: u8 = 0x04;
+pub(crate) const CAON: u8 = 0x08;
+pub(crate) const CAREF_MASK: u8 = 0x30;
+/// 0 = the reference goes to the + terminal, 1 = to the - terminal
+pub(crate) const CARSEL: u8 = 0x40;
+pub(crate) const CAEX: u8 = 0x80;
+
+// CACTL2
+pub(crate) const CAOUT: u8 = 0x01;
+pub(crate) const CAF: u8 = 0x02;
+pub(crate) const P2CA0: u8 = 0x04;
+/// P2CA3-P2CA1 select the - terminal input
+const P2CA_MINUS_MASK: u8 = 0x38;
+pub(crate) const P2CA4: u8 = 0x40;
+pub(crate) const CASHORT: u8 = 0x80;
+
+pub(crate) const COMPARATOR_VECTOR: u16 = 0xfff6;
+
+pub(crate) const CHANNELS: usize = 8;
+/// Forward voltage of the diode reference (CAREF = 3)
+const DIODE_MV: u16 = 550;
+
+/// Comparator_A+ of the G2xx parts. The inputs CA0-CA7 are voltages the host plays in
+/// (`run --analog`), undriven inputs are at 0 V. CAOUT is the + terminal being above the - terminal,
+/// CAEX swaps the inputs and inverts the output so it changes nothing here. CAF, CASHORT and CAPD
+/// are stored only.
+#[derive(Clone)]
+pub(crate) struct ComparatorDevice {
+    ctl1: u8,
+    ctl2: u8,
+    pd: u8,
+    inputs_mv: [u16; CHANNELS],
+    supply_mv: u16,
+}
+
+impl ComparatorDevice {
+    pub(crate) fn new(supply_mv: u16) -> ComparatorDevice {
+        return ComparatorDevice {
+            ctl1: 0,
+            ctl2: 0,
+            pd: 0,
+            inputs_mv: [0; CHANNELS],
+            supply_mv,
+        };
+    }
+
+    /// The inputs stay, they are driven from outside
+    pub(crate) fn reset(&mut self) {
+        self.ctl1 = 0;
+        self.ctl2 = 0;
+        self.pd = 0;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (CACTL1..=CAPD).contains(&address);
+    }
+
+    /// Voltage on CAx changed
+    pub(crate) fn set_input(&mut self, channel: usize, millivolts: u16) {
+        self.inputs_mv[channel] = millivolts;
+        self.evaluate();
+    }
+
+    /// The supply changed, the CAREF levels follow it
+    pub(crate) fn set_supply(&mut self, millivolts: u16) {
+        if self.supply_mv != millivolts {
+            self.supply_mv = millivolts;
+            self.evaluate();
+        }
+    }
+
+    /// Internal reference selected by CAREF, `None` while it is off
+    fn reference_mv(&self) -> Option<u16> {
+        return match (self.ctl1 & CAREF_MASK) >> 4 {
+            1 => Some(self.supply_mv / 4),
+            2 => Some(self.supply_mv / 2),
+            3 => Some(DIODE_MV),
+            _ => None,
+        };
+    }
+
+    /// Voltages on the + and - terminals, an unconnected terminal is at 0 V
+    fn terminals_mv(&self) -> (u16, u16) {
+        let plus: Option<usize> = match (self.ctl2 & P2CA4 != 0, self.ctl2 & P2CA0 != 0) {
+            (false, false) => None,
+            (false, true) => Some(0),
+            (true, false) => Some(1),
+            (true, true) => Some(2),
+        };
+        let minus: Option<usize> = match (self.ctl2 & P2CA_MINUS_MASK) >> 3 {
+            0 => None,
+            channel => Some(channel as usize),
+        };
+        let mut plus: u16 = plus.map(|c| self.inputs_mv[c]).unwrap_or(0);
+        let mut minus: u16 = minus.map(|c| self.inputs_mv[c]).unwrap_or(0);
+        if let Some(reference) = self.reference_mv() {
+            if self.ctl1 & CARSEL == 0 {
+                plus = reference;
+            } else {
+                minus = reference;
+            }
+        }
+        return (plus, minus);
+    }
+
+    /// Update CAOUT, flagging the selected edge
+    fn evaluate(&mut self) {
+        let (plus, minus) = self.terminals_mv();
+        let out: bool = self.ctl1 & CAON != 0 && plus > minus;
+        let was: bool = self.ctl2 & CAOUT != 0;
+        if out != was && out == (self.ctl1 & CAIES == 0) {
+            self.ctl1 |= CAIFG;
+        }
+        self.ctl2 = if out {self.ctl2 | CAOUT} else {self.ctl2 & !CAOUT};
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "comparator",
+            vector: COMPARATOR_VECTOR,
+            nmi: false,
+            enabled: self.ctl1 & CAIE != 0,
+            flagged: self.ctl1 & CAIFG != 0,
+        };
+    }
+
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        if self.ctl1 & CAIE != 0 && self.ctl1 & CAIFG != 0 {
+            return Some(COMPARATOR_VECTOR);
+        }
+        return None;
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let reference: String = self.reference_mv().map(volts).unwrap_or("off".to_string());
+        let (plus, minus) = self.terminals_mv();
+        let ctl2: u16 = self.ctl2 as u16;
+        return vec![
+            RegisterView::byte("CACTL1", CACTL1, self.ctl1)
+                .flag("CAIFG", CAIFG as u16).flag("CAIE", CAIE as u16).flag("CAIES", CAIES as u16).flag("CAON", CAON as u16)
+                .field("CAREF", reference).flag("CARSEL", CARSEL as u16).flag("CAEX", CAEX as u16),
+            RegisterView::byte("CACTL2", CACTL2, self.ctl2)
+                .flag("CAOUT", CAOUT as u16).flag("CAF", CAF as u16)
+                .field("P2CA", format!("{:#04x}", (ctl2 & (P2CA0 | P2CA_MINUS_MASK | P2CA4) as u16) >> 2))
+                .flag("CASHORT", CASHORT as u16)
+                .field("plus", volts(plus)).field("minus", volts(minus)),
+            RegisterView::byte("CAPD", CAPD, self.pd).field("disabled", pins(1, self.pd)),
+        ];
+    }
+
+    pub(crate) fn read_byte(&self, address: u16) -> u8 {
+        return match address {
+            CACTL1 => self.ctl1,
+            CACTL2 => self.ctl2,
+            CAPD => self.pd,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            CACTL1 => self.ctl1 = value,
+            CACTL2 => self.ctl2 = (value & !CAOUT) | (self.ctl2 & CAOUT), // CAOUT is read only
+            CAPD => self.pd = value,
+            _ => {},
+        }
+        self.evaluate();
+    }
+}
+
+impl BusDevice for ComparatorDevice {
+    fn claims(&self, address: u16) -> bool {
+        return ComparatorDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::read_bytes(address, word, |address| self.read_byte(address));
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, _bus: &mut BusAccess) {
+        bus::write_bytes(address, value, word, |address, value| self.write_byte(address, value));
+    }
+}
+
+impl SnapshotState for ComparatorDevice {
+    const TAG: [u8; 4] = *b"COMP";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.ctl1);
+        w.u8(self.ctl2);
+        w.u8(self.pd);
+        for input in self.inputs_mv {
+            w.u16(input);
+        }
+        w.u16(self.supply_mv);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.ctl1 = r.u8();
+        self.ctl2 = r.u8();
+        self.pd = r.u8();
+        for input in &mut self.inputs_mv {
+            *input = r.u16();
+        }
+        self.supply_mv = r.u16();
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const CONSOLE_IN: u16 = 0x01c8;
+pub(crate) const CONSOLE_STATUS: u16 = 0x01ca;
+pub(crate) const CONSOLE_CTL: u16 = 0x01cc;
+
+pub(crate) const STATUS_AVAILABLE: u16 = 0x0001;
+pub(crate) const STATUS_CLOSED: u16 = 0x0002;
+pub(crate) const CTL_INTERRUPT: u16 = 0x0001;
+
+/// USCIAB0TX on the 2xx parts, unused since the emulated UART has no TX interrupt
+pub(crate) const CONSOLE_VECTOR: u16 = 0xffec;
+
+/// Input FIFO fed from the host's stdin (`run --stdin`), a simpler path than the UART for
+/// interactive console programs
+#[derive(Clone)]
+pub(crate) struct ConsoleDevice {
+    fifo: VecDeque<u8>,
+    /// the host has no more input to give
+    closed: bool,
+    ctl: u16,
+}
+
+impl ConsoleDevice {
+    pub(crate) fn new() -> ConsoleDevice {
+        return ConsoleDevice {
+            fifo: VecDeque::new(),
+            closed: false,
+            ctl: 0,
+        };
+    }
+
+    /// Unread input is dropped, a closed input stays closed
+    pub(crate) fn reset(&mut self) {
+        self.fifo.clear();
+        self.ctl = 0;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (CONSOLE_IN..=CONSOLE_CTL + 1).contains(&address);
+    }
+
+    /// Queue host input for firmware to read
+    pub(crate) fn receive(&mut self, bytes: &[u8]) {
+        self.fifo.extend(bytes);
+    }
+
+    /// The host input ended (e.g. stdin reached EOF)
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "console input",
+            vector: CONSOLE_VECTOR,
+            nmi: false,
+            enabled: self.ctl & CTL_INTERRUPT != 0,
+            flagged: !self.fifo.is_empty(),
+        };
+    }
+
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        if self.ctl & CTL_INTERRUPT != 0 && !self.fifo.is_empty() {
+            return Some(CONSOLE_VECTOR);
+        }
+        return None;
+    }
+
+    /// CONSOLE_IN is left out, reading it would take a byte
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let status: u16 = (if self.fifo.is_empty() {0} else {STATUS_AVAILABLE}) | if self.closed {STATUS_CLOSED} else {0};
+        return vec![
+            RegisterView::word("CONSOLE_STATUS", CONSOLE_STATUS, status)
+                .flag("AVAILABLE", STATUS_AVAILABLE).flag("CLOSED", STATUS_CLOSED)
+                .field("queued", self.fifo.len().to_string()),
+            RegisterView::word("CONSOLE_CTL", CONSOLE_CTL, self.ctl).flag("IE", CTL_INTERRUPT),
+        ];
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            CONSOLE_IN => self.fifo.pop_front().map(|b| b as u16).unwrap_or(0xffff),
+            CONSOLE_STATUS => (if self.fifo.is_empty() {0} else {STATUS_AVAILABLE})
+                | if self.closed {STATUS_CLOSED} else {0},
+            CONSOLE_CTL => self.ctl,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            CONSOLE_CTL => self.ctl = value & CTL_INTERRUPT,
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for ConsoleDevice {
+    fn claims(&self, address: u16) -> bool {
+        return ConsoleDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+impl SnapshotState for ConsoleDevice {
+    const TAG: [u8; 4] = *b"CONS";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.ctl);
+        w.bool(self.closed);
+        w.bytes(&self.fifo.iter().copied().collect::<Vec<u8>>());
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.ctl = r.u16();
+        self.closed = r.bool();
+        self.fifo = r.bytes().into();
+    }
+}
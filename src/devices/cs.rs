@@ -0,0 +1,288 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const SFRIE1: u16 = 0x0100;
+pub(crate) const SFRIFG1: u16 = 0x0102;
+pub(crate) const CSCTL0: u16 = 0x0160;
+pub(crate) const CSCTL4: u16 = 0x0168;
+pub(crate) const CSCTL5: u16 = 0x016a;
+const CSCTL6: u16 = 0x016c;
+
+// SFRIE1/SFRIFG1, the other special function bits aren't modeled
+pub(crate) const OFIE: u16 = 0x0002;
+pub(crate) const OFIFG: u16 = 0x0002;
+
+/// high byte of a CSCTL0 word write, anything else is a PUC
+pub(crate) const CSKEY: u16 = 0xa500;
+/// what the high byte of CSCTL0 reads as
+const CSKEY_READ: u16 = 0x9600;
+
+// CSCTL4
+pub(crate) const LFXTOFF: u16 = 0x0001;
+pub(crate) const HFXTOFF: u16 = 0x0100;
+
+// CSCTL5
+pub(crate) const LFXTOFFG: u16 = 0x0001;
+pub(crate) const HFXTOFFG: u16 = 0x0002;
+const CSCTL5_MASK: u16 = LFXTOFFG | HFXTOFFG;
+
+/// UNMI, the oscillator fault is the only user NMI modeled
+pub(crate) const UNMI_VECTOR: u16 = 0xfffa;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Crystal {
+    /// 32768 Hz watch crystal
+    Lfxt,
+    Hfxt,
+}
+
+impl Crystal {
+    pub(crate) fn from_id(id: u8) -> Option<Crystal> {
+        return match id {
+            0 => Some(Crystal::Lfxt),
+            1 => Some(Crystal::Hfxt),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            Crystal::Lfxt => 0,
+            Crystal::Hfxt => 1,
+        };
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            Crystal::Lfxt => "LFXT",
+            Crystal::Hfxt => "HFXT",
+        };
+    }
+
+    /// CSCTL4 bit that turns the oscillator off, and its CSCTL5 fault flag
+    fn bits(&self) -> (u16, u16) {
+        return match self {
+            Crystal::Lfxt => (LFXTOFF, LFXTOFFG),
+            Crystal::Hfxt => (HFXTOFF, HFXTOFFG),
+        };
+    }
+}
+
+/// Clock system of the FR5xx/FR6xx parts, as far as oscillator faults go. The crystals work
+/// unless the host breaks one (control command 28), then the fault flag of a running crystal
+/// stays set, and so does OFIFG, however often firmware clears them. OFIFG with OFIE raises the
+/// user NMI. The clocks themselves don't change, there is no fail-safe switch to the internal
+/// oscillators to model since the emulated clocks don't depend on the crystals.
+/// Registers are password protected: a word write to CSCTL0 must carry CSKEY in the high byte, which
+/// opens the other registers until a byte other than 0xa5 is written to CSCTL0_H.
+#[derive(Clone)]
+pub(crate) struct CsDevice {
+    /// CSCTL1 to CSCTL6, stored as written apart from the CSCTL5 fault flags
+    ctl: [u16; 6],
+    ie: u16,
+    ifg: u16,
+    open: bool,
+    puc_requested: bool,
+    /// crystals the host broke, a physical fault that a reset doesn't fix
+    failed: [bool; 2],
+}
+
+impl CsDevice {
+    pub(crate) fn new() -> CsDevice {
+        let mut ctl: [u16; 6] = [0; 6];
+        ctl[Self::index(CSCTL4)] = LFXTOFF | HFXTOFF;
+        return CsDevice {
+            ctl,
+            ie: 0,
+            ifg: 0,
+            open: false,
+            puc_requested: false,
+            failed: [false; 2],
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let failed: [bool; 2] = self.failed;
+        *self = CsDevice::new();
+        self.failed = failed;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (SFRIE1..=SFRIFG1 + 1).contains(&address) || (CSCTL0..=CSCTL6 + 1).contains(&address);
+    }
+
+    /// Position of CSCTL1-6 in `ctl`
+    fn index(address: u16) -> usize {
+        return ((address - CSCTL0) / 2 - 1) as usize;
+    }
+
+    /// Break or repair a crystal
+    pub(crate) fn set_failed(&mut self, crystal: Crystal, failed: bool) {
+        self.failed[crystal.id() as usize] = failed;
+        self.evaluate();
+    }
+
+    pub(crate) fn failed(&self, crystal: Crystal) -> bool {
+        return self.failed[crystal.id() as usize];
+    }
+
+    /// A broken crystal that is turned on keeps its fault flag set, any fault flag keeps OFIFG set
+    fn evaluate(&mut self) {
+        for crystal in [Crystal::Lfxt, Crystal::Hfxt] {
+            let (off, flag) = crystal.bits();
+            if self.failed(crystal) && self.ctl[Self::index(CSCTL4)] & off == 0 {
+                self.ctl[Self::index(CSCTL5)] |= flag;
+            }
+        }
+        if self.ctl[Self::index(CSCTL5)] & CSCTL5_MASK != 0 {
+            self.ifg |= OFIFG;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn pending_nmi(&self) -> Option<u16> {
+        if self.ie & OFIE != 0 && self.ifg & OFIFG != 0 {
+            return Some(UNMI_VECTOR);
+        }
+        return None;
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "oscillator fault",
+            vector: UNMI_VECTOR,
+            nmi: true,
+            enabled: self.ie & OFIE != 0,
+            flagged: self.ifg & OFIFG != 0,
+        };
+    }
+
+    /// Whether a PUC was requested since the last call
+    pub(crate) fn take_puc(&mut self) -> bool {
+        return std::mem::replace(&mut self.puc_requested, false);
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let ctl4: u16 = self.ctl[Self::index(CSCTL4)];
+        let ctl5: u16 = self.ctl[Self::index(CSCTL5)];
+        let state = |crystal: Crystal| (if self.failed(crystal) {"failed"} else {"ok"}).to_string();
+        return vec![
+            RegisterView::word("SFRIE1", SFRIE1, self.ie).flag("OFIE", OFIE),
+            RegisterView::word("SFRIFG1", SFRIFG1, self.ifg).flag("OFIFG", OFIFG),
+            RegisterView::word("CSCTL0", CSCTL0, self.read_word(CSCTL0)).field("open", (self.open as u8).to_string()),
+            RegisterView::word("CSCTL4", CSCTL4, ctl4).flag("LFXTOFF", LFXTOFF).flag("HFXTOFF", HFXTOFF),
+            RegisterView::word("CSCTL5", CSCTL5, ctl5).flag("LFXTOFFG", LFXTOFFG).flag("HFXTOFFG", HFXTOFFG)
+                .field("LFXT", state(Crystal::Lfxt)).field("HFXT", state(Crystal::Hfxt)),
+        ];
+    }
+
+    pub(crate) fn read_word(&self, address: u16) -> u16 {
+        return match address {
+            SFRIE1 => self.ie,
+            SFRIFG1 => self.ifg,
+            CSCTL0 => CSKEY_READ,
+            _ if Self::claims(address) => self.ctl[Self::index(address)],
+            _ => 0,
+        };
+    }
+
+    /// A byte write other than 0xa5 to CSCTL0_H closes the registers without a PUC, the way
+    /// firmware usually locks them again
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        if address == CSCTL0 { // the high byte, memory is big-endian
+            self.open = value as u16 == CSKEY >> 8;
+            return;
+        }
+        if address == CSCTL0 + 1 {
+            return;
+        }
+        let word: u16 = self.read_word(address & 0xfffe);
+        let word: u16 = if address & 1 == 0 {
+            (word & 0x00ff) | ((value as u16) << 8)
+        } else {
+            (word & 0xff00) | value as u16
+        };
+        self.write_word(address & 0xfffe, word);
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            SFRIE1 => self.ie = value & OFIE,
+            SFRIFG1 => self.ifg = value & OFIFG,
+            CSCTL0 => {
+                self.open = value & 0xff00 == CSKEY;
+                self.puc_requested = !self.open;
+            },
+            CSCTL5 if self.open => self.ctl[Self::index(CSCTL5)] = value & CSCTL5_MASK,
+            _ if self.open => self.ctl[Self::index(address)] = value,
+            _ => {},
+        }
+        self.evaluate();
+    }
+}
+
+impl BusDevice for CsDevice {
+    fn claims(&self, address: u16) -> bool {
+        return CsDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, _bus: &mut BusAccess) {
+        if word {
+            self.write_word(address, value);
+        } else {
+            self.write_byte(address, value as u8);
+        }
+    }
+}
+
+impl SnapshotState for CsDevice {
+    const TAG: [u8; 4] = *b"CS  ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for register in self.ctl {
+            w.u16(register);
+        }
+        w.u16(self.ie);
+        w.u16(self.ifg);
+        w.bool(self.open);
+        w.bool(self.puc_requested);
+        w.bool(self.failed[0]);
+        w.bool(self.failed[1]);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for register in &mut self.ctl {
+            *register = r.u16();
+        }
+        self.ie = r.u16();
+        self.ifg = r.u16();
+        self.open = r.bool();
+        self.puc_requested = r.bool();
+        self.failed = [r.bool(), r.bool()];
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const FILE_NAME: u16 = 0x01e4;
+pub(crate) const FILE_CTL: u16 = 0x01e6;
+pub(crate) const FILE_DATA: u16 = 0x01e8;
+
+/// FILE_CTL commands
+pub(crate) const CMD_CLOSE: u16 = 0;
+pub(crate) const CMD_READ: u16 = 1;
+pub(crate) const CMD_WRITE: u16 = 2;
+pub(crate) const CMD_APPEND: u16 = 3;
+
+/// FILE_CTL status bits
+pub(crate) const STATUS_OPEN: u16 = 0x0001;
+pub(crate) const STATUS_ERROR: u16 = 0x0002;
+pub(crate) const STATUS_END: u16 = 0x0004;
+
+/// Longest name FILE_NAME collects, anything past it fails the next open
+const MAX_NAME: usize = 255;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    Read,
+    /// writing, truncated when opened or appending
+    Write,
+}
+
+/// Gives firmware files on the host under one directory (`run --file-dir`), so data loggers can be
+/// tested end-to-end and their output inspected. Writes reach the file right away, a file being
+/// read is taken in whole when opened.
+#[derive(Clone)]
+pub(crate) struct HostFileDevice {
+    /// the sandbox, without one every open fails
+    root: Option<PathBuf>,
+    name: Vec<u8>,
+    open: Option<(String, Mode)>,
+    /// contents of the file being read and how far firmware got
+    contents: Vec<u8>,
+    position: usize,
+    /// the last command or transfer failed
+    error: bool,
+}
+
+impl HostFileDevice {
+    pub(crate) fn new() -> HostFileDevice {
+        return HostFileDevice {
+            root: None,
+            name: Vec::new(),
+            open: None,
+            contents: Vec::new(),
+            position: 0,
+            error: false,
+        };
+    }
+
+    /// Closes the file, the directory stays
+    pub(crate) fn reset(&mut self) {
+        *self = HostFileDevice { root: self.root.take(), ..HostFileDevice::new() };
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (FILE_NAME..=FILE_DATA + 1).contains(&address);
+    }
+
+    pub(crate) fn set_root(&mut self, root: &str) {
+        self.root = Some(PathBuf::from(root));
+    }
+
+    /// The host path of `name`, which must stay inside the directory: relative, no `..`
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let root: &PathBuf = self.root.as_ref()?;
+        let path: &Path = Path::new(name);
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        return Some(root.join(path));
+    }
+
+    fn command(&mut self, command: u16) {
+        self.open = None;
+        self.contents.clear();
+        self.position = 0;
+        let name: Option<String> = String::from_utf8(std::mem::take(&mut self.name)).ok()
+            .filter(|name| name.len() <= MAX_NAME);
+        if command == CMD_CLOSE {
+            self.error = false;
+            return;
+        }
+        let path: Option<PathBuf> = name.as_deref().and_then(|name| self.resolve(name));
+        let opened: Option<Mode> = path.and_then(|path| match command {
+            CMD_READ => fs::read(path).ok().map(|contents| {
+                self.contents = contents;
+                Mode::Read
+            }),
+            CMD_WRITE => fs::write(path, []).ok().map(|_| Mode::Write),
+            CMD_APPEND => OpenOptions::new().create(true).append(true).open(path).ok().map(|_| Mode::Write),
+            _ => None,
+        });
+        self.error = opened.is_none();
+        self.open = opened.map(|mode| (name.expect("opened by name"), mode));
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        let written: bool = match &self.open {
+            Some((name, Mode::Write)) => self.resolve(name)
+                .and_then(|path| OpenOptions::new().append(true).open(path).ok())
+                .is_some_and(|mut file| file.write_all(&[byte]).is_ok()),
+            _ => false,
+        };
+        self.error |= !written;
+    }
+
+    /// 0xffff at the end and without a file being read. Not an error: writing FILE_DATA with a
+    /// two-operand instruction reads it first
+    fn read_data(&mut self) -> u16 {
+        if !matches!(self.open, Some((_, Mode::Read))) {
+            return 0xffff;
+        }
+        return match self.contents.get(self.position) {
+            Some(&byte) => {
+                self.position += 1;
+                byte as u16
+            },
+            None => 0xffff,
+        };
+    }
+
+    fn status(&self) -> u16 {
+        let end: bool = matches!(self.open, Some((_, Mode::Read))) && self.position >= self.contents.len();
+        return (if self.open.is_some() {STATUS_OPEN} else {0})
+            | (if self.error {STATUS_ERROR} else {0})
+            | if end {STATUS_END} else {0};
+    }
+
+    /// FILE_DATA is left out, reading it would take a byte
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let open: String = match &self.open {
+            Some((name, Mode::Read)) => format!("{} (read, {} of {} bytes)", name, self.position, self.contents.len()),
+            Some((name, Mode::Write)) => format!("{} (write)", name),
+            None => "none".to_string(),
+        };
+        return vec![
+            RegisterView::word("FILE_CTL", FILE_CTL, self.status())
+                .flag("OPEN", STATUS_OPEN).flag("ERROR", STATUS_ERROR).flag("END", STATUS_END)
+                .field("file", open)
+                .field("directory", self.root.as_ref().map(|root| root.display().to_string()).unwrap_or("none".to_string())),
+        ];
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            FILE_CTL => self.status(),
+            FILE_DATA => self.read_data(),
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            FILE_NAME if self.name.len() <= MAX_NAME => self.name.push((value & 0xff) as u8),
+            FILE_CTL => self.command(value),
+            FILE_DATA => self.write_data((value & 0xff) as u8),
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for HostFileDevice {
+    fn claims(&self, address: u16) -> bool {
+        return HostFileDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+/// The open file by name, a file being read continues from where it was (with its contents as
+/// they are when restoring)
+impl SnapshotState for HostFileDevice {
+    const TAG: [u8; 4] = *b"FILE";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.name);
+        let (name, mode) = match &self.open {
+            Some((name, Mode::Read)) => (name.as_str(), CMD_READ),
+            Some((name, Mode::Write)) => (name.as_str(), CMD_APPEND),
+            None => ("", CMD_CLOSE),
+        };
+        w.u16(mode);
+        w.bytes(name.as_bytes());
+        w.u32(self.position as u32);
+        w.bool(self.error);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        let pending: Vec<u8> = r.bytes();
+        let mode: u16 = r.u16();
+        self.name = r.bytes();
+        self.command(mode);
+        self.name = pending;
+        self.position = r.u32() as usize;
+        self.error = r.bool();
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+
+pub(crate) const TEST_ID: u16 = 0x01f0;
+pub(crate) const TEST_EXPECT: u16 = 0x01f2;
+pub(crate) const TEST_ASSERT_EQ: u16 = 0x01f4;
+pub(crate) const TEST_ASSERT: u16 = 0x01f6;
+pub(crate) const TEST_RESULT: u16 = 0x01f8;
+
+pub(crate) const RESULT_PASS: u16 = 0x0001;
+pub(crate) const RESULT_FAIL: u16 = 0x0002;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum AssertionKind {
+    /// value written to TEST_ASSERT_EQ did not match TEST_EXPECT
+    Equal,
+    /// zero written to TEST_ASSERT
+    True,
+    /// firmware wrote RESULT_FAIL to TEST_RESULT
+    Explicit,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct TestFailure {
+    /// address of the instruction that triggered the failure
+    pub(crate) pc: u16,
+    /// value of TEST_ID when the failure happened
+    pub(crate) id: u16,
+    pub(crate) kind: AssertionKind,
+    pub(crate) expected: u16,
+    pub(crate) actual: u16,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum TestStatus {
+    /// firmware hasn't reported a result yet
+    Running,
+    Passed,
+    Failed,
+}
+
+/// Lets firmware assert conditions and report pass/fail by writing to magic addresses:
+/// ```text
+/// mov #42 &TEST_EXPECT
+/// mov r5 &TEST_ASSERT_EQ ; ASSERT_EQ r5, #42
+/// mov #1 &TEST_RESULT    ; done, report pass (overridden by any recorded failure)
+/// ```
+#[derive(Clone)]
+pub(crate) struct FirmwareTestDevice {
+    id: u16,
+    expected: u16,
+    status: TestStatus,
+    failures: Vec<TestFailure>,
+}
+
+#[allow(dead_code)]
+impl FirmwareTestDevice {
+    pub(crate) fn new() -> FirmwareTestDevice {
+        return FirmwareTestDevice {
+            id: 0,
+            expected: 0,
+            status: TestStatus::Running,
+            failures: Vec::new(),
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.id = 0;
+        self.expected = 0;
+        self.status = TestStatus::Running;
+        self.failures.clear();
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (TEST_ID..=TEST_RESULT).contains(&address);
+    }
+
+    pub(crate) fn status(&self) -> TestStatus {
+        return self.status;
+    }
+
+    pub(crate) fn failures(&self) -> &[TestFailure] {
+        return &self.failures;
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            TEST_ID => self.id,
+            TEST_EXPECT => self.expected,
+            TEST_RESULT => self.failures.len().min(0xffff) as u16,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16, pc: u16) {
+        match address {
+            TEST_ID => self.id = value,
+            TEST_EXPECT => self.expected = value,
+            TEST_ASSERT_EQ if value != self.expected => self.fail(pc, AssertionKind::Equal, self.expected, value),
+            TEST_ASSERT if value == 0 => self.fail(pc, AssertionKind::True, 1, value),
+            TEST_RESULT => {
+                if value == RESULT_FAIL {
+                    self.fail(pc, AssertionKind::Explicit, RESULT_PASS, value);
+                } else if value == RESULT_PASS && self.status == TestStatus::Running {
+                    self.status = TestStatus::Passed;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn fail(&mut self, pc: u16, kind: AssertionKind, expected: u16, actual: u16) {
+        self.status = TestStatus::Failed;
+        self.failures.push(TestFailure { pc, id: self.id, kind, expected, actual });
+    }
+}
+
+impl BusDevice for FirmwareTestDevice {
+    fn claims(&self, address: u16) -> bool {
+        return FirmwareTestDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value, bus.pc);
+    }
+}
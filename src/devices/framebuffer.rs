@@ -0,0 +1,123 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::framebuffer::FrameBufferSpec;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const FB_BASE: u16 = 0x01d6;
+pub(crate) const FB_WIDTH: u16 = 0x01d8;
+pub(crate) const FB_HEIGHT: u16 = 0x01da;
+pub(crate) const FB_FORMAT: u16 = 0x01dc;
+pub(crate) const FB_FRAME: u16 = 0x01de;
+
+/// Describes the frame buffer given with `run --framebuffer` to firmware and counts the frames it
+/// presents. The pixels are plain memory at the spec's address, the device never touches them:
+/// frontends read them from the memory map, `run --framebuffer-images` renders them when presented.
+/// Without a frame buffer every register reads 0.
+#[derive(Clone)]
+pub(crate) struct FrameBufferDevice {
+    spec: Option<FrameBufferSpec>,
+    /// frames presented since reset
+    frames: u64,
+}
+
+impl FrameBufferDevice {
+    pub(crate) fn new() -> FrameBufferDevice {
+        return FrameBufferDevice { spec: None, frames: 0 };
+    }
+
+    /// The frame counter starts over, the frame buffer stays
+    pub(crate) fn reset(&mut self) {
+        self.frames = 0;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (FB_BASE..=FB_FRAME + 1).contains(&address);
+    }
+
+    pub(crate) fn set_spec(&mut self, spec: FrameBufferSpec) {
+        self.spec = Some(spec);
+    }
+
+    pub(crate) fn spec(&self) -> Option<&FrameBufferSpec> {
+        return self.spec.as_ref();
+    }
+
+    pub(crate) fn frames(&self) -> u64 {
+        return self.frames;
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let spec: String = self.spec.map_or("none".to_string(), |spec| spec.to_string());
+        return vec![
+            RegisterView::word("FB_FRAME", FB_FRAME, self.frames as u16)
+                .field("frame buffer", spec)
+                .field("frames", self.frames.to_string()),
+        ];
+    }
+
+    pub(crate) fn read_word(&self, address: u16) -> u16 {
+        let Some(spec) = &self.spec else {
+            return 0;
+        };
+        return match address {
+            FB_BASE => spec.base,
+            FB_WIDTH => spec.width,
+            FB_HEIGHT => spec.height,
+            FB_FORMAT => spec.format.id() as u16,
+            FB_FRAME => self.frames as u16,
+            _ => 0,
+        };
+    }
+
+    /// Any write to FB_FRAME presents a frame, the other registers are read only
+    pub(crate) fn write_word(&mut self, address: u16, _value: u16) {
+        if address == FB_FRAME && self.spec.is_some() {
+            self.frames += 1;
+        }
+    }
+}
+
+impl BusDevice for FrameBufferDevice {
+    fn claims(&self, address: u16) -> bool {
+        return FrameBufferDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+/// The frame buffer itself is configuration, it stays as `run --framebuffer` set it
+impl SnapshotState for FrameBufferDevice {
+    const TAG: [u8; 4] = *b"FBUF";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u64(self.frames);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.frames = r.u64();
+    }
+}
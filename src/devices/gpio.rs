@@ -0,0 +1,299 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::{pins, RegisterView};
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const P1_BASE: u16 = 0x0020;
+pub(crate) const P2_BASE: u16 = 0x0028;
+pub(crate) const PORT_COUNT: usize = 2;
+
+pub(crate) const PORT1_VECTOR: u16 = 0xffe4;
+pub(crate) const PORT2_VECTOR: u16 = 0xffe6;
+
+// register offsets from the port base, same layout as the 2xx P1/P2
+pub(crate) const PXIN: u16 = 0;
+pub(crate) const PXOUT: u16 = 1;
+pub(crate) const PXDIR: u16 = 2;
+pub(crate) const PXIFG: u16 = 3;
+pub(crate) const PXIES: u16 = 4;
+pub(crate) const PXIE: u16 = 5;
+pub(crate) const PXSEL: u16 = 6;
+pub(crate) const PXREN: u16 = 7;
+
+/// A single pin, e.g. `P1.3` (ports are numbered from 1 like the datasheet)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(crate) struct PinId {
+    pub(crate) port: u8,
+    pub(crate) pin: u8,
+}
+
+impl FromStr for PinId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec: String = s.trim().to_ascii_uppercase();
+        let (port, pin) = spec.strip_prefix('P').and_then(|rest| rest.split_once('.'))
+            .ok_or(format!("'{}' is not a pin (expected e.g. P1.3)", s))?;
+        let port: u8 = port.parse().map_err(|_| format!("Invalid port in '{}'", s))?;
+        let pin: u8 = pin.parse().map_err(|_| format!("Invalid pin in '{}'", s))?;
+        if port == 0 || port as usize > PORT_COUNT || pin > 7 {
+            return Err(format!("'{}' does not exist (P1.0 - P{}.7)", s, PORT_COUNT));
+        }
+        return Ok(PinId { port, pin });
+    }
+}
+
+impl fmt::Display for PinId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "P{}.{}", self.port, self.pin);
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Port {
+    out: u8,
+    dir: u8,
+    ifg: u8,
+    ies: u8,
+    ie: u8,
+    sel: u8,
+    ren: u8,
+    /// level applied to each pin from outside (wires, stimulus)
+    external: u8,
+    /// which pins are driven from outside at all, undriven inputs fall back to the pull resistor
+    driven: u8,
+}
+
+impl Port {
+    /// Level currently on each pin
+    fn level(&self) -> u8 {
+        let outputs: u8 = self.out & self.dir;
+        let driven_inputs: u8 = self.external & self.driven & !self.dir;
+        // with REN set, OUT selects pull-up (1) or pull-down (0); floating pins read 0
+        let pulled_inputs: u8 = self.ren & self.out & !self.driven & !self.dir;
+        return outputs | driven_inputs | pulled_inputs;
+    }
+
+    /// Apply a change and latch interrupt flags for input pins that saw the selected edge
+    fn update<F: FnOnce(&mut Port)>(&mut self, change: F) {
+        let before: u8 = self.level();
+        change(self);
+        let after: u8 = self.level();
+        let inputs: u8 = !self.dir;
+        let rising: u8 = !before & after & inputs;
+        let falling: u8 = before & !after & inputs;
+        self.ifg |= (rising & !self.ies) | (falling & self.ies);
+    }
+}
+
+/// Digital I/O ports P1 and P2
+#[derive(Clone)]
+pub(crate) struct GpioDevice {
+    ports: [Port; PORT_COUNT],
+    /// set whenever firmware changes what it drives onto a pin, so links know when to propagate
+    outputs_changed: bool,
+}
+
+#[allow(dead_code)]
+impl GpioDevice {
+    pub(crate) fn new() -> GpioDevice {
+        return GpioDevice {
+            ports: [Port::default(); PORT_COUNT],
+            outputs_changed: false,
+        };
+    }
+
+    /// Registers reset, levels applied from outside are kept (the wires are still attached)
+    pub(crate) fn reset(&mut self) {
+        for port in self.ports.iter_mut() {
+            *port = Port { external: port.external, driven: port.driven, ..Port::default() };
+        }
+        self.outputs_changed = true;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (P1_BASE..P2_BASE + 8).contains(&address);
+    }
+
+    fn locate(address: u16) -> (usize, u16) {
+        return (((address - P1_BASE) / 8) as usize, (address - P1_BASE) % 8);
+    }
+
+    pub(crate) fn interrupt_sources(&self) -> [InterruptSource; 2] {
+        let source = |name: &'static str, vector: u16, port: &Port| InterruptSource {
+            name,
+            vector,
+            nmi: false,
+            enabled: port.ie != 0,
+            flagged: port.ifg != 0,
+        };
+        return [source("port 1", PORT1_VECTOR, &self.ports[0]), source("port 2", PORT2_VECTOR, &self.ports[1])];
+    }
+
+    /// Registers of P1 and P2 with the pins each bit stands for
+    pub(crate) fn registers(&self) -> [Vec<RegisterView>; PORT_COUNT] {
+        return std::array::from_fn(|index| {
+            let port: &Port = &self.ports[index];
+            let base: u16 = P1_BASE + 8 * index as u16;
+            let number: usize = index + 1;
+            let register = |name: &str, offset: u16, value: u8, field: &'static str| {
+                return RegisterView::byte(&format!("P{}{}", number, name), base + offset, value)
+                    .field(field, pins(number, value));
+            };
+            return vec![
+                register("IN", PXIN, port.level(), "high"),
+                register("OUT", PXOUT, port.out, "high"),
+                register("DIR", PXDIR, port.dir, "output"),
+                register("IFG", PXIFG, port.ifg, "flagged"),
+                register("IES", PXIES, port.ies, "falling"),
+                register("IE", PXIE, port.ie, "enabled"),
+                register("SEL", PXSEL, port.sel, "selected"),
+                register("REN", PXREN, port.ren, "pull"),
+            ];
+        });
+    }
+
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        if self.ports[0].ifg & self.ports[0].ie != 0 {
+            return Some(PORT1_VECTOR);
+        }
+        if self.ports[1].ifg & self.ports[1].ie != 0 {
+            return Some(PORT2_VECTOR);
+        }
+        return None;
+    }
+
+    /// Returns true (and clears the flag) if firmware changed any output since the last call
+    pub(crate) fn take_outputs_changed(&mut self) -> bool {
+        let changed: bool = self.outputs_changed;
+        self.outputs_changed = false;
+        return changed;
+    }
+
+    /// Drive a pin from outside, `None` releases it (it then floats or follows its pull resistor)
+    pub(crate) fn set_input(&mut self, pin: PinId, level: Option<bool>) {
+        let mask: u8 = 1 << pin.pin;
+        self.ports[pin.port as usize - 1].update(|p| {
+            match level {
+                Some(high) => {
+                    p.driven |= mask;
+                    p.external = if high {p.external | mask} else {p.external & !mask};
+                },
+                None => p.driven &= !mask,
+            }
+        });
+    }
+
+    /// What firmware drives onto a pin, `None` if the pin isn't an output
+    pub(crate) fn output(&self, pin: PinId) -> Option<bool> {
+        let port: &Port = &self.ports[pin.port as usize - 1];
+        let mask: u8 = 1 << pin.pin;
+        if port.dir & mask == 0 {
+            return None;
+        }
+        return Some(port.out & mask != 0);
+    }
+
+    /// PxSEL is set for a pin
+    pub(crate) fn selected(&self, pin: PinId) -> bool {
+        return self.ports[pin.port as usize - 1].sel & (1 << pin.pin) != 0;
+    }
+
+    /// Level on a pin, whoever drives it
+    pub(crate) fn level(&self, pin: PinId) -> bool {
+        return self.ports[pin.port as usize - 1].level() & (1 << pin.pin) != 0;
+    }
+
+    pub(crate) fn read_byte(&mut self, address: u16) -> u8 {
+        let (index, offset) = Self::locate(address);
+        let port: &Port = &self.ports[index];
+        return match offset {
+            PXIN => port.level(),
+            PXOUT => port.out,
+            PXDIR => port.dir,
+            PXIFG => port.ifg,
+            PXIES => port.ies,
+            PXIE => port.ie,
+            PXSEL => port.sel,
+            PXREN => port.ren,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        let (index, offset) = Self::locate(address);
+        let port: &mut Port = &mut self.ports[index];
+        match offset {
+            PXOUT => port.update(|p| p.out = value),
+            PXDIR => port.update(|p| p.dir = value),
+            PXREN => port.update(|p| p.ren = value),
+            PXIFG => port.ifg = value,
+            // changing the edge select can set the flag on real hardware, we don't model that
+            PXIES => port.ies = value,
+            PXIE => port.ie = value,
+            PXSEL => port.sel = value,
+            _ => {}, // PxIN is read only
+        }
+        if offset == PXOUT || offset == PXDIR {
+            self.outputs_changed = true;
+        }
+    }
+}
+
+impl BusDevice for GpioDevice {
+    fn claims(&self, address: u16) -> bool {
+        return GpioDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::read_bytes(address, word, |address| self.read_byte(address));
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, _bus: &mut BusAccess) {
+        bus::write_bytes(address, value, word, |address, value| self.write_byte(address, value));
+    }
+}
+
+impl SnapshotState for GpioDevice {
+    const TAG: [u8; 4] = *b"GPIO";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for port in &self.ports {
+            for register in [port.out, port.dir, port.ifg, port.ies, port.ie, port.sel, port.ren, port.external, port.driven] {
+                w.u8(register);
+            }
+        }
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for port in &mut self.ports {
+            for register in [&mut port.out, &mut port.dir, &mut port.ifg, &mut port.ies, &mut port.ie, &mut port.sel,
+                             &mut port.ren, &mut port.external, &mut port.driven] {
+                *register = r.u8();
+            }
+        }
+        self.outputs_changed = true;
+    }
+}
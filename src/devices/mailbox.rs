@@ -0,0 +1,161 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const SYSJMBC: u16 = 0x0186;
+pub(crate) const SYSJMBI0: u16 = 0x0188;
+pub(crate) const SYSJMBI1: u16 = 0x018a;
+pub(crate) const SYSJMBO0: u16 = 0x018c;
+pub(crate) const SYSJMBO1: u16 = 0x018e;
+
+// SYSJMBC
+pub(crate) const JMBIN0FG: u16 = 0x0001;
+pub(crate) const JMBIN1FG: u16 = 0x0002;
+pub(crate) const JMBOUT0FG: u16 = 0x0004;
+pub(crate) const JMBOUT1FG: u16 = 0x0008;
+
+pub(crate) const CHANNELS: usize = 2;
+
+/// JTAG mailbox of the FR5xx SYS module, 16-bit mode. Each channel has an input word the host
+/// writes and firmware reads, and an output word going the other way. JMBINxFG is set while an
+/// input word is unread, JMBOUTxFG while the output register is free for firmware to write.
+#[derive(Clone)]
+pub(crate) struct MailboxDevice {
+    input: [u16; CHANNELS],
+    input_full: [bool; CHANNELS],
+    output: [u16; CHANNELS],
+    output_full: [bool; CHANNELS],
+}
+
+impl MailboxDevice {
+    pub(crate) fn new() -> MailboxDevice {
+        return MailboxDevice {
+            input: [0; CHANNELS],
+            input_full: [false; CHANNELS],
+            output: [0; CHANNELS],
+            output_full: [false; CHANNELS],
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = MailboxDevice::new();
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (SYSJMBC..=SYSJMBO1 + 1).contains(&address);
+    }
+
+    /// Host side: hand firmware a word, replacing an unread one
+    pub(crate) fn host_write(&mut self, channel: usize, value: u16) {
+        self.input[channel] = value;
+        self.input_full[channel] = true;
+    }
+
+    /// Host side: take the word firmware wrote, `None` if it hasn't written one since the last read
+    pub(crate) fn host_read(&mut self, channel: usize) -> Option<u16> {
+        if !self.output_full[channel] {
+            return None;
+        }
+        self.output_full[channel] = false;
+        return Some(self.output[channel]);
+    }
+
+    fn control(&self) -> u16 {
+        return if self.input_full[0] {JMBIN0FG} else {0}
+            | if self.input_full[1] {JMBIN1FG} else {0}
+            | if self.output_full[0] {0} else {JMBOUT0FG}
+            | if self.output_full[1] {0} else {JMBOUT1FG};
+    }
+
+    /// The input words are shown without acknowledging them
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        return vec![
+            RegisterView::word("SYSJMBC", SYSJMBC, self.control())
+                .flag("JMBIN0FG", JMBIN0FG).flag("JMBIN1FG", JMBIN1FG)
+                .flag("JMBOUT0FG", JMBOUT0FG).flag("JMBOUT1FG", JMBOUT1FG),
+            RegisterView::word("SYSJMBI0", SYSJMBI0, self.input[0]),
+            RegisterView::word("SYSJMBI1", SYSJMBI1, self.input[1]),
+            RegisterView::word("SYSJMBO0", SYSJMBO0, self.output[0]),
+            RegisterView::word("SYSJMBO1", SYSJMBO1, self.output[1]),
+        ];
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            SYSJMBC => self.control(),
+            SYSJMBI0 | SYSJMBI1 => {
+                let channel: usize = ((address - SYSJMBI0) / 2) as usize;
+                self.input_full[channel] = false; // reading acknowledges the word
+                self.input[channel]
+            },
+            SYSJMBO0 | SYSJMBO1 => self.output[((address - SYSJMBO0) / 2) as usize],
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            SYSJMBO0 | SYSJMBO1 => {
+                let channel: usize = ((address - SYSJMBO0) / 2) as usize;
+                self.output[channel] = value;
+                self.output_full[channel] = true;
+            },
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for MailboxDevice {
+    fn claims(&self, address: u16) -> bool {
+        return MailboxDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+impl SnapshotState for MailboxDevice {
+    const TAG: [u8; 4] = *b"MBOX";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for channel in 0..CHANNELS {
+            w.u16(self.input[channel]);
+            w.bool(self.input_full[channel]);
+            w.u16(self.output[channel]);
+            w.bool(self.output_full[channel]);
+        }
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for channel in 0..CHANNELS {
+            self.input[channel] = r.u16();
+            self.input_full[channel] = r.bool();
+            self.output[channel] = r.u16();
+            self.output_full[channel] = r.bool();
+        }
+    }
+}
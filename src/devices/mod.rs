@@ -0,0 +1,334 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Emulator-defined memory mapped devices (see emulator_devices.txt for the register map)
+
+pub(crate) mod comparator;
+pub(crate) mod console;
+pub(crate) mod cs;
+pub(crate) mod files;
+pub(crate) mod firmware_test;
+pub(crate) mod framebuffer;
+pub(crate) mod gpio;
+pub(crate) mod mailbox;
+pub(crate) mod mpu;
+pub(crate) mod pmap;
+pub(crate) mod pmm;
+pub(crate) mod radio;
+pub(crate) mod rng;
+pub(crate) mod rtc;
+pub(crate) mod sd_card;
+pub(crate) mod spi;
+pub(crate) mod touch;
+pub(crate) mod uart;
+pub(crate) mod wdt;
+
+use crate::bus::{BusAccess, BusDevice, BusMap};
+use crate::clock::Clock;
+use crate::interrupts::InterruptSource;
+use comparator::ComparatorDevice;
+use console::ConsoleDevice;
+use cs::CsDevice;
+use files::HostFileDevice;
+use firmware_test::FirmwareTestDevice;
+use framebuffer::FrameBufferDevice;
+use gpio::GpioDevice;
+use mailbox::MailboxDevice;
+use mpu::MpuDevice;
+use pmap::PmapDevice;
+use pmm::PmmDevice;
+use rng::RngDevice;
+use rtc::RtcDevice;
+use spi::SpiDevice;
+use touch::TouchDevice;
+use uart::UartDevice;
+use wdt::WdtDevice;
+
+/// Every device the emulator exposes to firmware, each registered on the bus map as a `BusDevice`.
+/// Addresses not claimed by a device fall through to plain memory.
+#[derive(Clone)]
+pub(crate) struct Devices {
+    pub(crate) firmware_test: FirmwareTestDevice,
+    pub(crate) rng: RngDevice,
+    pub(crate) rtc: RtcDevice,
+    pub(crate) uart: UartDevice,
+    pub(crate) gpio: GpioDevice,
+    pub(crate) mpu: MpuDevice,
+    pub(crate) mailbox: MailboxDevice,
+    pub(crate) pmm: PmmDevice,
+    pub(crate) console: ConsoleDevice,
+    pub(crate) pmap: PmapDevice,
+    pub(crate) cs: CsDevice,
+    pub(crate) touch: TouchDevice,
+    pub(crate) comparator: ComparatorDevice,
+    pub(crate) files: HostFileDevice,
+    pub(crate) framebuffer: FrameBufferDevice,
+    pub(crate) spi: SpiDevice,
+    pub(crate) wdt: WdtDevice,
+    /// default vector and the profile's, for interrupts the part has elsewhere (configuration, kept
+    /// by resets)
+    pub(crate) vectors: Vec<(u16, u16)>,
+}
+
+impl Devices {
+    pub(crate) fn new() -> Devices {
+        return Devices {
+            firmware_test: FirmwareTestDevice::new(),
+            rng: RngDevice::new(0),
+            rtc: RtcDevice::new(),
+            uart: UartDevice::new(),
+            gpio: GpioDevice::new(),
+            mpu: MpuDevice::new(),
+            mailbox: MailboxDevice::new(),
+            pmm: PmmDevice::new(),
+            console: ConsoleDevice::new(),
+            pmap: PmapDevice::new(),
+            cs: CsDevice::new(),
+            touch: TouchDevice::new(),
+            comparator: ComparatorDevice::new(pmm::DEFAULT_SUPPLY_MV),
+            files: HostFileDevice::new(),
+            framebuffer: FrameBufferDevice::new(),
+            spi: SpiDevice::new(),
+            wdt: WdtDevice::new(),
+            vectors: Vec::new(),
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.firmware_test.reset();
+        self.rng.reset();
+        self.rtc.reset();
+        self.uart.reset();
+        self.gpio.reset();
+        self.mpu.reset();
+        self.mailbox.reset();
+        self.pmm.reset();
+        self.console.reset();
+        self.pmap.reset();
+        self.cs.reset();
+        self.touch.reset();
+        self.comparator.reset();
+        self.files.reset();
+        self.framebuffer.reset();
+        self.spi.reset();
+        self.wdt.reset();
+    }
+
+    /// Reset by the supply supervisor, everything but the supply itself starts over
+    pub(crate) fn brownout(&mut self) {
+        let mut pmm: PmmDevice = self.pmm.clone();
+        self.reset();
+        pmm.brownout();
+        self.pmm = pmm;
+    }
+
+    /// Power came back after being off, everything starts over except the RTC, which runs from
+    /// the backup supply
+    pub(crate) fn power_cycle(&mut self) {
+        let rtc: RtcDevice = self.rtc.clone();
+        self.reset();
+        self.rtc = rtc;
+    }
+
+    /// Vector of the first device currently requesting an interrupt
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        return self.gpio.pending_interrupt()
+            .or(self.uart.pending_interrupt())
+            .or(self.console.pending_interrupt())
+            .or(self.comparator.pending_interrupt())
+            .or(self.wdt.pending_interrupt())
+            .map(|vector| self.vector(vector));
+    }
+
+    /// The CPU took the interrupt at `vector`, single source flags clear themselves
+    #[inline]
+    pub(crate) fn accepted(&mut self, vector: u16) {
+        if vector == self.vector(wdt::WDT_VECTOR) {
+            self.wdt.accepted();
+        }
+    }
+
+    /// Vector of a pending non-maskable interrupt, these are taken even without GIE
+    #[inline]
+    pub(crate) fn pending_nmi(&self) -> Option<u16> {
+        return self.mpu.pending_nmi()
+            .or(self.pmm.pending_nmi())
+            .or(self.cs.pending_nmi())
+            .map(|vector| self.vector(vector));
+    }
+
+    /// Where the part has the interrupt the devices raise at `default`
+    #[inline]
+    fn vector(&self, default: u16) -> u16 {
+        return self.vectors.iter().find(|(from, _)| *from == default).map_or(default, |(_, to)| *to);
+    }
+
+    /// Returns true (and clears the requests) if a device asked for a PUC
+    pub(crate) fn take_puc(&mut self) -> bool {
+        return self.mpu.take_puc() | self.cs.take_puc() | self.wdt.take_puc();
+    }
+
+    /// Every interrupt request line, in the order `pending_interrupt` checks them (NMIs last)
+    pub(crate) fn interrupt_sources(&self) -> Vec<InterruptSource> {
+        let mut sources: Vec<InterruptSource> = self.gpio.interrupt_sources().to_vec();
+        sources.push(self.uart.interrupt_source());
+        sources.push(self.console.interrupt_source());
+        sources.push(self.comparator.interrupt_source());
+        sources.push(self.wdt.interrupt_source());
+        sources.push(self.mpu.interrupt_source());
+        sources.push(self.pmm.interrupt_source());
+        sources.push(self.cs.interrupt_source());
+        sources.iter_mut().for_each(|source| source.vector = self.vector(source.vector));
+        return sources;
+    }
+
+    /// A pin leaving or entering its peripheral function starts or stops its pin oscillator, SPI
+    /// devices follow their chip select and control pins
+    fn gpio_written(&mut self, address: u16, clock: &Clock) {
+        if (address - gpio::P1_BASE) % 8 == gpio::PXSEL {
+            self.touch.update(&self.gpio, clock);
+        }
+        self.spi.update_pins(&mut self.gpio);
+    }
+
+    /// A packet from the air reaches the radio, if there is one
+    pub(crate) fn radio_receive(&mut self, packet: &radio::Packet) {
+        if let Some(radio) = &mut self.spi.radio {
+            radio.receive(packet, &mut self.gpio);
+        }
+    }
+
+    /// The device registered as `id`
+    pub(crate) fn device(&self, id: DeviceId) -> &dyn BusDevice {
+        return match id {
+            DeviceId::Gpio => &self.gpio,
+            DeviceId::FirmwareTest => &self.firmware_test,
+            DeviceId::Rng => &self.rng,
+            DeviceId::Rtc => &self.rtc,
+            DeviceId::Uart => &self.uart,
+            DeviceId::Mpu => &self.mpu,
+            DeviceId::Mailbox => &self.mailbox,
+            DeviceId::Pmm => &self.pmm,
+            DeviceId::Console => &self.console,
+            DeviceId::Pmap => &self.pmap,
+            DeviceId::Cs => &self.cs,
+            DeviceId::Touch => &self.touch,
+            DeviceId::Comparator => &self.comparator,
+            DeviceId::Files => &self.files,
+            DeviceId::FrameBuffer => &self.framebuffer,
+            DeviceId::Spi => &self.spi,
+            DeviceId::Wdt => &self.wdt,
+        };
+    }
+
+    /// The device registered as `id` and, unless it is the ports themselves, the ports
+    fn device_mut(&mut self, id: DeviceId) -> (&mut dyn BusDevice, Option<&mut GpioDevice>) {
+        let Devices {firmware_test, rng, rtc, uart, gpio, mpu, mailbox, pmm, console, pmap, cs, touch,
+            comparator, files, framebuffer, spi, wdt, ..} = self;
+        let device: &mut dyn BusDevice = match id {
+            DeviceId::Gpio => return (gpio, None),
+            DeviceId::FirmwareTest => firmware_test,
+            DeviceId::Rng => rng,
+            DeviceId::Rtc => rtc,
+            DeviceId::Uart => uart,
+            DeviceId::Mpu => mpu,
+            DeviceId::Mailbox => mailbox,
+            DeviceId::Pmm => pmm,
+            DeviceId::Console => console,
+            DeviceId::Pmap => pmap,
+            DeviceId::Cs => cs,
+            DeviceId::Touch => touch,
+            DeviceId::Comparator => comparator,
+            DeviceId::Files => files,
+            DeviceId::FrameBuffer => framebuffer,
+            DeviceId::Spi => spi,
+            DeviceId::Wdt => wdt,
+        };
+        return (device, Some(gpio));
+    }
+
+    /// Read the word (or byte) at `address` from the device `map` routes it to, `None` if it is no
+    /// device's. A word holding registers of two devices is read a byte from each.
+    /// `pc` is the address of the instruction performing the read.
+    pub(crate) fn read(&mut self, map: &BusMap, address: u16, word: bool, pc: u16, clock: &Clock) -> Option<u16> {
+        let address: u16 = if word {address & 0xfffe} else {address};
+        let id: DeviceId = map.device(address)?;
+        if word && map.device(address + 1) != Some(id) {
+            let high: u16 = self.read(map, address, false, pc, clock)?;
+            return Some((high << 8) | self.read(map, address + 1, false, pc, clock).unwrap_or(0));
+        }
+        let (device, gpio) = self.device_mut(id);
+        return Some(device.read(address, word, &mut BusAccess { clock, pc, gpio }));
+    }
+
+    /// Write the word (or byte) at `address` to the device `map` routes it to, false if it is no
+    /// device's. A word holding registers of two devices is written a byte to each.
+    /// `pc` is the address of the instruction performing the write.
+    pub(crate) fn write(&mut self, map: &BusMap, address: u16, value: u16, word: bool, pc: u16, clock: &Clock) -> bool {
+        let address: u16 = if word {address & 0xfffe} else {address};
+        let Some(id) = map.device(address) else {
+            return false;
+        };
+        if word && map.device(address + 1) != Some(id) {
+            self.write(map, address, value >> 8, false, pc, clock);
+            self.write(map, address + 1, value & 0xff, false, pc, clock);
+            return true;
+        }
+        let (device, gpio) = self.device_mut(id);
+        device.write(address, value, word, &mut BusAccess { clock, pc, gpio });
+        if id == DeviceId::Gpio {
+            self.gpio_written(address, clock);
+            if word {
+                self.gpio_written(address + 1, clock);
+            }
+        }
+        return true;
+    }
+}
+
+/// Names a device on the data bus (`BusMap` records which device answers each address)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum DeviceId {
+    Gpio,
+    FirmwareTest,
+    Rng,
+    Rtc,
+    Uart,
+    Mpu,
+    Mailbox,
+    Pmm,
+    Console,
+    Pmap,
+    Cs,
+    Touch,
+    Comparator,
+    Files,
+    FrameBuffer,
+    Spi,
+    Wdt,
+}
+
+impl DeviceId {
+    /// Every device, in the order they are registered on the bus
+    pub(crate) const ALL: [DeviceId; 17] = [
+        DeviceId::Gpio, DeviceId::FirmwareTest, DeviceId::Rng, DeviceId::Rtc, DeviceId::Uart, DeviceId::Mpu,
+        DeviceId::Mailbox, DeviceId::Pmm, DeviceId::Console, DeviceId::Pmap, DeviceId::Cs, DeviceId::Touch,
+        DeviceId::Comparator, DeviceId::Files, DeviceId::FrameBuffer, DeviceId::Spi, DeviceId::Wdt,
+    ];
+}
@@ -0,0 +1,366 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const MPUCTL0: u16 = 0x05a0;
+/// memory is big-endian, so the high byte comes first
+pub(crate) const MPUCTL0_H: u16 = 0x05a0;
+const MPUCTL0_L: u16 = 0x05a1;
+pub(crate) const MPUCTL1: u16 = 0x05a2;
+pub(crate) const MPUSEGB2: u16 = 0x05a4;
+pub(crate) const MPUSEGB1: u16 = 0x05a6;
+pub(crate) const MPUSAM: u16 = 0x05a8;
+pub(crate) const MPUIPC0: u16 = 0x05aa;
+pub(crate) const MPUIPSEGB2: u16 = 0x05ac;
+pub(crate) const MPUIPSEGB1: u16 = 0x05ae;
+
+/// high byte of MPUCTL0 writes, anything else is a password violation
+pub(crate) const MPUPW: u16 = 0xa500;
+/// high byte of MPUCTL0 reads
+const MPUPW_READ: u16 = 0x9600;
+
+// MPUCTL0
+pub(crate) const MPUENA: u16 = 0x0001;
+pub(crate) const MPULOCK: u16 = 0x0002;
+pub(crate) const MPUSEGIE: u16 = 0x0010;
+const CTL0_MASK: u16 = MPUENA | MPULOCK | MPUSEGIE;
+
+// MPUCTL1, one violation flag per segment
+pub(crate) const MPUSEG1IFG: u16 = 0x0001;
+pub(crate) const MPUSEG2IFG: u16 = 0x0002;
+pub(crate) const MPUSEG3IFG: u16 = 0x0004;
+pub(crate) const MPUSEGIIFG: u16 = 0x0008;
+pub(crate) const MPUSEGIPIFG: u16 = 0x0010;
+const CTL1_MASK: u16 = MPUSEG1IFG | MPUSEG2IFG | MPUSEG3IFG | MPUSEGIIFG | MPUSEGIPIFG;
+
+// MPUIPC0
+/// IPE violations cause a PUC instead of only setting the flag
+pub(crate) const MPUIPVS: u16 = 0x0020;
+pub(crate) const MPUIPENA: u16 = 0x0040;
+pub(crate) const MPUIPLOCK: u16 = 0x0080;
+const IPC0_MASK: u16 = MPUIPVS | MPUIPENA | MPUIPLOCK;
+
+// MPUSAM has a nibble per segment (1, 2, 3, info memory) of these
+pub(crate) const SAM_READ: u16 = 0x1;
+pub(crate) const SAM_WRITE: u16 = 0x2;
+pub(crate) const SAM_EXECUTE: u16 = 0x4;
+/// violations in the segment cause a PUC instead of only setting the flag
+pub(crate) const SAM_VIOLATION_PUC: u16 = 0x8;
+const SAM_RESET: u16 = 0x7777;
+
+/// SYSNMI on the FR5xx parts
+pub(crate) const SYSNMI_VECTOR: u16 = 0xfffc;
+
+/// Main FRAM of the FR5969, split into segments 1-3 by the borders
+const MAIN_START: u32 = 0x4400;
+const MAIN_END: u32 = 0x13fff;
+const INFO_START: u32 = 0x1800;
+const INFO_END: u32 = 0x19ff;
+
+/// What a blocked read returns, and what a blocked fetch executes (`jmp $`)
+pub(crate) const VIOLATION_READ: u16 = 0x3fff;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// FRAM memory protection unit (FR58xx/FR59xx). Guards main memory in three segments split at
+/// MPUSEGB1/MPUSEGB2 plus the info memory, each with its own read/write/execute permissions.
+/// The IP encapsulation region (MPUIPSEGB1 up to MPUIPSEGB2) can only be read or written by code
+/// running inside it, code anywhere may still call into it.
+/// Registers are password protected: a word write to MPUCTL0 must carry MPUPW in the high byte,
+/// which also opens the other registers for writing until a byte other than 0xa5 is written to MPUCTL0_H.
+#[derive(Clone)]
+pub(crate) struct MpuDevice {
+    ctl0: u16,
+    ctl1: u16,
+    segb1: u16,
+    segb2: u16,
+    sam: u16,
+    ipc0: u16,
+    ipsegb1: u16,
+    ipsegb2: u16,
+    /// the password was written and access hasn't been closed again
+    open: bool,
+    /// a password or PUC-selected segment violation happened, the computer restarts after this instruction
+    puc_requested: bool,
+}
+
+impl MpuDevice {
+    pub(crate) fn new() -> MpuDevice {
+        return MpuDevice {
+            ctl0: 0,
+            ctl1: 0,
+            segb1: 0,
+            segb2: 0,
+            sam: SAM_RESET,
+            ipc0: 0,
+            ipsegb1: 0,
+            ipsegb2: 0,
+            open: false,
+            puc_requested: false,
+        };
+    }
+
+    /// Brownout reset, the only thing that clears MPULOCK
+    pub(crate) fn reset(&mut self) {
+        *self = MpuDevice::new();
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (MPUCTL0..=MPUIPSEGB1 + 1).contains(&address);
+    }
+
+    #[inline]
+    pub(crate) fn enabled(&self) -> bool {
+        return self.ctl0 & MPUENA != 0;
+    }
+
+    #[inline]
+    fn locked(&self) -> bool {
+        return self.ctl0 & MPULOCK != 0;
+    }
+
+    #[inline]
+    fn ip_locked(&self) -> bool {
+        return self.ipc0 & MPUIPLOCK != 0;
+    }
+
+    /// Inside the IP encapsulation region, borders hold bits 19:4 like the segment borders
+    #[inline]
+    fn in_ip(&self, address: u16) -> bool {
+        let address: u32 = address as u32;
+        return (self.ipsegb1 as u32) << 4 <= address && address < (self.ipsegb2 as u32) << 4;
+    }
+
+    /// Record a violation, requesting a PUC if `puc` selects that
+    fn violation(&mut self, flag: u16, puc: bool) {
+        self.ctl1 |= flag;
+        if puc {
+            self.puc_requested = true;
+        }
+    }
+
+    /// MPUSAM nibble shift and MPUCTL1 flag of the segment `address` is in, `None` if unprotected
+    fn segment(&self, address: u32) -> Option<(u16, u16)> {
+        if (INFO_START..=INFO_END).contains(&address) {
+            return Some((12, MPUSEGIIFG));
+        }
+        if !(MAIN_START..=MAIN_END).contains(&address) {
+            return None;
+        }
+        // borders hold bits 19:4 of the address
+        if address < (self.segb1 as u32) << 4 {
+            return Some((0, MPUSEG1IFG));
+        }
+        if address < (self.segb2 as u32) << 4 {
+            return Some((4, MPUSEG2IFG));
+        }
+        return Some((8, MPUSEG3IFG));
+    }
+
+    /// Whether the access made by the instruction at `pc` may go ahead, a denied access sets
+    /// the segment's violation flag
+    #[inline]
+    pub(crate) fn allows(&mut self, address: u16, access: Access, pc: u16) -> bool {
+        if self.ipc0 & MPUIPENA != 0 && access != Access::Execute && self.in_ip(address) && !self.in_ip(pc) {
+            self.violation(MPUSEGIPIFG, self.ipc0 & MPUIPVS != 0);
+            return false;
+        }
+        if !self.enabled() {
+            return true;
+        }
+        let (shift, flag) = match self.segment(address as u32) {
+            Some(segment) => segment,
+            None => return true,
+        };
+        let permission: u16 = match access {
+            Access::Read => SAM_READ,
+            Access::Write => SAM_WRITE,
+            Access::Execute => SAM_EXECUTE,
+        };
+        let permissions: u16 = (self.sam >> shift) & 0xf;
+        if permissions & permission != 0 {
+            return true;
+        }
+        self.violation(flag, permissions & SAM_VIOLATION_PUC != 0);
+        return false;
+    }
+
+    /// Whether a PUC was requested since the last call
+    pub(crate) fn take_puc(&mut self) -> bool {
+        return std::mem::replace(&mut self.puc_requested, false);
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "MPU violation",
+            vector: SYSNMI_VECTOR,
+            nmi: true,
+            enabled: self.ctl0 & MPUSEGIE != 0,
+            flagged: self.ctl1 & CTL1_MASK != 0,
+        };
+    }
+
+    /// Pending while a violation flag is set and MPUSEGIE enables the NMI, firmware clears MPUCTL1 to acknowledge
+    #[inline]
+    pub(crate) fn pending_nmi(&self) -> Option<u16> {
+        if self.ctl0 & MPUSEGIE != 0 && self.ctl1 & CTL1_MASK != 0 {
+            return Some(SYSNMI_VECTOR);
+        }
+        return None;
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        // borders hold bits 19:4 of the address
+        let border = |value: u16| format!("{:#07x}", (value as u32) << 4);
+        let mut registers: Vec<RegisterView> = vec![
+            RegisterView::word("MPUCTL0", MPUCTL0, self.read_word(MPUCTL0))
+                .flag("MPUENA", MPUENA).flag("MPULOCK", MPULOCK).flag("MPUSEGIE", MPUSEGIE),
+            RegisterView::word("MPUCTL1", MPUCTL1, self.ctl1)
+                .flag("MPUSEG1IFG", MPUSEG1IFG).flag("MPUSEG2IFG", MPUSEG2IFG).flag("MPUSEG3IFG", MPUSEG3IFG)
+                .flag("MPUSEGIIFG", MPUSEGIIFG).flag("MPUSEGIPIFG", MPUSEGIPIFG),
+            RegisterView::word("MPUSEGB2", MPUSEGB2, self.segb2).field("start", border(self.segb2)),
+            RegisterView::word("MPUSEGB1", MPUSEGB1, self.segb1).field("start", border(self.segb1)),
+        ];
+        // a nibble per segment, `rwx` plus `!` when a violation causes a PUC
+        let mut sam: RegisterView = RegisterView::word("MPUSAM", MPUSAM, self.sam);
+        for (name, shift) in [("SEG1", 0), ("SEG2", 4), ("SEG3", 8), ("INFO", 12)] {
+            let nibble: u16 = self.sam >> shift;
+            let permissions: String = [(SAM_READ, 'r'), (SAM_WRITE, 'w'), (SAM_EXECUTE, 'x'), (SAM_VIOLATION_PUC, '!')].iter()
+                .map(|(bit, c)| if nibble & bit != 0 {*c} else {'-'}).collect();
+            sam = sam.field(name, permissions);
+        }
+        registers.push(sam);
+        registers.push(RegisterView::word("MPUIPC0", MPUIPC0, self.ipc0)
+            .flag("MPUIPVS", MPUIPVS).flag("MPUIPENA", MPUIPENA).flag("MPUIPLOCK", MPUIPLOCK));
+        registers.push(RegisterView::word("MPUIPSEGB2", MPUIPSEGB2, self.ipsegb2).field("end", border(self.ipsegb2)));
+        registers.push(RegisterView::word("MPUIPSEGB1", MPUIPSEGB1, self.ipsegb1).field("start", border(self.ipsegb1)));
+        return registers;
+    }
+
+    pub(crate) fn read_word(&self, address: u16) -> u16 {
+        return match address {
+            MPUCTL0 => MPUPW_READ | self.ctl0,
+            MPUCTL1 => self.ctl1,
+            MPUSEGB2 => self.segb2,
+            MPUSEGB1 => self.segb1,
+            MPUSAM => self.sam,
+            MPUIPC0 => self.ipc0,
+            MPUIPSEGB2 => self.ipsegb2,
+            MPUIPSEGB1 => self.ipsegb1,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        if address == MPUCTL0 {
+            if value & 0xff00 != MPUPW {
+                self.puc_requested = true;
+                return;
+            }
+            self.open = true;
+            if !self.locked() {
+                self.ctl0 = value & CTL0_MASK;
+            }
+            return;
+        }
+        if !self.open {
+            return; // registers are write protected until the password is written
+        }
+        match address {
+            MPUCTL1 => self.ctl1 = value & CTL1_MASK,
+            MPUSEGB2 if !self.locked() => self.segb2 = value,
+            MPUSEGB1 if !self.locked() => self.segb1 = value,
+            MPUSAM if !self.locked() => self.sam = value,
+            MPUIPC0 if !self.ip_locked() => self.ipc0 = value & IPC0_MASK,
+            MPUIPSEGB2 if !self.ip_locked() => self.ipsegb2 = value,
+            MPUIPSEGB1 if !self.ip_locked() => self.ipsegb1 = value,
+            _ => {},
+        }
+    }
+
+    /// Byte writes only matter for MPUCTL0_H, which opens (0xa5) or closes register access,
+    /// other bytes act on their half of the register
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        if address == MPUCTL0_H {
+            self.open = value as u16 == MPUPW >> 8;
+            return;
+        }
+        if address == MPUCTL0_L {
+            if self.open && !self.locked() {
+                self.ctl0 = value as u16 & CTL0_MASK;
+            }
+            return;
+        }
+        let word: u16 = self.read_word(address & 0xfffe);
+        let word: u16 = if address & 1 == 0 {
+            (word & 0x00ff) | ((value as u16) << 8)
+        } else {
+            (word & 0xff00) | value as u16
+        };
+        self.write_word(address & 0xfffe, word);
+    }
+}
+
+impl BusDevice for MpuDevice {
+    fn claims(&self, address: u16) -> bool {
+        return MpuDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, _bus: &mut BusAccess) {
+        if word {
+            self.write_word(address, value);
+        } else {
+            self.write_byte(address, value as u8);
+        }
+    }
+}
+
+impl SnapshotState for MpuDevice {
+    const TAG: [u8; 4] = *b"MPU ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for register in [self.ctl0, self.ctl1, self.segb1, self.segb2, self.sam, self.ipc0, self.ipsegb1, self.ipsegb2] {
+            w.u16(register);
+        }
+        w.bool(self.open);
+        w.bool(self.puc_requested);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for register in [&mut self.ctl0, &mut self.ctl1, &mut self.segb1, &mut self.segb2, &mut self.sam,
+                         &mut self.ipc0, &mut self.ipsegb1, &mut self.ipsegb2] {
+            *register = r.u16();
+        }
+        self.open = r.bool();
+        self.puc_requested = r.bool();
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{BusAccess, BusDevice};
+use crate::devices::gpio::PORT_COUNT;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const PMAPKEYID: u16 = 0x01a0;
+pub(crate) const PMAPCTL: u16 = 0x01a2;
+pub(crate) const P1MAP: u16 = 0x01a8;
+pub(crate) const P2MAP: u16 = 0x01b0;
+
+/// Written to PMAPKEYID to open the mapping registers, anything else closes them
+pub(crate) const PMAPPW: u16 = 0x2d52;
+/// What PMAPKEYID reads as
+const PMAPKEY_READ: u16 = 0x96a5;
+
+// PMAPCTL
+/// read only, set while the mapping registers are closed
+pub(crate) const PMAPLOCKED: u16 = 0x0001;
+/// the mapping may be changed again after it was closed once
+pub(crate) const PMAPRECFG: u16 = 0x0002;
+
+/// Function numbers with the same meaning on every part, the rest are device specific
+pub(crate) const PM_NONE: u8 = 0;
+pub(crate) const PM_ANALOG: u8 = 31;
+
+/// Port mapping controller of the F5xx/FR5xx parts, for P1 and P2 (the real parts map other
+/// ports, and keep it at 0x01c0 where the emulator's UART lives). Each pin has a PxMAPy byte
+/// selecting the peripheral function it carries while PxSEL is set.
+/// The registers are password protected: PMAPPW in PMAPKEYID opens them until any other value is
+/// written there. Without PMAPRECFG they can only be opened once per reset.
+#[derive(Clone)]
+pub(crate) struct PmapDevice {
+    ctl: u16,
+    maps: [[u8; 8]; PORT_COUNT],
+    open: bool,
+    /// closed after being configured without PMAPRECFG, stays closed until reset
+    frozen: bool,
+}
+
+impl PmapDevice {
+    pub(crate) fn new() -> PmapDevice {
+        return PmapDevice {
+            ctl: 0,
+            maps: [[PM_NONE; 8]; PORT_COUNT],
+            open: false,
+            frozen: false,
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = PmapDevice::new();
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (PMAPKEYID..=PMAPCTL + 1).contains(&address) || (P1MAP..P2MAP + 8).contains(&address);
+    }
+
+    /// Port index and pin of a PxMAPy byte
+    fn locate(address: u16) -> Option<(usize, usize)> {
+        if !(P1MAP..P2MAP + 8).contains(&address) {
+            return None;
+        }
+        return Some((((address - P1MAP) / 8) as usize, ((address - P1MAP) % 8) as usize));
+    }
+
+    fn control(&self) -> u16 {
+        return self.ctl | if self.open {0} else {PMAPLOCKED};
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let mut registers: Vec<RegisterView> = vec![
+            RegisterView::word("PMAPKEYID", PMAPKEYID, PMAPKEY_READ),
+            RegisterView::word("PMAPCTL", PMAPCTL, self.control()).flag("PMAPLOCKED", PMAPLOCKED).flag("PMAPRECFG", PMAPRECFG),
+        ];
+        for (index, map) in self.maps.iter().enumerate() {
+            for (pin, function) in map.iter().enumerate() {
+                let name: String = match *function {
+                    PM_NONE => "none".to_string(),
+                    PM_ANALOG => "analog".to_string(),
+                    f => f.to_string(),
+                };
+                registers.push(RegisterView::byte(&format!("P{}MAP{}", index + 1, pin), P1MAP + (8 * index + pin) as u16, *function)
+                    .field("function", name));
+            }
+        }
+        return registers;
+    }
+
+    pub(crate) fn read_byte(&self, address: u16) -> u8 {
+        return match Self::locate(address) {
+            Some((port, pin)) => self.maps[port][pin],
+            None => {
+                let word: u16 = self.read_word(address & 0xfffe);
+                if address & 1 == 0 {(word >> 8) as u8} else {(word & 0xff) as u8}
+            },
+        };
+    }
+
+    /// PMAPKEYID and PMAPCTL only take word writes, a byte write acts as a word write of the zero-extended byte
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8) {
+        match Self::locate(address) {
+            Some((port, pin)) => {
+                if self.open {
+                    self.maps[port][pin] = value;
+                }
+            },
+            None => self.write_word(address & 0xfffe, value as u16),
+        }
+    }
+
+    pub(crate) fn read_word(&self, address: u16) -> u16 {
+        return match address {
+            PMAPKEYID => PMAPKEY_READ,
+            PMAPCTL => self.control(),
+            _ => ((self.read_byte(address) as u16) << 8) | self.read_byte(address + 1) as u16,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            PMAPKEYID => {
+                let was_open: bool = self.open;
+                self.open = value == PMAPPW && !self.frozen;
+                if was_open && !self.open && self.ctl & PMAPRECFG == 0 {
+                    self.frozen = true;
+                }
+            },
+            PMAPCTL => {
+                if self.open {
+                    self.ctl = value & PMAPRECFG;
+                }
+            },
+            _ => {
+                self.write_byte(address, (value >> 8) as u8);
+                self.write_byte(address + 1, (value & 0xff) as u8);
+            },
+        }
+    }
+}
+
+impl BusDevice for PmapDevice {
+    fn claims(&self, address: u16) -> bool {
+        return PmapDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return if word {self.read_word(address)} else {self.read_byte(address) as u16};
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, _bus: &mut BusAccess) {
+        if word {
+            self.write_word(address, value);
+        } else {
+            self.write_byte(address, value as u8);
+        }
+    }
+}
+
+impl SnapshotState for PmapDevice {
+    const TAG: [u8; 4] = *b"PMAP";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.ctl);
+        for map in self.maps.iter().flatten() {
+            w.u8(*map);
+        }
+        w.bool(self.open);
+        w.bool(self.frozen);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.ctl = r.u16();
+        for map in self.maps.iter_mut().flatten() {
+            *map = r.u8();
+        }
+        self.open = r.bool();
+        self.frozen = r.bool();
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::{volts, RegisterView};
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const SVSMHCTL: u16 = 0x0124;
+pub(crate) const PMMIFG: u16 = 0x012c;
+pub(crate) const PMMRIE: u16 = 0x012e;
+
+// SVSMHCTL
+pub(crate) const SVSMHRRL_MASK: u16 = 0x0007;
+pub(crate) const SVSHRVL_MASK: u16 = 0x0300;
+pub(crate) const SVSHE: u16 = 0x0400;
+pub(crate) const SVMHE: u16 = 0x4000;
+const SVSMHCTL_MASK: u16 = SVSMHRRL_MASK | SVSHRVL_MASK | SVSHE | SVMHE;
+
+// PMMIFG
+pub(crate) const SVMHIFG: u16 = 0x0020;
+pub(crate) const PMMBORIFG: u16 = 0x0100;
+pub(crate) const SVSHIFG: u16 = 0x1000;
+const PMMIFG_MASK: u16 = SVMHIFG | PMMBORIFG | SVSHIFG;
+
+// PMMRIE
+pub(crate) const SVMHIE: u16 = 0x0020;
+/// the SVS holds the CPU in reset (brownout) instead of only setting SVSHIFG
+pub(crate) const SVSHPE: u16 = 0x1000;
+const PMMRIE_MASK: u16 = SVMHIE | SVSHPE;
+
+/// SYSNMI, shared with the MPU
+pub(crate) const SVM_VECTOR: u16 = 0xfffc;
+
+pub(crate) const DEFAULT_SUPPLY_MV: u16 = 3300;
+/// SVS reset levels selected by SVSHRVL
+const SVS_LEVELS_MV: [u16; 4] = [1740, 1940, 2140, 2260];
+/// SVM interrupt levels selected by SVSMHRRL
+const SVM_LEVELS_MV: [u16; 8] = [1740, 1940, 2140, 2260, 2400, 2700, 3100, 3100];
+
+/// Linear change of the supply between two points in emulated time
+#[derive(Clone)]
+struct Ramp {
+    from_mv: u16,
+    to_mv: u16,
+    start: u64,
+    end: u64,
+}
+
+/// High-side supply supervisor and monitor of the F5xx power management module, driven by a
+/// simulated supply voltage. The SVS holds the CPU in reset while the supply is below its level,
+/// the SVM raises an NMI when the supply falls below its level.
+#[derive(Clone)]
+pub(crate) struct PmmDevice {
+    svsmhctl: u16,
+    ifg: u16,
+    rie: u16,
+    supply_mv: u16,
+    ramp: Option<Ramp>,
+    below_svs: bool,
+    below_svm: bool,
+}
+
+impl PmmDevice {
+    pub(crate) fn new() -> PmmDevice {
+        return PmmDevice {
+            svsmhctl: SVSHE | SVMHE,
+            ifg: 0,
+            rie: SVSHPE,
+            supply_mv: DEFAULT_SUPPLY_MV,
+            ramp: None,
+            below_svs: false,
+            below_svm: false,
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = PmmDevice::new();
+    }
+
+    /// Reset caused by the SVS, the registers go back to their defaults but the supply stays
+    /// and the flags record why the device restarted
+    pub(crate) fn brownout(&mut self) {
+        self.svsmhctl = SVSHE | SVMHE;
+        self.rie = SVSHPE;
+        self.ifg |= PMMBORIFG;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (SVSMHCTL..=SVSMHCTL + 1).contains(&address) || (PMMIFG..=PMMRIE + 1).contains(&address);
+    }
+
+    pub(crate) fn supply_mv(&self) -> u16 {
+        return self.supply_mv;
+    }
+
+    /// Move the supply to `target_mv` over `cycles` emulated cycles (0 = right away)
+    pub(crate) fn set_supply(&mut self, target_mv: u16, cycles: u64, now: u64) {
+        self.ramp = Some(Ramp { from_mv: self.supply_mv, to_mv: target_mv, start: now, end: now + cycles });
+        self.update(now);
+    }
+
+    /// Follow the supply ramp up to cycle `now`
+    #[inline]
+    pub(crate) fn update(&mut self, now: u64) {
+        let ramp = match &self.ramp {
+            Some(ramp) => ramp,
+            None => return,
+        };
+        if now >= ramp.end {
+            self.supply_mv = ramp.to_mv;
+            self.ramp = None;
+        } else {
+            let progress: i64 = (now - ramp.start) as i64;
+            let delta: i64 = ramp.to_mv as i64 - ramp.from_mv as i64;
+            self.supply_mv = (ramp.from_mv as i64 + delta * progress / (ramp.end - ramp.start) as i64) as u16;
+        }
+        self.evaluate();
+    }
+
+    /// Compare the supply against the levels, flags are set when it falls below one
+    fn evaluate(&mut self) {
+        let svs_level: u16 = SVS_LEVELS_MV[((self.svsmhctl & SVSHRVL_MASK) >> 8) as usize];
+        let svm_level: u16 = SVM_LEVELS_MV[(self.svsmhctl & SVSMHRRL_MASK) as usize];
+        let below_svs: bool = self.svsmhctl & SVSHE != 0 && self.supply_mv < svs_level;
+        let below_svm: bool = self.svsmhctl & SVMHE != 0 && self.supply_mv < svm_level;
+        if below_svs && !self.below_svs {
+            self.ifg |= SVSHIFG;
+        }
+        if below_svm && !self.below_svm {
+            self.ifg |= SVMHIFG;
+        }
+        self.below_svs = below_svs;
+        self.below_svm = below_svm;
+    }
+
+    /// The SVS is holding the CPU in reset
+    #[inline]
+    pub(crate) fn in_reset(&self) -> bool {
+        return self.below_svs && self.rie & SVSHPE != 0;
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "supply monitor",
+            vector: SVM_VECTOR,
+            nmi: true,
+            enabled: self.rie & SVMHIE != 0,
+            flagged: self.ifg & SVMHIFG != 0,
+        };
+    }
+
+    #[inline]
+    pub(crate) fn pending_nmi(&self) -> Option<u16> {
+        if self.ifg & SVMHIFG != 0 && self.rie & SVMHIE != 0 {
+            return Some(SVM_VECTOR);
+        }
+        return None;
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let svs_level: u16 = SVS_LEVELS_MV[((self.svsmhctl & SVSHRVL_MASK) >> 8) as usize];
+        let svm_level: u16 = SVM_LEVELS_MV[(self.svsmhctl & SVSMHRRL_MASK) as usize];
+        return vec![
+            RegisterView::word("SVSMHCTL", SVSMHCTL, self.svsmhctl)
+                .field("SVSMHRRL", volts(svm_level)).field("SVSHRVL", volts(svs_level))
+                .flag("SVSHE", SVSHE).flag("SVMHE", SVMHE).field("supply", volts(self.supply_mv)),
+            RegisterView::word("PMMIFG", PMMIFG, self.ifg)
+                .flag("SVMHIFG", SVMHIFG).flag("PMMBORIFG", PMMBORIFG).flag("SVSHIFG", SVSHIFG),
+            RegisterView::word("PMMRIE", PMMRIE, self.rie).flag("SVMHIE", SVMHIE).flag("SVSHPE", SVSHPE),
+        ];
+    }
+
+    pub(crate) fn read_word(&self, address: u16) -> u16 {
+        return match address {
+            SVSMHCTL => self.svsmhctl,
+            PMMIFG => self.ifg,
+            PMMRIE => self.rie,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            SVSMHCTL => self.svsmhctl = value & SVSMHCTL_MASK,
+            PMMIFG => self.ifg = value & PMMIFG_MASK,
+            PMMRIE => self.rie = value & PMMRIE_MASK,
+            _ => {},
+        }
+        self.evaluate();
+    }
+}
+
+impl BusDevice for PmmDevice {
+    fn claims(&self, address: u16) -> bool {
+        return PmmDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+impl SnapshotState for PmmDevice {
+    const TAG: [u8; 4] = *b"PMM ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.svsmhctl);
+        w.u16(self.ifg);
+        w.u16(self.rie);
+        w.u16(self.supply_mv);
+        w.bool(self.below_svs);
+        w.bool(self.below_svm);
+        w.bool(self.ramp.is_some());
+        if let Some(ramp) = &self.ramp {
+            w.u16(ramp.from_mv);
+            w.u16(ramp.to_mv);
+            w.u64(ramp.start);
+            w.u64(ramp.end);
+        }
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.svsmhctl = r.u16();
+        self.ifg = r.u16();
+        self.rie = r.u16();
+        self.supply_mv = r.u16();
+        self.below_svs = r.bool();
+        self.below_svm = r.bool();
+        self.ramp = match r.bool() {
+            true => Some(Ramp { from_mv: r.u16(), to_mv: r.u16(), start: r.u64(), end: r.u64() }),
+            false => None,
+        };
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::snapshot::{Reader, Writer};
+
+/// Register addresses
+pub(crate) const CONFIG: u8 = 0x00;
+pub(crate) const EN_AA: u8 = 0x01;
+pub(crate) const EN_RXADDR: u8 = 0x02;
+pub(crate) const SETUP_AW: u8 = 0x03;
+pub(crate) const RF_CH: u8 = 0x05;
+pub(crate) const STATUS: u8 = 0x07;
+pub(crate) const RX_ADDR_P0: u8 = 0x0a;
+pub(crate) const RX_ADDR_P1: u8 = 0x0b;
+pub(crate) const TX_ADDR: u8 = 0x10;
+pub(crate) const RX_PW_P0: u8 = 0x11;
+pub(crate) const FIFO_STATUS: u8 = 0x17;
+
+/// Commands
+pub(crate) const R_REGISTER: u8 = 0x00;
+pub(crate) const W_REGISTER: u8 = 0x20;
+pub(crate) const R_RX_PL_WID: u8 = 0x60;
+pub(crate) const R_RX_PAYLOAD: u8 = 0x61;
+pub(crate) const W_TX_PAYLOAD: u8 = 0xa0;
+pub(crate) const W_TX_PAYLOAD_NOACK: u8 = 0xb0;
+pub(crate) const FLUSH_TX: u8 = 0xe1;
+pub(crate) const FLUSH_RX: u8 = 0xe2;
+
+/// CONFIG bits
+pub(crate) const PRIM_RX: u8 = 0x01;
+pub(crate) const PWR_UP: u8 = 0x02;
+pub(crate) const MASK_MAX_RT: u8 = 0x10;
+pub(crate) const MASK_TX_DS: u8 = 0x20;
+pub(crate) const MASK_RX_DR: u8 = 0x40;
+/// STATUS bits
+pub(crate) const TX_FULL: u8 = 0x01;
+pub(crate) const MAX_RT: u8 = 0x10;
+pub(crate) const TX_DS: u8 = 0x20;
+pub(crate) const RX_DR: u8 = 0x40;
+/// FIFO_STATUS bits
+const RX_EMPTY: u8 = 0x01;
+const RX_FULL: u8 = 0x02;
+const TX_EMPTY: u8 = 0x10;
+const FIFO_FULL: u8 = 0x20;
+
+const FIFO_DEPTH: usize = 3;
+pub(crate) const MAX_PAYLOAD: usize = 32;
+const REGISTER_COUNT: usize = 0x1e;
+
+/// `run --radio CSN:CE:IRQ`, e.g. P2.0:P2.1:P2.2
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct RadioSpec {
+    pub(crate) csn: PinId,
+    pub(crate) ce: PinId,
+    pub(crate) irq: PinId,
+}
+
+impl FromStr for RadioSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pins: Vec<&str> = s.split(':').collect();
+        if pins.len() != 3 {
+            return Err(format!("'{}' is not CSN:CE:IRQ, e.g. P2.0:P2.1:P2.2", s));
+        }
+        return Ok(RadioSpec { csn: pins[0].parse()?, ce: pins[1].parse()?, irq: pins[2].parse()? });
+    }
+}
+
+impl fmt::Display for RadioSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}:{}:{}", self.csn, self.ce, self.irq);
+    }
+}
+
+/// What goes over the air: the channel, the address it is sent to and the payload
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Packet {
+    pub(crate) channel: u8,
+    pub(crate) address: Vec<u8>,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Packet {
+    /// As sent between instances: channel, address width, address, payload
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        return [&[self.channel, self.address.len() as u8][..], &self.address, &self.payload].concat();
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Option<Packet> {
+        let width: usize = *data.get(1)? as usize;
+        if !(3..=5).contains(&width) || data.len() < 2 + width || data.len() > 2 + width + MAX_PAYLOAD {
+            return None;
+        }
+        return Some(Packet { channel: data[0], address: data[2..2 + width].to_vec(), payload: data[2 + width..].to_vec() });
+    }
+}
+
+/// The command being clocked in while CSN is low
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Transaction {
+    /// the next byte is a command
+    Idle,
+    ReadRegister(u8, usize),
+    WriteRegister(u8, usize),
+    ReadPayload(Vec<u8>, usize),
+    WritePayload(Vec<u8>),
+    /// done or unsupported, the rest is clocked out as 0
+    Ignore,
+}
+
+/// An nRF24L01-style 2.4 GHz transceiver on the SPI bus: the command set, CONFIG, EN_RXADDR,
+/// SETUP_AW, RF_CH, STATUS, pipes 0 and 1 with static payload widths, TX_ADDR and FIFO_STATUS, the
+/// other registers are stored only. There is no auto-acknowledgement: every packet sent counts as
+/// delivered (TX_DS), MAX_RT never happens. The air is whatever connects radios (radio_link.rs).
+#[derive(Clone)]
+pub(crate) struct Radio {
+    pub(crate) pins: RadioSpec,
+    registers: [u8; REGISTER_COUNT],
+    rx_address: [[u8; 5]; 2],
+    tx_address: [u8; 5],
+    rx_fifo: VecDeque<(u8, Vec<u8>)>,
+    tx_fifo: VecDeque<Vec<u8>>,
+    transaction: Transaction,
+    /// sent, waiting for the link to take them
+    outgoing: Vec<Packet>,
+    /// CSN at the last pin update
+    selected: bool,
+}
+
+impl Radio {
+    pub(crate) fn new(pins: RadioSpec) -> Radio {
+        let mut registers: [u8; REGISTER_COUNT] = [0; REGISTER_COUNT];
+        registers[CONFIG as usize] = 0x08;
+        registers[EN_AA as usize] = 0x3f;
+        registers[EN_RXADDR as usize] = 0x03;
+        registers[SETUP_AW as usize] = 0x03;
+        registers[0x04] = 0x03;
+        registers[RF_CH as usize] = 0x02;
+        registers[0x06] = 0x0e;
+        return Radio {
+            pins,
+            registers,
+            rx_address: [[0xe7; 5], [0xc2; 5]],
+            tx_address: [0xe7; 5],
+            rx_fifo: VecDeque::new(),
+            tx_fifo: VecDeque::new(),
+            transaction: Transaction::Idle,
+            outgoing: Vec::new(),
+            selected: false,
+        };
+    }
+
+    /// Power-on state, packets on their way stay on their way
+    pub(crate) fn reset(&mut self) {
+        *self = Radio { outgoing: std::mem::take(&mut self.outgoing), ..Radio::new(self.pins) };
+    }
+
+    fn address_width(&self) -> usize {
+        return match self.registers[SETUP_AW as usize] & 0x03 {
+            1 => 3,
+            2 => 4,
+            _ => 5,
+        };
+    }
+
+    fn status(&self) -> u8 {
+        let pipe: u8 = self.rx_fifo.front().map(|(pipe, _)| *pipe).unwrap_or(7);
+        return (self.registers[STATUS as usize] & (RX_DR | TX_DS | MAX_RT)) | (pipe << 1)
+            | if self.tx_fifo.len() == FIFO_DEPTH {TX_FULL} else {0};
+    }
+
+    fn read_register(&self, register: u8, index: usize) -> u8 {
+        return match register {
+            STATUS => self.status(),
+            RX_ADDR_P0 | RX_ADDR_P1 => self.rx_address[(register - RX_ADDR_P0) as usize].get(index).copied().unwrap_or(0),
+            TX_ADDR => self.tx_address.get(index).copied().unwrap_or(0),
+            FIFO_STATUS => (if self.rx_fifo.is_empty() {RX_EMPTY} else {0})
+                | (if self.rx_fifo.len() == FIFO_DEPTH {RX_FULL} else {0})
+                | (if self.tx_fifo.is_empty() {TX_EMPTY} else {0})
+                | if self.tx_fifo.len() == FIFO_DEPTH {FIFO_FULL} else {0},
+            _ if index == 0 => self.registers.get(register as usize).copied().unwrap_or(0),
+            _ => 0,
+        };
+    }
+
+    fn write_register(&mut self, register: u8, index: usize, value: u8) {
+        match register {
+            // interrupt flags are cleared by writing 1
+            STATUS => self.registers[STATUS as usize] &= !(value & (RX_DR | TX_DS | MAX_RT)),
+            RX_ADDR_P0 | RX_ADDR_P1 if index < 5 => self.rx_address[(register - RX_ADDR_P0) as usize][index] = value,
+            TX_ADDR if index < 5 => self.tx_address[index] = value,
+            FIFO_STATUS => {},
+            _ if index == 0 && (register as usize) < REGISTER_COUNT => self.registers[register as usize] = value,
+            _ => {},
+        }
+    }
+
+    /// One SPI byte each way while CSN is low
+    pub(crate) fn exchange(&mut self, mosi: u8) -> u8 {
+        let (miso, next) = match std::mem::replace(&mut self.transaction, Transaction::Ignore) {
+            Transaction::Idle => (self.status(), self.command(mosi)),
+            Transaction::ReadRegister(register, index) => {
+                (self.read_register(register, index), Transaction::ReadRegister(register, index + 1))
+            },
+            Transaction::WriteRegister(register, index) => {
+                self.write_register(register, index, mosi);
+                (0, Transaction::WriteRegister(register, index + 1))
+            },
+            Transaction::ReadPayload(payload, index) => {
+                (payload.get(index).copied().unwrap_or(0), Transaction::ReadPayload(payload, index + 1))
+            },
+            Transaction::WritePayload(mut payload) => {
+                if payload.len() < MAX_PAYLOAD {
+                    payload.push(mosi);
+                }
+                (0, Transaction::WritePayload(payload))
+            },
+            Transaction::Ignore => (0, Transaction::Ignore),
+        };
+        self.transaction = next;
+        return miso;
+    }
+
+    fn command(&mut self, command: u8) -> Transaction {
+        return match command {
+            R_REGISTER..W_REGISTER => Transaction::ReadRegister(command & 0x1f, 0),
+            W_REGISTER..=0x3f => Transaction::WriteRegister(command & 0x1f, 0),
+            R_RX_PL_WID => Transaction::ReadPayload(vec![self.rx_fifo.front().map(|(_, p)| p.len() as u8).unwrap_or(0)], 0),
+            R_RX_PAYLOAD => {
+                let payload: Vec<u8> = self.rx_fifo.pop_front().map(|(_, payload)| payload).unwrap_or_default();
+                Transaction::ReadPayload(payload, 0)
+            },
+            W_TX_PAYLOAD | W_TX_PAYLOAD_NOACK => Transaction::WritePayload(Vec::new()),
+            FLUSH_TX => {
+                self.tx_fifo.clear();
+                Transaction::Ignore
+            },
+            FLUSH_RX => {
+                self.rx_fifo.clear();
+                Transaction::Ignore
+            },
+            _ => Transaction::Ignore,
+        };
+    }
+
+    /// CSN went high, the command is complete
+    fn end_transaction(&mut self) {
+        if let Transaction::WritePayload(payload) = std::mem::replace(&mut self.transaction, Transaction::Idle) {
+            if !payload.is_empty() && self.tx_fifo.len() < FIFO_DEPTH {
+                self.tx_fifo.push_back(payload);
+            }
+        }
+    }
+
+    /// Follow CSN and CE, send what's queued and drive IRQ (low while an unmasked flag is set)
+    pub(crate) fn update_pins(&mut self, gpio: &mut GpioDevice) {
+        let selected: bool = gpio.output(self.pins.csn) == Some(false);
+        if self.selected && !selected {
+            self.end_transaction();
+        }
+        if !selected {
+            self.transaction = Transaction::Idle;
+        }
+        self.selected = selected;
+
+        let config: u8 = self.registers[CONFIG as usize];
+        if config & PWR_UP != 0 && config & PRIM_RX == 0 && gpio.output(self.pins.ce) == Some(true) {
+            while let Some(payload) = self.tx_fifo.pop_front() {
+                self.outgoing.push(Packet {
+                    channel: self.registers[RF_CH as usize],
+                    address: self.tx_address[..self.address_width()].to_vec(),
+                    payload,
+                });
+                self.registers[STATUS as usize] |= TX_DS;
+            }
+        }
+        let masked: u8 = (if config & MASK_RX_DR != 0 {RX_DR} else {0}) | (if config & MASK_TX_DS != 0 {TX_DS} else {0})
+            | if config & MASK_MAX_RT != 0 {MAX_RT} else {0};
+        let asserted: bool = self.registers[STATUS as usize] & (RX_DR | TX_DS | MAX_RT) & !masked != 0;
+        if gpio.level(self.pins.irq) == asserted {
+            gpio.set_input(self.pins.irq, Some(!asserted));
+        }
+    }
+
+    pub(crate) fn take_outgoing(&mut self) -> Vec<Packet> {
+        return std::mem::take(&mut self.outgoing);
+    }
+
+    /// A packet on the air, kept if the radio is listening on its channel and one of its pipes
+    /// has the address and payload width
+    pub(crate) fn receive(&mut self, packet: &Packet, gpio: &mut GpioDevice) {
+        let config: u8 = self.registers[CONFIG as usize];
+        let listening: bool = config & PWR_UP != 0 && config & PRIM_RX != 0 && gpio.output(self.pins.ce) == Some(true);
+        if !listening || packet.channel != self.registers[RF_CH as usize] || self.rx_fifo.len() == FIFO_DEPTH {
+            return;
+        }
+        let width: usize = self.address_width();
+        let pipe: Option<usize> = (0..2).find(|&pipe| {
+            self.registers[EN_RXADDR as usize] & (1 << pipe) != 0
+                && packet.address == self.rx_address[pipe][..width]
+                && packet.payload.len() == self.registers[(RX_PW_P0 as usize) + pipe] as usize
+        });
+        if let Some(pipe) = pipe {
+            self.rx_fifo.push_back((pipe as u8, packet.payload.clone()));
+            self.registers[STATUS as usize] |= RX_DR;
+            self.update_pins(gpio);
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let config: u8 = self.registers[CONFIG as usize];
+        let mode: &str = match (config & PWR_UP != 0, config & PRIM_RX != 0) {
+            (false, _) => "powered down",
+            (true, false) => "TX",
+            (true, true) => "RX",
+        };
+        return format!("{}, channel {}, {} received, {} to send, CSN/CE/IRQ {}", mode, self.registers[RF_CH as usize],
+                       self.rx_fifo.len(), self.tx_fifo.len(), self.pins);
+    }
+
+    /// Registers and FIFOs, the pins are the run's
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.bytes(&self.registers);
+        w.bytes(&[self.rx_address[0], self.rx_address[1], self.tx_address].concat());
+        w.u8(self.rx_fifo.len() as u8);
+        for (pipe, payload) in &self.rx_fifo {
+            w.u8(*pipe);
+            w.bytes(payload);
+        }
+        w.u8(self.tx_fifo.len() as u8);
+        for payload in &self.tx_fifo {
+            w.bytes(payload);
+        }
+    }
+
+    pub(crate) fn restore(&mut self, r: &mut Reader) {
+        let registers: Vec<u8> = r.bytes();
+        let count: usize = registers.len().min(REGISTER_COUNT);
+        self.registers[..count].copy_from_slice(&registers[..count]);
+        let addresses: Vec<u8> = r.bytes();
+        if addresses.len() == 15 {
+            self.rx_address = [addresses[0..5].try_into().expect("5 bytes"), addresses[5..10].try_into().expect("5 bytes")];
+            self.tx_address = addresses[10..15].try_into().expect("5 bytes");
+        }
+        self.rx_fifo = (0..r.u8()).map(|_| (r.u8(), r.bytes())).collect();
+        self.tx_fifo = (0..r.u8()).map(|_| r.bytes()).collect();
+        self.transaction = Transaction::Idle;
+    }
+}
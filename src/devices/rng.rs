@@ -0,0 +1,113 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const RNG_DATA: u16 = 0x01e0;
+pub(crate) const RNG_SEED: u16 = 0x01e2;
+
+/// SplitMix64, small and good enough for firmware entropy, and trivially replayable
+pub(crate) fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z: u64 = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+/// Memory mapped random number generator. The stream is fully determined by the host seed
+/// (and any seed firmware writes), so a run can be replayed by reusing the seed.
+#[derive(Clone)]
+pub(crate) struct RngDevice {
+    seed: u64,
+    state: u64,
+}
+
+#[allow(dead_code)]
+impl RngDevice {
+    pub(crate) fn new(seed: u64) -> RngDevice {
+        return RngDevice { seed, state: seed };
+    }
+
+    /// Restart the stream from the host seed
+    pub(crate) fn reset(&mut self) {
+        self.state = self.seed;
+    }
+
+    pub(crate) fn seed(&self) -> u64 {
+        return self.seed;
+    }
+
+    /// Change the host seed and restart the stream
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.reset();
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (RNG_DATA..=RNG_SEED).contains(&address);
+    }
+
+    pub(crate) fn next_word(&mut self) -> u16 {
+        return (split_mix(&mut self.state) >> 48) as u16;
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            RNG_DATA => self.next_word(),
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            // firmware seeds are mixed with the host seed so different host seeds still diverge
+            RNG_SEED => self.state = self.seed ^ (value as u64).wrapping_mul(0x9e3779b97f4a7c15),
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for RngDevice {
+    fn claims(&self, address: u16) -> bool {
+        return RngDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+/// Only the generator's position, the seed is configuration (`--seed`)
+impl SnapshotState for RngDevice {
+    const TAG: [u8; 4] = *b"RNG ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u64(self.state);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.state = r.u64();
+    }
+}
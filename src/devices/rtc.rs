@@ -0,0 +1,101 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::clock::{Clock, ACLK_HZ};
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const RTC_ACLK: u16 = 0x01d0;
+pub(crate) const RTC_SECONDS: u16 = 0x01d2;
+
+/// Free-running 32kHz ACLK counter and seconds counter, timed by whichever source the clock uses.
+/// Both stand still while OSCOFF stops ACLK (LPM4).
+#[derive(Clone)]
+pub(crate) struct RtcDevice {
+    /// added to the elapsed seconds, so firmware can set the time
+    seconds_offset: u16,
+}
+
+impl RtcDevice {
+    pub(crate) fn new() -> RtcDevice {
+        return RtcDevice { seconds_offset: 0 };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.seconds_offset = 0;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (RTC_ACLK..=RTC_SECONDS).contains(&address);
+    }
+
+    fn elapsed_seconds(clock: &Clock) -> u16 {
+        return (clock.aclk_ticks(1) & 0xffff) as u16;
+    }
+
+    pub(crate) fn registers(&self, clock: &Clock) -> Vec<RegisterView> {
+        return vec![
+            RegisterView::word("RTC_ACLK", RTC_ACLK, (clock.aclk_ticks(ACLK_HZ) & 0xffff) as u16),
+            RegisterView::word("RTC_SECONDS", RTC_SECONDS, Self::elapsed_seconds(clock).wrapping_add(self.seconds_offset))
+                .field("offset", self.seconds_offset.to_string()),
+        ];
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16, clock: &Clock) -> u16 {
+        return match address {
+            RTC_ACLK => (clock.aclk_ticks(ACLK_HZ) & 0xffff) as u16,
+            RTC_SECONDS => Self::elapsed_seconds(clock).wrapping_add(self.seconds_offset),
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16, clock: &Clock) {
+        match address {
+            RTC_SECONDS => self.seconds_offset = value.wrapping_sub(Self::elapsed_seconds(clock)),
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for RtcDevice {
+    fn claims(&self, address: u16) -> bool {
+        return RtcDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe, bus.clock), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value, bus.clock);
+    }
+}
+
+impl SnapshotState for RtcDevice {
+    const TAG: [u8; 4] = *b"RTC ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.seconds_offset);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.seconds_offset = r.u16();
+    }
+}
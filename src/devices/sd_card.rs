@@ -0,0 +1,264 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+use crate::devices::gpio::PinId;
+use crate::snapshot::{Reader, Writer};
+
+pub(crate) const BLOCK_SIZE: usize = 512;
+
+/// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+/// Starts a data block in either direction
+const DATA_TOKEN: u8 = 0xfe;
+/// Data response: accepted
+const DATA_ACCEPTED: u8 = 0x05;
+/// Powered up, SDHC (block addressed), 2.7 - 3.6 V
+const OCR: u32 = 0xc0ff_8000;
+
+/// `run --sd-card CS:IMAGE`, e.g. P1.4:card.img
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct SdCardSpec {
+    pub(crate) chip_select: PinId,
+    pub(crate) path: String,
+}
+
+impl FromStr for SdCardSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pin, path) = s.split_once(':').ok_or(format!("'{}' is not CS:IMAGE, e.g. P1.4:card.img", s))?;
+        if path.is_empty() {
+            return Err(format!("'{}' has no image file", s));
+        }
+        return Ok(SdCardSpec { chip_select: pin.parse()?, path: path.to_string() });
+    }
+}
+
+impl fmt::Display for SdCardSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}:{}", self.chip_select, self.path);
+    }
+}
+
+/// What the card does with the bytes it receives
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Phase {
+    /// collecting a 6 byte command frame
+    Command(Vec<u8>),
+    /// CMD24 was accepted, waiting for the data token
+    WriteToken(u32),
+    /// the block and its 2 CRC bytes
+    WriteData(u32, Vec<u8>),
+}
+
+/// An SDHC card in SPI mode, backed by an image file: CMD0, CMD8, CMD9, CMD16, CMD17, CMD24,
+/// CMD55/ACMD41 and CMD58, enough for FatFs to mount it. CRCs are neither checked nor computed.
+/// Written blocks go to the image file right away.
+#[derive(Clone)]
+pub(crate) struct SdCard {
+    pub(crate) chip_select: PinId,
+    path: String,
+    /// shared with forks until either writes to it
+    image: Rc<Vec<u8>>,
+    idle: bool,
+    /// the last command was CMD55, the next is an application command
+    app_command: bool,
+    phase: Phase,
+    /// bytes the card sends next, 0xff once they run out
+    out: VecDeque<u8>,
+}
+
+impl SdCard {
+    pub(crate) fn open(spec: &SdCardSpec) -> Result<SdCard, String> {
+        let image: Vec<u8> = std::fs::read(&spec.path).map_err(|e| e.to_string())?;
+        if image.is_empty() || !image.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(format!("the image is {} bytes, not a multiple of {}", image.len(), BLOCK_SIZE));
+        }
+        return Ok(SdCard {
+            chip_select: spec.chip_select,
+            path: spec.path.clone(),
+            image: Rc::new(image),
+            idle: true,
+            app_command: false,
+            phase: Phase::Command(Vec::new()),
+            out: VecDeque::new(),
+        });
+    }
+
+    /// Power cycle, the image stays
+    pub(crate) fn reset(&mut self) {
+        self.idle = true;
+        self.app_command = false;
+        self.phase = Phase::Command(Vec::new());
+        self.out.clear();
+    }
+
+    pub(crate) fn blocks(&self) -> u32 {
+        return (self.image.len() / BLOCK_SIZE) as u32;
+    }
+
+    /// CS went high, a command being sent is dropped
+    pub(crate) fn deselect(&mut self) {
+        if let Phase::Command(frame) = &mut self.phase {
+            frame.clear();
+        }
+    }
+
+    /// One SPI byte each way
+    pub(crate) fn exchange(&mut self, mosi: u8) -> u8 {
+        let miso: u8 = self.out.pop_front().unwrap_or(0xff);
+        match &mut self.phase {
+            Phase::Command(frame) => {
+                // a frame starts with 01 in the top bits, the host clocks 0xff while waiting
+                if !frame.is_empty() || mosi & 0xc0 == 0x40 {
+                    frame.push(mosi);
+                }
+                if frame.len() == 6 {
+                    let frame: Vec<u8> = std::mem::take(frame);
+                    self.command(frame[0] & 0x3f, u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]));
+                }
+            },
+            &mut Phase::WriteToken(block) => {
+                if mosi == DATA_TOKEN {
+                    self.phase = Phase::WriteData(block, Vec::with_capacity(BLOCK_SIZE + 2));
+                }
+            },
+            Phase::WriteData(block, data) => {
+                data.push(mosi);
+                if data.len() == BLOCK_SIZE + 2 {
+                    let block: u32 = *block;
+                    let data: Vec<u8> = std::mem::take(data);
+                    self.write_block(block, &data[..BLOCK_SIZE]);
+                    self.phase = Phase::Command(Vec::new());
+                    // accepted, then busy for a byte
+                    self.out.extend([DATA_ACCEPTED, 0x00]);
+                }
+            },
+        }
+        return miso;
+    }
+
+    fn command(&mut self, index: u8, argument: u32) {
+        if index == 0 {
+            self.reset();
+        }
+        let app: bool = std::mem::take(&mut self.app_command);
+        let status: u8 = if self.idle {R1_IDLE} else {0};
+        // one byte of Ncr before every response
+        self.out.push_back(0xff);
+        match (app, index) {
+            (_, 0) => self.out.push_back(R1_IDLE),
+            (_, 8) => self.out.extend([status, 0x00, 0x00, ((argument >> 8) & 0x0f) as u8, (argument & 0xff) as u8]),
+            (_, 55) => {
+                self.app_command = true;
+                self.out.push_back(status);
+            },
+            (true, 41) => {
+                self.idle = false;
+                self.out.push_back(0);
+            },
+            (_, 58) => {
+                self.out.push_back(status);
+                self.out.extend(OCR.to_be_bytes());
+            },
+            _ if self.idle => self.out.push_back(status | R1_ILLEGAL_COMMAND),
+            (_, 9) => {
+                self.out.extend([status, 0xff, DATA_TOKEN]);
+                self.out.extend(self.csd());
+                self.out.extend([0xff, 0xff]);
+            },
+            (_, 16) => self.out.push_back(if argument as usize == BLOCK_SIZE {status} else {status | R1_PARAMETER_ERROR}),
+            (_, 17) if argument < self.blocks() => {
+                let start: usize = argument as usize * BLOCK_SIZE;
+                self.out.extend([status, 0xff, DATA_TOKEN]);
+                self.out.extend(&self.image[start..start + BLOCK_SIZE]);
+                self.out.extend([0xff, 0xff]);
+            },
+            (_, 24) if argument < self.blocks() => {
+                self.out.push_back(status);
+                self.phase = Phase::WriteToken(argument);
+            },
+            (_, 17 | 24) => self.out.push_back(status | R1_PARAMETER_ERROR),
+            _ => self.out.push_back(status | R1_ILLEGAL_COMMAND),
+        }
+    }
+
+    /// CSD version 2.0, the capacity counts whole 512K units
+    fn csd(&self) -> [u8; 16] {
+        let c_size: u32 = (self.blocks() / 1024).saturating_sub(1);
+        return [0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, ((c_size >> 16) & 0x3f) as u8, (c_size >> 8) as u8,
+                c_size as u8, 0x7f, 0x80, 0x0a, 0x40, 0x00, 0x01];
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) {
+        let start: usize = block as usize * BLOCK_SIZE;
+        Rc::make_mut(&mut self.image)[start..start + BLOCK_SIZE].copy_from_slice(data);
+        let written = OpenOptions::new().write(true).open(&self.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(start as u64))?;
+            return file.write_all(data);
+        });
+        if let Err(e) = written {
+            eprintln!("SD card: failed to write block {} to '{}': {}", block, self.path, e);
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let phase: String = match &self.phase {
+            Phase::Command(_) => "command".to_string(),
+            Phase::WriteToken(block) | Phase::WriteData(block, _) => format!("writing block {}", block),
+        };
+        return format!("{}, {} blocks, CS {}, {}{}", self.path, self.blocks(), self.chip_select,
+                       if self.idle {"idle, "} else {""}, phase);
+    }
+
+    /// The protocol state, the image is the file's
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.bool(self.idle);
+        w.bool(self.app_command);
+        let (phase, block, data): (u8, u32, &[u8]) = match &self.phase {
+            Phase::Command(frame) => (0, 0, frame),
+            Phase::WriteToken(block) => (1, *block, &[]),
+            Phase::WriteData(block, data) => (2, *block, data),
+        };
+        w.u8(phase);
+        w.u32(block);
+        w.bytes(data);
+        w.bytes(&self.out.iter().copied().collect::<Vec<u8>>());
+    }
+
+    pub(crate) fn restore(&mut self, r: &mut Reader) {
+        self.idle = r.bool();
+        self.app_command = r.bool();
+        let (phase, block, data) = (r.u8(), r.u32(), r.bytes());
+        self.phase = match phase {
+            1 => Phase::WriteToken(block),
+            2 => Phase::WriteData(block, data),
+            _ => Phase::Command(data),
+        };
+        self.out = r.bytes().into();
+    }
+}
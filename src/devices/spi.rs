@@ -0,0 +1,248 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::devices::gpio::GpioDevice;
+use crate::devices::radio::Radio;
+use crate::devices::sd_card::SdCard;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const IFG2: u16 = 0x0003;
+pub(crate) const UCB0CTL0: u16 = 0x0068;
+pub(crate) const UCB0CTL1: u16 = 0x0069;
+pub(crate) const UCB0BR0: u16 = 0x006a;
+pub(crate) const UCB0BR1: u16 = 0x006b;
+pub(crate) const UCB0STAT: u16 = 0x006d;
+pub(crate) const UCB0RXBUF: u16 = 0x006e;
+pub(crate) const UCB0TXBUF: u16 = 0x006f;
+
+/// UCB0CTL1
+pub(crate) const UCSWRST: u8 = 0x01;
+/// UCB0STAT
+pub(crate) const UCOE: u8 = 0x20;
+/// IFG2, the low bits belong to USCI_A0 and are stored only
+pub(crate) const UCB0RXIFG: u8 = 0x04;
+pub(crate) const UCB0TXIFG: u8 = 0x08;
+
+/// USCI_B0 of the G2xx parts as an SPI master. A byte written to UCB0TXBUF is exchanged at once
+/// (UCBUSY never shows) with the attached devices whose chip select pin is driven low, MISO reads
+/// 0xff without one (several answering at once pull it low together). Clock phase, polarity, bit order and the bit rate are stored only.
+/// There are no interrupts, USCIAB0TX and USCIAB0RX are taken by the console and the UART.
+#[derive(Clone)]
+pub(crate) struct SpiDevice {
+    ctl0: u8,
+    ctl1: u8,
+    br0: u8,
+    br1: u8,
+    stat: u8,
+    rxbuf: u8,
+    ifg2: u8,
+    /// bytes exchanged since the emulator started
+    transferred: u64,
+    pub(crate) sd_card: Option<SdCard>,
+    pub(crate) radio: Option<Radio>,
+}
+
+impl SpiDevice {
+    pub(crate) fn new() -> SpiDevice {
+        return SpiDevice {
+            ctl0: 0x01,
+            ctl1: UCSWRST,
+            br0: 0,
+            br1: 0,
+            stat: 0,
+            rxbuf: 0,
+            ifg2: 0,
+            transferred: 0,
+            sd_card: None,
+            radio: None,
+        };
+    }
+
+    /// The card and radio stay attached, they power up again
+    pub(crate) fn reset(&mut self) {
+        let mut sd_card: Option<SdCard> = self.sd_card.take();
+        if let Some(card) = &mut sd_card {
+            card.reset();
+        }
+        let mut radio: Option<Radio> = self.radio.take();
+        if let Some(radio) = &mut radio {
+            radio.reset();
+        }
+        *self = SpiDevice { transferred: self.transferred, sd_card, radio, ..SpiDevice::new() };
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return address == IFG2 || (UCB0CTL0..=UCB0TXBUF).contains(&address) && address != 0x006c;
+    }
+
+    fn transmit(&mut self, mosi: u8, gpio: &GpioDevice) {
+        if self.ctl1 & UCSWRST != 0 {
+            return;
+        }
+        let mut miso: u8 = 0xff;
+        if let Some(card) = &mut self.sd_card {
+            if gpio.output(card.chip_select) == Some(false) {
+                miso &= card.exchange(mosi);
+            }
+        }
+        if let Some(radio) = &mut self.radio {
+            if gpio.output(radio.pins.csn) == Some(false) {
+                miso &= radio.exchange(mosi);
+            }
+        }
+        if self.ifg2 & UCB0RXIFG != 0 {
+            self.stat |= UCOE;
+        }
+        self.rxbuf = miso;
+        self.ifg2 |= UCB0RXIFG;
+        self.transferred += 1;
+    }
+
+    /// Pins changed or a byte went over the bus: devices see their chip selects go high and drive
+    /// their outputs
+    pub(crate) fn update_pins(&mut self, gpio: &mut GpioDevice) {
+        if let Some(card) = &mut self.sd_card {
+            if gpio.output(card.chip_select) != Some(false) {
+                card.deselect();
+            }
+        }
+        if let Some(radio) = &mut self.radio {
+            radio.update_pins(gpio);
+        }
+    }
+
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let card: String = self.sd_card.as_ref().map(|card| card.describe()).unwrap_or("none".to_string());
+        return vec![
+            RegisterView::byte("UCB0CTL0", UCB0CTL0, self.ctl0),
+            RegisterView::byte("UCB0CTL1", UCB0CTL1, self.ctl1).flag("UCSWRST", UCSWRST as u16),
+            RegisterView::byte("UCB0BR0", UCB0BR0, self.br0),
+            RegisterView::byte("UCB0BR1", UCB0BR1, self.br1),
+            RegisterView::byte("UCB0STAT", UCB0STAT, self.stat).flag("UCOE", UCOE as u16),
+            RegisterView::byte("UCB0RXBUF", UCB0RXBUF, self.rxbuf),
+            RegisterView::byte("IFG2", IFG2, self.read_byte_imut(IFG2))
+                .flag("UCB0RXIFG", UCB0RXIFG as u16).flag("UCB0TXIFG", UCB0TXIFG as u16)
+                .field("transferred", self.transferred.to_string())
+                .field("SD card", card)
+                .field("radio", self.radio.as_ref().map(|radio| radio.describe()).unwrap_or("none".to_string())),
+        ];
+    }
+
+    fn read_byte_imut(&self, address: u16) -> u8 {
+        return match address {
+            IFG2 => self.ifg2 | if self.ctl1 & UCSWRST == 0 {UCB0TXIFG} else {0},
+            UCB0CTL0 => self.ctl0,
+            UCB0CTL1 => self.ctl1,
+            UCB0BR0 => self.br0,
+            UCB0BR1 => self.br1,
+            UCB0STAT => self.stat,
+            UCB0RXBUF => self.rxbuf,
+            _ => 0,
+        };
+    }
+
+    /// Reading UCB0RXBUF clears UCB0RXIFG and UCOE
+    pub(crate) fn read_byte(&mut self, address: u16) -> u8 {
+        let value: u8 = self.read_byte_imut(address);
+        if address == UCB0RXBUF {
+            self.ifg2 &= !UCB0RXIFG;
+            self.stat &= !UCOE;
+        }
+        return value;
+    }
+
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8, gpio: &GpioDevice) {
+        match address {
+            IFG2 => self.ifg2 = value & !UCB0TXIFG,
+            UCB0CTL0 => self.ctl0 = value,
+            UCB0CTL1 => {
+                self.ctl1 = value;
+                if value & UCSWRST != 0 {
+                    self.ifg2 &= !UCB0RXIFG;
+                    self.stat &= !UCOE;
+                }
+            },
+            UCB0BR0 => self.br0 = value,
+            UCB0BR1 => self.br1 = value,
+            UCB0STAT => self.stat = (self.stat & UCOE) | (value & !UCOE),
+            UCB0TXBUF => self.transmit(value, gpio),
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for SpiDevice {
+    fn claims(&self, address: u16) -> bool {
+        return SpiDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::read_bytes(address, word, |address| self.read_byte(address));
+    }
+
+    /// The pins follow once the whole access is done
+    fn write(&mut self, address: u16, value: u16, word: bool, bus: &mut BusAccess) {
+        let gpio: &mut GpioDevice = bus.gpio.as_deref_mut().expect("the bus drives the ports");
+        bus::write_bytes(address, value, word, |address, value| self.write_byte(address, value, gpio));
+        self.update_pins(gpio);
+    }
+}
+
+impl SnapshotState for SpiDevice {
+    const TAG: [u8; 4] = *b"SPI ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for register in [self.ctl0, self.ctl1, self.br0, self.br1, self.stat, self.rxbuf, self.ifg2] {
+            w.u8(register);
+        }
+        w.u64(self.transferred);
+        w.bool(self.sd_card.is_some());
+        if let Some(card) = &self.sd_card {
+            w.nested(|w| card.save(w));
+        }
+        w.bool(self.radio.is_some());
+        if let Some(radio) = &self.radio {
+            w.nested(|w| radio.save(w));
+        }
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for register in [&mut self.ctl0, &mut self.ctl1, &mut self.br0, &mut self.br1, &mut self.stat,
+                         &mut self.rxbuf, &mut self.ifg2] {
+            *register = r.u8();
+        }
+        self.transferred = r.u64();
+        // the card and radio are the ones of this run (`--sd-card`, `--radio`), saved ones only
+        // bring their state
+        if r.bool() {
+            let mut nested: Reader = r.nested();
+            if let Some(card) = &mut self.sd_card {
+                card.restore(&mut nested);
+            }
+        }
+        if r.bool() {
+            let mut nested: Reader = r.nested();
+            if let Some(radio) = &mut self.radio {
+                radio.restore(&mut nested);
+            }
+        }
+    }
+}
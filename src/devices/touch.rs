@@ -0,0 +1,242 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::bus::{BusAccess, BusDevice};
+use crate::clock::Clock;
+use crate::devices::gpio::{GpioDevice, PinId, PORT_COUNT};
+use crate::peripherals::{pins, RegisterView};
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const P1SEL2: u16 = 0x0041;
+pub(crate) const P2SEL2: u16 = 0x0042;
+pub(crate) const TOUCH_COUNT: u16 = 0x01d4;
+/// memory is big-endian, so the low byte comes second
+const TOUCH_COUNT_L: u16 = 0x01d5;
+
+/// Capacitance of a pad nobody touches, in femtofarads
+pub(crate) const DEFAULT_PAD_FF: u32 = 10_000;
+/// Pin oscillator frequency times capacitance (Hz * fF), about 1.5 MHz for an untouched pad
+const OSCILLATOR_HZ_FF: u128 = 1_500_000 * DEFAULT_PAD_FF as u128;
+
+/// Capacitance on a pin, `P2.0:12.5` in pF (`run --touch-pad`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct TouchPad {
+    pub(crate) pin: PinId,
+    pub(crate) femtofarads: u32,
+}
+
+impl FromStr for TouchPad {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pin, pf) = s.split_once(':').ok_or(format!("'{}' is not a touch pad (expected e.g. P2.0:12.5)", s))?;
+        let pf: f64 = pf.trim().parse().map_err(|_| format!("Invalid capacitance in '{}'", s))?;
+        if !(0.001..=65.0).contains(&pf) {
+            return Err(format!("Capacitance in '{}' is out of range (0.001 - 65 pF)", s));
+        }
+        return Ok(TouchPad { pin: pin.parse()?, femtofarads: (pf * 1000.0).round() as u32 });
+    }
+}
+
+impl fmt::Display for TouchPad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}:{}", self.pin, self.femtofarads as f64 / 1000.0);
+    }
+}
+
+/// Pin oscillators of the G2xx capacitive touch I/O. A pin with PxSEL2 set and PxSEL clear
+/// oscillates at a frequency inversely proportional to the capacitance on it, which the host sets
+/// per pin. On hardware Timer_A counts the oscillations (INCLK), there is no Timer_A here so the
+/// emulator-defined TOUCH_COUNT counts them instead.
+#[derive(Clone)]
+pub(crate) struct TouchDevice {
+    sel2: [u8; PORT_COUNT],
+    femtofarads: [[u32; 8]; PORT_COUNT],
+    /// the oscillating pin, the lowest one if firmware enabled several
+    active: Option<PinId>,
+    /// TOUCH_COUNT at cycle `since`
+    base: u64,
+    since: u64,
+}
+
+impl TouchDevice {
+    pub(crate) fn new() -> TouchDevice {
+        return TouchDevice {
+            sel2: [0; PORT_COUNT],
+            femtofarads: [[DEFAULT_PAD_FF; 8]; PORT_COUNT],
+            active: None,
+            base: 0,
+            since: 0,
+        };
+    }
+
+    /// The pads keep their capacitance, it isn't something a reset changes
+    pub(crate) fn reset(&mut self) {
+        let femtofarads = self.femtofarads;
+        *self = TouchDevice::new();
+        self.femtofarads = femtofarads;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (P1SEL2..=P2SEL2).contains(&address) || (TOUCH_COUNT..=TOUCH_COUNT + 1).contains(&address);
+    }
+
+    pub(crate) fn set_pad(&mut self, pad: TouchPad, gpio: &GpioDevice, clock: &Clock) {
+        self.rebase(clock);
+        self.femtofarads[pad.pin.port as usize - 1][pad.pin.pin as usize] = pad.femtofarads.max(1);
+        self.update(gpio, clock);
+    }
+
+    pub(crate) fn pad(&self, pin: PinId) -> TouchPad {
+        return TouchPad { pin, femtofarads: self.femtofarads[pin.port as usize - 1][pin.pin as usize] };
+    }
+
+    /// Oscillation frequency of a pin in pin oscillator mode
+    pub(crate) fn frequency_hz(&self, pin: PinId) -> u64 {
+        return (OSCILLATOR_HZ_FF / self.pad(pin).femtofarads as u128) as u64;
+    }
+
+    fn count(&self, clock: &Clock) -> u64 {
+        let oscillations: u128 = match self.active {
+            Some(pin) => clock.cycles().saturating_sub(self.since) as u128 * self.frequency_hz(pin) as u128 / clock.mclk_hz() as u128,
+            None => 0,
+        };
+        return self.base + oscillations as u64;
+    }
+
+    /// Fold the oscillations so far into `base`, before anything changes the frequency
+    fn rebase(&mut self, clock: &Clock) {
+        self.base = self.count(clock);
+        self.since = clock.cycles();
+    }
+
+    /// Pick the oscillating pin again, after PxSEL or PxSEL2 changed
+    pub(crate) fn update(&mut self, gpio: &GpioDevice, clock: &Clock) {
+        self.rebase(clock);
+        self.active = (0..PORT_COUNT).flat_map(|port| (0..8).map(move |pin| PinId { port: port as u8 + 1, pin }))
+            .find(|&pin| self.sel2[pin.port as usize - 1] & (1 << pin.pin) != 0 && !gpio.selected(pin));
+    }
+
+    pub(crate) fn registers(&self, clock: &Clock) -> Vec<RegisterView> {
+        let mut registers: Vec<RegisterView> = (0..PORT_COUNT).map(|port| {
+            RegisterView::byte(&format!("P{}SEL2", port + 1), P1SEL2 + port as u16, self.sel2[port])
+                .field("selected", pins(port + 1, self.sel2[port]))
+        }).collect();
+        let mut count: RegisterView = RegisterView::word("TOUCH_COUNT", TOUCH_COUNT, (self.count(clock) & 0xffff) as u16);
+        if let Some(pin) = self.active {
+            count = count.field("pin", pin.to_string()).field("pad", format!("{}pF", self.pad(pin).femtofarads as f64 / 1000.0))
+                .field("frequency", format!("{}Hz", self.frequency_hz(pin)));
+        }
+        registers.push(count);
+        return registers;
+    }
+
+    /// TOUCH_COUNT follows the emulator-defined device rules: a byte read returns its low byte,
+    /// a byte write acts as a word write of the zero-extended byte
+    pub(crate) fn read_byte(&self, address: u16, clock: &Clock) -> u8 {
+        return match address {
+            P1SEL2 | P2SEL2 => self.sel2[(address - P1SEL2) as usize],
+            TOUCH_COUNT | TOUCH_COUNT_L => (self.count(clock) & 0xff) as u8,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8, gpio: &GpioDevice, clock: &Clock) {
+        match address {
+            P1SEL2 | P2SEL2 => {
+                self.sel2[(address - P1SEL2) as usize] = value;
+                self.update(gpio, clock);
+            },
+            TOUCH_COUNT | TOUCH_COUNT_L => self.write_word(TOUCH_COUNT, value as u16, gpio, clock),
+            _ => {},
+        }
+    }
+
+    pub(crate) fn read_word(&self, address: u16, clock: &Clock) -> u16 {
+        return match address {
+            TOUCH_COUNT => (self.count(clock) & 0xffff) as u16,
+            _ => ((self.read_byte(address, clock) as u16) << 8) | self.read_byte(address + 1, clock) as u16,
+        };
+    }
+
+    /// Writing TOUCH_COUNT sets it, usually to 0 before a measurement
+    pub(crate) fn write_word(&mut self, address: u16, value: u16, gpio: &GpioDevice, clock: &Clock) {
+        match address {
+            TOUCH_COUNT => {
+                self.base = value as u64;
+                self.since = clock.cycles();
+            },
+            _ => {
+                self.write_byte(address, (value >> 8) as u8, gpio, clock);
+                self.write_byte(address + 1, (value & 0xff) as u8, gpio, clock);
+            },
+        }
+    }
+}
+
+impl BusDevice for TouchDevice {
+    fn claims(&self, address: u16) -> bool {
+        return TouchDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, bus: &mut BusAccess) -> u16 {
+        return if word {self.read_word(address, bus.clock)} else {self.read_byte(address, bus.clock) as u16};
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, bus: &mut BusAccess) {
+        let gpio: &GpioDevice = bus.gpio.as_deref().expect("the pads sense the ports");
+        if word {
+            self.write_word(address, value, gpio, bus.clock);
+        } else {
+            self.write_byte(address, value as u8, gpio, bus.clock);
+        }
+    }
+}
+
+/// Pad capacitances are configuration (`--touch`) and not saved
+impl SnapshotState for TouchDevice {
+    const TAG: [u8; 4] = *b"TOUC";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        for sel2 in self.sel2 {
+            w.u8(sel2);
+        }
+        w.bool(self.active.is_some());
+        if let Some(pin) = self.active {
+            w.u8(pin.port);
+            w.u8(pin.pin);
+        }
+        w.u64(self.base);
+        w.u64(self.since);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        for sel2 in &mut self.sel2 {
+            *sel2 = r.u8();
+        }
+        self.active = match r.bool() {
+            true => Some(PinId { port: r.u8(), pin: r.u8() }),
+            false => None,
+        };
+        self.base = r.u64();
+        self.since = r.u64();
+    }
+}
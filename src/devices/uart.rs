@@ -0,0 +1,175 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+pub(crate) const UART_TX: u16 = 0x01c0;
+pub(crate) const UART_RX: u16 = 0x01c2;
+pub(crate) const UART_STATUS: u16 = 0x01c4;
+pub(crate) const UART_CTL: u16 = 0x01c6;
+
+pub(crate) const STATUS_RX_AVAILABLE: u16 = 0x0001;
+pub(crate) const STATUS_TX_READY: u16 = 0x0002;
+pub(crate) const CTL_RX_INTERRUPT: u16 = 0x0001;
+
+/// USCIAB0RX on the 2xx parts
+pub(crate) const UART_RX_VECTOR: u16 = 0xffee;
+
+/// Byte-oriented serial port. Transmitted bytes queue up until whatever the UART is wired to
+/// (another instance, a socket, a file) takes them, received bytes queue up until firmware reads them.
+#[derive(Clone)]
+pub(crate) struct UartDevice {
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    ctl: u16,
+    /// bytes firmware transmitted since the emulator started, and the last of them
+    transmitted: u64,
+    last_tx: u8,
+}
+
+#[allow(dead_code)]
+impl UartDevice {
+    pub(crate) fn new() -> UartDevice {
+        return UartDevice {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            ctl: 0,
+            transmitted: 0,
+            last_tx: 0,
+        };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.rx.clear();
+        self.tx.clear();
+        self.ctl = 0;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (UART_TX..=UART_CTL).contains(&address);
+    }
+
+    /// Queue bytes for firmware to receive
+    pub(crate) fn receive(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    #[inline]
+    pub(crate) fn has_tx(&self) -> bool {
+        return !self.tx.is_empty();
+    }
+
+    /// Bytes transmitted so far (resets don't clear it) and the last one
+    pub(crate) fn transmitted(&self) -> (u64, u8) {
+        return (self.transmitted, self.last_tx);
+    }
+
+    /// Take everything firmware transmitted since the last call
+    pub(crate) fn take_tx(&mut self) -> Vec<u8> {
+        return self.tx.drain(..).collect();
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "UART RX",
+            vector: UART_RX_VECTOR,
+            nmi: false,
+            enabled: self.ctl & CTL_RX_INTERRUPT != 0,
+            flagged: !self.rx.is_empty(),
+        };
+    }
+
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        if self.ctl & CTL_RX_INTERRUPT != 0 && !self.rx.is_empty() {
+            return Some(UART_RX_VECTOR);
+        }
+        return None;
+    }
+
+    /// UART_TX and UART_RX are left out, reading RX would take a byte
+    pub(crate) fn registers(&self) -> Vec<RegisterView> {
+        let status: u16 = STATUS_TX_READY | if self.rx.is_empty() {0} else {STATUS_RX_AVAILABLE};
+        return vec![
+            RegisterView::word("UART_STATUS", UART_STATUS, status)
+                .flag("RXAVAIL", STATUS_RX_AVAILABLE).flag("TXREADY", STATUS_TX_READY)
+                .field("rx_queued", self.rx.len().to_string()).field("tx_queued", self.tx.len().to_string()),
+            RegisterView::word("UART_CTL", UART_CTL, self.ctl).flag("RXIE", CTL_RX_INTERRUPT),
+        ];
+    }
+
+    pub(crate) fn read_word(&mut self, address: u16) -> u16 {
+        return match address {
+            UART_RX => self.rx.pop_front().unwrap_or(0) as u16,
+            UART_STATUS => STATUS_TX_READY | if self.rx.is_empty() {0} else {STATUS_RX_AVAILABLE},
+            UART_CTL => self.ctl,
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16) {
+        match address {
+            UART_TX => {
+                self.tx.push_back((value & 0xff) as u8);
+                self.transmitted += 1;
+                self.last_tx = (value & 0xff) as u8;
+            },
+            UART_CTL => self.ctl = value,
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for UartDevice {
+    fn claims(&self, address: u16) -> bool {
+        return UartDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::word_register(self.read_word(address & 0xfffe), word);
+    }
+
+    fn write(&mut self, address: u16, value: u16, _word: bool, _bus: &mut BusAccess) {
+        self.write_word(address & 0xfffe, value);
+    }
+}
+
+impl SnapshotState for UartDevice {
+    const TAG: [u8; 4] = *b"UART";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.ctl);
+        w.bytes(&self.rx.iter().copied().collect::<Vec<u8>>());
+        w.bytes(&self.tx.iter().copied().collect::<Vec<u8>>());
+        w.u64(self.transmitted);
+        w.u8(self.last_tx);
+    }
+
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.ctl = r.u16();
+        self.rx = r.bytes().into();
+        self.tx = r.bytes().into();
+        self.transmitted = r.u64();
+        self.last_tx = r.u8();
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::bus::{self, BusAccess, BusDevice};
+use crate::clock::{Clock, ACLK_HZ};
+use crate::interrupts::InterruptSource;
+use crate::peripherals::RegisterView;
+use crate::snapshot::{Reader, SnapshotState, Writer};
+
+// special function registers of the G2xx parts, byte registers
+pub(crate) const IE1: u16 = 0x0000;
+pub(crate) const IE2: u16 = 0x0001;
+pub(crate) const IFG1: u16 = 0x0002;
+pub(crate) const WDTCTL: u16 = 0x0120;
+
+// IE1/IFG1, the other bits are stored only
+pub(crate) const WDTIE: u8 = 0x01;
+pub(crate) const WDTIFG: u8 = 0x01;
+
+/// high byte of a WDTCTL write, anything else (or a byte write) is a PUC
+pub(crate) const WDTPW: u16 = 0x5a00;
+/// what the high byte of WDTCTL reads as
+const WDTPW_READ: u16 = 0x6900;
+
+// WDTCTL, WDTNMIES and WDTNMI (bits 6-5) are stored only
+pub(crate) const WDTHOLD: u8 = 0x80;
+/// interval timer mode instead of watchdog mode
+pub(crate) const WDTTMSEL: u8 = 0x10;
+/// write only, clears the counter
+pub(crate) const WDTCNTCL: u8 = 0x08;
+/// count ACLK instead of SMCLK
+pub(crate) const WDTSSEL: u8 = 0x04;
+const WDTIS: u8 = 0x03;
+
+/// clock ticks until the counter expires, by WDTIS
+const INTERVALS: [u64; 4] = [32768, 8192, 512, 64];
+/// WDTCNT is 16 bits, every interval divides its range
+const COUNTER_RANGE: u64 = 0x10000;
+
+/// WDT interval timer
+pub(crate) const WDT_VECTOR: u16 = 0xfff4;
+
+/// Watchdog timer+ of the G2xx parts, with IE1, IE2 and IFG1 that hold its interrupt bits.
+/// WDTCTL is password protected: word writes must carry WDTPW in the high byte, anything else
+/// (byte writes included) requests a PUC. The 16-bit counter counts SMCLK or ACLK unless held and
+/// wraps around, it expires whenever it passes a multiple of the interval: in watchdog mode that
+/// requests a PUC, in interval timer mode it sets WDTIFG. Only WDTCNTCL and a PUC clear it, changing
+/// the interval doesn't. Counting is worked out from the clock when asked, the CPU only polls the
+/// device once `deadline` is reached.
+#[derive(Clone)]
+pub(crate) struct WdtDevice {
+    /// low byte of WDTCTL, WDTCNTCL reads as 0
+    ctl: u8,
+    ie1: u8,
+    ie2: u8,
+    ifg1: u8,
+    /// WDTCNT at `since`, counting ticks of the selected clock
+    counted: u64,
+    since: u64,
+    /// cycle before which the counter can't expire, `u64::MAX` while held
+    deadline: u64,
+    puc_requested: bool,
+    /// whether WDTCTL comes out of a reset running, as on every part, or held (`run --hold-watchdog`)
+    armed: bool,
+}
+
+impl WdtDevice {
+    pub(crate) fn new() -> WdtDevice {
+        return WdtDevice {
+            ctl: 0,
+            ie1: 0,
+            ie2: 0,
+            ifg1: 0,
+            counted: 0,
+            since: 0,
+            deadline: 0,
+            puc_requested: false,
+            armed: true,
+        };
+    }
+
+    /// Everything starts over, including WDTIFG
+    pub(crate) fn reset(&mut self) {
+        let armed: bool = self.armed;
+        *self = WdtDevice::new();
+        if !armed {
+            self.hold();
+        }
+    }
+
+    /// Held from the start, as if firmware wrote WDTPW | WDTHOLD
+    fn hold(&mut self) {
+        self.armed = false;
+        self.ctl = WDTHOLD;
+        self.deadline = u64::MAX;
+    }
+
+    /// WDTCTL and the counter start over on a PUC, the interrupt registers are kept
+    pub(crate) fn puc(&mut self, clock: &Clock) {
+        self.ctl = if self.armed {0} else {WDTHOLD};
+        self.ie1 &= !WDTIE;
+        self.counted = 0;
+        self.since = self.ticks(clock);
+        self.puc_requested = false;
+        self.schedule(clock);
+    }
+
+    /// Come out of a reset held instead of running like the watchdog of a real part does
+    pub(crate) fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+    }
+
+    pub(crate) fn claims(address: u16) -> bool {
+        return (IE1..=IFG1).contains(&address) || address == WDTCTL || address == WDTCTL + 1;
+    }
+
+    fn held(&self) -> bool {
+        return self.ctl & WDTHOLD != 0;
+    }
+
+    fn interval(&self) -> u64 {
+        return INTERVALS[(self.ctl & WDTIS) as usize];
+    }
+
+    /// Ticks of the selected clock since reset
+    fn ticks(&self, clock: &Clock) -> u64 {
+        return if self.ctl & WDTSSEL != 0 {clock.aclk_ticks(ACLK_HZ)} else {clock.smclk_ticks()};
+    }
+
+    /// Cycle the CPU has to poll `update` at, its earliest chance to expire
+    #[inline]
+    pub(crate) fn deadline(&self) -> u64 {
+        return self.deadline;
+    }
+
+    /// Bring the counter up to date, handling every expiry since the last call
+    pub(crate) fn update(&mut self, clock: &Clock) {
+        let now: u64 = self.ticks(clock);
+        let before: u64 = self.counted;
+        if !self.held() {
+            self.counted += now.saturating_sub(self.since);
+        }
+        self.since = now;
+        if self.counted / self.interval() > before / self.interval() {
+            self.ifg1 |= WDTIFG;
+            if self.ctl & WDTTMSEL == 0 {
+                self.puc_requested = true;
+            }
+        }
+        self.counted %= COUNTER_RANGE;
+        self.schedule(clock);
+    }
+
+    /// An SMCLK tick is at most a cycle, an ACLK tick at least as many cycles as MCLK is faster.
+    /// Either may come late (a stopped clock, host time), `update` is then polled again.
+    fn schedule(&mut self, clock: &Clock) {
+        if self.held() {
+            self.deadline = u64::MAX;
+            return;
+        }
+        let remaining: u64 = self.interval() - self.counted % self.interval();
+        let cycles: u64 = if self.ctl & WDTSSEL != 0 {remaining * clock.mclk_hz() / ACLK_HZ} else {remaining};
+        self.deadline = clock.cycles() + cycles.max(1);
+    }
+
+    /// WDTCNT as it is now, for display
+    fn count(&self, clock: &Clock) -> u64 {
+        let running: u64 = if self.held() {0} else {self.ticks(clock).saturating_sub(self.since)};
+        return (self.counted + running) % COUNTER_RANGE;
+    }
+
+    #[inline]
+    pub(crate) fn pending_interrupt(&self) -> Option<u16> {
+        if self.ie1 & WDTIE != 0 && self.ifg1 & WDTIFG != 0 && self.ctl & WDTTMSEL != 0 {
+            return Some(WDT_VECTOR);
+        }
+        return None;
+    }
+
+    /// WDTIFG clears itself when the CPU takes the interval interrupt
+    pub(crate) fn accepted(&mut self) {
+        self.ifg1 &= !WDTIFG;
+    }
+
+    pub(crate) fn interrupt_source(&self) -> InterruptSource {
+        return InterruptSource {
+            name: "watchdog interval",
+            vector: WDT_VECTOR,
+            nmi: false,
+            enabled: self.ie1 & WDTIE != 0 && self.ctl & WDTTMSEL != 0,
+            flagged: self.ifg1 & WDTIFG != 0,
+        };
+    }
+
+    /// Whether a PUC was requested since the last call
+    pub(crate) fn take_puc(&mut self) -> bool {
+        return std::mem::replace(&mut self.puc_requested, false);
+    }
+
+    pub(crate) fn registers(&self, clock: &Clock) -> Vec<RegisterView> {
+        let mode: &str = if self.held() {"held"} else if self.ctl & WDTTMSEL != 0 {"interval"} else {"watchdog"};
+        return vec![
+            RegisterView::byte("IE1", IE1, self.ie1).flag("WDTIE", WDTIE as u16),
+            RegisterView::byte("IFG1", IFG1, self.ifg1).flag("WDTIFG", WDTIFG as u16),
+            RegisterView::word("WDTCTL", WDTCTL, WDTPW_READ | self.ctl as u16)
+                .flag("WDTHOLD", WDTHOLD as u16).flag("WDTTMSEL", WDTTMSEL as u16).flag("WDTSSEL", WDTSSEL as u16)
+                .field("mode", mode.to_string())
+                .field("count", format!("{}/{}", self.count(clock), self.interval())),
+        ];
+    }
+
+    /// WDTCTL reads as two bytes, the high one (at 0x0120, memory is big-endian) always 0x69
+    pub(crate) fn read_byte(&self, address: u16) -> u8 {
+        return match address {
+            IE1 => self.ie1,
+            IE2 => self.ie2,
+            IFG1 => self.ifg1,
+            WDTCTL => (WDTPW_READ >> 8) as u8,
+            _ if address == WDTCTL + 1 => self.ctl,
+            _ => 0,
+        };
+    }
+
+    /// A byte write to WDTCTL carries no password, it is a PUC like on the real part
+    pub(crate) fn write_byte(&mut self, address: u16, value: u8, clock: &Clock) {
+        self.update(clock);
+        match address {
+            IE1 => self.ie1 = value,
+            IE2 => self.ie2 = value,
+            IFG1 => self.ifg1 = value,
+            _ => self.violation(),
+        }
+    }
+
+    pub(crate) fn write_word(&mut self, address: u16, value: u16, clock: &Clock) {
+        if address != WDTCTL {
+            self.write_byte(address, (value >> 8) as u8, clock);
+            self.write_byte(address + 1, (value & 0xff) as u8, clock);
+            return;
+        }
+        if value & 0xff00 != WDTPW {
+            self.update(clock);
+            self.violation();
+            return;
+        }
+        self.update(clock); // counts up to now with the old settings
+        let ctl: u8 = (value & 0xff) as u8;
+        if ctl & WDTCNTCL != 0 {
+            self.counted = 0;
+        }
+        self.ctl = ctl & !WDTCNTCL;
+        self.since = self.ticks(clock);
+        self.schedule(clock);
+    }
+
+    /// A security key violation: WDTIFG and a PUC, in either mode
+    fn violation(&mut self) {
+        self.ifg1 |= WDTIFG;
+        self.puc_requested = true;
+    }
+}
+
+impl BusDevice for WdtDevice {
+    fn claims(&self, address: u16) -> bool {
+        return WdtDevice::claims(address);
+    }
+
+    fn read(&mut self, address: u16, word: bool, _bus: &mut BusAccess) -> u16 {
+        return bus::read_bytes(address, word, |address| self.read_byte(address));
+    }
+
+    fn write(&mut self, address: u16, value: u16, word: bool, bus: &mut BusAccess) {
+        if word {
+            self.write_word(address, value, bus.clock);
+        } else {
+            self.write_byte(address, value as u8, bus.clock);
+        }
+    }
+}
+
+impl SnapshotState for WdtDevice {
+    const TAG: [u8; 4] = *b"WDT ";
+    const VERSION: u16 = 1;
+
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.ctl);
+        w.u8(self.ie1);
+        w.u8(self.ie2);
+        w.u8(self.ifg1);
+        w.u64(self.counted);
+        w.u64(self.since);
+        w.u64(self.deadline);
+        w.bool(self.puc_requested);
+    }
+
+    /// `armed` is configuration, it stays as the profile set it
+    fn restore(&mut self, _version: u16, r: &mut Reader) {
+        self.ctl = r.u8();
+        self.ie1 = r.u8();
+        self.ie2 = r.u8();
+        self.ifg1 = r.u8();
+        self.counted = r.u64();
+        self.since = r.u64();
+        self.deadline = r.u64();
+        self.puc_requested = r.bool();
+    }
+}
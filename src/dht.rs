@@ -0,0 +1,169 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::sensors::{cycles_for, micros, Transaction};
+
+/// Delay between the end of the start pulse and the sensor's response
+const RESPONSE_DELAY_US: u64 = 30;
+/// The response is this long low, then this long high
+const RESPONSE_US: u64 = 80;
+/// Every bit starts low for this long, then stays high for one of the other two
+const BIT_LOW_US: u64 = 50;
+const ZERO_HIGH_US: u64 = 26;
+const ONE_HIGH_US: u64 = 70;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum DhtKind {
+    Dht11,
+    Dht22,
+}
+
+impl DhtKind {
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            DhtKind::Dht11 => "DHT11",
+            DhtKind::Dht22 => "DHT22",
+        };
+    }
+
+    /// The shortest start pulse the sensor answers
+    fn start_us(&self) -> u64 {
+        return match self {
+            DhtKind::Dht11 => 18_000,
+            DhtKind::Dht22 => 800,
+        };
+    }
+}
+
+/// A DHT11/DHT22 humidity and temperature sensor on a single pin: a low pulse from the firmware
+/// starts a reading, the sensor answers with 40 bits told apart by how long the pin stays high
+#[derive(Clone)]
+pub(crate) struct Dht {
+    pub(crate) pin: PinId,
+    pub(crate) kind: DhtKind,
+    pub(crate) celsius: f64,
+    /// relative humidity, %
+    pub(crate) humidity: f64,
+    master_low: bool,
+    /// when the firmware last pulled the pin low
+    fell: u64,
+    /// level driven onto the pin last time, `None` before the first sample
+    driven: Option<bool>,
+    /// levels of the answer being sent and the cycles they start at
+    answer: Vec<(u64, bool)>,
+    next: usize,
+}
+
+impl Dht {
+    pub(crate) fn new(pin: PinId, kind: DhtKind, celsius: f64, humidity: f64) -> Dht {
+        return Dht { pin, kind, celsius, humidity, master_low: false, fell: 0, driven: None, answer: Vec::new(), next: 0 };
+    }
+
+    /// Stop answering, the pin is pulled up again on the next sample
+    pub(crate) fn reset(&mut self) {
+        *self = Dht::new(self.pin, self.kind, self.celsius, self.humidity);
+    }
+
+    /// Humidity, humidity decimals, temperature, temperature decimals and the checksum
+    pub(crate) fn reading(&self) -> [u8; 5] {
+        let [h0, h1, t0, t1]: [u8; 4] = match self.kind {
+            DhtKind::Dht11 => {
+                let celsius: f64 = self.celsius.clamp(0.0, 50.0);
+                [self.humidity.clamp(0.0, 100.0).round() as u8, 0, celsius.trunc() as u8, (celsius.fract() * 10.0).round() as u8]
+            },
+            DhtKind::Dht22 => {
+                // tenths, the temperature as sign and magnitude
+                let humidity: u16 = (self.humidity.clamp(0.0, 100.0) * 10.0).round() as u16;
+                let celsius: u16 = (self.celsius.clamp(-40.0, 80.0).abs() * 10.0).round() as u16
+                    | if self.celsius < 0.0 {0x8000} else {0};
+                let [h0, h1] = humidity.to_be_bytes();
+                let [t0, t1] = celsius.to_be_bytes();
+                [h0, h1, t0, t1]
+            },
+        };
+        return [h0, h1, t0, t1, h0.wrapping_add(h1).wrapping_add(t0).wrapping_add(t1)];
+    }
+
+    fn describe(&self, reading: &[u8; 5]) -> String {
+        return match self.kind {
+            DhtKind::Dht11 => format!("{}% {}.{} °C", reading[0], reading[2], reading[3]),
+            DhtKind::Dht22 => {
+                let celsius: f64 = (u16::from_be_bytes([reading[2] & 0x7f, reading[3]]) as f64) / 10.0;
+                format!("{:.1}% {:.1} °C", u16::from_be_bytes([reading[0], reading[1]]) as f64 / 10.0,
+                        if reading[2] & 0x80 != 0 {-celsius} else {celsius})
+            },
+        };
+    }
+
+    /// The levels of an answer starting at `start`
+    fn answer(&self, start: u64, mclk_hz: u64, reading: &[u8; 5]) -> Vec<(u64, bool)> {
+        let mut answer: Vec<(u64, bool)> = Vec::new();
+        let mut at: u64 = RESPONSE_DELAY_US;
+        let mut level = |us: u64, high: bool| {
+            answer.push((start + cycles_for(at, mclk_hz), high));
+            at += us;
+        };
+        level(RESPONSE_US, false);
+        level(RESPONSE_US, true);
+        for bit in 0..40 {
+            let one: bool = (reading[bit / 8] >> (7 - bit % 8)) & 1 != 0;
+            level(BIT_LOW_US, false);
+            level(if one {ONE_HIGH_US} else {ZERO_HIGH_US}, true);
+        }
+        level(BIT_LOW_US, false);
+        level(0, true);
+        return answer;
+    }
+
+    pub(crate) fn sample(&mut self, now: u64, mclk_hz: u64, gpio: &mut GpioDevice, log: &mut Vec<Transaction>) {
+        let master_low: bool = gpio.output(self.pin) == Some(false);
+        if master_low && !self.master_low {
+            if self.next < self.answer.len() {
+                log.push(self.transaction(format!("answer cut off after {} of 40 bits", self.next.saturating_sub(2) / 2)));
+            }
+            self.answer.clear();
+            self.next = 0;
+            self.fell = now;
+        } else if !master_low && self.master_low {
+            let low: u64 = micros(now - self.fell, mclk_hz);
+            if low >= self.kind.start_us() {
+                let reading: [u8; 5] = self.reading();
+                self.answer = self.answer(now, mclk_hz, &reading);
+                log.push(self.transaction(format!("start ({} µs low), answered {} ({})", low, self.describe(&reading),
+                                                  reading.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" "))));
+            } else {
+                log.push(self.transaction(format!("start pulse too short ({} µs, needs {} µs), ignored", low, self.kind.start_us())));
+            }
+        }
+        self.master_low = master_low;
+
+        while self.answer.get(self.next).is_some_and(|(at, _)| *at <= now) {
+            self.next += 1;
+        }
+        let high: bool = self.next == 0 || self.answer[self.next - 1].1;
+        if self.driven != Some(high) {
+            gpio.set_input(self.pin, Some(high));
+            self.driven = Some(high);
+        }
+    }
+
+    fn transaction(&self, text: String) -> Transaction {
+        return Transaction { pin: self.pin, protocol: self.kind.name(), text };
+    }
+}
@@ -0,0 +1,284 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use clap::ValueEnum;
+use crate::decode::Decoded;
+use crate::StatusFlags;
+
+// Things firmware gets away with here that would behave differently on hardware. Each category has
+// a severity, from the emulation mode's preset or `run --check CATEGORY[=SEVERITY]`. Raised
+// diagnostics are logged as warnings and pushed as notifications (shared_memory_protocol.txt,
+// command 33), `break` also stops the run, `reset` restarts the CPU and `trap` faults it instead.
+
+/// `mov #0 r3`, the one write to the constant generator firmware means to make
+const NOP: u16 = 0x4303;
+/// SR bits 9-15 have no function
+pub(crate) const SR_RESERVED: u16 = 0xfe00;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, clap::ValueEnum)]
+pub enum Category {
+    /// Word reads and writes at odd addresses, the hardware ignores bit 0
+    OddAddress,
+    /// Data writes to the profile's flash, which needs the flash controller unlocked first (not
+    /// emulated, so nothing can have unlocked it)
+    FlashWrite,
+    /// Reads in the profile's peripheral space that no emulated device answers
+    Unimplemented,
+    /// Writes to r3 (the constant generator), which are discarded, except `nop`
+    CgWrite,
+    /// Setting SR's reserved bits (9-15)
+    SrReserved,
+    /// A handler clearing GIE in the SR it returns to, interrupts stay off after its RETI
+    GieInIsr,
+    /// Instruction words the MSP430 CPU doesn't define (MSP430X ones included), executed as nothing
+    IllegalInstruction,
+    /// Data writes to read-only memory (the profile's ROM, `run --read-only`), which are dropped
+    ReadOnlyWrite,
+}
+
+const CATEGORIES: usize = 8;
+
+impl Category {
+    /// For notifications, counts from 1 in declaration order
+    pub(crate) fn id(&self) -> u16 {
+        return *self as u16 + 1;
+    }
+
+    /// As given to `run --check`
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            Category::OddAddress => "odd-address",
+            Category::FlashWrite => "flash-write",
+            Category::Unimplemented => "unimplemented",
+            Category::CgWrite => "cg-write",
+            Category::SrReserved => "sr-reserved",
+            Category::GieInIsr => "gie-in-isr",
+            Category::IllegalInstruction => "illegal-instruction",
+            Category::ReadOnlyWrite => "read-only-write",
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, clap::ValueEnum)]
+pub(crate) enum Severity {
+    #[default]
+    Ignore,
+    /// Logged as a warning and notified, once per category and address
+    Log,
+    /// Also stops the run after the instruction
+    Break,
+    /// Logged like log, and a PUC restarts the CPU from the reset vector after the instruction
+    Reset,
+    /// Faults the CPU after the instruction, it stops until a reset
+    Trap,
+}
+
+/// How forgiving the emulator is about firmware relying on undefined or implementation-defined
+/// behaviour, the default severity of every diagnostic category
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, clap::ValueEnum)]
+pub enum EmulationMode {
+    /// Do what the emulator always did, only writes to memory marked read-only are logged (for
+    /// interactive exploration). Illegal instruction words execute as nothing without a fault.
+    #[default]
+    Permissive,
+    /// Fault on anything that would behave differently on hardware (for CI)
+    Strict,
+}
+
+impl EmulationMode {
+    /// Read-only memory is asked for, so writes to it are worth a warning even when permissive
+    fn severity(&self, category: Category) -> Severity {
+        return match self {
+            EmulationMode::Permissive if category == Category::ReadOnlyWrite => Severity::Log,
+            EmulationMode::Permissive => Severity::Ignore,
+            EmulationMode::Strict => Severity::Trap,
+        };
+    }
+}
+
+/// `CATEGORY[=SEVERITY]` for `run --check`, the severity defaults to log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct CheckSpec {
+    pub(crate) category: Category,
+    pub(crate) severity: Severity,
+}
+
+impl FromStr for CheckSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (category, severity) = s.split_once('=').unwrap_or((s, "log"));
+        return Ok(CheckSpec {
+            category: Category::from_str(category.trim(), true)?,
+            severity: Severity::from_str(severity.trim(), true)?,
+        });
+    }
+}
+
+impl fmt::Display for CheckSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}={}", self.category.name(),
+                      self.severity.to_possible_value().expect("No skipped variants").get_name());
+    }
+}
+
+/// A raised diagnostic
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub category: Category,
+    pub pc: u16,
+    /// the address accessed, SR after the instruction for the SR categories, 0 for cg-write, the
+    /// instruction word for illegal-instruction
+    pub value: u16,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.category {
+            Category::OddAddress => write!(f, "{:#06x}: word access at odd address {:#06x}", self.pc, self.value),
+            Category::FlashWrite => write!(f, "{:#06x}: write to flash at {:#06x} without unlocking it", self.pc, self.value),
+            Category::Unimplemented => write!(f, "{:#06x}: read of unimplemented peripheral address {:#06x}", self.pc, self.value),
+            Category::CgWrite => write!(f, "{:#06x}: write to r3 (constant generator) is discarded", self.pc),
+            Category::SrReserved => write!(f, "{:#06x}: reserved SR bits set (SR {:#06x})", self.pc, self.value),
+            Category::GieInIsr => write!(f, "{:#06x}: RETI restored SR {:#06x}, the handler cleared GIE in the saved SR",
+                                         self.pc, self.value),
+            Category::IllegalInstruction => write!(f, "{:#06x}: illegal instruction {:#06x}", self.pc, self.value),
+            Category::ReadOnlyWrite => write!(f, "{:#06x}: write to read-only memory at {:#06x} dropped", self.pc, self.value),
+        };
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Diagnostics {
+    severities: [Severity; CATEGORIES],
+    /// SR saved by each interrupt being serviced, innermost last (gie-in-isr)
+    frames: Vec<u16>,
+    reported: HashSet<(Category, u16)>,
+    pending: Vec<Diagnostic>,
+    /// a diagnostic with severity break was raised since `take_break`
+    break_requested: bool,
+    /// a diagnostic with severity reset was raised since `take_reset`
+    reset_requested: bool,
+    /// the first diagnostic with severity trap since `take_trap`
+    trapped: Option<Diagnostic>,
+}
+
+/// Whether an instruction (`decoded` from `instruction`) writes its result to r3
+fn writes_cg(instruction: u16, decoded: Decoded) -> bool {
+    return match decoded {
+        // RRC, SWPB, RRA and SXT write back, PUSH/CALL/RETI don't
+        Decoded::Single { opcode, reg: 3, as_: 0, .. } => opcode <= 3,
+        // CMP and BIT only set flags
+        Decoded::Double { opcode, ad: 0, dst_reg: 3, .. } => opcode != 5 && opcode != 7 && instruction != NOP,
+        _ => false,
+    };
+}
+
+impl Diagnostics {
+    /// The mode's preset, with `checks` overriding it per category
+    pub(crate) fn new(mode: EmulationMode, checks: &[CheckSpec]) -> Diagnostics {
+        let severities: [Severity; CATEGORIES] = std::array::from_fn(|i| mode.severity(Category::value_variants()[i]));
+        let mut diagnostics: Diagnostics = Diagnostics { severities, ..Diagnostics::default() };
+        for check in checks {
+            diagnostics.severities[check.category as usize] = check.severity;
+        }
+        return diagnostics;
+    }
+
+    #[inline]
+    pub(crate) fn enabled(&self, category: Category) -> bool {
+        return self.severities[category as usize] != Severity::Ignore;
+    }
+
+    /// Whether `retired` has anything to check
+    #[inline]
+    pub(crate) fn checks_instructions(&self) -> bool {
+        return self.enabled(Category::CgWrite) || self.enabled(Category::SrReserved) || self.enabled(Category::GieInIsr);
+    }
+
+    pub(crate) fn raise(&mut self, category: Category, pc: u16, value: u16) {
+        let severity: Severity = self.severities[category as usize];
+        if severity == Severity::Trap {
+            // every time, the fault is what reports it
+            self.trapped.get_or_insert(Diagnostic { category, pc, value });
+            return;
+        }
+        if severity == Severity::Reset {
+            self.reset_requested = true; // every time, it's only logged once
+        }
+        if severity == Severity::Ignore || !self.reported.insert((category, pc)) {
+            return;
+        }
+        self.pending.push(Diagnostic { category, pc, value });
+        if severity == Severity::Break {
+            self.break_requested = true;
+        }
+    }
+
+    /// An interrupt was accepted with SR as it was before
+    pub(crate) fn entered(&mut self, sr: u16) {
+        if self.enabled(Category::GieInIsr) {
+            self.frames.push(sr);
+        }
+    }
+
+    /// After each instruction, `sr_before` is SR before it ran
+    pub(crate) fn retired(&mut self, pc: u16, instruction: u16, decoded: Decoded, sr_before: u16, sr: u16) {
+        if writes_cg(instruction, decoded) {
+            self.raise(Category::CgWrite, pc, 0);
+        }
+        if instruction == crate::stepping::RETI {
+            // restoring reserved bits isn't setting them, that was reported where it happened
+            let saved: Option<u16> = self.frames.pop();
+            let gie: u16 = StatusFlags::GIE.bits();
+            if saved.is_some_and(|saved| saved & gie != 0) && sr & gie == 0 {
+                self.raise(Category::GieInIsr, pc, sr);
+            }
+        } else if sr & SR_RESERVED & !sr_before != 0 {
+            self.raise(Category::SrReserved, pc, sr);
+        }
+    }
+
+    /// Diagnostics raised since the last call
+    pub(crate) fn take(&mut self) -> Vec<Diagnostic> {
+        return std::mem::take(&mut self.pending);
+    }
+
+    /// Whether a diagnostic with severity break was raised since the last call
+    pub(crate) fn take_break(&mut self) -> bool {
+        return std::mem::take(&mut self.break_requested);
+    }
+
+    /// Whether a diagnostic with severity reset was raised since the last call
+    pub(crate) fn take_reset(&mut self) -> bool {
+        return std::mem::take(&mut self.reset_requested);
+    }
+
+    /// The first diagnostic with severity trap since the last call
+    pub(crate) fn take_trap(&mut self) -> Option<Diagnostic> {
+        return self.trapped.take();
+    }
+
+    /// The CPU was reset, no interrupt is being serviced anymore
+    pub(crate) fn reset(&mut self) {
+        self.frames.clear();
+    }
+}
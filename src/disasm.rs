@@ -0,0 +1,212 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Output uses the assembler's syntax (`mov #0x4400 sp`), except that jumps and symbolic operands
+// show the address they refer to rather than an offset.
+
+/// A decoded instruction
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Disassembled {
+    pub(crate) text: String,
+    /// bytes, including extension words
+    pub(crate) length: u16,
+}
+
+const DOUBLE_OPERAND: [&str; 12] = ["mov", "add", "addc", "subc", "sub", "cmp", "dadd", "bit", "bic", "bis", "xor", "and"];
+const SINGLE_OPERAND: [&str; 7] = ["rrc", "swpb", "rra", "sxt", "push", "call", "reti"];
+const JUMPS: [&str; 8] = ["jnz", "jz", "jnc", "jc", "jn", "jge", "jl", "jmp"];
+
+fn register(number: u16) -> String {
+    return match number {
+        0 => "pc".to_string(),
+        1 => "sp".to_string(),
+        2 => "sr".to_string(),
+        n => format!("r{}", n),
+    };
+}
+
+/// An operand, decoded enough to recognize emulated instructions
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Operand {
+    Register(u16),
+    Constant(i32),
+    Immediate(u16),
+    Indexed(u16, u16),
+    /// PC-relative, the address it refers to
+    Symbolic(u16),
+    Absolute(u16),
+    Indirect(u16),
+    Autoincrement(u16),
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Operand::Register(n) => write!(f, "{}", register(*n)),
+            Operand::Constant(value) => write!(f, "#{}", value),
+            Operand::Immediate(value) => write!(f, "#{:#06x}", value),
+            Operand::Indexed(offset, n) => write!(f, "{:#x}({})", offset, register(*n)),
+            Operand::Symbolic(address) => write!(f, "{:#06x}", address),
+            Operand::Absolute(address) => write!(f, "&{:#06x}", address),
+            Operand::Indirect(n) => write!(f, "@{}", register(*n)),
+            Operand::Autoincrement(n) => write!(f, "@{}+", register(*n)),
+        };
+    }
+}
+
+/// Reads extension words as they're consumed
+struct Words<'a> {
+    read: &'a dyn Fn(u16) -> u16,
+    next: u16,
+}
+
+impl Words<'_> {
+    fn take(&mut self) -> (u16, u16) {
+        let address: u16 = self.next;
+        self.next = self.next.wrapping_add(2);
+        return (address, (self.read)(address));
+    }
+}
+
+fn source(words: &mut Words, reg: u16, as_: u16) -> Operand {
+    return match (reg, as_) {
+        (3, 0) => Operand::Constant(0),
+        (3, 1) => Operand::Constant(1),
+        (3, 2) => Operand::Constant(2),
+        (3, _) => Operand::Constant(-1),
+        (2, 2) => Operand::Constant(4),
+        (2, 3) => Operand::Constant(8),
+        (_, 0) => Operand::Register(reg),
+        (_, 1) => destination(words, reg, 1),
+        (_, 2) => Operand::Indirect(reg),
+        (0, _) => Operand::Immediate(words.take().1),
+        _ => Operand::Autoincrement(reg),
+    };
+}
+
+fn destination(words: &mut Words, reg: u16, ad: u16) -> Operand {
+    if ad == 0 {
+        return Operand::Register(reg);
+    }
+    let (address, offset) = words.take();
+    return match reg {
+        0 => Operand::Symbolic(address.wrapping_add(offset)),
+        2 => Operand::Absolute(offset),
+        _ => Operand::Indexed(offset, reg),
+    };
+}
+
+/// The emulated instruction a double operand instruction stands for, if any
+fn emulated(name: &str, byte: bool, src: &Operand, dst: &Operand) -> Option<String> {
+    let suffix: &str = if byte {".b"} else {""};
+    let word_only = |text: &str| if byte {None} else {Some(text.to_string())};
+    return match (name, src, dst) {
+        ("mov", Operand::Constant(0), Operand::Register(3)) => word_only("nop"),
+        ("mov", Operand::Autoincrement(1), Operand::Register(0)) => word_only("ret"),
+        ("mov", Operand::Autoincrement(1), _) => Some(format!("pop{} {}", suffix, dst)),
+        ("mov", _, Operand::Register(0)) => word_only(&format!("br {}", src)),
+        ("mov", Operand::Constant(0), _) => Some(format!("clr{} {}", suffix, dst)),
+        ("bis", Operand::Constant(1), Operand::Register(2)) => word_only("setc"),
+        ("bis", Operand::Constant(2), Operand::Register(2)) => word_only("setz"),
+        ("bis", Operand::Constant(4), Operand::Register(2)) => word_only("setn"),
+        ("bis", Operand::Constant(8), Operand::Register(2)) => word_only("eint"),
+        ("bic", Operand::Constant(1), Operand::Register(2)) => word_only("clrc"),
+        ("bic", Operand::Constant(2), Operand::Register(2)) => word_only("clrz"),
+        ("bic", Operand::Constant(4), Operand::Register(2)) => word_only("clrn"),
+        ("bic", Operand::Constant(8), Operand::Register(2)) => word_only("dint"),
+        ("add", Operand::Constant(1), _) => Some(format!("inc{} {}", suffix, dst)),
+        ("add", Operand::Constant(2), _) => Some(format!("incd{} {}", suffix, dst)),
+        ("sub", Operand::Constant(1), _) => Some(format!("dec{} {}", suffix, dst)),
+        ("sub", Operand::Constant(2), _) => Some(format!("decd{} {}", suffix, dst)),
+        ("addc", Operand::Constant(0), _) => Some(format!("adc{} {}", suffix, dst)),
+        ("subc", Operand::Constant(0), _) => Some(format!("sbc{} {}", suffix, dst)),
+        ("dadd", Operand::Constant(0), _) => Some(format!("dadc{} {}", suffix, dst)),
+        ("xor", Operand::Constant(-1), _) => Some(format!("inv{} {}", suffix, dst)),
+        ("cmp", Operand::Constant(0), _) => Some(format!("tst{} {}", suffix, dst)),
+        // the same operand twice, e.g. `add r5 r5`, an indexed one needs the same offset too
+        ("add", _, _) if src == dst => Some(format!("rla{} {}", suffix, dst)),
+        ("addc", _, _) if src == dst => Some(format!("rlc{} {}", suffix, dst)),
+        _ => None,
+    };
+}
+
+/// Decode the instruction at `address`, `read` gives memory words. Emulated instructions (`ret`,
+/// `clr r5`, `inc.b &0x0200`...) are shown as such unless `canonical` is set, then every
+/// instruction is shown as the one it's encoded as (`mov @sp+ pc`).
+pub(crate) fn disassemble(address: u16, read: &dyn Fn(u16) -> u16, canonical: bool) -> Disassembled {
+    let instruction: u16 = read(address);
+    let mut words = Words { read, next: address.wrapping_add(2) };
+    let byte: bool = (instruction >> 6) & 1 == 1;
+    let text: String = if instruction >> 13 == 1 {
+        let mut offset: i32 = (instruction & 0x3ff) as i32;
+        if offset >= 512 {
+            offset -= 1024;
+        }
+        let target: u16 = (address as i32 + 2 + offset * 2) as u16;
+        format!("{} {:#06x}", JUMPS[((instruction >> 10) & 7) as usize], target)
+    } else if instruction >> 10 == 4 {
+        let opcode: usize = ((instruction >> 7) & 7) as usize;
+        match SINGLE_OPERAND.get(opcode) {
+            Some(&"reti") if instruction == 0x1300 => "reti".to_string(),
+            Some(&"reti") => format!(".word {:#06x}", instruction),
+            Some(name) => {
+                if byte && matches!(*name, "swpb" | "sxt" | "call") {
+                    format!(".word {:#06x}", instruction)
+                } else {
+                    let operand: Operand = source(&mut words, instruction & 0xf, (instruction >> 4) & 3);
+                    format!("{}{} {}", name, if byte {".b"} else {""}, operand)
+                }
+            },
+            None => format!(".word {:#06x}", instruction),
+        }
+    } else if instruction >> 12 >= 4 {
+        let name: &str = DOUBLE_OPERAND[(instruction >> 12) as usize - 4];
+        let src: Operand = source(&mut words, (instruction >> 8) & 0xf, (instruction >> 4) & 3);
+        let dst: Operand = destination(&mut words, instruction & 0xf, (instruction >> 7) & 1);
+        match emulated(name, byte, &src, &dst).filter(|_| !canonical) {
+            Some(text) => text,
+            None => format!("{}{} {} {}", name, if byte {".b"} else {""}, src, dst),
+        }
+    } else {
+        format!(".word {:#06x}", instruction)
+    };
+    return Disassembled { text, length: words.next.wrapping_sub(address) };
+}
+
+/// Disassemble `data` (emulator memory order) as if it was at `start`, one line per instruction:
+/// address, its words and the instruction, with a label line wherever a symbol starts
+pub(crate) fn listing(start: u16, data: &[u8], symbols: &[crate::image::Symbol], canonical: bool) -> String {
+    let read = |address: u16| {
+        let offset: usize = address.wrapping_sub(start) as usize;
+        let byte = |i: usize| data.get(i).copied().unwrap_or(0) as u16;
+        return (byte(offset) << 8) | byte(offset + 1);
+    };
+    let mut out: String = String::new();
+    let mut offset: usize = 0;
+    while offset + 1 < data.len() {
+        let address: u16 = start.wrapping_add(offset as u16);
+        for symbol in symbols.iter().filter(|s| s.address == address) {
+            out.push_str(&format!("{}:\n", symbol.name));
+        }
+        let decoded: Disassembled = disassemble(address, &read, canonical);
+        let words: Vec<String> = (0..decoded.length / 2).map(|i| format!("{:04x}", read(address.wrapping_add(2 * i)))).collect();
+        out.push_str(&format!("{:04x}: {:<15} {}\n", address, words.join(" "), decoded.text));
+        offset += decoded.length as usize;
+    }
+    return out;
+}
@@ -0,0 +1,125 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{formats, MemoryMap};
+use crate::elf::to_memory_order;
+use crate::image::{ProgramImage, Segment, Symbol};
+use crate::profile::Region;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum DumpFormat {
+    /// Device-order bytes
+    Raw,
+    /// Intel HEX
+    Hex,
+    /// TI-TXT
+    TiTxt,
+    /// Offsets, bytes and ASCII, 16 bytes a line, with the symbols that start on each line
+    Hexdump,
+}
+
+impl DumpFormat {
+    pub(crate) fn from_id(id: u8) -> Option<DumpFormat> {
+        return match id {
+            0 => Some(DumpFormat::Raw),
+            1 => Some(DumpFormat::Hex),
+            2 => Some(DumpFormat::TiTxt),
+            3 => Some(DumpFormat::Hexdump),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            DumpFormat::Raw => 0,
+            DumpFormat::Hex => 1,
+            DumpFormat::TiTxt => 2,
+            DumpFormat::Hexdump => 3,
+        };
+    }
+}
+
+/// A memory range to write to a file once the run ends (`run --dump START-END:FORMAT:PATH`)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct DumpSpec {
+    pub(crate) region: Region,
+    pub(crate) format: DumpFormat,
+    pub(crate) path: String,
+}
+
+impl std::str::FromStr for DumpSpec {
+    type Err = String;
+
+    /// The path comes last so it may contain ':'
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (region, format, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(region), Some(format), Some(path)) if !path.is_empty() => (region, format, path),
+            _ => return Err(format!("'{}' is not START-END:FORMAT:PATH", s)),
+        };
+        let format: DumpFormat = clap::ValueEnum::from_str(format, true)
+            .map_err(|_| format!("'{}' is not a dump format (raw, hex, ti-txt, hexdump)", format))?;
+        return Ok(DumpSpec { region: region.parse()?, format, path: path.to_string() });
+    }
+}
+
+impl std::fmt::Display for DumpSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = clap::ValueEnum::to_possible_value(&self.format).expect("No skipped variants");
+        return write!(f, "{}:{}:{}", self.region, format.get_name(), self.path);
+    }
+}
+
+/// Contents of `region` in `format`, `symbols` annotate the hexdump
+pub(crate) fn dump(memory: &MemoryMap, region: Region, format: DumpFormat, symbols: &[Symbol]) -> Vec<u8> {
+    let data: Vec<u8> = (region.start..=region.end).map(|address| memory.get_byte(address)).collect();
+    let mut image = ProgramImage::new();
+    image.segments.push(Segment { address: region.start, data });
+    return match format {
+        DumpFormat::Raw => formats::write(formats::Format::Binary, &image),
+        DumpFormat::Hex => formats::write(formats::Format::Ihex, &image),
+        DumpFormat::TiTxt => formats::write(formats::Format::TiTxt, &image),
+        DumpFormat::Hexdump => Ok(hexdump(region.start, &image.segments[0].data, symbols).into_bytes()),
+    }.expect("a single in-range segment always converts");
+}
+
+fn hexdump(start: u16, memory_order: &[u8], symbols: &[Symbol]) -> String {
+    let bytes: Vec<u8> = to_memory_order(start, memory_order);
+    let mut out: String = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let address: u32 = start as u32 + (i * 16) as u32;
+        out.push_str(&format!("{:04x}:", address));
+        for byte in line {
+            out.push_str(&format!(" {:02x}", byte));
+        }
+        out.push_str(&"   ".repeat(16 - line.len()));
+        out.push_str("  |");
+        out.extend(line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' {*b as char} else {'.'}));
+        out.push('|');
+        let names: Vec<String> = symbols.iter()
+            .filter(|s| (address..address + line.len() as u32).contains(&(s.address as u32)))
+            .map(|s| format!("{}={:04x}", s.name, s.address))
+            .collect();
+        if !names.is_empty() {
+            out.push_str("  ");
+            out.push_str(&names.join(" "));
+        }
+        out.push('\n');
+    }
+    return out;
+}
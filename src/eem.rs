@@ -0,0 +1,230 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// Memory bus triggers, as many as the large (EEM-L) debug module has
+pub(crate) const TRIGGER_COUNT: usize = 8;
+/// Breakpoints, each combining any of the triggers
+pub(crate) const BREAKPOINT_COUNT: usize = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum TriggerKind {
+    Fetch,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl TriggerKind {
+    pub(crate) fn from_id(id: u8) -> Option<TriggerKind> {
+        return match id {
+            1 => Some(TriggerKind::Fetch),
+            2 => Some(TriggerKind::Read),
+            3 => Some(TriggerKind::Write),
+            4 => Some(TriggerKind::ReadWrite),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            TriggerKind::Fetch => 1,
+            TriggerKind::Read => 2,
+            TriggerKind::Write => 3,
+            TriggerKind::ReadWrite => 4,
+        };
+    }
+}
+
+/// One comparator on the memory bus. Mask bits that are set are ignored when comparing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Trigger {
+    pub(crate) kind: TriggerKind,
+    pub(crate) address: u16,
+    pub(crate) address_mask: u16,
+    /// (value, mask) the data read or written must also match
+    pub(crate) data: Option<(u16, u16)>,
+}
+
+impl Trigger {
+    fn matches(&self, address: u16, value: u16) -> bool {
+        if (address ^ self.address) & !self.address_mask != 0 {
+            return false;
+        }
+        return match self.data {
+            Some((data, mask)) => (value ^ data) & !mask == 0,
+            None => true,
+        };
+    }
+}
+
+/// Hardware breakpoint block modeled on the MSP430 embedded emulation module: a fixed set of
+/// triggers compared against every bus access, and breakpoints that halt the CPU once all of their
+/// triggers fired during the same instruction. A breakpoint made only of fetch triggers halts
+/// before the instruction executes, anything involving data halts right after it.
+#[derive(Clone)]
+pub(crate) struct Eem {
+    triggers: [Option<Trigger>; TRIGGER_COUNT],
+    /// trigger mask of each breakpoint, 0 = unused
+    breakpoints: [u8; BREAKPOINT_COUNT],
+    /// triggers that fired during the current instruction
+    fired: u8,
+    /// the fetch at this address already halted, let it execute when resumed
+    resume_at: Option<u16>,
+    last_hit: Option<usize>,
+    /// the last hit halted before its instruction executed (fetch triggers only)
+    last_hit_on_fetch: bool,
+    hit: bool,
+    /// breakpoints are passed over while set (a step that doesn't stop at them)
+    suspended: bool,
+}
+
+impl Eem {
+    pub(crate) fn new() -> Eem {
+        return Eem {
+            triggers: [None; TRIGGER_COUNT],
+            breakpoints: [0; BREAKPOINT_COUNT],
+            fired: 0,
+            resume_at: None,
+            last_hit: None,
+            last_hit_on_fetch: false,
+            hit: false,
+            suspended: false,
+        };
+    }
+
+    /// Forget what happened, the configured triggers and breakpoints stay
+    pub(crate) fn reset(&mut self) {
+        self.fired = 0;
+        self.resume_at = None;
+        self.last_hit = None;
+        self.last_hit_on_fetch = false;
+        self.hit = false;
+    }
+
+    #[inline]
+    pub(crate) fn armed(&self) -> bool {
+        return !self.suspended && self.breakpoints.iter().any(|b| *b != 0);
+    }
+
+    pub(crate) fn set_suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+    }
+
+    pub(crate) fn set_trigger(&mut self, index: usize, trigger: Option<Trigger>) -> bool {
+        if index >= TRIGGER_COUNT {
+            return false;
+        }
+        self.triggers[index] = trigger;
+        return true;
+    }
+
+    pub(crate) fn set_breakpoint(&mut self, index: usize, triggers: u8) -> bool {
+        if index >= BREAKPOINT_COUNT {
+            return false;
+        }
+        self.breakpoints[index] = triggers;
+        return true;
+    }
+
+    /// Breakpoint that halted the CPU most recently
+    pub(crate) fn last_hit(&self) -> Option<usize> {
+        return self.last_hit;
+    }
+
+    /// Whether the most recent breakpoint halted before its instruction executed, otherwise it
+    /// halted right after the instruction whose data accesses fired it
+    pub(crate) fn last_hit_on_fetch(&self) -> bool {
+        return self.last_hit_on_fetch;
+    }
+
+    /// Whether a breakpoint halted the CPU since the last call
+    pub(crate) fn take_hit(&mut self) -> bool {
+        return std::mem::replace(&mut self.hit, false);
+    }
+
+    fn fire(&mut self, address: u16, value: u16, kinds: &[TriggerKind]) {
+        for (i, trigger) in self.triggers.iter().enumerate() {
+            if let Some(trigger) = trigger {
+                if kinds.contains(&trigger.kind) && trigger.matches(address, value) {
+                    self.fired |= 1 << i;
+                }
+            }
+        }
+    }
+
+    fn is_fetch_only(&self, breakpoint: u8) -> bool {
+        return (0..TRIGGER_COUNT).filter(|i| breakpoint & (1 << i) != 0)
+            .all(|i| matches!(self.triggers[i], Some(Trigger { kind: TriggerKind::Fetch, .. })));
+    }
+
+    /// First satisfied breakpoint among the fetch-only ones, or among the others
+    fn satisfied(&self, fetch_only: bool) -> Option<usize> {
+        return self.breakpoints.iter().position(|b| *b != 0 && self.fired & b == *b
+            && self.is_fetch_only(*b) == fetch_only);
+    }
+
+    fn halt(&mut self, breakpoint: usize, on_fetch: bool) {
+        self.last_hit = Some(breakpoint);
+        self.last_hit_on_fetch = on_fetch;
+        self.hit = true;
+    }
+
+    /// Start of an instruction, returns false if it must not execute because a breakpoint halted on its fetch
+    #[inline]
+    pub(crate) fn fetch(&mut self, pc: u16, instruction: u16) -> bool {
+        if !self.armed() {
+            return true;
+        }
+        self.fire(pc, instruction, &[TriggerKind::Fetch]);
+        if self.resume_at.take() == Some(pc) {
+            return true;
+        }
+        if let Some(breakpoint) = self.satisfied(true) {
+            self.resume_at = Some(pc);
+            self.fired = 0;
+            self.halt(breakpoint, true);
+            return false;
+        }
+        return true;
+    }
+
+    #[inline]
+    pub(crate) fn read(&mut self, address: u16, value: u16) {
+        if self.armed() {
+            self.fire(address, value, &[TriggerKind::Read, TriggerKind::ReadWrite]);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn write(&mut self, address: u16, value: u16) {
+        if self.armed() {
+            self.fire(address, value, &[TriggerKind::Write, TriggerKind::ReadWrite]);
+        }
+    }
+
+    /// End of an instruction, breakpoints that needed its data accesses halt now
+    #[inline]
+    pub(crate) fn retire(&mut self) {
+        if self.fired != 0 {
+            if let Some(breakpoint) = self.satisfied(false) {
+                self.halt(breakpoint, false);
+            }
+            self.fired = 0;
+        }
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Minimal ELF32 reader: loadable segments and the symbol table, nothing else
+
+use crate::image::{ProgramImage, Segment, Symbol};
+
+pub(crate) const ELF_MAGIC: &[u8] = &[0x7f, b'E', b'L', b'F'];
+const EM_MSP430: u16 = 105;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    return match data.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(format!("ELF truncated at offset {:#x}", offset)),
+    };
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    return match data.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(format!("ELF truncated at offset {:#x}", offset)),
+    };
+}
+
+fn read_c_string(data: &[u8], offset: usize) -> Result<String, String> {
+    let tail: &[u8] = data.get(offset..).ok_or(format!("String offset {:#x} out of bounds", offset))?;
+    let end: usize = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    return Ok(String::from_utf8_lossy(&tail[..end]).into_owned());
+}
+
+/// ELF stores little-endian words, this emulator keeps the high byte first,
+/// so every aligned word is swapped while loading (swapping again converts back)
+pub(crate) fn to_memory_order(address: u16, bytes: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = bytes.to_vec();
+    let mut i: usize = (address & 1) as usize; // an odd start can't pair its first byte
+    while i + 1 < out.len() {
+        out.swap(i, i + 1);
+        i += 2;
+    }
+    return out;
+}
+
+/// Parse an MSP430 ELF32 executable.
+/// Segments are placed at their load address, and additionally at their run address if that
+/// differs (.data), since tests and tools often jump straight into functions without running crt0.
+pub fn parse_elf(data: &[u8]) -> Result<ProgramImage, String> {
+    if data.len() < 52 || !data.starts_with(ELF_MAGIC) {
+        return Err("Not an ELF file".to_string());
+    }
+    if data[4] != 1 || data[5] != 1 {
+        return Err("Only little-endian ELF32 files are supported".to_string());
+    }
+    let machine: u16 = read_u16(data, 18)?;
+    if machine != EM_MSP430 {
+        return Err(format!("ELF machine is {}, not MSP430 ({})", machine, EM_MSP430));
+    }
+
+    let mut image: ProgramImage = ProgramImage::new();
+    image.entry = Some(read_u32(data, 24)? as u16);
+
+    let ph_offset: usize = read_u32(data, 28)? as usize;
+    let ph_entry_size: usize = read_u16(data, 42)? as usize;
+    let ph_count: usize = read_u16(data, 44)? as usize;
+    for i in 0..ph_count {
+        let ph: usize = ph_offset + i * ph_entry_size;
+        if read_u32(data, ph)? != PT_LOAD {
+            continue;
+        }
+        let offset: usize = read_u32(data, ph + 4)? as usize;
+        let virtual_address: u16 = read_u32(data, ph + 8)? as u16;
+        let physical_address: u16 = read_u32(data, ph + 12)? as u16;
+        let file_size: usize = read_u32(data, ph + 16)? as usize;
+        if file_size == 0 {
+            continue; // .bss, memory is already zeroed
+        }
+        let bytes: &[u8] = data.get(offset..offset + file_size)
+            .ok_or(format!("Segment {} extends past end of file", i))?;
+        image.segments.push(Segment { address: physical_address, data: to_memory_order(physical_address, bytes) });
+        if virtual_address != physical_address {
+            image.segments.push(Segment { address: virtual_address, data: to_memory_order(virtual_address, bytes) });
+        }
+    }
+
+    let sh_offset: usize = read_u32(data, 32)? as usize;
+    let sh_entry_size: usize = read_u16(data, 46)? as usize;
+    let sh_count: usize = read_u16(data, 48)? as usize;
+    for i in 0..sh_count {
+        let sh: usize = sh_offset + i * sh_entry_size;
+        if read_u32(data, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let sym_offset: usize = read_u32(data, sh + 16)? as usize;
+        let sym_size: usize = read_u32(data, sh + 20)? as usize;
+        let strtab_index: usize = read_u32(data, sh + 24)? as usize;
+        let sym_entry_size: usize = (read_u32(data, sh + 36)? as usize).max(16);
+        let strtab_offset: usize = read_u32(data, sh_offset + strtab_index * sh_entry_size + 16)? as usize;
+
+        for j in 0..(sym_size / sym_entry_size) {
+            let sym: usize = sym_offset + j * sym_entry_size;
+            let name_offset: usize = read_u32(data, sym)? as usize;
+            let value: u32 = read_u32(data, sym + 4)?;
+            let info: u8 = *data.get(sym + 12).ok_or("Symbol table truncated".to_string())?;
+            let section: u16 = read_u16(data, sym + 14)?;
+            let sym_type: u8 = info & 0xf;
+            if section == SHN_UNDEF || name_offset == 0 || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&sym_type) {
+                continue;
+            }
+            let name: String = read_c_string(data, strtab_offset + name_offset)?;
+            if name.is_empty() || name.starts_with('.') || image.symbol(&name).is_some() {
+                continue;
+            }
+            image.symbols.push(Symbol { name, address: value as u16 });
+        }
+    }
+    image.symbols.sort_by_key(|s| s.address);
+
+    return Ok(image);
+}
@@ -0,0 +1,255 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use std::str::FromStr;
+use image::Symbol;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl BinOp {
+    /// Same relative precedence as C
+    fn precedence(&self) -> u8 {
+        return match self {
+            BinOp::Or => 1,
+            BinOp::Xor => 2,
+            BinOp::And => 3,
+            BinOp::Shl | BinOp::Shr => 4,
+            BinOp::Add | BinOp::Sub => 5,
+        };
+    }
+
+    fn apply(&self, a: u16, b: u16) -> u16 {
+        return match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+            BinOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+        };
+    }
+}
+
+/// An address/value expression, e.g. `main+0x12`, `sp-4`, `&buffer[2]`, `b[r4+1] & 0x0f`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Expr {
+    Const(u16),
+    Register(u8),
+    /// memory word at an address (read directly, without device side effects)
+    Word(Box<Expr>),
+    /// memory byte at an address
+    Byte(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Op(BinOp),
+    Open(char),
+    Close(char),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i: usize = 0;
+    while i < chars.len() {
+        let ch: char = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch.is_ascii_alphanumeric() || ch == '_' {
+            let start: usize = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if ch.is_ascii_digit() {
+                tokens.push(Token::Number(utils::parse_u16(&word)?));
+            } else {
+                tokens.push(Token::Name(word.to_ascii_lowercase()));
+            }
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let (token, len) = match (ch, two.as_str()) {
+                (_, "<<") => (Token::Op(BinOp::Shl), 2),
+                (_, ">>") => (Token::Op(BinOp::Shr), 2),
+                ('+', _) => (Token::Op(BinOp::Add), 1),
+                ('-', _) => (Token::Op(BinOp::Sub), 1),
+                ('&', _) => (Token::Op(BinOp::And), 1),
+                ('|', _) => (Token::Op(BinOp::Or), 1),
+                ('^', _) => (Token::Op(BinOp::Xor), 1),
+                ('[', _) | ('(', _) => (Token::Open(ch), 1),
+                (']', _) | (')', _) => (Token::Close(ch), 1),
+                _ => return Err(format!("Unexpected '{}'", ch)),
+            };
+            tokens.push(token);
+            i += len;
+        }
+    }
+    return Ok(tokens);
+}
+
+fn register_id(name: &str) -> Option<u8> {
+    return match name {
+        "pc" => Some(0),
+        "sp" => Some(1),
+        "sr" => Some(2),
+        "cg" => Some(3),
+        _ => name.strip_prefix('r').and_then(|n| n.parse::<u8>().ok()).filter(|&n| n < 16),
+    };
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a [Symbol],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos);
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("Unexpected end of expression".to_string())?;
+        self.pos += 1;
+        return Ok(token);
+    }
+
+    fn expect_close(&mut self, close: char) -> Result<(), String> {
+        return match self.next()? {
+            Token::Close(c) if c == close => Ok(()),
+            other => Err(format!("Expected '{}', found {:?}", close, other)),
+        };
+    }
+
+    fn symbol(&self, name: &str) -> Result<u16, String> {
+        return self.symbols.iter().find(|s| s.name.eq_ignore_ascii_case(name)).map(|s| s.address)
+            .ok_or(format!("Unknown register or symbol '{}'", name));
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        return match self.next()? {
+            Token::Number(n) => Ok(Expr::Const(n)),
+            Token::Open('(') => {
+                let inner = self.expr(0)?;
+                self.expect_close(')')?;
+                Ok(inner)
+            },
+            Token::Open(_) => {
+                let inner = self.expr(0)?;
+                self.expect_close(']')?;
+                Ok(Expr::Word(Box::new(inner)))
+            },
+            Token::Name(name) if name == "b" && self.peek() == Some(&Token::Open('[')) => {
+                self.pos += 1;
+                let inner = self.expr(0)?;
+                self.expect_close(']')?;
+                Ok(Expr::Byte(Box::new(inner)))
+            },
+            // `&name[index]` is the address `index` bytes into `name`, like C with a byte array
+            Token::Op(BinOp::And) => match self.next()? {
+                Token::Name(name) => {
+                    let base = Expr::Const(self.symbol(&name)?);
+                    if self.peek() == Some(&Token::Open('[')) {
+                        self.pos += 1;
+                        let index = self.expr(0)?;
+                        self.expect_close(']')?;
+                        Ok(Expr::Binary(BinOp::Add, Box::new(base), Box::new(index)))
+                    } else {
+                        Ok(base)
+                    }
+                },
+                other => Err(format!("Expected a symbol after '&', found {:?}", other)),
+            },
+            // registers win over symbols of the same name
+            Token::Name(name) => match register_id(&name) {
+                Some(id) => Ok(Expr::Register(id)),
+                None => Ok(Expr::Const(self.symbol(&name)?)),
+            },
+            other => Err(format!("Unexpected {:?}", other)),
+        };
+    }
+
+    /// Precedence climbing, only operators binding at least as tightly as `min` are consumed
+    fn expr(&mut self, min: u8) -> Result<Expr, String> {
+        let mut lhs = self.atom()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op.precedence() < min {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+}
+
+/// Parse an expression, symbol names resolve to their addresses
+pub(crate) fn parse(s: &str, symbols: &[Symbol]) -> Result<Expr, String> {
+    let mut parser = Parser { tokens: tokenize(s)?, pos: 0, symbols };
+    let expr = parser.expr(0)?;
+    if parser.pos != parser.tokens.len() {
+        return Err(format!("Unexpected {:?} after expression", parser.tokens[parser.pos]));
+    }
+    return Ok(expr);
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    /// Without symbols, see `parse`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return parse(s, &[]);
+    }
+}
+
+impl Expr {
+    /// The value if it doesn't depend on registers or memory
+    pub(crate) fn constant(&self) -> Option<u16> {
+        return match self {
+            Expr::Const(n) => Some(*n),
+            Expr::Register(_) | Expr::Word(_) | Expr::Byte(_) => None,
+            Expr::Binary(op, a, b) => Some(op.apply(a.constant()?, b.constant()?)),
+        };
+    }
+
+    pub(crate) fn eval(&self, computer: &Computer) -> u16 {
+        return match self {
+            Expr::Const(n) => *n,
+            Expr::Register(id) => computer.get_register_imut(*id).get_word(),
+            Expr::Word(address) => computer.memory.get_word(address.eval(computer)),
+            Expr::Byte(address) => computer.memory.get_byte(address.eval(computer)) as u16,
+            Expr::Binary(op, a, b) => op.apply(a.eval(computer), b.eval(computer)),
+        };
+    }
+}
@@ -0,0 +1,321 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Interchange formats of other tools, converted with the `convert` subcommand. These files hold
+// bytes in device order (little-endian words), images keep emulator memory order.
+
+use crate::elf::{self, to_memory_order};
+use crate::image::{ProgramImage, Segment, V2_MAGIC};
+use crate::utils;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum Format {
+    /// Old raw format: start address, then the bytes from there
+    Raw,
+    /// New segmented format
+    Segmented,
+    /// Segmented with checksums and metadata
+    V2,
+    /// Intel HEX
+    Ihex,
+    /// TI-TXT, as used by TI's flashing tools
+    TiTxt,
+    /// Motorola S-record
+    Srec,
+    /// MSP430 ELF executable (input only)
+    Elf,
+    /// Flat device-order bytes from the lowest to the highest address, gaps filled with 0xff (output only)
+    Binary,
+}
+
+/// Guess the format from the content, text formats only if the whole file is printable
+pub(crate) fn detect(data: &[u8]) -> Format {
+    if data.starts_with(elf::ELF_MAGIC) {
+        return Format::Elf;
+    }
+    if data.starts_with(V2_MAGIC) {
+        return Format::V2;
+    }
+    if data.starts_with(&[0xff, 0xff]) {
+        return Format::Segmented;
+    }
+    if data.iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace()) {
+        match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b':') => return Format::Ihex,
+            Some(b'@') => return Format::TiTxt,
+            Some(b'S') => return Format::Srec,
+            _ => {},
+        }
+    }
+    return Format::Raw;
+}
+
+pub(crate) fn read(format: Format, data: &[u8]) -> Result<ProgramImage, String> {
+    return match format {
+        Format::Raw => {
+            if data.len() < 2 {
+                return Err("File too short for the raw format".to_string());
+            }
+            ProgramImage::from_segmented(&utils::convert_code_fmt(data))
+        },
+        Format::Segmented => ProgramImage::from_segmented(data),
+        Format::V2 => ProgramImage::from_v2(data),
+        Format::Ihex => read_ihex(&text(data)?),
+        Format::TiTxt => read_ti_txt(&text(data)?),
+        Format::Srec => read_srec(&text(data)?),
+        Format::Elf => elf::parse_elf(data),
+        Format::Binary => Err("Flat binaries have no addresses, load them with FILE@ADDRESS instead".to_string()),
+    };
+}
+
+pub(crate) fn write(format: Format, image: &ProgramImage) -> Result<Vec<u8>, String> {
+    return match format {
+        Format::Raw => write_raw(image),
+        Format::Segmented => image.to_segmented(),
+        Format::V2 => image.to_v2(),
+        Format::Ihex => Ok(write_ihex(image).into_bytes()),
+        Format::TiTxt => Ok(write_ti_txt(image).into_bytes()),
+        Format::Srec => Ok(write_srec(image).into_bytes()),
+        Format::Elf => Err("Writing ELF files is not supported".to_string()),
+        Format::Binary => flatten(image).map(|(address, bytes)| to_memory_order(address, &bytes)),
+    };
+}
+
+fn text(data: &[u8]) -> Result<String, String> {
+    return String::from_utf8(data.to_vec()).map_err(|_| "Not a text file".to_string());
+}
+
+fn hex_bytes(line: &str, number: usize) -> Result<Vec<u8>, String> {
+    if !line.len().is_multiple_of(2) {
+        return Err(format!("Line {}: odd number of hex digits", number));
+    }
+    return (0..line.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&line[i..i + 2], 16).map_err(|_| format!("Line {}: invalid hex '{}'", number, &line[i..i + 2])))
+        .collect();
+}
+
+/// Collect (address, device-order bytes) records into image segments, joining contiguous ones
+/// first so words split across records are still swapped as a pair
+fn build(records: Vec<(u32, Vec<u8>)>, entry: Option<u16>) -> Result<ProgramImage, String> {
+    let mut joined: Vec<(u16, Vec<u8>)> = Vec::new();
+    for (address, bytes) in records {
+        if address as usize + bytes.len() > 0x10000 {
+            return Err(format!("Data at {:#x} is beyond the 64 KB address space", address));
+        }
+        match joined.last_mut() {
+            Some((start, data)) if *start as usize + data.len() == address as usize => data.extend(bytes),
+            _ => joined.push((address as u16, bytes)),
+        }
+    }
+    let mut image = ProgramImage::new();
+    image.entry = entry;
+    for (address, bytes) in joined {
+        image.segments.push(Segment { address, data: to_memory_order(address, &bytes) });
+    }
+    return Ok(image);
+}
+
+/// Lowest address and memory-order bytes up to the highest one, gaps filled with 0xff
+fn flatten(image: &ProgramImage) -> Result<(u16, Vec<u8>), String> {
+    let start: usize = image.segments.iter().map(|s| s.address as usize).min().ok_or("Image is empty")?;
+    let end: usize = image.segments.iter().map(|s| s.address as usize + s.data.len()).max().unwrap_or(start);
+    let mut bytes: Vec<u8> = vec![0xff; end - start];
+    for segment in &image.segments {
+        let offset: usize = segment.address as usize - start;
+        bytes[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    return Ok((start as u16, bytes));
+}
+
+/// Device-order bytes of every segment, split in lines of 16
+fn lines(image: &ProgramImage) -> Vec<(u16, Vec<u8>)> {
+    let mut out: Vec<(u16, Vec<u8>)> = Vec::new();
+    for segment in &image.segments {
+        let bytes: Vec<u8> = to_memory_order(segment.address, &segment.data);
+        for (i, chunk) in bytes.chunks(16).enumerate() {
+            out.push((segment.address.wrapping_add((i * 16) as u16), chunk.to_vec()));
+        }
+    }
+    return out;
+}
+
+/// Execution starts at the first byte, so the image must be one block starting at the entry
+/// point. A reset vector pointing there is implied by the format and left out.
+fn write_raw(image: &ProgramImage) -> Result<Vec<u8>, String> {
+    let mut image: ProgramImage = image.clone();
+    let entry: u16 = image.entry.or(image.reset_vector()).ok_or("Image has no entry point or reset vector")?;
+    image.segments.retain(|s| !(s.address == 0xfffe && s.data == entry.to_be_bytes()));
+    let (start, bytes) = flatten(&image)?;
+    if start != entry {
+        return Err(format!("The raw format starts executing at its first byte ({:#06x}), but the entry point is {:#06x}",
+                           start, entry));
+    }
+    let mut out: Vec<u8> = start.to_be_bytes().to_vec();
+    out.extend(bytes);
+    return Ok(out);
+}
+
+fn read_ihex(text: &str) -> Result<ProgramImage, String> {
+    let mut records: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut base: u32 = 0;
+    let mut entry: Option<u16> = None;
+    for (i, line) in text.lines().enumerate() {
+        let number: usize = i + 1;
+        let line: &str = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes: Vec<u8> = hex_bytes(line.strip_prefix(':').ok_or(format!("Line {}: missing ':'", number))?, number)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format!("Line {}: bad record length", number));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(format!("Line {}: checksum mismatch", number));
+        }
+        let address: u32 = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data: &[u8] = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => records.push((base + address, data.to_vec())),
+            0x01 => break,
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            0x03 if data.len() == 4 => entry = Some(u16::from_be_bytes([data[2], data[3]])),
+            0x05 if data.len() == 4 => entry = Some(u16::from_be_bytes([data[2], data[3]])),
+            kind => return Err(format!("Line {}: unsupported record type {:02x}", number, kind)),
+        }
+    }
+    return build(records, entry);
+}
+
+fn ihex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes: Vec<u8> = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum: u8 = bytes.iter().fold(0u8, |sum, b| sum.wrapping_sub(*b));
+    bytes.push(checksum);
+    return format!(":{}\n", bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>());
+}
+
+fn write_ihex(image: &ProgramImage) -> String {
+    let mut out: String = String::new();
+    for (address, bytes) in lines(image) {
+        out.push_str(&ihex_record(0x00, address, &bytes));
+    }
+    if let Some(entry) = image.entry {
+        out.push_str(&ihex_record(0x05, 0, &(entry as u32).to_be_bytes()));
+    }
+    out.push_str(&ihex_record(0x01, 0, &[]));
+    return out;
+}
+
+fn read_ti_txt(text: &str) -> Result<ProgramImage, String> {
+    let mut records: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut address: Option<u32> = None;
+    for (i, line) in text.lines().enumerate() {
+        let number: usize = i + 1;
+        let line: &str = line.trim();
+        if line.eq_ignore_ascii_case("q") {
+            break;
+        }
+        if let Some(start) = line.strip_prefix('@') {
+            address = Some(u32::from_str_radix(start, 16).map_err(|_| format!("Line {}: invalid address", number))?);
+            continue;
+        }
+        let bytes: Vec<u8> = hex_bytes(&line.split_whitespace().collect::<String>(), number)?;
+        if bytes.is_empty() {
+            continue;
+        }
+        let start: u32 = address.ok_or(format!("Line {}: data before the first @address", number))?;
+        address = Some(start + bytes.len() as u32);
+        records.push((start, bytes));
+    }
+    return build(records, None);
+}
+
+/// TI-TXT has no entry point, the reset vector in the data is what the device uses
+fn write_ti_txt(image: &ProgramImage) -> String {
+    let mut out: String = String::new();
+    let mut next: Option<u16> = None;
+    for (address, bytes) in lines(image) {
+        if next != Some(address) {
+            out.push_str(&format!("@{:04X}\n", address));
+        }
+        next = Some(address.wrapping_add(bytes.len() as u16));
+        out.push_str(&bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" "));
+        out.push('\n');
+    }
+    out.push_str("q\n");
+    return out;
+}
+
+fn read_srec(text: &str) -> Result<ProgramImage, String> {
+    let mut records: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut entry: Option<u16> = None;
+    for (i, line) in text.lines().enumerate() {
+        let number: usize = i + 1;
+        let line: &str = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let kind: char = line.strip_prefix('S').and_then(|rest| rest.chars().next())
+            .ok_or(format!("Line {}: missing 'S'", number))?;
+        let bytes: Vec<u8> = hex_bytes(&line[2..], number)?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(format!("Line {}: bad record length", number));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            return Err(format!("Line {}: checksum mismatch", number));
+        }
+        let address_length: usize = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(format!("Line {}: unsupported record type S{}", number, kind)),
+        };
+        if bytes.len() < address_length + 2 {
+            return Err(format!("Line {}: bad record length", number));
+        }
+        let address: u32 = bytes[1..1 + address_length].iter().fold(0, |a, b| (a << 8) | *b as u32);
+        let data: &[u8] = &bytes[1 + address_length..bytes.len() - 1];
+        match kind {
+            '1' | '2' | '3' => records.push((address, data.to_vec())),
+            '7' | '8' | '9' if address != 0 => entry = Some(address as u16),
+            _ => {}, // header and counts
+        }
+    }
+    return build(records, entry);
+}
+
+fn srec_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes: Vec<u8> = vec![(data.len() + 3) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.extend_from_slice(data);
+    let checksum: u8 = !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(checksum);
+    return format!("S{}{}\n", kind, bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>());
+}
+
+fn write_srec(image: &ProgramImage) -> String {
+    let mut out: String = srec_record(0, 0, b"msp430_rust");
+    for (address, bytes) in lines(image) {
+        out.push_str(&srec_record(1, address, &bytes));
+    }
+    out.push_str(&srec_record(9, image.entry.unwrap_or(0), &[]));
+    return out;
+}
@@ -0,0 +1,177 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::profile::Region;
+use crate::utils::parse_u16;
+use crate::ws2812::Rgb;
+
+/// How pixels are packed into the frame buffer's memory, rows start on a byte
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum PixelFormat {
+    /// 1 bit per pixel, the leftmost in the most significant bit, 1 = lit
+    Mono,
+    /// 1 byte per pixel, 0 = black
+    Gray,
+    /// 1 byte per pixel, RRRGGGBB
+    Rgb332,
+    /// 1 word per pixel, RRRRRGGGGGGBBBBB in device order (high byte at the lower address)
+    Rgb565,
+}
+
+impl PixelFormat {
+    const ALL: [PixelFormat; 4] = [PixelFormat::Mono, PixelFormat::Gray, PixelFormat::Rgb332, PixelFormat::Rgb565];
+
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            PixelFormat::Mono => "mono",
+            PixelFormat::Gray => "gray",
+            PixelFormat::Rgb332 => "rgb332",
+            PixelFormat::Rgb565 => "rgb565",
+        };
+    }
+
+    /// As FB_FORMAT reads and the frame buffer command replies
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            PixelFormat::Mono => 0,
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgb332 => 2,
+            PixelFormat::Rgb565 => 3,
+        };
+    }
+
+    fn bits(&self) -> usize {
+        return match self {
+            PixelFormat::Mono => 1,
+            PixelFormat::Gray | PixelFormat::Rgb332 => 8,
+            PixelFormat::Rgb565 => 16,
+        };
+    }
+}
+
+/// `run --framebuffer WIDTHxHEIGHT:FORMAT@ADDRESS`, e.g. `128x64:mono@0x2400`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct FrameBufferSpec {
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    pub(crate) format: PixelFormat,
+    /// address of the top left pixel
+    pub(crate) base: u16,
+}
+
+impl FrameBufferSpec {
+    fn row_bytes(&self) -> usize {
+        return (self.width as usize * self.format.bits()).div_ceil(8);
+    }
+
+    /// Bytes of memory the pixels take
+    pub(crate) fn size(&self) -> usize {
+        return self.row_bytes() * self.height as usize;
+    }
+
+    pub(crate) fn region(&self) -> Region {
+        return Region { start: self.base, end: self.base + (self.size() - 1) as u16 };
+    }
+
+    /// The pixels in `memory` (the frame buffer's bytes from `base` on), row by row
+    pub(crate) fn render(&self, memory: &[u8]) -> Vec<Rgb> {
+        let mut pixels: Vec<Rgb> = Vec::with_capacity(self.width as usize * self.height as usize);
+        for row in memory.chunks_exact(self.row_bytes()) {
+            for x in 0..self.width as usize {
+                pixels.push(match self.format {
+                    PixelFormat::Mono => {
+                        let level: u8 = if row[x / 8] & (0x80 >> (x % 8)) != 0 {0xff} else {0};
+                        Rgb { r: level, g: level, b: level }
+                    },
+                    PixelFormat::Gray => Rgb { r: row[x], g: row[x], b: row[x] },
+                    PixelFormat::Rgb332 => {
+                        let pixel: u8 = row[x];
+                        Rgb { r: scale(pixel >> 5, 7), g: scale((pixel >> 2) & 7, 7), b: scale(pixel & 3, 3) }
+                    },
+                    PixelFormat::Rgb565 => {
+                        let pixel: u16 = ((row[x * 2] as u16) << 8) | row[x * 2 + 1] as u16;
+                        Rgb { r: scale((pixel >> 11) as u8, 31), g: scale(((pixel >> 5) & 63) as u8, 63), b: scale((pixel & 31) as u8, 31) }
+                    },
+                });
+            }
+        }
+        return pixels;
+    }
+
+    /// Binary PPM (P6) of `pixels`, which any image viewer opens
+    pub(crate) fn ppm(&self, pixels: &[Rgb]) -> Vec<u8> {
+        let header: String = format!("P6\n{} {}\n255\n", self.width, self.height);
+        return header.into_bytes().into_iter().chain(pixels.iter().flat_map(|p| [p.r, p.g, p.b])).collect();
+    }
+}
+
+/// A channel of `max` levels stretched to a byte
+fn scale(level: u8, max: u8) -> u8 {
+    return (level as u16 * 255 / max as u16) as u8;
+}
+
+impl FromStr for FrameBufferSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a frame buffer (expected e.g. 128x64:mono@0x2400)", s);
+        let (size, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (format, base) = rest.split_once('@').ok_or_else(invalid)?;
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let spec = FrameBufferSpec {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            format: *PixelFormat::ALL.iter().find(|f| f.name() == format)
+                .ok_or(format!("Unknown pixel format '{}' (mono, gray, rgb332 or rgb565)", format))?,
+            base: parse_u16(base)?,
+        };
+        if spec.width == 0 || spec.height == 0 {
+            return Err(format!("Frame buffer '{}' has no pixels", s));
+        }
+        if spec.base as usize + spec.size() > 0x10000 {
+            return Err(format!("Frame buffer '{}' takes {} bytes, past the end of memory", s, spec.size()));
+        }
+        return Ok(spec);
+    }
+}
+
+impl fmt::Display for FrameBufferSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}x{}:{}@{:#06x}", self.width, self.height, self.format.name(), self.base);
+    }
+}
+
+/// Writes every frame firmware presents to a directory as `frame-000001.ppm`, ... (`run
+/// --framebuffer-images`), numbered by the frame counter so images of a restored run line up
+pub(crate) struct FrameExport {
+    dir: PathBuf,
+}
+
+impl FrameExport {
+    pub(crate) fn create(dir: &str) -> io::Result<FrameExport> {
+        std::fs::create_dir_all(dir)?;
+        return Ok(FrameExport { dir: PathBuf::from(dir) });
+    }
+
+    pub(crate) fn write(&mut self, spec: &FrameBufferSpec, frame: u64, pixels: &[Rgb]) -> io::Result<()> {
+        return std::fs::write(self.dir.join(format!("frame-{:06}.ppm", frame)), spec.ppm(pixels));
+    }
+}
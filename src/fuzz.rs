@@ -0,0 +1,450 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crate::{Computer, RegisterData};
+use crate::decode::{self, Decoded};
+use crate::devices::rng::split_mix;
+
+/// Generated code is placed here, like assembled programs
+const CODE_START: u16 = 0x4400;
+/// C, Z, N and V, the only status bits generated code touches
+const FLAGS: u16 = 0x0107;
+/// Values the constant generators (r2 and r3) produce, with the register and As giving them
+const CONSTANTS: [(u16, u8, u8); 6] = [(0, 3, 0), (1, 3, 1), (2, 3, 2), (0xffff, 3, 3), (4, 2, 2), (8, 2, 3)];
+
+/// Instructions the fuzzer generates, register-mode destinations only. DADD is left out, the
+/// flag model doesn't know BCD.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(crate) enum Op {
+    Mov, Add, Addc, Subc, Sub, Cmp, Bit, Bic, Bis, Xor, And,
+    Rrc, Swpb, Rra, Sxt,
+}
+
+const OPS: [Op; 15] = [Op::Mov, Op::Add, Op::Addc, Op::Subc, Op::Sub, Op::Cmp, Op::Bit, Op::Bic, Op::Bis, Op::Xor, Op::And,
+                       Op::Rrc, Op::Swpb, Op::Rra, Op::Sxt];
+
+impl Op {
+    fn decoded(&self, bw: bool, src_reg: u8, as_: u8, dst: u8) -> Decoded {
+        return match self {
+            Op::Rrc => Decoded::Single { opcode: 0, reg: dst, as_: 0, bw },
+            Op::Swpb => Decoded::Single { opcode: 1, reg: dst, as_: 0, bw },
+            Op::Rra => Decoded::Single { opcode: 2, reg: dst, as_: 0, bw },
+            Op::Sxt => Decoded::Single { opcode: 3, reg: dst, as_: 0, bw },
+            double => {
+                // MOV is 0, DADD (6) isn't generated
+                let index: u8 = OPS.iter().position(|op| op == double).unwrap() as u8;
+                let opcode: u8 = if index >= 6 {index + 1} else {index};
+                Decoded::Double { opcode, src_reg, as_, ad: 0, bw, dst_reg: dst }
+            },
+        };
+    }
+
+    fn from_decoded(decoded: Decoded) -> Option<Op> {
+        return match decoded {
+            Decoded::Single { opcode: opcode @ 0..=3, .. } => Some([Op::Rrc, Op::Swpb, Op::Rra, Op::Sxt][opcode as usize]),
+            Decoded::Double { opcode: 6, .. } => None,
+            Decoded::Double { opcode, .. } => OPS.get(if opcode > 6 {opcode as usize - 1} else {opcode as usize}).copied(),
+            _ => None,
+        };
+    }
+
+    fn single(&self) -> bool {
+        return matches!(self, Op::Rrc | Op::Swpb | Op::Rra | Op::Sxt);
+    }
+
+    fn name(&self) -> &'static str {
+        return ["mov", "add", "addc", "subc", "sub", "cmp", "bit", "bic", "bis", "xor", "and", "rrc", "swpb", "rra", "sxt"]
+            [OPS.iter().position(|op| op == self).unwrap()];
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Source {
+    Register(u8),
+    /// `#N` through an extension word
+    Immediate(u16),
+    /// `#N` from the constant generators, one of `CONSTANTS`
+    Constant(u16),
+}
+
+/// One generated instruction, `src` is `None` for single operand ones
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct FuzzInstruction {
+    pub(crate) op: Op,
+    pub(crate) bw: bool,
+    pub(crate) src: Option<Source>,
+    pub(crate) dst: u8,
+}
+
+impl FuzzInstruction {
+    /// Encoded, with the extension word if there is one
+    pub(crate) fn words(&self) -> Vec<u16> {
+        let (src_reg, as_, extension): (u8, u8, Option<u16>) = match self.src {
+            None => (0, 0, None),
+            Some(Source::Register(reg)) => (reg, 0, None),
+            // byte immediates sit in the first byte of the extension word, like the assembler puts them
+            Some(Source::Immediate(value)) => (0, 3, Some(if self.bw {value << 8} else {value})),
+            Some(Source::Constant(value)) => {
+                let (_, reg, as_) = CONSTANTS.iter().find(|(constant, _, _)| *constant == value).expect("Not a constant");
+                (*reg, *as_, None)
+            },
+        };
+        let mut words: Vec<u16> = vec![decode::encode(self.op.decoded(self.bw, src_reg, as_, self.dst))];
+        words.extend(extension);
+        return words;
+    }
+
+    /// The instruction at the start of `words` and how many words it takes, `None` for anything
+    /// the fuzzer doesn't generate
+    pub(crate) fn from_words(words: &[u16]) -> Option<(FuzzInstruction, usize)> {
+        let decoded: Decoded = decode::decode(*words.first()?);
+        let op: Op = Op::from_decoded(decoded)?;
+        return match decoded {
+            Decoded::Single { reg, as_: 0, bw, .. } if reg >= 4 => Some((FuzzInstruction { op, bw, src: None, dst: reg }, 1)),
+            Decoded::Double { src_reg, as_, ad: 0, bw, dst_reg, .. } if dst_reg >= 4 => {
+                let (src, length): (Source, usize) = match (src_reg, as_) {
+                    (4..=15, 0) => (Source::Register(src_reg), 1),
+                    (0, 3) => (Source::Immediate(if bw {*words.get(1)? >> 8} else {*words.get(1)?}), 2),
+                    _ => (Source::Constant(CONSTANTS.iter().find(|(_, reg, a)| *reg == src_reg && *a == as_)?.0), 1),
+                };
+                Some((FuzzInstruction { op, bw, src: Some(src), dst: dst_reg }, length))
+            },
+            _ => None,
+        };
+    }
+
+    fn random(state: &mut u64) -> FuzzInstruction {
+        let op: Op = OPS[(split_mix(state) % OPS.len() as u64) as usize];
+        // SWPB and SXT have no byte form
+        let bw: bool = !matches!(op, Op::Swpb | Op::Sxt) && split_mix(state) & 1 != 0;
+        let dst: u8 = 4 + (split_mix(state) % 12) as u8;
+        let src: Option<Source> = if op.single() {None} else {
+            Some(match split_mix(state) % 4 {
+                0 | 1 => Source::Register(4 + (split_mix(state) % 12) as u8),
+                2 => Source::Immediate(if bw {interesting(state) & 0xff} else {interesting(state)}),
+                _ => Source::Constant(CONSTANTS[(split_mix(state) % CONSTANTS.len() as u64) as usize].0),
+            })
+        };
+        return FuzzInstruction { op, bw, src, dst };
+    }
+}
+
+impl fmt::Display for FuzzInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{} ", self.op.name(), if self.bw {".b"} else {""})?;
+        match self.src {
+            Some(Source::Register(reg)) => write!(f, "r{} ", reg)?,
+            Some(Source::Immediate(value)) | Some(Source::Constant(value)) => write!(f, "#{:#06x} ", value)?,
+            None => {},
+        }
+        return write!(f, "r{}", self.dst);
+    }
+}
+
+/// Mostly edge values, where flag bugs live
+fn interesting(state: &mut u64) -> u16 {
+    const EDGES: [u16; 10] = [0, 1, 0x7f, 0x80, 0xff, 0x100, 0x7fff, 0x8000, 0xfffe, 0xffff];
+    let random: u64 = split_mix(state);
+    return if random & 1 == 0 {EDGES[(random >> 1) as usize % EDGES.len()]} else {(random >> 16) as u16};
+}
+
+/// What the flag model and the emulator are compared on: r4 - r15 and the C, Z, N, V flags
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct FuzzState {
+    pub(crate) registers: [u16; 12],
+    pub(crate) sr: u16,
+}
+
+impl fmt::Display for FuzzState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, value) in self.registers.iter().enumerate() {
+            write!(f, "r{}={:#06x} ", i + 4, value)?;
+        }
+        let flag = |mask: u16, name: char| if self.sr & mask != 0 {name} else {'-'};
+        return write!(f, "{}{}{}{}", flag(0x100, 'V'), flag(0x4, 'N'), flag(0x2, 'Z'), flag(0x1, 'C'));
+    }
+}
+
+impl FuzzState {
+    /// What the instruction should do, straight from the family user's guide
+    pub(crate) fn execute(&mut self, instruction: &FuzzInstruction) {
+        let (mask, msb): (u32, u32) = if instruction.bw {(0xff, 0x80)} else {(0xffff, 0x8000)};
+        let carry: u32 = (self.sr & 1) as u32;
+        let dst: u32 = self.registers[instruction.dst as usize - 4] as u32 & mask;
+        let src: u32 = match instruction.src {
+            Some(Source::Register(reg)) => self.registers[reg as usize - 4] as u32,
+            Some(Source::Immediate(value)) | Some(Source::Constant(value)) => value as u32,
+            None => 0,
+        } & mask;
+        // result, then C, V (None leaves the flags alone)
+        let add = |a: u32, b: u32, carry: u32| {
+            let sum: u32 = a + b + carry;
+            let result: u32 = sum & mask;
+            return (result, Some((sum > mask, (a ^ result) & (b ^ result) & msb != 0)));
+        };
+        let logic = |result: u32| (result, Some((result != 0, false)));
+        let (result, flags): (u32, Option<(bool, bool)>) = match instruction.op {
+            Op::Mov => (src, None),
+            Op::Add => add(src, dst, 0),
+            Op::Addc => add(src, dst, carry),
+            Op::Sub | Op::Cmp => add(!src & mask, dst, 1),
+            Op::Subc => add(!src & mask, dst, carry),
+            Op::And | Op::Bit => logic(src & dst),
+            Op::Bic => (dst & !src, None),
+            Op::Bis => (dst | src, None),
+            Op::Xor => (src ^ dst, Some((src ^ dst != 0, src & dst & msb != 0))),
+            Op::Rrc => ((dst >> 1) | if carry != 0 {msb} else {0}, Some((dst & 1 != 0, false))),
+            Op::Rra => ((dst >> 1) | (dst & msb), Some((dst & 1 != 0, false))),
+            Op::Swpb => (((dst & 0xff) << 8) | (dst >> 8), None),
+            Op::Sxt => logic((dst & 0xff) as u8 as i8 as i16 as u16 as u32),
+        };
+        if let Some((c, v)) = flags {
+            let mut sr: u16 = 0;
+            if c { sr |= 0x1; }
+            if result == 0 { sr |= 0x2; }
+            if result & msb != 0 { sr |= 0x4; }
+            if v { sr |= 0x100; }
+            self.sr = sr;
+        }
+        if !matches!(instruction.op, Op::Cmp | Op::Bit) {
+            self.registers[instruction.dst as usize - 4] = result as u16;
+        }
+    }
+}
+
+/// Starting state and code for one run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct FuzzCase {
+    pub(crate) state: FuzzState,
+    pub(crate) instructions: Vec<FuzzInstruction>,
+}
+
+/// The first instruction after which the emulator and the flag model disagree
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Mismatch {
+    pub(crate) index: usize,
+    pub(crate) expected: FuzzState,
+    pub(crate) actual: FuzzState,
+}
+
+impl FuzzCase {
+    fn random(state: &mut u64, length: usize) -> FuzzCase {
+        let registers: [u16; 12] = std::array::from_fn(|_| interesting(state));
+        let sr: u16 = split_mix(state) as u16 & FLAGS;
+        let instructions: Vec<FuzzInstruction> = (0..1 + split_mix(state) as usize % length)
+            .map(|_| FuzzInstruction::random(state)).collect();
+        return FuzzCase { state: FuzzState { registers, sr }, instructions };
+    }
+
+    /// Change one thing about the case: an instruction, a register or the flags
+    fn mutate(&self, state: &mut u64, length: usize) -> FuzzCase {
+        let mut case: FuzzCase = self.clone();
+        let index: usize = split_mix(state) as usize % case.instructions.len();
+        match split_mix(state) % 4 {
+            0 => case.instructions[index] = FuzzInstruction::random(state),
+            1 if case.instructions.len() < length => case.instructions.insert(index, FuzzInstruction::random(state)),
+            2 => case.state.registers[split_mix(state) as usize % 12] = interesting(state),
+            _ => case.state.sr ^= [0x1, 0x2, 0x4, 0x100][split_mix(state) as usize % 4],
+        }
+        return case;
+    }
+
+    /// Run on a fresh computer, checking every instruction against the model. `coverage` collects
+    /// which instruction ended with which flags.
+    pub(crate) fn check(&self, coverage: &mut HashSet<(Op, bool, u16)>) -> Result<(), Mismatch> {
+        let c: &mut Computer = &mut Computer::new();
+        c.reset();
+        let mut address: u16 = CODE_START;
+        for word in self.instructions.iter().flat_map(|instruction| instruction.words()) {
+            c.memory.set_word(address, word);
+            address += 2;
+        }
+        for (i, value) in self.state.registers.iter().enumerate() {
+            c.get_register(i as u8 + 4).set_word(*value);
+        }
+        c.sr.set_word(self.state.sr);
+        c.pc.set_word(CODE_START);
+        let mut expected: FuzzState = self.state;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            c.step();
+            expected.execute(instruction);
+            let actual: FuzzState = FuzzState {
+                registers: std::array::from_fn(|i| c.get_register_imut(i as u8 + 4).get_word()),
+                sr: c.sr.get_word(),
+            };
+            if actual != expected {
+                return Err(Mismatch { index, expected, actual });
+            }
+            coverage.insert((instruction.op, instruction.bw, actual.sr));
+        }
+        return Ok(());
+    }
+
+    fn fails(&self) -> bool {
+        return self.check(&mut HashSet::new()).is_err();
+    }
+
+    /// Smallest case that still fails the way `fails` decides: instructions after the failing one
+    /// and any that don't matter are dropped, registers and flags that don't matter are cleared
+    pub(crate) fn shrink(&self, fails: &dyn Fn(&FuzzCase) -> bool) -> FuzzCase {
+        let mut case: FuzzCase = self.clone();
+        let mut changed: bool = true;
+        while changed {
+            changed = false;
+            for i in (0..case.instructions.len()).rev() {
+                let mut smaller: FuzzCase = case.clone();
+                smaller.instructions.truncate(i);
+                if !smaller.instructions.is_empty() && fails(&smaller) {
+                    case = smaller;
+                    changed = true;
+                }
+            }
+            for i in (0..case.instructions.len()).rev() {
+                let mut smaller: FuzzCase = case.clone();
+                smaller.instructions.remove(i);
+                if !smaller.instructions.is_empty() && fails(&smaller) {
+                    case = smaller;
+                    changed = true;
+                }
+            }
+            for i in 0..12 {
+                let mut simpler: FuzzCase = case.clone();
+                simpler.state.registers[i] = 0;
+                if simpler != case && fails(&simpler) {
+                    case = simpler;
+                    changed = true;
+                }
+            }
+            for flag in [0x1, 0x2, 0x4, 0x100] {
+                let mut simpler: FuzzCase = case.clone();
+                simpler.state.sr &= !flag;
+                if simpler != case && fails(&simpler) {
+                    case = simpler;
+                    changed = true;
+                }
+            }
+        }
+        return case;
+    }
+
+    /// Regression file contents: the starting state and code as words, the instructions as comments
+    pub(crate) fn to_regression(&self, note: &str) -> String {
+        let mut text: String = format!("; {}\n", note);
+        for instruction in &self.instructions {
+            text.push_str(&format!(";   {}\n", instruction));
+        }
+        text.push_str(&format!("registers {}\n", self.state.registers.map(|value| format!("{:#06x}", value)).join(" ")));
+        text.push_str(&format!("sr {:#06x}\n", self.state.sr));
+        let words: Vec<String> = self.instructions.iter().flat_map(|instruction| instruction.words())
+            .map(|word| format!("{:#06x}", word)).collect();
+        text.push_str(&format!("code {}\n", words.join(" ")));
+        return text;
+    }
+
+    pub(crate) fn from_regression(text: &str) -> Result<FuzzCase, String> {
+        let number = |word: &str| u16::from_str_radix(word.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid number '{}'", word));
+        let mut case: FuzzCase = FuzzCase { state: FuzzState { registers: [0; 12], sr: 0 }, instructions: vec![] };
+        for line in text.lines().map(|line| line.split(';').next().unwrap_or("").trim()).filter(|line| !line.is_empty()) {
+            let (key, values) = line.split_once(' ').unwrap_or((line, ""));
+            let values: Vec<u16> = values.split_whitespace().map(number).collect::<Result<Vec<u16>, String>>()?;
+            match key {
+                "registers" if values.len() == 12 => case.state.registers.copy_from_slice(&values),
+                "sr" if values.len() == 1 => case.state.sr = values[0],
+                "code" => {
+                    let mut rest: &[u16] = &values;
+                    while !rest.is_empty() {
+                        let (instruction, length) = FuzzInstruction::from_words(rest)
+                            .ok_or_else(|| format!("Not an instruction the fuzzer generates: {:#06x}", rest[0]))?;
+                        case.instructions.push(instruction);
+                        rest = &rest[length..];
+                    }
+                },
+                _ => return Err(format!("Unexpected line '{}'", line)),
+            }
+        }
+        return Ok(case);
+    }
+}
+
+/// What a `fuzz` run found
+pub(crate) struct FuzzReport {
+    pub(crate) cases: u64,
+    /// (instruction, byte mode, flags after) combinations seen
+    pub(crate) coverage: usize,
+    pub(crate) failure: Option<(FuzzCase, Mismatch)>,
+}
+
+/// Generate `cases` random instruction sequences of up to `length` instructions and check each
+/// against the flag model. Sequences that reach a new instruction/flags combination are kept and
+/// mutated further, half of the cases come from them. Stops at the first failure, shrunk.
+pub(crate) fn fuzz(seed: u64, cases: u64, length: usize) -> FuzzReport {
+    let mut state: u64 = seed;
+    let mut coverage: HashSet<(Op, bool, u16)> = HashSet::new();
+    let mut corpus: Vec<FuzzCase> = vec![];
+    for run in 0..cases {
+        let case: FuzzCase = if !corpus.is_empty() && split_mix(&mut state) & 1 == 0 {
+            corpus[split_mix(&mut state) as usize % corpus.len()].mutate(&mut state, length.max(1))
+        } else {
+            FuzzCase::random(&mut state, length.max(1))
+        };
+        let seen: usize = coverage.len();
+        if case.check(&mut coverage).is_err() {
+            let shrunk: FuzzCase = case.shrink(&FuzzCase::fails);
+            let mismatch: Mismatch = shrunk.check(&mut coverage).expect_err("Shrinking keeps the failure");
+            return FuzzReport { cases: run + 1, coverage: coverage.len(), failure: Some((shrunk, mismatch)) };
+        }
+        if coverage.len() > seen {
+            corpus.push(case);
+        }
+    }
+    return FuzzReport { cases, coverage: coverage.len(), failure: None };
+}
+
+/// Save a failing case in `directory` as a regression the test suite replays, returns the path
+pub(crate) fn save_regression(directory: &Path, case: &FuzzCase, note: &str) -> std::io::Result<String> {
+    fs::create_dir_all(directory)?;
+    let text: String = case.to_regression(note);
+    let name: u32 = crate::utils::crc32(text.as_bytes());
+    let path = directory.join(format!("{:08x}.txt", name));
+    fs::write(&path, text)?;
+    return Ok(path.display().to_string());
+}
+
+/// Every regression saved in `directory` with its file name, none if the directory doesn't exist
+pub(crate) fn load_regressions(directory: &Path) -> Result<Vec<(String, FuzzCase)>, String> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut regressions: Vec<(String, FuzzCase)> = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "txt") {
+            let name: String = path.display().to_string();
+            let text: String = fs::read_to_string(&path).map_err(|e| format!("{}: {}", name, e))?;
+            regressions.push((name.clone(), FuzzCase::from_regression(&text).map_err(|e| format!("{}: {}", name, e))?));
+        }
+    }
+    regressions.sort_by(|a, b| a.0.cmp(&b.0));
+    return Ok(regressions);
+}
@@ -0,0 +1,177 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use crate::devices::gpio::{GpioDevice, PinId};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Pull {
+    /// the destination floats when the source isn't driving
+    None,
+    Up,
+    Down,
+}
+
+/// Connects an output pin on one instance to an input pin on another
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Wire {
+    pub(crate) from: PinId,
+    pub(crate) to: PinId,
+    pub(crate) invert: bool,
+    pub(crate) pull: Pull,
+}
+
+impl FromStr for Wire {
+    type Err = String;
+
+    /// `P1.0>P2.3`, optionally followed by `:invert`, `:pullup` or `:pulldown`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (from, to) = parts.next().and_then(|pins| pins.split_once('>'))
+            .ok_or(format!("'{}' is not a wire (expected e.g. P1.0>P2.3)", s))?;
+        let mut wire = Wire { from: from.parse()?, to: to.parse()?, invert: false, pull: Pull::None };
+        for option in parts {
+            match option.trim().to_ascii_lowercase().as_str() {
+                "invert" => wire.invert = true,
+                "pullup" => wire.pull = Pull::Up,
+                "pulldown" => wire.pull = Pull::Down,
+                other => return Err(format!("Unknown wire option '{}'", other)),
+            }
+        }
+        return Ok(wire);
+    }
+}
+
+impl fmt::Display for Wire {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}>{}", self.from, self.to)?;
+        if self.invert {
+            write!(f, ":invert")?;
+        }
+        return match self.pull {
+            Pull::None => Ok(()),
+            Pull::Up => write!(f, ":pullup"),
+            Pull::Down => write!(f, ":pulldown"),
+        };
+    }
+}
+
+impl Wire {
+    /// Level seen at the destination, `None` if it is left floating
+    pub(crate) fn level(&self, source: &GpioDevice) -> Option<bool> {
+        return match source.output(self.from) {
+            Some(level) => Some(level ^ self.invert),
+            None => match self.pull {
+                Pull::None => None,
+                Pull::Up => Some(true),
+                Pull::Down => Some(false),
+            },
+        };
+    }
+}
+
+/// Propagate every wire from one instance to another in the same process
+#[allow(dead_code)]
+pub(crate) fn propagate(wires: &[Wire], from: &GpioDevice, to: &mut GpioDevice) {
+    for wire in wires {
+        to.set_input(wire.to, wire.level(from));
+    }
+}
+
+const LEVEL_LOW: u8 = 0;
+const LEVEL_HIGH: u8 = 1;
+const LEVEL_FLOATING: u8 = 2;
+
+/// Carries wires to another emulator instance over TCP. Each side sends the levels of the wires
+/// it drives as 3-byte messages (port, pin, level), whenever they change.
+pub(crate) struct TcpGpioLink {
+    stream: TcpStream,
+    wires: Vec<Wire>,
+    last_sent: Vec<Option<Option<bool>>>,
+    pending: Vec<u8>,
+}
+
+impl TcpGpioLink {
+    pub(crate) fn listen(address: &str, wires: Vec<Wire>) -> io::Result<TcpGpioLink> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        return TcpGpioLink::new(stream, wires);
+    }
+
+    pub(crate) fn connect(address: &str, wires: Vec<Wire>) -> io::Result<TcpGpioLink> {
+        return TcpGpioLink::new(TcpStream::connect(address)?, wires);
+    }
+
+    fn new(stream: TcpStream, wires: Vec<Wire>) -> io::Result<TcpGpioLink> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        let last_sent = vec![None; wires.len()];
+        return Ok(TcpGpioLink { stream, wires, last_sent, pending: Vec::new() });
+    }
+
+    /// Send changed wire levels and apply levels received from the peer, without blocking
+    pub(crate) fn pump(&mut self, gpio: &mut GpioDevice) -> io::Result<()> {
+        let mut message: Vec<u8> = Vec::new();
+        for (i, wire) in self.wires.iter().enumerate() {
+            let level: Option<bool> = wire.level(gpio);
+            if self.last_sent[i] != Some(level) {
+                self.last_sent[i] = Some(level);
+                let code: u8 = match level {
+                    Some(true) => LEVEL_HIGH,
+                    Some(false) => LEVEL_LOW,
+                    None => LEVEL_FLOATING,
+                };
+                message.extend_from_slice(&[wire.to.port, wire.to.pin, code]);
+            }
+        }
+        if !message.is_empty() {
+            self.stream.set_nonblocking(false)?;
+            let result = self.stream.write_all(&message);
+            self.stream.set_nonblocking(true)?;
+            result?;
+        }
+
+        let mut buf: [u8; 256] = [0; 256];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "GPIO peer disconnected")),
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let complete: usize = self.pending.len() - self.pending.len() % 3;
+        for chunk in self.pending[..complete].chunks(3) {
+            let pin = PinId { port: chunk[0], pin: chunk[1] };
+            if pin.port == 0 || pin.port as usize > crate::devices::gpio::PORT_COUNT || pin.pin > 7 {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("GPIO peer sent invalid pin {}", pin)));
+            }
+            let level: Option<bool> = match chunk[2] {
+                LEVEL_LOW => Some(false),
+                LEVEL_HIGH => Some(true),
+                _ => None,
+            };
+            gpio.set_input(pin, level);
+        }
+        self.pending.drain(..complete);
+        return Ok(());
+    }
+}
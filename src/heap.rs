@@ -0,0 +1,203 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use crate::image::Symbol;
+
+/// What a call the tracker is waiting on the return of was asked to do
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Request {
+    Malloc { size: u16 },
+    Sbrk { increment: i16 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Pending {
+    request: Request,
+    call_site: u16,
+    return_pc: u16,
+    /// SP at the entry, pointing at the return address
+    sp: u16,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Allocation {
+    pub(crate) address: u16,
+    pub(crate) size: u16,
+    /// the CALL to malloc
+    pub(crate) pc: u16,
+}
+
+/// Misuse of the heap, found when `free` is called
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum HeapError {
+    DoubleFree { address: u16, pc: u16 },
+    /// a pointer malloc never returned
+    InvalidFree { address: u16, pc: u16 },
+}
+
+impl std::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            HeapError::DoubleFree { address, pc } => write!(f, "double free of {:#06x} at pc {:#06x}", address, pc),
+            HeapError::InvalidFree { address, pc } =>
+                write!(f, "free of {:#06x} at pc {:#06x}, not allocated by malloc", address, pc),
+        };
+    }
+}
+
+/// Follows `malloc`, `free` and `sbrk` of C firmware by their symbols: arguments and results are
+/// taken from R12 at the entry and the return (the msp430-elf-gcc ABI), reported at exit
+#[derive(Clone)]
+pub(crate) struct HeapTracker {
+    malloc: Option<u16>,
+    free: Option<u16>,
+    sbrk: Option<u16>,
+    pending: Vec<Pending>,
+    /// live allocations by address
+    live: BTreeMap<u16, Allocation>,
+    /// freed addresses, to tell a double free from a bad pointer
+    freed: Vec<u16>,
+    pub(crate) allocations: u64,
+    live_bytes: u32,
+    pub(crate) peak_bytes: u32,
+    pub(crate) peak_allocations: usize,
+    /// break before the first sbrk and the highest it reached
+    break_start: Option<u16>,
+    break_peak: u16,
+    pub(crate) errors: Vec<HeapError>,
+}
+
+impl HeapTracker {
+    /// `None` unless the image has `malloc` or `free` (`sbrk` or `_sbrk` is optional)
+    pub(crate) fn from_symbols(symbols: &[Symbol]) -> Option<HeapTracker> {
+        let address = |names: &[&str]| symbols.iter().find(|s| names.contains(&s.name.as_str())).map(|s| s.address);
+        let (malloc, free) = (address(&["malloc"]), address(&["free"]));
+        if malloc.is_none() && free.is_none() {
+            return None;
+        }
+        return Some(HeapTracker {
+            malloc, free, sbrk: address(&["sbrk", "_sbrk"]),
+            pending: Vec::new(),
+            live: BTreeMap::new(),
+            freed: Vec::new(),
+            allocations: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            peak_allocations: 0,
+            break_start: None,
+            break_peak: 0,
+            errors: Vec::new(),
+        });
+    }
+
+    /// The instruction at `pc` is next, `call_site` ran before it. `return_pc` is the word SP points at.
+    #[inline]
+    pub(crate) fn fetch(&mut self, pc: u16, call_site: u16, sp: u16, r12: u16, return_pc: u16) {
+        while let Some(pending) = self.pending.last().copied() {
+            let returned_sp: u16 = pending.sp.wrapping_add(2);
+            if sp > returned_sp {
+                self.pending.pop(); // the stack unwound past it without returning
+            } else if sp == returned_sp && pc == pending.return_pc {
+                self.pending.pop();
+                self.returned(pending, r12);
+            } else {
+                break;
+            }
+        }
+        let request: Option<Request> = if Some(pc) == self.malloc {
+            Some(Request::Malloc { size: r12 })
+        } else if Some(pc) == self.sbrk {
+            Some(Request::Sbrk { increment: r12 as i16 })
+        } else {
+            if Some(pc) == self.free && r12 != 0 {
+                self.freeing(r12, call_site);
+            }
+            None
+        };
+        if let Some(request) = request {
+            self.pending.push(Pending { request, call_site, return_pc, sp });
+        }
+    }
+
+    fn returned(&mut self, pending: Pending, result: u16) {
+        match pending.request {
+            Request::Malloc { size } if result != 0 => {
+                self.allocations += 1;
+                self.freed.retain(|a| *a != result);
+                self.live.insert(result, Allocation { address: result, size, pc: pending.call_site });
+                self.live_bytes += size as u32;
+                self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+                self.peak_allocations = self.peak_allocations.max(self.live.len());
+            },
+            Request::Malloc { .. } => {},
+            // (void *) -1 when out of memory
+            Request::Sbrk { increment } if result != 0xffff => {
+                self.break_start.get_or_insert(result);
+                self.break_peak = self.break_peak.max(result.wrapping_add(increment as u16));
+            },
+            Request::Sbrk { .. } => {},
+        }
+    }
+
+    fn freeing(&mut self, address: u16, pc: u16) {
+        match self.live.remove(&address) {
+            Some(allocation) => {
+                self.live_bytes -= allocation.size as u32;
+                self.freed.push(address);
+            },
+            None if self.freed.contains(&address) => self.errors.push(HeapError::DoubleFree { address, pc }),
+            None => self.errors.push(HeapError::InvalidFree { address, pc }),
+        }
+    }
+
+    /// The CPU restarted, the C runtime starts a new heap
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.live.clear();
+        self.freed.clear();
+        self.live_bytes = 0;
+    }
+
+    /// Allocations not freed yet, leaks at exit
+    pub(crate) fn live(&self) -> Vec<Allocation> {
+        return self.live.values().copied().collect();
+    }
+
+    /// How far sbrk moved the break at most
+    pub(crate) fn break_growth(&self) -> Option<u16> {
+        return self.break_start.map(|start| self.break_peak.saturating_sub(start));
+    }
+
+    pub(crate) fn report(&self) -> String {
+        let leaked: Vec<Allocation> = self.live();
+        let mut out: String = format!("heap: {} allocations, peak {} bytes in {} live, {} bytes in {} leaked\n",
+                                      self.allocations, self.peak_bytes, self.peak_allocations,
+                                      leaked.iter().map(|a| a.size as u32).sum::<u32>(), leaked.len());
+        if let Some(growth) = self.break_growth() {
+            out.push_str(&format!("heap: sbrk grew the break by {} bytes\n", growth));
+        }
+        for a in &leaked {
+            out.push_str(&format!("  leak: {} bytes at {:#06x}, allocated at pc {:#06x}\n", a.size, a.address, a.pc));
+        }
+        for e in &self.errors {
+            out.push_str(&format!("  error: {}\n", e));
+        }
+        return out;
+    }
+}
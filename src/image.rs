@@ -0,0 +1,357 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use utils::U8Stream;
+
+/// A contiguous block of program bytes, stored in emulator memory order
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Segment {
+    pub(crate) address: u16,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Identifies a build, carried by the v2 format
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Metadata {
+    pub(crate) name: String,
+    pub(crate) build_id: Vec<u8>,
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let build_id: String = self.build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        return write!(f, "{} (build {})", self.name, build_id);
+    }
+}
+
+/// Magic of the v2 format, as a raw format start address it would be odd and so impossible
+pub(crate) const V2_MAGIC: &[u8; 4] = b"MSPI";
+const V2_VERSION: u8 = 2;
+const V2_HAS_ENTRY: u8 = 0x01;
+const V2_HAS_METADATA: u8 = 0x02;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Symbol {
+    pub(crate) name: String,
+    pub(crate) address: u16,
+}
+
+/// A loaded program, independent of the file format it came from
+#[derive(Debug, Clone)]
+pub struct ProgramImage {
+    pub(crate) segments: Vec<Segment>,
+    /// if `None`, execution starts at the reset vector (0xfffe)
+    pub(crate) entry: Option<u16>,
+    pub(crate) symbols: Vec<Symbol>,
+    pub(crate) metadata: Option<Metadata>,
+}
+
+#[allow(dead_code)]
+impl ProgramImage {
+    pub(crate) fn new() -> ProgramImage {
+        return ProgramImage {
+            segments: Vec::new(),
+            entry: None,
+            symbols: Vec::new(),
+            metadata: None,
+        };
+    }
+
+    /// Parse the segmented format (see binary_formats.txt)
+    pub fn from_segmented(byte_data: &[u8]) -> Result<ProgramImage, String> {
+        if byte_data.len() < 4 {
+            return Err("File too short for the segmented format".to_string());
+        }
+        let mut d = U8Stream::new(byte_data);
+        if d.pop_word() != 0xffff {
+            return Err("Invalid marker for segmented format".to_string());
+        }
+        let mut image = ProgramImage::new();
+        let segment_count: u16 = d.pop_word();
+        for i in 0..segment_count {
+            if d.remaining() < 4 {
+                return Err(format!("Segment {} header is truncated", i));
+            }
+            let address: u16 = d.pop_word();
+            let segment_length: u16 = d.pop_word();
+            if d.remaining() < segment_length as usize {
+                return Err(format!("Segment {} at {:#06x} is truncated", i, address));
+            }
+            let data: Vec<u8> = (0..segment_length).map(|_| d.pop_byte()).collect();
+            image.segments.push(Segment { address, data });
+        }
+        return Ok(image);
+    }
+
+    /// Parse the v2 format (see binary_formats.txt), checksums are verified so a corrupted file
+    /// is reported instead of loaded
+    pub fn from_v2(byte_data: &[u8]) -> Result<ProgramImage, String> {
+        if !byte_data.starts_with(V2_MAGIC) {
+            return Err("Invalid marker for the v2 format".to_string());
+        }
+        if byte_data.len() < 14 {
+            return Err("File too short for the v2 format".to_string());
+        }
+        let (body, checksum) = byte_data.split_at(byte_data.len() - 4);
+        let mut d = U8Stream::new(&body[V2_MAGIC.len()..]);
+        let version: u8 = d.pop_byte();
+        if version != V2_VERSION {
+            return Err(format!("Unsupported image version {}", version));
+        }
+        let flags: u8 = d.pop_byte();
+        let entry: u16 = d.pop_word();
+        let mut image = ProgramImage::new();
+        if flags & V2_HAS_ENTRY != 0 {
+            image.entry = Some(entry);
+        }
+        let truncated = || "Header is truncated".to_string();
+        if flags & V2_HAS_METADATA != 0 {
+            let block = |d: &mut U8Stream| -> Result<Vec<u8>, String> {
+                let length: usize = if d.remaining() > 0 {d.pop_byte() as usize} else {return Err(truncated())};
+                if d.remaining() < length {
+                    return Err(truncated());
+                }
+                return Ok((0..length).map(|_| d.pop_byte()).collect());
+            };
+            let name: String = String::from_utf8(block(&mut d)?).map_err(|_| "Name is not UTF-8".to_string())?;
+            let build_id: Vec<u8> = block(&mut d)?;
+            image.metadata = Some(Metadata { name, build_id });
+        }
+        if d.remaining() < 2 {
+            return Err(truncated());
+        }
+        let segment_count: u16 = d.pop_word();
+        for i in 0..segment_count {
+            if d.remaining() < 8 {
+                return Err(format!("Segment {} header is truncated", i));
+            }
+            let address: u16 = d.pop_word();
+            let segment_length: u16 = d.pop_word();
+            let crc: u32 = d.pop_long();
+            if d.remaining() < segment_length as usize {
+                return Err(format!("Segment {} at {:#06x} is truncated", i, address));
+            }
+            let data: Vec<u8> = (0..segment_length).map(|_| d.pop_byte()).collect();
+            if utils::crc32(&data) != crc {
+                return Err(format!("Segment {} at {:#06x} is corrupt (checksum {:#010x}, expected {:#010x})",
+                                   i, address, utils::crc32(&data), crc));
+            }
+            image.segments.push(Segment { address, data });
+        }
+        if d.remaining() != 0 {
+            return Err(format!("{} unexpected bytes after the last segment", d.remaining()));
+        }
+        let expected: u32 = U8Stream::new(checksum).pop_long();
+        if utils::crc32(body) != expected {
+            return Err(format!("Image is corrupt (checksum {:#010x}, expected {:#010x})", utils::crc32(body), expected));
+        }
+        return Ok(image);
+    }
+
+    /// Serialize to the v2 format. The entry point has its own record, segments longer than the
+    /// format allows are split.
+    pub fn to_v2(&self) -> Result<Vec<u8>, String> {
+        let chunks: Vec<(u16, &[u8])> = self.chunks()?;
+        let mut out: Vec<u8> = V2_MAGIC.to_vec();
+        out.push(V2_VERSION);
+        out.push(if self.entry.is_some() {V2_HAS_ENTRY} else {0} | if self.metadata.is_some() {V2_HAS_METADATA} else {0});
+        out.extend_from_slice(&self.entry.unwrap_or(0).to_be_bytes());
+        if let Some(metadata) = &self.metadata {
+            for block in [metadata.name.as_bytes(), &metadata.build_id] {
+                if block.len() > 0xff {
+                    return Err("Name and build id are limited to 255 bytes".to_string());
+                }
+                out.push(block.len() as u8);
+                out.extend_from_slice(block);
+            }
+        }
+        out.extend_from_slice(&(chunks.len() as u16).to_be_bytes());
+        for (address, data) in chunks {
+            out.extend_from_slice(&address.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            out.extend_from_slice(&utils::crc32(data).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        out.extend_from_slice(&utils::crc32(&out).to_be_bytes());
+        return Ok(out);
+    }
+
+    /// An ELF file, the v2 format or either raw format (see binary_formats.txt), whichever `byte_data` is
+    pub fn parse(byte_data: &[u8]) -> Result<ProgramImage, String> {
+        if byte_data.starts_with(elf::ELF_MAGIC) {
+            return elf::parse_elf(byte_data);
+        }
+        if byte_data.starts_with(V2_MAGIC) {
+            return ProgramImage::from_v2(byte_data);
+        }
+        if byte_data.len() < 2 {
+            return Err("File too short".to_string());
+        }
+        if byte_data[0] != 0xff || byte_data[1] != 0xff {
+            return ProgramImage::from_segmented(&utils::convert_code_fmt(byte_data));
+        }
+        return ProgramImage::from_segmented(byte_data);
+    }
+
+    /// Write the segments over the current memory. Nothing else changes, not even the PC.
+    pub fn overlay(&self, computer: &mut Computer) {
+        for segment in &self.segments {
+            for (offset, byte) in segment.data.iter().enumerate() {
+                computer.memory.set_byte(segment.address.wrapping_add(offset as u16), *byte);
+            }
+        }
+    }
+
+    /// Serialize to the segmented format (see binary_formats.txt). An explicit entry point is
+    /// written as the reset vector, segments longer than the format allows are split.
+    pub fn to_segmented(&self) -> Result<Vec<u8>, String> {
+        let mut chunks: Vec<(u16, &[u8])> = self.chunks()?;
+        let entry: Option<[u8; 2]> = self.entry.map(|entry| entry.to_be_bytes());
+        if let Some(entry) = &entry {
+            chunks.push((0xfffe, entry));
+        }
+        if chunks.len() > 0xffff {
+            return Err(format!("{} segments, at most 65535 fit", chunks.len()));
+        }
+        let mut out: Vec<u8> = vec![0xff, 0xff];
+        out.extend_from_slice(&(chunks.len() as u16).to_be_bytes());
+        for (address, data) in chunks {
+            out.extend_from_slice(&address.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        return Ok(out);
+    }
+
+    /// Segments split to fit the 16-bit length of the file formats
+    fn chunks(&self) -> Result<Vec<(u16, &[u8])>, String> {
+        let mut chunks: Vec<(u16, &[u8])> = Vec::new();
+        for segment in &self.segments {
+            if segment.address as usize + segment.data.len() > 0x10000 {
+                return Err(format!("Segment at {:#06x} runs past the end of memory", segment.address));
+            }
+            for (i, chunk) in segment.data.chunks(0xfffe).enumerate() {
+                chunks.push((segment.address + (i * 0xfffe) as u16, chunk));
+            }
+        }
+        if chunks.len() > 0xffff {
+            return Err(format!("{} segments, at most 65535 fit", chunks.len()));
+        }
+        return Ok(chunks);
+    }
+
+    /// This does NOT reset the computer, other than loading the PC
+    pub fn load(&self, computer: &mut Computer) {
+        self.overlay(computer);
+        let entry: u16 = match self.entry {
+            Some(entry) => entry,
+            None => computer.memory.get_word(0xfffe),
+        };
+        computer.pc.set_word(entry);
+    }
+
+    /// Word the segments leave at 0xfffe
+    pub(crate) fn reset_vector(&self) -> Option<u16> {
+        let byte = |address: u16| self.segments.iter().rev()
+            .find(|s| s.address <= address && ((address - s.address) as usize) < s.data.len())
+            .map(|s| s.data[(address - s.address) as usize]);
+        return Some(u16::from_be_bytes([byte(0xfffe)?, byte(0xffff)?]));
+    }
+
+    /// CRC-32 over the segments (address, length and data each) and the entry point, tells a
+    /// frontend whether the image it knows is the one loaded
+    pub(crate) fn checksum(&self) -> u32 {
+        let mut bytes: Vec<u8> = Vec::new();
+        for segment in &self.segments {
+            bytes.extend_from_slice(&segment.address.to_be_bytes());
+            bytes.extend_from_slice(&(segment.data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&segment.data);
+        }
+        if let Some(entry) = self.entry {
+            bytes.extend_from_slice(&entry.to_be_bytes());
+        }
+        return utils::crc32(&bytes);
+    }
+
+    pub(crate) fn symbol(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.iter().find(|s| s.name == name);
+    }
+
+    /// Combine images loaded one after the other: later segments are written over earlier ones,
+    /// the last explicit entry point wins and later symbols replace earlier ones of the same name
+    pub fn merge(images: Vec<ProgramImage>) -> ProgramImage {
+        let mut merged = ProgramImage::new();
+        for image in images {
+            merged.segments.extend(image.segments);
+            merged.entry = image.entry.or(merged.entry);
+            merged.metadata = image.metadata.or(merged.metadata);
+            merged.symbols.retain(|s| image.symbols.iter().all(|other| other.name != s.name));
+            merged.symbols.extend(image.symbols);
+        }
+        return merged;
+    }
+}
+
+/// One image to load before execution starts (`run --load FILE[@ADDRESS]`). With an address
+/// the file is a plain data blob placed there, otherwise it's parsed as ELF or a raw format.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ImageSpec {
+    pub(crate) path: String,
+    pub(crate) address: Option<u16>,
+}
+
+impl ImageSpec {
+    pub(crate) fn read(&self) -> Result<ProgramImage, String> {
+        let data: Vec<u8> = std::fs::read(&self.path).map_err(|e| format!("'{}': {}", self.path, e))?;
+        return match self.address {
+            Some(address) => {
+                if address as usize + data.len() > 0x10000 {
+                    return Err(format!("'{}' does not fit at {:#06x}", self.path, address));
+                }
+                let mut image = ProgramImage::new();
+                image.segments.push(Segment { address, data });
+                Ok(image)
+            },
+            None => ProgramImage::parse(&data).map_err(|e| format!("'{}': {}", self.path, e)),
+        };
+    }
+}
+
+impl std::str::FromStr for ImageSpec {
+    type Err = String;
+
+    /// The part after the last `@` is an address only if it parses as one, so paths may contain `@`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((path, address)) = s.rsplit_once('@') {
+            if let Ok(address) = utils::parse_u16(address) {
+                return Ok(ImageSpec { path: path.to_string(), address: Some(address) });
+            }
+        }
+        return Ok(ImageSpec { path: s.to_string(), address: None });
+    }
+}
+
+impl std::fmt::Display for ImageSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self.address {
+            Some(address) => write!(f, "{}@{:#06x}", self.path, address),
+            None => write!(f, "{}", self.path),
+        };
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::Computer;
+use crate::image::Symbol;
+
+pub(crate) const VECTOR_TABLE: u16 = 0xffe0;
+pub(crate) const RESET_VECTOR: u16 = 0xfffe;
+
+/// One device's interrupt request, `flagged` is its interrupt flag whether or not it is enabled
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct InterruptSource {
+    pub(crate) name: &'static str,
+    pub(crate) vector: u16,
+    /// taken even without GIE
+    pub(crate) nmi: bool,
+    pub(crate) enabled: bool,
+    pub(crate) flagged: bool,
+}
+
+impl InterruptSource {
+    pub(crate) fn pending(&self) -> bool {
+        return self.enabled && self.flagged;
+    }
+}
+
+/// A slot of the vector table and the sources that use it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct VectorEntry {
+    pub(crate) vector: u16,
+    pub(crate) handler: u16,
+    /// symbol at the handler address
+    pub(crate) symbol: Option<String>,
+    pub(crate) sources: Vec<InterruptSource>,
+}
+
+impl VectorEntry {
+    /// Erased flash reads 0xffff and cleared memory 0, neither is a handler
+    pub(crate) fn populated(&self) -> bool {
+        return self.handler != 0xffff && self.handler != 0;
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        return self.sources.iter().any(|s| s.enabled);
+    }
+
+    pub(crate) fn flagged(&self) -> bool {
+        return self.sources.iter().any(|s| s.flagged);
+    }
+
+    pub(crate) fn pending(&self) -> bool {
+        return self.sources.iter().any(|s| s.pending());
+    }
+
+    pub(crate) fn nmi(&self) -> bool {
+        return self.sources.iter().any(|s| s.nmi);
+    }
+}
+
+/// All 16 slots of 0xffe0-0xfffe, lowest first
+pub(crate) fn vector_map(c: &Computer, symbols: &[Symbol]) -> Vec<VectorEntry> {
+    let sources: Vec<InterruptSource> = c.devices.interrupt_sources();
+    return (VECTOR_TABLE..=RESET_VECTOR).step_by(2).map(|vector| {
+        let handler: u16 = c.memory.get_word(vector);
+        return VectorEntry {
+            vector,
+            handler,
+            symbol: symbols.iter().find(|s| s.address == handler).map(|s| s.name.clone()),
+            sources: sources.iter().filter(|s| s.vector == vector).copied().collect(),
+        };
+    }).collect();
+}
+
+/// Populated vectors and vectors with a device behind them, one per line
+pub(crate) fn describe(entries: &[VectorEntry]) -> String {
+    let mut out: String = String::new();
+    for entry in entries.iter().filter(|e| e.populated() || !e.sources.is_empty()) {
+        let handler: String = match (&entry.symbol, entry.populated()) {
+            (Some(symbol), _) => format!("{:#06x} {}", entry.handler, symbol),
+            (None, true) => format!("{:#06x}", entry.handler),
+            (None, false) => "unset".to_string(),
+        };
+        let mut sources: Vec<String> = entry.sources.iter().map(|s| {
+            let state: &str = match (s.enabled, s.flagged) {
+                (true, true) => "pending",
+                (true, false) => "enabled",
+                (false, true) => "flagged",
+                (false, false) => "disabled",
+            };
+            format!("{}{} {}", s.name, if s.nmi {" (NMI)"} else {""}, state)
+        }).collect();
+        if entry.vector == RESET_VECTOR {
+            sources.push("reset".to_string());
+        }
+        out += &format!("{:#06x} -> {:<24} {}\n", entry.vector, handler, sources.join(", "));
+    }
+    return out;
+}
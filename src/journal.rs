@@ -0,0 +1,124 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 4] = b"MSPJ";
+const VERSION: u8 = 1;
+const ENTRY_SIZE: usize = 17;
+
+const FLAG_BYTE: u8 = 0x01;
+const FLAG_DEVICE: u8 = 0x02;
+
+/// One data write made by firmware
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct JournalEntry {
+    pub(crate) cycle: u64,
+    pub(crate) pc: u16,
+    pub(crate) address: u16,
+    /// value in memory before the write (0 for device registers, they aren't backed by memory)
+    pub(crate) old: u16,
+    pub(crate) new: u16,
+    pub(crate) byte: bool,
+    /// the write went to a device register rather than memory
+    pub(crate) device: bool,
+}
+
+impl JournalEntry {
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut out: [u8; ENTRY_SIZE] = [0; ENTRY_SIZE];
+        out[0..8].copy_from_slice(&self.cycle.to_be_bytes());
+        out[8..10].copy_from_slice(&self.pc.to_be_bytes());
+        out[10..12].copy_from_slice(&self.address.to_be_bytes());
+        out[12..14].copy_from_slice(&self.old.to_be_bytes());
+        out[14..16].copy_from_slice(&self.new.to_be_bytes());
+        out[16] = if self.byte {FLAG_BYTE} else {0} | if self.device {FLAG_DEVICE} else {0};
+        return out;
+    }
+
+    fn decode(data: &[u8; ENTRY_SIZE]) -> JournalEntry {
+        let word = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        return JournalEntry {
+            cycle: u64::from_be_bytes(data[0..8].try_into().expect("8 bytes")),
+            pc: word(8),
+            address: word(10),
+            old: word(12),
+            new: word(14),
+            byte: data[16] & FLAG_BYTE != 0,
+            device: data[16] & FLAG_DEVICE != 0,
+        };
+    }
+}
+
+/// Appends every firmware write to a binary journal: a "MSPJ" + version header followed by
+/// fixed 17-byte entries (cycle u64, pc, address, old, new as big-endian u16s, flags)
+pub(crate) struct WriteJournal {
+    out: BufWriter<Box<dyn Write>>,
+}
+
+impl WriteJournal {
+    pub(crate) fn new(out: Box<dyn Write>) -> io::Result<WriteJournal> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        return Ok(WriteJournal { out });
+    }
+
+    pub(crate) fn create(path: &str) -> io::Result<WriteJournal> {
+        return WriteJournal::new(Box::new(std::fs::File::create(path)?));
+    }
+
+    pub(crate) fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        return self.out.write_all(&entry.encode());
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+}
+
+pub(crate) fn read_journal<R: Read>(mut input: R) -> io::Result<Vec<JournalEntry>> {
+    let mut header: [u8; 5] = [0; 5];
+    input.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not a write journal"));
+    }
+    if header[4] != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Unsupported journal version {}", header[4])));
+    }
+    let mut data: Vec<u8> = Vec::new();
+    input.read_to_end(&mut data)?;
+    // a run killed mid-write can leave a partial entry at the end, drop it
+    return Ok(data.chunks_exact(ENTRY_SIZE)
+        .map(|chunk| JournalEntry::decode(chunk.try_into().expect("exact chunk")))
+        .collect());
+}
+
+pub(crate) fn to_csv<W: Write>(entries: &[JournalEntry], mut out: W) -> io::Result<()> {
+    writeln!(out, "cycle,pc,address,width,old,new,target")?;
+    for e in entries {
+        let (width, old, new) = if e.byte {
+            ("byte", format!("0x{:02x}", e.old), format!("0x{:02x}", e.new))
+        } else {
+            ("word", format!("0x{:04x}", e.old), format!("0x{:04x}", e.new))
+        };
+        writeln!(out, "{},0x{:04x},0x{:04x},{},{},{},{}", e.cycle, e.pc, e.address, width, old, new,
+                 if e.device {"device"} else {"memory"})?;
+    }
+    return out.flush();
+}
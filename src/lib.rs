@@ -0,0 +1,5047 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// the codebase deliberately uses explicit returns and upper-case opcode names
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::new_ret_no_self, clippy::new_without_default,
+    clippy::needless_late_init, clippy::single_match, clippy::vec_init_then_push, clippy::needless_range_loop)]
+
+use std::{time::{Duration, Instant}, fs::File, io::Read, sync::{Arc, atomic::{AtomicBool, Ordering}}, env, process::{self}};
+use std::ffi::{c_char, CStr};
+use std::str;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::path::Path;
+
+use bitflags::bitflags;
+use num_enum::TryFromPrimitive;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use shared_memory::{Shmem, ShmemConf, ShmemError};
+use sysinfo::{System, SystemExt, Pid};
+
+use devices::Devices;
+use devices::framebuffer::FrameBufferDevice;
+use bus::{BusMap, Handler};
+use devices::mpu::{self, Access};
+use devices::mailbox;
+use devices::cs::Crystal;
+use devices::touch::TouchPad;
+use devices::sd_card::{SdCard, SdCardSpec};
+use devices::radio::{Packet, Radio, RadioSpec};
+use clock::{Clock, TimeSource};
+use uart_link::TcpUartLink;
+use radio_link::UdpRadioLink;
+use gpio_link::TcpGpioLink;
+use stdin_link::StdinLink;
+use journal::{JournalEntry, WriteJournal};
+use uart_capture::UartCapture;
+use framebuffer::{FrameBufferSpec, FrameExport};
+use branch_trace::{BranchKind, BranchRecord, BranchTrace};
+use pc_history::PcHistory;
+use watch::{WatchEvent, WatchList};
+use notify::Notifier;
+use script::Script;
+use profile::{DeviceProfile, Profile, Region, RegisterReset, StartupState};
+use builder::ComputerBuilder;
+use eem::{Eem, Trigger, TriggerKind};
+use stimulus::Stimulus;
+use analog::{AnalogSpec, Waveform};
+use image::{ImageSpec, ProgramImage};
+use dump::{DumpFormat, DumpSpec};
+use pwm::PwmAnalyzer;
+use sensors::{SensorSpec, Sensors};
+use ws2812::{LedStrip, LedStripSpec};
+use widgets::{EncoderSpec, MatrixSpec, Widgets};
+use midi_link::{MidiLink, MidiSource, OscMapping, WriteTap};
+use worker::Hosted;
+use profiler::Profiler;
+use heap::HeapTracker;
+use runaway::RunawayDetector;
+use diagnostics::{Category, CheckSpec, Diagnostic, Diagnostics, EmulationMode};
+use shadow_stack::{Corruption, ShadowStack};
+use trace_hash::TraceHasher;
+use lockstep::{Lockstep, MachineConfig, Outcome};
+use fuzz::FuzzReport;
+use storm::InterruptStorm;
+use shmem_layout as layout;
+use stepping::{StepGoal, StepRun, StopReason};
+use devices::gpio::PinId;
+use decode::Decoded;
+use run_log::{LogFormat, LogLevel, RunLog};
+use metrics::RunMetrics;
+use scheduler::BatchSizer;
+use replay::{CommandRecorder, RecordedCommand, RecordedEnd, Recording, ReplayStep};
+use serde_json::json;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+enum CLI {
+    /// benchmark code (only in dev env)
+    Benchmark,
+    /// Run emulator in foreground [PARENT_PID]
+    Run(RunForkedArgs),
+    /// Run emulator in separate process [PARENT_PID]
+    RunForked(RunForkedArgs),
+    /// Run the control commands recorded with `run --record` again, headless
+    Replay(ReplayArgs),
+    /// Run firmware unit tests from an ELF file
+    Test(TestArgs),
+    /// Convert a write journal (`run --journal`) to CSV
+    JournalCsv(JournalCsvArgs),
+    /// Convert a branch trace (`run --branch-trace`) to text
+    BranchTraceText(BranchTraceTextArgs),
+    /// Combine images into one file in the segmented format
+    Pack(PackArgs),
+    /// Convert a firmware image between formats
+    Convert(ConvertArgs),
+    /// Assemble a source file into a relocatable object
+    Assemble(AssembleArgs),
+    /// Link relocatable objects into an image
+    Link(LinkArgs),
+    /// List the instructions of an image
+    Disassemble(DisassembleArgs),
+    /// List an image's interrupt vectors and the devices behind them
+    Vectors(VectorsArgs),
+    /// Run an image headless and print a hash of its execution, to check that emulator changes
+    /// keep execution bit-identical
+    TraceHash(TraceHashArgs),
+    /// Run an image on two differently set up machines at once and stop at the first instruction
+    /// after which they disagree
+    Lockstep(LockstepArgs),
+    /// Run random instruction sequences against a reference flag model, shrinking and saving any
+    /// disagreement as a regression test
+    Fuzz(FuzzArgs),
+    /// Run an image while firing random interrupts at it, checking every handler restores the
+    /// stack, SR and registers
+    Storm(StormArgs),
+    /// Relay radio packets between instances started with `run --radio-broker`
+    RadioBroker(RadioBrokerArgs),
+}
+
+#[derive(Parser)]
+struct RunForkedArgs {
+    /// Process to listen for
+    parent_pid: Option<u64>,
+    /// Once the process listened for exits, wait this many seconds for a new frontend to attach
+    /// (shared memory command 25) before exiting
+    #[arg(long, default_value_t = 0)]
+    orphan_grace: u64,
+    /// Seed for the RNG device (random if not given, the seed used is printed so runs can be replayed)
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Make runs bit-identical: the seed defaults to 0, time is emulated, batches have a fixed size
+    /// and options that feed in host input as it arrives (links to other instances, stdin) are refused
+    #[arg(long, conflicts_with_all = ["uart_listen", "uart_connect", "gpio_listen", "gpio_connect", "radio_broker", "stdin"])]
+    deterministic: bool,
+    /// What drives real-time devices (RTC, ACLK), always emulated with --deterministic
+    #[arg(long, value_enum, default_value_t = TimeSource::Emulated, conflicts_with = "deterministic")]
+    time_source: TimeSource,
+    /// Let MCLK drift within this many percent of 1 MHz, the way an uncalibrated DCO does (the drift
+    /// follows --seed, 0 keeps the clock exact)
+    #[arg(long, default_value_t = 0.0)]
+    dco_tolerance: f64,
+    /// Wait for another instance to connect its UART to ours at this address (e.g. 127.0.0.1:4300)
+    #[arg(long, conflicts_with = "uart_connect")]
+    uart_listen: Option<String>,
+    /// Connect our UART to another instance listening at this address
+    #[arg(long)]
+    uart_connect: Option<String>,
+    /// Parse what firmware writes here as MIDI, `uart` or an address whose byte writes are the
+    /// stream (repeatable), sent to --midi-out and --osc-out
+    #[arg(long = "midi-source")]
+    midi_sources: Vec<MidiSource>,
+    /// Send every write to an address as an OSC message with the value, ADDRESS:/PATH, e.g.
+    /// 0x0200:/synth/cutoff (repeatable)
+    #[arg(long = "osc-map", requires = "osc_out")]
+    osc_mappings: Vec<OscMapping>,
+    /// Write the MIDI messages to this file or device as raw bytes, e.g. /dev/snd/midiC1D0
+    #[arg(long)]
+    midi_out: Option<String>,
+    /// Send MIDI messages and --osc-map writes as OSC over UDP to this address, e.g. 127.0.0.1:57120
+    #[arg(long)]
+    osc_out: Option<String>,
+    /// Feed our stdin to the console input device (with run-forked stdin is not connected, the
+    /// input reads as closed right away)
+    #[arg(long)]
+    stdin: bool,
+    /// Let firmware open files in this directory through the host file device (nothing outside it
+    /// can be reached, without it every open fails)
+    #[arg(long)]
+    file_dir: Option<String>,
+    /// Attach an SD card to the SPI bus (USCI_B0), CS:IMAGE with CS the chip select pin and IMAGE
+    /// a disk image whose size is a multiple of 512 bytes, e.g. P1.4:card.img. Written blocks go to
+    /// the image file.
+    #[arg(long)]
+    sd_card: Option<SdCardSpec>,
+    /// Attach an nRF24L01-style radio to the SPI bus, CSN:CE:IRQ pins, e.g. P2.0:P2.1:P2.2
+    #[arg(long)]
+    radio: Option<RadioSpec>,
+    /// Put the radio on the air of a `radio-broker` at this address, with every other instance
+    /// using it (e.g. 127.0.0.1:4400)
+    #[arg(long, requires = "radio")]
+    radio_broker: Option<String>,
+    /// Wait for another instance to connect GPIO wires to ours at this address
+    #[arg(long, conflicts_with = "gpio_connect")]
+    gpio_listen: Option<String>,
+    /// Connect GPIO wires to another instance listening at this address
+    #[arg(long)]
+    gpio_connect: Option<String>,
+    /// Drive a pin on the linked instance from one of ours, e.g. P1.0>P2.3[:invert][:pullup|:pulldown] (repeatable)
+    #[arg(long = "gpio-wire")]
+    gpio_wires: Vec<gpio_link::Wire>,
+    /// Run the MIDI/OSC bridge and the radio link on their own threads, meeting the CPU every this
+    /// many cycles: the CPU waits there for them to finish the previous quantum
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    peripheral_quantum: Option<u64>,
+    /// Record every memory write made by firmware to this file (convert with `journal-csv`)
+    #[arg(long)]
+    journal: Option<String>,
+    /// Record every control-flow change (jumps, calls, returns, interrupts) to this file, enough to
+    /// reconstruct the executed path with the image (convert with `branch-trace-text`)
+    #[arg(long)]
+    branch_trace: Option<String>,
+    /// Write every byte transmitted on the UART to this file, a line each with the cycle it was
+    /// sent at, for golden-output tests that check timing too
+    #[arg(long)]
+    uart_capture: Option<String>,
+    /// How many executed instructions to remember for post-mortem dumps (0 disables)
+    #[arg(long, default_value_t = pc_history::DEFAULT_CAPACITY)]
+    pc_history: usize,
+    /// Show the PC history as encoded instead of with emulated mnemonics (`mov @sp+ pc` for `ret`)
+    #[arg(long)]
+    canonical: bool,
+    /// Load this image before starting, FILE (ELF or raw format) or FILE@ADDRESS for a plain data
+    /// blob (repeatable, loaded in order so later images overwrite earlier ones). The CPU waits at
+    /// the entry point until a frontend runs or steps it.
+    #[arg(long = "load")]
+    images: Vec<ImageSpec>,
+    /// Write a memory range to a file when the run ends, START-END:FORMAT:PATH with FORMAT one of
+    /// raw, hex, ti-txt or hexdump (repeatable)
+    #[arg(long = "dump")]
+    dumps: Vec<DumpSpec>,
+    /// Handle the control commands in this file before any from the frontend (breakpoints, loads,
+    /// memory, run, ...), each waits until the emulator is stopped
+    #[arg(long = "exec")]
+    script: Option<String>,
+    /// Replay input pin levels from this file (CSV `cycle,pin,level` or a JSON array of events)
+    #[arg(long)]
+    stimulus: Option<String>,
+    /// Play a waveform into a comparator input, CA0:FILE with FILE CSV `cycle,millivolts` or a
+    /// PCM WAV file (repeatable)
+    #[arg(long = "analog")]
+    analog_inputs: Vec<AnalogSpec>,
+    /// Measure frequency and duty cycle of this output pin, e.g. P1.2 (repeatable), reported on exit
+    /// and over shared memory
+    #[arg(long = "pwm")]
+    pwm_pins: Vec<PinId>,
+    /// Answer a bit-banged sensor protocol on a pin and log its transactions, e.g. ds18b20:P1.4:21.5
+    /// or dht22:P1.5:21.5:40 (KIND:PIN[:CELSIUS[:HUMIDITY]], repeatable, DS18B20s may share a pin)
+    #[arg(long = "sensor")]
+    sensors: Vec<SensorSpec>,
+    /// Decode a strip of WS2812 LEDs from this output pin, PIN[:COUNT] (repeatable), its colors are
+    /// printed on exit and can be queried over shared memory
+    #[arg(long = "ws2812")]
+    led_strips: Vec<LedStripSpec>,
+    /// Write every frame the LED strips latch to this file, CSV `cycle,pin,leds`
+    #[arg(long, requires = "led_strips")]
+    ws2812_frames: Option<String>,
+    /// Give firmware a frame buffer in memory, WIDTHxHEIGHT:FORMAT@ADDRESS with FORMAT one of mono,
+    /// gray, rgb332 or rgb565, e.g. 128x64:mono@0x2400 (see emulator_devices.txt)
+    #[arg(long)]
+    framebuffer: Option<FrameBufferSpec>,
+    /// Write every frame firmware presents to this directory as frame-NNNNNN.ppm
+    #[arg(long, requires = "framebuffer")]
+    framebuffer_images: Option<String>,
+    /// Attach a rotary encoder to these pins, A,B[,SWITCH] (repeatable, numbered from 0 in order),
+    /// turned and pressed over shared memory or from `--exec` scripts
+    #[arg(long = "encoder")]
+    encoders: Vec<EncoderSpec>,
+    /// Attach a keypad scanned through row and column pins, ROWS:COLUMNS, e.g. P1.0,P1.1:P2.0,P2.1,P2.2
+    /// (repeatable, numbered from 0 in order), its keys are pressed like the encoders
+    #[arg(long = "button-matrix")]
+    button_matrices: Vec<MatrixSpec>,
+    /// Capacitance on a pin's touch pad in pF, e.g. P2.0:12.5 (repeatable, untouched pads are 10 pF),
+    /// sets the frequency of its pin oscillator
+    #[arg(long = "touch-pad")]
+    touch_pads: Vec<TouchPad>,
+    /// Halt once this many cycles pass without reaching new code or servicing the watchdog
+    #[arg(long)]
+    runaway_cycles: Option<u64>,
+    /// Halt before a RET or RETI pops a return address other than the one its CALL or interrupt pushed
+    #[arg(long)]
+    shadow_stack: bool,
+    /// Diagnose firmware that would behave differently on hardware, CATEGORY[=SEVERITY] with
+    /// SEVERITY ignore, log (the default, a warning once per category and address), break (also
+    /// stops the run), reset (also restarts the CPU with a PUC) or trap (faults the CPU) (repeatable)
+    #[arg(long = "check", value_name = "CATEGORY[=SEVERITY]")]
+    checks: Vec<CheckSpec>,
+    /// Default severity of every --check category: permissive ignores them all, strict traps
+    /// (faults the CPU) on any of them
+    #[arg(long, value_enum, default_value_t = EmulationMode::Permissive)]
+    emulation: EmulationMode,
+    /// Print cycles by firmware function (inclusive and exclusive, attributed with the image's
+    /// symbols) at exit
+    #[arg(long)]
+    function_profile: bool,
+    /// Write the function profile to this file in callgrind format at exit (for KCachegrind)
+    #[arg(long)]
+    callgrind: Option<String>,
+    /// Memory layout of the emulated part, decides which regions are no-execute
+    #[arg(long, value_enum, default_value_t = Profile::Generic)]
+    profile: Profile,
+    /// Stack pointer after a reset (the profile's default is the top of its RAM)
+    #[arg(long, value_parser = utils::parse_u16)]
+    reset_sp: Option<u16>,
+    /// Register value after a reset instead of 0, e.g. r4=0xffff (repeatable)
+    #[arg(long = "reset-register")]
+    reset_registers: Vec<RegisterReset>,
+    /// Byte the profile's RAM holds at power-up instead of 0, e.g. 0xcd to catch uninitialized reads
+    #[arg(long, value_parser = utils::parse_u8)]
+    ram_fill: Option<u8>,
+    /// Also halt if an instruction is fetched from this region, e.g. 0x0200-0x03ff (repeatable)
+    #[arg(long = "no-execute")]
+    no_execute: Vec<Region>,
+    /// Keep this region across power cycles (shared memory command 30) like the profile's backup
+    /// memory, e.g. 0x0200-0x021f (repeatable)
+    #[arg(long = "retain")]
+    retained: Vec<Region>,
+    /// Drop (and diagnose, `--check read-only-write`) data writes to this region like to ROM, in
+    /// addition to the profile's, e.g. 0xc000-0xffff (repeatable)
+    #[arg(long = "read-only")]
+    read_only: Vec<Region>,
+    /// Make the segments of loaded images read-only too (`--load`, shared memory commands 4 and 19),
+    /// so stray writes can't corrupt the program
+    #[arg(long)]
+    read_only_image: bool,
+    /// Bring the watchdog out of a reset held instead of running, for firmware that never stops or
+    /// services it
+    #[arg(long)]
+    hold_watchdog: bool,
+    /// Name of this instance, needed to run several emulators side by side (shared memory id becomes msp430_shmem_id_<NAME>)
+    #[arg(long)]
+    instance: Option<String>,
+    /// How run loop events (loads, commands, run mode changes, errors) are printed
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Least important events printed, `debug` adds every handled command
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+    /// Record every control command with when it was handled to this file, for `replay`
+    #[arg(long)]
+    record: Option<String>,
+    /// Milliseconds between command polls while running, instructions run in batches sized to match
+    #[arg(long, default_value_t = 10)]
+    poll_interval: u64,
+}
+
+impl RunForkedArgs {
+    /// Arguments to pass to a `run` child process so it behaves identically
+    fn to_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec!["run".to_string()];
+        if let Some(pid) = self.parent_pid {
+            args.push(pid.to_string());
+        }
+        if self.orphan_grace > 0 {
+            args.push("--orphan-grace".to_string());
+            args.push(self.orphan_grace.to_string());
+        }
+        if let Some(seed) = self.seed {
+            args.push("--seed".to_string());
+            args.push(seed.to_string());
+        }
+        if self.deterministic {
+            args.push("--deterministic".to_string());
+        }
+        if self.time_source != TimeSource::Emulated {
+            args.push("--time-source".to_string());
+            args.push(self.time_source.to_possible_value().expect("No skipped variants").get_name().to_string());
+        }
+        if self.dco_tolerance != 0.0 {
+            args.push("--dco-tolerance".to_string());
+            args.push(self.dco_tolerance.to_string());
+        }
+        if let Some(address) = &self.uart_listen {
+            args.push("--uart-listen".to_string());
+            args.push(address.clone());
+        }
+        if let Some(address) = &self.uart_connect {
+            args.push("--uart-connect".to_string());
+            args.push(address.clone());
+        }
+        for source in &self.midi_sources {
+            args.push("--midi-source".to_string());
+            args.push(source.to_string());
+        }
+        for mapping in &self.osc_mappings {
+            args.push("--osc-map".to_string());
+            args.push(mapping.to_string());
+        }
+        if let Some(path) = &self.midi_out {
+            args.push("--midi-out".to_string());
+            args.push(path.clone());
+        }
+        if let Some(address) = &self.osc_out {
+            args.push("--osc-out".to_string());
+            args.push(address.clone());
+        }
+        if self.stdin {
+            args.push("--stdin".to_string());
+        }
+        if let Some(dir) = &self.file_dir {
+            args.push("--file-dir".to_string());
+            args.push(dir.clone());
+        }
+        if let Some(card) = &self.sd_card {
+            args.push("--sd-card".to_string());
+            args.push(card.to_string());
+        }
+        if let Some(radio) = &self.radio {
+            args.push("--radio".to_string());
+            args.push(radio.to_string());
+        }
+        if let Some(address) = &self.radio_broker {
+            args.push("--radio-broker".to_string());
+            args.push(address.clone());
+        }
+        if let Some(address) = &self.gpio_listen {
+            args.push("--gpio-listen".to_string());
+            args.push(address.clone());
+        }
+        if let Some(address) = &self.gpio_connect {
+            args.push("--gpio-connect".to_string());
+            args.push(address.clone());
+        }
+        for wire in &self.gpio_wires {
+            args.push("--gpio-wire".to_string());
+            args.push(wire.to_string());
+        }
+        if let Some(cycles) = self.peripheral_quantum {
+            args.push("--peripheral-quantum".to_string());
+            args.push(cycles.to_string());
+        }
+        if let Some(path) = &self.journal {
+            args.push("--journal".to_string());
+            args.push(path.clone());
+        }
+        if let Some(path) = &self.branch_trace {
+            args.push("--branch-trace".to_string());
+            args.push(path.clone());
+        }
+        if let Some(path) = &self.uart_capture {
+            args.push("--uart-capture".to_string());
+            args.push(path.clone());
+        }
+        args.push("--pc-history".to_string());
+        args.push(self.pc_history.to_string());
+        if self.canonical {
+            args.push("--canonical".to_string());
+        }
+        for image in &self.images {
+            args.push("--load".to_string());
+            args.push(image.to_string());
+        }
+        for dump in &self.dumps {
+            args.push("--dump".to_string());
+            args.push(dump.to_string());
+        }
+        if let Some(path) = &self.script {
+            args.push("--exec".to_string());
+            args.push(path.clone());
+        }
+        if let Some(path) = &self.stimulus {
+            args.push("--stimulus".to_string());
+            args.push(path.clone());
+        }
+        for input in &self.analog_inputs {
+            args.push("--analog".to_string());
+            args.push(input.to_string());
+        }
+        for pin in &self.pwm_pins {
+            args.push("--pwm".to_string());
+            args.push(pin.to_string());
+        }
+        for sensor in &self.sensors {
+            args.push("--sensor".to_string());
+            args.push(sensor.to_string());
+        }
+        for strip in &self.led_strips {
+            args.push("--ws2812".to_string());
+            args.push(strip.to_string());
+        }
+        if let Some(path) = &self.ws2812_frames {
+            args.push("--ws2812-frames".to_string());
+            args.push(path.clone());
+        }
+        if let Some(spec) = &self.framebuffer {
+            args.push("--framebuffer".to_string());
+            args.push(spec.to_string());
+        }
+        if let Some(dir) = &self.framebuffer_images {
+            args.push("--framebuffer-images".to_string());
+            args.push(dir.clone());
+        }
+        for encoder in &self.encoders {
+            args.push("--encoder".to_string());
+            args.push(encoder.to_string());
+        }
+        for matrix in &self.button_matrices {
+            args.push("--button-matrix".to_string());
+            args.push(matrix.to_string());
+        }
+        for pad in &self.touch_pads {
+            args.push("--touch-pad".to_string());
+            args.push(pad.to_string());
+        }
+        if let Some(cycles) = self.runaway_cycles {
+            args.push("--runaway-cycles".to_string());
+            args.push(cycles.to_string());
+        }
+        if self.shadow_stack {
+            args.push("--shadow-stack".to_string());
+        }
+        for check in &self.checks {
+            args.push("--check".to_string());
+            args.push(check.to_string());
+        }
+        args.push("--emulation".to_string());
+        args.push(self.emulation.to_possible_value().expect("No skipped variants").get_name().to_string());
+        if self.function_profile {
+            args.push("--function-profile".to_string());
+        }
+        if let Some(path) = &self.callgrind {
+            args.push("--callgrind".to_string());
+            args.push(path.clone());
+        }
+        args.push("--profile".to_string());
+        args.push(self.profile.to_possible_value().expect("No skipped variants").get_name().to_string());
+        if let Some(sp) = self.reset_sp {
+            args.push("--reset-sp".to_string());
+            args.push(format!("{:#06x}", sp));
+        }
+        for register in &self.reset_registers {
+            args.push("--reset-register".to_string());
+            args.push(register.to_string());
+        }
+        if let Some(fill) = self.ram_fill {
+            args.push("--ram-fill".to_string());
+            args.push(format!("{:#04x}", fill));
+        }
+        for region in &self.no_execute {
+            args.push("--no-execute".to_string());
+            args.push(region.to_string());
+        }
+        for region in &self.retained {
+            args.push("--retain".to_string());
+            args.push(region.to_string());
+        }
+        for region in &self.read_only {
+            args.push("--read-only".to_string());
+            args.push(region.to_string());
+        }
+        if self.read_only_image {
+            args.push("--read-only-image".to_string());
+        }
+        if self.hold_watchdog {
+            args.push("--hold-watchdog".to_string());
+        }
+        if let Some(name) = &self.instance {
+            args.push("--instance".to_string());
+            args.push(name.clone());
+        }
+        args.push("--log-format".to_string());
+        args.push(self.log_format.to_possible_value().expect("No skipped variants").get_name().to_string());
+        args.push("--log-level".to_string());
+        args.push(self.log_level.to_possible_value().expect("No skipped variants").get_name().to_string());
+        if let Some(path) = &self.record {
+            args.push("--record".to_string());
+            args.push(path.clone());
+        }
+        args.push("--poll-interval".to_string());
+        args.push(self.poll_interval.to_string());
+        return args;
+    }
+
+    /// The profile with the extra regions and startup overrides given
+    fn builder(&self) -> ComputerBuilder {
+        let mut profile: DeviceProfile = self.profile.device();
+        profile.no_execute.extend_from_slice(&self.no_execute);
+        profile.backup_memory.extend_from_slice(&self.retained);
+        profile.read_only.extend_from_slice(&self.read_only);
+        profile.watchdog_held |= self.hold_watchdog;
+        let mut builder: ComputerBuilder = ComputerBuilder::new().profile(profile);
+        if let Some(sp) = self.reset_sp {
+            builder = builder.reset_sp(sp);
+        }
+        for reset in &self.reset_registers {
+            builder = builder.reset_register(*reset);
+        }
+        if let Some(fill) = self.ram_fill {
+            builder = builder.ram_fill(fill);
+        }
+        builder = builder.emulation(self.emulation);
+        for check in &self.checks {
+            builder = builder.check(*check);
+        }
+        return builder;
+    }
+
+    /// Arguments a replay needs to start the same way, with the seed that was actually used
+    fn recording_args(&self, seed: u64) -> Vec<String> {
+        let mut args: Vec<String> = self.to_args();
+        if self.parent_pid.is_some() {
+            args.remove(1);
+        }
+        // the script's commands are recorded like the frontend's
+        for option in ["--record", "--exec"] {
+            if let Some(i) = args.iter().position(|arg| arg == option) {
+                args.drain(i..i + 2);
+            }
+        }
+        if self.seed.is_none() {
+            args.push("--seed".to_string());
+            args.push(seed.to_string());
+        }
+        return args;
+    }
+}
+
+#[derive(Parser)]
+struct ReplayArgs {
+    /// File written by `run --record`
+    recording: String,
+    /// Write a memory range to a file when the replay ends, START-END:FORMAT:PATH (repeatable)
+    #[arg(long = "dump")]
+    dumps: Vec<DumpSpec>,
+}
+
+#[derive(Parser)]
+struct TestArgs {
+    /// ELF file containing the tests
+    elf: String,
+    /// Every function whose name starts with this is run as a test
+    #[arg(long, default_value = "test_")]
+    prefix: String,
+    /// Maximum instructions per test before it is considered hung
+    #[arg(long, default_value_t = 1_000_000)]
+    timeout: u64,
+    /// Stack pointer each test starts with, may use symbols (e.g. `__stack`)
+    #[arg(long, default_value = "0x4400")]
+    stack_top: String,
+    /// Also write a JUnit XML report to this file
+    #[arg(long)]
+    junit: Option<String>,
+    /// Memory layout of the emulated part, a test fetching from a no-execute region fails
+    #[arg(long, value_enum, default_value_t = Profile::Generic)]
+    profile: Profile,
+    /// Fail a test that runs this many cycles without reaching new code or servicing the watchdog
+    #[arg(long)]
+    runaway_cycles: Option<u64>,
+    /// Fail a test that returns to an address other than the one its CALL or interrupt pushed
+    #[arg(long)]
+    shadow_stack: bool,
+    /// Strict fails a test that does anything that would behave differently on hardware (odd word
+    /// addresses, flash writes, unimplemented peripherals of the profile, writes to r3, ...)
+    #[arg(long, value_enum, default_value_t = EmulationMode::Permissive)]
+    emulation: EmulationMode,
+    /// Directory tests can open files in through the host file device
+    #[arg(long)]
+    file_dir: Option<String>,
+    /// Answer a bit-banged sensor protocol on a pin, as for `run` (repeatable), the transactions of
+    /// failing tests are printed
+    #[arg(long = "sensor")]
+    sensors: Vec<SensorSpec>,
+    /// Start each test with the watchdog held instead of running
+    #[arg(long)]
+    hold_watchdog: bool,
+}
+
+#[derive(Parser)]
+struct RadioBrokerArgs {
+    /// UDP address to listen on, e.g. 127.0.0.1:4400
+    address: String,
+}
+
+#[derive(Parser)]
+struct JournalCsvArgs {
+    /// Journal written by `run --journal`
+    journal: String,
+    /// Where to write the CSV (stdout if not given)
+    output: Option<String>,
+}
+
+#[derive(Parser)]
+struct BranchTraceTextArgs {
+    /// Trace written by `run --branch-trace`
+    trace: String,
+    /// Where to write the text (stdout if not given)
+    output: Option<String>,
+}
+
+#[derive(Parser)]
+struct PackArgs {
+    /// Where to write the packed image
+    output: String,
+    /// Images to combine in order, FILE (ELF or raw format) or FILE@ADDRESS for a plain data blob
+    #[arg(required = true)]
+    images: Vec<ImageSpec>,
+    /// Entry point of the packed image, may use symbols of the inputs (default: from the inputs)
+    #[arg(long)]
+    entry: Option<String>,
+    /// Format of the packed image
+    #[arg(long, value_enum, default_value_t = PackFormat::Segmented)]
+    format: PackFormat,
+    /// Name recorded in a v2 image
+    #[arg(long, requires = "build_id")]
+    name: Option<String>,
+    /// Build id recorded in a v2 image, as hex (e.g. a git commit hash)
+    #[arg(long, requires = "name")]
+    build_id: Option<String>,
+}
+
+#[derive(Parser)]
+struct ConvertArgs {
+    input: String,
+    output: String,
+    /// Format of the input (guessed from its content if not given)
+    #[arg(long, value_enum)]
+    from: Option<formats::Format>,
+    /// Format to write
+    #[arg(long, value_enum)]
+    to: formats::Format,
+}
+
+#[derive(Parser)]
+struct AssembleArgs {
+    /// Assembly source (may use macros, `.global`, `.extern` and `.section`)
+    source: String,
+    /// Where to write the object
+    #[arg(short)]
+    output: String,
+}
+
+#[derive(Parser)]
+struct LinkArgs {
+    /// Objects written by `assemble`
+    #[arg(required = true)]
+    objects: Vec<String>,
+    /// Linker script (default: every object one after another from 0x4400)
+    #[arg(short = 'T')]
+    script: Option<String>,
+    /// Where to write the image
+    #[arg(short)]
+    output: String,
+    /// Format of the image
+    #[arg(long, value_enum, default_value_t = formats::Format::Segmented)]
+    to: formats::Format,
+}
+
+#[derive(Parser)]
+struct DisassembleArgs {
+    /// Image in any format `convert` reads
+    image: String,
+    /// First address to list (default: every segment of the image)
+    #[arg(long, value_parser = utils::parse_u16)]
+    start: Option<u16>,
+    /// Last address to list
+    #[arg(long, value_parser = utils::parse_u16)]
+    end: Option<u16>,
+    /// Show instructions as encoded instead of with emulated mnemonics (`mov @sp+ pc` for `ret`)
+    #[arg(long)]
+    canonical: bool,
+}
+
+#[derive(Parser)]
+struct VectorsArgs {
+    /// Image in any format `convert` reads
+    image: String,
+}
+
+#[derive(Parser)]
+struct TraceHashArgs {
+    /// Images to load in order, FILE or FILE@ADDRESS like `run --load`
+    #[arg(required = true)]
+    images: Vec<ImageSpec>,
+    /// Instructions to run (fewer if the firmware halts on a fault)
+    #[arg(long)]
+    steps: u64,
+    /// Hash the registers after every N instructions, memory writes are always hashed
+    #[arg(long, default_value_t = 1)]
+    every: u64,
+    /// Seed for the RNG device
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Exit with status 1 unless the digest is this (hex)
+    #[arg(long)]
+    expect: Option<String>,
+}
+
+#[derive(Parser)]
+struct LockstepArgs {
+    /// Images to load in order, FILE or FILE@ADDRESS like `run --load`
+    #[arg(required = true)]
+    images: Vec<ImageSpec>,
+    /// Instructions to run (fewer if both halt on the same fault)
+    #[arg(long)]
+    steps: u64,
+    /// How machine a is set up, comma separated profile=PROFILE, seed=N and dco=PERCENT
+    #[arg(long, default_value = "profile=generic")]
+    a: MachineConfig,
+    /// How machine b is set up, like --a
+    #[arg(long, default_value = "profile=generic")]
+    b: MachineConfig,
+}
+
+#[derive(Parser)]
+struct FuzzArgs {
+    /// Instruction sequences to try
+    #[arg(long, default_value_t = 10_000)]
+    cases: u64,
+    /// Longest sequence generated
+    #[arg(long, default_value_t = 8)]
+    length: usize,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Where a failing sequence is saved, the test suite replays every file in it
+    #[arg(long, default_value = "src/tests/fuzz_regressions")]
+    save: String,
+}
+
+#[derive(Parser)]
+struct StormArgs {
+    /// Images to load in order, FILE or FILE@ADDRESS like `run --load`
+    #[arg(required = true)]
+    images: Vec<ImageSpec>,
+    /// Instructions to run (fewer if the firmware halts on a fault)
+    #[arg(long)]
+    steps: u64,
+    /// Average instructions between fired interrupts
+    #[arg(long, default_value_t = 50)]
+    rate: u64,
+    /// Vector to fire (repeatable), every populated maskable vector if none are given
+    #[arg(long = "vector", value_parser = utils::parse_u16)]
+    vectors: Vec<u16>,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+enum PackFormat {
+    /// The new format, the entry point is written as the reset vector
+    Segmented,
+    /// Versioned, with checksums, an entry point record and optional name/build id
+    V2,
+}
+
+#[allow(dead_code)]
+trait RegisterData {
+    fn get_word(&self) -> u16;
+    fn get_byte(&self) -> u8;
+    fn set_word(&mut self, value: u16);
+    fn set_byte(&mut self, value: u8);
+    fn get_id(&self) -> u8;
+}
+
+
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct BasicRegister {
+    id: u8,
+    _value: u16
+}
+impl BasicRegister {
+    fn new(id: u8) -> BasicRegister {
+        return BasicRegister {
+            id,
+            _value: 0
+        };
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone)]
+struct EvenRegister {
+    id: u8,
+    _value: u16
+}
+impl EvenRegister {
+    fn new(id: u8) -> EvenRegister {
+        return EvenRegister {
+            id,
+            _value: 0
+        };
+    }
+}
+
+#[derive(Clone)]
+struct StatusRegister {
+    _value: u16
+}
+
+
+impl RegisterData for BasicRegister {
+    fn get_word(&self) -> u16 {
+        return self._value;
+    }
+
+    fn get_byte(&self) -> u8 {
+        return (self._value & 0xff) as u8;
+    }
+
+    fn set_word(&mut self, value: u16) {
+        self._value = value;
+    }
+
+    fn set_byte(&mut self, value: u8) {
+        self._value = value as u16;
+    }
+    
+    fn get_id(&self) -> u8 {
+        return self.id;
+    }
+}
+
+impl RegisterData for EvenRegister {
+    fn get_word(&self) -> u16 {
+        return self._value;
+    }
+
+    fn get_byte(&self) -> u8 {
+        return (self._value & 0xff) as u8;
+    }
+
+    fn set_word(&mut self, value: u16) {
+        self._value = value & 0xfffe;
+    }
+
+    fn set_byte(&mut self, value: u8) {
+        self._value = (value & 0xfe) as u16;
+    }
+
+    fn get_id(&self) -> u8 {
+        return self.id;
+    }
+}
+
+impl RegisterData for StatusRegister {
+    fn get_word(&self) -> u16 {
+        return self._value;
+    }
+
+    fn get_byte(&self) -> u8 {
+        return (self._value & 0xff) as u8;
+    }
+
+    fn set_word(&mut self, value: u16) {
+        self._value = value;
+    }
+
+    fn set_byte(&mut self, value: u8) {
+        self._value = value as u16;
+    }
+    
+    fn get_id(&self) -> u8 {
+        return 2;
+    }
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug,Copy,Clone)]
+    pub struct StatusFlags: u16 {
+        const CARRY    = 0x001;
+        const ZERO     = 0x002;
+        const NEGATIVE = 0x004;
+        const GIE      = 0x008;
+        const CPUOFF   = 0x010;
+        const OSCOFF   = 0x020;
+        const SCG0     = 0x040;
+        const SCG1     = 0x080;
+        const OVERFLOW = 0x100;
+
+        // any bits may be set
+        const _ = !0;
+    }
+}
+
+/// The status register's bits as booleans, from `Computer::flags`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Flags {
+    pub carry: bool,
+    pub zero: bool,
+    pub negative: bool,
+    pub overflow: bool,
+    pub gie: bool,
+    pub cpuoff: bool,
+    pub oscoff: bool,
+    pub scg0: bool,
+    pub scg1: bool,
+}
+
+impl Flags {
+    pub fn from_sr(sr: u16) -> Flags {
+        let set = |flag: StatusFlags| sr & flag.bits() != 0;
+        return Flags {
+            carry: set(StatusFlags::CARRY),
+            zero: set(StatusFlags::ZERO),
+            negative: set(StatusFlags::NEGATIVE),
+            overflow: set(StatusFlags::OVERFLOW),
+            gie: set(StatusFlags::GIE),
+            cpuoff: set(StatusFlags::CPUOFF),
+            oscoff: set(StatusFlags::OSCOFF),
+            scg0: set(StatusFlags::SCG0),
+            scg1: set(StatusFlags::SCG1),
+        };
+    }
+
+    /// LPM0-LPM4 while CPUOFF is set: OSCOFF makes it LPM4, otherwise SCG1 and SCG0 count up from
+    /// LPM0 (SCG0 alone is LPM1, both are LPM3). `None` while the CPU runs.
+    pub fn low_power_mode(&self) -> Option<u8> {
+        if !self.cpuoff {
+            return None;
+        }
+        return Some(if self.oscoff {4} else {(self.scg1 as u8) << 1 | self.scg0 as u8});
+    }
+
+    /// The flags in SR bit order, bit 0 (C) to bit 8 (V)
+    pub fn bits(&self) -> [bool; 9] {
+        return [self.carry, self.zero, self.negative, self.gie, self.cpuoff, self.oscoff, self.scg0, self.scg1, self.overflow];
+    }
+}
+
+impl std::fmt::Display for Flags {
+    /// Set flags in capitals, e.g. `V n Z c GIE cpuoff oscoff scg0 scg1`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [(self.overflow, "V"), (self.negative, "N"), (self.zero, "Z"), (self.carry, "C"), (self.gie, "GIE"),
+                     (self.cpuoff, "CPUOFF"), (self.oscoff, "OSCOFF"), (self.scg0, "SCG0"), (self.scg1, "SCG1")];
+        let shown: Vec<String> = names.iter()
+            .map(|(set, name)| if *set {name.to_string()} else {name.to_lowercase()})
+            .collect();
+        return write!(f, "{}", shown.join(" "));
+    }
+}
+
+#[allow(dead_code)]
+impl StatusRegister {
+    fn new() -> StatusRegister {
+        return StatusRegister {_value: 0 };
+    }
+
+    fn get_status(&self, flag: StatusFlags) -> bool {
+        return self.get_word() & flag.bits() != 0;
+    }
+
+    fn set_status(&mut self, flag: StatusFlags, set: bool) {
+        if set {
+            self.set_word(self.get_word() | flag.bits());
+        } else {
+            self.set_word(self.get_word() & !flag.bits());
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ConstantGeneratorRegister {}
+impl ConstantGeneratorRegister {
+    fn new() -> ConstantGeneratorRegister {
+        return ConstantGeneratorRegister {};
+    }
+}
+
+impl RegisterData for ConstantGeneratorRegister {
+    fn get_word(&self) -> u16 {
+return 0;
+    }
+
+    fn get_byte(&self) -> u8 {
+        return 0;
+    }
+
+    fn set_word(&mut self, _value: u16) {}
+
+    fn set_byte(&mut self, _value: u8) {}
+    
+    fn get_id(&self) -> u8 {
+        return 3;
+    }
+}
+
+/// Size of the MSP430X (20-bit) address space
+const EXTENDED_ADDRESS_SPACE: u32 = 0x100000;
+const PAGE_BITS: u32 = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: u16 = (PAGE_SIZE - 1) as u16;
+const LOW_PAGES: usize = 0x10000 / PAGE_SIZE;
+
+type Page = Rc<[u8; PAGE_SIZE]>;
+
+/// Memory is split into pages shared between forked computers until one of them writes,
+/// see `Computer::fork`
+#[derive(Clone)]
+struct MemoryMap {
+    _pages: [Page; LOW_PAGES],
+    /// memory above 64K (MSP430X parts only), pages are allocated the first time they are written
+    _extended: HashMap<u32, Page>,
+}
+
+#[allow(dead_code)]
+impl MemoryMap {
+    fn new() -> MemoryMap {
+        let zero: Page = Rc::new([0; PAGE_SIZE]);
+        return MemoryMap {
+            _pages: std::array::from_fn(|_| zero.clone()),
+            _extended: HashMap::new(),
+        };
+    }
+
+    fn reset(&mut self) {
+        *self = MemoryMap::new();
+    }
+
+    fn get_word(&self, index: u16) -> u16 {
+        //assert_eq!(index % 2, 0);
+        return ((self.get_byte(index) as u16) << 8u16) + (self.get_byte(index.wrapping_add(1)) as u16);
+    }
+
+    fn set_word(&mut self, index: u16, value: u16) {
+        //assert_eq!(index % 2, 0);
+        self.set_byte(index, ((value >> 8) & 0xff) as u8);
+        self.set_byte(index.wrapping_add(1), (value & 0xff) as u8);
+    }
+
+    #[inline]
+    fn get_byte(&self, index: u16) -> u8 {
+        return self._pages[(index >> PAGE_BITS) as usize][(index & PAGE_MASK) as usize];
+    }
+
+    #[inline]
+    fn set_byte(&mut self, index: u16, value: u8) {
+        // copies the page first if another computer still shares it
+        Rc::make_mut(&mut self._pages[(index >> PAGE_BITS) as usize])[(index & PAGE_MASK) as usize] = value;
+    }
+
+    /// 20-bit access, the low 64K is the same memory as the 16-bit accessors use
+    fn get_byte_20(&self, address: u32) -> u8 {
+        let address: u32 = address % EXTENDED_ADDRESS_SPACE;
+        if address < 0x10000 {
+            return self.get_byte(address as u16);
+        }
+        return match self._extended.get(&(address >> PAGE_BITS)) {
+            Some(page) => page[address as usize % PAGE_SIZE],
+            None => 0, // never written
+        };
+    }
+
+    fn set_byte_20(&mut self, address: u32, value: u8) {
+        let address: u32 = address % EXTENDED_ADDRESS_SPACE;
+        if address < 0x10000 {
+            self.set_byte(address as u16, value);
+            return;
+        }
+        let page = self._extended.entry(address >> PAGE_BITS)
+            .or_insert_with(|| Rc::new([0; PAGE_SIZE]));
+        Rc::make_mut(page)[address as usize % PAGE_SIZE] = value;
+    }
+
+    fn get_word_20(&self, address: u32) -> u16 {
+        return ((self.get_byte_20(address) as u16) << 8) | self.get_byte_20(address + 1) as u16;
+    }
+
+    fn set_word_20(&mut self, address: u32, value: u16) {
+        self.set_byte_20(address, (value >> 8) as u8);
+        self.set_byte_20(address + 1, (value & 0xff) as u8);
+    }
+
+    /// Bytes allocated for memory above 64K
+    fn extended_allocated(&self) -> usize {
+        return self._extended.len() * PAGE_SIZE;
+    }
+
+    /// Every byte of the low 64K that differs from `earlier` as (address, old, new),
+    /// pages still shared with it are skipped without being compared
+    fn changes_since(&self, earlier: &MemoryMap) -> Vec<(u16, u8, u8)> {
+        let mut changes: Vec<(u16, u8, u8)> = Vec::new();
+        for (index, (page, old_page)) in self._pages.iter().zip(earlier._pages.iter()).enumerate() {
+            if Rc::ptr_eq(page, old_page) {
+                continue;
+            }
+            for offset in 0..PAGE_SIZE {
+                if page[offset] != old_page[offset] {
+                    changes.push(((index * PAGE_SIZE + offset) as u16, old_page[offset], page[offset]));
+                }
+            }
+        }
+        return changes;
+    }
+
+    /// Number of pages (of the low 64K) this map shares with `other`
+    fn shared_pages(&self, other: &MemoryMap) -> usize {
+        return self._pages.iter().zip(other._pages.iter()).filter(|(a, b)| Rc::ptr_eq(a, b)).count();
+    }
+}
+
+trait WriteTarget {
+    fn set_word(&mut self, value: u16, computer: &mut Computer);
+    fn set_byte(&mut self, value: u8, computer: &mut Computer);
+}
+
+#[allow(dead_code)]
+struct VoidWriteTarget {}
+impl WriteTarget for VoidWriteTarget {
+    fn set_word(&mut self, _value: u16, _computer: &mut Computer) {}
+    fn set_byte(&mut self, _value: u8, _computer: &mut Computer) {}
+}
+
+#[derive(Copy, Clone)]
+struct RegisterWriteTarget {
+    register: u8
+}
+#[allow(dead_code)]
+impl RegisterWriteTarget {
+    fn new(reg: u8) -> WriteTargets {
+        return WriteTargets::REGISTER(RegisterWriteTarget {
+            register: reg
+        });
+    }
+}
+impl WriteTarget for RegisterWriteTarget {
+    fn set_word(&mut self, value: u16, computer: &mut Computer) {
+        computer.get_register(self.register).set_word(value);
+    }
+
+    fn set_byte(&mut self, value: u8, computer: &mut Computer) {
+        computer.get_register(self.register).set_byte(value);
+    }
+}
+
+#[derive(Copy, Clone)]
+struct MemoryWriteTarget {
+    address: u16
+}
+#[allow(dead_code)]
+impl MemoryWriteTarget {
+    fn new(address: u16) -> WriteTargets {
+        return WriteTargets::MEMORY(MemoryWriteTarget {
+            address
+        });
+    }
+}
+impl WriteTarget for MemoryWriteTarget {
+    fn set_word(&mut self, value: u16, computer: &mut Computer) {
+        computer.write_word(self.address, value);
+    }
+
+    fn set_byte(&mut self, value: u8, computer: &mut Computer) {
+        computer.write_byte(self.address, value);
+    }
+}
+
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+enum WriteTargets {
+    VOID,
+    REGISTER(RegisterWriteTarget), 
+    MEMORY(MemoryWriteTarget)
+}
+
+impl WriteTarget for WriteTargets {
+    fn set_word(&mut self, value: u16, computer: &mut Computer) {
+        match self {
+            WriteTargets::VOID => {},
+            WriteTargets::REGISTER(t) => t.set_word(value, computer),
+            WriteTargets::MEMORY(t) => t.set_word(value, computer),
+        }
+    }
+
+    fn set_byte(&mut self, value: u8, computer: &mut Computer) {
+        match self {
+            WriteTargets::VOID => {},
+            WriteTargets::REGISTER(t) => t.set_byte(value, computer),
+            WriteTargets::MEMORY(t) => t.set_byte(value, computer),
+        }
+    }
+}
+
+/// A resolved operand, what both instruction formats read and write back through
+#[derive(Copy, Clone)]
+struct Operand {
+    value: u16,
+    target: WriteTargets,
+    /// Offset words following the instruction that this operand used (indexed, absolute and symbolic modes)
+    words: u16,
+}
+
+#[allow(dead_code, non_upper_case_globals)]
+#[derive(Debug, TryFromPrimitive)]
+#[repr(u8)]
+enum SingleOperandOpcodes {
+    RRC,
+    SWPB,
+    RRA,
+    SXT,
+    PUSH,
+    CALL,
+    RETI
+}
+
+#[allow(dead_code, non_upper_case_globals)]
+#[derive(Debug, TryFromPrimitive, Eq, PartialEq)]
+#[repr(u8)]
+enum DoubleOperandOpcodes {
+    MOV,
+    ADD,
+    ADDC,
+    SUBC,
+    SUB,
+    CMP,
+    DADD,
+    BIT,
+    BIC,
+    BIS,
+    XOR,
+    AND
+}
+
+/// Something the firmware did that real hardware would not survive, the computer stops stepping
+/// until it is reset
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    /// instruction fetch from a region marked no-execute
+    NoExecute { pc: u16, region: Region },
+    /// no new instruction reached and the watchdog not serviced for `cycles`, stuck in `first`..=`last`
+    Runaway { first: u16, last: u16, cycles: u64 },
+    /// the RET or RETI at `pc` was about to pop `found` from `slot` where its CALL or interrupt put `expected`
+    ReturnAddress { pc: u16, slot: u16, expected: u16, found: u16 },
+    /// a diagnostic with severity trap (strict emulation)
+    Diagnostic(Diagnostic),
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Fault::NoExecute { pc, region } =>
+                write!(f, "instruction fetch from no-execute region {} at pc {:#06x}", region, pc),
+            Fault::Runaway { first, last, cycles } if first == last =>
+                write!(f, "tight infinite loop at {:#06x} (no new code reached and watchdog not serviced for {} cycles)",
+                       first, cycles),
+            Fault::Runaway { first, last, cycles } =>
+                write!(f, "infinite loop in {:#06x}-{:#06x} (no new code reached and watchdog not serviced for {} cycles)",
+                       first, last, cycles),
+            Fault::ReturnAddress { pc, slot, expected, found } =>
+                write!(f, "return address corrupted at pc {:#06x} ({:#06x} at {:#06x} on the stack, the call pushed {:#06x})",
+                       pc, found, slot, expected),
+            Fault::Diagnostic(diagnostic) => write!(f, "trapped at {}", diagnostic),
+        };
+    }
+}
+
+/// Why the CPU last restarted from the reset vector without a load
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ResetCause {
+    /// a device asked for a power-up clear (watchdog, security violation)
+    Puc,
+    /// the supply came back after the supervisor held the CPU in reset
+    Brownout,
+    /// power cycle command
+    PowerCycle,
+    /// reset command
+    Command(ResetKind),
+    /// a diagnostic with severity reset was raised (`run --check`)
+    Diagnostic,
+}
+
+impl ResetCause {
+    pub(crate) fn id(&self) -> u16 {
+        return match self {
+            ResetCause::Puc => 1,
+            ResetCause::Brownout => 2,
+            ResetCause::PowerCycle => 3,
+            ResetCause::Command(ResetKind::Bor) => 4,
+            ResetCause::Command(ResetKind::Puc) => 5,
+            ResetCause::Diagnostic => 6,
+        };
+    }
+}
+
+/// Reset a frontend asks for (control command 34)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ResetKind {
+    /// brownout reset, what the RST pin or power-up does: devices and RAM start over, flash and
+    /// FRAM are kept
+    Bor,
+    /// power-up clear: only the CPU restarts, memory and devices are kept
+    Puc,
+}
+
+impl ResetKind {
+    pub(crate) fn from_id(id: u8) -> Option<ResetKind> {
+        return match id {
+            0 => Some(ResetKind::Bor),
+            1 => Some(ResetKind::Puc),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            ResetKind::Bor => 0,
+            ResetKind::Puc => 1,
+        };
+    }
+}
+
+pub struct Computer {
+    numbered_registers: [BasicRegister; 12],
+    memory: MemoryMap,
+    devices: Devices,
+    clock: Clock,
+    pc: EvenRegister,
+    sp: EvenRegister,
+    sr: StatusRegister,
+    cg: ConstantGeneratorRegister,
+    /// address of the instruction currently being executed
+    instruction_pc: u16,
+    /// records every data write when enabled (`run --journal`)
+    journal: Option<WriteJournal>,
+    /// records every control-flow change when enabled (`run --branch-trace`)
+    branch_trace: Option<BranchTrace>,
+    /// records every byte sent on the UART when enabled (`run --uart-capture`)
+    uart_capture: Option<UartCapture>,
+    /// writes every presented frame when enabled (`run --framebuffer-images`)
+    frame_export: Option<FrameExport>,
+    pc_history: PcHistory,
+    /// instructions may not be fetched from these (`run --profile`, `--no-execute`)
+    no_execute: Vec<Region>,
+    /// cleared by a power cycle (`run --profile`)
+    ram: Vec<Region>,
+    /// kept by a power cycle even inside `ram` (backup memory, `--retain`)
+    retained: Vec<Region>,
+    /// peripheral space and flash, for diagnostics (`run --profile`, `--check`)
+    peripherals: Vec<Region>,
+    flash: Vec<Region>,
+    /// data writes are dropped (`run --profile`, `--read-only`)
+    read_only: Vec<Region>,
+    /// who answers each address, made from the regions above and the devices
+    bus: BusMap,
+    /// registers and RAM contents after a reset (`run --profile`, `--reset-sp`, ...)
+    startup: StartupState,
+    fault: Option<Fault>,
+    /// inside a non-maskable interrupt handler, further NMIs are held off until RETI
+    servicing_nmi: bool,
+    eem: Eem,
+    /// the supply supervisor is holding the CPU in reset
+    in_brownout: bool,
+    /// input levels replayed into the GPIO pins (`run --stimulus`)
+    stimulus: Option<Stimulus>,
+    /// voltages replayed into the comparator inputs (`run --analog`)
+    analog: Vec<Waveform>,
+    /// measures output pins (`run --pwm`)
+    pwm: Option<PwmAnalyzer>,
+    /// answer bit-banged protocols on pins (`run --sensor`)
+    sensors: Option<Sensors>,
+    /// decode WS2812 LEDs from pins (`run --ws2812`)
+    led_strips: Vec<LedStrip>,
+    /// encoders and keypads on pins (`run --encoder`, `run --button-matrix`)
+    widgets: Option<Widgets>,
+    /// collects writes for the MIDI/OSC bridge (`run --midi-source ADDRESS`, `--osc-map`)
+    write_tap: Option<WriteTap>,
+    /// halts firmware that stopped making progress (`--runaway-cycles`)
+    runaway: Option<RunawayDetector>,
+    /// halts firmware that smashed a return address (`--shadow-stack`)
+    shadow_stack: Option<ShadowStack>,
+    /// behaviour that would differ on hardware (`--check`)
+    diagnostics: Diagnostics,
+    /// cycles by function (`--function-profile`, `--callgrind`)
+    profiler: Option<Profiler>,
+    /// allocations of C firmware, when the image has malloc/free symbols
+    heap: Option<HeapTracker>,
+    /// hashes execution for determinism checks (`trace-hash`)
+    trace_hash: Option<TraceHasher>,
+    /// whether anything runs after every instruction (pin models, branch trace, profiler, trace
+    /// hash), so a plain step skips them all with one check. Set by `attach_hooks`.
+    hooks: bool,
+    /// fires random interrupts and checks handlers return cleanly (`storm`)
+    storm: Option<InterruptStorm>,
+    /// instructions executed so far
+    retired: u64,
+    /// restarts from the reset vector so far and why the last one happened
+    resets: u32,
+    last_reset: Option<ResetCause>,
+    /// maskable interrupts stay pending, set while a debugger steps with them masked
+    interrupts_masked: bool,
+    /// snapshot sections a newer emulator wrote, kept for the next save
+    foreign_sections: Vec<snapshot::Section>,
+}
+
+#[allow(dead_code)]
+impl Computer {
+    pub fn new() -> Computer {
+        let pc: EvenRegister = EvenRegister::new(0);
+        let sp: EvenRegister = EvenRegister::new(1);
+        let sr: StatusRegister = StatusRegister::new();
+        let cg: ConstantGeneratorRegister = ConstantGeneratorRegister::new();
+        let numbered_registers: &mut [BasicRegister; 12] = &mut [BasicRegister::new(255); 12];
+        for i in 4..16u8 {
+            numbered_registers[i as usize - 4] = BasicRegister::new(i);
+        }
+        let devices: Devices = Devices::new();
+        let bus: BusMap = BusMap::new(&devices);
+        return Computer {
+            numbered_registers: *numbered_registers,
+            memory: MemoryMap::new(),
+            devices,
+            clock: Clock::new(TimeSource::Emulated),
+            pc, sp, sr, cg,
+            instruction_pc: 0,
+            journal: None,
+            branch_trace: None,
+            uart_capture: None,
+            frame_export: None,
+            pc_history: PcHistory::new(pc_history::DEFAULT_CAPACITY),
+            no_execute: Vec::new(),
+            ram: Vec::new(),
+            retained: Vec::new(),
+            peripherals: Vec::new(),
+            flash: Vec::new(),
+            read_only: Vec::new(),
+            bus,
+            startup: StartupState::default(),
+            fault: None,
+            servicing_nmi: false,
+            eem: Eem::new(),
+            in_brownout: false,
+            stimulus: None,
+            analog: Vec::new(),
+            pwm: None,
+            sensors: None,
+            led_strips: Vec::new(),
+            widgets: None,
+            write_tap: None,
+            runaway: None,
+            shadow_stack: None,
+            diagnostics: Diagnostics::default(),
+            profiler: None,
+            hooks: false,
+            heap: None,
+            trace_hash: None,
+            storm: None,
+            retired: 0,
+            resets: 0,
+            last_reset: None,
+            interrupts_masked: false,
+            foreign_sections: Vec::new(),
+        };
+    }
+
+    /// Cheap copy of the whole machine state for exploring alternatives (fuzzing, "what if this
+    /// interrupt fired here"). Memory pages are shared until either copy writes to them.
+    /// The write journal, branch trace, UART capture and frame images stay with the original.
+    pub fn fork(&self) -> Computer {
+        return Computer {
+            numbered_registers: self.numbered_registers,
+            memory: self.memory.clone(),
+            devices: self.devices.clone(),
+            clock: self.clock.clone(),
+            pc: self.pc.clone(),
+            sp: self.sp.clone(),
+            sr: self.sr.clone(),
+            cg: self.cg.clone(),
+            instruction_pc: self.instruction_pc,
+            journal: None,
+            branch_trace: None,
+            uart_capture: None,
+            frame_export: None,
+            pc_history: self.pc_history.clone(),
+            no_execute: self.no_execute.clone(),
+            ram: self.ram.clone(),
+            retained: self.retained.clone(),
+            peripherals: self.peripherals.clone(),
+            flash: self.flash.clone(),
+            read_only: self.read_only.clone(),
+            bus: self.bus.clone(),
+            startup: self.startup,
+            fault: self.fault,
+            servicing_nmi: self.servicing_nmi,
+            eem: self.eem.clone(),
+            in_brownout: self.in_brownout,
+            stimulus: self.stimulus.clone(),
+            analog: self.analog.clone(),
+            pwm: self.pwm.clone(),
+            sensors: self.sensors.clone(),
+            led_strips: self.led_strips.clone(),
+            widgets: self.widgets.clone(),
+            write_tap: self.write_tap.clone(),
+            runaway: self.runaway.clone(),
+            shadow_stack: self.shadow_stack.clone(),
+            diagnostics: self.diagnostics.clone(),
+            profiler: self.profiler.clone(),
+            hooks: self.hooks,
+            heap: self.heap.clone(),
+            trace_hash: self.trace_hash.clone(),
+            storm: self.storm.clone(),
+            retired: self.retired,
+            resets: self.resets,
+            last_reset: self.last_reset,
+            interrupts_masked: self.interrupts_masked,
+            foreign_sections: self.foreign_sections.clone(),
+        };
+    }
+
+    /// Back to a computer fresh from `new` with the configuration kept: memory is cleared, program
+    /// included, and the PC is 0 until something is loaded. `power_on_reset` and `puc` reset a
+    /// loaded machine the way hardware does.
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        self.devices.reset();
+        self.clock.reset();
+        self.instruction_pc = 0;
+        self.pc_history.clear();
+        self.fault = None;
+        self.servicing_nmi = false;
+        self.eem.reset();
+        self.in_brownout = false;
+        if let Some(stimulus) = &mut self.stimulus {
+            stimulus.rewind();
+        }
+        for waveform in &mut self.analog {
+            waveform.rewind();
+        }
+        if let Some(pwm) = &mut self.pwm {
+            pwm.reset();
+        }
+        if let Some(sensors) = &mut self.sensors {
+            sensors.reset();
+        }
+        for strip in &mut self.led_strips {
+            strip.reset();
+        }
+        if let Some(widgets) = &mut self.widgets {
+            widgets.reset();
+        }
+        if let Some(runaway) = &mut self.runaway {
+            runaway.reset();
+        }
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.reset();
+        }
+        self.diagnostics.reset();
+        if let Some(heap) = &mut self.heap {
+            heap.reset();
+        }
+        if let Some(trace_hash) = &mut self.trace_hash {
+            trace_hash.reset();
+        }
+        if let Some(storm) = &mut self.storm {
+            storm.abandon();
+        }
+        self.pc.set_word(0);
+        self.sp.set_word(self.startup.sp);
+        self.sr.set_word(0);
+        self.cg.set_word(0);
+
+        for i in 0..12 {
+            self.numbered_registers[i].set_word(self.startup.registers[i]);
+        }
+        self.fill_ram();
+    }
+
+    /// Machine state in the snapshot format (binary_formats.txt), loadable by this and later versions
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        return snapshot::save(self);
+    }
+
+    /// Continue from a snapshot, parts it doesn't cover start over as after a reset
+    pub fn restore_snapshot(&mut self, file: &[u8]) -> Result<snapshot::RestoreReport, String> {
+        return snapshot::restore(self, file);
+    }
+
+    /// Rebuild the bus map after the RAM, flash, peripheral, retained or read-only regions changed
+    pub(crate) fn map_bus(&mut self) {
+        self.bus = BusMap::with_regions(&self.devices, &self.ram, &self.flash, &self.peripherals, &self.retained, &self.read_only);
+    }
+
+    /// Make `segments` (the loaded image's) read-only as well, instead of the last image's
+    /// (`run --read-only-image`)
+    pub(crate) fn protect_image(&mut self, segments: &[Region]) {
+        self.map_bus();
+        for region in segments {
+            self.bus.map(*region, Handler::ReadOnly);
+        }
+    }
+
+    /// RAM outside the retained regions gets the startup fill pattern, like at power-up
+    fn fill_ram(&mut self) {
+        for region in &self.ram {
+            for address in region.start..=region.end {
+                if !self.retained.iter().any(|r| r.contains(address)) {
+                    self.memory.set_byte(address, self.startup.ram_fill);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn get_register(&mut self, id: u8) -> &mut dyn RegisterData {
+        if id == 0 {
+            return &mut self.pc;
+        } else if id == 1 {
+            return &mut self.sp;
+        } else if id == 2 {
+            return &mut self.sr;
+        } else if id == 3 {
+            return &mut self.cg;
+        } else {
+            return &mut self.numbered_registers[(id - 4) as usize];
+        }
+    }
+
+    fn get_register_imut(&self, id: u8) -> &dyn RegisterData {
+        if id == 0 {
+            return &self.pc;
+        } else if id == 1 {
+            return &self.sp;
+        } else if id == 2 {
+            return &self.sr;
+        } else if id == 3 {
+            return &self.cg;
+        } else {
+            return &self.numbered_registers[(id - 4) as usize];
+        }
+    }
+
+    /// r0 - r15
+    fn register_words(&self) -> [u16; 16] {
+        return std::array::from_fn(|i| self.get_register_imut(i as u8).get_word());
+    }
+
+    /// Digest of the execution so far, `None` unless `trace-hash` enabled hashing
+    fn trace_digest(&self) -> Option<u64> {
+        return self.trace_hash.as_ref().map(|h| h.digest(&self.register_words(), self.clock.cycles(), &self.memory));
+    }
+
+    /// Data reads/writes go through here so that devices can claim their addresses,
+    /// everything else is plain memory. The bus map routes each access to its device, if any.
+    #[inline]
+    fn read_word(&mut self, address: u16) -> u16 {
+        let claimed: Option<u16> = self.devices.read(&self.bus, address, true, self.instruction_pc, &self.clock);
+        self._diagnose_access(address, true, false, claimed.is_some());
+        let value: u16 = if let Some(value) = claimed {
+            value
+        } else if !self.devices.mpu.allows(address, Access::Read, self.instruction_pc) {
+            mpu::VIOLATION_READ
+        } else {
+            self.memory.get_word(address)
+        };
+        self.eem.read(address, value);
+        return value;
+    }
+
+    #[inline]
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let claimed: Option<u8> = self.devices.read(&self.bus, address, false, self.instruction_pc, &self.clock).map(|v| v as u8);
+        self._diagnose_access(address, false, false, claimed.is_some());
+        let value: u8 = if let Some(value) = claimed {
+            value
+        } else if !self.devices.mpu.allows(address, Access::Read, self.instruction_pc) {
+            (mpu::VIOLATION_READ & 0xff) as u8
+        } else {
+            self.memory.get_byte(address)
+        };
+        self.eem.read(address, value as u16);
+        return value;
+    }
+
+    #[inline]
+    fn write_word(&mut self, address: u16, value: u16) {
+        if !self.devices.mpu.allows(address, Access::Write, self.instruction_pc) {
+            return; // blocked, memory is left as it was
+        }
+        if self.bus.read_only(address, true) {
+            self.diagnostics.raise(Category::ReadOnlyWrite, self.instruction_pc, address);
+            return;
+        }
+        let device: bool = self.devices.write(&self.bus, address, value, true, self.instruction_pc, &self.clock);
+        self._diagnose_access(address, true, true, device);
+        if device && self.uart_capture.is_some() && address & 0xfffe == devices::uart::UART_TX {
+            self._capture_uart(value as u8);
+        }
+        if device && self.frame_export.is_some() && address & 0xfffe == devices::framebuffer::FB_FRAME {
+            self._export_frame();
+        }
+        let old: u16 = if device {0} else {self.memory.get_word(address)};
+        if !device {
+            self.memory.set_word(address, value);
+        }
+        self.eem.write(address, value);
+        if let Some(runaway) = &mut self.runaway {
+            runaway.write(address, value);
+        }
+        if let Some(trace_hash) = &mut self.trace_hash {
+            trace_hash.write(address, value, false);
+        }
+        if let Some(tap) = &mut self.write_tap {
+            tap.write(address, value);
+        }
+        self._journal(address, old, value, false, device);
+    }
+
+    #[inline]
+    fn write_byte(&mut self, address: u16, value: u8) {
+        if !self.devices.mpu.allows(address, Access::Write, self.instruction_pc) {
+            return;
+        }
+        if self.bus.read_only(address, false) {
+            self.diagnostics.raise(Category::ReadOnlyWrite, self.instruction_pc, address);
+            return;
+        }
+        let device: bool = self.devices.write(&self.bus, address, value as u16, false, self.instruction_pc, &self.clock);
+        self._diagnose_access(address, false, true, device);
+        if device && self.uart_capture.is_some() && address & 0xfffe == devices::uart::UART_TX {
+            self._capture_uart(value);
+        }
+        if device && self.frame_export.is_some() && address & 0xfffe == devices::framebuffer::FB_FRAME {
+            self._export_frame();
+        }
+        let old: u8 = if device {0} else {self.memory.get_byte(address)};
+        if !device {
+            self.memory.set_byte(address, value);
+        }
+        self.eem.write(address, value as u16);
+        if let Some(trace_hash) = &mut self.trace_hash {
+            trace_hash.write(address, value as u16, true);
+        }
+        if let Some(tap) = &mut self.write_tap {
+            tap.write(address, value as u16);
+        }
+        self._journal(address, old as u16, value as u16, true, device);
+    }
+
+    /// Raises the diagnostics about data accesses (`--check`), `device` if an emulated device took it
+    #[inline]
+    fn _diagnose_access(&mut self, address: u16, word: bool, write: bool, device: bool) {
+        if word && address & 1 != 0 {
+            self.diagnostics.raise(Category::OddAddress, self.instruction_pc, address);
+        }
+        if device {
+            return;
+        }
+        let handler: Handler = self.bus.handler(address);
+        if write {
+            if handler == Handler::Flash && self.diagnostics.enabled(Category::FlashWrite) {
+                self.diagnostics.raise(Category::FlashWrite, self.instruction_pc, address);
+            }
+        } else if handler == Handler::Peripheral && self.diagnostics.enabled(Category::Unimplemented) {
+            self.diagnostics.raise(Category::Unimplemented, self.instruction_pc, address);
+        }
+    }
+
+    fn _capture_uart(&mut self, byte: u8) {
+        if let Some(capture) = &mut self.uart_capture {
+            if let Err(e) = capture.record(self.clock.cycles(), byte) {
+                eprintln!("UART capture disabled: {}", e);
+                self.uart_capture = None;
+            }
+        }
+    }
+
+    /// The frame buffer's pixels as they are now, `None` without `run --framebuffer`
+    pub(crate) fn frame(&self) -> Option<Vec<ws2812::Rgb>> {
+        let spec: &FrameBufferSpec = self.devices.framebuffer.spec()?;
+        let region: Region = spec.region();
+        let memory: Vec<u8> = (region.start..=region.end).map(|address| self.memory.get_byte(address)).collect();
+        return Some(spec.render(&memory));
+    }
+
+    /// Firmware presented a frame, it is written as it is in memory at the write
+    fn _export_frame(&mut self) {
+        let (Some(spec), Some(pixels)) = (self.devices.framebuffer.spec().copied(), self.frame()) else {
+            return;
+        };
+        let frame: u64 = self.devices.framebuffer.frames();
+        if let Some(export) = &mut self.frame_export {
+            if let Err(e) = export.write(&spec, frame, &pixels) {
+                eprintln!("Frame images disabled: {}", e);
+                self.frame_export = None;
+            }
+        }
+    }
+
+    fn _journal(&mut self, address: u16, old: u16, new: u16, byte: bool, device: bool) {
+        if let Some(journal) = &mut self.journal {
+            let entry = JournalEntry {
+                cycle: self.clock.cycles(),
+                pc: self.instruction_pc,
+                address, old, new, byte, device,
+            };
+            if let Err(e) = journal.record(&entry) {
+                eprintln!("Write journal disabled: {}", e);
+                self.journal = None;
+            }
+        }
+    }
+
+    fn _branch(&mut self, kind: BranchKind, source: u16, target: u16, taken: bool) {
+        if let Some(branch_trace) = &mut self.branch_trace {
+            if let Err(e) = branch_trace.record(&BranchRecord { kind, source, target, taken }) {
+                eprintln!("Branch trace disabled: {}", e);
+                self.branch_trace = None;
+            }
+        }
+    }
+
+    /// After executing `instruction` from `pc`, record where it sent the PC if it can change it
+    fn _trace_branch(&mut self, pc: u16, instruction: u16, decoded: Decoded) {
+        let target: u16 = self.pc.get_word();
+        let kind: BranchKind = match decoded {
+            Decoded::Jump { offset, .. } => {
+                let destination: u16 = pc.wrapping_add(2).wrapping_add(offset as u16);
+                self._branch(BranchKind::Jump, pc, destination, target == destination);
+                return;
+            },
+            Decoded::Single { opcode: stepping::CALL_OPCODE, bw: false, .. } => BranchKind::Call,
+            _ if instruction == stepping::RET || instruction == stepping::RETI => BranchKind::Return,
+            Decoded::Double { opcode, ad: 0, dst_reg: 0, .. } if !matches!(DoubleOperandOpcodes::try_from(opcode),
+                                                                           Ok(DoubleOperandOpcodes::CMP | DoubleOperandOpcodes::BIT)) =>
+                BranchKind::PcWrite,
+            _ => return,
+        };
+        self._branch(kind, pc, target, true);
+    }
+
+    /// Whether a status register flag is set (all of them, for several)
+    pub fn flag(&self, flag: StatusFlags) -> bool {
+        return self.sr.get_word() & flag.bits() == flag.bits();
+    }
+
+    pub fn set_flag(&mut self, flag: StatusFlags, set: bool) {
+        self.sr.set_status(flag, set);
+    }
+
+    /// Snapshot of the status register's flags
+    pub fn flags(&self) -> Flags {
+        return Flags::from_sr(self.sr.get_word());
+    }
+
+    pub fn interrupt(&mut self, id: u16) {
+        if self.sr.get_status(StatusFlags::GIE) { // only actually interrupt if interrupts are enabled
+            self._enter_interrupt(id);
+        }
+    }
+
+    fn _enter_interrupt(&mut self, id: u16) {
+        let registers: [u16; 12] = std::array::from_fn(|i| self.numbered_registers[i].get_word());
+        if let Some(storm) = &mut self.storm {
+            storm.entered(id, self.sp.get_word(), self.pc.get_word(), self.sr.get_word(), registers);
+        }
+        // push PC and SR onto the stack for restoring after the interrupt handler
+        self._push(self.pc.get_word(), false);
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.pushed(self.sp.get_word(), self.pc.get_word());
+        }
+        let handler: u16 = self.memory.get_word(id);
+        self._branch(BranchKind::Interrupt, self.pc.get_word(), handler, true);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.called(self.pc.get_word(), handler, self.sp.get_word(), self.clock.cycles());
+        }
+        self._push(self.sr.get_word(), false);
+        self.diagnostics.entered(self.sr.get_word());
+        // clear status register (setting GIE to 0)
+        self.sr.set_word(0);
+        // load interrupt vector into pc
+        self.pc.set_word(handler);
+        self.clock.advance(timing::INTERRUPT_CYCLES);
+    }
+
+    /// The supply came back after the SVS held the CPU in reset: like a power-up, except memory is kept
+    fn brownout(&mut self) {
+        self.devices.brownout();
+        self._puc(ResetCause::Brownout);
+    }
+
+    /// The supply goes away for `off_cycles` and comes back: RAM outside the retained regions is
+    /// cleared and the CPU starts over like at power-up, the RTC keeps counting on the backup supply
+    pub(crate) fn power_cycle(&mut self, off_cycles: u64) {
+        self.power_up(off_cycles, ResetCause::PowerCycle);
+    }
+
+    fn power_up(&mut self, off_cycles: u64, cause: ResetCause) {
+        self.fill_ram();
+        self.devices.power_cycle();
+        self.clock.advance(off_cycles);
+        self.fault = None;
+        self.in_brownout = false;
+        self._puc(cause);
+    }
+
+    /// Reset without reloading anything, a BOR is a power cycle without off time
+    pub(crate) fn reset_as(&mut self, kind: ResetKind) {
+        match kind {
+            ResetKind::Bor => self.power_up(0, ResetCause::Command(kind)),
+            ResetKind::Puc => {
+                self.fault = None;
+                self._puc(ResetCause::Command(kind));
+            },
+        }
+    }
+
+    /// Power-on reset of a machine with its program loaded: RAM gets the startup fill and devices
+    /// start over, while code, flash and FRAM are kept and the CPU starts from the vector at 0xfffe
+    pub fn power_on_reset(&mut self) {
+        self.reset_as(ResetKind::Bor);
+    }
+
+    /// Power-up clear: only the CPU restarts from the vector at 0xfffe, with the startup SP and
+    /// registers and SR cleared. RAM and devices are kept, apart from the watchdog.
+    pub fn puc(&mut self) {
+        self.reset_as(ResetKind::Puc);
+    }
+
+    /// Power-up clear, what a security violation or the watchdog does on real hardware: the CPU
+    /// restarts from the reset vector while memory (FRAM) and the MPU configuration are kept, the
+    /// watchdog starts over
+    fn _puc(&mut self, cause: ResetCause) {
+        self.resets = self.resets.wrapping_add(1);
+        self.last_reset = Some(cause);
+        for i in 0..12 {
+            self.numbered_registers[i].set_word(self.startup.registers[i]);
+        }
+        self.sp.set_word(self.startup.sp);
+        self.sr.set_word(0);
+        self.servicing_nmi = false;
+        self.devices.wdt.puc(&self.clock);
+        if let Some(storm) = &mut self.storm {
+            storm.abandon();
+        }
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.reset();
+        }
+        self.diagnostics.reset();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.abandon(self.clock.cycles());
+        }
+        if let Some(heap) = &mut self.heap {
+            heap.reset();
+        }
+        self._branch(BranchKind::Reset, self.pc.get_word(), self.memory.get_word(0xfffe), true);
+        self.pc.set_word(self.memory.get_word(0xfffe));
+    }
+
+    /// Why the computer stopped stepping, until the next reset. Illegal instruction words fault
+    /// (`Fault::Diagnostic`) under strict emulation or an illegal-instruction check with severity
+    /// trap; permissive emulation, the default, executes them as nothing and carries on.
+    pub fn fault(&self) -> Option<Fault> {
+        return self.fault;
+    }
+
+    pub fn step(&mut self) {
+        if let Some(stimulus) = &mut self.stimulus {
+            stimulus.apply(self.clock.cycles(), &mut self.devices.gpio);
+        }
+        for waveform in &mut self.analog {
+            waveform.apply(self.clock.cycles(), &mut self.devices.comparator);
+        }
+        self.devices.pmm.update(self.clock.cycles());
+        self.devices.comparator.set_supply(self.devices.pmm.supply_mv());
+        if self.devices.pmm.in_reset() {
+            self.in_brownout = true;
+            self.clock.advance(1); // time keeps passing so the supply can recover
+            return;
+        }
+        if self.in_brownout {
+            self.in_brownout = false;
+            self.brownout();
+        }
+        if self.clock.cycles() >= self.devices.wdt.deadline() {
+            self.devices.wdt.update(&self.clock);
+            if self.devices.take_puc() {
+                self._puc(ResetCause::Puc);
+            }
+        }
+        if self.fault.is_some() {
+            return; // nothing, interrupts included, touches the state the fault left behind
+        }
+        let gie: bool = self.sr.get_status(StatusFlags::GIE) && !self.interrupts_masked;
+        let fired: Option<u16> = self.storm.as_mut().and_then(|storm| storm.fire(gie));
+        if let Some(vector) = fired {
+            self._enter_interrupt(vector);
+        }
+        if !self.servicing_nmi {
+            if let Some(vector) = self.devices.pending_nmi() {
+                self.servicing_nmi = true; // further NMIs wait for the RETI
+                self._enter_interrupt(vector);
+            }
+        }
+        if gie {
+            if let Some(vector) = self.devices.pending_interrupt() {
+                self.devices.accepted(vector);
+                self.interrupt(vector);
+            }
+        }
+        self.clock.gate_aclk(self.sr.get_status(StatusFlags::OSCOFF));
+        self.clock.gate_smclk(self.sr.get_status(StatusFlags::SCG1));
+        if self.sr.get_status(StatusFlags::CPUOFF) {
+            // MCLK is off, the clocks left running and what samples the pins carry on until an
+            // interrupt clears the SR, its RETI restores the low-power bits unless the handler cleared them
+            self.clock.advance(1);
+            if self.hooks {
+                self._sample_devices();
+            }
+            return;
+        }
+        let pc_w: u16 = self.pc.get_word();
+        if let Some(region) = self.no_execute.iter().find(|r| r.contains(pc_w)) {
+            self.fault = Some(Fault::NoExecute { pc: pc_w, region: *region });
+            return;
+        }
+        if let Some(runaway) = &mut self.runaway {
+            if let Some((first, last)) = runaway.fetch(pc_w) {
+                self.fault = Some(Fault::Runaway { first, last, cycles: runaway.budget() });
+                return;
+            }
+        }
+        if let Some(heap) = &mut self.heap {
+            let sp: u16 = self.sp.get_word();
+            heap.fetch(pc_w, self.instruction_pc, sp, self.numbered_registers[8].get_word(), self.memory.get_word(sp));
+        }
+        self.instruction_pc = pc_w;
+        let instruction: u16 = if self.devices.mpu.allows(pc_w, Access::Execute, pc_w) {
+            self.memory.get_word(pc_w)
+        } else {
+            mpu::VIOLATION_READ
+        };
+        if !self.eem.fetch(pc_w, instruction) {
+            return; // hardware breakpoint, halted before executing
+        }
+        // where a RET pops its return address from, RETI pops SR first and the return address sits above it
+        let return_slot: Option<u16> = match instruction {
+            stepping::RET => Some(self.sp.get_word()),
+            stepping::RETI => Some(self.sp.get_word().wrapping_add(2)),
+            _ => None,
+        };
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            let corruption = return_slot.and_then(|slot| shadow_stack.popping(slot, self.memory.get_word(slot)));
+            if let Some(Corruption { slot, expected, found }) = corruption {
+                self.fault = Some(Fault::ReturnAddress { pc: pc_w, slot, expected, found });
+                return;
+            }
+        }
+        self.pc_history.record(pc_w, instruction);
+        self.pc.set_word(pc_w.wrapping_add(2));
+
+        let started: u64 = self.clock.cycles();
+        let sr_before: u16 = self.sr.get_word();
+        let decoded: Decoded = decode::decode(instruction);
+        if self.diagnostics.enabled(Category::IllegalInstruction) && decode::illegal(instruction, decoded) {
+            self.diagnostics.raise(Category::IllegalInstruction, pc_w, instruction);
+        }
+        self._execute(decoded);
+        if self.diagnostics.checks_instructions() {
+            self.diagnostics.retired(pc_w, instruction, decoded, sr_before, self.sr.get_word());
+        }
+        if let Some(diagnostic) = self.diagnostics.take_trap() {
+            self.fault = Some(Fault::Diagnostic(diagnostic));
+        }
+        self.retired += 1;
+        self.clock.advance(timing::cycles(decoded));
+        debug_assert!(self.hooks || !self._any_hooks(), "A hook was attached without attach_hooks");
+        if self.hooks {
+            self._run_hooks(pc_w, instruction, decoded, started, return_slot);
+        }
+        self.eem.retire();
+        let diagnostic_reset: bool = self.diagnostics.take_reset();
+        if self.devices.take_puc() {
+            self._puc(ResetCause::Puc);
+        } else if diagnostic_reset {
+            self._puc(ResetCause::Diagnostic);
+        }
+    }
+
+    /// Call after attaching a per-instruction hook: the PWM analyzer, sensors, LED strips, input
+    /// widgets, branch trace, profiler or trace hash. Steps skip them all until then.
+    pub(crate) fn attach_hooks(&mut self) {
+        self.hooks = self._any_hooks();
+    }
+
+    fn _any_hooks(&self) -> bool {
+        return self.pwm.is_some() || self.sensors.is_some() || !self.led_strips.is_empty() || self.widgets.is_some()
+            || self.branch_trace.is_some() || self.profiler.is_some() || self.trace_hash.is_some();
+    }
+
+    /// Everything that follows the instruction at `pc` that just retired, `started` is the cycle it
+    /// started at
+    fn _run_hooks(&mut self, pc: u16, instruction: u16, decoded: Decoded, started: u64, return_slot: Option<u16>) {
+        if self.branch_trace.is_some() {
+            self._trace_branch(pc, instruction, decoded);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.retired(pc, self.clock.cycles() - started);
+            if let Some(slot) = return_slot {
+                profiler.returned(slot, self.clock.cycles());
+            } else if matches!(decoded, Decoded::Single { opcode: stepping::CALL_OPCODE, bw: false, .. }) {
+                profiler.called(pc, self.pc.get_word(), self.sp.get_word(), self.clock.cycles());
+            }
+        }
+        self._sample_devices();
+        let registers: Option<[u16; 16]> = self.trace_hash.as_ref().map(|_| self.register_words());
+        if let (Some(trace_hash), Some(registers)) = (&mut self.trace_hash, registers) {
+            trace_hash.retire(&registers, self.clock.cycles());
+        }
+    }
+
+    /// Models that watch or drive the pins, they follow time whether the CPU runs or not
+    #[inline]
+    fn _sample_devices(&mut self) {
+        if let Some(pwm) = &mut self.pwm {
+            pwm.sample(self.clock.cycles(), &self.devices.gpio);
+        }
+        if let Some(sensors) = &mut self.sensors {
+            sensors.sample(self.clock.cycles(), self.clock.mclk_hz(), &mut self.devices.gpio);
+        }
+        for strip in &mut self.led_strips {
+            strip.sample(self.clock.cycles(), self.clock.mclk_hz(), &self.devices.gpio);
+        }
+        if let Some(widgets) = &mut self.widgets {
+            widgets.sample(self.clock.cycles(), self.clock.mclk_hz(), &mut self.devices.gpio);
+        }
+    }
+
+    fn _execute(&mut self, decoded: Decoded) {
+        match decoded {
+            Decoded::Single { opcode, reg, as_, bw } => self._execute_single_operand(opcode, reg, as_, bw),
+            Decoded::Jump { condition, offset } => self._execute_jump(condition, offset),
+            Decoded::Double { opcode, src_reg, as_, ad, bw, dst_reg } =>
+                self._execute_double_operand(opcode, src_reg, as_, ad, bw, dst_reg),
+            Decoded::None => {},
+        }
+    }
+
+    fn _print_flags(&self) {
+        println!("Flags:");
+        println!("\t N: {}", self.sr.get_status(StatusFlags::NEGATIVE));
+        println!("\t Z: {}", self.sr.get_status(StatusFlags::ZERO));
+        println!("\t C: {}", self.sr.get_status(StatusFlags::CARRY));
+        println!("\t V: {}", self.sr.get_status(StatusFlags::OVERFLOW));
+    }
+
+    /// `offset` is in bytes
+    fn _execute_jump(&mut self, condition: u8, offset: i16) { // all of this is tested
+        match condition {
+            0 => { // JNE/JNZ
+                if self.sr.get_status(StatusFlags::ZERO) {return;}
+            },
+            1 => { // JEQ/JZ
+                if !self.sr.get_status(StatusFlags::ZERO) {return;}
+            },
+            2 => { // JNC/JLO
+                if self.sr.get_status(StatusFlags::CARRY) {return;}
+            },
+            3 => { // JC/JHS
+                //println!("JHS");
+                if !self.sr.get_status(StatusFlags::CARRY) {
+                    //println!("Continuing");
+                    return;
+                }
+                //println!("Jumping");
+            },
+            4 => { // JN
+                if !self.sr.get_status(StatusFlags::NEGATIVE) {return;}
+            },
+            5 => { // JGE
+                if self.sr.get_status(StatusFlags::NEGATIVE) ^ self.sr.get_status(StatusFlags::OVERFLOW) {return;}
+            },
+            6 => { // JL
+                if !(self.sr.get_status(StatusFlags::NEGATIVE) ^ self.sr.get_status(StatusFlags::OVERFLOW)) {return;}
+            },
+            7 => { // JMP
+                // unconditional jump
+            }
+            _ => println!("Unknown condition"),
+        }
+
+        self.pc.set_word((self.pc.get_word() as i32 + offset as i32) as u16);
+    }
+
+    /// Resolves an operand in any addressing mode, `mode` is As for sources and Ad for destinations.
+    /// Indexed modes leave their offset word for the caller to skip (see `Operand::words`)
+    #[inline]
+    fn _resolve_operand(&mut self, reg: u8, mode: u8, bw: bool, destination: bool) -> Operand {
+        if !destination && (reg == 3 || (reg == 2 && mode > 1)) { // CG (or SR outside of Register or Indexed modes)
+            let value: u16 = match (reg, mode) {
+                (2, 2) => 4,
+                (2, _) => 8,
+                (_, 0) => 0,
+                (_, 1) => 1,
+                (_, 2) => 2,
+                _ => if bw {0xff} else {0xffff},
+            };
+            return Operand { value, target: WriteTargets::VOID, words: 0 };
+        }
+
+        if mode == 0 { // Register Mode
+            let value: u16 = if bw {
+                self.get_register(reg).get_byte() as u16
+            } else {
+                self.get_register(reg).get_word()
+            };
+            return Operand { value, target: RegisterWriteTarget::new(reg), words: 0 };
+        }
+        let (address, words): (u16, u16) = if mode == 1 { // Indexed Mode
+            let offset: u16 = self.memory.get_word(self.pc.get_word());
+            // SR reads as 0 here (Absolute Mode), as does CG for destinations
+            let base: u16 = if reg == 2 {0} else {self.get_register(reg).get_word()};
+            (offset.wrapping_add(base), 1)
+        } else if mode == 2 { // Register Indirect Mode
+            (self.get_register(reg).get_word(), 0)
+        } else if mode == 3 { // Register Indirect Autoincrement Mode
+            let address: u16 = self.get_register(reg).get_word();
+            let step: u16 = if bw && reg != 0 && reg != 1 {1} else {2}; // PC and SP stay even
+            self.get_register(reg).set_word(address.wrapping_add(step));
+            (address, 0)
+        } else {
+            panic!("Impossible addressing mode");
+        };
+        let value: u16 = if bw {self.read_byte(address) as u16} else {self.read_word(address)};
+        return Operand { value, target: MemoryWriteTarget::new(address), words };
+    }
+
+    /// Resolves an operand and moves the PC past its offset word, if any
+    #[inline]
+    fn _fetch_operand(&mut self, reg: u8, mode: u8, bw: bool, destination: bool) -> Operand {
+        let operand: Operand = self._resolve_operand(reg, mode, bw, destination);
+        self.pc.set_word(self.pc.get_word().wrapping_add(2 * operand.words));
+        return operand;
+    }
+
+    fn _push(&mut self, value: u16, bw: bool) {
+        let sp_word: u16 = self.sp.get_word().wrapping_sub(2);
+        self.sp.set_word(sp_word);
+        if bw {
+            self.write_byte(sp_word+1, (value & 0xff) as u8);
+        } else {
+            self.write_word(sp_word, value);
+        }
+    }
+
+    fn _execute_single_operand(&mut self, opcode: u8, src_reg: u8, as_: u8, bw: bool) { // PUSH implementation: decrement SP,
+                                                                                     // then execute as usual
+        let Ok(opc) = SingleOperandOpcodes::try_from(opcode) else {
+            return; // MSP430X extension words, executed as nothing (see the illegal-instruction diagnostic)
+        };
+        let bw_num: u16 = if bw {7} else {15};
+
+        // read source
+        let Operand { value, target: mut wt, .. } = self._fetch_operand(src_reg, as_, bw, false);
+        let src: &mut u16 = &mut 0;
+        *src = value;
+
+        let no_write: &mut bool = &mut false;
+        
+        // apply operation
+        match opc {
+            SingleOperandOpcodes::RRC => { // tested
+                let carry: bool = (*src & 1) == 1;
+                *src >>= 1;
+                // put carry back in, taking into account byte-mode as bw
+                *src |= (self.sr.get_status(StatusFlags::CARRY) as u16) << bw_num;
+
+                self._write_flags(carry, *src == 0, (*src >> bw_num & 1) == 1, false);
+            },
+            SingleOperandOpcodes::SWPB => { // tested
+                if !bw {
+                    *src = ((*src & 0xff00) >> 8) | ((*src & 0xff) << 8);
+                }
+            },
+            SingleOperandOpcodes::RRA => { // tested
+                let carry: bool = *src & 1 == 1;
+                let msb_to_or: u16 = *src & (if bw {128} else {32768});
+                *src >>= 1;
+                *src |= msb_to_or;
+                self._write_flags(carry, *src == 0, (*src >> bw_num) & 1 == 1, false);
+            },
+            SingleOperandOpcodes::SXT => { // tested
+                if !bw {
+                    *src &= 0xff;
+                    if (*src >> 7 & 1) == 1 {
+                        *src |= 0xff00;
+                    }
+                    self._set_logic_flags(*src, false, false);
+                }
+            },
+            SingleOperandOpcodes::PUSH => { // tested (indirectly) by other tests
+                //println!("Pushing {}", *src);
+                self._push(*src, bw);
+                *no_write = true;
+            },
+            SingleOperandOpcodes::CALL => { // tested
+                if !bw {
+                    self.sp.set_word(self.sp.get_word().wrapping_sub(2));
+                    self.write_word(self.sp.get_word(), self.pc.get_word());
+                    if let Some(shadow_stack) = &mut self.shadow_stack {
+                        shadow_stack.pushed(self.sp.get_word(), self.pc.get_word());
+                    }
+                    self.pc.set_word(*src);
+                    *no_write = true;
+                }
+            },
+            SingleOperandOpcodes::RETI => { // tested
+                let popped_sr: u16 = self.read_word(self.sp.get_word());
+                // pop SR
+                self.sr.set_word(popped_sr);
+                self.sp.set_word(self.sp.get_word().wrapping_add(2));
+
+                let popped_pc = self.read_word(self.sp.get_word());
+                // pop PC
+                self.pc.set_word(popped_pc);
+                self.sp.set_word(self.sp.get_word().wrapping_add(2));
+                self.servicing_nmi = false;
+                let registers: [u16; 12] = std::array::from_fn(|i| self.numbered_registers[i].get_word());
+                if let Some(storm) = &mut self.storm {
+                    storm.returned(self.sp.get_word(), popped_pc, popped_sr, registers);
+                }
+                *no_write = true;
+            }
+        }
+
+        if !(*no_write) {
+            if bw {
+                wt.set_byte((*src & 0xff) as u8, self);
+            } else {
+                wt.set_word(*src, self);
+            }
+        }
+    }
+
+    /// `src` is the operand that was added, the complemented source for subtraction
+    #[inline]
+    fn _set_flags(&mut self, src: u16, prev_dst: u16, full_dst: u32, dst: u16, byte_mode: bool) {
+        let byte_int: u16 = if byte_mode {7} else {15};
+        let dst_sign: u16 = dst >> byte_int & 1;
+        let prev_dst_sign: u16 = prev_dst >> byte_int & 1;
+        let carry: bool = full_dst > (if byte_mode {0xff} else {0xffff});
+        // overflow is set if the sign of the operands is the same, and the sign of the result is different
+        // (e.g. positive + positive = negative, or negative + negative = positive)
+        let overflow: bool = prev_dst_sign == (src >> byte_int & 1) && prev_dst_sign != dst_sign;
+        self._write_flags(carry, dst == 0, dst_sign == 1, overflow);
+    }
+
+    /// AND, BIT and XOR: carry is the inverse of zero
+    #[inline]
+    fn _set_logic_flags(&mut self, result: u16, overflow: bool, byte_mode: bool) {
+        let byte_int: u16 = if byte_mode {7} else {15};
+        self._write_flags(result != 0, result == 0, result >> byte_int & 1 == 1, overflow);
+    }
+
+    /// Replaces C, Z, N and V with one SR write
+    #[inline]
+    fn _write_flags(&mut self, carry: bool, zero: bool, negative: bool, overflow: bool) {
+        let arithmetic: StatusFlags = StatusFlags::CARRY | StatusFlags::ZERO | StatusFlags::NEGATIVE | StatusFlags::OVERFLOW;
+        let mut sr: u16 = self.sr.get_word() & !arithmetic.bits();
+        sr |= (carry as u16) * StatusFlags::CARRY.bits();
+        sr |= (zero as u16) * StatusFlags::ZERO.bits();
+        sr |= (negative as u16) * StatusFlags::NEGATIVE.bits();
+        sr |= (overflow as u16) * StatusFlags::OVERFLOW.bits();
+        self.sr.set_word(sr);
+    }
+
+    /// `opcode` counts from MOV, nonexistent opcodes never get here (see decode.rs)
+    fn _execute_double_operand(&mut self, opcode: u8, src_reg: u8, as_: u8, ad: u8, bw: bool, dst_reg: u8) {
+        let byte_int: u16 = if bw {7} else {15};
+
+        // read source
+        let src: u16 = self._fetch_operand(src_reg, as_, bw, false).value;
+
+        // read value of dst and make a write target
+        let Operand { value, target: mut wt, .. } = self._fetch_operand(dst_reg, ad, bw, true);
+        let dst: &mut u16 = &mut 0;
+        *dst = value;
+
+        let no_write: &mut bool = &mut false;
+
+        //println!("opcode: {}", opcode);
+        let opc: DoubleOperandOpcodes = DoubleOperandOpcodes::try_from(opcode).unwrap();
+        //println!("opc: {:#?}", opc);
+
+        let cutoff: u32 = if bw {0xff} else {0xffff};
+
+        match opc {
+            DoubleOperandOpcodes::MOV => { // tested
+                *dst = src;
+            },
+            DoubleOperandOpcodes::ADD => { // tested
+                let prev_dst: u16 = *dst;
+                let full_dst: u32 = (*dst as u32) + (src as u32);
+                *dst = (full_dst & cutoff) as u16;
+                self._set_flags(src, prev_dst, full_dst, *dst, bw);
+            },
+            DoubleOperandOpcodes::ADDC => { // tested
+                let prev_dst: u16 = *dst;
+                let full_dst: u32 = (*dst as u32) + (src as u32) + (self.sr.get_status(StatusFlags::CARRY) as u32);
+                *dst = (full_dst & cutoff) as u16;
+                self._set_flags(src, prev_dst, full_dst, *dst, bw);
+            },
+            DoubleOperandOpcodes::SUBC => { // Fuzzed
+                let prev_dst: u16 = *dst;
+                // dst - src - 1 + sr(CARRY) X old
+                // dst + !src + sr(CARRY) <---
+                let not_src: u16 = !src & cutoff as u16;
+                let full_dst: u32 = (*dst as u32).wrapping_add(not_src as u32)
+                    .wrapping_add(self.sr.get_status(StatusFlags::CARRY) as u32);
+                *dst = (full_dst & cutoff) as u16;
+                self._set_flags(not_src, prev_dst, full_dst, *dst, bw);
+            },
+            DoubleOperandOpcodes::SUB => { // tested & fuzzed
+                let prev_dst: u16 = *dst;
+                //println!("SUB running {} - {}", *dst, src);
+                let not_src: u16 = !src & cutoff as u16;
+                let full_dst: u32 = (*dst as u32).wrapping_add(not_src as u32).wrapping_add(1);
+                *dst = (full_dst & cutoff) as u16;
+                self._set_flags(not_src, prev_dst, full_dst, *dst, bw);
+            },
+            DoubleOperandOpcodes::CMP => { // not tested, but same impl as SUB
+                //println!("CMP {} {}", src, *dst);
+                let prev_dst: u16 = *dst;
+                let not_src: u16 = !src & cutoff as u16;
+                let full_dst: u32 = (*dst as u32).wrapping_add(not_src as u32).wrapping_add(1);
+                // println!("still CMP, ({}).wrapping_sub({}) = {}", *dst as u32, src as u32, full_dst);
+                let fake_dst: u16 = (full_dst & cutoff) as u16;
+                self._set_flags(not_src, prev_dst, full_dst, fake_dst, bw);
+                //self._print_flags();
+                *no_write = true;
+            },
+            DoubleOperandOpcodes::DADD => { // tested
+                // decimal add of src, dst and carry, one BCD digit at a time
+                let digits: u16 = if bw {2} else {4};
+                let mut carry: u16 = self.sr.get_status(StatusFlags::CARRY) as u16;
+                let mut result: u16 = 0;
+                for digit in 0..digits {
+                    let mut sum: u16 = (src >> (digit * 4) & 0xf) + (*dst >> (digit * 4) & 0xf) + carry;
+                    carry = (sum > 9) as u16;
+                    if sum > 9 {
+                        sum -= 10;
+                    }
+                    result |= (sum & 0xf) << (digit * 4);
+                }
+                *dst = result;
+                self._write_flags(carry == 1, result == 0, (result >> byte_int & 1) == 1, false);
+            },
+            DoubleOperandOpcodes::BIT => { // not tested, but same impl as AND
+                self._set_logic_flags(*dst & src, false, bw);
+                *no_write = true;
+            },
+            DoubleOperandOpcodes::BIC => { // tested
+                *dst &= !src;
+            },
+            DoubleOperandOpcodes::BIS => { // tested
+                *dst |= src;
+            },
+            DoubleOperandOpcodes::XOR => { // tested
+                let prev_dst: u16 = *dst;
+                *dst ^= src;
+                self._set_logic_flags(*dst, (src >> byte_int & 1) == 1 && (prev_dst >> byte_int & 1) == 1, bw);
+            },
+            DoubleOperandOpcodes::AND => { // tested
+                *dst &= src;
+                self._set_logic_flags(*dst, false, bw);
+            },
+        }
+        if !(*no_write) {
+            if bw {
+                wt.set_byte((*dst & 0xff) as u8, self);
+            } else {
+                wt.set_word(*dst, self);
+            }
+        }
+    }
+}
+
+fn file_as_byte_vec(filename: &String) -> Vec<u8> {
+    println!("Decoding file: '{}'", filename);
+    let mut f = File::open(filename).expect("File not found");
+    let mut buf: Vec<u8> = Vec::new();
+    f.read_to_end(&mut buf).expect("Failed to read file");
+    return buf;
+}
+
+#[derive(Debug)]
+enum ShmemCommands {
+    None,
+    Stop,
+    Run,
+    Step(u16),
+    LoadFile(String),
+    /// load without resetting, memory and registers are kept
+    OverlayFile(String),
+    SetMem(u16, u16),
+    Interrupt(u16),
+    Seed(u64),
+    PcHistory,
+    AddWatch(String),
+    RemoveWatch(u16),
+    Evaluate(String),
+    MailboxSend(u8, u16),
+    MailboxReceive(u8),
+    /// trigger index, `None` to disable it
+    SetTrigger(u8, Option<Trigger>),
+    /// breakpoint index, mask of the triggers it combines
+    SetBreakpoint(u8, u8),
+    EemInfo,
+    PwmMeasurement(PinId),
+    /// pin of a `run --ws2812` strip
+    LedStrip(PinId),
+    FrameBuffer,
+    /// encoder index, detents to turn (negative counter-clockwise), switch pressed or released
+    Encoder(u8, i16, Option<bool>),
+    /// button matrix index, row, column, pressed
+    Key(u8, u8, u8, bool),
+    /// target millivolts, ramp duration in cycles
+    Supply(u16, u32),
+    /// `None` for an unknown format
+    Dump(Option<DumpFormat>, Region, String),
+    StepOver,
+    StepOut,
+    FinishInterrupt,
+    StopReason,
+    /// new owner PID, 0 for none
+    Attach(u32),
+    InterruptVectors,
+    /// index into `peripherals::describe`
+    Peripheral(u8),
+    /// crystal, broken or repaired
+    OscillatorFault(Crystal, bool),
+    TouchPad(TouchPad),
+    /// cycles the power stays off
+    PowerCycle(u32),
+    /// first and last address, read back run-length coded
+    ReadMemory(Region),
+    /// start address, run-length coded bytes to write there
+    LoadBinary(u16, Vec<u8>),
+    /// mask of the notification kinds to push as events
+    Notify(u16),
+    Reset(ResetKind),
+    /// step with options
+    StepWith(StepRun),
+    /// path to write the machine state to
+    SaveSnapshot(String),
+    /// path of a snapshot to continue from
+    LoadSnapshot(String),
+    /// path of a final snapshot, if any
+    Shutdown(Option<String>),
+    Unknown
+}
+
+impl ShmemCommands {
+    /// The command area bytes `SharedMemorySystem::get_command` reads this from, for recordings
+    fn encode(&self) -> Vec<u8> {
+        let string = |id: u8, text: &str| [&[id], text.as_bytes(), &[0]].concat();
+        return match self {
+            ShmemCommands::None => vec![0],
+            ShmemCommands::Stop => vec![1],
+            ShmemCommands::Run => vec![2],
+            ShmemCommands::Step(n) => [&[3][..], &n.to_be_bytes()].concat(),
+            ShmemCommands::LoadFile(path) => string(4, path),
+            ShmemCommands::SetMem(address, value) => [&[5][..], &address.to_be_bytes(), &value.to_be_bytes()].concat(),
+            ShmemCommands::Interrupt(vector) => [&[6][..], &vector.to_be_bytes()].concat(),
+            ShmemCommands::Seed(seed) => [&[7][..], &seed.to_be_bytes()].concat(),
+            ShmemCommands::PcHistory => vec![8],
+            ShmemCommands::AddWatch(expression) => string(9, expression),
+            ShmemCommands::RemoveWatch(id) => [&[10][..], &id.to_be_bytes()].concat(),
+            ShmemCommands::Evaluate(expression) => string(11, expression),
+            ShmemCommands::MailboxSend(channel, word) => [&[12, *channel][..], &word.to_be_bytes()].concat(),
+            ShmemCommands::MailboxReceive(channel) => vec![13, *channel],
+            ShmemCommands::SetTrigger(index, trigger) => match trigger {
+                Some(t) => {
+                    let (compare, (data, data_mask)) = match t.data {
+                        Some(data) => (1, data),
+                        None => (0, (0, 0)),
+                    };
+                    [&[14, *index, t.kind.id()][..], &t.address.to_be_bytes(), &t.address_mask.to_be_bytes(), &[compare],
+                     &data.to_be_bytes(), &data_mask.to_be_bytes()].concat()
+                },
+                None => vec![14, *index, 0],
+            },
+            ShmemCommands::SetBreakpoint(index, triggers) => vec![15, *index, *triggers],
+            ShmemCommands::EemInfo => vec![16],
+            ShmemCommands::Supply(target, cycles) => [&[17][..], &target.to_be_bytes(), &cycles.to_be_bytes()].concat(),
+            ShmemCommands::PwmMeasurement(pin) => vec![18, pin.port, pin.pin],
+            ShmemCommands::LedStrip(pin) => vec![38, pin.port, pin.pin],
+            ShmemCommands::FrameBuffer => vec![42],
+            ShmemCommands::Encoder(index, detents, switch) => {
+                let switch: u8 = match switch {
+                    None => 0,
+                    Some(true) => 1,
+                    Some(false) => 2,
+                };
+                [&[39, *index][..], &detents.to_be_bytes(), &[switch]].concat()
+            },
+            ShmemCommands::Key(index, row, column, pressed) => vec![40, *index, *row, *column, *pressed as u8],
+            ShmemCommands::Shutdown(snapshot) => string(41, snapshot.as_deref().unwrap_or("")),
+            ShmemCommands::OverlayFile(path) => string(19, path),
+            ShmemCommands::Dump(format, region, path) => {
+                [&[20, format.map(|f| f.id()).unwrap_or(0xff)][..], &region.start.to_be_bytes(), &region.end.to_be_bytes(),
+                 &string(0, path)[1..]].concat()
+            },
+            ShmemCommands::StepOver => vec![21],
+            ShmemCommands::StepOut => vec![22],
+            ShmemCommands::FinishInterrupt => vec![23],
+            ShmemCommands::StopReason => vec![24],
+            ShmemCommands::Attach(pid) => [&[25][..], &pid.to_be_bytes()].concat(),
+            ShmemCommands::InterruptVectors => vec![26],
+            ShmemCommands::Peripheral(index) => vec![27, *index],
+            ShmemCommands::OscillatorFault(crystal, failed) => vec![28, crystal.id(), *failed as u8],
+            ShmemCommands::PowerCycle(cycles) => [&[30][..], &cycles.to_be_bytes()].concat(),
+            ShmemCommands::TouchPad(pad) => [&[29, pad.pin.port, pad.pin.pin][..], &(pad.femtofarads as u16).to_be_bytes()].concat(),
+            ShmemCommands::ReadMemory(region) => [&[31][..], &region.start.to_be_bytes(), &region.end.to_be_bytes()].concat(),
+            ShmemCommands::LoadBinary(address, data) => {
+                [&[32][..], &address.to_be_bytes(), &(data.len() as u16).to_be_bytes(), data].concat()
+            },
+            ShmemCommands::Notify(mask) => [&[33][..], &mask.to_be_bytes()].concat(),
+            ShmemCommands::Reset(kind) => vec![34, kind.id()],
+            ShmemCommands::StepWith(run) => [&[35][..], &run.remaining.to_be_bytes(), &[run.options()]].concat(),
+            ShmemCommands::SaveSnapshot(path) => string(36, path),
+            ShmemCommands::LoadSnapshot(path) => string(37, path),
+            ShmemCommands::Unknown => vec![0xff],
+        };
+    }
+}
+
+enum RunMode {
+    Stopped,
+    Running,
+    Stepping(StepRun),
+    /// running until a step-over/step-out/finish-interrupt is done
+    Until(StepGoal),
+    /// the firmware set CPUOFF in one of the other modes, which resumes once an interrupt wakes it
+    Idle(Box<RunMode>),
+}
+
+impl RunMode {
+    fn name(&self) -> &'static str {
+        return match self {
+            RunMode::Stopped => "stopped",
+            RunMode::Running => "running",
+            RunMode::Stepping(_) => "stepping",
+            RunMode::Until(_) => "running to a step goal",
+            RunMode::Idle(_) => "idle (CPU off)",
+        };
+    }
+
+    /// As reported in the status block
+    fn id(&self) -> u8 {
+        return match self {
+            RunMode::Stopped => 0,
+            RunMode::Running => 1,
+            RunMode::Stepping(_) => 2,
+            RunMode::Until(_) => 3,
+            RunMode::Idle(_) => 4,
+        };
+    }
+}
+
+/// Identity of the loaded program, so a frontend that reattaches can tell whether it still matches
+/// what it knows
+#[derive(Debug, Default)]
+struct LoadedImage {
+    /// counts loads and overlays, 0 until the first
+    generation: u32,
+    checksum: u32,
+    name: String,
+    /// PC the last load started at, overlays keep it
+    entry: u16,
+    /// segments of the image and the overlays on it, in load order
+    segments: Vec<LoadedSegment>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct LoadedSegment {
+    /// first and last address
+    region: Region,
+    /// CRC-32 of the segment's bytes
+    checksum: u32,
+}
+
+impl LoadedSegment {
+    fn of(segment: &image::Segment) -> LoadedSegment {
+        let last: u16 = segment.address.wrapping_add((segment.data.len() as u16).wrapping_sub(1));
+        return LoadedSegment { region: Region { start: segment.address, end: last }, checksum: utils::crc32(&segment.data) };
+    }
+}
+
+impl LoadedImage {
+    fn load(&mut self, image: &ProgramImage, name: &str, entry: u16) {
+        self.generation = self.generation.wrapping_add(1);
+        self.checksum = image.checksum();
+        self.name = image.metadata.as_ref().map(|m| m.name.clone()).unwrap_or_else(|| name.to_string());
+        self.entry = entry;
+        self.segments = image.segments.iter().filter(|s| !s.data.is_empty()).map(LoadedSegment::of).collect();
+    }
+
+    /// The checksum covers the previous one, so it still identifies the whole stack of images
+    fn overlay(&mut self, image: &ProgramImage, name: &str) {
+        let previous: u32 = self.checksum;
+        let base: String = std::mem::take(&mut self.name);
+        let mut segments: Vec<LoadedSegment> = std::mem::take(&mut self.segments);
+        self.load(image, name, self.entry);
+        if !base.is_empty() {
+            self.name = format!("{} + {}", base, self.name);
+        }
+        let chained: Vec<u8> = previous.to_be_bytes().iter().chain(self.checksum.to_be_bytes().iter()).copied().collect();
+        self.checksum = utils::crc32(&chained);
+        segments.append(&mut self.segments);
+        self.segments = segments;
+    }
+
+    fn regions(&self) -> Vec<Region> {
+        return self.segments.iter().map(|segment| segment.region).collect();
+    }
+}
+
+struct SharedMemorySystem {
+    raw_ptr: *mut u8,
+    events_written: u32,
+    /// memory as currently mirrored into shared memory
+    mirrored: MemoryMap,
+    /// memory as of the last diff handed to the frontend
+    published: MemoryMap
+}
+impl SharedMemorySystem {
+    fn new(raw_ptr: *mut u8) -> SharedMemorySystem {
+        return SharedMemorySystem {
+            raw_ptr,
+            events_written: 0,
+            mirrored: MemoryMap::new(),
+            published: MemoryMap::new(),
+        };
+    }
+
+    fn write_byte(&mut self, idx: usize, value: u8) {
+        if idx >= layout::SIZE {
+            panic!("Index error in write byte, {} is more than 65 kb", idx);
+        }
+        unsafe {
+            std::ptr::write_volatile(self.raw_ptr.add(idx), value);
+        }
+    }
+
+    fn read_byte(&self, idx: usize) -> u8 {
+        if idx >= layout::SIZE {
+            panic!("Index error in read byte, {} is more than 65 kb", idx);
+        }
+        unsafe {
+            return std::ptr::read_volatile(self.raw_ptr.add(idx));
+        }
+    }
+
+    fn read_string(&self, idx: usize) -> String {
+        if idx >= layout::SIZE {
+            panic!("Index error in read byte, {} is more than 65 kb", idx);
+        }
+        let c_buf: *const c_char = unsafe { self.raw_ptr.add(idx) } as *const c_char;
+        let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
+        return c_str.to_str().unwrap().to_owned();
+    }
+
+    fn write(&mut self, computer: &Computer) {
+        // the mirror starts zeroed like a fresh MemoryMap, so only changes need copying
+        for (address, _, new) in computer.memory.changes_since(&self.mirrored) {
+            self.write_byte(address as usize, new);
+        }
+        self.mirrored = computer.memory.clone();
+        self.publish_diff(computer);
+        for i in 0..=15 {
+            let reg_val: u16 = computer.get_register_imut(i).get_word();
+            let high: u8 = ((reg_val & 0xff00) >> 8) as u8;
+            let low: u8 = (reg_val & 0xff) as u8;
+            self.write_byte((i as usize)*2 + layout::REGISTERS, high);
+            self.write_byte((i as usize)*2 + layout::REGISTERS + 1 , low);
+        }
+        self.write_disassembly(computer);
+    }
+
+    /// The instruction at the PC and the ones after it in memory, disassembled so frontends can
+    /// show what is about to execute. Read from memory directly, devices see no accesses.
+    fn write_disassembly(&mut self, computer: &Computer) {
+        let mut address: u16 = computer.pc.get_word();
+        for slot in 0..layout::DISASSEMBLY_SLOTS {
+            let decoded: disasm::Disassembled = disasm::disassemble(address, &|a| computer.memory.get_word(a), false);
+            let entry: usize = layout::DISASSEMBLY_ENTRIES + slot * layout::DISASSEMBLY_SIZE;
+            let text: &[u8] = decoded.text.as_bytes();
+            let text: &[u8] = &text[..text.len().min(layout::DISASSEMBLY_SIZE - 5)]; // ASCII, cut anywhere
+            let header = [address.to_be_bytes()[0], address.to_be_bytes()[1], decoded.length as u8, 0];
+            for (i, byte) in header.iter().chain(text).chain(std::iter::once(&0)).enumerate() {
+                self.write_byte(entry + i, *byte);
+            }
+            address = address.wrapping_add(decoded.length);
+        }
+        self.write_byte(layout::DISASSEMBLY, layout::DISASSEMBLY_SLOTS as u8);
+    }
+
+    fn get_command(&self) -> ShmemCommands {
+        let cmd_id = self.read_byte(layout::COMMAND);
+
+        return match cmd_id {
+            0 => ShmemCommands::None,
+            1 => ShmemCommands::Stop,
+            2 => ShmemCommands::Run,
+            3 => {
+                let high: u16 = self.read_byte(layout::COMMAND + 1) as u16;
+                let low: u16 = self.read_byte(layout::COMMAND + 2) as u16;
+                return ShmemCommands::Step((high << 8) | low);
+            },
+            4 => {
+                return ShmemCommands::LoadFile(self.read_string(layout::COMMAND + 1));
+            },
+            5 => {
+                let high_addr: u16 = self.read_byte(layout::COMMAND + 1) as u16;
+                let low_addr: u16 = self.read_byte(layout::COMMAND + 2) as u16;
+                let high_val: u16 = self.read_byte(layout::COMMAND + 3) as u16;
+                let low_val: u16 = self.read_byte(layout::COMMAND + 4) as u16;
+                return ShmemCommands::SetMem((high_addr << 8) | low_addr, (high_val << 8) | low_val);
+            },
+            6 => {
+                let high: u16 = self.read_byte(layout::COMMAND + 1) as u16;
+                let low: u16 = self.read_byte(layout::COMMAND + 2) as u16;
+                return ShmemCommands::Interrupt((high << 8) | low);
+            },
+            7 => {
+                let mut seed: u64 = 0;
+                for i in 0..8 {
+                    seed = (seed << 8) | self.read_byte(layout::COMMAND + 1 + i) as u64;
+                }
+                return ShmemCommands::Seed(seed);
+            },
+            8 => ShmemCommands::PcHistory,
+            9 => ShmemCommands::AddWatch(self.read_string(layout::COMMAND + 1)),
+            10 => {
+                let high: u16 = self.read_byte(layout::COMMAND + 1) as u16;
+                let low: u16 = self.read_byte(layout::COMMAND + 2) as u16;
+                return ShmemCommands::RemoveWatch((high << 8) | low);
+            },
+            11 => ShmemCommands::Evaluate(self.read_string(layout::COMMAND + 1)),
+            12 => {
+                let high: u16 = self.read_byte(layout::COMMAND + 2) as u16;
+                let low: u16 = self.read_byte(layout::COMMAND + 3) as u16;
+                return ShmemCommands::MailboxSend(self.read_byte(layout::COMMAND + 1), (high << 8) | low);
+            },
+            13 => ShmemCommands::MailboxReceive(self.read_byte(layout::COMMAND + 1)),
+            14 => {
+                let word = |offset: usize| ((self.read_byte(layout::COMMAND + offset) as u16) << 8) | self.read_byte(layout::COMMAND + offset + 1) as u16;
+                let trigger = TriggerKind::from_id(self.read_byte(layout::COMMAND + 2)).map(|kind| Trigger {
+                    kind,
+                    address: word(3),
+                    address_mask: word(5),
+                    data: if self.read_byte(layout::COMMAND + 7) != 0 {Some((word(8), word(10)))} else {None},
+                });
+                return ShmemCommands::SetTrigger(self.read_byte(layout::COMMAND + 1), trigger);
+            },
+            15 => ShmemCommands::SetBreakpoint(self.read_byte(layout::COMMAND + 1), self.read_byte(layout::COMMAND + 2)),
+            16 => ShmemCommands::EemInfo,
+            17 => {
+                let target: u16 = ((self.read_byte(layout::COMMAND + 1) as u16) << 8) | self.read_byte(layout::COMMAND + 2) as u16;
+                let mut cycles: u32 = 0;
+                for i in 0..4 {
+                    cycles = (cycles << 8) | self.read_byte(layout::COMMAND + 3 + i) as u32;
+                }
+                return ShmemCommands::Supply(target, cycles);
+            },
+            18 => {
+                let pin = PinId { port: self.read_byte(layout::COMMAND + 1), pin: self.read_byte(layout::COMMAND + 2) };
+                return match pin.to_string().parse::<PinId>() {
+                    Ok(pin) => ShmemCommands::PwmMeasurement(pin),
+                    Err(_) => ShmemCommands::Unknown,
+                };
+            },
+            19 => ShmemCommands::OverlayFile(self.read_string(layout::COMMAND + 1)),
+            20 => {
+                let start: u16 = ((self.read_byte(layout::COMMAND + 2) as u16) << 8) | self.read_byte(layout::COMMAND + 3) as u16;
+                let end: u16 = ((self.read_byte(layout::COMMAND + 4) as u16) << 8) | self.read_byte(layout::COMMAND + 5) as u16;
+                if start > end {
+                    return ShmemCommands::Unknown;
+                }
+                return ShmemCommands::Dump(DumpFormat::from_id(self.read_byte(layout::COMMAND + 1)), Region { start, end },
+                                           self.read_string(layout::COMMAND + 6));
+            },
+            21 => ShmemCommands::StepOver,
+            22 => ShmemCommands::StepOut,
+            23 => ShmemCommands::FinishInterrupt,
+            24 => ShmemCommands::StopReason,
+            25 => {
+                let mut pid: u32 = 0;
+                for i in 0..4 {
+                    pid = (pid << 8) | self.read_byte(layout::COMMAND + 1 + i) as u32;
+                }
+                return ShmemCommands::Attach(pid);
+            },
+            26 => ShmemCommands::InterruptVectors,
+            27 => ShmemCommands::Peripheral(self.read_byte(layout::COMMAND + 1)),
+            28 => {
+                return match Crystal::from_id(self.read_byte(layout::COMMAND + 1)) {
+                    Some(crystal) => ShmemCommands::OscillatorFault(crystal, self.read_byte(layout::COMMAND + 2) != 0),
+                    None => ShmemCommands::Unknown,
+                };
+            },
+            29 => {
+                let pin = PinId { port: self.read_byte(layout::COMMAND + 1), pin: self.read_byte(layout::COMMAND + 2) };
+                let femtofarads: u32 = ((self.read_byte(layout::COMMAND + 3) as u32) << 8) | self.read_byte(layout::COMMAND + 4) as u32;
+                return match pin.to_string().parse::<PinId>() {
+                    Ok(pin) if femtofarads != 0 => ShmemCommands::TouchPad(TouchPad { pin, femtofarads }),
+                    _ => ShmemCommands::Unknown,
+                };
+            },
+            30 => {
+                let mut cycles: u32 = 0;
+                for i in 0..4 {
+                    cycles = (cycles << 8) | self.read_byte(layout::COMMAND + 1 + i) as u32;
+                }
+                return ShmemCommands::PowerCycle(cycles);
+            },
+            31 => {
+                let start: u16 = ((self.read_byte(layout::COMMAND + 1) as u16) << 8) | self.read_byte(layout::COMMAND + 2) as u16;
+                let end: u16 = ((self.read_byte(layout::COMMAND + 3) as u16) << 8) | self.read_byte(layout::COMMAND + 4) as u16;
+                if start > end {
+                    return ShmemCommands::Unknown;
+                }
+                return ShmemCommands::ReadMemory(Region { start, end });
+            },
+            32 => {
+                let address: u16 = ((self.read_byte(layout::COMMAND + 1) as u16) << 8) | self.read_byte(layout::COMMAND + 2) as u16;
+                let length: usize = ((self.read_byte(layout::COMMAND + 3) as usize) << 8) | self.read_byte(layout::COMMAND + 4) as usize;
+                if layout::COMMAND + 5 + length > layout::EVENTS {
+                    return ShmemCommands::Unknown;
+                }
+                let data: Vec<u8> = (0..length).map(|i| self.read_byte(layout::COMMAND + 5 + i)).collect();
+                return ShmemCommands::LoadBinary(address, data);
+            },
+            33 => ShmemCommands::Notify(((self.read_byte(layout::COMMAND + 1) as u16) << 8) | self.read_byte(layout::COMMAND + 2) as u16),
+            34 => {
+                return match ResetKind::from_id(self.read_byte(layout::COMMAND + 1)) {
+                    Some(kind) => ShmemCommands::Reset(kind),
+                    None => ShmemCommands::Unknown,
+                };
+            },
+            35 => {
+                let count: u16 = ((self.read_byte(layout::COMMAND + 1) as u16) << 8) | self.read_byte(layout::COMMAND + 2) as u16;
+                return ShmemCommands::StepWith(StepRun::with_options(count, self.read_byte(layout::COMMAND + 3)));
+            },
+            36 => ShmemCommands::SaveSnapshot(self.read_string(layout::COMMAND + 1)),
+            37 => ShmemCommands::LoadSnapshot(self.read_string(layout::COMMAND + 1)),
+            38 => {
+                let pin = PinId { port: self.read_byte(layout::COMMAND + 1), pin: self.read_byte(layout::COMMAND + 2) };
+                return match pin.to_string().parse::<PinId>() {
+                    Ok(pin) => ShmemCommands::LedStrip(pin),
+                    Err(_) => ShmemCommands::Unknown,
+                };
+            },
+            39 => {
+                let detents: i16 = i16::from_be_bytes([self.read_byte(layout::COMMAND + 2), self.read_byte(layout::COMMAND + 3)]);
+                let switch: Option<bool> = match self.read_byte(layout::COMMAND + 4) {
+                    0 => None,
+                    1 => Some(true),
+                    2 => Some(false),
+                    _ => return ShmemCommands::Unknown,
+                };
+                return ShmemCommands::Encoder(self.read_byte(layout::COMMAND + 1), detents, switch);
+            },
+            40 => ShmemCommands::Key(self.read_byte(layout::COMMAND + 1), self.read_byte(layout::COMMAND + 2),
+                                     self.read_byte(layout::COMMAND + 3), self.read_byte(layout::COMMAND + 4) != 0),
+            41 => {
+                let path: String = self.read_string(layout::COMMAND + 1);
+                return ShmemCommands::Shutdown((!path.is_empty()).then_some(path));
+            },
+            42 => ShmemCommands::FrameBuffer,
+            _ => ShmemCommands::Unknown
+        };
+    }
+
+    /// Reply to the PC history command in the command area: 2 bytes count, then (pc, instruction)
+    /// word pairs, oldest first. Only the newest entries that fit are sent.
+    fn write_pc_history(&mut self, history: &PcHistory) {
+        const MAX_ENTRIES: usize = (layout::EVENTS - (layout::COMMAND + 3)) / 4;
+        let entries = history.entries();
+        let entries = &entries[entries.len().saturating_sub(MAX_ENTRIES)..];
+        let mut data: Vec<u8> = Vec::with_capacity(2 + entries.len() * 4);
+        data.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+        for entry in entries {
+            data.extend_from_slice(&entry.pc.to_be_bytes());
+            data.extend_from_slice(&entry.instruction.to_be_bytes());
+        }
+        for (i, byte) in data.into_iter().enumerate() {
+            self.write_byte(layout::COMMAND + 1 + i, byte);
+        }
+    }
+
+    /// Reply to a command with a word in place of its follow-up bytes
+    fn write_reply_word(&mut self, value: u16) {
+        self.write_byte(layout::COMMAND + 1, (value >> 8) as u8);
+        self.write_byte(layout::COMMAND + 2, (value & 0xff) as u8);
+    }
+
+    /// Reply with 1 byte status (0 = ok) and a word in the command area
+    fn write_status_reply(&mut self, status: u8, value: u16) {
+        self.write_byte(layout::COMMAND + 1, status);
+        self.write_byte(layout::COMMAND + 2, (value >> 8) as u8);
+        self.write_byte(layout::COMMAND + 3, (value & 0xff) as u8);
+    }
+
+    /// Reply to the read memory command: 1 byte status (0 = ok), the last address covered, the
+    /// length of the coded bytes and the bytes, as much of the region as fits before the event area
+    fn write_memory_block(&mut self, memory: &MemoryMap, region: Region) {
+        let bytes: Vec<u8> = (region.start..=region.end).map(|address| memory.get_byte(address)).collect();
+        let (coded, covered) = rle::encode_prefix(&bytes, layout::EVENTS - (layout::COMMAND + 6));
+        let last: u16 = region.start + (covered as u16).wrapping_sub(1);
+        let header: [u8; 5] = [0, (last >> 8) as u8, (last & 0xff) as u8, (coded.len() >> 8) as u8, (coded.len() & 0xff) as u8];
+        for (i, byte) in header.into_iter().chain(coded).enumerate() {
+            self.write_byte(layout::COMMAND + 1 + i, byte);
+        }
+    }
+
+    /// Reply to the EEM info command: trigger count, breakpoint count, last breakpoint hit (0xff if none)
+    fn write_eem_info(&mut self, eem: &Eem) {
+        self.write_byte(layout::COMMAND + 1, eem::TRIGGER_COUNT as u8);
+        self.write_byte(layout::COMMAND + 2, eem::BREAKPOINT_COUNT as u8);
+        self.write_byte(layout::COMMAND + 3, eem.last_hit().map(|b| b as u8).unwrap_or(0xff));
+    }
+
+    /// Reply to the stop reason command: 1 byte reason (0 = hasn't stopped since the last run or
+    /// step command), 1 byte breakpoint index (0xff if none), 2 bytes PC, 4 bytes instructions
+    /// executed since the last run or step command
+    fn write_stop_reason(&mut self, reason: Option<StopReason>, pc: u16, executed: u64) {
+        self.write_byte(layout::COMMAND + 1, reason.map(|r| r.id()).unwrap_or(0));
+        self.write_byte(layout::COMMAND + 2, reason.and_then(|r| r.breakpoint()).map(|b| b as u8).unwrap_or(0xff));
+        self.write_byte(layout::COMMAND + 3, (pc >> 8) as u8);
+        self.write_byte(layout::COMMAND + 4, (pc & 0xff) as u8);
+        for (i, byte) in (executed.min(u32::MAX as u64) as u32).to_be_bytes().into_iter().enumerate() {
+            self.write_byte(layout::COMMAND + 5 + i, byte);
+        }
+    }
+
+    /// Status block, rewritten at every command check so a frontend attaching mid-run can pick up
+    /// the state without asking
+    fn write_status(&mut self, heartbeat: u32, owner: Option<u64>, run_mode: &RunMode,
+                    stop_reason: Option<StopReason>, loaded: &LoadedImage, flags: Flags) {
+        let words: [u32; 3] = [heartbeat, process::id(), owner.map(|pid| pid as u32).unwrap_or(0)];
+        for (i, word) in words.iter().enumerate() {
+            for (j, byte) in word.to_be_bytes().iter().enumerate() {
+                self.write_byte(layout::STATUS + i * 4 + j, *byte);
+            }
+        }
+        self.write_byte(layout::STATUS_RUN_MODE, run_mode.id());
+        self.write_byte(layout::STATUS_STOP_REASON, stop_reason.map(|r| r.id()).unwrap_or(0));
+        self.write_byte(layout::STATUS_ENTRY, (loaded.entry >> 8) as u8);
+        self.write_byte(layout::STATUS_ENTRY + 1, (loaded.entry & 0xff) as u8);
+        for (i, byte) in loaded.generation.to_be_bytes().iter().chain(loaded.checksum.to_be_bytes().iter()).enumerate() {
+            self.write_byte(layout::STATUS_GENERATION + i, *byte);
+        }
+        for (i, set) in flags.bits().into_iter().enumerate() {
+            self.write_byte(layout::STATUS_FLAGS + i, set as u8);
+        }
+        self.write_byte(layout::STATUS_BUSY, 0);
+        let count: u16 = loaded.segments.len().min(u16::MAX as usize) as u16;
+        self.write_byte(layout::SEGMENTS, (count >> 8) as u8);
+        self.write_byte(layout::SEGMENTS + 1, (count & 0xff) as u8);
+        for (i, segment) in loaded.segments.iter().take(layout::SEGMENT_SLOTS).enumerate() {
+            let bytes = [segment.region.start.to_be_bytes(), segment.region.end.to_be_bytes()].concat();
+            for (j, byte) in bytes.iter().chain(segment.checksum.to_be_bytes().iter()).enumerate() {
+                self.write_byte(layout::SEGMENT_ENTRIES + i * layout::SEGMENT_SIZE + j, *byte);
+            }
+        }
+        // truncated on a character boundary, so the C-string stays valid UTF-8
+        let mut name: &str = &loaded.name;
+        while name.len() > layout::STATUS_NAME_SIZE - 1 {
+            let mut end: usize = name.len() - 1;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = &name[..end];
+        }
+        for (i, byte) in name.bytes().chain(std::iter::once(0)).enumerate() {
+            self.write_byte(layout::STATUS_NAME + i, byte);
+        }
+    }
+
+    /// Between status writes while `command` does host I/O: moves the heartbeat and marks the
+    /// emulator busy, the rest of the status block can't change meanwhile
+    fn write_busy(&mut self, heartbeat: &mut u32, command: u8) {
+        *heartbeat = heartbeat.wrapping_add(1);
+        for (i, byte) in heartbeat.to_be_bytes().iter().enumerate() {
+            self.write_byte(layout::STATUS + i, *byte);
+        }
+        self.write_byte(layout::STATUS_BUSY, command);
+    }
+
+    /// Reply to the interrupt vectors command: 1 byte GIE, per vector 0xffe0-0xfffe the 2 byte
+    /// handler and 1 byte flags, then the handlers' symbols as C-Strings in the same order
+    fn write_vector_map(&mut self, entries: &[interrupts::VectorEntry], gie: bool) {
+        const SYMBOL_MAX: usize = 47;
+        self.write_byte(layout::COMMAND + 1, gie as u8);
+        let mut idx: usize = layout::COMMAND + 2;
+        for entry in entries {
+            let flags: u8 = entry.enabled() as u8 | (entry.flagged() as u8) << 1 | (entry.pending() as u8) << 2
+                | (entry.nmi() as u8) << 3 | (!entry.sources.is_empty() as u8) << 4;
+            for byte in entry.handler.to_be_bytes().iter().chain(std::iter::once(&flags)) {
+                self.write_byte(idx, *byte);
+                idx += 1;
+            }
+        }
+        for entry in entries {
+            let symbol: &str = entry.symbol.as_deref().unwrap_or("");
+            let mut end: usize = symbol.len().min(SYMBOL_MAX);
+            while !symbol.is_char_boundary(end) {
+                end -= 1;
+            }
+            for byte in symbol[..end].bytes().chain(std::iter::once(0)) {
+                self.write_byte(idx, byte);
+                idx += 1;
+            }
+        }
+    }
+
+    /// Reply to the peripheral command: 1 byte status (0 ok, 1 no such peripheral), 1 byte peripheral
+    /// count, then the peripheral's registers as text, cut off to fit the command area
+    fn write_peripheral(&mut self, views: &[peripherals::PeripheralView], index: u8) {
+        let view: Option<&peripherals::PeripheralView> = views.get(index as usize);
+        self.write_byte(layout::COMMAND + 1, if view.is_some() {0} else {1});
+        self.write_byte(layout::COMMAND + 2, views.len() as u8);
+        let text: String = view.map(|v| v.to_string()).unwrap_or_default();
+        let mut end: usize = text.len().min(layout::EVENTS - (layout::COMMAND + 3) - 1);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        for (i, byte) in text[..end].bytes().chain(std::iter::once(0)).enumerate() {
+            self.write_byte(layout::COMMAND + 3 + i, byte);
+        }
+    }
+
+    fn write_metrics(&mut self, metrics: &RunMetrics, cycles: u64) {
+        for (i, byte) in metrics.encode(cycles).iter().enumerate() {
+            self.write_byte(layout::METRICS + i, *byte);
+        }
+    }
+
+    /// Reply to the PWM measurement command: 1 byte status (0 = ok, 1 = pin not analyzed or no
+    /// complete period yet), 4 bytes period and 4 bytes high time in cycles
+    fn write_pwm_measurement(&mut self, measurement: Option<pwm::PwmMeasurement>) {
+        let (status, period, high) = match measurement {
+            Some(m) => (0, m.period as u32, m.high as u32),
+            None => (1, 0, 0),
+        };
+        self.write_byte(layout::COMMAND + 1, status);
+        for (i, byte) in period.to_be_bytes().iter().chain(high.to_be_bytes().iter()).enumerate() {
+            self.write_byte(layout::COMMAND + 2 + i, *byte);
+        }
+    }
+
+    /// Reply to the LED strip command: 1 byte status (0 = ok, 1 = no strip on the pin), 4 bytes frames
+    /// latched, 2 bytes LED count, then red, green and blue of as many LEDs as fit the command area
+    fn write_led_strip(&mut self, strip: Option<&LedStrip>) {
+        self.write_byte(layout::COMMAND + 1, if strip.is_some() {0} else {1});
+        let frames: u32 = strip.map_or(0, |s| s.frames() as u32);
+        let leds: &[ws2812::Rgb] = strip.map_or(&[], |s| s.leds());
+        let header: Vec<u8> = [&frames.to_be_bytes()[..], &(leds.len() as u16).to_be_bytes()].concat();
+        let fit: usize = (layout::EVENTS - (layout::COMMAND + 8)) / 3;
+        let colors = leds.iter().take(fit).flat_map(|led| [led.r, led.g, led.b]);
+        for (i, byte) in header.into_iter().chain(colors).enumerate() {
+            self.write_byte(layout::COMMAND + 2 + i, byte);
+        }
+    }
+
+    /// Reply to the frame buffer command: 1 byte status (0 = ok, 1 = no frame buffer), 1 byte format,
+    /// 2 bytes address, width and height, 4 bytes frames presented
+    fn write_frame_buffer(&mut self, framebuffer: &FrameBufferDevice) {
+        let reply: Vec<u8> = match framebuffer.spec() {
+            Some(spec) => [&[0, spec.format.id()][..], &spec.base.to_be_bytes(), &spec.width.to_be_bytes(),
+                           &spec.height.to_be_bytes(), &(framebuffer.frames() as u32).to_be_bytes()].concat(),
+            None => vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        for (i, byte) in reply.into_iter().enumerate() {
+            self.write_byte(layout::COMMAND + 1 + i, byte);
+        }
+    }
+
+    /// Reply to an evaluation: 1 byte status (0 = ok, 1 = invalid expression), then the value
+    fn write_evaluation(&mut self, result: Result<u16, ()>) {
+        match result {
+            Ok(value) => self.write_status_reply(0, value),
+            Err(()) => self.write_status_reply(1, 0),
+        }
+    }
+
+    /// Hand the frontend the memory changes since the diff it last consumed, once it has consumed it
+    fn publish_diff(&mut self, computer: &Computer) {
+        if self.read_byte(layout::DIFF) != 0 {
+            return; // frontend hasn't read the previous diff yet, keep accumulating
+        }
+        let changes = computer.memory.changes_since(&self.published);
+        if changes.is_empty() {
+            return;
+        }
+        let count: u16 = if changes.len() > layout::DIFF_CAPACITY {
+            0xffff // too many, the frontend should re-read the whole mirror
+        } else {
+            for (i, (address, old, new)) in changes.iter().enumerate() {
+                let entry: usize = layout::DIFF_ENTRIES + i * 4;
+                self.write_byte(entry, (address >> 8) as u8);
+                self.write_byte(entry + 1, (address & 0xff) as u8);
+                self.write_byte(entry + 2, *old);
+                self.write_byte(entry + 3, *new);
+            }
+            changes.len() as u16
+        };
+        self.write_byte(layout::DIFF_COUNT, (count >> 8) as u8);
+        self.write_byte(layout::DIFF_COUNT + 1, (count & 0xff) as u8);
+        self.published = computer.memory.clone();
+        self.write_byte(layout::DIFF, 1); // ready, written last
+    }
+
+    /// Append to the event ring, the counter is updated last so a reader never sees a partial event
+    fn push_event(&mut self, event: &WatchEvent) {
+        let slot: usize = layout::EVENT_SLOTS_START + (self.events_written as usize % layout::EVENT_SLOTS) * layout::EVENT_SIZE;
+        let words: [u16; 4] = [event.id, event.old, event.new, event.pc];
+        for (i, word) in words.iter().enumerate() {
+            self.write_byte(slot + i * 2, (word >> 8) as u8);
+            self.write_byte(slot + i * 2 + 1, (word & 0xff) as u8);
+        }
+        self.events_written = self.events_written.wrapping_add(1);
+        for (i, byte) in self.events_written.to_be_bytes().into_iter().enumerate() {
+            self.write_byte(layout::EVENTS + i, byte);
+        }
+    }
+
+    fn acknowledge_command(&mut self) {
+        self.write_byte(layout::COMMAND, 0);
+    }
+
+    /// Place a command as a frontend would, the command byte last
+    fn write_command(&mut self, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate().skip(1) {
+            self.write_byte(layout::COMMAND + i, *byte);
+        }
+        self.write_byte(layout::COMMAND, bytes[0]);
+    }
+}
+
+/// Step, printing the PC history if the emulator panics (an emulator bug) or the firmware faults. Returns why it stopped if the computer faulted or hit a hardware breakpoint
+/// on this step.
+fn step_or_dump(c: &mut Computer) -> Option<StopReason> {
+    let faulted: bool = c.fault.is_some();
+    if let Err(panic) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| c.step())) {
+        c.pc_history.dump("panic", &c.memory);
+        std::panic::resume_unwind(panic);
+    }
+    if let (false, Some(fault)) = (faulted, c.fault) {
+        eprintln!("Halted: {}", fault);
+        c.pc_history.dump("the fault", &c.memory);
+        return Some(StopReason::Fault);
+    }
+    if c.diagnostics.take_break() {
+        return Some(StopReason::Diagnostic);
+    }
+    if c.eem.take_hit() {
+        let breakpoint: usize = c.eem.last_hit().expect("just hit");
+        if c.eem.last_hit_on_fetch() {
+            return Some(StopReason::Breakpoint(breakpoint));
+        }
+        return Some(StopReason::Watchpoint(breakpoint));
+    }
+    return None;
+}
+
+/// Create the shared memory mapping and its link, taking over a link left by a crashed emulator
+fn open_shmem(log: &RunLog, shmem_path: &std::path::Path) -> Option<Shmem> {
+    let shmem_flink: &str = shmem_path.to_str().expect("Failed to get shared memory path");
+    // Create or open the shared memory mapping
+    let mut created = ShmemConf::new().size(layout::SIZE).flink(shmem_flink).create();
+    if let Err(ShmemError::LinkExists) = created {
+        // the link outlives a crashed emulator (on Windows the mapping itself is gone then), only
+        // a link that still opens belongs to a running instance
+        if ShmemConf::new().flink(shmem_flink).open().is_err() && std::fs::remove_file(shmem_path).is_ok() {
+            log.warn("shmem", format!("Removed stale shared memory link {}", shmem_flink), &[("link", json!(shmem_flink))]);
+            created = ShmemConf::new().size(layout::SIZE).flink(shmem_flink).create();
+        }
+    }
+    let mut shmem: Shmem = match created {
+        Ok(m) => m,
+        Err(ShmemError::LinkExists) => {
+            log.error("shmem", "Shared memory already exists, make sure msp430_rust is not already running".to_string(),
+                      &[("link", json!(shmem_flink))]);
+            return None;
+        },
+        Err(e) => {
+            log.error("shmem", format!("Unable to create or open shmem flink {} : {}", shmem_flink, e),
+                      &[("link", json!(shmem_flink)), ("error", json!(e.to_string()))]);
+            return None;
+        }
+    };
+    shmem.set_owner(true);
+
+    log.debug("shmem", format!("Shared memory id {} shared at {}", shmem.get_os_id(), shmem_flink),
+              &[("os_id", json!(shmem.get_os_id())), ("link", json!(shmem_flink))]);
+    return Some(shmem);
+}
+
+fn actually_run(running: Arc<AtomicBool>, args: RunForkedArgs, mut replay: Option<Recording>) {
+    let log: RunLog = RunLog::new(args.log_format, args.log_level);
+    let mut parent_pid: Option<u64> = args.parent_pid;
+    // when the parent was first seen gone, while waiting for another frontend to attach
+    let mut orphaned_since: Option<Instant> = None;
+    let shmem_path = match &args.instance {
+        Some(name) => std::env::temp_dir().join(format!("msp430_shmem_id_{}", name)),
+        None => std::env::temp_dir().join("msp430_shmem_id"),
+    };
+    // a replay has no frontend, replies go to memory nobody reads
+    let mut detached: Vec<u8> = Vec::new();
+    let shmem: Option<Shmem> = match replay {
+        Some(_) => None,
+        None => match open_shmem(&log, &shmem_path) {
+            Some(shmem) => Some(shmem),
+            None => return,
+        },
+    };
+    let raw_ptr: *mut u8 = match &shmem {
+        Some(shmem) => shmem.as_ptr(),
+        None => {
+            detached.resize(layout::SIZE, 0);
+            detached.as_mut_ptr()
+        },
+    };
+
+    let mut mem = SharedMemorySystem::new(raw_ptr);
+
+    let mut run_mode: RunMode = RunMode::Stopped;
+    let mut stop_reason: Option<StopReason> = None;
+    // instructions executed when the last run or step command was handled
+    let mut retired_at_start: u64 = 0;
+    let mut loaded: LoadedImage = LoadedImage::default();
+    let mut heartbeat: u32 = 0;
+
+    let c: &mut Computer = &mut Computer::new();
+    let seed: u64 = args.seed.or(args.deterministic.then_some(0)).unwrap_or_else(|| std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0));
+    c.devices.rng.set_seed(seed);
+    log.info("seed", format!("RNG seed: {}", seed), &[("seed", json!(seed))]);
+    let mut recorder: Option<CommandRecorder> = None;
+    if let Some(path) = &args.record {
+        match CommandRecorder::create(path, &args.recording_args(seed)) {
+            Ok(r) => recorder = Some(r),
+            Err(e) => {
+                log.error("record", format!("Failed to create recording '{}': {}", path, e),
+                          &[("path", json!(path)), ("error", json!(e.to_string()))]);
+                return;
+            }
+        }
+    }
+    c.clock.set_source(args.time_source);
+    if args.dco_tolerance < 0.0 || args.dco_tolerance >= 50.0 {
+        eprintln!("DCO tolerance must be between 0 and 50 percent, got {}", args.dco_tolerance);
+        process::exit(2);
+    }
+    c.clock.set_dco_tolerance((args.dco_tolerance * 10_000.0).round() as u32, seed);
+    if args.dco_tolerance != 0.0 {
+        log.info("dco", format!("MCLK drifts within {}% of nominal, starting at {} Hz", args.dco_tolerance, c.clock.mclk_hz()),
+                 &[("tolerance_percent", json!(args.dco_tolerance)), ("mclk_hz", json!(c.clock.mclk_hz()))]);
+    }
+    c.pc_history.set_capacity(args.pc_history);
+    c.pc_history.set_canonical(args.canonical);
+    if let Some(path) = &args.stimulus {
+        match Stimulus::load(path) {
+            Ok(stimulus) => c.stimulus = Some(stimulus),
+            Err(e) => {
+                log.error("stimulus", format!("Failed to load stimulus '{}': {}", path, e),
+                          &[("path", json!(path)), ("error", json!(e))]);
+                return;
+            }
+        }
+    }
+    let mut script: Option<Script> = match (&args.script, &replay) {
+        (Some(path), None) => match Script::load(path) {
+            Ok(script) => Some(script),
+            Err(e) => {
+                log.error("script", format!("Failed to load script {}", e), &[("path", json!(path)), ("error", json!(e))]);
+                return;
+            }
+        },
+        _ => None,
+    };
+    for input in &args.analog_inputs {
+        match Waveform::load(input, c.clock.mclk_hz()) {
+            Ok(waveform) => c.analog.push(waveform),
+            Err(e) => {
+                log.error("analog", format!("Failed to load waveform '{}': {}", input.path, e),
+                          &[("path", json!(input.path)), ("error", json!(e))]);
+                return;
+            }
+        }
+    }
+    c.runaway = args.runaway_cycles.map(RunawayDetector::new);
+    c.shadow_stack = args.shadow_stack.then(ShadowStack::new);
+    c.profiler = (args.function_profile || args.callgrind.is_some()).then(Profiler::new);
+    if !args.pwm_pins.is_empty() {
+        c.pwm = Some(PwmAnalyzer::new(&args.pwm_pins));
+    }
+    if !args.sensors.is_empty() {
+        match Sensors::new(&args.sensors) {
+            Ok(sensors) => c.sensors = Some(sensors),
+            Err(e) => {
+                log.error("sensor", format!("Invalid sensors: {}", e), &[("error", json!(e))]);
+                return;
+            },
+        }
+    }
+    c.led_strips = args.led_strips.iter().map(|spec| LedStrip::new(*spec)).collect();
+    if !args.encoders.is_empty() || !args.button_matrices.is_empty() {
+        match Widgets::new(&args.encoders, &args.button_matrices) {
+            Ok(widgets) => c.widgets = Some(widgets),
+            Err(e) => {
+                log.error("input", format!("Invalid input widgets: {}", e), &[("error", json!(e))]);
+                return;
+            },
+        }
+    }
+    let mut frames_file: Option<std::io::BufWriter<File>> = None;
+    if let Some(path) = &args.ws2812_frames {
+        let mut file = match File::create(path) {
+            Ok(file) => std::io::BufWriter::new(file),
+            Err(e) => {
+                log.error("ws2812", format!("Failed to create '{}': {}", path, e), &[("path", json!(path)), ("error", json!(e.to_string()))]);
+                return;
+            },
+        };
+        let _ = std::io::Write::write_all(&mut file, b"cycle,pin,leds\n");
+        c.led_strips.iter_mut().for_each(|strip| strip.export = true);
+        frames_file = Some(file);
+    }
+    for pad in &args.touch_pads {
+        c.devices.touch.set_pad(*pad, &c.devices.gpio, &c.clock);
+    }
+    if let Some(dir) = &args.file_dir {
+        if !std::path::Path::new(dir).is_dir() {
+            log.error("files", format!("File directory '{}' does not exist", dir), &[("path", json!(dir))]);
+            return;
+        }
+        c.devices.files.set_root(dir);
+    }
+    if let Some(spec) = &args.sd_card {
+        match SdCard::open(spec) {
+            Ok(card) => {
+                log.info("sd-card", format!("SD card {} ({} blocks), chip select {}", spec.path, card.blocks(), spec.chip_select),
+                         &[("path", json!(spec.path)), ("blocks", json!(card.blocks()))]);
+                c.devices.spi.sd_card = Some(card);
+            },
+            Err(e) => {
+                log.error("sd-card", format!("Failed to open SD card image '{}': {}", spec.path, e),
+                          &[("path", json!(spec.path)), ("error", json!(e))]);
+                return;
+            },
+        }
+    }
+    if let Some(spec) = args.radio {
+        c.devices.spi.radio = Some(Radio::new(spec));
+        c.devices.spi.update_pins(&mut c.devices.gpio);
+    }
+    args.builder().apply(c);
+    if let Some(path) = &args.journal {
+        match WriteJournal::create(path) {
+            Ok(journal) => c.journal = Some(journal), // flushed when dropped, even on panic
+            Err(e) => {
+                log.error("journal", format!("Failed to create write journal '{}': {}", path, e),
+                          &[("path", json!(path)), ("error", json!(e.to_string()))]);
+                return;
+            }
+        }
+    }
+    if let Some(path) = &args.branch_trace {
+        match BranchTrace::create(path) {
+            Ok(trace) => c.branch_trace = Some(trace),
+            Err(e) => {
+                log.error("branch_trace", format!("Failed to create branch trace '{}': {}", path, e),
+                          &[("path", json!(path)), ("error", json!(e.to_string()))]);
+                return;
+            }
+        }
+    }
+    c.attach_hooks();
+    if let Some(path) = &args.uart_capture {
+        match UartCapture::create(path) {
+            Ok(capture) => c.uart_capture = Some(capture),
+            Err(e) => {
+                log.error("uart_capture", format!("Failed to create UART capture '{}': {}", path, e),
+                          &[("path", json!(path)), ("error", json!(e.to_string()))]);
+                return;
+            }
+        }
+    }
+    if let Some(spec) = args.framebuffer {
+        c.devices.framebuffer.set_spec(spec);
+    }
+    if let Some(dir) = &args.framebuffer_images {
+        match FrameExport::create(dir) {
+            Ok(export) => c.frame_export = Some(export),
+            Err(e) => {
+                log.error("framebuffer", format!("Failed to create image directory '{}': {}", dir, e),
+                          &[("path", json!(dir)), ("error", json!(e.to_string()))]);
+                return;
+            }
+        }
+    }
+
+    let uart_link = if let Some(address) = &args.uart_listen {
+        log.info("uart", format!("Waiting for UART peer on {}", address), &[("address", json!(address))]);
+        Some(TcpUartLink::listen(address))
+    } else {
+        args.uart_connect.as_ref().map(|address| TcpUartLink::connect(address))
+    };
+    let mut uart_link: Option<TcpUartLink> = match uart_link {
+        Some(Ok(link)) => Some(link),
+        Some(Err(e)) => {
+            log.error("uart", format!("Failed to set up UART link: {}", e), &[("error", json!(e.to_string()))]);
+            return;
+        },
+        None => None,
+    };
+    let threaded: bool = args.peripheral_quantum.is_some();
+    let mut midi_link: Option<Hosted<MidiLink>> = None;
+    let mut midi_uart: bool = false;
+    if !args.midi_sources.is_empty() || !args.osc_mappings.is_empty() {
+        let error: Option<&str> = if args.midi_out.is_none() && args.osc_out.is_none() {
+            Some("MIDI sources need --midi-out or --osc-out")
+        } else if args.midi_sources.contains(&MidiSource::Uart) && uart_link.is_some() {
+            Some("the UART can't be a MIDI source while it is linked to another instance")
+        } else {
+            None
+        };
+        if let Some(e) = error {
+            log.error("midi", format!("Invalid MIDI bridge: {}", e), &[("error", json!(e))]);
+            return;
+        }
+        let link = MidiLink::open(&args.midi_sources, &args.osc_mappings, args.midi_out.as_deref(), args.osc_out.as_deref())
+            .and_then(|link| {
+                c.write_tap = link.tap();
+                midi_uart = link.takes_uart();
+                Hosted::new("midi", link, threaded)
+            });
+        match link {
+            Ok(link) => midi_link = Some(link),
+            Err(e) => {
+                log.error("midi", format!("Failed to set up the MIDI/OSC bridge: {}", e), &[("error", json!(e.to_string()))]);
+                return;
+            },
+        }
+    }
+    let mut stdin_link: Option<StdinLink> = if args.stdin {Some(StdinLink::start())} else {None};
+    let gpio_link = if let Some(address) = &args.gpio_listen {
+        log.info("gpio", format!("Waiting for GPIO peer on {}", address), &[("address", json!(address))]);
+        Some(TcpGpioLink::listen(address, args.gpio_wires.clone()))
+    } else {
+        args.gpio_connect.as_ref().map(|address| TcpGpioLink::connect(address, args.gpio_wires.clone()))
+    };
+    let mut gpio_link: Option<TcpGpioLink> = match gpio_link {
+        Some(Ok(link)) => Some(link),
+        Some(Err(e)) => {
+            log.error("gpio", format!("Failed to set up GPIO link: {}", e), &[("error", json!(e.to_string()))]);
+            return;
+        },
+        None => None,
+    };
+    let radio_link = args.radio_broker.as_ref()
+        .map(|address| UdpRadioLink::connect(address).and_then(|link| Hosted::new("radio", link, threaded)));
+    let mut radio_link: Option<Hosted<UdpRadioLink>> = match radio_link {
+        Some(Ok(link)) => Some(link),
+        Some(Err(e)) => {
+            log.error("radio", format!("Failed to reach the radio broker: {}", e), &[("error", json!(e.to_string()))]);
+            return;
+        },
+        None => None,
+    };
+    let mut watches: WatchList = WatchList::new();
+    let mut notifier: Notifier = Notifier::new(c);
+    // symbols of the loaded program, for expressions
+    let mut symbols: Vec<image::Symbol> = Vec::new();
+    if !args.images.is_empty() {
+        match args.images.iter().map(|spec| spec.read()).collect::<Result<Vec<ProgramImage>, String>>() {
+            Ok(images) => {
+                let image: ProgramImage = ProgramImage::merge(images);
+                c.reset();
+                image.load(c);
+                loaded.load(&image, &args.images.iter().map(|spec| spec.to_string()).collect::<Vec<String>>().join(" "), c.pc.get_word());
+                if args.read_only_image {
+                    c.protect_image(&loaded.regions());
+                }
+                symbols = image.symbols;
+                c.heap = HeapTracker::from_symbols(&symbols);
+                log.info("load", format!("Loaded {} images, starting at {:#06x}", args.images.len(), c.pc.get_word()),
+                         &load_fields(&loaded, c.pc.get_word()));
+            },
+            Err(e) => {
+                log.error("load", format!("Failed to load {}", e), &[("error", json!(e))]);
+                return;
+            },
+        }
+    }
+    let mut logged_mode: u8 = run_mode.id();
+    let mut metrics: RunMetrics = RunMetrics::new();
+    let poll: Duration = Duration::from_millis(args.poll_interval.max(1));
+    let mut batch: BatchSizer = if args.deterministic {BatchSizer::fixed(scheduler::FIXED_BATCH)} else {BatchSizer::new(poll)};
+    // listing processes is slow, the owner is looked for at most this often
+    const OWNER_CHECK_EVERY: Duration = Duration::from_secs(1);
+    let mut owner_checked: Option<Instant> = None;
+
+    while running.load(Ordering::SeqCst) { // ensure that shared memory is properly
+                                           // dropped before exit
+        if replay.as_ref().is_some_and(|r| r.finished(metrics.instructions(), c.clock.cycles(), matches!(run_mode, RunMode::Stopped))) {
+            break;
+        }
+        // threaded peripherals meet the CPU at multiples of the quantum, the others at every poll
+        let quantum_end: Option<u64> = args.peripheral_quantum.map(|q| (c.clock.cycles() / q + 1) * q);
+        if !matches!(run_mode, RunMode::Stopped) {
+            let mut limit: u64 = batch.size();
+            if let Some(at) = replay.as_ref().and_then(|r| r.next_poll()) {
+                // poll exactly where the recorded run handled its next command
+                limit = limit.min(at.saturating_sub(metrics.instructions())).max(1);
+            }
+            let started: Instant = Instant::now();
+            let executed: u64 = run_batch(c, &mut run_mode, &mut stop_reason, limit, quantum_end.unwrap_or(u64::MAX),
+                                          &mut watches, &mut mem, &mut metrics);
+            batch.update(executed, started.elapsed());
+            if let (RunMode::Stopped, Some(reason)) = (&run_mode, stop_reason) {
+                if let Some(event) = notifier.stopped(reason, c) {
+                    mem.push_event(&event);
+                }
+            }
+        }
+        for event in notifier.poll(c) {
+            mem.push_event(&event);
+        }
+        for diagnostic in c.diagnostics.take() {
+            log.warn("diagnostic", diagnostic.to_string(), &[("category", json!(diagnostic.category.name())),
+                     ("pc", json!(diagnostic.pc)), ("value", json!(diagnostic.value))]);
+            if let Some(event) = notifier.diagnostic(&diagnostic) {
+                mem.push_event(&event);
+            }
+        }
+        if let Some(sensors) = &mut c.sensors {
+            for transaction in sensors.take() {
+                log.info("sensor", transaction.to_string(), &[("pin", json!(transaction.pin.to_string())),
+                         ("protocol", json!(transaction.protocol)), ("transaction", json!(transaction.text))]);
+            }
+        }
+        if let Some(file) = &mut frames_file {
+            let mut frames: Vec<ws2812::Frame> = c.led_strips.iter_mut().flat_map(LedStrip::take_frames).collect();
+            frames.sort_by_key(|frame| frame.cycle);
+            let written = frames.iter().try_for_each(|frame| std::io::Write::write_all(file, format!("{}\n", frame.csv_line()).as_bytes()))
+                .and_then(|()| std::io::Write::flush(file));
+            if let Err(e) = written {
+                log.error("ws2812", format!("Frame export disabled: {}", e), &[("error", json!(e.to_string()))]);
+                c.led_strips.iter_mut().for_each(|strip| strip.export = false);
+                frames_file = None;
+            }
+        }
+        log_mode_change(&log, &mut logged_mode, &run_mode, stop_reason, c.pc.get_word());
+        if !watches.is_empty() {
+            // also while stopped, so changes made by commands are reported
+            for event in watches.check(c) {
+                mem.push_event(&event);
+            }
+        }
+        if let Some(link) = &mut uart_link {
+            if let Err(e) = link.pump(&mut c.devices.uart) {
+                log.error("uart", format!("UART link closed: {}", e), &[("error", json!(e.to_string()))]);
+                uart_link = None;
+            }
+        }
+        let at_boundary: bool = quantum_end.is_none_or(|end| c.clock.cycles() >= end);
+        if let Some(link) = midi_link.as_mut().filter(|_| at_boundary) {
+            let uart: Vec<u8> = if midi_uart {c.devices.uart.take_tx()} else {Vec::new()};
+            let writes: Vec<midi_link::TappedWrite> = c.write_tap.as_mut().map(WriteTap::take).unwrap_or_default();
+            let sent = link.exchange(c.clock.cycles(), (uart, writes));
+            if !log_midi(&log, sent.or_else(|| (!link.alive()).then(worker_stopped))) {
+                c.write_tap = None;
+                midi_link = None;
+            }
+        }
+        if let Some(link) = &mut stdin_link {
+            if !link.pump(&mut c.devices.console) {
+                stdin_link = None;
+            }
+        }
+        if let Some(link) = &mut gpio_link {
+            if let Err(e) = link.pump(&mut c.devices.gpio) {
+                log.error("gpio", format!("GPIO link closed: {}", e), &[("error", json!(e.to_string()))]);
+                gpio_link = None;
+            }
+        }
+        if let Some(link) = radio_link.as_mut().filter(|_| at_boundary) {
+            let outgoing: Vec<Packet> = c.devices.spi.radio.as_mut().map(Radio::take_outgoing).unwrap_or_default();
+            match link.exchange(c.clock.cycles(), outgoing).or_else(|| (!link.alive()).then(worker_stopped)) {
+                Some(Ok(received)) => received.iter().for_each(|packet| c.devices.radio_receive(packet)),
+                Some(Err(e)) => {
+                    log.error("radio", format!("Radio link closed: {}", e), &[("error", json!(e.to_string()))]);
+                    radio_link = None;
+                },
+                None => {},
+            }
+        }
+        // keep the files current so they can be inspected while the emulator is paused
+        flush_traces(&log, c);
+        let cmd = &match &mut replay {
+            Some(recording) => match recording.next(metrics.instructions(), c.clock.cycles(), matches!(run_mode, RunMode::Stopped)) {
+                ReplayStep::Command(bytes) => {
+                    mem.write_command(&bytes);
+                    mem.get_command()
+                },
+                ReplayStep::Wait => ShmemCommands::None,
+                ReplayStep::Diverged(why) => {
+                    log.error("replay", format!("Replay diverged: {}", why), &[("reason", json!(why))]);
+                    break;
+                },
+            },
+            None => match script.as_mut().filter(|_| matches!(run_mode, RunMode::Stopped)).and_then(|s| s.next(c, &symbols)) {
+                Some(Ok(command)) => command,
+                Some(Err(e)) => {
+                    log.error("script", format!("Script stopped: {}", e), &[("error", json!(e))]);
+                    script = None;
+                    ShmemCommands::None
+                },
+                None => mem.get_command(),
+            },
+        };
+        if script.as_ref().is_some_and(|s| s.is_done()) {
+            log.info("script", "Script done".to_string(), &[]);
+            script = None;
+        }
+        if let (Some(r), false) = (&mut recorder, matches!(cmd, ShmemCommands::None | ShmemCommands::Unknown)) {
+            let command = RecordedCommand { instructions: metrics.instructions(), cycles: c.clock.cycles(), bytes: cmd.encode() };
+            if let Err(e) = r.record(&command, &format!("{:?}", cmd)) {
+                log.error("record", format!("Recording disabled: {}", e), &[("error", json!(e.to_string()))]);
+                recorder = None;
+            }
+        }
+
+        let parent_alive: bool = match parent_pid {
+            // a replay keeps the recorded Attach commands but has no frontend
+            Some(pid) if replay.is_none() => {
+                if owner_checked.is_some_and(|at| at.elapsed() < OWNER_CHECK_EVERY) {
+                    true
+                } else {
+                    owner_checked = Some(Instant::now());
+                    System::new_all().process(Pid::from(pid as usize)).is_some()
+                }
+            },
+            _ => true,
+        };
+        if parent_alive {
+            orphaned_since = None;
+        } else {
+            let since: Instant = *orphaned_since.get_or_insert_with(|| {
+                if args.orphan_grace > 0 {
+                    log.warn("owner", format!("Parent process death detected, waiting {} s for a frontend to attach", args.orphan_grace),
+                             &[("pid", json!(parent_pid)), ("grace", json!(args.orphan_grace))]);
+                }
+                Instant::now()
+            });
+            if since.elapsed().as_secs() >= args.orphan_grace {
+                log.info("exit", "Parent process death detected".to_string(), &[("pid", json!(parent_pid))]);
+                finish_recording(&log, &mut recorder, metrics.instructions(), c);
+                running.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+        // a client may have replaced or removed the link while reconnecting
+        if let Some(shmem) = &shmem {
+            restore_flink(&log, &shmem_path, shmem.get_os_id());
+        }
+        heartbeat = heartbeat.wrapping_add(1);
+        // the machine stands still while a command is handled, however long the host takes
+        c.clock.freeze();
+        let busy: u8 = cmd.encode()[0];
+
+        match cmd {
+            ShmemCommands::None => {
+                let started: Instant = Instant::now();
+                mem.write(c);
+                metrics.synced(started.elapsed());
+                metrics.sample(Instant::now());
+                mem.write_status(heartbeat, parent_pid, &run_mode, stop_reason, &loaded, c.flags());
+                mem.write_metrics(&metrics, c.clock.cycles());
+                c.clock.thaw();
+                if matches!(run_mode, RunMode::Stopped) && replay.is_none() {
+                    // nothing to do until the frontend sends a command
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                continue;
+            },
+            ShmemCommands::Stop => {
+                if !matches!(run_mode, RunMode::Stopped) {
+                    stop_reason = Some(StopReason::HaltRequest);
+                    if let Some(event) = notifier.stopped(StopReason::HaltRequest, c) {
+                        mem.push_event(&event);
+                    }
+                }
+                run_mode = RunMode::Stopped;
+            },
+            ShmemCommands::Run => {
+                run_mode = RunMode::Running;
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            ShmemCommands::Step(n) => {
+                run_mode = RunMode::Stepping(StepRun::count(*n));
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            &ShmemCommands::StepWith(run) => {
+                run_mode = RunMode::Stepping(run);
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            ShmemCommands::StopReason => mem.write_stop_reason(stop_reason, c.pc.get_word(), c.retired - retired_at_start),
+            ShmemCommands::StepOver => {
+                run_mode = match StepGoal::step_over(c) {
+                    Some(goal) => RunMode::Until(goal),
+                    None => RunMode::Stepping(StepRun::count(1)),
+                };
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            ShmemCommands::StepOut => {
+                run_mode = RunMode::Until(StepGoal::step_out(c));
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            ShmemCommands::FinishInterrupt => {
+                run_mode = RunMode::Until(StepGoal::finish_interrupt(c));
+                stop_reason = None;
+                retired_at_start = c.retired;
+            },
+            ShmemCommands::LoadFile(path) => {
+                c.reset();
+                run_mode = RunMode::Stopped;
+                stop_reason = None;
+                // load program into computer
+                symbols.clear();
+                c.heap = None;
+                let read = host_io(poll, || std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| ProgramImage::parse(&data)),
+                                   || mem.write_busy(&mut heartbeat, busy));
+                match read {
+                    Ok(image) => {
+                        image.load(c);
+                        loaded.load(&image, path, c.pc.get_word());
+                        if args.read_only_image {
+                            c.protect_image(&loaded.regions());
+                        }
+                        let shown: String = image.metadata.as_ref().map(|m| m.to_string()).unwrap_or_else(|| path.clone());
+                        log.info("load", format!("Loaded {}, starting at {:#06x}", shown, c.pc.get_word()),
+                                 &load_fields(&loaded, c.pc.get_word()));
+                        symbols = image.symbols;
+                        c.heap = HeapTracker::from_symbols(&symbols);
+                    },
+                    Err(e) => log.error("load", format!("Failed to load '{}': {}", path, e),
+                                        &[("path", json!(path)), ("error", json!(e))]),
+                }
+            },
+            ShmemCommands::OverlayFile(path) => {
+                // on top of the current state, the machine keeps running if it was
+                let read = host_io(poll, || std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| ProgramImage::parse(&data)),
+                                   || mem.write_busy(&mut heartbeat, busy));
+                match read {
+                    Ok(image) => {
+                        image.overlay(c);
+                        loaded.overlay(&image, path);
+                        if args.read_only_image {
+                            c.protect_image(&loaded.regions());
+                        }
+                        log.info("load", format!("Overlaid {}", path), &load_fields(&loaded, c.pc.get_word()));
+                        symbols.retain(|s| image.symbol(&s.name).is_none());
+                        symbols.extend(image.symbols);
+                        if c.heap.is_none() {
+                            c.heap = HeapTracker::from_symbols(&symbols);
+                        }
+                    },
+                    Err(e) => log.error("load", format!("Failed to overlay '{}': {}", path, e),
+                                        &[("path", json!(path)), ("error", json!(e))]),
+                }
+            },
+            &ShmemCommands::SetMem(addr, val) => {
+                c.memory.set_word(addr, val);
+            },
+            &ShmemCommands::Interrupt(vector) => {
+                c.interrupt(vector);
+            },
+            &ShmemCommands::Seed(seed) => {
+                c.devices.rng.set_seed(seed);
+            },
+            ShmemCommands::PcHistory => {
+                mem.write_pc_history(&c.pc_history);
+            },
+            ShmemCommands::AddWatch(expression) => {
+                match expr::parse(expression, &symbols) {
+                    Ok(expr) => mem.write_reply_word(watches.add(expr, c)),
+                    Err(e) => {
+                        log.warn("watch", format!("Invalid watch expression '{}': {}", expression, e),
+                                 &[("expression", json!(expression)), ("error", json!(e))]);
+                        mem.write_reply_word(0xffff);
+                    },
+                }
+            },
+            &ShmemCommands::RemoveWatch(id) => {
+                watches.remove(id);
+            },
+            ShmemCommands::Evaluate(expression) => {
+                match expr::parse(expression, &symbols) {
+                    Ok(expr) => mem.write_evaluation(Ok(expr.eval(c))),
+                    Err(e) => {
+                        log.warn("evaluate", format!("Invalid expression '{}': {}", expression, e),
+                                 &[("expression", json!(expression)), ("error", json!(e))]);
+                        mem.write_evaluation(Err(()));
+                    },
+                }
+            },
+            ShmemCommands::MailboxSend(channel, value) => {
+                if (*channel as usize) < mailbox::CHANNELS {
+                    c.devices.mailbox.host_write(*channel as usize, *value);
+                }
+            },
+            ShmemCommands::MailboxReceive(channel) => {
+                let received: Option<u16> = if (*channel as usize) < mailbox::CHANNELS {
+                    c.devices.mailbox.host_read(*channel as usize)
+                } else {
+                    None
+                };
+                match received {
+                    Some(value) => mem.write_status_reply(0, value),
+                    None => mem.write_status_reply(1, 0),
+                }
+            },
+            ShmemCommands::SetTrigger(index, trigger) => {
+                let ok: bool = c.eem.set_trigger(*index as usize, *trigger);
+                mem.write_status_reply(if ok {0} else {1}, 0);
+            },
+            &ShmemCommands::SetBreakpoint(index, triggers) => {
+                let ok: bool = c.eem.set_breakpoint(index as usize, triggers);
+                mem.write_status_reply(if ok {0} else {1}, 0);
+            },
+            ShmemCommands::EemInfo => mem.write_eem_info(&c.eem),
+            ShmemCommands::Dump(format, region, path) => {
+                let written: bool = match format {
+                    Some(format) => write_dump(&log, dump::dump(&c.memory, *region, *format, &symbols), path, poll,
+                                               || mem.write_busy(&mut heartbeat, busy)),
+                    None => false,
+                };
+                mem.write_status_reply(if written {0} else {1}, 0);
+            },
+            ShmemCommands::SaveSnapshot(path) => {
+                let saved: bool = save_snapshot(&log, c, path, poll, || mem.write_busy(&mut heartbeat, busy));
+                mem.write_status_reply(if saved {0} else {1}, 0);
+            },
+            ShmemCommands::LoadSnapshot(path) => {
+                run_mode = RunMode::Stopped;
+                stop_reason = None;
+                let restored = host_io(poll, || std::fs::read(path), || mem.write_busy(&mut heartbeat, busy))
+                    .map_err(|e| e.to_string()).and_then(|file| c.restore_snapshot(&file));
+                match &restored {
+                    Ok(report) => log.info("snapshot", format!("Restored snapshot {}, pc {:#06x}", path, c.pc.get_word()),
+                                           &[("path", json!(path)), ("defaulted", json!(report.defaulted)),
+                                             ("preserved", json!(report.preserved))]),
+                    Err(e) => log.error("snapshot", format!("Failed to restore snapshot '{}': {}", path, e),
+                                        &[("path", json!(path)), ("error", json!(e))]),
+                }
+                mem.write_status_reply(if restored.is_ok() {0} else {1}, 0);
+            },
+            &ShmemCommands::PwmMeasurement(pin) => {
+                mem.write_pwm_measurement(c.pwm.as_ref().and_then(|pwm| pwm.measurement(pin)));
+            },
+            &ShmemCommands::LedStrip(pin) => {
+                mem.write_led_strip(c.led_strips.iter().find(|s| s.spec.pin == pin));
+            },
+            ShmemCommands::FrameBuffer => {
+                mem.write_frame_buffer(&c.devices.framebuffer);
+            },
+            &ShmemCommands::Encoder(index, detents, switch) => {
+                match c.widgets.as_mut().and_then(|w| w.encoders.get_mut(index as usize)) {
+                    Some(encoder) => {
+                        encoder.turn(detents as i64);
+                        if let Some(pressed) = switch {
+                            encoder.press(pressed);
+                        }
+                        log.debug("input", format!("Encoder {} turned {} detents{}", index, detents,
+                                                   match switch {Some(true) => ", pressed", Some(false) => ", released", None => ""}),
+                                  &[("encoder", json!(index)), ("detents", json!(detents)), ("pressed", json!(switch))]);
+                        mem.write_status_reply(0, encoder.position as u16);
+                    },
+                    None => mem.write_status_reply(1, 0),
+                }
+            },
+            &ShmemCommands::Key(index, row, column, pressed) => {
+                let matrix = c.widgets.as_mut().and_then(|w| w.matrices.get_mut(index as usize));
+                if matrix.is_some_and(|m| m.press(row as usize, column as usize, pressed)) {
+                    log.debug("input", format!("Key {},{} of button matrix {} {}", row, column, index,
+                                               if pressed {"pressed"} else {"released"}),
+                              &[("matrix", json!(index)), ("row", json!(row)), ("column", json!(column)), ("pressed", json!(pressed))]);
+                    mem.write_status_reply(0, 0);
+                } else {
+                    mem.write_status_reply(1, 0);
+                }
+            },
+            &ShmemCommands::Supply(target_mv, cycles) => {
+                log.info("supply", format!("Supply {} mV -> {} mV over {} cycles", c.devices.pmm.supply_mv(), target_mv, cycles),
+                         &[("from_mv", json!(c.devices.pmm.supply_mv())), ("to_mv", json!(target_mv)), ("cycles", json!(cycles))]);
+                c.devices.pmm.set_supply(target_mv, cycles as u64, c.clock.cycles());
+            },
+            ShmemCommands::InterruptVectors => {
+                mem.write_vector_map(&interrupts::vector_map(c, &symbols), c.sr.get_status(StatusFlags::GIE));
+            },
+            &ShmemCommands::OscillatorFault(crystal, failed) => {
+                log.info("oscillator", format!("{} {}", crystal.name(), if failed {"failed"} else {"repaired"}),
+                         &[("crystal", json!(crystal.name())), ("failed", json!(failed))]);
+                c.devices.cs.set_failed(crystal, failed);
+            },
+            &ShmemCommands::Reset(kind) => {
+                log.info("reset", format!("{:?} reset", kind), &[("kind", json!(format!("{:?}", kind).to_lowercase()))]);
+                c.reset_as(kind);
+            },
+            &ShmemCommands::PowerCycle(cycles) => {
+                log.info("power", format!("Power off for {} cycles", cycles), &[("cycles", json!(cycles))]);
+                c.power_cycle(cycles as u64);
+            },
+            &ShmemCommands::Notify(mask) => {
+                log.debug("notify", format!("Notifications {:#06x}", mask), &[("mask", json!(mask))]);
+                notifier.subscribe(mask);
+            },
+            &ShmemCommands::ReadMemory(region) => mem.write_memory_block(&c.memory, region),
+            ShmemCommands::LoadBinary(address, data) => {
+                match rle::decode(data) {
+                    Some(bytes) if !bytes.is_empty() && *address as usize + bytes.len() <= 0x10000 => {
+                        log.debug("load", format!("Loaded {} bytes at {:#06x} ({} coded)", bytes.len(), address, data.len()),
+                                  &[("address", json!(address)), ("bytes", json!(bytes.len())), ("coded", json!(data.len()))]);
+                        for (i, byte) in bytes.iter().enumerate() {
+                            c.memory.set_byte(address + i as u16, *byte);
+                        }
+                        mem.write_status_reply(0, address + (bytes.len() - 1) as u16);
+                    },
+                    _ => mem.write_status_reply(1, 0),
+                }
+            },
+            &ShmemCommands::TouchPad(pad) => {
+                log.debug("touch", format!("Touch pad {} pF", pad),
+                          &[("pin", json!(pad.pin.to_string())), ("femtofarads", json!(pad.femtofarads))]);
+                c.devices.touch.set_pad(pad, &c.devices.gpio, &c.clock);
+            },
+            &ShmemCommands::Peripheral(index) => {
+                mem.write_peripheral(&peripherals::describe(c), index);
+            },
+            ShmemCommands::Shutdown(snapshot) => {
+                if !matches!(run_mode, RunMode::Stopped) {
+                    stop_reason = Some(StopReason::HaltRequest);
+                }
+                run_mode = RunMode::Stopped;
+                let saved: bool = snapshot.as_ref().is_none_or(|path| save_snapshot(&log, c, path, poll, || mem.write_busy(&mut heartbeat, busy)));
+                mem.write_status_reply(if saved {0} else {1}, 0);
+                log.info("exit", "Shutdown requested".to_string(), &[("snapshot", json!(snapshot))]);
+                running.store(false, Ordering::SeqCst);
+            },
+            ShmemCommands::Attach(pid) => {
+                parent_pid = if *pid == 0 {None} else {Some(*pid as u64)};
+                orphaned_since = None;
+                owner_checked = None;
+                log.info("owner", format!("Frontend {} attached", pid), &[("pid", json!(pid))]);
+            },
+            ShmemCommands::Unknown => {},
+        };
+        
+        c.clock.thaw();
+        mem.acknowledge_command();
+        metrics.command();
+        let started: Instant = Instant::now();
+        mem.write(c);
+        metrics.synced(started.elapsed());
+        metrics.sample(Instant::now());
+        mem.write_status(heartbeat, parent_pid, &run_mode, stop_reason, &loaded, c.flags());
+        mem.write_metrics(&metrics, c.clock.cycles());
+        if log.enabled(LogLevel::Debug) {
+            log.debug("command", format!("Handled command: {:?}", cmd), &[("command", json!(format!("{:?}", cmd)))]);
+        }
+        log_mode_change(&log, &mut logged_mode, &run_mode, stop_reason, c.pc.get_word());
+    }
+    log.info("exit", format!("Executed {} instructions, {} cycles", metrics.instructions(), c.clock.cycles()),
+             &[("instructions", json!(metrics.instructions())), ("cycles", json!(c.clock.cycles()))]);
+    flush_traces(&log, c);
+    if let Some(link) = midi_link {
+        // what the bridge still had in flight
+        log_midi(&log, link.finish());
+    }
+    finish_recording(&log, &mut recorder, metrics.instructions(), c);
+    if let Some(recording) = &replay {
+        let end = RecordedEnd { instructions: metrics.instructions(), cycles: c.clock.cycles(), pc: c.pc.get_word() };
+        let matches: bool = recording.end.is_none_or(|recorded| recorded == end);
+        log.info("replay", format!("Replayed {} commands, ended at pc {:#06x}{}", recording.replayed(), end.pc,
+                                   if matches {""} else {", the recorded run ended elsewhere"}),
+                 &[("commands", json!(recording.replayed())), ("pc", json!(end.pc)), ("matches", json!(matches))]);
+    }
+    if let Some(pwm) = &c.pwm {
+        print!("{}", pwm.report(c.clock.mclk_hz()));
+    }
+    for strip in &c.led_strips {
+        print!("{}", strip.report());
+    }
+    if let Some(spec) = c.devices.framebuffer.spec() {
+        println!("Frame buffer {}: {} frames presented", spec, c.devices.framebuffer.frames());
+    }
+    if let Some(heap) = &c.heap {
+        print!("{}", heap.report());
+    }
+    if let Some(profiler) = c.profiler.as_ref().filter(|_| args.function_profile) {
+        print!("{}", profiler.report(&symbols, c.clock.cycles()));
+    }
+    if let (Some(profiler), Some(path)) = (&c.profiler, &args.callgrind) {
+        if let Err(e) = std::fs::write(path, profiler.callgrind(&symbols, c.clock.cycles())) {
+            log.error("profile", format!("Failed to write callgrind profile to '{}': {}", path, e),
+                      &[("path", json!(path)), ("error", json!(e.to_string()))]);
+        }
+    }
+    for spec in &args.dumps {
+        write_dump(&log, dump::dump(&c.memory, spec.region, spec.format, &symbols), &spec.path, poll, || {});
+    }
+    if let Some(shmem) = shmem {
+        // removes the link and the mapping, a frontend still attached keeps its view
+        log.debug("shmem", "Released shared memory".to_string(), &[("os_id", json!(shmem.get_os_id()))]);
+        drop(shmem);
+    }
+}
+
+/// Run up to `limit` instructions in the current mode, until it ends. Returns how many ran.
+/// Also ends once the clock reaches `until_cycle`, a peripheral quantum boundary, or after `limit`
+/// steps that executed nothing (idle with CPUOFF), so a sleeping CPU still lets the frontend in.
+#[allow(clippy::too_many_arguments)]
+fn run_batch(c: &mut Computer, run_mode: &mut RunMode, stop_reason: &mut Option<StopReason>, limit: u64, until_cycle: u64,
+             watches: &mut WatchList, mem: &mut SharedMemorySystem, metrics: &mut RunMetrics) -> u64 {
+    let mut executed: u64 = 0;
+    let mut stalled: u64 = 0;
+    while executed < limit && stalled < limit && c.clock.cycles() < until_cycle {
+        let retired: u64 = c.retired;
+        let idles: bool = match run_mode {
+            RunMode::Running | RunMode::Until(_) => true,
+            RunMode::Stepping(run) => !run.cpu_off,
+            RunMode::Stopped | RunMode::Idle(_) => false,
+        };
+        let awake: bool = !c.flag(StatusFlags::CPUOFF);
+        if idles && !awake && c.fault.is_none() {
+            // nothing executes until an interrupt clears CPUOFF, a step count waits with it
+            let resume: RunMode = std::mem::replace(run_mode, RunMode::Stopped);
+            *run_mode = RunMode::Idle(Box::new(resume));
+        } else if awake && matches!(run_mode, RunMode::Idle(_)) {
+            if let RunMode::Idle(resume) = std::mem::replace(run_mode, RunMode::Stopped) {
+                *run_mode = *resume;
+            }
+        }
+        match run_mode {
+            RunMode::Stopped => break,
+            RunMode::Running => {
+                if let Some(reason) = step_or_dump(c) {
+                    *run_mode = RunMode::Stopped;
+                    *stop_reason = Some(reason);
+                }
+            },
+            RunMode::Stepping(run) => {
+                let run: StepRun = *run;
+                let reason: Option<StopReason> = if c.fault.is_some() {
+                    Some(StopReason::Fault) // nothing would execute
+                } else if run.cpu_off && c.flag(StatusFlags::CPUOFF) {
+                    Some(StopReason::CpuOff)
+                } else {
+                    c.eem.set_suspended(!run.breakpoints);
+                    c.interrupts_masked = run.mask_interrupts;
+                    let reason: Option<StopReason> = step_or_dump(c);
+                    c.eem.set_suspended(false);
+                    c.interrupts_masked = false;
+                    reason.or((run.remaining <= 1).then_some(StopReason::Step))
+                };
+                match reason {
+                    Some(reason) => {
+                        *run_mode = RunMode::Stopped;
+                        *stop_reason = Some(reason);
+                    },
+                    None => *run_mode = RunMode::Stepping(StepRun { remaining: run.remaining - 1, ..run }),
+                }
+            },
+            RunMode::Until(goal) => {
+                let goal: StepGoal = *goal;
+                let reason: Option<StopReason> = step_or_dump(c).or(goal.reached(c).then_some(StopReason::Step));
+                if reason.is_some() {
+                    *run_mode = RunMode::Stopped;
+                    *stop_reason = reason;
+                }
+            },
+            RunMode::Idle(_) => {
+                // time passes and interrupts are taken, the woken mode picks up with the handler
+                if let Some(reason) = step_or_dump(c) {
+                    *run_mode = RunMode::Stopped;
+                    *stop_reason = Some(reason);
+                }
+            },
+        }
+        if c.retired > retired {
+            executed += 1;
+            metrics.instruction();
+        } else {
+            stalled += 1;
+        }
+        if !watches.is_empty() {
+            // events carry the PC after the step that changed the value
+            for event in watches.check(c) {
+                mem.push_event(&event);
+            }
+        }
+    }
+    return executed;
+}
+
+fn finish_recording(log: &RunLog, recorder: &mut Option<CommandRecorder>, instructions: u64, c: &Computer) {
+    if let Some(r) = recorder {
+        if let Err(e) = r.finish(RecordedEnd { instructions, cycles: c.clock.cycles(), pc: c.pc.get_word() }) {
+            log.error("record", format!("Failed to finish recording: {}", e), &[("error", json!(e.to_string()))]);
+        }
+    }
+    *recorder = None;
+}
+
+fn load_fields(loaded: &LoadedImage, pc: u16) -> [(&'static str, serde_json::Value); 6] {
+    let segments: Vec<serde_json::Value> = loaded.segments.iter()
+        .map(|s| json!({"start": s.region.start, "end": s.region.end, "checksum": format!("{:08x}", s.checksum)}))
+        .collect();
+    return [("name", json!(loaded.name)), ("generation", json!(loaded.generation)),
+            ("checksum", json!(format!("{:08x}", loaded.checksum))), ("entry", json!(loaded.entry)),
+            ("segments", json!(segments)), ("pc", json!(pc))];
+}
+
+/// Stepping is left and entered for every step a frontend makes, so those changes are only logged
+/// at debug level
+fn log_mode_change(log: &RunLog, logged: &mut u8, run_mode: &RunMode, stop_reason: Option<StopReason>, pc: u16) {
+    let previous: u8 = *logged;
+    if run_mode.id() == previous {
+        return;
+    }
+    *logged = run_mode.id();
+    let message: String = match (run_mode, stop_reason) {
+        (RunMode::Stopped, Some(reason)) => match reason.breakpoint() {
+            Some(index) => format!("Stopped at {} {}, pc {:#06x}", reason.name(), index, pc),
+            None => format!("Stopped ({}), pc {:#06x}", reason.name(), pc),
+        },
+        _ => format!("{}, pc {:#06x}", run_mode.name(), pc),
+    };
+    let fields = [("mode", json!(run_mode.name())), ("reason", json!(stop_reason.map(|r| r.name()))),
+                  ("breakpoint", json!(stop_reason.and_then(|r| r.breakpoint()))), ("pc", json!(pc))];
+    // firmware in low-power mode goes idle at every interrupt
+    let frequent: [u8; 2] = [RunMode::Stepping(StepRun::count(0)).id(), RunMode::Idle(Box::new(RunMode::Stopped)).id()];
+    if frequent.contains(&previous) || frequent.contains(logged) {
+        log.debug("mode", message, &fields);
+    } else {
+        log.info("mode", message, &fields);
+    }
+}
+
+/// Point the link back at our mapping if it was removed or now names another one
+fn restore_flink(log: &RunLog, path: &std::path::Path, os_id: &str) {
+    if std::fs::read_to_string(path).is_ok_and(|id| id == os_id) {
+        return;
+    }
+    match std::fs::write(path, os_id) {
+        Ok(()) => log.warn("shmem", format!("Restored shared memory link {}", path.display()), &[("link", json!(path))]),
+        Err(e) => log.error("shmem", format!("Failed to restore shared memory link {}: {}", path.display(), e),
+                            &[("link", json!(path)), ("error", json!(e.to_string()))]),
+    }
+}
+
+/// Log what the MIDI/OSC bridge sent, false once it failed
+fn log_midi(log: &RunLog, sent: Option<std::io::Result<Vec<midi_link::Sent>>>) -> bool {
+    match sent {
+        Some(Ok(sent)) => for item in sent {
+            log.debug("midi", item.to_string(), &[("message", json!(item.to_string()))]);
+        },
+        Some(Err(e)) => {
+            log.error("midi", format!("MIDI/OSC bridge closed: {}", e), &[("error", json!(e.to_string()))]);
+            return false;
+        },
+        None => {},
+    }
+    return true;
+}
+
+/// What a threaded peripheral answers once its thread is gone (it panicked)
+fn worker_stopped<T>() -> std::io::Result<T> {
+    return Err(std::io::Error::other("worker thread stopped"));
+}
+
+fn save_snapshot(log: &RunLog, c: &Computer, path: &str, interval: Duration, beat: impl FnMut()) -> bool {
+    let snapshot: Vec<u8> = c.save_snapshot();
+    if let Err(e) = host_io(interval, || std::fs::write(path, snapshot), beat) {
+        log.error("snapshot", format!("Failed to save snapshot '{}': {}", path, e),
+                  &[("path", json!(path)), ("error", json!(e.to_string()))]);
+        return false;
+    }
+    log.info("snapshot", format!("Saved snapshot to {}", path), &[("path", json!(path))]);
+    return true;
+}
+
+/// Write the journal, branch trace and UART capture files up to the last instruction, a file that fails is
+/// disabled
+fn flush_traces(log: &RunLog, c: &mut Computer) {
+    if let Some(journal) = &mut c.journal {
+        if let Err(e) = journal.flush() {
+            log.error("journal", format!("Write journal disabled: {}", e), &[("error", json!(e.to_string()))]);
+            c.journal = None;
+        }
+    }
+    if let Some(trace) = &mut c.branch_trace {
+        if let Err(e) = trace.flush() {
+            log.error("branch_trace", format!("Branch trace disabled: {}", e), &[("error", json!(e.to_string()))]);
+            c.branch_trace = None;
+        }
+    }
+    if let Some(capture) = &mut c.uart_capture {
+        if let Err(e) = capture.flush() {
+            log.error("uart_capture", format!("UART capture disabled: {}", e), &[("error", json!(e.to_string()))]);
+            c.uart_capture = None;
+        }
+    }
+}
+
+/// Write `text` from `dump::dump` to `path`
+fn write_dump(log: &RunLog, text: Vec<u8>, path: &str, interval: Duration, beat: impl FnMut()) -> bool {
+    if let Err(e) = host_io(interval, || std::fs::write(path, text), beat) {
+        log.error("dump", format!("Failed to write dump '{}': {}", path, e), &[("path", json!(path)), ("error", json!(e.to_string()))]);
+        return false;
+    }
+    return true;
+}
+
+/// Slow host work for a command (reading or writing a file a frontend named) on another thread,
+/// calling `beat` every `interval` until it's done so the heartbeat doesn't stall meanwhile
+fn host_io<T: Send>(interval: Duration, work: impl FnOnce() -> T + Send, mut beat: impl FnMut()) -> T {
+    return std::thread::scope(|scope| {
+        let worker = scope.spawn(work);
+        while !worker.is_finished() {
+            beat();
+            std::thread::sleep(interval);
+        }
+        return worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    });
+}
+
+fn run_wrapper(args: RunForkedArgs) {
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    }).expect("Error setting Ctrl-C handler");
+
+    actually_run(running, args, None);
+}
+
+/// Run a recorded command stream again without shared memory or a frontend
+/// The recorded run's arguments. Only emulated time can be replayed exactly, so a recording of a
+/// `--time-source host` run is refused like any other invalid argument.
+fn replay_args(recorded: &[String]) -> Result<RunForkedArgs, clap::Error> {
+    let command: clap::Command = RunForkedArgs::command().mut_arg("time_source", |arg| {
+        arg.value_parser(PossibleValuesParser::new(["emulated"]).map(|_| TimeSource::Emulated))
+    });
+    return RunForkedArgs::from_arg_matches(&command.try_get_matches_from(recorded)?);
+}
+
+fn replay_recording(args: ReplayArgs) {
+    let recording: Recording = match Recording::load(&args.recording) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("Failed to load recording {}", e);
+            process::exit(1);
+        }
+    };
+    let mut run_args: RunForkedArgs = match replay_args(&recording.args) {
+        Ok(run_args) => run_args,
+        Err(e) => {
+            eprintln!("Invalid arguments in recording '{}': {}", args.recording, e);
+            process::exit(1);
+        }
+    };
+    if run_args.uart_listen.is_some() || run_args.uart_connect.is_some() || run_args.gpio_listen.is_some()
+        || run_args.gpio_connect.is_some() || run_args.stdin || run_args.radio_broker.is_some() {
+        eprintln!("The recorded run had UART, GPIO, stdin or radio links, their input is not replayed");
+    }
+    run_args.parent_pid = None;
+    run_args.uart_listen = None;
+    run_args.uart_connect = None;
+    run_args.gpio_listen = None;
+    run_args.gpio_connect = None;
+    run_args.stdin = false;
+    run_args.radio_broker = None;
+    // don't overwrite the recorded run's output
+    run_args.journal = None;
+    run_args.branch_trace = None;
+    run_args.uart_capture = None;
+    run_args.framebuffer_images = None;
+    run_args.dumps = args.dumps;
+    run_args.record = None;
+    let running = Arc::new(AtomicBool::new(true));
+    actually_run(running, run_args, Some(recording));
+}
+
+/// Start `run` with the same arguments as a detached background process and print its PID
+fn fork_and_run(args: RunForkedArgs) {
+    let mut command = process::Command::new(env::current_exe().expect("current_exe() failed, cannot fork"));
+    command.args(args.to_args())
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+    match detach::spawn_detached(&mut command) {
+        Ok(child) => println!("{}", child.id()),
+        Err(e) => {
+            eprintln!("Failed to start the emulator process: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Entry point of the command line interface
+pub fn run_cli() {
+    let args: CLI = CLI::parse();
+
+    match args {
+        CLI::Benchmark => run_benchmarks(),
+        CLI::Run(args) => run_wrapper(args),
+        CLI::Replay(args) => replay_recording(args),
+        CLI::RunForked(args) => fork_and_run(args),
+        CLI::Test(args) => run_firmware_tests(args),
+        CLI::JournalCsv(args) => convert_journal(args),
+        CLI::BranchTraceText(args) => convert_branch_trace(args),
+        CLI::Pack(args) => pack_images(args),
+        CLI::Convert(args) => convert_image(args),
+        CLI::Assemble(args) => assemble_object(args),
+        CLI::Link(args) => link_objects(args),
+        CLI::Disassemble(args) => disassemble_image(args),
+        CLI::Vectors(args) => list_vectors(args),
+        CLI::TraceHash(args) => hash_trace(args),
+        CLI::Lockstep(args) => run_lockstep(args),
+        CLI::Fuzz(args) => run_fuzzer(args),
+        CLI::Storm(args) => run_storm(args),
+        CLI::RadioBroker(args) => {
+            if let Err(e) = radio_link::run_broker(&args.address) {
+                eprintln!("Radio broker stopped: {}", e);
+                process::exit(1);
+            }
+        },
+    }
+}
+
+fn run_storm(args: StormArgs) {
+    let image: ProgramImage = match args.images.iter().map(|spec| spec.read()).collect::<Result<Vec<ProgramImage>, String>>() {
+        Ok(images) => ProgramImage::merge(images),
+        Err(e) => {
+            eprintln!("Failed to load {}", e);
+            process::exit(2);
+        }
+    };
+    let c: &mut Computer = &mut Computer::new();
+    c.reset();
+    image.load(c);
+    c.devices.rng.set_seed(args.seed);
+    let vectors: Vec<u16> = if args.vectors.is_empty() {storm::maskable_vectors(c)} else {args.vectors.clone()};
+    if vectors.is_empty() {
+        eprintln!("The image has no interrupt handlers, name vectors with --vector");
+        process::exit(2);
+    }
+    c.storm = Some(InterruptStorm::new(vectors.clone(), args.rate, args.seed));
+    for _ in 0..args.steps {
+        c.step();
+        if c.fault.is_some() {
+            break;
+        }
+    }
+    let storm: &InterruptStorm = c.storm.as_ref().expect("storm is enabled");
+    let names: Vec<String> = vectors.iter().map(|vector| format!("{:#06x}", vector)).collect();
+    println!("Fired {} interrupts at {}, {} delivered, {} returned, {} still running, nested up to {} deep",
+             storm.fired, names.join(" "), storm.delivered, storm.returned, storm.depth(), storm.max_depth);
+    if let Some(fault) = c.fault {
+        println!("Halted: {}", fault);
+    }
+    for violation in &storm.violations {
+        println!("{}", violation);
+    }
+    if !storm.violations.is_empty() || c.fault.is_some() {
+        process::exit(1);
+    }
+}
+
+fn run_fuzzer(args: FuzzArgs) {
+    match fuzz::load_regressions(Path::new(&args.save)) {
+        Ok(regressions) => {
+            let failing: Vec<&String> = regressions.iter().filter(|(_, case)| case.check(&mut HashSet::new()).is_err())
+                .map(|(name, _)| name).collect();
+            for name in &failing {
+                println!("Still failing: {}", name);
+            }
+            if !failing.is_empty() {
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to load regressions: {}", e);
+            process::exit(2);
+        },
+    }
+    let report: FuzzReport = fuzz::fuzz(args.seed, args.cases, args.length);
+    println!("{} cases, {} instruction/flag combinations covered", report.cases, report.coverage);
+    if let Some((case, mismatch)) = report.failure {
+        println!("Emulator and flag model disagree after instruction {}:", mismatch.index + 1);
+        for instruction in &case.instructions {
+            println!("  {}", instruction);
+        }
+        println!("start    {}", case.state);
+        println!("expected {}", mismatch.expected);
+        println!("actual   {}", mismatch.actual);
+        let note: String = format!("found by `fuzz --seed {}`", args.seed);
+        match fuzz::save_regression(Path::new(&args.save), &case, &note) {
+            Ok(path) => println!("Saved as {}", path),
+            Err(e) => eprintln!("Failed to save the regression in '{}': {}", args.save, e),
+        }
+        process::exit(1);
+    }
+}
+
+fn run_lockstep(args: LockstepArgs) {
+    let image: ProgramImage = match args.images.iter().map(|spec| spec.read()).collect::<Result<Vec<ProgramImage>, String>>() {
+        Ok(images) => ProgramImage::merge(images),
+        Err(e) => {
+            eprintln!("Failed to load {}", e);
+            process::exit(2);
+        }
+    };
+    println!("a: {}", args.a);
+    println!("b: {}", args.b);
+    let mut lockstep: Lockstep = Lockstep::new(args.a.build(&image), args.b.build(&image));
+    match lockstep.run(args.steps) {
+        Outcome::Agreed(steps) => println!("Agreed for {} instructions", steps),
+        Outcome::Halted(steps, fault) => println!("Agreed for {} instructions, both halted: {}", steps, fault),
+        Outcome::Diverged(divergence) => {
+            println!("{}", divergence);
+            process::exit(1);
+        },
+    }
+}
+
+fn hash_trace(args: TraceHashArgs) {
+    let image: ProgramImage = match args.images.iter().map(|spec| spec.read()).collect::<Result<Vec<ProgramImage>, String>>() {
+        Ok(images) => ProgramImage::merge(images),
+        Err(e) => {
+            eprintln!("Failed to load {}", e);
+            process::exit(2);
+        }
+    };
+    let expected: Option<u64> = match &args.expect {
+        Some(text) => match u64::from_str_radix(text.trim_start_matches("0x"), 16) {
+            Ok(digest) => Some(digest),
+            Err(_) => {
+                eprintln!("Invalid digest '{}', expected hex", text);
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+    let c: &mut Computer = &mut Computer::new();
+    c.reset();
+    image.load(c);
+    c.devices.rng.set_seed(args.seed);
+    c.trace_hash = Some(TraceHasher::new(args.every));
+    c.attach_hooks();
+    for _ in 0..args.steps {
+        c.step();
+        if c.fault.is_some() {
+            break;
+        }
+    }
+    let digest: u64 = c.trace_digest().expect("hashing is enabled");
+    let instructions: u64 = c.trace_hash.as_ref().map(|h| h.instructions()).unwrap_or(0);
+    match c.fault {
+        Some(fault) => println!("{:016x} ({} instructions, halted: {})", digest, instructions, fault),
+        None => println!("{:016x} ({} instructions)", digest, instructions),
+    }
+    if expected.is_some_and(|expected| expected != digest) {
+        eprintln!("Digest differs from the expected {:016x}", expected.unwrap());
+        process::exit(1);
+    }
+}
+
+fn disassemble_image(args: DisassembleArgs) {
+    let image: ProgramImage = match std::fs::read(&args.image).map_err(|e| e.to_string())
+        .and_then(|data| formats::read(formats::detect(&data), &data)) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to load '{}': {}", args.image, e);
+            process::exit(2);
+        }
+    };
+    let start: u16 = args.start.unwrap_or(0);
+    let end: u16 = args.end.unwrap_or(0xffff);
+    for segment in &image.segments {
+        let first: u32 = (segment.address as u32).max(start as u32);
+        let last: u32 = (segment.address as u32 + segment.data.len() as u32).min(end as u32 + 1);
+        if first >= last {
+            continue;
+        }
+        let data: &[u8] = &segment.data[(first - segment.address as u32) as usize..(last - segment.address as u32) as usize];
+        print!("{}", disasm::listing(first as u16, data, &image.symbols, args.canonical));
+    }
+}
+
+fn list_vectors(args: VectorsArgs) {
+    let image: ProgramImage = match std::fs::read(&args.image).map_err(|e| e.to_string())
+        .and_then(|data| formats::read(formats::detect(&data), &data)) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to load '{}': {}", args.image, e);
+            process::exit(2);
+        }
+    };
+    let c: &mut Computer = &mut Computer::new();
+    image.load(c);
+    print!("{}", interrupts::describe(&interrupts::vector_map(c, &image.symbols)));
+}
+
+fn assemble_object(args: AssembleArgs) {
+    let source: String = match std::fs::read_to_string(&args.source) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to read '{}': {}", args.source, e);
+            process::exit(2);
+        }
+    };
+    match object::Object::assemble(&source) {
+        Ok(object) => if let Err(e) = std::fs::write(&args.output, object.to_json()) {
+            eprintln!("Failed to write '{}': {}", args.output, e);
+            process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Failed to assemble '{}': {}", args.source, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn link_objects(args: LinkArgs) {
+    let mut objects: Vec<(String, object::Object)> = Vec::new();
+    for path in &args.objects {
+        match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| object::Object::from_json(&text)) {
+            Ok(object) => objects.push((path.clone(), object)),
+            Err(e) => {
+                eprintln!("Failed to load '{}': {}", path, e);
+                process::exit(2);
+            }
+        }
+    }
+    let script: linker::LinkerScript = match &args.script {
+        Some(path) => match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| linker::LinkerScript::parse(&text)) {
+            Ok(script) => script,
+            Err(e) => {
+                eprintln!("Failed to load linker script '{}': {}", path, e);
+                process::exit(2);
+            }
+        },
+        None => linker::LinkerScript::default(),
+    };
+    let linked = linker::link(&objects, &script).and_then(|image| formats::write(args.to, &image));
+    match linked {
+        Ok(linked) => if let Err(e) = std::fs::write(&args.output, linked) {
+            eprintln!("Failed to write '{}': {}", args.output, e);
+            process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Failed to link: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn convert_image(args: ConvertArgs) {
+    let data: Vec<u8> = match std::fs::read(&args.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read '{}': {}", args.input, e);
+            process::exit(2);
+        }
+    };
+    let from: formats::Format = args.from.unwrap_or_else(|| formats::detect(&data));
+    let converted = formats::read(from, &data).and_then(|image| formats::write(args.to, &image));
+    match converted {
+        Ok(converted) => if let Err(e) = std::fs::write(&args.output, converted) {
+            eprintln!("Failed to write '{}': {}", args.output, e);
+            process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Failed to convert '{}' ({:?} to {:?}): {}", args.input, from, args.to, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn pack_images(args: PackArgs) {
+    let mut image: ProgramImage = match args.images.iter().map(|spec| spec.read()).collect::<Result<Vec<ProgramImage>, String>>() {
+        Ok(images) => ProgramImage::merge(images),
+        Err(e) => {
+            eprintln!("Failed to load {}", e);
+            process::exit(2);
+        }
+    };
+    if let Some(entry) = &args.entry {
+        match expr::parse(entry, &image.symbols).and_then(|e| e.constant().ok_or("must not use registers or memory".to_string())) {
+            Ok(address) => image.entry = Some(address),
+            Err(e) => {
+                eprintln!("Invalid entry '{}': {}", entry, e);
+                process::exit(2);
+            }
+        }
+    }
+    if let (Some(name), Some(build_id)) = (&args.name, &args.build_id) {
+        let bytes: Result<Vec<u8>, _> = (0..build_id.len()).step_by(2)
+            .map(|i| u8::from_str_radix(build_id.get(i..i + 2).unwrap_or("x"), 16)).collect();
+        match bytes {
+            Ok(build_id) => image.metadata = Some(image::Metadata { name: name.clone(), build_id }),
+            Err(_) => {
+                eprintln!("Invalid build id '{}', expected hex digit pairs", build_id);
+                process::exit(2);
+            }
+        }
+    }
+    let packed = match args.format {
+        PackFormat::Segmented => image.to_segmented(),
+        PackFormat::V2 => image.to_v2(),
+    };
+    let packed: Vec<u8> = match packed {
+        Ok(packed) => packed,
+        Err(e) => {
+            eprintln!("Failed to pack: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = std::fs::write(&args.output, packed) {
+        eprintln!("Failed to write '{}': {}", args.output, e);
+        process::exit(1);
+    }
+}
+
+fn convert_journal(args: JournalCsvArgs) {
+    let entries = match File::open(&args.journal).and_then(journal::read_journal) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read '{}': {}", args.journal, e);
+            process::exit(2);
+        }
+    };
+    let result = match &args.output {
+        Some(path) => File::create(path).and_then(|f| journal::to_csv(&entries, std::io::BufWriter::new(f))),
+        None => journal::to_csv(&entries, std::io::stdout().lock()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to write CSV: {}", e);
+        process::exit(1);
+    }
+}
+
+fn convert_branch_trace(args: BranchTraceTextArgs) {
+    let records = match File::open(&args.trace).and_then(branch_trace::read_trace) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Failed to read '{}': {}", args.trace, e);
+            process::exit(2);
+        }
+    };
+    let result = match &args.output {
+        Some(path) => File::create(path).and_then(|f| branch_trace::to_text(&records, std::io::BufWriter::new(f))),
+        None => branch_trace::to_text(&records, std::io::stdout().lock()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to write text: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run_firmware_tests(args: TestArgs) {
+    let image = match elf::parse_elf(&file_as_byte_vec(&args.elf)) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to load '{}': {}", args.elf, e);
+            process::exit(2);
+        }
+    };
+    let stack_top: u16 = match expr::parse(&args.stack_top, &image.symbols)
+        .and_then(|e| e.constant().ok_or("must not use registers or memory".to_string())) {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("Invalid stack top '{}': {}", args.stack_top, e);
+            process::exit(2);
+        }
+    };
+    let sensors: Option<Sensors> = match Sensors::new(&args.sensors) {
+        Ok(sensors) => (!args.sensors.is_empty()).then_some(sensors),
+        Err(e) => {
+            eprintln!("Invalid sensors: {}", e);
+            process::exit(2);
+        }
+    };
+    let options = test_runner::TestOptions {
+        prefix: args.prefix,
+        max_steps: args.timeout,
+        stack_top,
+        profile: DeviceProfile { watchdog_held: args.hold_watchdog, ..args.profile.device() },
+        emulation: args.emulation,
+        runaway_cycles: args.runaway_cycles,
+        shadow_stack: args.shadow_stack,
+        file_dir: args.file_dir,
+        sensors,
+    };
+    let results = test_runner::run_tests(&image, &options);
+    test_runner::print_results(&results);
+
+    if let Some(path) = args.junit {
+        std::fs::write(&path, test_runner::junit_report(&args.elf, &results)).expect("Failed to write JUnit report");
+    }
+    if results.iter().any(|r| r.outcome != test_runner::TestOutcome::Passed) {
+        process::exit(1);
+    }
+}
+
+fn run_benchmarks() {
+    let rounds = 1_000_000;
+    let steps = 500;
+    let mut time_elapsed: u128 = 0;
+    
+    println!("Running {} rounds of {} steps each...", rounds, steps);
+    let assembled = utils::assemble(r#"
+.define "r5" A
+.define "r6" B
+.define "r15" OUT
+mov #0 [A]
+mov #1 [B]
+mov #0x4400 sp
+
+loop:
+add [A] [B] ; add value of A into B
+mov [B] [OUT] ; copy value of B into OUT
+add [B] [A] ; add value of B into A
+mov [A] [OUT] ; copy value of A into OUT
+jmp loop
+"#);
+    let trimmed = assembled.trim();
+
+    for _ in 0..rounds {
+        let c: &mut Computer = &mut Computer::new();
+        utils::execute(c, trimmed, 0);
+        let start = Instant::now();
+        for _ in 0..steps {
+            c.step();
+        }
+        let elapsed = start.elapsed();
+        time_elapsed += elapsed.as_micros();
+    }
+    let micros_per_cycle: f64 = (time_elapsed as f64) / (rounds as f64) / (steps as f64);
+    let hz = 1000000.0 / micros_per_cycle;
+    let khz = hz / 1000.0;
+    let mhz = khz / 1000.0;
+
+    println!("{} us/cycle ({} Hz, {} KHz, {} MHz)", micros_per_cycle, hz, khz, mhz);
+}
+
+#[cfg(test)]
+mod tests;
+
+pub mod utils;
+
+pub(crate) mod devices;
+pub(crate) mod bus;
+pub(crate) mod clock;
+pub(crate) mod uart_link;
+pub(crate) mod radio_link;
+pub(crate) mod stdin_link;
+pub(crate) mod gpio_link;
+pub(crate) mod journal;
+pub(crate) mod branch_trace;
+pub(crate) mod pc_history;
+pub(crate) mod expr;
+pub(crate) mod watch;
+pub mod image;
+pub mod elf;
+pub(crate) mod formats;
+pub(crate) mod dump;
+pub(crate) mod macros;
+pub(crate) mod decode;
+pub(crate) mod trace_hash;
+pub(crate) mod stepping;
+pub(crate) mod detach;
+pub(crate) mod run_log;
+pub(crate) mod metrics;
+pub(crate) mod replay;
+pub(crate) mod scheduler;
+pub(crate) mod interrupts;
+pub(crate) mod peripherals;
+pub(crate) mod disasm;
+pub(crate) mod object;
+pub(crate) mod linker;
+pub(crate) mod test_runner;
+pub mod profile;
+pub mod builder;
+pub(crate) mod eem;
+pub(crate) mod stimulus;
+pub(crate) mod analog;
+pub(crate) mod pwm;
+pub(crate) mod sensors;
+pub(crate) mod onewire;
+pub(crate) mod dht;
+pub(crate) mod ws2812;
+pub(crate) mod timing;
+pub(crate) mod widgets;
+pub(crate) mod midi_link;
+pub(crate) mod profiler;
+pub(crate) mod heap;
+pub(crate) mod runaway;
+pub mod diagnostics;
+pub(crate) mod shadow_stack;
+pub(crate) mod lockstep;
+pub(crate) mod fuzz;
+pub(crate) mod storm;
+pub(crate) mod shmem_layout;
+pub(crate) mod rle;
+pub(crate) mod notify;
+pub(crate) mod script;
+pub(crate) mod worker;
+pub(crate) mod uart_capture;
+pub(crate) mod framebuffer;
+pub mod snapshot;
+
+/*
+fn main() {
+    println!("Hello, world!");
+
+    print!("What's your name? ");
+    io::stdout().flush().unwrap();
+
+    let name: &mut String = &mut String::new();
+    let result: Result<usize, std::io::Error> = io::stdin().read_line(name);
+    match result {
+        Ok(_) => greet(name),
+        Err(_) => println!("There was an error during input")
+    };
+}
+
+fn greet(name: &String) {
+    println!("Hello, {}!", &name.trim());
+}*/
@@ -0,0 +1,282 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Places relocatable objects (see object.rs) and resolves their symbols. Linker scripts are a
+// small subset of GNU ld's:
+//   ENTRY(main)
+//   SECTIONS {
+//       .text 0x4400 : { *(.text) }
+//       .boot 0xf000 : { boot.o *(.init) }
+//   }
+// Inside an output section, `*(.a .b)` takes objects in those sections, `file.o` takes that
+// file and `file.o(.a)` that file only if its section matches. Objects are laid out in the
+// order they are taken, each object only once.
+
+use crate::image::{ProgramImage, Segment, Symbol};
+use crate::object::{Object, CODE_BASE};
+use crate::utils;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Input {
+    Sections(Vec<String>),
+    File(String, Option<Vec<String>>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Placement {
+    name: String,
+    address: u16,
+    inputs: Vec<Input>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct LinkerScript {
+    entry: Option<String>,
+    placements: Vec<Placement>,
+}
+
+fn strip_comments(text: &str) -> Result<String, String> {
+    let mut out: String = String::new();
+    let mut rest: &str = text;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        let end: usize = rest[start..].find("*/").ok_or("Unterminated comment")?;
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    return Ok(out);
+}
+
+/// Splits into words and the punctuation `{ } ( ) :`
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut current: String = String::new();
+    for c in text.chars() {
+        if c.is_whitespace() || "{}():".contains(c) {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    return tokens;
+}
+
+/// Base name of a path, what scripts refer to files by
+fn file_name(path: &str) -> &str {
+    return path.rsplit(['/', '\\']).next().unwrap_or(path);
+}
+
+impl LinkerScript {
+    /// Every object one after another from the start of main memory
+    pub(crate) fn default() -> LinkerScript {
+        return LinkerScript {
+            entry: None,
+            placements: vec![Placement { name: ".text".to_string(), address: CODE_BASE, inputs: vec![Input::Sections(vec!["*".to_string()])] }],
+        };
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<LinkerScript, String> {
+        let tokens: Vec<String> = tokenize(&strip_comments(text)?);
+        let mut script = LinkerScript { entry: None, placements: Vec::new() };
+        let mut i: usize = 0;
+        let expect = |i: &mut usize, what: &str| -> Result<(), String> {
+            if tokens.get(*i).map(|t| t.as_str()) != Some(what) {
+                return Err(format!("Expected '{}' in linker script, found '{}'", what, tokens.get(*i).map(|t| t.as_str()).unwrap_or("end of file")));
+            }
+            *i += 1;
+            return Ok(());
+        };
+        let next = |i: &mut usize| -> Result<String, String> {
+            let token: String = tokens.get(*i).cloned().ok_or("Unexpected end of linker script")?;
+            *i += 1;
+            return Ok(token);
+        };
+        while i < tokens.len() {
+            match next(&mut i)?.as_str() {
+                "ENTRY" => {
+                    expect(&mut i, "(")?;
+                    script.entry = Some(next(&mut i)?);
+                    expect(&mut i, ")")?;
+                },
+                "SECTIONS" => {
+                    expect(&mut i, "{")?;
+                    loop {
+                        let name: String = next(&mut i)?;
+                        if name == "}" {
+                            break;
+                        }
+                        let address: u16 = utils::parse_u16(&next(&mut i)?)?;
+                        expect(&mut i, ":")?;
+                        expect(&mut i, "{")?;
+                        let mut inputs: Vec<Input> = Vec::new();
+                        loop {
+                            let token: String = next(&mut i)?;
+                            if token == "}" {
+                                break;
+                            }
+                            let sections: Option<Vec<String>> = if tokens.get(i).map(|t| t.as_str()) == Some("(") {
+                                i += 1;
+                                let mut sections: Vec<String> = Vec::new();
+                                loop {
+                                    let section: String = next(&mut i)?;
+                                    if section == ")" {
+                                        break;
+                                    }
+                                    sections.push(section);
+                                }
+                                Some(sections)
+                            } else {
+                                None
+                            };
+                            inputs.push(match (token.as_str(), sections) {
+                                ("*", Some(sections)) => Input::Sections(sections),
+                                ("*", None) => Input::Sections(vec!["*".to_string()]),
+                                (_, sections) => Input::File(token, sections),
+                            });
+                        }
+                        script.placements.push(Placement { name, address, inputs });
+                    }
+                },
+                other => return Err(format!("Unknown linker script command '{}'", other)),
+            }
+        }
+        return Ok(script);
+    }
+}
+
+fn section_matches(sections: &[String], section: &str) -> bool {
+    return sections.iter().any(|s| s == "*" || s == section);
+}
+
+/// Relocated value of a word whose code moved from `CODE_BASE` to `base`
+fn relocate(word: u16, factor: i8, base: u16, symbol_delta: u16) -> u16 {
+    let moved: u16 = base.wrapping_sub(CODE_BASE);
+    let adjusted: u16 = match factor {
+        1 => word.wrapping_add(moved),
+        -1 => word.wrapping_sub(moved),
+        _ => word,
+    };
+    return adjusted.wrapping_add(symbol_delta);
+}
+
+/// Link `objects` (path, object) into one image. Only the first object's reset vector is kept,
+/// unless the script names an ENTRY symbol.
+pub(crate) fn link(objects: &[(String, Object)], script: &LinkerScript) -> Result<ProgramImage, String> {
+    let mut bases: Vec<Option<u16>> = vec![None; objects.len()];
+    for placement in &script.placements {
+        let mut cursor: u32 = placement.address as u32;
+        for input in &placement.inputs {
+            for (index, (path, object)) in objects.iter().enumerate() {
+                let taken: bool = match input {
+                    Input::Sections(sections) => section_matches(sections, &object.section),
+                    Input::File(name, sections) => (name == path || name == file_name(path))
+                        && sections.as_ref().is_none_or(|s| section_matches(s, &object.section)),
+                };
+                if !taken || bases[index].is_some() {
+                    continue;
+                }
+                cursor += cursor & 1;
+                if cursor + object.code.len() as u32 > 0x10000 {
+                    return Err(format!("{} doesn't fit in {} at {:#06x}", path, placement.name, placement.address));
+                }
+                bases[index] = Some(cursor as u16);
+                cursor += object.code.len() as u32;
+            }
+        }
+    }
+    let mut ranges: Vec<(u32, u32, &str)> = Vec::new();
+    for (index, (path, object)) in objects.iter().enumerate() {
+        let base: u16 = bases[index].ok_or(format!("{} ({}) is not placed by the linker script", path, object.section))?;
+        ranges.push((base as u32, base as u32 + object.code.len() as u32, path));
+    }
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        if pair[0].1 > pair[1].0 {
+            return Err(format!("{} and {} overlap", pair[0].2, pair[1].2));
+        }
+    }
+
+    let mut symbols: Vec<Symbol> = Vec::new();
+    for (index, (path, object)) in objects.iter().enumerate() {
+        for (name, offset) in &object.symbols {
+            if symbols.iter().any(|s| &s.name == name) {
+                return Err(format!("Symbol '{}' is defined again in {}", name, path));
+            }
+            symbols.push(Symbol { name: name.clone(), address: bases[index].unwrap().wrapping_add(*offset) });
+        }
+    }
+
+    let mut image: ProgramImage = ProgramImage::new();
+    let mut reset_vector: Option<Segment> = None;
+    for (index, (path, object)) in objects.iter().enumerate() {
+        let base: u16 = bases[index].unwrap();
+        let mut code: Vec<u8> = object.code.clone();
+        let mut absolute: Vec<Segment> = object.absolute.clone();
+        for relocation in &object.relocations {
+            let symbol_delta: u16 = match &relocation.symbol {
+                Some(name) => {
+                    let placeholder: u16 = object.externs.iter().find(|(n, _)| n == name).map(|(_, p)| *p)
+                        .ok_or(format!("{} refers to '{}' without declaring it", path, name))?;
+                    let symbol: &Symbol = symbols.iter().find(|s| &s.name == name)
+                        .ok_or(format!("Undefined symbol '{}' in {}", name, path))?;
+                    symbol.address.wrapping_sub(placeholder)
+                },
+                None => 0,
+            };
+            let data: &mut Vec<u8> = match relocation.segment {
+                None => &mut code,
+                Some(address) => &mut absolute.iter_mut().find(|s| s.address == address)
+                    .ok_or(format!("{} relocates a missing segment at {:#06x}", path, address))?.data,
+            };
+            let offset: usize = relocation.offset as usize;
+            if offset + 1 >= data.len() {
+                return Err(format!("{} has a relocation past the end of its data", path));
+            }
+            let word: u16 = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let value: u16 = relocate(word, relocation.base, base, symbol_delta);
+            data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+        }
+        image.segments.push(Segment { address: base, data: code });
+        for segment in absolute {
+            if segment.address == 0xfffe && segment.data.len() == 2 {
+                if reset_vector.is_none() {
+                    reset_vector = Some(segment);
+                }
+            } else {
+                image.segments.push(segment);
+            }
+        }
+    }
+    match &script.entry {
+        Some(name) => {
+            let symbol: &Symbol = symbols.iter().find(|s| &s.name == name).ok_or(format!("Entry symbol '{}' is not defined", name))?;
+            image.segments.push(Segment { address: 0xfffe, data: symbol.address.to_be_bytes().to_vec() });
+        },
+        None => image.segments.extend(reset_vector),
+    }
+    image.symbols = symbols;
+    return Ok(image);
+}
@@ -0,0 +1,223 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use clap::ValueEnum;
+use crate::{Computer, Fault};
+use crate::disasm;
+use crate::image::ProgramImage;
+use crate::builder::ComputerBuilder;
+use crate::profile::Profile;
+use crate::trace_hash::TraceHasher;
+
+/// Differing memory bytes listed before the rest are only counted
+const MAX_MEMORY_DIFFERENCES: usize = 16;
+
+/// How one side of a `lockstep` run is set up, e.g. `profile=g2553,seed=3,dco=2.5`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct MachineConfig {
+    pub(crate) profile: Profile,
+    /// RNG seed, also drives the DCO drift
+    pub(crate) seed: u64,
+    /// like `run --dco-tolerance`, in percent
+    pub(crate) dco_tolerance: f64,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        return MachineConfig { profile: Profile::Generic, seed: 0, dco_tolerance: 0.0 };
+    }
+}
+
+impl FromStr for MachineConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config: MachineConfig = MachineConfig::default();
+        for setting in s.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=')
+                .ok_or_else(|| format!("Expected KEY=VALUE, got '{}'", setting))?;
+            match key {
+                "profile" => config.profile = Profile::from_str(value, true)?,
+                "seed" => config.seed = value.parse().map_err(|_| format!("Invalid seed '{}'", value))?,
+                "dco" => {
+                    config.dco_tolerance = value.parse().map_err(|_| format!("Invalid DCO tolerance '{}'", value))?;
+                    if !(0.0..50.0).contains(&config.dco_tolerance) {
+                        return Err(format!("DCO tolerance must be between 0 and 50 percent, got {}", value));
+                    }
+                },
+                _ => return Err(format!("Unknown setting '{}', expected profile, seed or dco", key)),
+            }
+        }
+        return Ok(config);
+    }
+}
+
+impl fmt::Display for MachineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "profile={},seed={},dco={}",
+                      self.profile.to_possible_value().expect("No skipped variants").get_name(), self.seed, self.dco_tolerance);
+    }
+}
+
+impl MachineConfig {
+    /// A computer set up this way with `image` loaded, ready for `Lockstep`
+    pub(crate) fn build(&self, image: &ProgramImage) -> Computer {
+        let mut c: Computer = ComputerBuilder::new().profile(self.profile.device()).build();
+        image.load(&mut c);
+        c.devices.rng.set_seed(self.seed);
+        c.clock.set_dco_tolerance((self.dco_tolerance * 10_000.0).round() as u32, self.seed);
+        return c;
+    }
+}
+
+/// One way the two machines disagree
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Difference {
+    Register { register: u8, a: u16, b: u16 },
+    Cycles { a: u64, b: u64 },
+    Memory { address: u16, a: u8, b: u8 },
+    /// the sequence of memory and device writes differs, though memory ended up the same
+    Writes,
+    Fault { a: Option<Fault>, b: Option<Fault> },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fault = |fault: &Option<Fault>| fault.map(|fault| fault.to_string()).unwrap_or_else(|| "running".to_string());
+        return match self {
+            Difference::Register { register, a, b } => write!(f, "r{:<2}      a={:#06x} b={:#06x}", register, a, b),
+            Difference::Cycles { a, b } => write!(f, "cycles   a={} b={}", a, b),
+            Difference::Memory { address, a, b } => write!(f, "{:#06x}   a={:#04x} b={:#04x}", address, a, b),
+            Difference::Writes => write!(f, "writes   different values were written to device registers"),
+            Difference::Fault { a, b } => write!(f, "fault    a={} b={}", fault(a), fault(b)),
+        };
+    }
+}
+
+/// The first instruction after which the two machines no longer agree
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Divergence {
+    /// instructions both ran, counting the one that diverged
+    pub(crate) step: u64,
+    /// address of the instruction that diverged, on machine a
+    pub(crate) pc: u16,
+    pub(crate) instruction: String,
+    pub(crate) differences: Vec<Difference>,
+    /// more differing memory bytes than were listed
+    pub(crate) more_memory: usize,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Diverged at instruction {} ({:#06x}: {})", self.step, self.pc, self.instruction)?;
+        for difference in &self.differences {
+            write!(f, "\n  {}", difference)?;
+        }
+        if self.more_memory > 0 {
+            write!(f, "\n  ... and {} more bytes of memory", self.more_memory)?;
+        }
+        return Ok(());
+    }
+}
+
+/// How a lockstep run ended
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Outcome {
+    /// both ran every instruction asked for and still agree
+    Agreed(u64),
+    /// both halted on the same fault after this many instructions
+    Halted(u64, Fault),
+    Diverged(Divergence),
+}
+
+/// Two computers with different configurations stepped together on the same firmware (`lockstep`),
+/// stopping at the first instruction after which their registers, cycle count, memory or writes
+/// differ. Writes are compared through a running trace hash so memory only has to be compared
+/// when something already differs.
+pub(crate) struct Lockstep {
+    pub(crate) a: Computer,
+    pub(crate) b: Computer,
+    steps: u64,
+}
+
+impl Lockstep {
+    pub(crate) fn new(mut a: Computer, mut b: Computer) -> Lockstep {
+        a.trace_hash = Some(TraceHasher::new(u64::MAX));
+        b.trace_hash = Some(TraceHasher::new(u64::MAX));
+        a.attach_hooks();
+        b.attach_hooks();
+        return Lockstep { a, b, steps: 0 };
+    }
+
+    /// Run one instruction on both, `Some` if they disagree afterwards
+    pub(crate) fn step(&mut self) -> Option<Divergence> {
+        self.a.step();
+        self.b.step();
+        self.steps += 1;
+        let mut differences: Vec<Difference> = vec![];
+        let (registers_a, registers_b) = (self.a.register_words(), self.b.register_words());
+        for register in 0..16u8 {
+            let (a, b) = (registers_a[register as usize], registers_b[register as usize]);
+            if a != b {
+                differences.push(Difference::Register { register, a, b });
+            }
+        }
+        if self.a.clock.cycles() != self.b.clock.cycles() {
+            differences.push(Difference::Cycles { a: self.a.clock.cycles(), b: self.b.clock.cycles() });
+        }
+        if self.a.fault != self.b.fault {
+            differences.push(Difference::Fault { a: self.a.fault, b: self.b.fault });
+        }
+        let writes = |c: &Computer| c.trace_hash.as_ref().map(|h| h.running());
+        if differences.is_empty() && writes(&self.a) == writes(&self.b) {
+            return None;
+        }
+        let mut listed: usize = 0;
+        let mut more_memory: usize = 0;
+        for address in 0..=0xffffu16 {
+            let (a, b) = (self.a.memory.get_byte(address), self.b.memory.get_byte(address));
+            if a != b && listed < MAX_MEMORY_DIFFERENCES {
+                differences.push(Difference::Memory { address, a, b });
+                listed += 1;
+            } else if a != b {
+                more_memory += 1;
+            }
+        }
+        if differences.is_empty() {
+            differences.push(Difference::Writes);
+        }
+        let pc: u16 = self.a.instruction_pc;
+        let instruction: String = disasm::disassemble(pc, &|address| self.a.memory.get_word(address), true).text;
+        return Some(Divergence { step: self.steps, pc, instruction, differences, more_memory });
+    }
+
+    /// Step up to `steps` instructions
+    pub(crate) fn run(&mut self, steps: u64) -> Outcome {
+        for _ in 0..steps {
+            if let Some(divergence) = self.step() {
+                return Outcome::Diverged(divergence);
+            }
+            if let Some(fault) = self.a.fault {
+                return Outcome::Halted(self.steps, fault);
+            }
+        }
+        return Outcome::Agreed(self.steps);
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Macro preprocessor run before source is handed to the assembler, which only knows `.define`:
+//
+//   .macro delay count, reg=r15
+//       mov #\count \reg
+//   wait\@:
+//       dec \reg
+//       jnz wait\@
+//   .endm
+//       delay 100
+//
+// `\name` is replaced by the argument, `\@` by a number unique to each expansion so labels in the
+// body don't clash. Arguments are separated by commas, parameters may have defaults.
+
+/// How deep macros may call other macros, catches accidental recursion
+const MAX_DEPTH: usize = 32;
+
+struct Macro {
+    name: String,
+    params: Vec<(String, Option<String>)>,
+    body: Vec<String>,
+}
+
+fn without_comment(line: &str) -> &str {
+    return line.split(';').next().unwrap_or("").trim();
+}
+
+fn split_args(text: &str) -> Vec<String> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    return text.split(',').map(|a| a.trim().to_string()).collect();
+}
+
+/// Expand every macro in `code`, lines outside macros pass through untouched
+pub(crate) fn expand(code: &str) -> Result<String, String> {
+    let mut macros: Vec<Macro> = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut defining: Option<Macro> = None;
+    for (i, line) in code.lines().enumerate() {
+        let content: &str = without_comment(line);
+        if let Some(header) = content.strip_prefix(".macro") {
+            if defining.is_some() {
+                return Err(format!("Line {}: .macro inside a macro definition", i + 1));
+            }
+            let header: &str = header.trim();
+            let (name, params) = header.split_once(char::is_whitespace).unwrap_or((header, ""));
+            if name.is_empty() {
+                return Err(format!("Line {}: .macro needs a name", i + 1));
+            }
+            let params = split_args(params).into_iter().map(|p| match p.split_once('=') {
+                Some((name, default)) => (name.trim().to_string(), Some(default.trim().to_string())),
+                None => (p, None),
+            }).collect();
+            defining = Some(Macro { name: name.to_string(), params, body: Vec::new() });
+        } else if content == ".endm" {
+            macros.push(defining.take().ok_or(format!("Line {}: .endm without .macro", i + 1))?);
+        } else if let Some(m) = &mut defining {
+            m.body.push(line.to_string());
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if let Some(m) = defining {
+        return Err(format!("Macro '{}' is missing .endm", m.name));
+    }
+    let mut out: Vec<String> = Vec::new();
+    let mut counter: usize = 0;
+    for line in &lines {
+        expand_line(line, &macros, &mut counter, 0, &mut out)?;
+    }
+    return Ok(out.join("\n") + "\n");
+}
+
+fn expand_line(line: &str, macros: &[Macro], counter: &mut usize, depth: usize, out: &mut Vec<String>) -> Result<(), String> {
+    let content: &str = without_comment(line);
+    let (name, args) = content.split_once(char::is_whitespace).unwrap_or((content, ""));
+    let m: &Macro = match macros.iter().find(|m| m.name == name) {
+        Some(m) => m,
+        None => {
+            out.push(line.to_string());
+            return Ok(());
+        },
+    };
+    if depth >= MAX_DEPTH {
+        return Err(format!("Macro '{}' nested more than {} deep", name, MAX_DEPTH));
+    }
+    let args: Vec<String> = split_args(args);
+    if args.len() > m.params.len() {
+        return Err(format!("Macro '{}' takes {} arguments, got {}", name, m.params.len(), args.len()));
+    }
+    let mut values: Vec<(&str, String)> = Vec::new();
+    for (i, (param, default)) in m.params.iter().enumerate() {
+        let value: String = match args.get(i).filter(|a| !a.is_empty()).or(default.as_ref()) {
+            Some(value) => value.clone(),
+            None => return Err(format!("Macro '{}' is missing argument '{}'", name, param)),
+        };
+        values.push((param, value));
+    }
+    // longest names first so `\count` isn't replaced inside `\counter`
+    values.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+    *counter += 1;
+    let unique: String = format!("_m{}", counter);
+    for body_line in &m.body {
+        let mut expanded: String = body_line.replace("\\@", &unique);
+        for (param, value) in &values {
+            expanded = expanded.replace(&format!("\\{}", param), value);
+        }
+        expand_line(&expanded, macros, counter, depth + 1, out)?;
+    }
+    return Ok(());
+}
@@ -0,0 +1,99 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::{Duration, Instant};
+
+/// Size of the encoded block, see shared_memory_protocol.txt
+pub(crate) const METRICS_SIZE: usize = 40;
+
+/// Throughput counters of a run, published over shared memory so instances can be monitored
+/// without a frontend attached
+pub(crate) struct RunMetrics {
+    instructions: u64,
+    commands: u32,
+    /// instructions per second over the last complete window
+    rate: u32,
+    window_start: Instant,
+    window_instructions: u64,
+    last_sync: Duration,
+    total_sync: Duration,
+    syncs: u64,
+}
+
+impl RunMetrics {
+    /// The rate is measured over windows of at least this long
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new() -> RunMetrics {
+        return RunMetrics {
+            instructions: 0,
+            commands: 0,
+            rate: 0,
+            window_start: Instant::now(),
+            window_instructions: 0,
+            last_sync: Duration::ZERO,
+            total_sync: Duration::ZERO,
+            syncs: 0,
+        };
+    }
+
+    pub(crate) fn instruction(&mut self) {
+        self.instructions += 1;
+    }
+
+    pub(crate) fn command(&mut self) {
+        self.commands = self.commands.wrapping_add(1);
+    }
+
+    /// Time taken to mirror memory and registers into shared memory
+    pub(crate) fn synced(&mut self, took: Duration) {
+        self.last_sync = took;
+        self.total_sync += took;
+        self.syncs += 1;
+    }
+
+    /// Closes the rate window once it is long enough, a stopped emulator reads 0
+    pub(crate) fn sample(&mut self, now: Instant) {
+        let elapsed: Duration = now.duration_since(self.window_start);
+        if elapsed < RunMetrics::WINDOW {
+            return;
+        }
+        let executed: u64 = self.instructions - self.window_instructions;
+        self.rate = (executed as f64 / elapsed.as_secs_f64()).min(u32::MAX as f64) as u32;
+        self.window_start = now;
+        self.window_instructions = self.instructions;
+    }
+
+    pub(crate) fn instructions(&self) -> u64 {
+        return self.instructions;
+    }
+
+    /// Big-endian like the rest of the shared memory
+    pub(crate) fn encode(&self, cycles: u64) -> [u8; METRICS_SIZE] {
+        let average_sync: u128 = if self.syncs == 0 {0} else {self.total_sync.as_micros() / self.syncs as u128};
+        let mut bytes: [u8; METRICS_SIZE] = [0; METRICS_SIZE];
+        bytes[0..8].copy_from_slice(&self.instructions.to_be_bytes());
+        bytes[8..16].copy_from_slice(&cycles.to_be_bytes());
+        bytes[16..20].copy_from_slice(&self.commands.to_be_bytes());
+        bytes[20..24].copy_from_slice(&self.rate.to_be_bytes());
+        bytes[24..28].copy_from_slice(&(self.last_sync.as_micros().min(u32::MAX as u128) as u32).to_be_bytes());
+        bytes[28..32].copy_from_slice(&(average_sync.min(u32::MAX as u128) as u32).to_be_bytes());
+        bytes[32..40].copy_from_slice(&self.syncs.to_be_bytes());
+        return bytes;
+    }
+}
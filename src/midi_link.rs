@@ -0,0 +1,387 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::net::UdpSocket;
+use std::str::FromStr;
+use crate::utils::parse_u16;
+use crate::worker::QuantumModel;
+
+/// Where firmware writes a MIDI byte stream (`run --midi-source`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum MidiSource {
+    /// everything the UART transmits
+    Uart,
+    /// bytes written to an address (the low byte of word writes)
+    Address(u16),
+}
+
+impl FromStr for MidiSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("uart") {
+            return Ok(MidiSource::Uart);
+        }
+        return Ok(MidiSource::Address(parse_u16(s).map_err(|e| format!("{} (expected uart or an address)", e))?));
+    }
+}
+
+impl fmt::Display for MidiSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            MidiSource::Uart => write!(f, "uart"),
+            MidiSource::Address(address) => write!(f, "{:#06x}", address),
+        };
+    }
+}
+
+/// `run --osc-map ADDRESS:/PATH`, writes to ADDRESS are sent as OSC messages to PATH
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct OscMapping {
+    pub(crate) address: u16,
+    pub(crate) path: String,
+}
+
+impl FromStr for OscMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, path) = s.split_once(':').ok_or(format!("'{}' is not ADDRESS:/PATH", s))?;
+        if !path.starts_with('/') || path.contains(|c: char| c.is_whitespace() || "#*,?[]{}".contains(c)) {
+            return Err(format!("'{}' is not an OSC address (e.g. /synth/cutoff)", path));
+        }
+        return Ok(OscMapping { address: parse_u16(address)?, path: path.to_string() });
+    }
+}
+
+impl fmt::Display for OscMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:#06x}:{}", self.address, self.path);
+    }
+}
+
+/// A data write firmware made to an address the bridge listens to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct TappedWrite {
+    pub(crate) address: u16,
+    pub(crate) value: u16,
+}
+
+/// Collects the writes to the bridge's addresses while instructions run, for the run loop to take
+#[derive(Clone)]
+pub(crate) struct WriteTap {
+    addresses: Vec<u16>,
+    writes: Vec<TappedWrite>,
+}
+
+impl WriteTap {
+    #[inline]
+    pub(crate) fn write(&mut self, address: u16, value: u16) {
+        if self.addresses.contains(&address) {
+            self.writes.push(TappedWrite { address, value });
+        }
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<TappedWrite> {
+        return std::mem::take(&mut self.writes);
+    }
+}
+
+/// A complete MIDI message, status byte first (sysex with its F0 and F7)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct MidiMessage(pub(crate) Vec<u8>);
+
+impl MidiMessage {
+    fn channel(&self) -> i32 {
+        return (self.0[0] & 0x0f) as i32 + 1;
+    }
+
+    fn data(&self, index: usize) -> i32 {
+        return self.0[index] as i32;
+    }
+
+    /// OSC path and arguments under /midi, channels numbered 1-16 and a note on with velocity 0
+    /// sent as the note off it means
+    pub(crate) fn osc(&self) -> (&'static str, Vec<OscArg>) {
+        let int = OscArg::Int;
+        let status: u8 = self.0[0];
+        return match status & 0xf0 {
+            0x90 if self.0[2] == 0 => ("/midi/note_off", vec![int(self.channel()), int(self.data(1)), int(0)]),
+            0x80 => ("/midi/note_off", vec![int(self.channel()), int(self.data(1)), int(self.data(2))]),
+            0x90 => ("/midi/note_on", vec![int(self.channel()), int(self.data(1)), int(self.data(2))]),
+            0xa0 => ("/midi/poly_pressure", vec![int(self.channel()), int(self.data(1)), int(self.data(2))]),
+            0xb0 => ("/midi/cc", vec![int(self.channel()), int(self.data(1)), int(self.data(2))]),
+            0xc0 => ("/midi/program", vec![int(self.channel()), int(self.data(1))]),
+            0xd0 => ("/midi/pressure", vec![int(self.channel()), int(self.data(1))]),
+            0xe0 => ("/midi/pitch_bend", vec![int(self.channel()), int((self.data(2) << 7 | self.data(1)) - 8192)]),
+            _ => match status {
+                0xf0 => ("/midi/sysex", vec![OscArg::Blob(self.0[1..self.0.len() - 1].to_vec())]),
+                0xf8 => ("/midi/clock", Vec::new()),
+                0xfa => ("/midi/start", Vec::new()),
+                0xfb => ("/midi/continue", Vec::new()),
+                0xfc => ("/midi/stop", Vec::new()),
+                _ => ("/midi/system", self.0.iter().map(|b| int(*b as i32)).collect()),
+            },
+        };
+    }
+}
+
+impl fmt::Display for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (path, args) = self.osc();
+        write!(f, "{}", path.trim_start_matches("/midi/").replace('_', " "))?;
+        for arg in args {
+            match arg {
+                OscArg::Int(value) => write!(f, " {}", value)?,
+                OscArg::Blob(bytes) => write!(f, " ({} bytes)", bytes.len())?,
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Data bytes following each channel message status, by its high nibble 8-E
+const CHANNEL_DATA: [usize; 7] = [2, 2, 2, 2, 1, 1, 2];
+/// Longest sysex passed on, anything longer is dropped
+const MAX_SYSEX: usize = 1024;
+
+/// Splits a MIDI byte stream into messages the way a receiving instrument does: running status,
+/// real-time bytes anywhere (even inside other messages), stray data bytes ignored
+#[derive(Clone, Default)]
+pub(crate) struct MidiParser {
+    running: Option<u8>,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>,
+}
+
+impl MidiParser {
+    pub(crate) fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        if byte >= 0xf8 {
+            return Some(MidiMessage(vec![byte]));
+        }
+        if byte == 0xf7 {
+            let sysex: Vec<u8> = self.sysex.take()?;
+            return Some(MidiMessage([&sysex[..], &[0xf7]].concat()));
+        }
+        if let Some(sysex) = &mut self.sysex {
+            if byte < 0x80 {
+                sysex.push(byte);
+                if sysex.len() > MAX_SYSEX {
+                    self.sysex = None;
+                }
+                return None;
+            }
+            self.sysex = None; // unterminated, dropped
+        }
+        if byte >= 0x80 {
+            self.data.clear();
+            return match byte {
+                0xf0 => {
+                    self.running = None;
+                    self.sysex = Some(vec![0xf0]);
+                    None
+                },
+                // tune request is complete on its own, F4 and F5 are undefined
+                0xf4..=0xf6 => {
+                    self.running = None;
+                    (byte == 0xf6).then(|| MidiMessage(vec![byte]))
+                },
+                _ => {
+                    self.running = Some(byte);
+                    None
+                },
+            };
+        }
+        let status: u8 = self.running?;
+        self.data.push(byte);
+        let needed: usize = match status {
+            0x80..=0xef => CHANNEL_DATA[(status >> 4) as usize - 8],
+            0xf2 => 2,
+            _ => 1, // MTC quarter frame and song select
+        };
+        if self.data.len() < needed {
+            return None;
+        }
+        let message: MidiMessage = MidiMessage([&[status][..], &self.data].concat());
+        self.data.clear();
+        if status >= 0xf0 {
+            self.running = None; // system common messages cancel running status
+        }
+        return Some(message);
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum OscArg {
+    Int(i32),
+    Blob(Vec<u8>),
+}
+
+/// OSC strings and blobs are padded with zeros to a multiple of 4 bytes
+fn pad(bytes: &mut Vec<u8>) {
+    while !bytes.len().is_multiple_of(4) {
+        bytes.push(0);
+    }
+}
+
+/// Encode an OSC 1.0 message
+pub(crate) fn osc_message(path: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = path.as_bytes().to_vec();
+    bytes.push(0);
+    pad(&mut bytes);
+    bytes.push(b',');
+    bytes.extend(args.iter().map(|arg| match arg {
+        OscArg::Int(_) => b'i',
+        OscArg::Blob(_) => b'b',
+    }));
+    bytes.push(0);
+    pad(&mut bytes);
+    for arg in args {
+        match arg {
+            OscArg::Int(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Blob(blob) => {
+                bytes.extend_from_slice(&(blob.len() as i32).to_be_bytes());
+                bytes.extend_from_slice(blob);
+                pad(&mut bytes);
+            },
+        }
+    }
+    return bytes;
+}
+
+/// Something the bridge sent to the host, for the log
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Sent {
+    Midi(MidiSource, MidiMessage),
+    Osc(String, u16),
+}
+
+impl fmt::Display for Sent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Sent::Midi(source, message) => write!(f, "MIDI from {}: {}", source, message),
+            Sent::Osc(path, value) => write!(f, "OSC {} {}", path, value),
+        };
+    }
+}
+
+/// Passes what firmware writes on to host MIDI and OSC (`run --midi-source`, `--osc-map`): MIDI
+/// byte streams are parsed into messages and written raw to a file or device (`--midi-out`) and
+/// as OSC messages under /midi (`--osc-out`), writes to mapped addresses become OSC messages with
+/// the value. Messages leave as the run loop handles them, so their timing follows emulation speed.
+pub(crate) struct MidiLink {
+    sources: Vec<(MidiSource, MidiParser)>,
+    mappings: Vec<OscMapping>,
+    midi_out: Option<File>,
+    osc_out: Option<UdpSocket>,
+}
+
+impl MidiLink {
+    pub(crate) fn open(sources: &[MidiSource], mappings: &[OscMapping], midi_out: Option<&str>, osc_out: Option<&str>) -> io::Result<MidiLink> {
+        let midi_out: Option<File> = midi_out.map(File::create).transpose()?;
+        let osc_out: Option<UdpSocket> = match osc_out {
+            Some(address) => {
+                let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address)?;
+                Some(socket)
+            },
+            None => None,
+        };
+        return Ok(MidiLink {
+            sources: sources.iter().map(|source| (*source, MidiParser::default())).collect(),
+            mappings: mappings.to_vec(),
+            midi_out,
+            osc_out,
+        });
+    }
+
+    /// What the computer has to collect for this bridge, `None` if it only listens to the UART
+    pub(crate) fn tap(&self) -> Option<WriteTap> {
+        let mut addresses: Vec<u16> = self.mappings.iter().map(|m| m.address).collect();
+        addresses.extend(self.sources.iter().filter_map(|(source, _)| match source {
+            MidiSource::Address(address) => Some(*address),
+            MidiSource::Uart => None,
+        }));
+        return (!addresses.is_empty()).then_some(WriteTap { addresses, writes: Vec::new() });
+    }
+
+    /// Whether the UART's output is a source, the run loop hands it over instead of leaving it queued
+    pub(crate) fn takes_uart(&self) -> bool {
+        return self.sources.iter().any(|(s, _)| *s == MidiSource::Uart);
+    }
+
+    /// Send what firmware wrote since the last call: `uart_bytes` the UART transmitted (if it's a
+    /// source) and the tapped `writes`
+    pub(crate) fn pump(&mut self, uart_bytes: &[u8], writes: &[TappedWrite]) -> io::Result<Vec<Sent>> {
+        let mut sent: Vec<Sent> = Vec::new();
+        for (source, parser) in &mut self.sources {
+            let bytes: Vec<u8> = match source {
+                MidiSource::Uart => uart_bytes.to_vec(),
+                MidiSource::Address(address) => writes.iter().filter(|w| w.address == *address).map(|w| w.value as u8).collect(),
+            };
+            sent.extend(bytes.into_iter().filter_map(|byte| parser.push(byte)).map(|message| Sent::Midi(*source, message)));
+        }
+        for write in writes {
+            for mapping in self.mappings.iter().filter(|m| m.address == write.address) {
+                sent.push(Sent::Osc(mapping.path.clone(), write.value));
+            }
+        }
+        for item in &sent {
+            match item {
+                Sent::Midi(_, message) => {
+                    if let Some(file) = &mut self.midi_out {
+                        file.write_all(&message.0)?;
+                    }
+                    if let Some(socket) = &self.osc_out {
+                        let (path, args) = message.osc();
+                        Self::send(socket, &osc_message(path, &args))?;
+                    }
+                },
+                Sent::Osc(path, value) => {
+                    if let Some(socket) = &self.osc_out {
+                        Self::send(socket, &osc_message(path, &[OscArg::Int(*value as i32)]))?;
+                    }
+                },
+            }
+        }
+        if let Some(file) = &mut self.midi_out {
+            file.flush()?;
+        }
+        return Ok(sent);
+    }
+
+    fn send(socket: &UdpSocket, datagram: &[u8]) -> io::Result<()> {
+        return match socket.send(datagram) {
+            // nothing listening (yet), like an unplugged cable
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(()),
+            result => result.map(|_| ()),
+        };
+    }
+}
+
+impl QuantumModel for MidiLink {
+    type Input = (Vec<u8>, Vec<TappedWrite>);
+    type Output = io::Result<Vec<Sent>>;
+
+    fn quantum(&mut self, _cycle: u64, (uart_bytes, writes): Self::Input) -> Self::Output {
+        return self.pump(&uart_bytes, &writes);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{Computer, Fault, RegisterData};
+use crate::diagnostics::Diagnostic;
+use crate::stepping::StopReason;
+use crate::watch::WatchEvent;
+
+// Notifications share the event ring with watches (shared_memory_protocol.txt, command 33): their
+// event id is NOTIFICATION_IDS + kind, watch ids stay below it. A frontend subscribes to the kinds
+// it wants, so one that only knows watches never sees them.
+
+pub(crate) const NOTIFICATION_IDS: u16 = 0xff00;
+
+/// Stop reasons use their `StopReason::id` as kind
+pub(crate) const KIND_RESET: u16 = 7;
+pub(crate) const KIND_UART: u16 = 8;
+pub(crate) const KIND_DIAGNOSTIC: u16 = 10;
+
+pub(crate) struct Notifier {
+    /// bit n set = kind n is pushed
+    subscribed: u16,
+    resets: u32,
+    transmitted: u64,
+}
+
+impl Notifier {
+    pub(crate) fn new(c: &Computer) -> Notifier {
+        return Notifier { subscribed: 0, resets: c.resets, transmitted: c.devices.uart.transmitted().0 };
+    }
+
+    pub(crate) fn subscribe(&mut self, mask: u16) {
+        self.subscribed = mask;
+    }
+
+    fn wants(&self, kind: u16) -> bool {
+        return self.subscribed & (1 << kind) != 0;
+    }
+
+    /// The run stopped: old = breakpoint index (0xffff if none), new = fault kind (1 = no-execute
+    /// fetch, 2 = runaway, 3 = corrupted return address, 4 = trapped diagnostic, 0 for other reasons)
+    pub(crate) fn stopped(&self, reason: StopReason, c: &Computer) -> Option<WatchEvent> {
+        let kind: u16 = reason.id() as u16;
+        if !self.wants(kind) {
+            return None;
+        }
+        let fault: u16 = match (reason, c.fault) {
+            (StopReason::Fault, Some(Fault::NoExecute { .. })) => 1,
+            (StopReason::Fault, Some(Fault::Runaway { .. })) => 2,
+            (StopReason::Fault, Some(Fault::ReturnAddress { .. })) => 3,
+            (StopReason::Fault, Some(Fault::Diagnostic(_))) => 4,
+            _ => 0,
+        };
+        let breakpoint: u16 = reason.breakpoint().map(|b| b as u16).unwrap_or(0xffff);
+        return Some(WatchEvent { id: NOTIFICATION_IDS + kind, old: breakpoint, new: fault, pc: c.pc.get_word() });
+    }
+
+    /// Resets and UART output since the last poll. A reset has old = its cause
+    /// (`ResetCause::id`) and new = how many happened, UART output old = bytes transmitted
+    /// (at most 0xffff) and new = the last byte.
+    pub(crate) fn poll(&mut self, c: &Computer) -> Vec<WatchEvent> {
+        let mut events: Vec<WatchEvent> = Vec::new();
+        let pc: u16 = c.pc.get_word();
+        if c.resets != self.resets {
+            let count: u32 = c.resets.wrapping_sub(self.resets);
+            self.resets = c.resets;
+            if let (true, Some(cause)) = (self.wants(KIND_RESET), c.last_reset) {
+                events.push(WatchEvent { id: NOTIFICATION_IDS + KIND_RESET, old: cause.id(), new: count.min(0xffff) as u16, pc });
+            }
+        }
+        let (transmitted, last) = c.devices.uart.transmitted();
+        if transmitted != self.transmitted {
+            let count: u64 = transmitted - self.transmitted;
+            self.transmitted = transmitted;
+            if self.wants(KIND_UART) {
+                events.push(WatchEvent { id: NOTIFICATION_IDS + KIND_UART, old: count.min(0xffff) as u16, new: last as u16, pc });
+            }
+        }
+        return events;
+    }
+
+    /// A raised diagnostic: old = its category (`Category::id`), new = the address or SR it concerns
+    pub(crate) fn diagnostic(&self, diagnostic: &Diagnostic) -> Option<WatchEvent> {
+        if !self.wants(KIND_DIAGNOSTIC) {
+            return None;
+        }
+        return Some(WatchEvent { id: NOTIFICATION_IDS + KIND_DIAGNOSTIC, old: diagnostic.category.id(), new: diagnostic.value, pc: diagnostic.pc });
+    }
+}
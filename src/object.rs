@@ -0,0 +1,301 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Relocatable objects built on top of the absolute-only assembler. Each source is assembled a few
+// times with the code shifted and every external symbol moved, and the words that follow along
+// are the relocations. Sources may use:
+//   .section NAME      which linker script section the file's code goes in (default .text)
+//   .global NAME, ...  labels other files can refer to
+//   .extern NAME, ...  symbols defined in other files, usable wherever a number is
+// External symbols can't be jump targets (jumps are too short to leave the file) or `.interrupt`
+// handlers, an exported label must be followed by an instruction.
+
+use serde_json::{json, Value};
+use crate::image::{ProgramImage, Segment};
+use crate::{macros, utils};
+
+/// Where the assembler places code
+pub(crate) const CODE_BASE: u16 = 0x4400;
+/// Code is shifted by this many bytes (nops) to find what depends on its address
+const SHIFT: u16 = 16;
+/// Value external symbol `i` stands for while assembling
+const PLACEHOLDER_BASE: u16 = 0x2000;
+const PLACEHOLDER_STEP: u16 = 0x0010;
+/// External symbols are moved by this much to find their uses
+const EXTERN_DELTA: u16 = 0x0100;
+/// Label addresses are read back from words `.interrupt` writes here, 2 bytes per exported label
+const SCRATCH: u16 = 0x0000;
+const OBJECT_VERSION: u64 = 1;
+
+/// Adjust the word at `offset` in the code (or in the absolute segment at `segment`) by
+/// `base` times how far the code moved, plus how far `symbol` is from its placeholder
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Relocation {
+    pub(crate) segment: Option<u16>,
+    pub(crate) offset: u16,
+    pub(crate) base: i8,
+    pub(crate) symbol: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Object {
+    pub(crate) section: String,
+    /// assembled for `CODE_BASE`, emulator memory order
+    pub(crate) code: Vec<u8>,
+    /// segments at fixed addresses, e.g. interrupt vectors
+    pub(crate) absolute: Vec<Segment>,
+    pub(crate) relocations: Vec<Relocation>,
+    /// exported labels and their offset into the code
+    pub(crate) symbols: Vec<(String, u16)>,
+    /// external symbols and the placeholder value they were assembled with
+    pub(crate) externs: Vec<(String, u16)>,
+}
+
+fn is_identifier(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_';
+}
+
+/// Replace whole-word occurrences of `name`, outside comments
+fn replace_word(line: &str, name: &str, value: &str) -> String {
+    let (code, comment) = match line.find(';') {
+        Some(i) => line.split_at(i),
+        None => (line, ""),
+    };
+    let mut out: String = String::new();
+    let mut rest: &str = code;
+    while let Some(i) = rest.find(name) {
+        let before: Option<char> = rest[..i].chars().last().or(out.chars().last());
+        let after: Option<char> = rest[i + name.len()..].chars().next();
+        out.push_str(&rest[..i]);
+        if before.is_some_and(is_identifier) || after.is_some_and(is_identifier) {
+            out.push_str(name);
+        } else {
+            out.push_str(value);
+        }
+        rest = &rest[i + name.len()..];
+    }
+    out.push_str(rest);
+    out.push_str(comment);
+    return out;
+}
+
+fn names(list: &str) -> Vec<String> {
+    return list.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect();
+}
+
+/// One assembler run: code (starting at `CODE_BASE`) and the other segments by address
+fn run(source: &str) -> Result<(Vec<u8>, Vec<Segment>), String> {
+    let image: ProgramImage = ProgramImage::from_segmented(&utils::try_assemble(source)?)?;
+    let mut code: Option<Vec<u8>> = None;
+    let mut absolute: Vec<Segment> = Vec::new();
+    for segment in image.segments {
+        if segment.address == CODE_BASE && code.is_none() {
+            code = Some(segment.data);
+        } else {
+            absolute.push(segment);
+        }
+    }
+    return Ok((code.ok_or("Source has no code")?, absolute));
+}
+
+fn word(data: &[u8], offset: usize) -> u16 {
+    return u16::from_be_bytes([data[offset], data[offset + 1]]);
+}
+
+impl Object {
+    /// Assemble `source` (macros are expanded first) into a relocatable object
+    pub(crate) fn assemble(source: &str) -> Result<Object, String> {
+        let expanded: String = macros::expand(source)?;
+        let mut section: String = ".text".to_string();
+        let mut globals: Vec<String> = Vec::new();
+        let mut extern_names: Vec<String> = Vec::new();
+        let mut lines: Vec<String> = Vec::new();
+        for line in expanded.lines() {
+            let content: &str = line.split(';').next().unwrap_or("").trim();
+            if let Some(name) = content.strip_prefix(".section") {
+                section = name.trim().to_string();
+            } else if let Some(list) = content.strip_prefix(".global") {
+                globals.extend(names(list));
+            } else if let Some(list) = content.strip_prefix(".extern") {
+                extern_names.extend(names(list));
+            } else {
+                lines.push(line.to_string());
+            }
+        }
+        let externs: Vec<(String, u16)> = extern_names.iter().enumerate()
+            .map(|(i, name)| (name.clone(), PLACEHOLDER_BASE + PLACEHOLDER_STEP * i as u16))
+            .collect();
+        let variant = |shift: bool, moved: Option<usize>| -> String {
+            let mut out: String = if shift {"nop\n".repeat(SHIFT as usize / 2)} else {String::new()};
+            for line in &lines {
+                let mut line: String = line.clone();
+                for (i, (name, placeholder)) in externs.iter().enumerate() {
+                    let value: u16 = if moved == Some(i) {placeholder + EXTERN_DELTA} else {*placeholder};
+                    line = replace_word(&line, name, &format!("{:#06x}", value));
+                }
+                out.push_str(&line);
+                out.push('\n');
+            }
+            for (i, name) in globals.iter().enumerate() {
+                out.push_str(&format!(".interrupt {:#06x} {}\n", SCRATCH + 2 * i as u16, name));
+            }
+            return out;
+        };
+        let is_scratch = |address: u16| (SCRATCH..SCRATCH + 2 * globals.len() as u16).contains(&address);
+
+        let (code, all_absolute) = run(&variant(false, None))?;
+        let mut symbols: Vec<(String, u16)> = Vec::new();
+        for (i, name) in globals.iter().enumerate() {
+            let address: u16 = all_absolute.iter().find(|s| s.address == SCRATCH + 2 * i as u16)
+                .map(|s| word(&s.data, 0)).ok_or(format!("Label '{}' not found", name))?;
+            if address < CODE_BASE || (address - CODE_BASE) as usize >= code.len() {
+                return Err(format!("Label '{}' is not in the code", name));
+            }
+            symbols.push((name.clone(), address - CODE_BASE));
+        }
+        let absolute: Vec<Segment> = all_absolute.into_iter().filter(|s| !is_scratch(s.address)).collect();
+        if absolute.iter().any(|s| s.address < SCRATCH + 2 * globals.len() as u16 && s.address + s.data.len() as u16 > SCRATCH) {
+            return Err(format!("Addresses below {:#06x} are used to find labels", SCRATCH + 2 * globals.len() as u16));
+        }
+
+        // (segment, offset) -> (base, symbol)
+        let mut found: Vec<(Option<u16>, u16, i8, Option<String>)> = Vec::new();
+        let mut compare = |base_code: &[u8], base_absolute: &[Segment], other_code: &[u8], other_absolute: &[Segment],
+                           moved: Option<&str>| -> Result<(), String> {
+            let expected: u16 = if moved.is_some() {EXTERN_DELTA} else {SHIFT};
+            if base_code.len() != other_code.len() {
+                return Err("Code size depends on symbol values, avoid -1, 0, 1, 2, 4 and 8 offsets from symbols".to_string());
+            }
+            let mut pairs: Vec<(Option<u16>, u16, u16, u16)> = Vec::new();
+            for offset in (0..base_code.len().saturating_sub(1)).step_by(2) {
+                pairs.push((None, offset as u16, word(base_code, offset), word(other_code, offset)));
+            }
+            for segment in base_absolute {
+                let other = other_absolute.iter().find(|s| s.address == segment.address && s.data.len() == segment.data.len())
+                    .ok_or(format!("Segment at {:#06x} depends on symbol values", segment.address))?;
+                for offset in (0..segment.data.len().saturating_sub(1)).step_by(2) {
+                    pairs.push((Some(segment.address), offset as u16, word(&segment.data, offset), word(&other.data, offset)));
+                }
+            }
+            for (segment, offset, before, after) in pairs {
+                let delta: u16 = after.wrapping_sub(before);
+                let factor: i8 = if delta == 0 {
+                    continue;
+                } else if delta == expected {
+                    1
+                } else if delta == expected.wrapping_neg() && moved.is_none() {
+                    -1
+                } else {
+                    return Err(format!("Word at offset {:#x} changed unexpectedly", offset));
+                };
+                let index: usize = match found.iter().position(|f| f.0 == segment && f.1 == offset) {
+                    Some(index) => index,
+                    None => {
+                        found.push((segment, offset, 0, None));
+                        found.len() - 1
+                    },
+                };
+                match moved {
+                    Some(name) => found[index].3 = Some(name.to_string()),
+                    None => found[index].2 = factor,
+                }
+            }
+            return Ok(());
+        };
+        let (shifted_code, shifted_absolute) = run(&variant(true, None))?;
+        if shifted_code.len() < SHIFT as usize {
+            return Err("Shifted code is too short".to_string());
+        }
+        let shifted_absolute: Vec<Segment> = shifted_absolute.into_iter().filter(|s| !is_scratch(s.address)).collect();
+        compare(&code, &absolute, &shifted_code[SHIFT as usize..], &shifted_absolute, None)?;
+        for (i, (name, _)) in externs.iter().enumerate() {
+            let (moved_code, moved_absolute) = run(&variant(false, Some(i)))?;
+            let moved_absolute: Vec<Segment> = moved_absolute.into_iter().filter(|s| !is_scratch(s.address)).collect();
+            compare(&code, &absolute, &moved_code, &moved_absolute, Some(name))?;
+        }
+        // the assembler's own reset vector points at the start of the code however it's shifted
+        let default_reset: bool = absolute.iter().any(|s| s.address == 0xfffe && s.data.len() == 2 && word(&s.data, 0) == CODE_BASE);
+        if default_reset && !found.iter().any(|f| f.0 == Some(0xfffe)) {
+            found.push((Some(0xfffe), 0, 1, None));
+        }
+        found.sort_by_key(|f| (f.0, f.1));
+        let relocations: Vec<Relocation> = found.into_iter()
+            .map(|(segment, offset, base, symbol)| Relocation { segment, offset, base, symbol })
+            .collect();
+        return Ok(Object { section, code, absolute, relocations, symbols, externs });
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let hex = |data: &[u8]| data.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let value: Value = json!({
+            "version": OBJECT_VERSION,
+            "section": self.section,
+            "code": hex(&self.code),
+            "absolute": self.absolute.iter().map(|s| json!({"address": s.address, "data": hex(&s.data)})).collect::<Vec<Value>>(),
+            "relocations": self.relocations.iter().map(|r| json!({
+                "segment": r.segment, "offset": r.offset, "base": r.base, "symbol": r.symbol,
+            })).collect::<Vec<Value>>(),
+            "symbols": self.symbols.iter().map(|(name, offset)| json!({"name": name, "offset": offset})).collect::<Vec<Value>>(),
+            "externs": self.externs.iter().map(|(name, placeholder)| json!({"name": name, "placeholder": placeholder})).collect::<Vec<Value>>(),
+        });
+        return serde_json::to_string_pretty(&value).expect("plain values serialize") + "\n";
+    }
+
+    pub(crate) fn from_json(text: &str) -> Result<Object, String> {
+        let root: Value = serde_json::from_str(text).map_err(|e| format!("Not an object file: {}", e))?;
+        if root["version"].as_u64() != Some(OBJECT_VERSION) {
+            return Err("Unsupported object file version".to_string());
+        }
+        let bad = |what: &str| format!("Object file has a bad {}", what);
+        let u16_of = |v: &Value, what: &str| v.as_u64().filter(|n| *n <= 0xffff).map(|n| n as u16).ok_or(bad(what));
+        let bytes = |v: &Value, what: &str| -> Result<Vec<u8>, String> {
+            let text: &str = v.as_str().ok_or(bad(what))?;
+            if !text.len().is_multiple_of(2) {
+                return Err(bad(what));
+            }
+            return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| bad(what))).collect();
+        };
+        let array = |v: &Value, what: &str| v.as_array().cloned().ok_or(bad(what));
+        let mut object = Object {
+            section: root["section"].as_str().ok_or(bad("section"))?.to_string(),
+            code: bytes(&root["code"], "code")?,
+            absolute: Vec::new(),
+            relocations: Vec::new(),
+            symbols: Vec::new(),
+            externs: Vec::new(),
+        };
+        for s in array(&root["absolute"], "segment list")? {
+            object.absolute.push(Segment { address: u16_of(&s["address"], "segment")?, data: bytes(&s["data"], "segment")? });
+        }
+        for r in array(&root["relocations"], "relocation list")? {
+            object.relocations.push(Relocation {
+                segment: if r["segment"].is_null() {None} else {Some(u16_of(&r["segment"], "relocation")?)},
+                offset: u16_of(&r["offset"], "relocation")?,
+                base: r["base"].as_i64().filter(|b| (-1..=1).contains(b)).ok_or(bad("relocation"))? as i8,
+                symbol: r["symbol"].as_str().map(|s| s.to_string()),
+            });
+        }
+        for s in array(&root["symbols"], "symbol list")? {
+            object.symbols.push((s["name"].as_str().ok_or(bad("symbol"))?.to_string(), u16_of(&s["offset"], "symbol")?));
+        }
+        for s in array(&root["externs"], "extern list")? {
+            object.externs.push((s["name"].as_str().ok_or(bad("extern"))?.to_string(), u16_of(&s["placeholder"], "extern")?));
+        }
+        return Ok(object);
+    }
+}
@@ -0,0 +1,449 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ops::Range;
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::sensors::{cycles_for, micros, Transaction};
+
+/// The master held the bus low at least this long: a reset
+const RESET_US: u64 = 480;
+/// The master released the bus within this long: a 1 (or a read slot)
+const WRITE_ONE_US: u64 = 15;
+/// Presence pulse, after the reset pulse ends
+const PRESENCE_DELAY_US: u64 = 30;
+const PRESENCE_US: u64 = 120;
+/// How long a device holds the bus low to send a 0
+const READ_ZERO_US: u64 = 30;
+/// A transaction is logged once the bus has been idle this long
+const QUIET_US: u64 = 1000;
+
+const READ_ROM: u8 = 0x33;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xcc;
+const SEARCH_ROM: u8 = 0xf0;
+const ALARM_SEARCH: u8 = 0xec;
+const CONVERT_T: u8 = 0x44;
+const WRITE_SCRATCHPAD: u8 = 0x4e;
+const READ_SCRATCHPAD: u8 = 0xbe;
+const COPY_SCRATCHPAD: u8 = 0x48;
+const RECALL_E2: u8 = 0xb8;
+const READ_POWER_SUPPLY: u8 = 0xb4;
+
+const DS18B20_FAMILY: u8 = 0x28;
+
+/// Dallas/Maxim CRC-8 (x^8 + x^5 + x^4 + 1) as used in ROM codes and the scratchpad
+pub(crate) fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in data {
+        let mut byte: u8 = *byte;
+        for _ in 0..8 {
+            let mix: u8 = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+    return crc;
+}
+
+fn command_name(command: u8, rom: bool) -> Option<&'static str> {
+    return match (rom, command) {
+        (true, READ_ROM) => Some("READ ROM"),
+        (true, MATCH_ROM) => Some("MATCH ROM"),
+        (true, SKIP_ROM) => Some("SKIP ROM"),
+        (true, SEARCH_ROM) => Some("SEARCH ROM"),
+        (true, ALARM_SEARCH) => Some("ALARM SEARCH"),
+        (false, CONVERT_T) => Some("CONVERT T"),
+        (false, WRITE_SCRATCHPAD) => Some("WRITE SCRATCHPAD"),
+        (false, READ_SCRATCHPAD) => Some("READ SCRATCHPAD"),
+        (false, COPY_SCRATCHPAD) => Some("COPY SCRATCHPAD"),
+        (false, RECALL_E2) => Some("RECALL E2"),
+        (false, READ_POWER_SUPPLY) => Some("READ POWER SUPPLY"),
+        _ => None,
+    };
+}
+
+/// Where a device is in a transaction
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Phase {
+    /// not addressed, waits for the next reset
+    Idle,
+    RomCommand,
+    MatchRom,
+    /// `step` 0 sends the ROM bit, 1 its complement, 2 receives the direction
+    Search { bit: u8, step: u8 },
+    FunctionCommand,
+    WriteScratchpad,
+    /// sends `data` LSB first, then 1s
+    Send { data: Vec<u8>, sent: usize },
+    /// read slots answer 0 until the conversion is done
+    Converting { done: u64 },
+    /// read slots answer 1 (external power, conversion done)
+    Ones,
+}
+
+/// A DS18B20 temperature sensor: ROM commands including SEARCH ROM, CONVERT T with the conversion
+/// time of the configured resolution, the scratchpad and its EEPROM copy
+#[derive(Clone)]
+pub(crate) struct Ds18b20 {
+    rom: [u8; 8],
+    /// what CONVERT T measures, °C
+    pub(crate) celsius: f64,
+    /// last conversion in 1/16 °C, 85 °C at power-up
+    reading: i16,
+    /// TH, TL and the configuration register, with their EEPROM copy
+    user: [u8; 3],
+    eeprom: [u8; 3],
+    phase: Phase,
+    /// bits received in the current phase, LSB first
+    shift: u64,
+    count: u8,
+    /// sent something in the current slot instead of receiving
+    sending: bool,
+    /// cycles in which it holds the bus low
+    pull: Range<u64>,
+}
+
+impl Ds18b20 {
+    /// `serial` tells devices on the same bus apart
+    pub(crate) fn new(serial: u64, celsius: f64) -> Ds18b20 {
+        let mut rom: [u8; 8] = [0; 8];
+        rom[0] = DS18B20_FAMILY;
+        rom[1..7].copy_from_slice(&serial.to_le_bytes()[..6]);
+        rom[7] = crc8(&rom[..7]);
+        return Ds18b20 {
+            rom,
+            celsius,
+            reading: 85 * 16,
+            user: [0x4b, 0x46, 0x7f],
+            eeprom: [0x4b, 0x46, 0x7f],
+            phase: Phase::Idle,
+            shift: 0,
+            count: 0,
+            sending: false,
+            pull: 0..0,
+        };
+    }
+
+    /// Power cycle, the EEPROM keeps its contents
+    pub(crate) fn reset(&mut self) {
+        *self = Ds18b20 { rom: self.rom, user: self.eeprom, eeprom: self.eeprom, ..Ds18b20::new(0, self.celsius) };
+    }
+
+    /// 9 to 12 bits, from the configuration register
+    fn resolution(&self) -> u32 {
+        return 9 + ((self.user[2] >> 5) & 0x03) as u32;
+    }
+
+    fn scratchpad(&self) -> Vec<u8> {
+        let [low, high] = self.reading.to_le_bytes();
+        let mut data: Vec<u8> = vec![low, high, self.user[0], self.user[1], self.user[2], 0xff, 0x0c, 0x10];
+        data.push(crc8(&data));
+        return data;
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.shift = 0;
+        self.count = 0;
+    }
+
+    /// The master ended a reset pulse, the device answers with a presence pulse
+    fn bus_reset(&mut self, now: u64, mclk_hz: u64) {
+        self.enter(Phase::RomCommand);
+        self.pull = now + cycles_for(PRESENCE_DELAY_US, mclk_hz)..now + cycles_for(PRESENCE_DELAY_US + PRESENCE_US, mclk_hz);
+    }
+
+    /// A slot started, returns the bit sent if this device is sending
+    fn slot_started(&mut self, now: u64, mclk_hz: u64) -> Option<bool> {
+        let bit: Option<bool> = match &mut self.phase {
+            Phase::Send { data, sent } => {
+                let bit: bool = data.get(*sent / 8).is_none_or(|byte| (byte >> (*sent % 8)) & 1 != 0);
+                *sent += 1;
+                Some(bit)
+            },
+            Phase::Search { bit, step: step @ (0 | 1) } => {
+                let value: bool = (self.rom[*bit as usize / 8] >> (*bit % 8)) & 1 != 0;
+                let sent: bool = if *step == 0 {value} else {!value};
+                *step += 1;
+                Some(sent)
+            },
+            Phase::Converting { done } => Some(now >= *done),
+            Phase::Ones => Some(true),
+            _ => None,
+        };
+        if bit == Some(false) {
+            self.pull = now..now + cycles_for(READ_ZERO_US, mclk_hz);
+        }
+        self.sending = bit.is_some();
+        return bit;
+    }
+
+    /// A slot in which this device wasn't sending ended with the master writing `bit`
+    fn received(&mut self, bit: bool, now: u64, mclk_hz: u64) {
+        if self.sending {
+            return;
+        }
+        if let Phase::Search { bit: index, step } = &mut self.phase {
+            let value: bool = (self.rom[*index as usize / 8] >> (*index % 8)) & 1 != 0;
+            if bit != value {
+                self.enter(Phase::Idle);
+            } else if *index == 63 {
+                self.enter(Phase::FunctionCommand);
+            } else {
+                *index += 1;
+                *step = 0;
+            }
+            return;
+        }
+        if matches!(self.phase, Phase::Idle) {
+            return;
+        }
+        self.shift |= (bit as u64) << self.count;
+        self.count += 1;
+        let byte: u8 = self.shift as u8;
+        match self.phase {
+            Phase::RomCommand if self.count == 8 => match byte {
+                READ_ROM => self.enter(Phase::Send { data: self.rom.to_vec(), sent: 0 }),
+                MATCH_ROM => self.enter(Phase::MatchRom),
+                SKIP_ROM => self.enter(Phase::FunctionCommand),
+                SEARCH_ROM => self.enter(Phase::Search { bit: 0, step: 0 }),
+                _ => self.enter(Phase::Idle), // ALARM SEARCH: never in alarm
+            },
+            Phase::MatchRom if self.count == 64 => {
+                let next: Phase = if self.shift == u64::from_le_bytes(self.rom) {Phase::FunctionCommand} else {Phase::Idle};
+                self.enter(next);
+            },
+            Phase::FunctionCommand if self.count == 8 => match byte {
+                CONVERT_T => {
+                    let bits: u32 = self.resolution();
+                    let sixteenths: i16 = (self.celsius * 16.0).round().clamp(-55.0 * 16.0, 125.0 * 16.0) as i16;
+                    self.reading = sixteenths & !((1i16 << (12 - bits)) - 1);
+                    // 93.75 ms at 9 bits, doubling with every bit
+                    let done: u64 = now + cycles_for(93_750 << (bits - 9), mclk_hz);
+                    self.enter(Phase::Converting { done });
+                },
+                WRITE_SCRATCHPAD => self.enter(Phase::WriteScratchpad),
+                READ_SCRATCHPAD => self.enter(Phase::Send { data: self.scratchpad(), sent: 0 }),
+                COPY_SCRATCHPAD => {
+                    self.eeprom = self.user;
+                    self.enter(Phase::Ones);
+                },
+                RECALL_E2 => {
+                    self.user = self.eeprom;
+                    self.enter(Phase::Ones);
+                },
+                READ_POWER_SUPPLY => self.enter(Phase::Ones),
+                _ => self.enter(Phase::Idle),
+            },
+            Phase::WriteScratchpad if self.count.is_multiple_of(8) => {
+                // TH, TL, then the configuration register, which only has the resolution bits
+                let index: usize = self.count as usize / 8 - 1;
+                let byte: u8 = (self.shift >> (self.count - 8)) as u8;
+                self.user[index] = if index == 2 {(byte & 0x60) | 0x1f} else {byte};
+                if index == 2 {
+                    self.enter(Phase::Idle);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Bytes of one direction within a transaction
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Segment {
+    read: bool,
+    bytes: Vec<u8>,
+    /// bits of the last byte so far
+    bits: u8,
+}
+
+/// A 1-Wire bus on one pin with the devices on it. The firmware is the master: it pulls the pin
+/// low by making it an output driving 0 and releases it by making it an input, the bus is pulled
+/// up otherwise. Slots are told apart by how long the master holds the bus low.
+#[derive(Clone)]
+pub(crate) struct OneWireBus {
+    pub(crate) pin: PinId,
+    pub(crate) devices: Vec<Ds18b20>,
+    master_low: bool,
+    /// when the master last pulled the bus low or released it
+    edge: u64,
+    /// level driven onto the pin last time, `None` before the first sample
+    driven: Option<bool>,
+    /// bit the devices sent in the current slot, `None` if none is sending
+    slot_read: Option<bool>,
+    /// a transaction is being decoded: devices present after its reset (`None` if it didn't start
+    /// with one) and the bytes since
+    open: bool,
+    present: Option<usize>,
+    segments: Vec<Segment>,
+    /// SEARCH ROM slots so far and the ROM code the master's directions spell
+    search_slots: usize,
+    search_rom: u64,
+}
+
+impl OneWireBus {
+    pub(crate) fn new(pin: PinId, devices: Vec<Ds18b20>) -> OneWireBus {
+        return OneWireBus {
+            pin,
+            devices,
+            master_low: false,
+            edge: 0,
+            driven: None,
+            slot_read: None,
+            open: false,
+            present: None,
+            segments: Vec::new(),
+            search_slots: 0,
+            search_rom: 0,
+        };
+    }
+
+    /// Power cycle the devices, the bus is pulled up again on the next sample
+    pub(crate) fn reset(&mut self) {
+        let devices: Vec<Ds18b20> = std::mem::take(&mut self.devices).into_iter().map(|mut d| {
+            d.reset();
+            d
+        }).collect();
+        *self = OneWireBus::new(self.pin, devices);
+    }
+
+    pub(crate) fn sample(&mut self, now: u64, mclk_hz: u64, gpio: &mut GpioDevice, log: &mut Vec<Transaction>) {
+        let master_low: bool = gpio.output(self.pin) == Some(false);
+        if master_low && !self.master_low {
+            // the devices sending a bit in this slot put it on the bus together, wired-AND
+            self.slot_read = self.devices.iter_mut().filter_map(|d| d.slot_started(now, mclk_hz)).reduce(|a, b| a && b);
+            self.edge = now;
+        } else if !master_low && self.master_low {
+            let low: u64 = micros(now - self.edge, mclk_hz);
+            if low >= RESET_US {
+                self.finish(log);
+                self.devices.iter_mut().for_each(|d| d.bus_reset(now, mclk_hz));
+                self.open = true;
+                self.present = Some(self.devices.len());
+            } else {
+                let written: bool = low < WRITE_ONE_US;
+                self.devices.iter_mut().for_each(|d| d.received(written, now, mclk_hz));
+                self.record(self.slot_read, written);
+            }
+            self.edge = now;
+        }
+        self.master_low = master_low;
+
+        let pulled: bool = self.devices.iter().any(|d| d.pull.contains(&now));
+        if self.driven != Some(!pulled) {
+            gpio.set_input(self.pin, Some(!pulled));
+            self.driven = Some(!pulled);
+        }
+        if !master_low && self.open && micros(now - self.edge, mclk_hz) >= QUIET_US {
+            self.finish(log);
+        }
+    }
+
+    /// SEARCH ROM was the ROM command and its 64 triplets aren't over yet
+    fn searching(&self) -> bool {
+        return self.search_slots < 192 && matches!(self.segments.as_slice(),
+            [Segment { read: false, bytes, bits: 0 }] if bytes.as_slice() == [SEARCH_ROM]);
+    }
+
+    /// Add a slot to the transaction being decoded
+    fn record(&mut self, read: Option<bool>, written: bool) {
+        if !self.open {
+            self.open = true;
+            self.present = None;
+        }
+        if self.searching() {
+            // each triplet is the ROM bit, its complement and the direction the master takes
+            if self.search_slots % 3 == 2 {
+                self.search_rom |= (written as u64) << (self.search_slots / 3);
+            }
+            self.search_slots += 1;
+            return;
+        }
+        let bit: bool = read.unwrap_or(written);
+        let is_read: bool = read.is_some();
+        if self.segments.last().is_none_or(|s| s.read != is_read) {
+            self.segments.push(Segment { read: is_read, bytes: Vec::new(), bits: 0 });
+        }
+        let segment: &mut Segment = self.segments.last_mut().unwrap();
+        if segment.bits == 0 {
+            segment.bytes.push(0);
+        }
+        *segment.bytes.last_mut().unwrap() |= (bit as u8) << segment.bits;
+        segment.bits = (segment.bits + 1) % 8;
+    }
+
+    /// Log the transaction being decoded, if any
+    fn finish(&mut self, log: &mut Vec<Transaction>) {
+        if !self.open {
+            return;
+        }
+        self.open = false;
+        let mut parts: Vec<String> = vec![match self.present {
+            None => "no reset".to_string(),
+            Some(0) => "reset, no presence".to_string(),
+            Some(1) => "reset, presence".to_string(),
+            Some(n) => format!("reset, {} present", n),
+        }];
+        // without a reset there are no commands to name
+        let mut commands: usize = if self.present.is_some() {0} else {2};
+        for segment in std::mem::take(&mut self.segments) {
+            let mut bytes: &[u8] = &segment.bytes;
+            if !segment.read {
+                // the first byte written is a ROM command, the one after its ROM code a function command
+                while commands < 2 && !bytes.is_empty() {
+                    let rom: bool = commands == 0;
+                    match command_name(bytes[0], rom) {
+                        Some("MATCH ROM") if bytes.len() >= 9 => {
+                            parts.push(format!("MATCH ROM {}", hex(&bytes[1..9])));
+                            bytes = &bytes[9..];
+                        },
+                        Some("SEARCH ROM") if self.search_slots == 192 => {
+                            parts.push(format!("SEARCH ROM {}", hex(&self.search_rom.to_le_bytes())));
+                            bytes = &bytes[1..];
+                        },
+                        Some("SEARCH ROM") => {
+                            parts.push(format!("SEARCH ROM ({} of 192 slots)", self.search_slots));
+                            bytes = &bytes[1..];
+                        },
+                        Some(name) => {
+                            parts.push(name.to_string());
+                            bytes = &bytes[1..];
+                        },
+                        None => break,
+                    }
+                    commands += 1;
+                }
+            }
+            if !bytes.is_empty() {
+                let partial: &str = if segment.bits != 0 {" (partial byte)"} else {""};
+                parts.push(format!("{} {}{}", if segment.read {"read"} else {"wrote"}, hex(bytes), partial));
+            }
+        }
+        self.search_slots = 0;
+        self.search_rom = 0;
+        log.push(Transaction { pin: self.pin, protocol: "1-Wire", text: parts.join("; ") });
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ");
+}
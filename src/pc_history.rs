@@ -0,0 +1,98 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::disasm;
+use crate::MemoryMap;
+
+pub(crate) const DEFAULT_CAPACITY: usize = 64;
+
+/// An executed instruction: where it was and the first word of it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct HistoryEntry {
+    pub(crate) pc: u16,
+    pub(crate) instruction: u16,
+}
+
+/// Ring of the most recently executed instructions, for finding out how the program got somewhere
+#[derive(Clone)]
+pub(crate) struct PcHistory {
+    entries: Vec<HistoryEntry>,
+    /// slot the next entry goes into
+    next: usize,
+    capacity: usize,
+    /// dump instructions as encoded instead of as emulated instructions
+    canonical: bool,
+}
+
+impl PcHistory {
+    pub(crate) fn new(capacity: usize) -> PcHistory {
+        return PcHistory { entries: Vec::with_capacity(capacity), next: 0, capacity, canonical: false };
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.next = 0;
+    }
+
+    /// Change how many entries are kept, this clears the history
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries = Vec::with_capacity(capacity);
+        self.next = 0;
+    }
+
+    pub(crate) fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    #[inline]
+    pub(crate) fn record(&mut self, pc: u16, instruction: u16) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = HistoryEntry { pc, instruction };
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else {
+            self.entries[self.next] = entry;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    /// Recorded instructions, oldest first
+    pub(crate) fn entries(&self) -> Vec<HistoryEntry> {
+        if self.entries.len() < self.capacity {
+            return self.entries.clone();
+        }
+        let mut ordered: Vec<HistoryEntry> = self.entries[self.next..].to_vec();
+        ordered.extend_from_slice(&self.entries[..self.next]);
+        return ordered;
+    }
+
+    /// Print the history to stderr, newest last. Extension words are read from `memory` as it is
+    /// now, the instruction word is the one that ran.
+    pub(crate) fn dump(&self, reason: &str, memory: &MemoryMap) {
+        let entries = self.entries();
+        eprintln!("Last {} instructions before {}:", entries.len(), reason);
+        for entry in entries {
+            let read = |address: u16| if address == entry.pc {entry.instruction} else {memory.get_word(address)};
+            let decoded = disasm::disassemble(entry.pc, &read, self.canonical);
+            eprintln!("  {:#06x}: {:#06x}  {}", entry.pc, entry.instruction, decoded.text);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use crate::Computer;
+
+/// A register's current value with its bit fields decoded, e.g. `MPUCTL0 = 0x9611 MPUENA=1 MPULOCK=0`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct RegisterView {
+    pub(crate) name: String,
+    pub(crate) address: u16,
+    pub(crate) value: u16,
+    /// byte registers print two hex digits
+    pub(crate) byte: bool,
+    pub(crate) fields: Vec<(&'static str, String)>,
+}
+
+impl RegisterView {
+    pub(crate) fn word(name: &str, address: u16, value: u16) -> RegisterView {
+        return RegisterView { name: name.to_string(), address, value, byte: false, fields: Vec::new() };
+    }
+
+    pub(crate) fn byte(name: &str, address: u16, value: u8) -> RegisterView {
+        return RegisterView { name: name.to_string(), address, value: value as u16, byte: true, fields: Vec::new() };
+    }
+
+    /// A single bit, shown as 0 or 1
+    pub(crate) fn flag(self, name: &'static str, mask: u16) -> RegisterView {
+        let set: bool = self.value & mask != 0;
+        return self.field(name, (set as u8).to_string());
+    }
+
+    pub(crate) fn field(mut self, name: &'static str, value: String) -> RegisterView {
+        self.fields.push((name, value));
+        return self;
+    }
+}
+
+impl fmt::Display for RegisterView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value: String = if self.byte {format!("{:#04x}", self.value)} else {format!("{:#06x}", self.value)};
+        write!(f, "{:<11} {:#06x} = {:<6}", self.name, self.address, value)?;
+        for (name, value) in &self.fields {
+            write!(f, " {}={}", name, value)?;
+        }
+        return Ok(());
+    }
+}
+
+/// One modeled peripheral and its registers in address order
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct PeripheralView {
+    pub(crate) name: &'static str,
+    pub(crate) registers: Vec<RegisterView>,
+}
+
+impl fmt::Display for PeripheralView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        for register in &self.registers {
+            writeln!(f, "  {}", register)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Every peripheral with registers worth looking at, the order is the index control command 27 takes.
+/// Reading them has no side effects, unlike reading UART_RX or SYSJMBIx from firmware.
+pub(crate) fn describe(c: &Computer) -> Vec<PeripheralView> {
+    let devices = &c.devices;
+    let [p1, p2] = devices.gpio.registers();
+    return vec![
+        PeripheralView { name: "Port 1", registers: p1 },
+        PeripheralView { name: "Port 2", registers: p2 },
+        PeripheralView { name: "UART", registers: devices.uart.registers() },
+        PeripheralView { name: "Console input", registers: devices.console.registers() },
+        PeripheralView { name: "Real-time clock", registers: devices.rtc.registers(&c.clock) },
+        PeripheralView { name: "Memory protection unit", registers: devices.mpu.registers() },
+        PeripheralView { name: "JTAG mailbox", registers: devices.mailbox.registers() },
+        PeripheralView { name: "Supply supervisor", registers: devices.pmm.registers() },
+        PeripheralView { name: "Port mapping", registers: devices.pmap.registers() },
+        PeripheralView { name: "Clock system", registers: devices.cs.registers() },
+        PeripheralView { name: "Pin oscillators", registers: devices.touch.registers(&c.clock) },
+        PeripheralView { name: "Comparator", registers: devices.comparator.registers() },
+        PeripheralView { name: "Host files", registers: devices.files.registers() },
+        PeripheralView { name: "SPI (USCI_B0)", registers: devices.spi.registers() },
+        PeripheralView { name: "Watchdog timer", registers: devices.wdt.registers(&c.clock) },
+        PeripheralView { name: "Frame buffer", registers: devices.framebuffer.registers() },
+    ];
+}
+
+/// The pins set in `mask` as `P1.0,P1.6`, `-` for none
+pub(crate) fn pins(port: usize, mask: u8) -> String {
+    let pins: Vec<String> = (0..8).filter(|pin| mask & (1 << pin) != 0).map(|pin| format!("P{}.{}", port, pin)).collect();
+    return if pins.is_empty() {"-".to_string()} else {pins.join(",")};
+}
+
+/// Millivolts as volts with two decimals, the way the datasheet lists the levels
+pub(crate) fn volts(mv: u16) -> String {
+    return format!("{}.{:02}V", mv / 1000, mv % 1000 / 10);
+}
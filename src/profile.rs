@@ -0,0 +1,326 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use clap::ValueEnum;
+use crate::devices::{comparator, console, cs, gpio, mpu, uart, wdt};
+use crate::interrupts::{RESET_VECTOR, VECTOR_TABLE};
+use crate::utils::parse_u16;
+
+/// Inclusive range of addresses
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Region {
+    pub const fn new(start: u16, end: u16) -> Region {
+        return Region { start, end };
+    }
+
+    #[inline]
+    pub fn contains(&self, address: u16) -> bool {
+        return self.start <= address && address <= self.end;
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// `START-END`, both inclusive, e.g. `0x0200-0x03ff`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-')
+            .ok_or(format!("'{}' is not a region (expected e.g. 0x0200-0x03ff)", s))?;
+        let region = Region { start: parse_u16(start)?, end: parse_u16(end)? };
+        if region.start > region.end {
+            return Err(format!("Region '{}' ends before it starts", s));
+        }
+        return Ok(region);
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:#06x}-{:#06x}", self.start, self.end);
+    }
+}
+
+/// Register and RAM contents the CPU starts with after a reset, instead of zeros
+/// (`run --profile`, `--reset-sp`, `--reset-register`, `--ram-fill`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub(crate) struct StartupState {
+    pub(crate) sp: u16,
+    /// r4 - r15
+    pub(crate) registers: [u16; 12],
+    /// byte the profile's RAM is filled with at power-up
+    pub(crate) ram_fill: u8,
+}
+
+/// Value of one of r4 - r15 after a reset, for `ComputerBuilder::reset_register` and
+/// `run --reset-register` (`rN=VALUE`, N from 4 to 15)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RegisterReset {
+    pub(crate) register: u8,
+    pub(crate) value: u16,
+}
+
+impl RegisterReset {
+    /// Only r4 - r15 have a reset value, the others are the CPU's
+    pub fn new(register: u8, value: u16) -> Result<RegisterReset, String> {
+        if !(4..=15).contains(&register) {
+            return Err(format!("'r{}' is not one of r4-r15 (use --reset-sp for the stack pointer)", register));
+        }
+        return Ok(RegisterReset { register, value });
+    }
+}
+
+impl FromStr for RegisterReset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (register, value) = s.split_once('=').ok_or(format!("'{}' is not rN=VALUE", s))?;
+        let number: u8 = register.trim().strip_prefix(['r', 'R']).and_then(|n| n.parse().ok())
+            .ok_or(format!("'{}' is not one of r4-r15 (use --reset-sp for the stack pointer)", register))?;
+        return RegisterReset::new(number, parse_u16(value)?)
+            .map_err(|_| format!("'{}' is not one of r4-r15 (use --reset-sp for the stack pointer)", register));
+    }
+}
+
+impl fmt::Display for RegisterReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "r{}={:#06x}", self.register, self.value);
+    }
+}
+
+/// An interrupt the emulator's devices raise, at the vector emulator_devices.txt gives it unless a
+/// profile moves it (`DeviceProfile::with_vector`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Interrupt {
+    Port1,
+    Port2,
+    UartRx,
+    Console,
+    Comparator,
+    /// watchdog interval timer
+    Watchdog,
+    /// MPU violations and the supply monitor
+    SystemNmi,
+    /// oscillator faults
+    UserNmi,
+}
+
+impl Interrupt {
+    pub fn default_vector(&self) -> u16 {
+        return match self {
+            Interrupt::Port1 => gpio::PORT1_VECTOR,
+            Interrupt::Port2 => gpio::PORT2_VECTOR,
+            Interrupt::UartRx => uart::UART_RX_VECTOR,
+            Interrupt::Console => console::CONSOLE_VECTOR,
+            Interrupt::Comparator => comparator::COMPARATOR_VECTOR,
+            Interrupt::Watchdog => wdt::WDT_VECTOR,
+            Interrupt::SystemNmi => mpu::SYSNMI_VECTOR,
+            Interrupt::UserNmi => cs::UNMI_VECTOR,
+        };
+    }
+}
+
+/// Memory layout and vector table of a part, built in (`Profile`) or defined by a library user and
+/// given to `ComputerBuilder::profile`. The emulator's devices are the same for every part.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct DeviceProfile {
+    pub name: String,
+    /// instructions must never be fetched from these (peripheral space and data RAM), the CPU
+    /// faults instead
+    pub no_execute: Vec<Region>,
+    /// memory that loses its contents when the power goes away, filled at power-up
+    pub ram: Vec<Region>,
+    /// memory kept across a power cycle by the backup supply, even inside `ram`
+    pub backup_memory: Vec<Region>,
+    /// peripheral registers, reads no emulated device answers are diagnosed (`run --check unimplemented`)
+    pub peripherals: Vec<Region>,
+    /// written through the flash controller only (`run --check flash-write`)
+    pub flash: Vec<Region>,
+    /// ROM, data writes are dropped and diagnosed (`run --check read-only-write`)
+    pub read_only: Vec<Region>,
+    /// the watchdog comes out of a reset held instead of running as on the part, for firmware that
+    /// never stops or services it (`run --hold-watchdog`)
+    pub watchdog_held: bool,
+    /// interrupts the part has at another slot of the vector table than the emulator's default
+    pub vectors: Vec<(Interrupt, u16)>,
+}
+
+impl DeviceProfile {
+    /// A part without restrictions, add regions to it
+    pub fn new(name: &str) -> DeviceProfile {
+        return DeviceProfile { name: name.to_string(), ..DeviceProfile::default() };
+    }
+
+    pub fn with_no_execute(mut self, region: Region) -> DeviceProfile {
+        self.no_execute.push(region);
+        return self;
+    }
+
+    pub fn with_ram(mut self, region: Region) -> DeviceProfile {
+        self.ram.push(region);
+        return self;
+    }
+
+    pub fn with_backup_memory(mut self, region: Region) -> DeviceProfile {
+        self.backup_memory.push(region);
+        return self;
+    }
+
+    pub fn with_peripherals(mut self, region: Region) -> DeviceProfile {
+        self.peripherals.push(region);
+        return self;
+    }
+
+    pub fn with_flash(mut self, region: Region) -> DeviceProfile {
+        self.flash.push(region);
+        return self;
+    }
+
+    pub fn with_read_only(mut self, region: Region) -> DeviceProfile {
+        self.read_only.push(region);
+        return self;
+    }
+
+    pub fn with_watchdog_held(mut self) -> DeviceProfile {
+        self.watchdog_held = true;
+        return self;
+    }
+
+    /// Take `interrupt` through the vector at `vector`, a slot of 0xffe0 - 0xfffc (0xfffe is reset)
+    pub fn with_vector(mut self, interrupt: Interrupt, vector: u16) -> Result<DeviceProfile, String> {
+        if !(VECTOR_TABLE..RESET_VECTOR).contains(&vector) || vector & 1 != 0 {
+            return Err(format!("{:#06x} is not an interrupt vector (0xffe0 - 0xfffc, even)", vector));
+        }
+        self.vectors.retain(|(moved, _)| *moved != interrupt);
+        self.vectors.push((interrupt, vector));
+        return Ok(self);
+    }
+
+    /// Where each interrupt the profile moves now goes, by its default vector
+    pub(crate) fn vector_remap(&self) -> Vec<(u16, u16)> {
+        return self.vectors.iter().map(|(interrupt, vector)| (interrupt.default_vector(), *vector)).collect();
+    }
+
+    /// What the CPU starts with: the stack at the top of the last RAM region, so firmware without
+    /// C startup code can push right away, everything else zero
+    pub(crate) fn startup(&self) -> StartupState {
+        let sp: u16 = self.ram.last().map(|ram| ram.end.wrapping_add(1)).unwrap_or(0);
+        return StartupState { sp, ..StartupState::default() };
+    }
+}
+
+/// Memory layout of the part being emulated
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum Profile {
+    /// No restrictions, code may run from anywhere (programs are loaded at 0x4400)
+    Generic,
+    /// MSP430G2553: peripherals 0x0000-0x01ff, 512 B RAM at 0x0200
+    G2553,
+    /// MSP430FR5969: peripherals 0x0000-0x0fff, 2 KB RAM at 0x1c00
+    Fr5969,
+    /// MSP430FR4133: peripherals 0x0000-0x0fff with 32 B backup memory at 0x0660, 2 KB RAM at 0x2000
+    Fr4133,
+}
+
+impl Profile {
+    /// Regions instructions must never be fetched from (peripheral space and data RAM)
+    pub(crate) fn no_execute(&self) -> Vec<Region> {
+        return match self {
+            Profile::Generic => vec![],
+            Profile::G2553 => vec![
+                Region { start: 0x0000, end: 0x01ff },
+                Region { start: 0x0200, end: 0x03ff },
+            ],
+            Profile::Fr5969 => vec![
+                Region { start: 0x0000, end: 0x0fff },
+                Region { start: 0x1c00, end: 0x23ff },
+            ],
+            Profile::Fr4133 => vec![
+                Region { start: 0x0000, end: 0x0fff },
+                Region { start: 0x2000, end: 0x27ff },
+            ],
+        };
+    }
+
+    /// Memory that loses its contents when the power goes away
+    pub(crate) fn ram(&self) -> Vec<Region> {
+        return match self {
+            Profile::Generic => vec![],
+            Profile::G2553 => vec![Region { start: 0x0200, end: 0x03ff }],
+            Profile::Fr5969 => vec![Region { start: 0x1c00, end: 0x23ff }],
+            Profile::Fr4133 => vec![Region { start: 0x2000, end: 0x27ff }],
+        };
+    }
+
+    /// Memory kept across a power cycle by the backup supply, even inside `ram`
+    pub(crate) fn backup_memory(&self) -> Vec<Region> {
+        return match self {
+            Profile::Fr4133 => vec![Region { start: 0x0660, end: 0x067f }],
+            _ => vec![],
+        };
+    }
+
+    /// Peripheral registers, backup memory inside them aside
+    pub(crate) fn peripherals(&self) -> Vec<Region> {
+        return match self {
+            Profile::Generic => vec![],
+            Profile::G2553 => vec![Region { start: 0x0000, end: 0x01ff }],
+            Profile::Fr5969 | Profile::Fr4133 => vec![Region { start: 0x0000, end: 0x0fff }],
+        };
+    }
+
+    /// Main and information flash, the FR parts have FRAM instead
+    pub(crate) fn flash(&self) -> Vec<Region> {
+        return match self {
+            Profile::G2553 => vec![
+                Region { start: 0x1000, end: 0x10ff },
+                Region { start: 0xc000, end: 0xffff },
+            ],
+            _ => vec![],
+        };
+    }
+
+    /// The bootstrap loader's ROM
+    pub(crate) fn read_only(&self) -> Vec<Region> {
+        return match self {
+            Profile::Generic => vec![],
+            Profile::G2553 => vec![Region { start: 0x0c00, end: 0x0fff }],
+            Profile::Fr5969 | Profile::Fr4133 => vec![Region { start: 0x1000, end: 0x17ff }],
+        };
+    }
+
+    pub(crate) fn device(&self) -> DeviceProfile {
+        return DeviceProfile {
+            name: self.to_possible_value().expect("No skipped variants").get_name().to_string(),
+            no_execute: self.no_execute(),
+            ram: self.ram(),
+            backup_memory: self.backup_memory(),
+            peripherals: self.peripherals(),
+            flash: self.flash(),
+            read_only: self.read_only(),
+            watchdog_held: false,
+            vectors: Vec::new(),
+        };
+    }
+}
@@ -0,0 +1,224 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use crate::image::Symbol;
+
+/// A call (or interrupt) that hasn't returned yet
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    callee: u16,
+    call_site: u16,
+    /// where the return address is, the RET popping it ends the call
+    slot: u16,
+    start: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+struct Cost {
+    calls: u64,
+    cycles: u64,
+}
+
+/// One function's share of the run, from `Profiler::functions`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct FunctionCost {
+    pub(crate) name: String,
+    pub(crate) calls: u64,
+    /// cycles between entering and returning, including the functions it called
+    pub(crate) inclusive: u64,
+    /// cycles spent on its own instructions
+    pub(crate) exclusive: u64,
+}
+
+/// Attributes cycles to firmware functions (`run --function-profile`, `run --callgrind`).
+/// Cycles are counted per instruction address and calls are tracked by the stack slot of their
+/// return address, symbols only come in for the report so loads don't disturb a profile.
+#[derive(Clone)]
+pub(crate) struct Profiler {
+    /// cycles spent at each instruction, indexed by word address
+    cycles: Box<[u64]>,
+    frames: Vec<Frame>,
+    /// calls by entry address, the cycles of the outermost activation when recursing
+    functions: HashMap<u16, Cost>,
+    /// calls by (call site, entry address)
+    edges: HashMap<(u16, u16), Cost>,
+    /// the first instruction profiled, its function gets the whole run
+    root: Option<u16>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Profiler {
+        return Profiler {
+            cycles: vec![0; 0x8000].into_boxed_slice(),
+            frames: Vec::new(),
+            functions: HashMap::new(),
+            edges: HashMap::new(),
+            root: None,
+        };
+    }
+
+    /// The instruction at `pc` took `cycles`
+    #[inline]
+    pub(crate) fn retired(&mut self, pc: u16, cycles: u64) {
+        self.cycles[(pc >> 1) as usize] += cycles;
+        self.root.get_or_insert(pc);
+    }
+
+    /// The CALL at `call_site` (or an interrupt there) stored its return address at `slot` and
+    /// entered `callee`, after the CALL's own cycles
+    pub(crate) fn called(&mut self, call_site: u16, callee: u16, slot: u16, now: u64) {
+        self.unwind(slot, now); // calls the stack was reused over never returned
+        self.frames.push(Frame { callee, call_site, slot, start: now });
+    }
+
+    /// A RET or RETI popped the return address at `slot`
+    pub(crate) fn returned(&mut self, slot: u16, now: u64) {
+        self.unwind(slot, now);
+    }
+
+    /// Everything on the stack is gone, after a reset
+    pub(crate) fn abandon(&mut self, now: u64) {
+        self.unwind(u16::MAX, now);
+    }
+
+    fn unwind(&mut self, slot: u16, now: u64) {
+        while let Some(frame) = self.frames.last().copied().filter(|frame| frame.slot <= slot) {
+            self.frames.pop();
+            let cycles: u64 = now - frame.start;
+            let edge: &mut Cost = self.edges.entry((frame.call_site, frame.callee)).or_default();
+            edge.calls += 1;
+            edge.cycles += cycles;
+            let recursing: bool = self.frames.iter().any(|f| f.callee == frame.callee);
+            let function: &mut Cost = self.functions.entry(frame.callee).or_default();
+            function.calls += 1;
+            if !recursing {
+                function.cycles += cycles;
+            }
+        }
+    }
+
+    /// The profile so far as if every call returned `now`, most exclusive cycles first
+    pub(crate) fn functions(&self, symbols: &[Symbol], now: u64) -> Vec<FunctionCost> {
+        let profile: Profiler = self.finished(now);
+        let functions: Functions = Functions::new(symbols);
+        let mut costs: HashMap<&str, FunctionCost> = HashMap::new();
+        for (index, cycles) in profile.cycles.iter().enumerate().filter(|(_, cycles)| **cycles != 0) {
+            cost(&mut costs, functions.name((index << 1) as u16)).exclusive += cycles;
+        }
+        for (entry, function) in &profile.functions {
+            let c: &mut FunctionCost = cost(&mut costs, functions.name(*entry));
+            c.calls += function.calls;
+            c.inclusive += function.cycles;
+        }
+        if let Some(root) = profile.root {
+            let total: u64 = profile.cycles.iter().sum();
+            let c: &mut FunctionCost = cost(&mut costs, functions.name(root));
+            c.inclusive = c.inclusive.max(total);
+        }
+        let mut sorted: Vec<FunctionCost> = costs.into_values().map(|mut c| {
+            c.inclusive = c.inclusive.max(c.exclusive); // reached by jumps only, e.g. tail calls
+            return c;
+        }).collect();
+        sorted.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(b.inclusive.cmp(&a.inclusive)).then(a.name.cmp(&b.name)));
+        return sorted;
+    }
+
+    /// Table of `functions` for the terminal
+    pub(crate) fn report(&self, symbols: &[Symbol], now: u64) -> String {
+        let functions: Vec<FunctionCost> = self.functions(symbols, now);
+        let total: u64 = self.cycles.iter().sum::<u64>().max(1);
+        let mut out: String = format!("{:>12} {:>6} {:>12} {:>6} {:>8}  function\n",
+                                      "exclusive", "%", "inclusive", "%", "calls");
+        for f in &functions {
+            out.push_str(&format!("{:>12} {:>5.1}% {:>12} {:>5.1}% {:>8}  {}\n",
+                                  f.exclusive, f.exclusive as f64 * 100.0 / total as f64,
+                                  f.inclusive, f.inclusive as f64 * 100.0 / total as f64, f.calls, f.name));
+        }
+        return out;
+    }
+
+    /// The profile in callgrind format for KCachegrind and friends, positions are instruction addresses
+    pub(crate) fn callgrind(&self, symbols: &[Symbol], now: u64) -> String {
+        let profile: Profiler = self.finished(now);
+        let functions: Functions = Functions::new(symbols);
+        // cost lines by function, in the order functions first show up
+        let mut by_function: Vec<(&str, Vec<String>)> = Vec::new();
+        for (index, cycles) in profile.cycles.iter().enumerate().filter(|(_, cycles)| **cycles != 0) {
+            let address: u16 = (index << 1) as u16;
+            lines(&mut by_function, functions.name(address)).push(format!("{:#06x} {}", address, cycles));
+        }
+        let mut edges: Vec<(&(u16, u16), &Cost)> = profile.edges.iter().collect();
+        edges.sort_by_key(|(edge, _)| **edge);
+        for ((call_site, callee), cost) in edges {
+            lines(&mut by_function, functions.name(*call_site)).push(format!("cfn={}\ncalls={} {:#06x}\n{:#06x} {}",
+                                                           functions.name(*callee), cost.calls, callee, call_site, cost.cycles));
+        }
+        let mut out: String = format!("# callgrind format\nversion: 1\ncreator: msp430_rust\npositions: instr\n\
+                                       events: Cycles\nsummary: {}\n", profile.cycles.iter().sum::<u64>());
+        for (name, lines) in by_function {
+            out.push_str(&format!("\nfn={}\n", name));
+            for line in lines {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        return out;
+    }
+
+    fn finished(&self, now: u64) -> Profiler {
+        let mut profile: Profiler = self.clone();
+        profile.abandon(now);
+        return profile;
+    }
+}
+
+fn cost<'a, 'n>(costs: &'a mut HashMap<&'n str, FunctionCost>, name: &'n str) -> &'a mut FunctionCost {
+    return costs.entry(name).or_insert_with(|| FunctionCost { name: name.to_string(), calls: 0, inclusive: 0, exclusive: 0 });
+}
+
+fn lines<'a, 'n>(by_function: &'a mut Vec<(&'n str, Vec<String>)>, name: &'n str) -> &'a mut Vec<String> {
+    let index: usize = match by_function.iter().position(|(n, _)| *n == name) {
+        Some(index) => index,
+        None => {
+            by_function.push((name, Vec::new()));
+            by_function.len() - 1
+        },
+    };
+    return &mut by_function[index].1;
+}
+
+/// Symbols by address, an address belongs to the closest symbol at or below it
+struct Functions<'a> {
+    sorted: Vec<&'a Symbol>,
+}
+
+const UNKNOWN: &str = "(unknown)";
+
+impl<'a> Functions<'a> {
+    fn new(symbols: &'a [Symbol]) -> Functions<'a> {
+        let mut sorted: Vec<&Symbol> = symbols.iter().collect();
+        sorted.sort_by_key(|s| s.address);
+        return Functions { sorted };
+    }
+
+    fn name(&self, address: u16) -> &'a str {
+        let index: usize = self.sorted.partition_point(|s| s.address <= address);
+        return if index == 0 { UNKNOWN } else { &self.sorted[index - 1].name };
+    }
+}
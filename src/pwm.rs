@@ -0,0 +1,121 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::devices::gpio::{GpioDevice, PinId};
+
+/// Timing of the last complete period of a pin's output, in cycles
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct PwmMeasurement {
+    pub(crate) period: u64,
+    pub(crate) high: u64,
+    /// complete periods seen so far
+    pub(crate) periods: u64,
+    pub(crate) min_period: u64,
+    pub(crate) max_period: u64,
+}
+
+impl PwmMeasurement {
+    pub(crate) fn frequency_hz(&self, mclk_hz: u64) -> f64 {
+        return mclk_hz as f64 / self.period as f64;
+    }
+
+    /// Fraction of the period spent high, 0.0 - 1.0
+    pub(crate) fn duty(&self) -> f64 {
+        return self.high as f64 / self.period as f64;
+    }
+}
+
+#[derive(Clone)]
+struct PinTrack {
+    pin: PinId,
+    level: Option<bool>,
+    last_rise: Option<u64>,
+    last_fall: Option<u64>,
+    measurement: Option<PwmMeasurement>,
+}
+
+/// Measures frequency and duty cycle of output pins from their edges (`run --pwm`), whatever
+/// drives them, so PWM firmware can be checked numerically
+#[derive(Clone)]
+pub(crate) struct PwmAnalyzer {
+    pins: Vec<PinTrack>,
+}
+
+impl PwmAnalyzer {
+    pub(crate) fn new(pins: &[PinId]) -> PwmAnalyzer {
+        return PwmAnalyzer {
+            pins: pins.iter().map(|pin| PinTrack { pin: *pin, level: None, last_rise: None, last_fall: None, measurement: None })
+                .collect(),
+        };
+    }
+
+    /// Forget everything measured, the pins stay
+    pub(crate) fn reset(&mut self) {
+        *self = PwmAnalyzer::new(&self.pins.iter().map(|t| t.pin).collect::<Vec<PinId>>());
+    }
+
+    /// Look at the outputs after an instruction ran, `cycle` is the current cycle count
+    #[inline]
+    pub(crate) fn sample(&mut self, cycle: u64, gpio: &GpioDevice) {
+        for track in self.pins.iter_mut() {
+            let level: Option<bool> = gpio.output(track.pin);
+            match (track.level, level) {
+                (Some(false), Some(true)) => {
+                    if let (Some(rise), Some(fall)) = (track.last_rise, track.last_fall) {
+                        if fall > rise {
+                            let period: u64 = cycle - rise;
+                            track.measurement = Some(match track.measurement {
+                                Some(m) => PwmMeasurement {
+                                    period,
+                                    high: fall - rise,
+                                    periods: m.periods + 1,
+                                    min_period: m.min_period.min(period),
+                                    max_period: m.max_period.max(period),
+                                },
+                                None => PwmMeasurement { period, high: fall - rise, periods: 1, min_period: period, max_period: period },
+                            });
+                        }
+                    }
+                    track.last_rise = Some(cycle);
+                },
+                (Some(true), Some(false)) => track.last_fall = Some(cycle),
+                _ => {},
+            }
+            track.level = level;
+        }
+    }
+
+    /// `None` if the pin isn't analyzed or hasn't completed a period yet
+    pub(crate) fn measurement(&self, pin: PinId) -> Option<PwmMeasurement> {
+        return self.pins.iter().find(|t| t.pin == pin).and_then(|t| t.measurement);
+    }
+
+    /// One line per pin, e.g. `P1.2: 1000.00 Hz, duty 25.0% (period 1000 cycles, 40 periods, 998 - 1002)`
+    pub(crate) fn report(&self, mclk_hz: u64) -> String {
+        let mut out: String = String::new();
+        for track in &self.pins {
+            match track.measurement {
+                Some(m) => out.push_str(&format!("{}: {:.2} Hz, duty {:.1}% (period {} cycles, {} periods, {} - {})\n",
+                                                 track.pin, m.frequency_hz(mclk_hz), m.duty() * 100.0,
+                                                 m.period, m.periods, m.min_period, m.max_period)),
+                None => out.push_str(&format!("{}: no complete period\n", track.pin)),
+            }
+        }
+        return out;
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use crate::devices::Devices;
+use crate::devices::radio::Packet;
+use crate::worker::QuantumModel;
+
+/// The air between radios in the same process: every packet sent reaches all the other radios
+#[allow(dead_code)]
+pub(crate) fn broadcast(devices: &mut [&mut Devices]) {
+    let sent: Vec<(usize, Vec<Packet>)> = devices.iter_mut().enumerate()
+        .filter_map(|(index, d)| d.spi.radio.as_mut().map(|radio| (index, radio.take_outgoing())))
+        .collect();
+    for (sender, packets) in sent {
+        for (index, d) in devices.iter_mut().enumerate() {
+            if index != sender {
+                packets.iter().for_each(|packet| d.radio_receive(packet));
+            }
+        }
+    }
+}
+
+/// The air between emulator instances: packets go to a broker (`radio-broker`) as UDP datagrams,
+/// which passes them on to every other instance that ever sent it one
+pub(crate) struct UdpRadioLink {
+    socket: UdpSocket,
+}
+
+impl UdpRadioLink {
+    pub(crate) fn connect(broker: &str) -> io::Result<UdpRadioLink> {
+        let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(broker)?;
+        socket.set_nonblocking(true)?;
+        // an empty datagram registers us, so packets reach this radio before it sent any
+        socket.send(&[])?;
+        return Ok(UdpRadioLink { socket });
+    }
+
+    /// Send what the radio transmitted and return what arrived, without blocking
+    pub(crate) fn pump(&mut self, outgoing: &[Packet]) -> io::Result<Vec<Packet>> {
+        for packet in outgoing {
+            self.socket.send(&packet.encode())?;
+        }
+        let mut received: Vec<Packet> = Vec::new();
+        let mut buf: [u8; 64] = [0; 64];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(n) => received.extend(Packet::decode(&buf[..n])),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(received),
+                // the broker isn't up (yet), packets sent meanwhile are lost like out of range
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => return Ok(received),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl QuantumModel for UdpRadioLink {
+    type Input = Vec<Packet>;
+    type Output = io::Result<Vec<Packet>>;
+
+    fn quantum(&mut self, _cycle: u64, outgoing: Vec<Packet>) -> Self::Output {
+        return self.pump(&outgoing);
+    }
+}
+
+/// Relays packets between instances until an error, `radio-broker ADDRESS`
+pub(crate) fn run_broker(address: &str) -> io::Result<()> {
+    let socket: UdpSocket = UdpSocket::bind(address)?;
+    let mut nodes: HashSet<SocketAddr> = HashSet::new();
+    let mut buf: [u8; 64] = [0; 64];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf)?;
+        if nodes.insert(from) {
+            println!("Radio node {} joined ({} nodes)", from, nodes.len());
+        }
+        if n == 0 {
+            continue;
+        }
+        for node in nodes.iter().filter(|&&node| node != from) {
+            // a node that went away only loses its packets
+            let _ = socket.send_to(&buf[..n], node);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use serde_json::{json, Value};
+
+/// One control command as a recorded run received it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct RecordedCommand {
+    /// instructions executed when it was handled
+    pub(crate) instructions: u64,
+    pub(crate) cycles: u64,
+    /// command area bytes, the command byte first
+    pub(crate) bytes: Vec<u8>,
+}
+
+/// Where a recorded run ended
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct RecordedEnd {
+    pub(crate) instructions: u64,
+    pub(crate) cycles: u64,
+    pub(crate) pc: u16,
+}
+
+/// Writes the commands a run handles as JSON lines (`run --record FILE`): first the run's arguments,
+/// then one line per command and, if the run ended cleanly, where it ended
+pub(crate) struct CommandRecorder {
+    out: BufWriter<File>,
+}
+
+impl CommandRecorder {
+    pub(crate) fn create(path: &str, args: &[String]) -> io::Result<CommandRecorder> {
+        let mut recorder = CommandRecorder { out: BufWriter::new(File::create(path)?) };
+        recorder.write_line(json!({"args": args}))?;
+        return Ok(recorder);
+    }
+
+    /// `description` is for people reading the file, replay goes by the bytes
+    pub(crate) fn record(&mut self, command: &RecordedCommand, description: &str) -> io::Result<()> {
+        let bytes: String = command.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        return self.write_line(json!({"instructions": command.instructions, "cycles": command.cycles,
+                                      "command": bytes, "description": description}));
+    }
+
+    pub(crate) fn finish(&mut self, end: RecordedEnd) -> io::Result<()> {
+        return self.write_line(json!({"end": end.instructions, "cycles": end.cycles, "pc": end.pc}));
+    }
+
+    /// Flushed right away, a crashed run still leaves everything up to the crash
+    fn write_line(&mut self, value: Value) -> io::Result<()> {
+        writeln!(self.out, "{}", value)?;
+        return self.out.flush();
+    }
+}
+
+/// What the replayed run loop does at a command check
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum ReplayStep {
+    /// handle this command now
+    Command(Vec<u8>),
+    /// no command at this point of the run
+    Wait,
+    /// the run went somewhere the recorded one didn't
+    Diverged(String),
+}
+
+/// A recorded command stream, fed back to the run loop in place of shared memory (`replay`)
+pub(crate) struct Recording {
+    /// `run` arguments of the recorded run, starting with "run"
+    pub(crate) args: Vec<String>,
+    commands: VecDeque<RecordedCommand>,
+    pub(crate) end: Option<RecordedEnd>,
+    replayed: usize,
+}
+
+impl Recording {
+    pub(crate) fn parse(text: &str) -> Result<Recording, String> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let parse_line = |(number, line): (usize, &str)| serde_json::from_str::<Value>(line)
+            .map_err(|e| format!("line {}: {}", number + 1, e)).map(|value| (number, value));
+        let (_, header) = parse_line(lines.next().ok_or("Empty recording")?)?;
+        let args: Vec<String> = header["args"].as_array().ok_or("line 1: expected the run arguments")?
+            .iter().map(|arg| arg.as_str().map(|s| s.to_string()).ok_or("line 1: arguments must be strings"))
+            .collect::<Result<Vec<String>, &str>>()?;
+        let mut recording = Recording { args, commands: VecDeque::new(), end: None, replayed: 0 };
+        for line in lines {
+            let (number, value) = parse_line(line)?;
+            let number: usize = number + 1;
+            if recording.end.is_some() {
+                return Err(format!("line {}: commands after the end of the run", number));
+            }
+            let field = |name: &str| value[name].as_u64().ok_or(format!("line {}: missing '{}'", number, name));
+            if value.get("end").is_some() {
+                let pc: u64 = field("pc")?;
+                recording.end = Some(RecordedEnd {
+                    instructions: field("end")?,
+                    cycles: field("cycles")?,
+                    pc: u16::try_from(pc).map_err(|_| format!("line {}: pc {} out of range", number, pc))?,
+                });
+                continue;
+            }
+            let hex: &str = value["command"].as_str().ok_or(format!("line {}: missing 'command'", number))?;
+            let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+                .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .filter(|bytes| !bytes.is_empty())
+                .ok_or(format!("line {}: command is not hex bytes", number))?;
+            let command = RecordedCommand { instructions: field("instructions")?, cycles: field("cycles")?, bytes };
+            if recording.commands.back().is_some_and(|previous| previous.instructions > command.instructions) {
+                return Err(format!("line {}: commands out of order", number));
+            }
+            recording.commands.push_back(command);
+        }
+        return Ok(recording);
+    }
+
+    pub(crate) fn load(path: &str) -> Result<Recording, String> {
+        let text: String = std::fs::read_to_string(path).map_err(|e| format!("'{}': {}", path, e))?;
+        return Recording::parse(&text).map_err(|e| format!("'{}': {}", path, e));
+    }
+
+    /// Commands are handled at the same instruction and cycle counts as in the recorded run, which
+    /// holds as long as the run is deterministic (the emulated time source, same images and seed).
+    /// The cycle count moves on its own while the CPU sleeps, the instruction count doesn't.
+    pub(crate) fn next(&mut self, instructions: u64, cycles: u64, stopped: bool) -> ReplayStep {
+        let Some(command) = self.commands.front() else {
+            return ReplayStep::Wait;
+        };
+        if command.instructions < instructions || (command.instructions > instructions && stopped) {
+            return ReplayStep::Diverged(format!("command {} was handled after {} instructions, replay is at {}{}",
+                                                self.replayed + 1, command.instructions, instructions,
+                                                if stopped {" and stopped"} else {""}));
+        }
+        if command.instructions > instructions || (command.cycles > cycles && !stopped) {
+            return ReplayStep::Wait;
+        }
+        if command.cycles != cycles {
+            return ReplayStep::Diverged(format!("command {} was handled at cycle {}, replay is at {}",
+                                                self.replayed + 1, command.cycles, cycles));
+        }
+        self.replayed += 1;
+        return ReplayStep::Command(self.commands.pop_front().expect("checked above").bytes);
+    }
+
+    /// Instruction count the next poll has to happen at, for a command or the end of the run
+    pub(crate) fn next_poll(&self) -> Option<u64> {
+        return self.commands.front().map(|c| c.instructions).or(self.end.map(|end| end.instructions));
+    }
+
+    /// All commands were handled and the run got as far as the recorded one (or can't go on)
+    pub(crate) fn finished(&self, instructions: u64, cycles: u64, stopped: bool) -> bool {
+        return self.commands.is_empty() && (stopped || self.end.is_none_or(|end| instructions > end.instructions
+            || (instructions == end.instructions && cycles >= end.cycles)));
+    }
+
+    pub(crate) fn replayed(&self) -> usize {
+        return self.replayed;
+    }
+}
@@ -0,0 +1,97 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Run-length coding for memory blocks sent through the command area (shared_memory_protocol.txt,
+// commands 31 and 32). Most of a device's memory is runs of 0x00 or 0xff, which this shrinks to
+// two bytes per 129. A control byte 0-127 is followed by that many plus one literal bytes, a
+// control byte 128-255 by one byte that repeats (control - 126) times, 2 to 129.
+
+const MAX_LITERALS: usize = 128;
+const MAX_RUN: usize = 129;
+
+/// Compress `data`, runs of two or more equal bytes are coded as runs
+pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut literals: Vec<u8> = Vec::new();
+    let mut i: usize = 0;
+    while i < data.len() {
+        let mut run: usize = 1;
+        while i + run < data.len() && run < MAX_RUN && data[i + run] == data[i] {
+            run += 1;
+        }
+        if run >= 2 {
+            flush_literals(&mut out, &mut literals);
+            out.push((run + 126) as u8);
+            out.push(data[i]);
+        } else {
+            literals.push(data[i]);
+            if literals.len() == MAX_LITERALS {
+                flush_literals(&mut out, &mut literals);
+            }
+        }
+        i += run;
+    }
+    flush_literals(&mut out, &mut literals);
+    return out;
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    if !literals.is_empty() {
+        out.push((literals.len() - 1) as u8);
+        out.append(literals);
+    }
+}
+
+/// Expand `data`, `None` if it ends in the middle of a literal block or run
+pub(crate) fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut i: usize = 0;
+    while i < data.len() {
+        let control: usize = data[i] as usize;
+        if control < MAX_LITERALS {
+            let literals = data.get(i + 1..i + 2 + control)?;
+            out.extend_from_slice(literals);
+            i += 2 + control;
+        } else {
+            let value: u8 = *data.get(i + 1)?;
+            out.extend(std::iter::repeat_n(value, control - 126));
+            i += 2;
+        }
+    }
+    return Some(out);
+}
+
+/// Compress `data` from the start until the output would exceed `limit` bytes.
+/// Returns the compressed bytes and how many input bytes they cover.
+pub(crate) fn encode_prefix(data: &[u8], limit: usize) -> (Vec<u8>, usize) {
+    let encoded: Vec<u8> = encode(data);
+    if encoded.len() <= limit {
+        return (encoded, data.len());
+    }
+    // the longest prefix that fits, the coded length grows with the prefix
+    let (mut fits, mut too_long): (usize, usize) = (0, data.len());
+    while too_long - fits > 1 {
+        let middle: usize = (fits + too_long) / 2;
+        if encode(&data[..middle]).len() <= limit {
+            fits = middle;
+        } else {
+            too_long = middle;
+        }
+    }
+    return (encode(&data[..fits]), fits);
+}
@@ -0,0 +1,112 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Plain messages, errors and warnings on stderr
+    Text,
+    /// One JSON object per line on stdout: time, level, event, message and event fields
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, clap::ValueEnum)]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// also every handled command
+    Debug,
+}
+
+impl LogLevel {
+    fn name(&self) -> &'static str {
+        return match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+    }
+}
+
+/// What the run loop reports: loads, handled commands, run mode changes and errors. Each record
+/// has an event name for filtering and fields that repeat the values in the message.
+pub(crate) struct RunLog {
+    format: LogFormat,
+    level: LogLevel,
+}
+
+impl RunLog {
+    pub(crate) fn new(format: LogFormat, level: LogLevel) -> RunLog {
+        return RunLog { format, level };
+    }
+
+    pub(crate) fn enabled(&self, level: LogLevel) -> bool {
+        return level <= self.level;
+    }
+
+    pub(crate) fn error(&self, event: &str, message: String, fields: &[(&str, Value)]) {
+        self.log(LogLevel::Error, event, message, fields);
+    }
+
+    pub(crate) fn warn(&self, event: &str, message: String, fields: &[(&str, Value)]) {
+        self.log(LogLevel::Warn, event, message, fields);
+    }
+
+    pub(crate) fn info(&self, event: &str, message: String, fields: &[(&str, Value)]) {
+        self.log(LogLevel::Info, event, message, fields);
+    }
+
+    pub(crate) fn debug(&self, event: &str, message: String, fields: &[(&str, Value)]) {
+        self.log(LogLevel::Debug, event, message, fields);
+    }
+
+    fn log(&self, level: LogLevel, event: &str, message: String, fields: &[(&str, Value)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let time: f64 = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        let line: String = self.format_line(time, level, event, &message, fields);
+        if self.format == LogFormat::Text && level <= LogLevel::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    pub(crate) fn format_line(&self, time: f64, level: LogLevel, event: &str, message: &str, fields: &[(&str, Value)]) -> String {
+        return match self.format {
+            LogFormat::Text => message.to_string(),
+            LogFormat::Json => {
+                let mut object: Map<String, Value> = Map::new();
+                // rounded to milliseconds, enough to order records and keeps lines short
+                object.insert("time".to_string(), Value::from((time * 1000.0).round() / 1000.0));
+                object.insert("level".to_string(), Value::from(level.name()));
+                object.insert("event".to_string(), Value::from(event));
+                object.insert("message".to_string(), Value::from(message));
+                for (name, value) in fields {
+                    object.insert(name.to_string(), value.clone());
+                }
+                Value::Object(object).to_string()
+            },
+        };
+    }
+}
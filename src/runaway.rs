@@ -0,0 +1,89 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// WDTCTL on the 2xx (G2553) and FR5xx parts, a write with the password and WDTCNTCL services the watchdog
+const WDTCTL_ADDRESSES: [u16; 2] = [0x0120, 0x015c];
+const WDTPW: u16 = 0x5a00;
+const WDTCNTCL: u16 = 0x0008;
+
+/// Halts firmware that stopped making progress: neither reached an instruction it never executed
+/// before nor serviced the watchdog within the budget (`--runaway-cycles`)
+#[derive(Clone)]
+pub(crate) struct RunawayDetector {
+    budget: u64,
+    /// one bit per word address, set once an instruction was fetched from it
+    visited: Box<[u64; 512]>,
+    /// instructions executed since the last progress
+    stalled: u64,
+    /// range of addresses executed since the last progress, where the firmware is stuck
+    low: u16,
+    high: u16,
+}
+
+impl RunawayDetector {
+    pub(crate) fn new(budget: u64) -> RunawayDetector {
+        return RunawayDetector {
+            budget,
+            visited: Box::new([0; 512]),
+            stalled: 0,
+            low: 0xffff,
+            high: 0,
+        };
+    }
+
+    pub(crate) fn budget(&self) -> u64 {
+        return self.budget;
+    }
+
+    /// Everything was new again after a reset
+    pub(crate) fn reset(&mut self) {
+        *self = RunawayDetector::new(self.budget);
+    }
+
+    fn progress(&mut self) {
+        self.stalled = 0;
+        self.low = 0xffff;
+        self.high = 0;
+    }
+
+    /// An instruction at `pc` is about to execute, returns the stuck address range (first, last)
+    /// once the budget is used up
+    #[inline]
+    pub(crate) fn fetch(&mut self, pc: u16) -> Option<(u16, u16)> {
+        let bit: usize = (pc >> 1) as usize;
+        if self.visited[bit / 64] & (1 << (bit % 64)) == 0 {
+            self.visited[bit / 64] |= 1 << (bit % 64);
+            self.progress();
+            return None;
+        }
+        self.low = self.low.min(pc);
+        self.high = self.high.max(pc);
+        self.stalled += 1;
+        if self.stalled > self.budget {
+            return Some((self.low, self.high));
+        }
+        return None;
+    }
+
+    #[inline]
+    pub(crate) fn write(&mut self, address: u16, value: u16) {
+        if WDTCTL_ADDRESSES.contains(&address) && value & 0xff00 == WDTPW && value & WDTCNTCL != 0 {
+            self.progress();
+        }
+    }
+}
@@ -0,0 +1,59 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+const MIN_BATCH: u64 = 1_000;
+const MAX_BATCH: u64 = 10_000_000;
+/// Batch size of a `--deterministic` run, polls come at the same instruction counts every run
+pub(crate) const FIXED_BATCH: u64 = 100_000;
+
+/// Picks how many instructions run between two command polls, so that polls (and with them
+/// command latency and shared memory syncs) come about every `target` whatever the host speed
+pub(crate) struct BatchSizer {
+    size: u64,
+    target: Duration,
+    adaptive: bool,
+}
+
+impl BatchSizer {
+    pub(crate) fn new(target: Duration) -> BatchSizer {
+        return BatchSizer { size: 10 * MIN_BATCH, target, adaptive: true };
+    }
+
+    /// Always `size`, whatever the host speed
+    pub(crate) fn fixed(size: u64) -> BatchSizer {
+        return BatchSizer { size, target: Duration::ZERO, adaptive: false };
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    /// `executed` instructions took `took`. Batches cut short (the emulator stopped, a replay
+    /// needed a poll) say little about the speed and are ignored.
+    pub(crate) fn update(&mut self, executed: u64, took: Duration) {
+        if !self.adaptive || executed < self.size / 2 || took.is_zero() {
+            return;
+        }
+        let ideal: f64 = executed as f64 * self.target.as_secs_f64() / took.as_secs_f64();
+        // halfway there, so one slow batch (the host was busy) doesn't swing it
+        let next: f64 = (self.size as f64 + ideal) / 2.0;
+        self.size = (next as u64).clamp(MIN_BATCH, MAX_BATCH);
+    }
+}
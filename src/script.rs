@@ -0,0 +1,179 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use crate::{expr, Computer, ResetKind, ShmemCommands};
+use crate::dump::DumpSpec;
+use crate::eem::{self, Trigger, TriggerKind};
+use crate::image::Symbol;
+
+/// One line of a startup script (`run --exec FILE`), see shared_memory_protocol.txt
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ScriptLine {
+    Load(String),
+    Overlay(String),
+    /// address and value expressions
+    Set(String, String),
+    /// fetch breakpoint at an address expression
+    Break(String),
+    Watch(String),
+    Seed(u64),
+    /// vector expression
+    Interrupt(String),
+    Run,
+    Step(u16),
+    Next,
+    Finish,
+    Dump(DumpSpec),
+    Reset(ResetKind),
+    /// encoder index, detents to turn, switch pressed or released
+    Encoder(u8, i16, Option<bool>),
+    /// button matrix index, row, column, pressed
+    Key(u8, u8, u8, bool),
+}
+
+impl ScriptLine {
+    fn parse(line: &str) -> Result<ScriptLine, String> {
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest: &str = rest.trim();
+        let required = |what: &str| if rest.is_empty() {Err(format!("'{}' needs {}", name, what))} else {Ok(rest.to_string())};
+        let none = |line: ScriptLine| if rest.is_empty() {Ok(line)} else {Err(format!("'{}' takes no arguments", name))};
+        return match name {
+            "load" => Ok(ScriptLine::Load(required("a path")?)),
+            "overlay" => Ok(ScriptLine::Overlay(required("a path")?)),
+            "set" => {
+                let (address, value) = rest.split_once('=').ok_or("'set' needs ADDRESS = VALUE")?;
+                Ok(ScriptLine::Set(address.trim().to_string(), value.trim().to_string()))
+            },
+            "break" => Ok(ScriptLine::Break(required("an address")?)),
+            "watch" => Ok(ScriptLine::Watch(required("an expression")?)),
+            "seed" => Ok(ScriptLine::Seed(required("a seed")?.parse().map_err(|_| format!("'{}' is not a seed", rest))?)),
+            "interrupt" => Ok(ScriptLine::Interrupt(required("a vector")?)),
+            "run" => none(ScriptLine::Run),
+            "step" if rest.is_empty() => Ok(ScriptLine::Step(1)),
+            "step" => Ok(ScriptLine::Step(rest.parse().ok().filter(|n| *n > 0).ok_or(format!("'{}' is not a step count", rest))?)),
+            "next" => none(ScriptLine::Next),
+            "finish" => none(ScriptLine::Finish),
+            "dump" => Ok(ScriptLine::Dump(required("START-END:FORMAT:PATH")?.parse()?)),
+            "reset" => match rest {
+                "" | "bor" => Ok(ScriptLine::Reset(ResetKind::Bor)),
+                "puc" => Ok(ScriptLine::Reset(ResetKind::Puc)),
+                _ => Err(format!("'{}' is not a reset kind (bor, puc)", rest)),
+            },
+            "encoder" => {
+                let (index, action) = rest.split_once(char::is_whitespace).ok_or("'encoder' needs INDEX DETENTS|press|release")?;
+                let index: u8 = index.parse().map_err(|_| format!("'{}' is not an encoder index", index))?;
+                Ok(match action.trim() {
+                    "press" => ScriptLine::Encoder(index, 0, Some(true)),
+                    "release" => ScriptLine::Encoder(index, 0, Some(false)),
+                    detents => ScriptLine::Encoder(index, detents.parse().map_err(|_| format!("'{}' is not a number of detents", detents))?, None),
+                })
+            },
+            "key" => {
+                let words: Vec<&str> = rest.split_whitespace().collect();
+                let [index, row, column, action] = words[..] else {
+                    return Err("'key' needs INDEX ROW COLUMN press|release".to_string());
+                };
+                let number = |s: &str| s.parse::<u8>().map_err(|_| format!("'{}' is not a number", s));
+                let pressed: bool = match action {
+                    "press" => true,
+                    "release" => false,
+                    _ => return Err(format!("'{}' is not press or release", action)),
+                };
+                Ok(ScriptLine::Key(number(index)?, number(row)?, number(column)?, pressed))
+            },
+            _ => Err(format!("unknown command '{}'", name)),
+        };
+    }
+}
+
+/// Control commands read from a file and handled before any from the frontend. Each command
+/// waits until the emulator is stopped, so the lines after `run` run once a breakpoint hits, the
+/// way GDB's `-x` scripts do.
+pub(crate) struct Script {
+    lines: VecDeque<(usize, ScriptLine)>,
+    /// commands of the current line not handed out yet
+    pending: VecDeque<ShmemCommands>,
+    breakpoints: usize,
+}
+
+impl Script {
+    /// Blank lines and lines starting with '#' are skipped
+    pub(crate) fn parse(text: &str) -> Result<Script, String> {
+        let mut lines: VecDeque<(usize, ScriptLine)> = VecDeque::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            lines.push_back((number + 1, ScriptLine::parse(line).map_err(|e| format!("line {}: {}", number + 1, e))?));
+        }
+        return Ok(Script { lines, pending: VecDeque::new(), breakpoints: 0 });
+    }
+
+    pub(crate) fn load(path: &str) -> Result<Script, String> {
+        let text: String = std::fs::read_to_string(path).map_err(|e| format!("'{}': {}", path, e))?;
+        return Script::parse(&text).map_err(|e| format!("'{}': {}", path, e));
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        return self.lines.is_empty() && self.pending.is_empty();
+    }
+
+    /// The next command to handle, `None` once the script is done. Expressions are evaluated
+    /// now, with the symbols of what the lines before loaded.
+    pub(crate) fn next(&mut self, c: &Computer, symbols: &[Symbol]) -> Option<Result<ShmemCommands, String>> {
+        if self.pending.is_empty() {
+            let (number, line) = self.lines.pop_front()?;
+            match self.expand(line, c, symbols) {
+                Ok(commands) => self.pending.extend(commands),
+                Err(e) => return Some(Err(format!("line {}: {}", number, e))),
+            }
+        }
+        return self.pending.pop_front().map(Ok);
+    }
+
+    fn expand(&mut self, line: ScriptLine, c: &Computer, symbols: &[Symbol]) -> Result<Vec<ShmemCommands>, String> {
+        let eval = |s: &str| expr::parse(s, symbols).map(|e| e.eval(c)).map_err(|e| format!("'{}': {}", s, e));
+        return Ok(match line {
+            ScriptLine::Load(path) => vec![ShmemCommands::LoadFile(path)],
+            ScriptLine::Overlay(path) => vec![ShmemCommands::OverlayFile(path)],
+            ScriptLine::Set(address, value) => vec![ShmemCommands::SetMem(eval(&address)?, eval(&value)?)],
+            ScriptLine::Break(address) => {
+                if self.breakpoints == eem::BREAKPOINT_COUNT {
+                    return Err(format!("at most {} breakpoints", eem::BREAKPOINT_COUNT));
+                }
+                let index: u8 = self.breakpoints as u8;
+                self.breakpoints += 1;
+                let trigger = Trigger { kind: TriggerKind::Fetch, address: eval(&address)?, address_mask: 0, data: None };
+                vec![ShmemCommands::SetTrigger(index, Some(trigger)), ShmemCommands::SetBreakpoint(index, 1 << index)]
+            },
+            ScriptLine::Watch(expression) => vec![ShmemCommands::AddWatch(expression)],
+            ScriptLine::Seed(seed) => vec![ShmemCommands::Seed(seed)],
+            ScriptLine::Interrupt(vector) => vec![ShmemCommands::Interrupt(eval(&vector)?)],
+            ScriptLine::Run => vec![ShmemCommands::Run],
+            ScriptLine::Step(n) => vec![ShmemCommands::Step(n)],
+            ScriptLine::Next => vec![ShmemCommands::StepOver],
+            ScriptLine::Finish => vec![ShmemCommands::StepOut],
+            ScriptLine::Dump(spec) => vec![ShmemCommands::Dump(Some(spec.format), spec.region, spec.path)],
+            ScriptLine::Reset(kind) => vec![ShmemCommands::Reset(kind)],
+            ScriptLine::Encoder(index, detents, switch) => vec![ShmemCommands::Encoder(index, detents, switch)],
+            ScriptLine::Key(index, row, column, pressed) => vec![ShmemCommands::Key(index, row, column, pressed)],
+        });
+    }
+}
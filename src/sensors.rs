@@ -0,0 +1,153 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::dht::{Dht, DhtKind};
+use crate::onewire::{Ds18b20, OneWireBus};
+
+const DEFAULT_CELSIUS: f64 = 25.0;
+const DEFAULT_HUMIDITY: f64 = 50.0;
+
+/// Emulated time in µs
+pub(crate) fn micros(cycles: u64, mclk_hz: u64) -> u64 {
+    return (cycles as u128 * 1_000_000 / mclk_hz as u128) as u64;
+}
+
+/// Cycles in `us` µs
+pub(crate) fn cycles_for(us: u64, mclk_hz: u64) -> u64 {
+    return (us as u128 * mclk_hz as u128 / 1_000_000) as u64;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum SensorKind {
+    Ds18b20,
+    Dht(DhtKind),
+}
+
+/// `run --sensor KIND:PIN[:VALUES]`: `ds18b20:P1.4[:CELSIUS]`, `dht22:P1.5[:CELSIUS[:HUMIDITY]]`
+/// or the same for `dht11`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct SensorSpec {
+    pub(crate) kind: SensorKind,
+    pub(crate) pin: PinId,
+    pub(crate) celsius: f64,
+    pub(crate) humidity: f64,
+}
+
+impl FromStr for SensorSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let kind: SensorKind = match fields[0].to_ascii_lowercase().as_str() {
+            "ds18b20" => SensorKind::Ds18b20,
+            "dht11" => SensorKind::Dht(DhtKind::Dht11),
+            "dht22" | "am2302" => SensorKind::Dht(DhtKind::Dht22),
+            other => return Err(format!("Unknown sensor '{}' (ds18b20, dht11 or dht22)", other)),
+        };
+        let values: usize = if kind == SensorKind::Ds18b20 {1} else {2};
+        if fields.len() < 2 || fields.len() > 2 + values {
+            return Err(format!("'{}' is not {}", s, if values == 1 {"KIND:PIN[:CELSIUS]"} else {"KIND:PIN[:CELSIUS[:HUMIDITY]]"}));
+        }
+        let value = |index: usize, default: f64| -> Result<f64, String> {
+            return fields.get(index).map_or(Ok(default), |v| v.parse().map_err(|_| format!("Invalid value '{}' in '{}'", v, s)));
+        };
+        return Ok(SensorSpec { kind, pin: fields[1].parse()?, celsius: value(2, DEFAULT_CELSIUS)?, humidity: value(3, DEFAULT_HUMIDITY)? });
+    }
+}
+
+impl fmt::Display for SensorSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.kind {
+            SensorKind::Ds18b20 => write!(f, "ds18b20:{}:{}", self.pin, self.celsius),
+            SensorKind::Dht(kind) => write!(f, "{}:{}:{}:{}", kind.name().to_ascii_lowercase(), self.pin, self.celsius, self.humidity),
+        };
+    }
+}
+
+/// Something a sensor saw happen on its pin
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Transaction {
+    pub(crate) pin: PinId,
+    pub(crate) protocol: &'static str,
+    pub(crate) text: String,
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{} {}: {}", self.protocol, self.pin, self.text);
+    }
+}
+
+/// Sensors answering bit-banged protocols on GPIO pins (`run --sensor`), timed from the cycle
+/// count, and the transactions they decoded
+#[derive(Clone)]
+pub(crate) struct Sensors {
+    pub(crate) buses: Vec<OneWireBus>,
+    pub(crate) dht: Vec<Dht>,
+    transactions: Vec<Transaction>,
+}
+
+impl Sensors {
+    /// DS18B20s on the same pin share a bus, anything else needs a pin of its own
+    pub(crate) fn new(specs: &[SensorSpec]) -> Result<Sensors, String> {
+        let mut sensors: Sensors = Sensors { buses: Vec::new(), dht: Vec::new(), transactions: Vec::new() };
+        for (index, spec) in specs.iter().enumerate() {
+            let bus: Option<usize> = sensors.buses.iter().position(|b| b.pin == spec.pin);
+            let taken: bool = sensors.dht.iter().any(|d| d.pin == spec.pin);
+            match spec.kind {
+                SensorKind::Ds18b20 if !taken => {
+                    let device: Ds18b20 = Ds18b20::new(index as u64 + 1, spec.celsius);
+                    match bus {
+                        Some(bus) => sensors.buses[bus].devices.push(device),
+                        None => sensors.buses.push(OneWireBus::new(spec.pin, vec![device])),
+                    }
+                },
+                SensorKind::Dht(kind) if !taken && bus.is_none() => {
+                    sensors.dht.push(Dht::new(spec.pin, kind, spec.celsius, spec.humidity));
+                },
+                _ => return Err(format!("{} already has a sensor", spec.pin)),
+            }
+        }
+        return Ok(sensors);
+    }
+
+    /// Power cycle every sensor, transactions not taken yet are kept
+    pub(crate) fn reset(&mut self) {
+        self.buses.iter_mut().for_each(OneWireBus::reset);
+        self.dht.iter_mut().for_each(Dht::reset);
+    }
+
+    /// Look at the pins after an instruction ran and drive them, `cycle` is the current cycle count
+    #[inline]
+    pub(crate) fn sample(&mut self, cycle: u64, mclk_hz: u64, gpio: &mut GpioDevice) {
+        for bus in self.buses.iter_mut() {
+            bus.sample(cycle, mclk_hz, gpio, &mut self.transactions);
+        }
+        for dht in self.dht.iter_mut() {
+            dht.sample(cycle, mclk_hz, gpio, &mut self.transactions);
+        }
+    }
+
+    /// Transactions decoded since the last call
+    pub(crate) fn take(&mut self) -> Vec<Transaction> {
+        return std::mem::take(&mut self.transactions);
+    }
+}
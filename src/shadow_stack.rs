@@ -0,0 +1,73 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// A return address CALL or an interrupt pushed, at `slot` on the stack
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Frame {
+    slot: u16,
+    return_pc: u16,
+}
+
+/// What a RET or RETI was about to pop instead of the address its CALL or interrupt pushed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Corruption {
+    pub(crate) slot: u16,
+    pub(crate) expected: u16,
+    pub(crate) found: u16,
+}
+
+/// Emulator-side copy of the return addresses on the stack, so firmware smashing its stack halts
+/// at the RET that would jump into the weeds instead of somewhere after it (`--shadow-stack`).
+/// Frames the stack pointer moved past without returning (longjmp, a reset stack, a task switch)
+/// are forgotten rather than reported.
+#[derive(Clone, Default)]
+pub(crate) struct ShadowStack {
+    /// deepest (lowest slot) last
+    frames: Vec<Frame>,
+}
+
+impl ShadowStack {
+    pub(crate) fn new() -> ShadowStack {
+        return ShadowStack::default();
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.frames.clear();
+    }
+
+    /// A CALL or interrupt stored `return_pc` at `slot`
+    pub(crate) fn pushed(&mut self, slot: u16, return_pc: u16) {
+        while self.frames.last().is_some_and(|frame| frame.slot <= slot) {
+            self.frames.pop(); // abandoned, the stack was reused above them
+        }
+        self.frames.push(Frame { slot, return_pc });
+    }
+
+    /// A RET or RETI is about to pop `found` from `slot`
+    pub(crate) fn popping(&mut self, slot: u16, found: u16) -> Option<Corruption> {
+        while self.frames.last().is_some_and(|frame| frame.slot < slot) {
+            self.frames.pop(); // unwound without returning
+        }
+        let frame: Frame = *self.frames.last().filter(|frame| frame.slot == slot)?;
+        self.frames.pop();
+        if frame.return_pc != found {
+            return Some(Corruption { slot, expected: frame.return_pc, found });
+        }
+        return None;
+    }
+}
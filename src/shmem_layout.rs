@@ -0,0 +1,146 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::mem::{offset_of, size_of};
+use crate::metrics::METRICS_SIZE;
+
+// The shared memory block as a type, see shared_memory_protocol.txt. Everything is made of byte
+// arrays (multi-byte values are big-endian), so there is no padding and nothing depends on the
+// host's alignment. The offsets below are what both sides of the protocol use; the assertions
+// pin them to the documented addresses, so growing one area can't shift the others unnoticed.
+
+pub(crate) const EVENT_SIZE: usize = 8;
+pub(crate) const EVENT_SLOTS: usize = 127;
+pub(crate) const DIFF_CAPACITY: usize = 1023;
+pub(crate) const STATUS_NAME_SIZE: usize = 0xd8;
+pub(crate) const STATUS_FLAG_COUNT: usize = 9;
+pub(crate) const SEGMENT_SIZE: usize = 8;
+pub(crate) const SEGMENT_SLOTS: usize = 63;
+pub(crate) const DISASSEMBLY_SIZE: usize = 0x40;
+pub(crate) const DISASSEMBLY_SLOTS: usize = 7;
+
+#[repr(C)]
+pub(crate) struct ShmemLayout {
+    pub(crate) memory: [u8; 0x10000],
+    /// r0 - r15
+    pub(crate) registers: [[u8; 2]; 16],
+    /// command byte, then the command's arguments or reply
+    pub(crate) command: [u8; 0x400],
+    pub(crate) events: EventRing,
+    pub(crate) diff: DiffArea,
+    pub(crate) status: StatusBlock,
+    pub(crate) metrics: [u8; 64],
+    pub(crate) segments: SegmentList,
+    pub(crate) disassembly: DisassemblyArea,
+}
+
+#[repr(C)]
+pub(crate) struct EventRing {
+    /// events written so far, updated after the event itself
+    pub(crate) written: [u8; 4],
+    /// event id, old value, new value, PC
+    pub(crate) slots: [[u8; EVENT_SIZE]; EVENT_SLOTS],
+    _reserved: [u8; 4],
+}
+
+#[repr(C)]
+pub(crate) struct DiffArea {
+    /// 1 once a diff is ready, the frontend clears it
+    pub(crate) ready: u8,
+    _reserved: u8,
+    pub(crate) count: [u8; 2],
+    /// address, old byte, new byte
+    pub(crate) entries: [[u8; 4]; DIFF_CAPACITY],
+}
+
+#[repr(C)]
+pub(crate) struct StatusBlock {
+    pub(crate) heartbeat: [u8; 4],
+    pub(crate) pid: [u8; 4],
+    pub(crate) owner: [u8; 4],
+    pub(crate) run_mode: u8,
+    pub(crate) stop_reason: u8,
+    /// PC the loaded image started at
+    pub(crate) entry: [u8; 2],
+    pub(crate) generation: [u8; 4],
+    pub(crate) checksum: [u8; 4],
+    /// C-String
+    pub(crate) name: [u8; STATUS_NAME_SIZE],
+    /// one byte per SR bit 0-8, 1 = set
+    pub(crate) flags: [u8; STATUS_FLAG_COUNT],
+    /// command whose host I/O is in progress, 0 if none
+    pub(crate) busy: u8,
+    _reserved: [u8; 6],
+}
+
+#[repr(C)]
+pub(crate) struct SegmentList {
+    /// segments loaded, only the first `SEGMENT_SLOTS` are listed
+    pub(crate) count: [u8; 2],
+    _reserved: [u8; 2],
+    /// first address, last address, CRC-32 of the bytes
+    pub(crate) entries: [[u8; SEGMENT_SIZE]; SEGMENT_SLOTS],
+    _padding: [u8; 4],
+}
+
+#[repr(C)]
+pub(crate) struct DisassemblyArea {
+    /// instructions listed, the first is the one at the PC
+    pub(crate) count: u8,
+    _reserved: [u8; 3],
+    /// address, length in bytes, reserved byte, C-String text
+    pub(crate) entries: [[u8; DISASSEMBLY_SIZE]; DISASSEMBLY_SLOTS],
+    _padding: [u8; 0x3c],
+}
+
+pub(crate) const SIZE: usize = size_of::<ShmemLayout>();
+pub(crate) const REGISTERS: usize = offset_of!(ShmemLayout, registers);
+pub(crate) const COMMAND: usize = offset_of!(ShmemLayout, command);
+pub(crate) const EVENTS: usize = offset_of!(ShmemLayout, events);
+pub(crate) const EVENT_SLOTS_START: usize = EVENTS + offset_of!(EventRing, slots);
+pub(crate) const DIFF: usize = offset_of!(ShmemLayout, diff);
+pub(crate) const DIFF_COUNT: usize = DIFF + offset_of!(DiffArea, count);
+pub(crate) const DIFF_ENTRIES: usize = DIFF + offset_of!(DiffArea, entries);
+pub(crate) const STATUS: usize = offset_of!(ShmemLayout, status);
+pub(crate) const STATUS_RUN_MODE: usize = STATUS + offset_of!(StatusBlock, run_mode);
+pub(crate) const STATUS_STOP_REASON: usize = STATUS + offset_of!(StatusBlock, stop_reason);
+pub(crate) const STATUS_ENTRY: usize = STATUS + offset_of!(StatusBlock, entry);
+pub(crate) const STATUS_GENERATION: usize = STATUS + offset_of!(StatusBlock, generation);
+pub(crate) const STATUS_NAME: usize = STATUS + offset_of!(StatusBlock, name);
+pub(crate) const STATUS_FLAGS: usize = STATUS + offset_of!(StatusBlock, flags);
+pub(crate) const STATUS_BUSY: usize = STATUS + offset_of!(StatusBlock, busy);
+pub(crate) const METRICS: usize = offset_of!(ShmemLayout, metrics);
+pub(crate) const SEGMENTS: usize = offset_of!(ShmemLayout, segments);
+pub(crate) const SEGMENT_ENTRIES: usize = SEGMENTS + offset_of!(SegmentList, entries);
+pub(crate) const DISASSEMBLY: usize = offset_of!(ShmemLayout, disassembly);
+pub(crate) const DISASSEMBLY_ENTRIES: usize = DISASSEMBLY + offset_of!(DisassemblyArea, entries);
+
+const _: () = assert!(REGISTERS == 0x10000);
+const _: () = assert!(COMMAND == 0x10020);
+const _: () = assert!(EVENTS == 0x10420 && EVENT_SLOTS_START == 0x10424 && EVENT_SLOTS == 127);
+const _: () = assert!(size_of::<EventRing>() == 0x400);
+const _: () = assert!(DIFF == 0x10820 && DIFF_COUNT == 0x10822 && DIFF_ENTRIES == 0x10824);
+const _: () = assert!(size_of::<DiffArea>() == 0x1000);
+const _: () = assert!(STATUS == 0x11820 && STATUS_RUN_MODE == 0x1182c && STATUS_ENTRY == 0x1182e);
+const _: () = assert!(STATUS_GENERATION == 0x11830);
+const _: () = assert!(STATUS_NAME == 0x11838 && STATUS_FLAGS == 0x11910 && size_of::<StatusBlock>() == 0x100);
+const _: () = assert!(STATUS_BUSY == 0x11919);
+const _: () = assert!(METRICS == 0x11920 && METRICS_SIZE <= SIZE - METRICS);
+const _: () = assert!(SEGMENTS == 0x11960 && SEGMENT_ENTRIES == 0x11964 && size_of::<SegmentList>() == 0x200);
+const _: () = assert!(DISASSEMBLY == 0x11b60 && DISASSEMBLY_ENTRIES == 0x11b64 && size_of::<DisassemblyArea>() == 0x200);
+const _: () = assert!(SIZE == 0x11d60);
@@ -0,0 +1,383 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use crate::{rle, utils, Computer, EXTENDED_ADDRESS_SPACE, PAGE_SIZE};
+
+// Machine state saved to a file and restored later, possibly by a newer emulator (binary_formats.txt,
+// "Snapshots"). The state is split in tagged sections, each versioned on its own. Within a version
+// fields are only ever appended: a reader defaults the ones missing at the end of an older section
+// and skips the ones a newer writer added after what it knows. Changes that can't work that way
+// bump the section's version, and readers keep handling the older ones.
+
+pub(crate) const MAGIC: &[u8; 4] = b"MSPS";
+/// Version of the container, sections have their own
+pub(crate) const FORMAT_VERSION: u8 = 1;
+
+/// Part of the machine that saves and restores its own state as one section
+pub(crate) trait SnapshotState {
+    const TAG: [u8; 4];
+    /// Version `save` writes, `restore` handles it and every older one
+    const VERSION: u16;
+    fn save(&self, w: &mut Writer);
+    fn restore(&mut self, version: u16, r: &mut Reader);
+}
+
+/// Big-endian fields of a section
+#[derive(Default)]
+pub(crate) struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Length-prefixed
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+    /// Fields of an optional part, length-prefixed so a reader without that part can skip them and
+    /// the part can gain fields at its end like a section
+    pub(crate) fn nested(&mut self, save: impl FnOnce(&mut Writer)) {
+        let mut nested: Writer = Writer::default();
+        save(&mut nested);
+        self.bytes(&nested.data);
+    }
+}
+
+/// Reads a section's fields in the order they were written, fields past its end read as 0 (an older
+/// writer didn't have them yet)
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+        return Reader { data, position: 0 };
+    }
+
+    fn take(&mut self, count: usize) -> &'a [u8] {
+        let start: usize = self.position.min(self.data.len());
+        let end: usize = (start + count).min(self.data.len());
+        self.position += count;
+        return &self.data[start..end];
+    }
+
+    /// Big-endian, short reads are padded with zeros at the end
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut array: [u8; N] = [0; N];
+        let taken: &[u8] = self.take(N);
+        array[..taken.len()].copy_from_slice(taken);
+        return array;
+    }
+
+    pub(crate) fn u8(&mut self) -> u8 {
+        return self.array::<1>()[0];
+    }
+
+    pub(crate) fn bool(&mut self) -> bool {
+        return self.u8() != 0;
+    }
+
+    pub(crate) fn u16(&mut self) -> u16 {
+        return u16::from_be_bytes(self.array());
+    }
+
+    pub(crate) fn u32(&mut self) -> u32 {
+        return u32::from_be_bytes(self.array());
+    }
+
+    pub(crate) fn u64(&mut self) -> u64 {
+        return u64::from_be_bytes(self.array());
+    }
+
+    pub(crate) fn bytes(&mut self) -> Vec<u8> {
+        let length: usize = self.u32() as usize;
+        return self.take(length).to_vec();
+    }
+    /// What `Writer::nested` wrote
+    pub(crate) fn nested(&mut self) -> Reader<'a> {
+        let length: usize = self.u32() as usize;
+        return Reader::new(self.take(length));
+    }
+}
+
+/// A tagged block of state as stored in the file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Section {
+    pub(crate) tag: [u8; 4],
+    pub(crate) version: u16,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Section {
+    fn of<T: SnapshotState>(state: &T) -> Section {
+        let mut w: Writer = Writer::default();
+        state.save(&mut w);
+        return Section { tag: T::TAG, version: T::VERSION, data: w.data };
+    }
+
+    fn name(&self) -> String {
+        return String::from_utf8_lossy(&self.tag).trim_end().to_string();
+    }
+}
+
+/// What restoring a snapshot couldn't take from it
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RestoreReport {
+    /// sections the snapshot lacks (or has in a newer version than this emulator reads), their
+    /// part of the machine starts over as after a reset
+    pub defaulted: Vec<String>,
+    /// sections this emulator doesn't know, kept and written back by the next save
+    pub preserved: Vec<String>,
+}
+
+const CPU_TAG: [u8; 4] = *b"CPU ";
+const CPU_VERSION: u16 = 1;
+const CLOCK_TAG: [u8; 4] = *b"CLK ";
+const CLOCK_VERSION: u16 = 1;
+const MEMORY_TAG: [u8; 4] = *b"MEM ";
+const MEMORY_VERSION: u16 = 1;
+
+/// Registers and execution counters
+fn save_cpu(c: &Computer) -> Section {
+    let mut w: Writer = Writer::default();
+    for register in 0..16 {
+        w.u16(c.get_register_imut(register).get_word());
+    }
+    w.bool(c.servicing_nmi);
+    w.u64(c.retired);
+    w.u32(c.resets);
+    return Section { tag: CPU_TAG, version: CPU_VERSION, data: w.data };
+}
+
+fn restore_cpu(c: &mut Computer, r: &mut Reader) {
+    for register in 0..16 {
+        let value: u16 = r.u16();
+        c.get_register(register).set_word(value);
+    }
+    c.servicing_nmi = r.bool();
+    c.retired = r.u64();
+    c.resets = r.u32();
+}
+
+/// The low 64K run-length coded (see rle.rs), then the extended pages that were ever written
+fn save_memory(c: &Computer) -> Section {
+    let mut w: Writer = Writer::default();
+    let low: Vec<u8> = (0..=0xffffu16).map(|address| c.memory.get_byte(address)).collect();
+    w.bytes(&rle::encode(&low));
+    let mut pages: Vec<u32> = c.memory._extended.keys().copied().collect();
+    pages.sort();
+    w.u32(pages.len() as u32);
+    for page in pages {
+        w.u32(page);
+        w.bytes(&rle::encode(&c.memory._extended[&page][..]));
+    }
+    return Section { tag: MEMORY_TAG, version: MEMORY_VERSION, data: w.data };
+}
+
+/// The memory section decoded, so a corrupted one is found before the machine changes
+struct SavedMemory {
+    low: Vec<u8>,
+    pages: Vec<(u32, Vec<u8>)>,
+}
+
+fn parse_memory(r: &mut Reader) -> Result<SavedMemory, String> {
+    let low: Vec<u8> = rle::decode(&r.bytes()).filter(|low| low.len() == 0x10000)
+        .ok_or("memory section is corrupted")?;
+    let mut pages: Vec<(u32, Vec<u8>)> = Vec::new();
+    for _ in 0..r.u32() {
+        let page: u32 = r.u32();
+        let data: Vec<u8> = rle::decode(&r.bytes()).filter(|data| data.len() == PAGE_SIZE)
+            .ok_or("memory section is corrupted")?;
+        pages.push((page, data));
+    }
+    return Ok(SavedMemory { low, pages });
+}
+
+fn restore_memory(c: &mut Computer, memory: &SavedMemory) {
+    c.memory.reset();
+    for (address, byte) in memory.low.iter().enumerate() {
+        c.memory.set_byte(address as u16, *byte);
+    }
+    for (page, data) in &memory.pages {
+        for (offset, byte) in data.iter().enumerate() {
+            c.memory.set_byte_20(((*page as usize * PAGE_SIZE + offset) as u32) % EXTENDED_ADDRESS_SPACE, *byte);
+        }
+    }
+}
+
+/// Every section of the machine's state, then those a restore preserved
+pub(crate) fn save(c: &Computer) -> Vec<u8> {
+    let mut w: Writer = Writer::default();
+    w.u64(c.clock.cycles());
+    w.u64(c.clock.aclk_lag_nanos());
+    w.u64(c.clock.smclk_lag());
+    let mut sections: Vec<Section> = vec![
+        save_cpu(c),
+        Section { tag: CLOCK_TAG, version: CLOCK_VERSION, data: w.data },
+        save_memory(c),
+        Section::of(&c.devices.gpio),
+        Section::of(&c.devices.uart),
+        Section::of(&c.devices.rng),
+        Section::of(&c.devices.rtc),
+        Section::of(&c.devices.mpu),
+        Section::of(&c.devices.mailbox),
+        Section::of(&c.devices.pmm),
+        Section::of(&c.devices.console),
+        Section::of(&c.devices.pmap),
+        Section::of(&c.devices.cs),
+        Section::of(&c.devices.touch),
+        Section::of(&c.devices.comparator),
+        Section::of(&c.devices.files),
+        Section::of(&c.devices.spi),
+        Section::of(&c.devices.wdt),
+        Section::of(&c.devices.framebuffer),
+    ];
+    sections.extend(c.foreign_sections.iter().cloned());
+    return write(&sections);
+}
+
+/// The container around `sections`
+pub(crate) fn write(sections: &[Section]) -> Vec<u8> {
+    let mut file: Vec<u8> = MAGIC.to_vec();
+    file.push(FORMAT_VERSION);
+    file.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+    for section in sections {
+        file.extend_from_slice(&section.tag);
+        file.extend_from_slice(&section.version.to_be_bytes());
+        file.extend_from_slice(&(section.data.len() as u32).to_be_bytes());
+        file.extend_from_slice(&section.data);
+    }
+    let crc: u32 = utils::crc32(&file);
+    file.extend_from_slice(&crc.to_be_bytes());
+    return file;
+}
+
+/// Sections of a snapshot file, checked against its CRC
+pub(crate) fn parse(file: &[u8]) -> Result<Vec<Section>, String> {
+    if file.len() < 11 || &file[..4] != MAGIC {
+        return Err("not a snapshot".to_string());
+    }
+    let (body, crc) = file.split_at(file.len() - 4);
+    if utils::crc32(body) != u32::from_be_bytes(crc.try_into().expect("4 bytes")) {
+        return Err("snapshot is corrupted (CRC mismatch)".to_string());
+    }
+    if body[4] > FORMAT_VERSION {
+        return Err(format!("snapshot format {} is newer than this emulator reads ({})", body[4], FORMAT_VERSION));
+    }
+    let mut r: Reader = Reader::new(&body[5..]);
+    let count: u16 = r.u16();
+    let mut sections: Vec<Section> = Vec::new();
+    for _ in 0..count {
+        let tag: [u8; 4] = r.array();
+        let version: u16 = r.u16();
+        let length: usize = r.u32() as usize;
+        let data: Vec<u8> = r.take(length).to_vec();
+        if data.len() != length {
+            return Err("snapshot is truncated".to_string());
+        }
+        sections.push(Section { tag, version, data });
+    }
+    return Ok(sections);
+}
+
+fn restore_device<T: SnapshotState>(device: &mut T, sections: &HashMap<[u8; 4], &Section>, report: &mut RestoreReport) {
+    match sections.get(&T::TAG) {
+        Some(section) if section.version <= T::VERSION => device.restore(section.version, &mut Reader::new(&section.data)),
+        Some(section) => report.defaulted.push(section.name()),
+        None => report.defaulted.push(String::from_utf8_lossy(&T::TAG).trim_end().to_string()),
+    }
+}
+
+/// Puts the machine in the saved state: the CPU, clock and memory must be there, devices start
+/// over from a reset and take what the snapshot has for them. The machine is left as it was when
+/// the file can't be restored.
+pub(crate) fn restore(c: &mut Computer, file: &[u8]) -> Result<RestoreReport, String> {
+    let sections: Vec<Section> = parse(file)?;
+    let by_tag: HashMap<[u8; 4], &Section> = sections.iter().map(|s| (s.tag, s)).collect();
+    for (tag, version) in [(CPU_TAG, CPU_VERSION), (CLOCK_TAG, CLOCK_VERSION), (MEMORY_TAG, MEMORY_VERSION)] {
+        match by_tag.get(&tag) {
+            None => return Err(format!("snapshot has no {} section", String::from_utf8_lossy(&tag).trim_end())),
+            Some(section) if section.version > version =>
+                return Err(format!("{} section version {} is newer than this emulator reads ({})",
+                                   section.name(), section.version, version)),
+            Some(_) => {},
+        }
+    }
+    let memory: SavedMemory = parse_memory(&mut Reader::new(&by_tag[&MEMORY_TAG].data))?;
+
+    let mut report: RestoreReport = RestoreReport::default();
+    c.reset();
+    restore_memory(c, &memory);
+    let mut clock: Reader = Reader::new(&by_tag[&CLOCK_TAG].data);
+    c.clock.restore_cycles(clock.u64());
+    c.clock.restore_aclk_lag(clock.u64());
+    c.clock.restore_smclk_lag(clock.u64());
+    restore_cpu(c, &mut Reader::new(&by_tag[&CPU_TAG].data));
+    c.devices.wdt.puc(&c.clock); // without a WDT section the counter starts from the restored time
+
+    let devices = &mut c.devices;
+    restore_device(&mut devices.gpio, &by_tag, &mut report);
+    restore_device(&mut devices.uart, &by_tag, &mut report);
+    restore_device(&mut devices.rng, &by_tag, &mut report);
+    restore_device(&mut devices.rtc, &by_tag, &mut report);
+    restore_device(&mut devices.mpu, &by_tag, &mut report);
+    restore_device(&mut devices.mailbox, &by_tag, &mut report);
+    restore_device(&mut devices.pmm, &by_tag, &mut report);
+    restore_device(&mut devices.console, &by_tag, &mut report);
+    restore_device(&mut devices.pmap, &by_tag, &mut report);
+    restore_device(&mut devices.cs, &by_tag, &mut report);
+    restore_device(&mut devices.touch, &by_tag, &mut report);
+    restore_device(&mut devices.comparator, &by_tag, &mut report);
+    restore_device(&mut devices.files, &by_tag, &mut report);
+    restore_device(&mut devices.spi, &by_tag, &mut report);
+    restore_device(&mut devices.wdt, &by_tag, &mut report);
+    restore_device(&mut devices.framebuffer, &by_tag, &mut report);
+
+    let known: [[u8; 4]; 19] = [CPU_TAG, CLOCK_TAG, MEMORY_TAG, crate::devices::gpio::GpioDevice::TAG,
+        crate::devices::uart::UartDevice::TAG, crate::devices::rng::RngDevice::TAG, crate::devices::rtc::RtcDevice::TAG,
+        crate::devices::mpu::MpuDevice::TAG, crate::devices::mailbox::MailboxDevice::TAG, crate::devices::pmm::PmmDevice::TAG,
+        crate::devices::console::ConsoleDevice::TAG, crate::devices::pmap::PmapDevice::TAG, crate::devices::cs::CsDevice::TAG,
+        crate::devices::touch::TouchDevice::TAG, crate::devices::comparator::ComparatorDevice::TAG,
+        crate::devices::files::HostFileDevice::TAG, crate::devices::spi::SpiDevice::TAG, crate::devices::wdt::WdtDevice::TAG,
+        crate::devices::framebuffer::FrameBufferDevice::TAG];
+    c.foreign_sections = sections.iter().filter(|s| !known.contains(&s.tag)).cloned().collect();
+    report.preserved = c.foreign_sections.iter().map(Section::name).collect();
+    return Ok(report);
+}
@@ -0,0 +1,62 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use crate::devices::console::ConsoleDevice;
+
+/// Feeds host stdin to the console input device. Reading stdin blocks, so a thread does it and
+/// hands the bytes over a channel.
+pub(crate) struct StdinLink {
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl StdinLink {
+    pub(crate) fn start() -> StdinLink {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buf: [u8; 256] = [0; 256];
+            loop {
+                match stdin.read(&mut buf) {
+                    Ok(0) | Err(_) => return, // dropping the sender reports the end
+                    Ok(n) => if sender.send(buf[..n].to_vec()).is_err() {
+                        return;
+                    },
+                }
+            }
+        });
+        return StdinLink { receiver };
+    }
+
+    /// Deliver whatever was typed since the last call, without blocking.
+    /// Returns false once stdin has ended, the console is closed then.
+    pub(crate) fn pump(&mut self, console: &mut ConsoleDevice) -> bool {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(bytes) => console.receive(&bytes),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    console.close();
+                    return false;
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::{Computer, RegisterData};
+use crate::decode::{self, Decoded};
+use crate::disasm;
+
+/// `mov @sp+ pc`
+pub(crate) const RET: u16 = 0x4130;
+pub(crate) const RETI: u16 = 0x1300;
+/// CALL in `Decoded::Single`
+pub(crate) const CALL_OPCODE: u8 = 5;
+
+/// Where a debugger step that runs several instructions stops. Frames are told apart by the stack
+/// pointer, so recursion and nested interrupts don't end a step early.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum StepGoal {
+    /// back at `pc` with the stack unwound to `sp`, after the CALL being stepped over
+    ReturnTo { pc: u16, sp: u16 },
+    /// right after the RET (or RETI) that pops the frame `sp` points into
+    FrameExit { sp: u16, reti: bool },
+}
+
+impl StepGoal {
+    /// `None` if the next instruction isn't a CALL, a plain step does then
+    pub(crate) fn step_over(c: &Computer) -> Option<StepGoal> {
+        let pc: u16 = c.pc.get_word();
+        let instruction: u16 = c.memory.get_word(pc);
+        return match decode::decode(instruction) {
+            Decoded::Single { opcode: CALL_OPCODE, bw: false, .. } => {
+                let length: u16 = disasm::disassemble(pc, &|address| c.memory.get_word(address), true).length;
+                Some(StepGoal::ReturnTo { pc: pc.wrapping_add(length), sp: c.sp.get_word() })
+            },
+            _ => None,
+        };
+    }
+
+    /// Run until the current function returns
+    pub(crate) fn step_out(c: &Computer) -> StepGoal {
+        return StepGoal::FrameExit { sp: c.sp.get_word(), reti: false };
+    }
+
+    /// Run until the current interrupt handler returns
+    pub(crate) fn finish_interrupt(c: &Computer) -> StepGoal {
+        return StepGoal::FrameExit { sp: c.sp.get_word(), reti: true };
+    }
+
+    /// Check after every step
+    pub(crate) fn reached(&self, c: &Computer) -> bool {
+        let sp: u16 = c.sp.get_word();
+        return match *self {
+            StepGoal::ReturnTo { pc, sp: frame } => c.pc.get_word() == pc && sp >= frame,
+            StepGoal::FrameExit { sp: frame, reti } => {
+                let (instruction, popped): (u16, u16) = if reti {(RETI, 4)} else {(RET, 2)};
+                c.memory.get_word(c.instruction_pc) == instruction && sp >= frame.wrapping_add(popped)
+            },
+        };
+    }
+}
+
+/// A step command in progress (control commands 3 and 35)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct StepRun {
+    /// instructions left, counting the current one
+    pub(crate) remaining: u16,
+    /// stop at hardware breakpoints, otherwise they are passed over
+    pub(crate) breakpoints: bool,
+    /// stop once CPUOFF is set (low-power mode) instead of going idle until an interrupt
+    pub(crate) cpu_off: bool,
+    /// maskable interrupts stay pending until the step is done, so it doesn't wander into handlers
+    pub(crate) mask_interrupts: bool,
+}
+
+impl StepRun {
+    /// What command 3 does
+    pub(crate) fn count(remaining: u16) -> StepRun {
+        return StepRun { remaining, breakpoints: true, cpu_off: false, mask_interrupts: false };
+    }
+
+    /// Options byte of command 35: bit 0 = stop at breakpoints, bit 1 = stop once the CPU is off,
+    /// bit 2 = mask interrupts
+    pub(crate) fn with_options(remaining: u16, options: u8) -> StepRun {
+        return StepRun {
+            remaining, breakpoints: options & 1 != 0, cpu_off: options & 2 != 0, mask_interrupts: options & 4 != 0,
+        };
+    }
+
+    pub(crate) fn options(&self) -> u8 {
+        return self.breakpoints as u8 | (self.cpu_off as u8) << 1 | (self.mask_interrupts as u8) << 2;
+    }
+}
+
+/// Why the emulator stopped, for frontends to show (control command 24)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum StopReason {
+    /// a breakpoint of fetch triggers, the instruction at the PC hasn't executed yet
+    Breakpoint(usize),
+    /// a breakpoint involving data accesses, the instruction that made them has executed
+    Watchpoint(usize),
+    /// a step, step-over, step-out or finish-interrupt is done
+    Step,
+    /// control command 1
+    HaltRequest,
+    Fault,
+    /// a step with the CPU-off option found CPUOFF set, nothing executes until an interrupt
+    CpuOff,
+    /// a diagnostic with severity break (`run --check`), the instruction that raised it has executed
+    Diagnostic,
+}
+
+impl StopReason {
+    pub(crate) fn id(&self) -> u8 {
+        return match self {
+            StopReason::Breakpoint(_) => 1,
+            StopReason::Watchpoint(_) => 2,
+            StopReason::Step => 3,
+            StopReason::HaltRequest => 4,
+            StopReason::Fault => 5,
+            StopReason::CpuOff => 6,
+            // 7 and 8 are notification kinds (see notify.rs)
+            StopReason::Diagnostic => 9,
+        };
+    }
+
+    pub(crate) fn breakpoint(&self) -> Option<usize> {
+        return match self {
+            StopReason::Breakpoint(index) | StopReason::Watchpoint(index) => Some(*index),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        return match self {
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Watchpoint(_) => "watchpoint",
+            StopReason::Step => "step",
+            StopReason::HaltRequest => "halt request",
+            StopReason::Fault => "fault",
+            StopReason::CpuOff => "CPU off",
+            StopReason::Diagnostic => "diagnostic",
+        };
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde_json::Value;
+use crate::devices::gpio::{GpioDevice, PinId};
+
+/// Drive `pin` to `level` (`None` releases it) once `cycle` cycles have run
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct StimulusEvent {
+    pub(crate) cycle: u64,
+    pub(crate) pin: PinId,
+    pub(crate) level: Option<bool>,
+}
+
+/// Scripted input levels replayed into the GPIO pins as emulated time passes (`run --stimulus`)
+#[derive(Clone)]
+pub(crate) struct Stimulus {
+    events: Vec<StimulusEvent>,
+    /// first event not applied yet
+    next: usize,
+}
+
+fn parse_level(text: &str) -> Result<Option<bool>, String> {
+    return match text.trim().to_ascii_lowercase().as_str() {
+        "0" | "low" => Ok(Some(false)),
+        "1" | "high" => Ok(Some(true)),
+        "z" | "float" => Ok(None),
+        other => Err(format!("'{}' is not a level (0, 1 or z)", other)),
+    };
+}
+
+fn parse_pin(text: &str) -> Result<PinId, String> {
+    if text.trim().to_ascii_uppercase().starts_with('A') {
+        return Err(format!("'{}': no ADC is modeled, only GPIO pins can be driven (analog inputs go to the comparator with --analog)", text.trim()));
+    }
+    return text.parse();
+}
+
+impl Stimulus {
+    pub(crate) fn new(mut events: Vec<StimulusEvent>) -> Stimulus {
+        events.sort_by_key(|e| e.cycle); // stable, so events at the same cycle keep file order
+        return Stimulus { events, next: 0 };
+    }
+
+    /// `cycle,pin,level` lines, e.g. `1000,P1.3,0`. Blank lines, `#` comments and a header are skipped.
+    pub(crate) fn from_csv(text: &str) -> Result<Stimulus, String> {
+        let mut events: Vec<StimulusEvent> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() || (number == 0 && line.starts_with("cycle")) {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').collect();
+            if fields.len() != 3 {
+                return Err(format!("Line {}: expected cycle,pin,level", number + 1));
+            }
+            let cycle: u64 = fields[0].trim().parse().map_err(|e| format!("Line {}: bad cycle: {}", number + 1, e))?;
+            let pin: PinId = parse_pin(fields[1]).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            let level = parse_level(fields[2]).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            events.push(StimulusEvent { cycle, pin, level });
+        }
+        return Ok(Stimulus::new(events));
+    }
+
+    /// An array of `{"cycle": 1000, "pin": "P1.3", "level": 0}`, level may also be `"z"` or `null`
+    pub(crate) fn from_json(text: &str) -> Result<Stimulus, String> {
+        let root: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let entries = root.as_array().ok_or("Expected an array of events")?;
+        let mut events: Vec<StimulusEvent> = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let cycle: u64 = entry["cycle"].as_u64().ok_or(format!("Event {}: missing cycle", i))?;
+            let pin: PinId = parse_pin(entry["pin"].as_str().ok_or(format!("Event {}: missing pin", i))?)
+                .map_err(|e| format!("Event {}: {}", i, e))?;
+            let level: Option<bool> = match &entry["level"] {
+                Value::Null => None,
+                Value::Bool(high) => Some(*high),
+                Value::Number(n) => parse_level(&n.to_string()).map_err(|e| format!("Event {}: {}", i, e))?,
+                Value::String(s) => parse_level(s).map_err(|e| format!("Event {}: {}", i, e))?,
+                _ => return Err(format!("Event {}: bad level", i)),
+            };
+            events.push(StimulusEvent { cycle, pin, level });
+        }
+        return Ok(Stimulus::new(events));
+    }
+
+    /// JSON if the file starts with `[`, CSV otherwise
+    pub(crate) fn load(path: &str) -> Result<Stimulus, String> {
+        let text: String = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        if text.trim_start().starts_with('[') {
+            return Stimulus::from_json(&text);
+        }
+        return Stimulus::from_csv(&text);
+    }
+
+    /// Replay from the start, for when the computer is reset
+    pub(crate) fn rewind(&mut self) {
+        self.next = 0;
+    }
+
+    /// Apply every event due by `cycle`
+    #[inline]
+    pub(crate) fn apply(&mut self, cycle: u64, gpio: &mut GpioDevice) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.cycle > cycle {
+                break;
+            }
+            gpio.set_input(event.pin, event.level);
+            self.next += 1;
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use crate::Computer;
+use crate::devices::rng::split_mix;
+use crate::interrupts::{self, VectorEntry};
+
+/// SCG1, SCG0, OSCOFF and CPUOFF, handlers clear these in the saved SR to wake the main loop
+const LOW_POWER_BITS: u16 = 0x00f0;
+
+/// Vectors the firmware has a handler for, except reset and the non-maskable ones
+pub(crate) fn maskable_vectors(c: &Computer) -> Vec<u16> {
+    return interrupts::vector_map(c, &[]).iter()
+        .filter(|entry: &&VectorEntry| entry.populated() && !entry.nmi() && entry.vector < 0xfffc)
+        .map(|entry| entry.vector).collect();
+}
+
+/// Something an interrupt handler broke, found when it returned
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Violation {
+    /// RETI ran with the stack somewhere other than where the interrupt left it
+    Stack { expected: u16, sp: u16 },
+    /// RETI restored another SR than the interrupt saved (low-power bits aside)
+    StatusRegister { saved: u16, restored: u16 },
+    ReturnAddress { saved: u16, restored: u16 },
+    /// r4-r15 must look untouched to the interrupted code
+    Register { register: u8, before: u16, after: u16 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Violation::Stack { expected, sp } => write!(f, "returned with SP {:#06x}, the frame ends at {:#06x}", sp, expected),
+            Violation::StatusRegister { saved, restored } => write!(f, "SR restored as {:#06x}, saved {:#06x}", restored, saved),
+            Violation::ReturnAddress { saved, restored } => write!(f, "returned to {:#06x}, interrupted at {:#06x}", restored, saved),
+            Violation::Register { register, before, after } => write!(f, "r{} changed from {:#06x} to {:#06x}", register, before, after),
+        };
+    }
+}
+
+/// A violation and where it happened
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct StormViolation {
+    /// instructions into the storm
+    pub(crate) step: u64,
+    pub(crate) vector: u16,
+    /// interrupts nested at the time, 1 for a handler that interrupted the main program
+    pub(crate) depth: usize,
+    pub(crate) violation: Violation,
+}
+
+impl fmt::Display for StormViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "instruction {}: handler for {:#06x} (depth {}) {}", self.step, self.vector, self.depth, self.violation);
+    }
+}
+
+/// What an interrupt saved, to compare against at its RETI
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    vector: u16,
+    /// SP with PC and SR pushed
+    sp: u16,
+    pc: u16,
+    sr: u16,
+    registers: [u16; 12],
+}
+
+/// Fires random interrupts at the firmware while it runs (`storm`) and checks every handler
+/// returns to exactly what it interrupted: same stack pointer, PC, SR and r4-r15. Every interrupt
+/// is tracked, not only the fired ones, so device interrupts and handlers that re-enable GIE and
+/// nest are checked too. Interrupts fired while GIE is clear stay pending like a flag would,
+/// the highest vector is delivered first.
+#[derive(Clone)]
+pub(crate) struct InterruptStorm {
+    vectors: Vec<u16>,
+    /// average instructions between fired interrupts
+    rate: u64,
+    state: u64,
+    pending: Vec<u16>,
+    frames: Vec<Frame>,
+    steps: u64,
+    pub(crate) fired: u64,
+    pub(crate) delivered: u64,
+    pub(crate) returned: u64,
+    pub(crate) max_depth: usize,
+    pub(crate) violations: Vec<StormViolation>,
+}
+
+impl InterruptStorm {
+    pub(crate) fn new(vectors: Vec<u16>, rate: u64, seed: u64) -> InterruptStorm {
+        return InterruptStorm {
+            vectors, rate: rate.max(1), state: seed, pending: vec![], frames: vec![],
+            steps: 0, fired: 0, delivered: 0, returned: 0, max_depth: 0, violations: vec![],
+        };
+    }
+
+    /// Before every step, the vector to interrupt with now if any
+    pub(crate) fn fire(&mut self, gie: bool) -> Option<u16> {
+        self.steps += 1;
+        if !self.vectors.is_empty() && split_mix(&mut self.state).is_multiple_of(self.rate) {
+            let vector: u16 = self.vectors[(split_mix(&mut self.state) % self.vectors.len() as u64) as usize];
+            if !self.pending.contains(&vector) {
+                self.pending.push(vector);
+            }
+            self.fired += 1;
+        }
+        if !gie || self.pending.is_empty() {
+            return None;
+        }
+        let highest: usize = (0..self.pending.len()).max_by_key(|i| self.pending[*i]).unwrap();
+        self.delivered += 1;
+        return Some(self.pending.swap_remove(highest));
+    }
+
+    /// Any interrupt is being entered, before PC and SR are pushed
+    pub(crate) fn entered(&mut self, vector: u16, sp: u16, pc: u16, sr: u16, registers: [u16; 12]) {
+        self.frames.push(Frame { vector, sp: sp.wrapping_sub(4), pc, sr, registers });
+        self.max_depth = self.max_depth.max(self.frames.len());
+    }
+
+    /// A RETI ran, with what it restored
+    pub(crate) fn returned(&mut self, sp: u16, pc: u16, sr: u16, registers: [u16; 12]) {
+        let depth: usize = self.frames.len();
+        let Some(frame) = self.frames.pop() else {
+            return; // firmware faking an interrupt return, nothing to compare with
+        };
+        self.returned += 1;
+        let mut report = |violation: Violation| {
+            self.violations.push(StormViolation { step: self.steps, vector: frame.vector, depth, violation });
+        };
+        let expected: u16 = frame.sp.wrapping_add(4);
+        if sp != expected {
+            // whatever RETI popped isn't the saved PC and SR
+            report(Violation::Stack { expected, sp });
+            return;
+        }
+        if pc != frame.pc {
+            report(Violation::ReturnAddress { saved: frame.pc, restored: pc });
+        }
+        if sr & !LOW_POWER_BITS != frame.sr & !LOW_POWER_BITS {
+            report(Violation::StatusRegister { saved: frame.sr, restored: sr });
+        }
+        for (i, (before, after)) in frame.registers.iter().zip(registers.iter()).enumerate() {
+            if before != after {
+                report(Violation::Register { register: i as u8 + 4, before: *before, after: *after });
+            }
+        }
+    }
+
+    /// Handlers running right now
+    pub(crate) fn depth(&self) -> usize {
+        return self.frames.len();
+    }
+
+    /// A PUC abandons every handler
+    pub(crate) fn abandon(&mut self) {
+        self.frames.clear();
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use std::time::Duration;
+use devices::firmware_test::{TestFailure, TestStatus};
+use image::{ProgramImage, Symbol};
+use sensors::{Sensors, Transaction};
+
+/// Test functions return here, nothing should ever be executing from address 0
+const RETURN_SENTINEL: u16 = 0x0000;
+
+pub(crate) struct TestOptions {
+    /// every symbol starting with this is a test
+    pub(crate) prefix: String,
+    /// instructions a test may execute before it is considered hung
+    pub(crate) max_steps: u64,
+    pub(crate) stack_top: u16,
+    /// no-execute regions fault a test, the others matter to strict emulation
+    pub(crate) profile: DeviceProfile,
+    /// strict faults a test on anything that would behave differently on hardware
+    pub(crate) emulation: EmulationMode,
+    /// fault a test that stops making progress for this many cycles
+    pub(crate) runaway_cycles: Option<u64>,
+    /// fault a test that returns to an address its CALL or interrupt didn't push
+    pub(crate) shadow_stack: bool,
+    /// directory of the host file device
+    pub(crate) file_dir: Option<String>,
+    /// bit-banged sensors on the pins
+    pub(crate) sensors: Option<Sensors>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum TestOutcome {
+    Passed,
+    Failed(Vec<TestFailure>),
+    TimedOut,
+    Faulted(Fault),
+}
+
+pub(crate) struct TestResult {
+    pub(crate) name: String,
+    pub(crate) outcome: TestOutcome,
+    pub(crate) steps: u64,
+    pub(crate) duration: Duration,
+    /// what the sensors decoded during the test
+    pub(crate) transactions: Vec<Transaction>,
+}
+
+/// Run a single test function from a freshly reset machine.
+/// A test passes if it returns (or reports a pass) without recording any failure.
+pub(crate) fn run_test(computer: &mut Computer, image: &ProgramImage, test: &Symbol, options: &TestOptions) -> TestResult {
+    let start = Instant::now();
+    computer.devices.wdt.set_armed(!options.profile.watchdog_held);
+    computer.reset();
+    if let Some(sensors) = &mut computer.sensors {
+        sensors.take(); // left over from the previous test
+    }
+    computer.no_execute = options.profile.no_execute.clone();
+    computer.peripherals = options.profile.peripherals.clone();
+    computer.flash = options.profile.flash.clone();
+    computer.read_only = options.profile.read_only.clone();
+    computer.map_bus();
+    computer.diagnostics = Diagnostics::new(options.emulation, &[]);
+    computer.runaway = options.runaway_cycles.map(RunawayDetector::new);
+    computer.shadow_stack = options.shadow_stack.then(ShadowStack::new);
+    image.load(computer);
+    computer.sp.set_word(options.stack_top);
+    computer._push(RETURN_SENTINEL, false);
+    computer.pc.set_word(test.address);
+
+    let mut steps: u64 = 0;
+    let mut returned: bool = false;
+    while steps < options.max_steps {
+        computer.step();
+        steps += 1;
+        if computer.pc.get_word() == RETURN_SENTINEL {
+            returned = true;
+            break;
+        }
+        if computer.devices.firmware_test.status() != TestStatus::Running || computer.fault.is_some() {
+            break;
+        }
+    }
+
+    let transactions: Vec<Transaction> = computer.sensors.as_mut().map(Sensors::take).unwrap_or_default();
+    let device = &computer.devices.firmware_test;
+    let outcome: TestOutcome = if !device.failures().is_empty() {
+        TestOutcome::Failed(device.failures().to_vec())
+    } else if let Some(fault) = computer.fault {
+        TestOutcome::Faulted(fault)
+    } else if returned || device.status() == TestStatus::Passed {
+        TestOutcome::Passed
+    } else {
+        TestOutcome::TimedOut
+    };
+
+    return TestResult {
+        name: test.name.clone(),
+        outcome,
+        steps,
+        duration: start.elapsed(),
+        transactions,
+    };
+}
+
+pub(crate) fn run_tests(image: &ProgramImage, options: &TestOptions) -> Vec<TestResult> {
+    let c: &mut Computer = &mut Computer::new();
+    if let Some(dir) = &options.file_dir {
+        c.devices.files.set_root(dir);
+    }
+    c.sensors = options.sensors.clone();
+    c.attach_hooks();
+    return image.symbols.iter()
+        .filter(|s| s.name.starts_with(&options.prefix))
+        .map(|s| run_test(c, image, s, options))
+        .collect();
+}
+
+pub(crate) fn print_results(results: &[TestResult]) {
+    println!("running {} tests", results.len());
+    for result in results {
+        match &result.outcome {
+            TestOutcome::Passed => println!("test {} ... ok", result.name),
+            TestOutcome::TimedOut => println!("test {} ... TIMED OUT after {} steps", result.name, result.steps),
+            TestOutcome::Faulted(fault) => println!("test {} ... FAULTED: {}", result.name, fault),
+            TestOutcome::Failed(failures) => {
+                println!("test {} ... FAILED", result.name);
+                for f in failures {
+                    println!("    {:?} assertion at pc {:#06x} (id {}): expected {:#06x}, got {:#06x}",
+                             f.kind, f.pc, f.id, f.expected, f.actual);
+                }
+            },
+        }
+        if result.outcome != TestOutcome::Passed {
+            for transaction in &result.transactions {
+                println!("    {}", transaction);
+            }
+        }
+    }
+    let passed: usize = results.iter().filter(|r| r.outcome == TestOutcome::Passed).count();
+    let failed: usize = results.len() - passed;
+    println!();
+    println!("test result: {}. {} passed; {} failed", if failed == 0 {"ok"} else {"FAILED"}, passed, failed);
+}
+
+fn xml_escape(text: &str) -> String {
+    return text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+pub(crate) fn junit_report(suite: &str, results: &[TestResult]) -> String {
+    let failures: usize = results.iter().filter(|r| r.outcome != TestOutcome::Passed).count();
+    let total_time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    let mut out: String = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!("<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.6}\">\n",
+                          xml_escape(suite), results.len(), failures, total_time));
+    for result in results {
+        out.push_str(&format!("  <testcase name=\"{}\" classname=\"{}\" time=\"{:.6}\"",
+                              xml_escape(&result.name), xml_escape(suite), result.duration.as_secs_f64()));
+        match &result.outcome {
+            TestOutcome::Passed => out.push_str("/>\n"),
+            TestOutcome::TimedOut => {
+                out.push_str(">\n");
+                out.push_str(&format!("    <failure message=\"timed out after {} steps\"/>\n", result.steps));
+                out.push_str("  </testcase>\n");
+            },
+            TestOutcome::Faulted(fault) => {
+                out.push_str(">\n");
+                out.push_str(&format!("    <failure message=\"{}\"/>\n", xml_escape(&fault.to_string())));
+                out.push_str("  </testcase>\n");
+            },
+            TestOutcome::Failed(failures) => {
+                out.push_str(">\n");
+                for f in failures {
+                    out.push_str(&format!("    <failure message=\"{:?} assertion at pc {:#06x} (id {}): expected {:#06x}, got {:#06x}\"/>\n",
+                                          f.kind, f.pc, f.id, f.expected, f.actual));
+                }
+                out.push_str("  </testcase>\n");
+            },
+        }
+    }
+    out.push_str("</testsuite>\n");
+    return out;
+}
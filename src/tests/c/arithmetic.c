@@ -0,0 +1,26 @@
+// expect: 120 3628800 21 -7 6 13
+#include "emulator.h"
+
+static unsigned long factorial(unsigned int n) {
+    return n <= 1 ? 1 : n * factorial(n - 1);
+}
+
+static unsigned int gcd(unsigned int a, unsigned int b) {
+    while (b != 0) {
+        unsigned int t = a % b;
+        a = b;
+        b = t;
+    }
+    return a;
+}
+
+int main(void) {
+    volatile int seven = 7;
+    put_uint(factorial(5)); put_char(' ');
+    put_uint(factorial(10)); put_char(' ');
+    put_uint(gcd(1071, 462)); put_char(' ');
+    put_int(-49 / seven); put_char(' ');
+    put_int(-1 * seven * -6 / 7); put_char(' ');
+    put_int(100 % (seven * 2) + 11);
+    finish();
+}
@@ -0,0 +1,19 @@
+// expect: 1 2 3 5 8 0 0 55
+#include "emulator.h"
+
+int initialized[5] = {1, 2, 3, 5, 8};
+int zeroed[2];
+
+int main(void) {
+    int sum = 0;
+    for (int i = 0; i < 5; i++) {
+        put_int(initialized[i]); put_char(' ');
+    }
+    put_int(zeroed[0]); put_char(' ');
+    put_int(zeroed[1]); put_char(' ');
+    for (int i = 0; i < 10; i++) {
+        sum += i + 1;
+    }
+    put_int(sum);
+    finish();
+}
@@ -0,0 +1,44 @@
+/*
+ * Emulator devices for the msp430-elf-gcc test programs (see emulator_devices.txt).
+ * Each program prints through the UART and ends by reporting a result to the firmware test
+ * device; its expected UART output is given on a `// expect:` line.
+ *
+ * Initialized byte data (string literals, char arrays in .data) is loaded word-swapped, the
+ * emulator keeps the high byte first, so the programs print from character constants.
+ */
+#ifndef EMULATOR_H
+#define EMULATOR_H
+
+#define UART_TX     (*(volatile unsigned int *)0x01c0)
+#define TEST_RESULT (*(volatile unsigned int *)0x01f8)
+
+static void put_char(char c) {
+    UART_TX = c;
+}
+
+static void put_uint(unsigned long value) {
+    char digits[10];
+    int count = 0;
+    do {
+        digits[count++] = '0' + value % 10;
+        value /= 10;
+    } while (value != 0);
+    while (count > 0) {
+        put_char(digits[--count]);
+    }
+}
+
+static void put_int(long value) {
+    if (value < 0) {
+        put_char('-');
+        value = -value;
+    }
+    put_uint(value);
+}
+
+static void finish(void) {
+    TEST_RESULT = 1;
+    for (;;) {}
+}
+
+#endif
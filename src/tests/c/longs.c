@@ -0,0 +1,13 @@
+// expect: 4294967295 305419896 829528191 65536 12
+#include "emulator.h"
+
+int main(void) {
+    volatile unsigned long all = 0xffffffffUL;
+    volatile unsigned long value = 0x12345678UL;
+    put_uint(all); put_char(' ');
+    put_uint(value); put_char(' ');
+    put_uint(value << 4 ^ 0x0000ffffUL ^ 0x12340000UL); put_char(' ');
+    put_uint((all >> 16) + 1); put_char(' ');
+    put_uint(value % 13 + value / 0x10000000UL * 10);
+    finish();
+}
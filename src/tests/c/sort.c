@@ -0,0 +1,40 @@
+// expect: -40 -3 0 7 12 99 1000
+#include "emulator.h"
+
+struct item {
+    int key;
+    unsigned char tag;
+};
+
+static int compare(const struct item *a, const struct item *b) {
+    return a->key < b->key ? -1 : a->key > b->key;
+}
+
+static void sort(struct item *items, int count, int (*cmp)(const struct item *, const struct item *)) {
+    for (int i = 1; i < count; i++) {
+        struct item current = items[i];
+        int j = i - 1;
+        while (j >= 0 && cmp(&items[j], &current) > 0) {
+            items[j + 1] = items[j];
+            j--;
+        }
+        items[j + 1] = current;
+    }
+}
+
+int main(void) {
+    struct item items[7];
+    int keys[7] = {12, -3, 1000, 0, 99, -40, 7};
+    for (int i = 0; i < 7; i++) {
+        items[i].key = keys[i];
+        items[i].tag = i;
+    }
+    sort(items, 7, compare);
+    for (int i = 0; i < 7; i++) {
+        if (i > 0) {
+            put_char(' ');
+        }
+        put_int(items[i].key);
+    }
+    finish();
+}
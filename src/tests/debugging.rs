@@ -0,0 +1,1645 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use crate::journal::{self, JournalEntry, WriteJournal};
+use crate::branch_trace::{self, BranchKind, BranchRecord, BranchTrace};
+use crate::expr::{self, Expr};
+use crate::image::Symbol;
+use crate::watch::{WatchEvent, WatchList};
+use crate::profile::{DeviceProfile, Profile, Region};
+use crate::builder::ComputerBuilder;
+use crate::eem::{self, Trigger, TriggerKind};
+use crate::runaway::RunawayDetector;
+use crate::shadow_stack::{Corruption, ShadowStack};
+use crate::diagnostics::{Category, CheckSpec, Diagnostic, Diagnostics, EmulationMode, Severity};
+use crate::profiler::{FunctionCost, Profiler};
+use crate::heap::{Allocation, HeapError, HeapTracker};
+use crate::dump::{self, DumpFormat, DumpSpec};
+use crate::disasm;
+use crate::rle;
+use crate::snapshot;
+use crate::script::Script;
+use crate::notify::{self, Notifier};
+use crate::trace_hash::TraceHasher;
+use crate::lockstep::{Difference, Lockstep, MachineConfig, Outcome};
+use crate::fuzz::{self, FuzzCase, FuzzInstruction, FuzzState};
+use crate::decode::{self, Decoded};
+use crate::storm::{self, InterruptStorm, StormViolation, Violation};
+use crate::stepping::{StepGoal, StepRun, StopReason};
+use crate::run_log::{LogFormat, LogLevel, RunLog};
+use crate::metrics::{RunMetrics, METRICS_SIZE};
+use crate::interrupts;
+use crate::scheduler::BatchSizer;
+use crate::replay::{CommandRecorder, RecordedCommand, RecordedEnd, Recording, ReplayStep};
+use base64::{Engine as _, engine::general_purpose};
+
+#[test]
+fn write_journal() {
+    let path = std::env::temp_dir().join(format!("msp430_journal_test_{}.bin", std::process::id()));
+    let path_str: String = path.to_str().unwrap().to_string();
+
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x1234 &0x0200
+mov.b #0x56 &0x0201
+push #0x0042
+mov #1 &0x01c0 ; UART TX
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    c.reset();
+    c.journal = Some(WriteJournal::create(&path_str).unwrap());
+    utils::execute_nr(c, &trimmed, 5);
+    c.journal = None; // flushes
+
+    let entries: Vec<JournalEntry> = journal::read_journal(std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(4, entries.len());
+    assert_eq!(JournalEntry { cycle: 2, pc: 0x4404, address: 0x0200, old: 0, new: 0x1234, byte: false, device: false }, entries[0]);
+    assert_eq!(JournalEntry { cycle: 7, pc: 0x440a, address: 0x0201, old: 0x34, new: 0x56, byte: true, device: false }, entries[1]);
+    assert_eq!((0x43fe, 0x0042), (entries[2].address, entries[2].new), "Pushes are data writes");
+    assert!(entries[3].device);
+
+    let mut csv: Vec<u8> = Vec::new();
+    journal::to_csv(&entries[1..2], &mut csv).unwrap();
+    assert_eq!("cycle,pc,address,width,old,new,target\n7,0x440a,0x0201,byte,0x34,0x56,memory\n", String::from_utf8(csv).unwrap());
+}
+
+#[test]
+fn uart_capture() {
+    let path = std::env::temp_dir().join(format!("msp430_uart_capture_test_{}.txt", std::process::id()));
+    let path_str: String = path.to_str().unwrap().to_string();
+
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x48 &0x01c0 ; UART TX
+mov.b #0x69 &0x01c1
+mov.b #0x0a &0x01c0
+mov #0x41 &0x0200 ; not the UART
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    c.reset();
+    c.uart_capture = Some(UartCapture::create(&path_str).unwrap());
+    utils::execute_nr(c, &trimmed, 5);
+    c.uart_capture = None; // flushes
+
+    let captured: String = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!("2 48 H\n7 69 i\n12 0a\n", captured, "Cycle each write started at, byte writes to either half count");
+}
+
+#[test]
+fn pc_history_keeps_newest() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #1 r5
+mov #2 r5
+nop
+nop
+mov #3 r5
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    c.pc_history.set_capacity(3);
+    execute(c, &trimmed, 2);
+    assert_eq!(vec![0x4400, 0x4402], c.pc_history.entries().iter().map(|e| e.pc).collect::<Vec<u16>>());
+
+    for _ in 0..3 {
+        c.step();
+    }
+    let entries = c.pc_history.entries();
+    assert_eq!(vec![0x4404, 0x4406, 0x4408], entries.iter().map(|e| e.pc).collect::<Vec<u16>>(), "Oldest first");
+    assert_eq!(c.memory.get_word(0x4408), entries[2].instruction);
+}
+
+#[test]
+fn watch_expressions() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x0200 r4
+mov #0x1234 &0x0202
+mov #5 r5
+mov #0x1234 &0x0202
+mov #0xff00 &0x0202
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 1);
+
+    let mut watches = WatchList::new();
+    let word: u16 = watches.add("[r4 + 2]".parse().unwrap(), c);
+    let masked: u16 = watches.add("b[r4+3] & 0x0f | r5 << 8".parse().unwrap(), c);
+    assert!("r16".parse::<Expr>().is_err());
+    assert!("[r4".parse::<Expr>().is_err());
+
+    let mut events: Vec<WatchEvent> = Vec::new();
+    for _ in 0..4 {
+        c.step();
+        events.extend(watches.check(c));
+    }
+    assert_eq!(vec![
+        WatchEvent { id: word, old: 0, new: 0x1234, pc: 0x440a },
+        WatchEvent { id: masked, old: 0, new: 0x0004, pc: 0x440a },
+        WatchEvent { id: masked, old: 0x0004, new: 0x0504, pc: 0x440e },
+        WatchEvent { id: word, old: 0x1234, new: 0xff00, pc: 0x441a },
+        WatchEvent { id: masked, old: 0x0504, new: 0x0500, pc: 0x441a },
+    ], events, "Rewriting the same value is not a change");
+}
+
+#[test]
+fn address_expressions() {
+    let c: &mut Computer = &mut Computer::new();
+    c.sp.set_word(0x4400);
+    c.memory.set_word(0x0210, 0xbeef);
+    let symbols: Vec<Symbol> = vec![
+        Symbol { name: "main".to_string(), address: 0xc000 },
+        Symbol { name: "buffer".to_string(), address: 0x0200 },
+    ];
+    let eval = |s: &str| expr::parse(s, &symbols).map(|e| e.eval(c));
+
+    assert_eq!(Ok(0xc012), eval("main+0x12"));
+    assert_eq!(Ok(0x43fc), eval("sp-4"));
+    assert_eq!(Ok(0x0202), eval("&buffer[2]"));
+    assert_eq!(Ok(0x0200), eval("&buffer"));
+    assert_eq!(Ok(0xbeef), eval("[&buffer[8 << 1]]"));
+    assert_eq!(Some(0xc002), expr::parse("main | 2", &symbols).unwrap().constant());
+    assert_eq!(None, expr::parse("main + r4", &symbols).unwrap().constant());
+    assert!(eval("missing+2").is_err());
+    assert!(eval("&r4").is_err());
+}
+
+#[test]
+fn no_execute_halts() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x0300 pc ; into RAM, like returning through a smashed stack
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    c.reset();
+    c.no_execute = Profile::G2553.no_execute();
+    utils::execute_nr(c, &trimmed, 3);
+    let fault = Fault::NoExecute { pc: 0x0300, region: Region { start: 0x0200, end: 0x03ff } };
+    assert_eq!(Some(fault), c.fault);
+    assert_eq!(0x4404, c.pc_history.entries().last().unwrap().pc, "The faulting fetch isn't executed");
+
+    c.step();
+    assert_eq!(0x0300, c.pc.get_word(), "Halted until reset");
+    c.reset();
+    assert_eq!(None, c.fault);
+    assert_eq!(2, c.no_execute.len(), "Regions are kept across resets");
+}
+
+#[test]
+fn branch_trace() {
+    let path = std::env::temp_dir().join(format!("msp430_branch_trace_test_{}.bin", std::process::id()));
+    let path_str: String = path.to_str().unwrap().to_string();
+
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #2 r5
+loop:
+call #func
+sub #1 r5
+jnz loop
+mov #done pc
+nop
+done:
+jmp done
+func:
+ret
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    c.reset();
+    c.branch_trace = Some(BranchTrace::create(&path_str).unwrap());
+    c.attach_hooks();
+    utils::execute_nr(c, &trimmed, 12);
+    c.branch_trace = None; // flushes
+
+    let size: u64 = std::fs::metadata(&path).unwrap().len();
+    let records: Vec<BranchRecord> = branch_trace::read_trace(std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let record = |kind: BranchKind, source: u16, target: u16, taken: bool| BranchRecord { kind, source, target, taken };
+    assert_eq!(vec![
+        record(BranchKind::Call, 0x4406, 0x4416, true),
+        record(BranchKind::Return, 0x4416, 0x440a, true),
+        record(BranchKind::Jump, 0x440c, 0x4406, true),
+        record(BranchKind::Call, 0x4406, 0x4416, true),
+        record(BranchKind::Return, 0x4416, 0x440a, true),
+        record(BranchKind::Jump, 0x440c, 0x4406, false),
+        record(BranchKind::PcWrite, 0x440e, 0x4414, true),
+        record(BranchKind::Jump, 0x4414, 0x4414, true),
+    ], records);
+    assert!(size <= 5 + 3 * 8 + 2, "{} bytes", size);
+
+    let mut text: Vec<u8> = Vec::new();
+    branch_trace::to_text(&records[5..7], &mut text).unwrap();
+    assert_eq!("0x440c -> 0x4406 jump (not taken)\n0x440e -> 0x4414 pc write\n", String::from_utf8(text).unwrap());
+}
+
+#[test]
+fn heap_tracking() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x0300 &0x0200
+mov #10 r12
+call #malloc
+mov r12 r10
+mov #6 r12
+call #malloc
+mov r10 r12
+call #free
+mov r10 r12
+call #free
+done:
+jmp done
+
+malloc:
+mov &0x0200 r13
+add r12 &0x0200
+mov r13 r12
+ret
+free:
+ret
+
+.interrupt 0xffa0 malloc
+.interrupt 0xffa2 free
+");
+    ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().load(c);
+    let symbols = vec![Symbol { name: "malloc".to_string(), address: c.memory.get_word(0xffa0) },
+                       Symbol { name: "free".to_string(), address: c.memory.get_word(0xffa2) }];
+    assert!(HeapTracker::from_symbols(&symbols[..0]).is_none(), "Nothing to track without the symbols");
+    c.heap = HeapTracker::from_symbols(&symbols);
+    for _ in 0..25 {
+        c.step();
+    }
+    let heap: &HeapTracker = c.heap.as_ref().unwrap();
+    assert_eq!((2, 16, 2), (heap.allocations, heap.peak_bytes, heap.peak_allocations));
+    assert_eq!(vec![Allocation { address: 0x030a, size: 6, pc: 0x4418 }], heap.live(), "The second allocation leaked");
+    assert_eq!(vec![HeapError::DoubleFree { address: 0x0300, pc: 0x4424 }], heap.errors);
+    assert_eq!(None, heap.break_growth());
+    assert_eq!("heap: 2 allocations, peak 16 bytes in 2 live, 6 bytes in 1 leaked\n\
+                \x20 leak: 6 bytes at 0x030a, allocated at pc 0x4418\n\
+                \x20 error: double free of 0x0300 at pc 0x4424\n", heap.report());
+
+    c.reset();
+    assert!(c.heap.as_ref().unwrap().live().is_empty(), "A reset starts a new heap");
+}
+
+#[test]
+fn runaway_detection() {
+    for (code, fault) in [
+        ("stuck:\njmp stuck", Some(Fault::Runaway { first: 0x4404, last: 0x4404, cycles: 10 })),
+        ("stuck:\nnop\nnop\njmp stuck", Some(Fault::Runaway { first: 0x4404, last: 0x4408, cycles: 10 })),
+        ("kick:\nmov #0x5a08 &0x0120 ; WDTCTL = WDTPW | WDTCNTCL\njmp kick", None),
+    ] {
+        let c: &mut Computer = &mut Computer::new();
+        let assembled = assemble(&format!("mov #0x4400 sp\n{}", code));
+        let trimmed = assembled.trim();
+        println!("'{}'", trimmed);
+        c.runaway = Some(RunawayDetector::new(10));
+        execute(c, &trimmed, 100);
+        assert_eq!(fault, c.fault, "{}", code);
+    }
+    assert_eq!("tight infinite loop at 0x4404 (no new code reached and watchdog not serviced for 10 cycles)",
+               Fault::Runaway { first: 0x4404, last: 0x4404, cycles: 10 }.to_string());
+}
+
+#[test]
+fn instruction_diagnostics() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+nop
+mov #5 r3
+bis #0x0200 sr
+eint
+done:
+jmp done
+handler:
+bic #8 0(sp)
+reti
+
+.interrupt 0xffe0 handler
+");
+    ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().load(c);
+    let checks: Vec<CheckSpec> = ["cg-write", "sr-reserved", "gie-in-isr"].iter().map(|s| s.parse().unwrap()).collect();
+    c.diagnostics = Diagnostics::new(EmulationMode::Permissive, &checks);
+    for _ in 0..5 {
+        c.step();
+    }
+    c.interrupt(0xffe0);
+    c.step(); // bic #8 0(sp)
+    c.step(); // reti
+    let raised: Vec<Diagnostic> = c.diagnostics.take();
+    assert_eq!(vec![
+        Diagnostic { category: Category::CgWrite, pc: 0x4406, value: 0 },
+        Diagnostic { category: Category::SrReserved, pc: 0x440a, value: 0x0200 },
+        Diagnostic { category: Category::GieInIsr, pc: 0x4416, value: 0x0200 },
+    ], raised);
+    assert_eq!("0x4416: RETI restored SR 0x0200, the handler cleared GIE in the saved SR", raised[2].to_string());
+    assert!(!c.diagnostics.take_break(), "Only logged");
+
+    let mut diagnostics = Diagnostics::new(EmulationMode::Permissive, &[CheckSpec { category: Category::CgWrite, severity: Severity::Log }]);
+    diagnostics.retired(0x4406, 0x4033, decode::decode(0x4033), 0, 0); // mov @pc+ r3
+    diagnostics.retired(0x4406, 0x4033, decode::decode(0x4033), 0, 0);
+    diagnostics.retired(0x4500, 0x4303, decode::decode(0x4303), 0, 0); // nop
+    diagnostics.retired(0x4502, 0x9303, decode::decode(0x9303), 0, 0); // tst r3
+    diagnostics.retired(0x4504, 0x4032, decode::decode(0x4032), 0, 0x0200); // mov @pc+ sr, not enabled
+    assert_eq!(1, diagnostics.take().len(), "Once per address, nop and compares are fine");
+}
+
+#[test]
+fn access_diagnostics() {
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).build();
+    let assembled = assemble("
+mov #0x0400 sp
+mov &0x0201 r5
+mov.b &0x0128 r6 ; FCTL1, no flash controller
+mov #1 &0xc000
+mov.b &0x0021 r7 ; P1OUT
+done:
+jmp done
+");
+    let checks: Vec<CheckSpec> = ["odd-address", "unimplemented", "flash-write=break"].iter().map(|s| s.parse().unwrap()).collect();
+    c.diagnostics = Diagnostics::new(EmulationMode::Permissive, &checks);
+    execute(c, assembled.trim(), 3);
+    assert!(!c.diagnostics.take_break());
+    c.step();
+    assert!(c.diagnostics.take_break(), "The flash write breaks");
+    c.step();
+    assert_eq!(vec![
+        Diagnostic { category: Category::OddAddress, pc: 0x4404, value: 0x0201 },
+        Diagnostic { category: Category::Unimplemented, pc: 0x4408, value: 0x0128 },
+        Diagnostic { category: Category::FlashWrite, pc: 0x440c, value: 0xc000 },
+    ], c.diagnostics.take(), "Device registers are implemented");
+
+    assert_eq!(Ok(CheckSpec { category: Category::OddAddress, severity: Severity::Log }), "odd-address".parse());
+    assert_eq!("flash-write=break", CheckSpec { category: Category::FlashWrite, severity: Severity::Break }.to_string());
+    assert!("odd-address=loud".parse::<CheckSpec>().is_err());
+}
+
+#[test]
+fn read_only_regions() {
+    let profile: DeviceProfile = Profile::G2553.device().with_read_only(Region { start: 0x2000, end: 0x20ff });
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(profile).build();
+    let assembled = assemble("
+mov #0x0400 sp
+mov #1 &0x2000
+mov #1 &0x20fe
+mov #1 &0x2100
+mov.b #1 &0x0c00 ; bootstrap loader ROM
+done:
+jmp done
+");
+    execute(c, assembled.trim(), 5);
+    assert_eq!(0, c.memory.get_word(0x2000), "Dropped");
+    assert_eq!(0, c.memory.get_word(0x20fe));
+    assert_eq!(1, c.memory.get_word(0x2100));
+    assert_eq!(0, c.memory.get_byte(0x0c00));
+    assert_eq!(vec![
+        Diagnostic { category: Category::ReadOnlyWrite, pc: 0x4404, value: 0x2000 },
+        Diagnostic { category: Category::ReadOnlyWrite, pc: 0x4408, value: 0x20fe },
+        Diagnostic { category: Category::ReadOnlyWrite, pc: 0x4410, value: 0x0c00 },
+    ], c.diagnostics.take(), "Logged even when permissive");
+    assert_eq!("0x4404: write to read-only memory at 0x2000 dropped",
+               Diagnostic { category: Category::ReadOnlyWrite, pc: 0x4404, value: 0x2000 }.to_string());
+
+    c.protect_image(&[Region { start: 0x2100, end: 0x2101 }]);
+    execute(c, assembled.trim(), 5);
+    assert_eq!(0, c.memory.get_word(0x2100), "The image's segments are read-only too");
+    c.protect_image(&[]);
+    execute(c, assembled.trim(), 5);
+    assert_eq!(1, c.memory.get_word(0x2100), "Until the next image");
+    assert_eq!(0, c.memory.get_word(0x2000), "The profile's stay");
+
+    let strict: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).emulation(EmulationMode::Strict).build();
+    execute(strict, assemble("mov.b #1 &0x0c00").trim(), 1);
+    assert_eq!(Some(Fault::Diagnostic(Diagnostic { category: Category::ReadOnlyWrite, pc: 0x4400, value: 0x0c00 })), strict.fault);
+}
+
+#[test]
+fn emulation_modes() {
+    let code: &str = "
+mov #0x0400 sp
+mov #1 &0xc000
+mov &0x0201 r5
+done:
+jmp done
+";
+    let assembled = assemble(code);
+    let permissive: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).build();
+    execute(permissive, assembled.trim(), 4);
+    assert_eq!(None, permissive.fault, "Permissive is what the emulator always did");
+    assert!(permissive.diagnostics.take().is_empty());
+
+    let builder: ComputerBuilder = ComputerBuilder::new().profile(Profile::G2553.device()).emulation(EmulationMode::Strict);
+    let strict: &mut Computer = &mut builder.build();
+    execute(strict, assembled.trim(), 4);
+    let trapped = Diagnostic { category: Category::FlashWrite, pc: 0x4404, value: 0xc000 };
+    assert_eq!(Some(Fault::Diagnostic(trapped)), strict.fault, "The flash write faults");
+    assert_eq!(0x4408, strict.pc.get_word(), "After the instruction, nothing more runs");
+    assert_eq!("trapped at 0x4404: write to flash at 0xc000 without unlocking it", Fault::Diagnostic(trapped).to_string());
+
+    let relaxed: &mut Computer = &mut builder.check("flash-write=ignore".parse().unwrap()).build();
+    execute(relaxed, assembled.trim(), 4);
+    assert_eq!(Some(Fault::Diagnostic(Diagnostic { category: Category::OddAddress, pc: 0x4408, value: 0x0201 })), relaxed.fault,
+               "Checks override the preset");
+}
+
+#[test]
+fn illegal_instructions() {
+    let assembled = assemble("
+mov #0x4400 sp
+mov #1 r5
+nop
+done:
+jmp done
+");
+    let load = |c: &mut Computer| {
+        execute(c, assembled.trim(), 1);
+        c.memory.set_word(0x4404, 0x1380); // MSP430X RRCM, in place of mov #1 r5
+    };
+    let permissive: &mut Computer = &mut Computer::new();
+    load(permissive);
+    permissive.step();
+    permissive.step();
+    assert_eq!(None, permissive.fault, "Executed as nothing");
+    assert_eq!(0x4408, permissive.pc.get_word());
+    assert_eq!(0, permissive.get_register(5).get_word());
+
+    let strict: &mut Computer = &mut ComputerBuilder::new().emulation(EmulationMode::Strict).build();
+    load(strict);
+    strict.step();
+    let illegal = Diagnostic { category: Category::IllegalInstruction, pc: 0x4404, value: 0x1380 };
+    assert_eq!(Some(Fault::Diagnostic(illegal)), strict.fault);
+    assert_eq!("trapped at 0x4404: illegal instruction 0x1380", strict.fault.unwrap().to_string());
+
+    let resetting: &mut Computer = &mut ComputerBuilder::new().check("illegal-instruction=reset".parse().unwrap()).build();
+    load(resetting);
+    resetting.memory.set_word(0xfffe, 0x4400);
+    resetting.step();
+    assert_eq!(None, resetting.fault);
+    assert_eq!(0x4400, resetting.pc.get_word(), "Restarted from the reset vector");
+    assert_eq!(Some(ResetCause::Diagnostic), resetting.last_reset);
+    assert_eq!(vec![illegal], resetting.diagnostics.take());
+
+    let is_illegal = |instruction: u16| decode::illegal(instruction, decode::decode(instruction));
+    assert!(is_illegal(0x0000) && is_illegal(0x1800), "MSP430X address and extension words");
+    assert!(is_illegal(0x1340) && is_illegal(0x1304), "Byte-mode CALL, RETI with an operand");
+    assert!(!is_illegal(0x1300) && !is_illegal(0x1284) && !is_illegal(0x4303));
+}
+
+/// What an embedder sees, through the public API only
+#[test]
+fn illegal_instruction_fault() {
+    // 0x4400: MSP430X RRCM, reset vector 0x4400
+    let image: ProgramImage = ProgramImage::from_segmented(&[0xff, 0xff, 0x00, 0x02, 0x44, 0x00, 0x00, 0x02, 0x13, 0x80,
+                                                             0xff, 0xfe, 0x00, 0x02, 0x44, 0x00]).unwrap();
+    let mut strict: Computer = ComputerBuilder::new().emulation(EmulationMode::Strict).build();
+    image.load(&mut strict);
+    assert_eq!(None, strict.fault());
+    strict.step();
+    match strict.fault() {
+        Some(Fault::Diagnostic(Diagnostic { category: Category::IllegalInstruction, pc: 0x4400, value: 0x1380 })) => {}
+        other => panic!("Expected an illegal instruction fault, got {:?}", other),
+    }
+    strict.step();
+    assert!(strict.fault().is_some(), "Stays faulted until a reset");
+
+    let mut permissive: Computer = ComputerBuilder::new().build();
+    image.load(&mut permissive);
+    permissive.step();
+    assert_eq!(None, permissive.fault(), "Executed as nothing");
+}
+
+#[test]
+fn shadow_stack() {
+    for (code, fault) in [
+        ("call #func\ndone:\njmp done\nfunc:\nmov #0x1234 0(sp)\nret",
+         Some(Fault::ReturnAddress { pc: 0x4410, slot: 0x43fe, expected: 0x4408, found: 0x1234 })),
+        ("call #func\ncall #func\ndone:\njmp done\nfunc:\nret", None),
+        // longjmp-style exit, the frame left behind is forgotten
+        ("call #func\ndone:\ncall #leaf\njmp done\nfunc:\nadd #2 sp\njmp done\nleaf:\nret", None),
+    ] {
+        let c: &mut Computer = &mut Computer::new();
+        let assembled = assemble(&format!("mov #0x4400 sp\n{}", code));
+        let trimmed = assembled.trim();
+        println!("'{}'", trimmed);
+        c.shadow_stack = Some(ShadowStack::new());
+        execute(c, &trimmed, 20);
+        assert_eq!(fault, c.fault, "{}", code);
+    }
+    assert_eq!("return address corrupted at pc 0x4410 (0x1234 at 0x43fe on the stack, the call pushed 0x4408)",
+               Fault::ReturnAddress { pc: 0x4410, slot: 0x43fe, expected: 0x4408, found: 0x1234 }.to_string());
+
+    let mut shadow = ShadowStack::new();
+    shadow.pushed(0x43fe, 0x4500);
+    shadow.pushed(0x43fa, 0xfffe); // interrupt, SR at 0x43f8
+    assert_eq!(None, shadow.popping(0x43fa, 0xfffe));
+    assert_eq!(Some(Corruption { slot: 0x43fe, expected: 0x4500, found: 0 }), shadow.popping(0x43fe, 0));
+    assert_eq!(None, shadow.popping(0x43fe, 0), "Nothing known about a return without a call");
+}
+
+#[test]
+fn function_profile() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+call #outer
+done:
+jmp done
+outer:
+call #leaf
+call #leaf
+ret
+leaf:
+nop
+ret
+");
+    c.profiler = Some(Profiler::new());
+    c.attach_hooks();
+    execute(c, assembled.trim(), 11);
+    let symbols = vec![Symbol { name: "main".to_string(), address: 0x4400 },
+                       Symbol { name: "leaf".to_string(), address: 0x4414 },
+                       Symbol { name: "outer".to_string(), address: 0x440a }];
+    let profiler: &Profiler = c.profiler.as_ref().unwrap();
+    let cost = |name: &str, calls: u64, inclusive: u64, exclusive: u64| FunctionCost {
+        name: name.to_string(), calls, inclusive, exclusive,
+    };
+    // mov 2, call 5, nop 1, ret 3, jmp 2 cycles
+    assert_eq!(vec![cost("outer", 1, 21, 13), cost("main", 0, 32, 11), cost("leaf", 2, 8, 8)],
+               profiler.functions(&symbols, c.clock.cycles()));
+
+    let report: String = profiler.report(&symbols, c.clock.cycles());
+    assert!(report.lines().nth(1).unwrap().ends_with("outer"), "{}", report);
+    let callgrind: String = profiler.callgrind(&symbols, c.clock.cycles());
+    assert!(callgrind.contains("summary: 32\n"), "{}", callgrind);
+    assert!(callgrind.contains("fn=outer\n0x440a 5\n0x440e 5\n0x4412 3\ncfn=leaf\ncalls=1 0x4414\n0x440a 4\n"), "{}", callgrind);
+}
+
+#[test]
+fn memory_dump() {
+    let c: &mut Computer = &mut Computer::new();
+    c.memory.set_word(0x0200, 0x4142); // "BA" in device order
+    c.memory.set_word(0x0210, 0x1234);
+    let symbols = vec![Symbol { name: "buffer".to_string(), address: 0x0210 }];
+    let region = Region { start: 0x0200, end: 0x0211 };
+
+    assert_eq!(vec![0x42, 0x41], dump::dump(&c.memory, region, DumpFormat::Raw, &symbols)[..2]);
+    let hexdump = String::from_utf8(dump::dump(&c.memory, region, DumpFormat::Hexdump, &symbols)).unwrap();
+    assert_eq!("0200: 42 41 00 00 00 00 00 00 00 00 00 00 00 00 00 00  |BA..............|\n\
+                0210: 34 12                                            |4.|  buffer=0210\n", hexdump);
+    let ti_txt = String::from_utf8(dump::dump(&c.memory, region, DumpFormat::TiTxt, &symbols)).unwrap();
+    assert!(ti_txt.starts_with("@0200\n42 41 00"));
+    assert!(String::from_utf8(dump::dump(&c.memory, region, DumpFormat::Hex, &symbols)).unwrap().ends_with(":00000001FF\n"));
+
+    let spec: DumpSpec = "0x0200-0x0211:ti-txt:C:\\out.txt".parse().unwrap();
+    assert_eq!(DumpSpec { region, format: DumpFormat::TiTxt, path: "C:\\out.txt".to_string() }, spec);
+    assert_eq!("0x0200-0x0211:ti-txt:C:\\out.txt", spec.to_string());
+    assert!("0x0200-0x0211:elf:out".parse::<DumpSpec>().is_err());
+    assert!("0x0200-0x0211:raw".parse::<DumpSpec>().is_err());
+}
+
+#[test]
+fn region_parsing() {
+    assert_eq!(Ok(Region { start: 0x0200, end: 0x03ff }), "0x0200-0x03ff".parse::<Region>());
+    assert_eq!("0x1c00-0x23ff", "7168-0x23ff".parse::<Region>().unwrap().to_string());
+    assert!("0x0400-0x0200".parse::<Region>().is_err());
+    assert!("0x0400".parse::<Region>().is_err());
+}
+
+#[test]
+fn hardware_breakpoints() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #5 r5
+mov r5 &0x0200
+mov #7 &0x0200
+mov &0x0200 r6
+loop:
+jmp loop
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 0);
+    let fetch = |address: u16, address_mask: u16| Trigger { kind: TriggerKind::Fetch, address, address_mask, data: None };
+    assert!(c.eem.set_trigger(0, Some(fetch(0x4408, 0))));
+    assert!(c.eem.set_trigger(1, Some(Trigger { kind: TriggerKind::Write, address: 0x0200, address_mask: 0, data: Some((7, 0)) })));
+    assert!(c.eem.set_trigger(2, Some(fetch(0x4400, 0x00ff))));
+    assert!(c.eem.set_breakpoint(0, 0b001));
+    assert!(c.eem.set_breakpoint(1, 0b110), "Writing 7 from code in 0x4400 - 0x44ff");
+    assert!(!c.eem.set_trigger(eem::TRIGGER_COUNT, None));
+
+    for _ in 0..3 {
+        c.step();
+    }
+    assert!(c.eem.take_hit());
+    assert_eq!((Some(0), 0x4408, 0), (c.eem.last_hit(), c.pc.get_word(), c.memory.get_word(0x0200)), "Halted before the fetch");
+
+    c.step();
+    assert!(!c.eem.take_hit(), "Resuming executes the instruction");
+    assert_eq!(5, c.memory.get_word(0x0200));
+
+    c.step();
+    assert!(c.eem.take_hit());
+    assert_eq!((Some(1), 0x4412, 7), (c.eem.last_hit(), c.pc.get_word(), c.memory.get_word(0x0200)), "Halted after the write");
+    c.step();
+    assert!(!c.eem.take_hit());
+    assert_eq!(7, c.get_register(6).get_word());
+}
+
+#[test]
+fn stop_reasons() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #5 r5
+mov r5 &0x0200
+mov #7 &0x0200
+mov &0x0200 r6
+loop:
+jmp loop
+");
+    execute(c, assembled.trim(), 0);
+    c.eem.set_trigger(0, Some(Trigger { kind: TriggerKind::Fetch, address: 0x4408, address_mask: 0, data: None }));
+    c.eem.set_trigger(1, Some(Trigger { kind: TriggerKind::Write, address: 0x0200, address_mask: 0, data: Some((7, 0)) }));
+    c.eem.set_breakpoint(0, 0b01);
+    c.eem.set_breakpoint(1, 0b10);
+    c.no_execute = vec![Region { start: 0x4416, end: 0x4417 }];
+
+    let reasons: Vec<(Option<StopReason>, u16)> = (0..7).map(|_| (step_or_dump(c), c.pc.get_word())).collect();
+    assert_eq!(vec![
+        (None, 0x4404),
+        (None, 0x4408),
+        (Some(StopReason::Breakpoint(0)), 0x4408),
+        (None, 0x440c),
+        (Some(StopReason::Watchpoint(1)), 0x4412),
+        (None, 0x4416),
+        (Some(StopReason::Fault), 0x4416),
+    ], reasons);
+    assert_eq!(7, c.memory.get_word(0x0200), "The watchpoint's write happened");
+    assert_eq!((1, Some(0)), (StopReason::Breakpoint(0).id(), StopReason::Breakpoint(0).breakpoint()));
+    assert_eq!((3, None), (StopReason::Step.id(), StopReason::Step.breakpoint()));
+}
+
+#[test]
+fn disassembler_emulated_mnemonics() {
+    let assembled = assemble("
+nop
+ret
+pop r5
+clr.b &0x0200
+inc r6
+decd 2(r7)
+tst r8
+xor.b #-1 r9
+rla r10
+setc
+dint
+br #0x4400
+mov.b @r4+ 0x4500
+back:
+jnz back
+push #0x1234
+");
+    let segment = ProgramImage::from_segmented(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().segments.remove(0);
+    let symbols = vec![Symbol { name: "back".to_string(), address: 0x4422 }];
+    let mnemonics = |canonical: bool| disasm::listing(0x4400, &segment.data, &symbols, canonical).lines()
+        .map(|line| line.get(22..).unwrap_or("").to_string()).collect::<Vec<String>>();
+    assert_eq!(vec!["nop", "ret", "pop r5", "clr.b &0x0200", "inc r6", "decd 0x2(r7)", "tst r8", "inv.b r9", "rla r10",
+                    "setc", "dint", "br #0x4400", "mov.b @r4+ 0x4500", "", "jnz 0x4422", "push #0x1234"], mnemonics(false));
+    assert_eq!(vec!["mov #0 r3", "mov @sp+ pc", "mov @sp+ r5", "mov.b #0 &0x0200", "add #1 r6", "sub #2 0x2(r7)", "cmp #0 r8",
+                    "xor.b #-1 r9", "add r10 r10", "bis #1 sr", "bic #8 sr", "mov #0x4400 pc"], mnemonics(true)[..12]);
+    assert!(disasm::listing(0x4400, &segment.data, &symbols, false).contains("4422: 23ff            jnz 0x4422"));
+
+    let unknown = |word: u16| disasm::disassemble(0x4400, &|_| word, false);
+    assert_eq!(".word 0x0000", unknown(0x0000).text, "Not an instruction");
+    assert_eq!(".word 0x13c0", unknown(0x13c0).text, "No single operand opcode 7");
+    assert_eq!(2, unknown(0x0000).length);
+}
+
+#[test]
+fn trace_hash_determinism() {
+    let digest = |code: &str, every: u64| {
+        let assembled = assemble(code);
+        let c: &mut Computer = &mut Computer::new();
+        execute(c, assembled.trim(), 0);
+        c.trace_hash = Some(TraceHasher::new(every));
+        c.attach_hooks();
+        for _ in 0..40 {
+            c.step();
+        }
+        assert_eq!(40, c.trace_hash.as_ref().unwrap().instructions());
+        return c.trace_digest().unwrap();
+    };
+    let code = "
+mov #0x4400 sp
+mov #0x0200 r4
+loop:
+add #3 r5
+mov r5 0(r4)
+push r5
+pop r6
+jmp loop
+";
+    assert_eq!(digest(code, 1), digest(code, 1), "Same firmware, same digest");
+    assert_ne!(digest(code, 1), digest(&code.replace("add #3 r5", "add #5 r5"), 1));
+    assert_ne!(digest(code, 1), digest(code, 4), "Registers are hashed less often");
+    assert_ne!(digest(code, 1), digest(&code.replace("mov r5 0(r4)", "mov r5 2(r4)"), 1), "Memory writes count");
+
+    let c: &mut Computer = &mut Computer::new();
+    assert_eq!(None, c.trace_digest(), "Off unless enabled");
+}
+
+#[test]
+fn lockstep_divergence() {
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x0200 r4
+mov #7 0(r4)
+mov &0x01e0 r5
+mov r5 2(r4)
+done:
+jmp done
+");
+    let image = ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap();
+    let run = |a: &str, b: &str, steps: u64| {
+        let (a, b): (MachineConfig, MachineConfig) = (a.parse().unwrap(), b.parse().unwrap());
+        return Lockstep::new(a.build(&image), b.build(&image)).run(steps);
+    };
+    assert_eq!(Outcome::Agreed(20), run("seed=3", "seed=3", 20), "Same setup, same execution");
+    assert_eq!(Outcome::Agreed(20), run("seed=3", "profile=fr5969,seed=3", 20), "Nothing the profiles disagree on");
+
+    let Outcome::Diverged(divergence) = run("seed=3", "seed=4", 20) else { panic!("The RNG should differ") };
+    assert_eq!(4, divergence.step, "Stops at the read, not the store after it");
+    assert_eq!("mov &0x01e0 r5", divergence.instruction);
+    assert!(matches!(divergence.differences[..], [Difference::Register { register: 5, .. }]), "{}", divergence);
+
+    assert_eq!(MachineConfig { profile: Profile::G2553, seed: 1, dco_tolerance: 2.5 }, "profile=G2553, seed=1,dco=2.5".parse().unwrap());
+    assert!("speed=1".parse::<MachineConfig>().is_err());
+    assert!("dco=60".parse::<MachineConfig>().is_err());
+}
+
+#[test]
+fn instruction_fuzzing() {
+    for word in 0..=0xffffu16 {
+        let decoded: Decoded = decode::decode(word);
+        if decoded != Decoded::None {
+            assert_eq!(word, decode::encode(decoded), "{:?}", decoded);
+        }
+    }
+
+    let report = fuzz::fuzz(1, 2000, 8);
+    assert!(report.failure.is_none(), "{:?}", report.failure);
+    assert!(report.coverage > 200, "Most instruction/flag combinations are reached, got {}", report.coverage);
+
+    let sxt = FuzzInstruction { op: fuzz::Op::Sxt, bw: false, src: None, dst: 9 };
+    let add = FuzzInstruction { op: fuzz::Op::Add, bw: true, src: Some(fuzz::Source::Immediate(0x80)), dst: 4 };
+    let case = FuzzCase { state: FuzzState { registers: [0x1234; 12], sr: 0x0107 }, instructions: vec![add, sxt, add, add] };
+    let shrunk = case.shrink(&|case: &FuzzCase| case.instructions.iter().any(|instruction| instruction.op == fuzz::Op::Sxt));
+    assert_eq!(FuzzCase { state: FuzzState { registers: [0; 12], sr: 0 }, instructions: vec![sxt] }, shrunk);
+    assert_eq!(case, FuzzCase::from_regression(&case.to_regression("note")).unwrap(), "Regression files round-trip");
+
+    let regressions = fuzz::load_regressions(Path::new("src/tests/fuzz_regressions")).unwrap();
+    assert!(!regressions.is_empty());
+    for (name, case) in regressions {
+        assert_eq!(Ok(()), case.check(&mut HashSet::new()), "{}", name);
+    }
+}
+
+#[test]
+fn interrupt_storm() {
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x1111 r4
+mov #0x2222 r5
+loop:
+add #1 r6
+bis #0x18 sr ; LPM0 with GIE, the handlers wake us
+sub #1 r6
+jmp loop
+
+clean:
+push r4
+mov #5 r4
+pop r4
+bic #0x10 0(sp) ; stay awake after returning
+reti
+
+nested:
+push r4
+eint
+mov #7 r4
+mov #8 r4
+dint
+pop r4
+reti
+
+clobber:
+mov #0 r5
+reti
+
+unbalanced:
+push r4
+reti
+
+.interrupt 0xffe0 clean
+.interrupt 0xffe2 nested
+.interrupt 0xffe4 clobber
+.interrupt 0xffe6 unbalanced
+");
+    let run = |vectors: &[u16]| {
+        let c: &mut Computer = &mut Computer::new();
+        execute(c, assembled.trim(), 0);
+        c.storm = Some(InterruptStorm::new(vectors.to_vec(), 5, 1));
+        for _ in 0..2000 {
+            c.step();
+        }
+        return c.storm.take().unwrap();
+    };
+    let storm = run(&[0xffe0, 0xffe2]);
+    assert_eq!(Vec::<StormViolation>::new(), storm.violations, "Handlers that clean up after themselves pass");
+    assert!(storm.delivered > 100 && storm.fired >= storm.delivered);
+    assert_eq!(storm.delivered, storm.returned + storm.depth() as u64, "Every delivered interrupt returned or is still running");
+    assert!(storm.max_depth >= 2, "The nested handler gets interrupted");
+
+    let storm = run(&[0xffe0, 0xffe4]);
+    assert!(!storm.violations.is_empty());
+    assert!(storm.violations.iter().all(|v| v.vector == 0xffe4
+        && v.violation == Violation::Register { register: 5, before: 0x2222, after: 0 }), "{:?}", storm.violations[0]);
+
+    let storm = run(&[0xffe6]);
+    assert!(matches!(storm.violations[0], StormViolation { vector: 0xffe6, depth: 1, violation: Violation::Stack { .. }, .. }));
+    let c: &mut Computer = &mut Computer::new();
+    execute(c, assembled.trim(), 0);
+    assert_eq!(vec![0xffe0, 0xffe2, 0xffe4, 0xffe6], storm::maskable_vectors(c));
+
+    // a faulted CPU is left as it was, fired interrupts aren't entered
+    execute(c, assembled.trim(), 5);
+    c.fault = Some(Fault::Runaway { first: 0x4400, last: 0x4400, cycles: 0 });
+    c.storm = Some(InterruptStorm::new(vec![0xffe0], 1, 1));
+    let sp: u16 = c.sp.get_word();
+    for _ in 0..100 {
+        c.step();
+    }
+    assert_eq!((sp, 0), (c.sp.get_word(), c.storm.as_ref().unwrap().delivered));
+}
+
+#[test]
+fn step_over_out_and_finish_interrupt() {
+    let assembled = assemble("
+mov #0x4400 sp
+eint
+call #outer
+mov #1 r10
+done:
+jmp done
+outer:
+push r4
+call #inner
+pop r4
+ret
+inner:
+mov #5 r5
+ret
+isr:
+mov #9 r9
+push r9
+pop r9
+reti
+.interrupt 0xffe4 isr
+");
+    let trimmed = assembled.trim();
+    let run_until = |c: &mut Computer, goal: StepGoal| {
+        for _ in 0..100 {
+            c.step();
+            if goal.reached(c) {
+                return;
+            }
+        }
+        panic!("{:?} never reached", goal);
+    };
+    let c: &mut Computer = &mut Computer::new();
+    execute(c, &trimmed, 1);
+    assert_eq!(None, StepGoal::step_over(c), "Only a CALL is stepped over");
+    c.step();
+    let goal: StepGoal = StepGoal::step_over(c).unwrap();
+    run_until(c, goal);
+    assert_eq!(0x440a, c.pc.get_word(), "Right after the call");
+    assert_eq!(5, c.get_register(5).get_word(), "Ran the called functions");
+    assert_eq!(0x4400, c.sp.get_word());
+
+    execute(c, &trimmed, 5); // mov, eint, call outer, push r4, call inner
+    let goal: StepGoal = StepGoal::step_out(c);
+    run_until(c, goal);
+    assert_eq!(0x4414, c.pc.get_word(), "Back in outer at `pop r4`");
+    let goal: StepGoal = StepGoal::step_out(c);
+    run_until(c, goal);
+    assert_eq!(0x440a, c.pc.get_word(), "Inner returns don't end the outer frame");
+
+    c.interrupt(0xffe4);
+    c.step(); // mov #9 r9
+    let goal: StepGoal = StepGoal::finish_interrupt(c);
+    run_until(c, goal);
+    assert_eq!(0x440a, c.pc.get_word(), "Back where the interrupt hit, past push/pop in the handler");
+    assert_eq!(9, c.get_register(9).get_word());
+}
+
+#[test]
+fn status_block() {
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut loaded = LoadedImage::default();
+    let mut image = ProgramImage::new();
+    image.segments.push(image::Segment { address: 0x4400, data: vec![0x43, 0x03] });
+    image.segments.push(image::Segment { address: 0xfffe, data: vec![0x44, 0x00] });
+    loaded.load(&image, "blink.bin", 0x4400);
+    let checksum: u32 = image.checksum();
+    mem.write_status(7, Some(1234), &RunMode::Stepping(StepRun::count(3)), Some(StopReason::Breakpoint(1)), &loaded, Flags::from_sr(0x0109));
+    drop(mem);
+
+    let word = |buffer: &[u8], offset: usize| u32::from_be_bytes(buffer[layout::STATUS + offset..layout::STATUS + offset + 4].try_into().unwrap());
+    assert_eq!(7, word(&buffer, 0x00));
+    assert_eq!(std::process::id(), word(&buffer, 0x04));
+    assert_eq!(1234, word(&buffer, 0x08));
+    assert_eq!(2, buffer[layout::STATUS + 0x0c]);
+    assert_eq!(1, buffer[layout::STATUS + 0x0d]);
+    assert_eq!(1, word(&buffer, 0x10));
+    assert_eq!(checksum, word(&buffer, 0x14));
+    assert_eq!(b"blink.bin\0", &buffer[layout::STATUS_NAME..layout::STATUS_NAME + 10]);
+    assert_eq!([0x44, 0x00], buffer[layout::STATUS_ENTRY..layout::STATUS_ENTRY + 2]);
+    assert_eq!([1, 0, 0, 1, 0, 0, 0, 0, 1], buffer[layout::STATUS_FLAGS..layout::STATUS_FLAGS + 9], "C, GIE and V");
+    assert_eq!([0, 2], buffer[layout::SEGMENTS..layout::SEGMENTS + 2]);
+    let segment = |buffer: &[u8], i: usize| buffer[layout::SEGMENT_ENTRIES + i * 8..layout::SEGMENT_ENTRIES + i * 8 + 8].to_vec();
+    assert_eq!([&[0x44, 0x00, 0x44, 0x01][..], &utils::crc32(&[0x43, 0x03]).to_be_bytes()].concat(), segment(&buffer, 0));
+    assert_eq!([0xff, 0xfe, 0xff, 0xff], segment(&buffer, 1)[..4]);
+
+    image.segments[0].data[1] = 0x13;
+    assert_ne!(checksum, image.checksum(), "Any changed byte changes the identity");
+    loaded.overlay(&image, "patch.bin");
+    assert_eq!(2, loaded.generation);
+    assert_eq!("blink.bin + patch.bin", loaded.name);
+    assert_ne!(image.checksum(), loaded.checksum, "An overlay's checksum covers the image below");
+    assert_eq!(0x4400, loaded.entry, "Overlays keep the entry point");
+    assert_eq!(4, loaded.segments.len());
+    assert_eq!(loaded.segments[0].region, loaded.segments[2].region);
+    assert_ne!(loaded.segments[0].checksum, loaded.segments[2].checksum);
+
+    loaded.name = "x".repeat(1000);
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_status(8, None, &RunMode::Stopped, None, &loaded, Flags::default());
+    drop(mem);
+    assert_eq!(0, word(&buffer, 0x08));
+    assert_eq!(0, buffer[layout::STATUS_FLAGS - 1], "Long names are cut to fit the block");
+    assert_eq!(b'x', buffer[layout::STATUS_FLAGS - 2]);
+    assert_eq!([0; 9], buffer[layout::STATUS_FLAGS..layout::STATUS_FLAGS + 9]);
+
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut heartbeat: u32 = 8;
+    let read = host_io(Duration::from_millis(1), || {
+        std::thread::sleep(Duration::from_millis(30));
+        "image"
+    }, || mem.write_busy(&mut heartbeat, 4));
+    assert_eq!("image", read);
+    assert!(heartbeat > 9, "The heartbeat keeps moving during slow host I/O: {}", heartbeat);
+    drop(mem);
+    assert_eq!(heartbeat, word(&buffer, 0x00));
+    assert_eq!(4, buffer[layout::STATUS_BUSY]);
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_status(heartbeat + 1, None, &RunMode::Stopped, None, &loaded, Flags::default());
+    drop(mem);
+    assert_eq!(0, buffer[layout::STATUS_BUSY], "Done once the status is written again");
+}
+
+#[test]
+fn run_log_lines() {
+    let fields = [("path", serde_json::json!("blink.bin")), ("pc", serde_json::json!(0x4400))];
+    let text = RunLog::new(LogFormat::Text, LogLevel::Info);
+    assert_eq!("Loaded blink.bin", text.format_line(12.5, LogLevel::Info, "load", "Loaded blink.bin", &fields));
+    assert!(text.enabled(LogLevel::Error));
+    assert!(!text.enabled(LogLevel::Debug), "Commands are only logged at debug level");
+
+    let json = RunLog::new(LogFormat::Json, LogLevel::Debug);
+    let line: String = json.format_line(12.3456, LogLevel::Warn, "load", "Loaded\nblink.bin", &fields);
+    assert!(!line.contains('\n'), "One record per line");
+    let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(12.346, record["time"]);
+    assert_eq!("warn", record["level"]);
+    assert_eq!("load", record["event"]);
+    assert_eq!("Loaded\nblink.bin", record["message"]);
+    assert_eq!("blink.bin", record["path"]);
+    assert_eq!(0x4400, record["pc"]);
+}
+
+#[test]
+fn run_metrics() {
+    let started = std::time::Instant::now();
+    let mut metrics = RunMetrics::new();
+    for _ in 0..3000 {
+        metrics.instruction();
+    }
+    metrics.command();
+    metrics.synced(std::time::Duration::from_micros(30));
+    metrics.synced(std::time::Duration::from_micros(10));
+    let field = |bytes: &[u8; METRICS_SIZE], offset: usize, size: usize| bytes[offset..offset + size].iter().fold(0u64, |v, b| (v << 8) | *b as u64);
+
+    metrics.sample(started);
+    let bytes = metrics.encode(12345);
+    assert_eq!(3000, field(&bytes, 0, 8));
+    assert_eq!(12345, field(&bytes, 8, 8));
+    assert_eq!(1, field(&bytes, 16, 4));
+    assert_eq!(0, field(&bytes, 20, 4), "No rate before a window completed");
+    assert_eq!(10, field(&bytes, 24, 4), "Last sync");
+    assert_eq!(20, field(&bytes, 28, 4), "Average sync");
+    assert_eq!(2, field(&bytes, 32, 8));
+
+    metrics.sample(started + std::time::Duration::from_secs(3));
+    let rate: u64 = field(&metrics.encode(0), 20, 4);
+    assert!((990..=1000).contains(&rate), "About 3000 instructions over 3 s, got {}", rate);
+}
+
+#[test]
+fn command_encoding_round_trip() {
+    let commands: Vec<ShmemCommands> = vec![
+        ShmemCommands::Stop, ShmemCommands::Run, ShmemCommands::Step(300),
+        ShmemCommands::LoadFile("blink.bin".to_string()), ShmemCommands::SetMem(0x0200, 0xbeef),
+        ShmemCommands::Interrupt(0xffe4), ShmemCommands::Seed(0x0123456789abcdef), ShmemCommands::PcHistory,
+        ShmemCommands::AddWatch("b[r4+3]".to_string()), ShmemCommands::RemoveWatch(2),
+        ShmemCommands::Evaluate("main+2".to_string()), ShmemCommands::MailboxSend(1, 0x1234),
+        ShmemCommands::MailboxReceive(0),
+        ShmemCommands::SetTrigger(3, Some(Trigger { kind: TriggerKind::Write, address: 0x0200, address_mask: 0x000f,
+                                                    data: Some((0x00ff, 0xff00)) })),
+        ShmemCommands::SetTrigger(1, None), ShmemCommands::SetBreakpoint(2, 0b101), ShmemCommands::EemInfo,
+        ShmemCommands::Supply(1800, 70000), ShmemCommands::PwmMeasurement("P1.2".parse().unwrap()),
+        ShmemCommands::LedStrip("P2.7".parse().unwrap()), ShmemCommands::Encoder(1, -3, Some(true)),
+        ShmemCommands::Encoder(0, 300, None), ShmemCommands::Key(0, 3, 2, true), ShmemCommands::Key(1, 0, 0, false),
+        ShmemCommands::OverlayFile("patch.bin".to_string()),
+        ShmemCommands::Dump(Some(DumpFormat::TiTxt), Region { start: 0x4400, end: 0x44ff }, "out.txt".to_string()),
+        ShmemCommands::StepOver, ShmemCommands::StepOut, ShmemCommands::FinishInterrupt, ShmemCommands::StopReason,
+        ShmemCommands::Attach(4321), ShmemCommands::InterruptVectors, ShmemCommands::Peripheral(6),
+        ShmemCommands::OscillatorFault(Crystal::Hfxt, true), ShmemCommands::OscillatorFault(Crystal::Lfxt, false),
+        ShmemCommands::TouchPad("P2.5:12.5".parse().unwrap()), ShmemCommands::PowerCycle(1_000_000),
+        ShmemCommands::ReadMemory(Region { start: 0x0200, end: 0xffff }), ShmemCommands::LoadBinary(0x4400, rle::encode(&[0, 0, 0, 7])),
+        ShmemCommands::Notify(1 << notify::KIND_UART | 1 << 5), ShmemCommands::Reset(ResetKind::Puc), ShmemCommands::Reset(ResetKind::Bor),
+        ShmemCommands::StepWith(StepRun::with_options(300, 0b11)), ShmemCommands::StepWith(StepRun::with_options(1, 0)),
+        ShmemCommands::SaveSnapshot("state.snap".to_string()), ShmemCommands::LoadSnapshot("state.snap".to_string()),
+        ShmemCommands::Shutdown(Some("final.snap".to_string())), ShmemCommands::Shutdown(None), ShmemCommands::FrameBuffer,
+    ];
+    let mut buffer: Vec<u8> = vec![0xaa; layout::SIZE]; // stale bytes must not leak into commands
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    for command in commands {
+        mem.write_command(&command.encode());
+        assert_eq!(format!("{:?}", command), format!("{:?}", mem.get_command()));
+    }
+}
+
+#[test]
+fn compressed_memory_blocks() {
+    let data: Vec<u8> = [vec![0; 300], vec![1, 2, 3], vec![0xff; 129], (0..=255).collect()].concat();
+    let coded: Vec<u8> = rle::encode(&data);
+    assert_eq!(2 * 3 + 4 + 2 + 2 * 129, coded.len(), "Three zero runs, three literals, one 0xff run, 256 literals");
+    assert_eq!(Some(data.clone()), rle::decode(&coded));
+    assert_eq!(Some(vec![]), rle::decode(&[]));
+    assert_eq!(None, rle::decode(&[5, 1, 2]), "Literal block cut short");
+    assert_eq!(None, rle::decode(&[0x80]), "Run without its byte");
+
+    let (prefix, covered) = rle::encode_prefix(&data, 50);
+    assert!(prefix.len() <= 50 && covered > 300 && covered < data.len());
+    assert_eq!(Some(data[..covered].to_vec()), rle::decode(&prefix));
+
+    let mut memory = MemoryMap::new();
+    memory.set_word(0x4400, 0x4031);
+    for address in 0xc000..=0xffffu16 {
+        memory.set_byte(address, (address >> 3) as u8);
+    }
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_memory_block(&memory, Region { start: 0x0000, end: 0xffff });
+    drop(mem);
+    let reply = &buffer[layout::COMMAND + 1..layout::EVENTS];
+    let last: u16 = u16::from_be_bytes([reply[1], reply[2]]);
+    let length: usize = u16::from_be_bytes([reply[3], reply[4]]) as usize;
+    assert_eq!(0, reply[0]);
+    assert!(last > 0xc000 && last < 0xffff, "Stops where the command area is full");
+    let bytes: Vec<u8> = rle::decode(&reply[5..5 + length]).unwrap();
+    assert_eq!(last as usize + 1, bytes.len());
+    assert_eq!([0x40, 0x31], bytes[0x4400..0x4402]);
+    assert!((0..=last).all(|address| bytes[address as usize] == memory.get_byte(address)));
+}
+
+#[test]
+fn disassembly_mirror() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+call #0x4410
+jmp 0
+");
+    execute(c, assembled.trim(), 1);
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write(c);
+    drop(mem);
+    let entry = |i: usize| {
+        let bytes: &[u8] = &buffer[layout::DISASSEMBLY_ENTRIES + i * layout::DISASSEMBLY_SIZE..][..layout::DISASSEMBLY_SIZE];
+        let end: usize = bytes[4..].iter().position(|b| *b == 0).unwrap();
+        return (u16::from_be_bytes([bytes[0], bytes[1]]), bytes[2], String::from_utf8(bytes[4..4 + end].to_vec()).unwrap());
+    };
+    assert_eq!(layout::DISASSEMBLY_SLOTS as u8, buffer[layout::DISASSEMBLY]);
+    assert_eq!((0x4404, 4, "call #0x4410".to_string()), entry(0), "The instruction at the PC");
+    assert_eq!((0x4408, 2, "jmp 0x4408".to_string()), entry(1));
+    assert_eq!((0x440a, 2, ".word 0x0000".to_string()), entry(2), "Empty memory");
+}
+
+#[test]
+fn notifications() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x48 &0x01c0
+mov #0x69 &0x01c0
+");
+    execute(c, assembled.trim(), 0);
+    let mut notifier = Notifier::new(c);
+    c.step();
+    assert!(notifier.poll(c).is_empty(), "Nothing subscribed");
+    assert_eq!(None, notifier.stopped(StopReason::Breakpoint(2), c));
+
+    notifier.subscribe(1 << notify::KIND_UART | 1 << notify::KIND_RESET | 1 << StopReason::Breakpoint(0).id());
+    c.step();
+    assert_eq!(vec![WatchEvent { id: 0xff08, old: 1, new: 0x69, pc: c.pc.get_word() }], notifier.poll(c),
+               "Only the byte sent since the last poll");
+    assert!(notifier.poll(c).is_empty());
+    assert_eq!(Some(WatchEvent { id: 0xff01, old: 2, new: 0, pc: c.pc.get_word() }), notifier.stopped(StopReason::Breakpoint(2), c));
+    assert_eq!(None, notifier.stopped(StopReason::Step, c));
+
+    c.memory.set_word(0xfffe, 0x4400);
+    c.power_cycle(100);
+    c.power_cycle(100);
+    assert_eq!(vec![WatchEvent { id: 0xff07, old: 3, new: 2, pc: 0x4400 }], notifier.poll(c));
+}
+
+#[test]
+fn startup_script() {
+    let c: &mut Computer = &mut Computer::new();
+    c.get_register(4).set_word(0x0200);
+    let symbols = vec![Symbol { name: "main".to_string(), address: 0x4400 }];
+    let mut script = Script::parse("
+# breakpoints first
+load blink.elf
+break main+4
+set r4+2 = 0x1234
+watch &r4
+run
+step
+step 3
+dump 0x0200-0x02ff:hex:out.hex
+reset puc
+encoder 0 -2
+encoder 1 press
+key 0 3 1 release
+").unwrap();
+    let mut commands: Vec<String> = Vec::new();
+    while let Some(command) = script.next(c, &symbols) {
+        commands.push(format!("{:?}", command.unwrap()));
+    }
+    assert!(script.is_done());
+    let expected = [
+        ShmemCommands::LoadFile("blink.elf".to_string()),
+        ShmemCommands::SetTrigger(0, Some(Trigger { kind: TriggerKind::Fetch, address: 0x4404, address_mask: 0, data: None })),
+        ShmemCommands::SetBreakpoint(0, 1),
+        ShmemCommands::SetMem(0x0202, 0x1234),
+        ShmemCommands::AddWatch("&r4".to_string()),
+        ShmemCommands::Run, ShmemCommands::Step(1), ShmemCommands::Step(3),
+        ShmemCommands::Dump(Some(DumpFormat::Hex), Region { start: 0x0200, end: 0x02ff }, "out.hex".to_string()),
+        ShmemCommands::Reset(ResetKind::Puc),
+        ShmemCommands::Encoder(0, -2, None), ShmemCommands::Encoder(1, 0, Some(true)), ShmemCommands::Key(0, 3, 1, false),
+    ];
+    assert_eq!(expected.iter().map(|c| format!("{:?}", c)).collect::<Vec<String>>(), commands);
+
+    assert_eq!("line 3: unknown command 'continue'", Script::parse("run\n\ncontinue").err().unwrap());
+    assert_eq!("line 1: 'set' needs ADDRESS = VALUE", Script::parse("set 0x200").err().unwrap());
+    assert!(Script::parse("step 0").is_err());
+    assert_eq!("line 1: 'key' needs INDEX ROW COLUMN press|release", Script::parse("key 0 1 press").err().unwrap());
+    let mut script = Script::parse("set nowhere = 1\nrun").unwrap();
+    assert!(script.next(c, &symbols).unwrap().unwrap_err().starts_with("line 1: 'nowhere'"));
+    let mut script = Script::parse(&"break 0x4400\n".repeat(5)).unwrap();
+    let errors: Vec<String> = std::iter::from_fn(|| script.next(c, &symbols)).filter_map(|r| r.err()).collect();
+    assert_eq!(vec!["line 5: at most 4 breakpoints".to_string()], errors);
+}
+
+#[test]
+fn replay_recording() {
+    let path = std::env::temp_dir().join(format!("msp430_recording_test_{}.jsonl", std::process::id()));
+    let path_str: String = path.to_str().unwrap().to_string();
+    let args: Vec<String> = vec!["run".to_string(), "--seed".to_string(), "5".to_string()];
+    let mut recorder = CommandRecorder::create(&path_str, &args).unwrap();
+    recorder.record(&RecordedCommand { instructions: 0, cycles: 0, bytes: vec![3, 0, 2] }, "Step(2)").unwrap();
+    recorder.record(&RecordedCommand { instructions: 2, cycles: 4, bytes: vec![2] }, "Run").unwrap();
+    recorder.finish(RecordedEnd { instructions: 9, cycles: 20, pc: 0x4410 }).unwrap();
+    drop(recorder);
+
+    let mut recording = Recording::load(&path_str).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(args, recording.args);
+    assert!(!recording.finished(0, 0, true));
+    assert_eq!(ReplayStep::Command(vec![3, 0, 2]), recording.next(0, 0, true));
+    assert_eq!(ReplayStep::Wait, recording.next(1, 2, false), "Not there yet");
+    assert_eq!(ReplayStep::Command(vec![2]), recording.next(2, 4, true));
+    assert_eq!(2, recording.replayed());
+    assert!(!recording.finished(5, 10, false));
+    assert!(!recording.finished(9, 18, false), "Asleep before the recorded end");
+    assert!(recording.finished(9, 20, false), "Ends where the recorded run ended");
+
+    let mut recording = Recording::parse("{\"args\":[\"run\"]}\n{\"instructions\":7,\"cycles\":9,\"command\":\"01\"}\n").unwrap();
+    assert!(matches!(recording.next(3, 5, true), ReplayStep::Diverged(_)), "A stopped run never gets there");
+    assert!(matches!(recording.next(8, 10, false), ReplayStep::Diverged(_)), "Went past it");
+    assert_eq!(ReplayStep::Wait, recording.next(7, 8, false), "Asleep until its cycle");
+    assert!(matches!(recording.next(7, 8, true), ReplayStep::Diverged(_)), "Stopped short of its cycle");
+    assert!(matches!(recording.next(7, 10, false), ReplayStep::Diverged(_)), "Slept past its cycle");
+    assert!(Recording::parse("{\"args\":[\"run\"]}\n{\"instructions\":1,\"cycles\":1,\"command\":\"0\"}").is_err());
+    assert!(Recording::parse("").is_err());
+}
+
+#[test]
+fn deterministic_arguments() {
+    let args = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<String>>();
+    let error = RunForkedArgs::try_parse_from(args("run --deterministic --time-source host")).err().unwrap();
+    assert_eq!(clap::error::ErrorKind::ArgumentConflict, error.kind(), "Refused by the argument parser");
+    let deterministic: RunForkedArgs = RunForkedArgs::try_parse_from(args("run --deterministic")).unwrap();
+    assert_eq!(TimeSource::Emulated, deterministic.time_source);
+    assert_eq!(deterministic.to_args(), replay_args(&deterministic.to_args()).unwrap().to_args(), "Its arguments parse back");
+
+    let host: RunForkedArgs = RunForkedArgs::try_parse_from(args("run --time-source host")).unwrap();
+    assert_eq!(clap::error::ErrorKind::InvalidValue, replay_args(&host.to_args()).err().unwrap().kind(),
+               "A host time recording can't be replayed");
+}
+
+#[test]
+fn interrupt_vector_map() {
+    let c: &mut Computer = &mut Computer::new();
+    c.memory.set_word(0xfffe, 0x4400);
+    c.memory.set_word(0xffe4, 0x4480);
+    c.memory.set_word(0xffee, 0xffff);
+    c.devices.gpio.write_byte(0x0025, 0x01); // P1IE
+    c.devices.gpio.write_byte(0x0023, 0x01); // P1IFG
+    c.devices.uart.receive(b"x"); // flagged, but the RX interrupt isn't enabled
+    let symbols = vec![Symbol { name: "main".to_string(), address: 0x4400 }, Symbol { name: "port1_isr".to_string(), address: 0x4480 }];
+
+    let entries = interrupts::vector_map(c, &symbols);
+    assert_eq!(16, entries.len());
+    let entry = |vector: u16| entries.iter().find(|e| e.vector == vector).unwrap();
+    assert_eq!(Some("port1_isr".to_string()), entry(0xffe4).symbol);
+    assert!(entry(0xffe4).pending());
+    assert!(!entry(0xffe6).populated() && !entry(0xffe6).enabled(), "P2 has a device but no handler");
+    assert!(!entry(0xffee).populated(), "Erased flash");
+    assert!(entry(0xffee).flagged() && !entry(0xffee).pending());
+    assert!(entry(0xfffc).nmi());
+    assert_eq!(Some("main".to_string()), entry(0xfffe).symbol);
+    assert!(!entry(0xffe0).populated() && entry(0xffe0).sources.is_empty());
+
+    let text: String = interrupts::describe(&entries);
+    assert!(text.contains("0xffe4 -> 0x4480 port1_isr"), "{}", text);
+    assert!(text.contains("port 1 pending"));
+    assert!(text.contains("UART RX flagged"));
+    assert!(!text.contains("0xffe0"), "Unused slots without a device are left out");
+
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_vector_map(&entries, true);
+    drop(mem);
+    let reply: &[u8] = &buffer[layout::COMMAND + 1..];
+    assert_eq!(1, reply[0], "GIE");
+    let p1: &[u8] = &reply[1 + 2 * 3..1 + 3 * 3];
+    assert_eq!([0x44, 0x80, 0b10111], p1, "Handler, then enabled, flagged, pending, has a source");
+    let symbols: Vec<&[u8]> = reply[1 + 16 * 3..].split(|b| *b == 0).take(16).collect();
+    assert_eq!(b"port1_isr", symbols[2]);
+    assert_eq!(b"main", symbols[15]);
+}
+
+#[test]
+fn peripheral_registers() {
+    let c: &mut Computer = &mut Computer::new();
+    c.devices.gpio.write_byte(0x0022, 0x41); // P1DIR
+    c.devices.gpio.write_byte(0x0021, 0x01); // P1OUT
+    c.devices.mpu.write_word(0x05a0, 0xa511); // MPUENA, MPUSEGIE
+    c.devices.mpu.write_word(0x05a8, 0x7715);
+    c.devices.mailbox.host_write(0, 0xbeef);
+    c.devices.uart.receive(b"ab");
+
+    let views = peripherals::describe(c);
+    let view = |name: &str| views.iter().find(|v| v.name == name).unwrap().to_string();
+    let port1: String = view("Port 1");
+    assert!(port1.contains("P1DIR       0x0022 = 0x41   output=P1.0,P1.6"), "{}", port1);
+    assert!(port1.contains("P1IN        0x0020 = 0x01   high=P1.0"), "{}", port1);
+    let mpu: String = view("Memory protection unit");
+    assert!(mpu.contains("MPUCTL0     0x05a0 = 0x9611 MPUENA=1 MPULOCK=0 MPUSEGIE=1"), "{}", mpu);
+    assert!(mpu.contains("SEG1=r-x- SEG2=r--- SEG3=rwx- INFO=rwx-"), "{}", mpu);
+    assert!(view("Supply supervisor").contains("supply=3.30V"));
+    assert!(view("UART").contains("RXAVAIL=1 TXREADY=1 rx_queued=2"));
+
+    // describing reads nothing the way firmware would
+    assert!(view("JTAG mailbox").contains("SYSJMBI0    0x0188 = 0xbeef"));
+    assert!(view("JTAG mailbox").contains("JMBIN0FG=1"));
+    assert_eq!(b'a' as u16, c.read_word(0x01c2));
+
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_peripheral(&views, 5);
+    drop(mem);
+    let reply: &[u8] = &buffer[layout::COMMAND + 1..layout::EVENTS];
+    assert_eq!([0, views.len() as u8], reply[..2]);
+    let text: &[u8] = reply[2..].split(|b| *b == 0).next().unwrap();
+    assert_eq!(view("Memory protection unit").as_bytes(), text);
+
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    mem.write_peripheral(&views, 200);
+    drop(mem);
+    assert_eq!([1, views.len() as u8, 0], buffer[layout::COMMAND + 1..layout::COMMAND + 4]);
+}
+
+#[test]
+fn batched_run_modes() {
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0 r5
+loop:
+add #1 r5
+jmp loop
+");
+    let c: &mut Computer = &mut Computer::new();
+    ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().load(c);
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut watches = WatchList::new();
+    let mut metrics = RunMetrics::new();
+    let mut stop_reason: Option<StopReason> = None;
+
+    let mut run_mode = RunMode::Running;
+    assert_eq!(100, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics));
+    assert!(matches!(run_mode, RunMode::Running));
+
+    let mut run_mode = RunMode::Stepping(StepRun::count(5));
+    assert_eq!(5, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics), "Ends with the mode");
+    assert!(matches!(run_mode, RunMode::Stopped));
+    assert_eq!(Some(StopReason::Step), stop_reason);
+    assert_eq!(0, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics));
+    assert_eq!(105, metrics.instructions());
+    let mut run_mode = RunMode::Running;
+    let end: u64 = c.clock.cycles() + 10;
+    let ran: u64 = run_batch(c, &mut run_mode, &mut stop_reason, 100, end, &mut watches, &mut mem, &mut metrics);
+    assert!(ran < 100 && (end..end + 2).contains(&c.clock.cycles()), "Ends at the quantum boundary");
+
+    let mut batch = BatchSizer::new(std::time::Duration::from_millis(10));
+    let first: u64 = batch.size();
+    batch.update(first / 4, std::time::Duration::from_millis(1));
+    assert_eq!(first, batch.size(), "Short batches don't count");
+    for _ in 0..20 {
+        let size: u64 = batch.size();
+        batch.update(size, std::time::Duration::from_nanos(size * 50)); // 20 M instructions a second
+    }
+    assert!((190_000..=200_000).contains(&batch.size()), "About 10 ms worth, got {}", batch.size());
+    for _ in 0..40 {
+        let size: u64 = batch.size();
+        batch.update(size, std::time::Duration::from_secs(size));
+    }
+    assert_eq!(1_000, batch.size(), "Never below the minimum");
+
+    let mut batch = BatchSizer::fixed(scheduler::FIXED_BATCH);
+    batch.update(scheduler::FIXED_BATCH, std::time::Duration::from_secs(1));
+    assert_eq!(scheduler::FIXED_BATCH, batch.size(), "Deterministic runs don't adapt");
+}
+
+#[test]
+fn step_options() {
+    let assembled = assemble("
+mov #0 r5
+add #1 r5
+add #1 r5
+bis #0x10 sr
+add #1 r5
+add #1 r5
+add #1 r5
+");
+    let c: &mut Computer = &mut Computer::new();
+    ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().load(c);
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut watches = WatchList::new();
+    let mut metrics = RunMetrics::new();
+    let mut stop_reason: Option<StopReason> = None;
+    c.eem.set_trigger(0, Some(Trigger { kind: TriggerKind::Fetch, address: 0x4404, address_mask: 0, data: None }));
+    c.eem.set_breakpoint(0, 1);
+
+    let mut run_mode = RunMode::Stepping(StepRun::with_options(10, 0b10));
+    run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics);
+    assert_eq!(Some(StopReason::CpuOff), stop_reason, "Passed over the breakpoint, stopped once the CPU was off");
+    assert_eq!((4, 2), (c.retired, c.get_register(5).get_word()));
+    assert!(c.eem.armed(), "Breakpoints are back after the step");
+
+    mem.write_stop_reason(stop_reason, c.pc.get_word(), c.retired);
+    assert_eq!([6, 0xff, 0x44, 0x0a, 0, 0, 0, 4], buffer[layout::COMMAND + 1..layout::COMMAND + 9]);
+
+    stop_reason = None;
+    let mut run_mode = RunMode::Stepping(StepRun::count(3));
+    let cycles: u64 = c.clock.cycles();
+    assert_eq!(0, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics),
+               "Idle steps execute nothing");
+    assert!(matches!(&run_mode, RunMode::Idle(resume) if matches!(**resume, RunMode::Stepping(StepRun { remaining: 3, .. }))),
+            "CPUOFF keeps the step count");
+    assert_eq!((None, 4, 4), (stop_reason, c.retired, run_mode.id()));
+    assert!(c.clock.cycles() > cycles, "Time passes while the CPU is off");
+
+    c.sr.set_word(0); // as if an interrupt returned with CPUOFF cleared
+    assert_eq!(3, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics));
+    assert_eq!((Some(StopReason::Step), 7, 5), (stop_reason, c.retired, c.get_register(5).get_word()), "The step resumed once awake");
+
+    c.fault = Some(Fault::Runaway { first: 0x4410, last: 0x4410, cycles: 1 });
+    let mut run_mode = RunMode::Stepping(StepRun::count(3));
+    assert_eq!(0, run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics));
+    assert_eq!((Some(StopReason::Fault), 7), (stop_reason, c.retired), "A faulted CPU stops the step right away");
+}
+
+#[test]
+fn cpu_off_woken_by_interrupt() {
+    let assembled = assemble("
+mov #0x4400 sp
+mov #1 &0x01c6 ; enable RX interrupt
+bis #0x18 sr ; LPM0 with GIE
+mov #1 r6
+loop:
+jmp loop
+
+handler:
+mov &0x01c2 r5
+bic #0x10 0(sp) ; stay awake after returning
+reti
+
+.interrupt 0xffee handler
+");
+    let c: &mut Computer = &mut Computer::new();
+    ProgramImage::parse(&general_purpose::STANDARD.decode(assembled.trim()).unwrap()).unwrap().load(c);
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut watches = WatchList::new();
+    let mut metrics = RunMetrics::new();
+    let mut stop_reason: Option<StopReason> = None;
+
+    let mut run_mode = RunMode::Running;
+    let executed: u64 = run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics);
+    assert!(matches!(&run_mode, RunMode::Idle(resume) if matches!(**resume, RunMode::Running)), "Asleep, {}", run_mode.name());
+    assert_eq!((3, 0), (c.retired, c.get_register(6).get_word()), "Nothing executes while the CPU is off");
+    assert_eq!((3, 3), (executed, metrics.instructions()), "Idle steps aren't counted as instructions");
+    assert!(c.clock.cycles() >= 100, "The batch still ended, after 100 idle steps");
+
+    c.devices.uart.receive(b"A");
+    run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics);
+    assert!(matches!(run_mode, RunMode::Running), "The interrupt woke it, {}", run_mode.name());
+    assert_eq!((None, 0x41, 1), (stop_reason, c.get_register(5).get_word(), c.get_register(6).get_word()),
+               "The handler ran and the code after the sleep");
+}
+
+#[test]
+fn step_masking_interrupts() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #1 &0x01c6 ; enable RX interrupt
+eint
+loop:
+jmp loop
+
+handler:
+mov &0x01c2 r5
+reti
+
+.interrupt 0xffee handler
+");
+    execute(c, assembled.trim(), 4);
+    c.devices.uart.receive(b"A");
+    let loop_pc: u16 = c.pc.get_word();
+    let mut buffer: Vec<u8> = vec![0; layout::SIZE];
+    let mut mem = SharedMemorySystem::new(buffer.as_mut_ptr());
+    let mut watches = WatchList::new();
+    let mut metrics = RunMetrics::new();
+    let mut stop_reason: Option<StopReason> = None;
+
+    let mut run_mode = RunMode::Stepping(StepRun::with_options(5, 0b101));
+    run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics);
+    assert_eq!((Some(StopReason::Step), loop_pc, 0), (stop_reason, c.pc.get_word(), c.get_register(5).get_word()),
+               "The handler waited");
+    assert!(c.devices.uart.pending_interrupt().is_some(), "The interrupt is still pending after the step");
+
+    let mut run_mode = RunMode::Stepping(StepRun::count(2));
+    run_batch(c, &mut run_mode, &mut stop_reason, 100, u64::MAX, &mut watches, &mut mem, &mut metrics);
+    assert_eq!(0x41, c.get_register(5).get_word(), "A plain step takes the interrupt");
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0 r5
+loop:
+add #1 r5
+mov r5 &0x0200
+mov &0x01e0 r6 ; random number
+mov.b r5 &0x01c0 ; UART transmit
+jmp loop
+");
+    execute(c, assembled.trim(), 20);
+    c.memory.set_byte_20(0x12345, 0x77);
+    c.devices.uart.receive(b"in");
+    let file: Vec<u8> = c.save_snapshot();
+
+    let restored: &mut Computer = &mut Computer::new();
+    let report = restored.restore_snapshot(&file).unwrap();
+    assert_eq!(snapshot::RestoreReport::default(), report, "Nothing missing or unknown");
+    assert_eq!(0x77, restored.memory.get_byte_20(0x12345), "Extended memory came along");
+    for _ in 0..30 {
+        c.step();
+        restored.step();
+    }
+    assert_eq!(c.register_words(), restored.register_words());
+    assert_eq!((c.clock.cycles(), c.retired), (restored.clock.cycles(), restored.retired));
+    assert_eq!(c.devices.uart.transmitted(), restored.devices.uart.transmitted());
+    assert_eq!(c.memory.get_word(0x0200), restored.memory.get_word(0x0200));
+    assert_eq!(c.save_snapshot(), restored.save_snapshot(), "The same state from there on");
+
+    let mut corrupted: Vec<u8> = file.clone();
+    corrupted[20] ^= 1;
+    assert!(restored.restore_snapshot(&corrupted).unwrap_err().contains("CRC"));
+}
+
+#[test]
+fn snapshot_compatibility() {
+    let c: &mut Computer = &mut Computer::new();
+    execute(c, assemble("mov #0x1234 r5\nmov #0x5678 r6\n").trim(), 2);
+    c.devices.uart.write_word(crate::devices::uart::UART_TX, 0x41);
+    c.devices.rtc.write_word(crate::devices::rtc::RTC_SECONDS, 100, &c.clock);
+    let sections: Vec<snapshot::Section> = snapshot::parse(&c.save_snapshot()).unwrap();
+    let edited = |edit: &dyn Fn(&mut Vec<snapshot::Section>)| {
+        let mut sections: Vec<snapshot::Section> = sections.clone();
+        edit(&mut sections);
+        return snapshot::write(&sections);
+    };
+
+    // a later version added a device and a field to the UART, and left out the RTC
+    let newer: Vec<u8> = edited(&|sections| {
+        sections.retain(|s| &s.tag != b"RTC ");
+        sections.iter_mut().find(|s| &s.tag == b"UART").unwrap().data.extend_from_slice(&[1, 2, 3]);
+        sections.push(snapshot::Section { tag: *b"DMA ", version: 3, data: vec![9; 10] });
+    });
+    let restored: &mut Computer = &mut Computer::new();
+    let report = restored.restore_snapshot(&newer).unwrap();
+    assert_eq!((vec!["RTC".to_string()], vec!["DMA".to_string()]), (report.defaulted, report.preserved));
+    assert_eq!((0x1234, 0x5678), (restored.get_register(5).get_word(), restored.get_register(6).get_word()));
+    assert_eq!((1, 0x41), restored.devices.uart.transmitted(), "Fields after the known ones are skipped");
+    let resaved: Vec<snapshot::Section> = snapshot::parse(&restored.save_snapshot()).unwrap();
+    assert_eq!(Some(&snapshot::Section { tag: *b"DMA ", version: 3, data: vec![9; 10] }), resaved.last(),
+               "The unknown section is written back");
+
+    // an older version didn't have the UART's last byte yet, and a newer one changed the MPU incompatibly
+    let older: Vec<u8> = edited(&|sections| {
+        sections.iter_mut().find(|s| &s.tag == b"UART").unwrap().data.pop();
+        sections.iter_mut().find(|s| &s.tag == b"MPU ").unwrap().version = 2;
+    });
+    let report = restored.restore_snapshot(&older).unwrap();
+    assert_eq!((vec!["MPU".to_string()], Vec::<String>::new()), (report.defaulted, report.preserved));
+    assert_eq!((1, 0), restored.devices.uart.transmitted(), "A missing trailing field is defaulted");
+
+    let no_memory: Vec<u8> = edited(&|sections| sections.retain(|s| &s.tag != b"MEM "));
+    assert!(restored.restore_snapshot(&no_memory).unwrap_err().contains("MEM"), "Memory can't be defaulted");
+    let newer_cpu: Vec<u8> = edited(&|sections| sections[0].version = 2);
+    assert!(restored.restore_snapshot(&newer_cpu).unwrap_err().contains("newer"));
+
+    let truncated_memory: Vec<u8> = edited(&|sections| sections.iter_mut().find(|s| &s.tag == b"MEM ").unwrap().data.truncate(8));
+    let before: Vec<u8> = restored.save_snapshot();
+    assert!(restored.restore_snapshot(&truncated_memory).unwrap_err().contains("memory section is corrupted"));
+    assert_eq!(before, restored.save_snapshot(), "The running machine is untouched");
+}
@@ -0,0 +1,1891 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use crate::devices::firmware_test::{AssertionKind, TestStatus};
+use crate::devices::gpio::PinId;
+use crate::devices::touch::TouchPad;
+use crate::devices::wdt::WdtDevice;
+use crate::devices::{comparator, console, cs, files, firmware_test, mailbox, mpu, pmap, pmm, radio, spi, wdt, DeviceId};
+use crate::devices::radio::{Packet, Radio, RadioSpec};
+use crate::devices::sd_card::{SdCard, SdCardSpec};
+use crate::radio_link;
+use crate::midi_link::{self, MidiLink, MidiMessage, MidiParser, MidiSource, OscArg, OscMapping, Sent};
+use crate::onewire;
+use crate::sensors::{SensorSpec, Sensors};
+use crate::ws2812::{LedStrip, LedStripSpec, Rgb};
+use crate::framebuffer::{self, FrameBufferSpec, FrameExport};
+use crate::widgets::{EncoderSpec, MatrixSpec, Widgets};
+use crate::stimulus::Stimulus;
+use crate::pwm::PwmAnalyzer;
+use crate::profile::{DeviceProfile, Interrupt, Profile, RegisterReset};
+use crate::bus::Handler;
+use crate::builder::ComputerBuilder;
+use crate::worker::{Hosted, QuantumModel, Worker};
+
+const TEST_DEFINES: &str = r#"
+.define "&0x01f0" TEST_ID
+.define "&0x01f2" TEST_EXPECT
+.define "&0x01f4" TEST_ASSERT_EQ
+.define "&0x01f6" TEST_ASSERT
+.define "&0x01f8" TEST_RESULT
+"#;
+
+#[test]
+fn firmware_assert_pass() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble(&format!("{}
+mov #5 r5
+mov #5 [TEST_EXPECT]
+mov r5 [TEST_ASSERT_EQ]
+mov #1 [TEST_ASSERT]
+mov #1 [TEST_RESULT]
+", TEST_DEFINES));
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 5);
+
+    assert_eq!(TestStatus::Passed, c.devices.firmware_test.status());
+    assert!(c.devices.firmware_test.failures().is_empty());
+}
+
+#[test]
+fn firmware_assert_fail() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble(&format!("{}
+mov #4 r5
+mov #7 [TEST_ID]
+mov #5 [TEST_EXPECT]
+mov r5 [TEST_ASSERT_EQ]
+mov #1 [TEST_RESULT]
+", TEST_DEFINES));
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 5);
+
+    assert_eq!(TestStatus::Failed, c.devices.firmware_test.status(), "Pass after a failure is ignored");
+    let failures = c.devices.firmware_test.failures();
+    assert_eq!(1, failures.len());
+    assert_eq!(AssertionKind::Equal, failures[0].kind);
+    assert_eq!(7, failures[0].id, "Failure is tagged with TEST_ID");
+    assert_eq!(5, failures[0].expected);
+    assert_eq!(4, failures[0].actual);
+    assert_eq!(0x4400 + 2 + 6 + 6, failures[0].pc, "Failure records the asserting instruction");
+    assert_eq!(0, c.memory.get_word(0x01f8), "Device writes don't reach memory");
+}
+
+#[test]
+fn rng_is_replayable() {
+    let code = "
+mov &0x01e0 r5
+mov &0x01e0 r6
+mov &0x01e0 r7
+";
+    let assembled = assemble(code);
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+
+    let first: &mut Computer = &mut Computer::new();
+    first.devices.rng.set_seed(1234);
+    execute(first, &trimmed, 3);
+    let second: &mut Computer = &mut Computer::new();
+    second.devices.rng.set_seed(1234);
+    execute(second, &trimmed, 3);
+    let other: &mut Computer = &mut Computer::new();
+    other.devices.rng.set_seed(4321);
+    execute(other, &trimmed, 3);
+
+    for reg in 5..=7 {
+        assert_eq!(first.get_register(reg).get_word(), second.get_register(reg).get_word(), "Same seed, same stream");
+    }
+    assert_ne!(first.get_register(5).get_word(), first.get_register(6).get_word(), "Reads advance the stream");
+    assert_ne!(first.get_register(5).get_word(), other.get_register(5).get_word(), "Different seed, different stream");
+}
+
+#[test]
+fn rng_firmware_seed() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #42 &0x01e2
+mov &0x01e0 r5
+mov #42 &0x01e2
+mov &0x01e0 r6
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 4);
+
+    assert_eq!(c.get_register(5).get_word(), c.get_register(6).get_word(), "Reseeding restarts the stream");
+}
+
+#[test]
+fn rtc_emulated_time() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x5a80 &0x0120 ; WDTPW | WDTHOLD
+mov &0x01d2 r5
+mov #100 &0x01d2
+mov &0x01d2 r6
+mov &0x01d0 r7
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 2);
+    c.clock.advance(2 * clock::DEFAULT_MCLK_HZ);
+    for _ in 0..3 {
+        c.step();
+    }
+
+    assert_eq!(0, c.get_register(5).get_word(), "No time has passed");
+    assert_eq!(100, c.get_register(6).get_word(), "Seconds can be set");
+    assert_eq!(((2 * clock::ACLK_HZ) & 0xffff) as u16, c.get_register(7).get_word(), "ACLK follows cycles");
+}
+
+#[test]
+fn dco_drift() {
+    let drifted = |seed: u64| {
+        let mut clock: Clock = Clock::new(TimeSource::Emulated);
+        clock.set_dco_tolerance(30_000, seed);
+        let mut rates: Vec<u64> = vec![];
+        for _ in 0..20 {
+            rates.push(clock.mclk_hz());
+            clock.advance(clock::DRIFT_SEGMENT_CYCLES);
+        }
+        return (rates, clock.ticks(clock::ACLK_HZ));
+    };
+    let (rates, ticks) = drifted(7);
+    assert_eq!((rates.clone(), ticks), drifted(7), "The same seed drifts the same way");
+    assert_ne!(rates, drifted(8).0, "Another seed drifts another way");
+    assert!(rates.iter().all(|hz| (970_000..=1_030_000).contains(hz)), "MCLK stays within 3%: {:?}", rates);
+    assert!(rates.windows(2).any(|pair| pair[0] != pair[1]), "MCLK drifts");
+    let nominal: u64 = 20 * clock::DRIFT_SEGMENT_CYCLES * clock::ACLK_HZ / clock::DEFAULT_MCLK_HZ;
+    assert_ne!(nominal, ticks, "Real time no longer follows cycles exactly");
+    assert!(ticks.abs_diff(nominal) <= nominal * 3 / 100, "ACLK is off by at most 3%");
+
+    let mut clock: Clock = Clock::new(TimeSource::Emulated);
+    clock.set_dco_tolerance(30_000, 7);
+    clock.advance(5 * clock::DRIFT_SEGMENT_CYCLES);
+    clock.reset();
+    assert_eq!(rates[0], clock.mclk_hz(), "Reset starts the drift over");
+    clock.set_dco_tolerance(0, 7);
+    assert_eq!(clock::DEFAULT_MCLK_HZ, clock.mclk_hz(), "No tolerance keeps MCLK exact");
+}
+
+#[test]
+fn rtc_host_time() {
+    let c: &mut Computer = &mut Computer::new();
+    c.clock.set_source(TimeSource::Host);
+    let assembled = assemble("
+mov &0x01d2 r5
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 0);
+    c.clock.advance(100 * clock::DEFAULT_MCLK_HZ);
+    c.step();
+
+    assert_eq!(0, c.get_register(5).get_word(), "Host time ignores emulated cycles");
+
+    let mut clock: Clock = Clock::new(TimeSource::Host);
+    clock.freeze();
+    let frozen: u128 = clock.elapsed_nanos();
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(frozen, clock.elapsed_nanos(), "Time stands still while a command is handled");
+    clock.reset();
+    assert_eq!(0, clock.elapsed_nanos(), "Also after a reset during the command");
+    clock.thaw();
+    assert!(clock.elapsed_nanos() < 20_000_000, "And picks up where it stopped");
+}
+
+#[test]
+fn watchdog_timer() {
+    let assembled = assemble("
+start:
+mov #0x4400 sp
+loop:
+jmp loop
+
+.interrupt 0xfffe start
+");
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).build();
+    execute(c, assembled.trim(), 1);
+    assert_eq!(0x6900, c.read_word(wdt::WDTCTL), "Running from reset on the G2553");
+    while c.clock.cycles() <= 32768 {
+        c.step();
+    }
+    assert_eq!((1, Some(ResetCause::Puc)), (c.resets, c.last_reset), "Expired after 32768 SMCLK cycles");
+    assert_eq!(wdt::WDTIFG, c.devices.wdt.read_byte(wdt::IFG1), "WDTIFG tells firmware why");
+
+    let assembled = assemble("
+start:
+mov #0x4400 sp
+mov #0x5a1b &0x0120 ; WDTPW | WDTTMSEL | WDTCNTCL | 64 SMCLK cycles
+bis.b #0x01 &0x0000 ; WDTIE
+eint
+loop:
+jmp loop
+
+tick:
+add #1 r5
+reti
+
+.interrupt 0xfffe start
+.interrupt 0xfff4 tick
+");
+    execute(c, assembled.trim(), 3);
+    let started: u64 = c.clock.cycles();
+    let resets: u32 = c.resets;
+    while c.clock.cycles() < started + 64 * 10 {
+        c.step();
+    }
+    assert_eq!((10, resets), (c.get_register(5).get_word(), c.resets), "Interval mode interrupts instead of resetting");
+    assert_eq!(0, c.devices.wdt.read_byte(wdt::IFG1) & wdt::WDTIFG, "Taking the interrupt clears WDTIFG");
+
+    c.write_word(wdt::WDTCTL, wdt::WDTPW | wdt::WDTHOLD as u16);
+    let count: u16 = c.get_register(5).get_word();
+    for _ in 0..1000 {
+        c.step();
+    }
+    assert_eq!(count, c.get_register(5).get_word(), "WDTHOLD stops the counter");
+
+    // serviced in time, a watchdog never expires
+    let assembled = assemble("
+start:
+mov #0x4400 sp
+mov #0x5a0b &0x0120 ; WDTPW | WDTCNTCL | 64 SMCLK cycles
+loop:
+mov #0x5a0b &0x0120
+jmp loop
+
+.interrupt 0xfffe start
+");
+    execute(c, assembled.trim(), 500);
+    assert_eq!(resets, c.resets);
+
+    c.write_byte(wdt::WDTCTL + 1, wdt::WDTHOLD);
+    c.step();
+    assert_eq!((resets + 1, Some(ResetCause::Puc)), (c.resets, c.last_reset), "A byte write is a password violation");
+    assert_eq!(0x6900, c.read_word(wdt::WDTCTL), "The PUC set the watchdog running again");
+
+    let generic: &mut Computer = &mut Computer::new();
+    generic.reset();
+    assert_eq!(0x6900, generic.read_word(wdt::WDTCTL), "Running on every part");
+    let held: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device().with_watchdog_held()).build();
+    assert_eq!(0x6980, held.read_word(wdt::WDTCTL), "Unless held from the start (run --hold-watchdog)");
+    held.memory.set_word(0x4400, 0x3fff); // jmp $
+    held.pc.set_word(0x4400);
+    held.write_byte(wdt::WDTCTL + 1, wdt::WDTHOLD);
+    held.step();
+    assert_eq!((0x6980, Some(ResetCause::Puc)), (held.read_word(wdt::WDTCTL), held.last_reset), "Also after a PUC");
+}
+
+#[test]
+fn watchdog_counter() {
+    let mut clock: Clock = Clock::new(TimeSource::Emulated);
+    let mut wdt: WdtDevice = WdtDevice::new();
+    let interval = |is: u16| wdt::WDTPW | wdt::WDTTMSEL as u16 | is;
+    wdt.write_word(wdt::WDTCTL, interval(2) | wdt::WDTCNTCL as u16, &clock); // 512
+    clock.advance(300);
+    wdt.update(&clock);
+    wdt.write_word(wdt::WDTCTL, interval(3), &clock); // 64, the count goes on
+    assert_eq!(clock.cycles() + 20, wdt.deadline(), "Expires at 320, the next multiple of 64");
+    clock.advance(20);
+    wdt.update(&clock);
+    assert_eq!(wdt::WDTIFG, wdt.read_byte(wdt::IFG1));
+
+    wdt.accepted();
+    wdt.write_word(wdt::WDTCTL, interval(1), &clock); // 8192
+    clock.advance(8192 - 320 - 1);
+    wdt.update(&clock);
+    assert_eq!(0, wdt.read_byte(wdt::IFG1), "Not cleared by the write, a tick short of 8192");
+    wdt.write_word(wdt::WDTCTL, interval(1) | wdt::WDTCNTCL as u16, &clock);
+    assert_eq!(clock.cycles() + 8192, wdt.deadline(), "WDTCNTCL clears it");
+
+    wdt.write_word(wdt::WDTCTL, interval(0), &clock); // 32768, twice is the counter's range
+    clock.advance(65536);
+    wdt.update(&clock);
+    wdt.accepted();
+    assert_eq!(clock.cycles() + 32768, wdt.deadline(), "Wrapped around to 0");
+}
+
+#[test]
+fn watchdog_password() {
+    let run = |write: &str| {
+        let assembled = assemble(&format!("
+start:
+mov #0x4400 sp
+add #1 &0x0200
+{}
+loop:
+jmp loop
+
+.interrupt 0xfffe start
+", write));
+        let c: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).build();
+        execute(c, assembled.trim(), 3);
+        return (c.resets, c.last_reset, c.memory.get_word(0x0200), c.devices.wdt.read_byte(wdt::IFG1) & wdt::WDTIFG);
+    };
+    assert_eq!((0, None, 1, 0), run("mov #0x5a80 &0x0120"), "Held with the password");
+    assert_eq!((1, Some(ResetCause::Puc), 1, wdt::WDTIFG), run("mov #0x1280 &0x0120"), "Wrong password");
+    assert_eq!((1, Some(ResetCause::Puc), 1, wdt::WDTIFG), run("mov.b #0x80 &0x0121"), "Byte write to WDTCTL");
+    assert_eq!((1, Some(ResetCause::Puc), 1, wdt::WDTIFG), run("mov.b #0x5a &0x0120"), "Even the password byte");
+}
+
+#[test]
+fn low_power_modes() {
+    let assembled = assemble("
+mov #0x4400 sp
+bis #0xd8 sr ; LPM3 with GIE
+bis #0xf8 sr ; LPM4 with GIE
+mov #1 r5
+
+stay:
+mov #2 r6
+reti
+
+wake:
+mov #3 r6
+bic #0xf0 0(sp)
+reti
+
+.interrupt 0xffe0 stay
+.interrupt 0xffe2 wake
+");
+    let c: &mut Computer = &mut Computer::new();
+    execute(c, assembled.trim(), 2);
+    assert_eq!(Some(3), c.flags().low_power_mode());
+    let retired: u64 = c.retired;
+    let aclk = |c: &mut Computer| c.read_word(crate::devices::rtc::RTC_ACLK);
+    for _ in 0..1000 {
+        c.step();
+    }
+    assert_eq!(retired, c.retired, "Nothing executes with the CPU off");
+    assert_eq!(c.clock.ticks(clock::ACLK_HZ) as u16, aclk(c), "ACLK keeps running in LPM3");
+
+    c.interrupt(0xffe0);
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!((2, Some(3)), (c.get_register(6).get_word(), c.flags().low_power_mode()),
+               "RETI restores the low-power bits the interrupt cleared");
+
+    c.interrupt(0xffe2);
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!((3, Some(4)), (c.get_register(6).get_word(), c.flags().low_power_mode()),
+               "The handler cleared them, the next instruction entered LPM4");
+    c.step();
+    let stopped: u16 = aclk(c);
+    c.clock.advance(clock::DEFAULT_MCLK_HZ);
+    c.step();
+    assert_eq!(stopped, aclk(c), "OSCOFF stops ACLK");
+    let lag: u64 = c.clock.aclk_lag_nanos();
+    assert!(lag >= 1_000_000_000, "The time it was stopped is remembered: {}", lag);
+
+    let snapshot: Vec<u8> = c.save_snapshot();
+    let restored: &mut Computer = &mut Computer::new();
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert_eq!(stopped, aclk(restored), "A restored snapshot keeps the lag");
+
+    c.interrupt(0xffe2);
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!((None, 1), (c.flags().low_power_mode(), c.get_register(5).get_word()), "Woken for good");
+    assert!(aclk(c).wrapping_sub(stopped) <= 1, "ACLK picks up where it stopped");
+}
+
+#[test]
+fn uart_cross_connect() {
+    let sender: &mut Computer = &mut Computer::new();
+    let receiver: &mut Computer = &mut Computer::new();
+    let sender_code = assemble("
+mov #0x48 &0x01c0
+mov #0x69 &0x01c0
+");
+    let receiver_code = assemble("
+wait:
+bit #1 &0x01c4
+jz wait
+mov &0x01c2 r5
+mov &0x01c2 r6
+mov &0x01c4 r7
+");
+    execute(sender, sender_code.trim(), 0);
+    execute(receiver, receiver_code.trim(), 0);
+    for _ in 0..10 {
+        sender.step();
+        receiver.step();
+        uart_link::cross_connect(&mut sender.devices.uart, &mut receiver.devices.uart);
+    }
+
+    assert_eq!(0x48, receiver.get_register(5).get_word());
+    assert_eq!(0x69, receiver.get_register(6).get_word());
+    assert_eq!(crate::devices::uart::STATUS_TX_READY, receiver.get_register(7).get_word(), "RX drained");
+}
+
+#[test]
+fn uart_rx_interrupt() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #1 &0x01c6 ; enable RX interrupt
+eint
+loop:
+jmp loop
+
+handler:
+mov &0x01c2 r5
+reti
+
+.interrupt 0xffee handler
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 5);
+    assert_eq!(0, c.get_register(5).get_word(), "No interrupt without data");
+
+    c.devices.uart.receive(b"A");
+    for _ in 0..3 {
+        c.step();
+    }
+    assert_eq!(0x41, c.get_register(5).get_word(), "Handler ran");
+    assert_eq!(None, c.devices.uart.pending_interrupt(), "Reading RX clears the request");
+}
+
+#[test]
+fn gpio_edge_interrupt() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov.b #0x08 &0x0027 ; P1REN pull on P1.3
+mov.b #0x08 &0x0021 ; P1OUT selects pull-up
+mov.b #0x08 &0x0024 ; P1IES falling edge
+clr.b &0x0023 ; enabling the pull-up was a rising edge
+mov.b #0x08 &0x0025 ; P1IE
+eint
+loop:
+jmp loop
+
+handler:
+mov.b &0x0020 r5
+mov.b &0x0023 r6
+bic.b #0x08 &0x0023
+reti
+
+.interrupt 0xffe4 handler
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 9);
+    let pin: PinId = "P1.3".parse().unwrap();
+    assert!(c.devices.gpio.level(pin), "Pull-up holds an undriven input high");
+    assert_eq!(None, c.devices.gpio.pending_interrupt());
+
+    c.devices.gpio.set_input(pin, Some(true));
+    assert_eq!(None, c.devices.gpio.pending_interrupt(), "No edge while the level stays high");
+
+    c.devices.gpio.set_input(pin, Some(false));
+    for _ in 0..5 {
+        c.step();
+    }
+    assert_eq!(0x00, c.get_register(5).get_word(), "Handler saw the pin low");
+    assert_eq!(0x08, c.get_register(6).get_word(), "Handler saw P1IFG.3");
+    assert_eq!(None, c.devices.gpio.pending_interrupt(), "Clearing P1IFG clears the request");
+}
+
+#[test]
+fn gpio_wire_between_instances() {
+    let driver: &mut Computer = &mut Computer::new();
+    let follower: &mut Computer = &mut Computer::new();
+    let driver_code = assemble("
+mov.b #0x01 &0x0022 ; P1.0 output
+mov.b #0x01 &0x0021
+mov.b #0x00 &0x0021
+");
+    let follower_code = assemble("
+nop
+nop
+nop
+");
+    execute(driver, driver_code.trim(), 0);
+    execute(follower, follower_code.trim(), 0);
+    let wires: Vec<gpio_link::Wire> = vec!["P1.0>P2.3:invert".parse().unwrap(), "P1.1>P2.4:pulldown".parse().unwrap()];
+    let inverted: PinId = "P2.3".parse().unwrap();
+    let pulled: PinId = "P2.4".parse().unwrap();
+
+    gpio_link::propagate(&wires, &driver.devices.gpio, &mut follower.devices.gpio);
+    assert!(!follower.devices.gpio.level(inverted), "Undriven wire without pull floats low");
+    assert!(!follower.devices.gpio.level(pulled));
+
+    let mut levels: Vec<bool> = Vec::new();
+    for _ in 0..3 {
+        driver.step();
+        follower.step();
+        gpio_link::propagate(&wires, &driver.devices.gpio, &mut follower.devices.gpio);
+        levels.push(follower.devices.gpio.level(inverted));
+    }
+    assert_eq!(vec![true, false, true], levels, "Follows P1.0 inverted");
+    assert_eq!("P1.0>P2.3:invert", wires[0].to_string());
+}
+
+#[test]
+fn mpu_violation_nmi() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0xa511 &0x05a0 ; password, MPUENA | MPUSEGIE
+mov #0x0500 &0x05a6 ; MPUSEGB1, segment 2 starts at 0x5000
+mov #0x0600 &0x05a4 ; MPUSEGB2, segment 3 starts at 0x6000
+mov #0x7715 &0x05a8 ; MPUSAM: segment 1 rx, segment 2 read only
+mov.b #0 &0x05a0 ; close register access
+mov #0x1234 &0x5000
+mov #1 r7
+loop:
+jmp loop
+
+nmi:
+mov &0x05a2 r6
+mov.b #0xa5 &0x05a0
+clr &0x05a2
+mov.b #0 &0x05a0
+reti
+
+.interrupt 0xfffc nmi
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 13);
+    assert_eq!(0x0000, c.memory.get_word(0x5000), "Write to a read only segment is blocked");
+    assert_eq!(mpu::MPUSEG2IFG, c.get_register(6).get_word(), "Handler saw the segment 2 flag");
+    assert_eq!(0x0001, c.get_register(7).get_word(), "Execution continues after the NMI");
+    assert_eq!(None, c.devices.pending_nmi(), "Clearing MPUCTL1 acknowledges the NMI");
+
+    c.devices.mpu.write_word(mpu::MPUSAM, 0x7777);
+    assert_eq!(0x7715, c.devices.mpu.read_word(mpu::MPUSAM), "Closed registers ignore writes");
+
+    // a faulted CPU doesn't take the NMI, the stack stays as the fault left it
+    c.fault = Some(Fault::Runaway { first: 0x4418, last: 0x4418, cycles: 0 });
+    c.write_word(0x5000, 0x1234);
+    assert!(c.devices.pending_nmi().is_some());
+    let sp: u16 = c.sp.get_word();
+    let stack: [u16; 2] = [c.memory.get_word(sp - 4), c.memory.get_word(sp - 2)];
+    c.step();
+    assert_eq!((sp, stack), (c.sp.get_word(), [c.memory.get_word(sp - 4), c.memory.get_word(sp - 2)]));
+    assert!(!c.servicing_nmi);
+}
+
+#[test]
+fn mpu_violation_puc() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+start:
+mov #0xa500 &0x05a0 ; password only, opens the registers
+mov #0x1400 &0x05a6 ; all of main memory is segment 1
+mov #0x1400 &0x05a4
+mov #0x777d &0x05a8 ; segment 1 rx, violations cause a PUC
+mov #0xa503 &0x05a0 ; MPUENA | MPULOCK
+mov #0x7777 &0x05a8 ; ignored while locked
+mov #0x5555 &0x4500
+mov #0x0001 &0x05a0 ; wrong password
+
+.interrupt 0xfffe start
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 7);
+    assert_eq!(0x4400, c.pc.get_word(), "Segment violation restarts from the reset vector");
+    assert_eq!(0x0000, c.memory.get_word(0x4500));
+    assert_eq!(0x777d, c.devices.mpu.read_word(mpu::MPUSAM), "Locked configuration survives the PUC");
+    assert_eq!(mpu::MPUSEG1IFG, c.devices.mpu.read_word(mpu::MPUCTL1));
+
+    c.pc.set_word(0x442a);
+    c.get_register(5).set_word(0x1234);
+    c.step();
+    assert_eq!((0x4400, 0), (c.pc.get_word(), c.get_register(5).get_word()), "Wrong password is a PUC too");
+}
+
+#[test]
+fn ip_encapsulation() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+jmp main
+secret: ; 0x4402, the IP region is 0x4400 - 0x440f
+mov &0x4408 r6
+ret
+nop ; 0x4408, secret data
+nop
+nop
+nop
+
+main:
+mov #0x4400 sp
+mov #0xa500 &0x05a0
+mov #0x0440 &0x05ae ; MPUIPSEGB1
+mov #0x0441 &0x05ac ; MPUIPSEGB2
+mov #0x0040 &0x05aa ; MPUIPENA
+mov &0x4408 r5
+mov #0 &0x4408
+call #secret
+mov #1 r7
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 0);
+    c.memory.set_word(0x4408, 0xbeef);
+    for _ in 0..8 {
+        c.step();
+    }
+    assert_eq!(mpu::VIOLATION_READ, c.get_register(5).get_word(), "Reads from outside the region are blocked");
+    assert_eq!(0xbeef, c.memory.get_word(0x4408), "So are writes");
+    assert_eq!(mpu::MPUSEGIPIFG, c.devices.mpu.read_word(mpu::MPUCTL1) & mpu::MPUSEGIPIFG);
+
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!(0xbeef, c.get_register(6).get_word(), "Code inside the region can read it");
+    assert_eq!(0x0001, c.get_register(7).get_word(), "Calling into the region is allowed");
+}
+
+#[test]
+fn jtag_mailbox() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+wait:
+bit #0x0001 &0x0186 ; JMBIN0FG
+jz wait
+mov &0x0188 r5
+add #1 r5
+mov r5 &0x018c
+loop:
+jmp loop
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 6);
+    assert_eq!(None, c.devices.mailbox.host_read(0), "Nothing written yet");
+    assert_eq!(mailbox::JMBOUT0FG | mailbox::JMBOUT1FG, c.devices.mailbox.read_word(mailbox::SYSJMBC));
+
+    c.devices.mailbox.host_write(0, 0x1234);
+    for _ in 0..6 {
+        c.step();
+    }
+    assert_eq!(mailbox::JMBOUT1FG, c.devices.mailbox.read_word(mailbox::SYSJMBC), "Input read, output 0 full");
+    assert_eq!(Some(0x1235), c.devices.mailbox.host_read(0));
+    assert_eq!(None, c.devices.mailbox.host_read(0), "Each word is read once");
+}
+
+#[test]
+fn supply_supervisor() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+start:
+mov #0x4400 sp
+mov #0x4405 &0x0124 ; SVMHE | SVSHE, SVM level 5 (2.7 V), SVS level 0 (1.74 V)
+mov #0x1020 &0x012e ; SVMHIE | SVSHPE
+loop:
+jmp loop
+
+nmi:
+mov &0x012c r6
+bic #0x0020 &0x012c
+reti
+
+.interrupt 0xfffc nmi
+.interrupt 0xfffe start
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 4);
+    assert_eq!(None, c.devices.pending_nmi());
+
+    c.devices.pmm.set_supply(2500, 100, c.clock.cycles());
+    for _ in 0..120 {
+        c.step();
+    }
+    assert_eq!(2500, c.devices.pmm.supply_mv());
+    assert_eq!(pmm::SVMHIFG, c.get_register(6).get_word(), "Handler ran when the supply fell below 2.7 V");
+    assert_eq!(None, c.devices.pending_nmi());
+
+    c.devices.pmm.set_supply(1500, 0, c.clock.cycles());
+    let pc: u16 = c.pc.get_word();
+    for _ in 0..10 {
+        c.step();
+    }
+    assert_eq!(pc, c.pc.get_word(), "Held in reset below the SVS level");
+
+    c.devices.pmm.set_supply(3300, 0, c.clock.cycles());
+    c.step();
+    assert_eq!((0x4404, 0x4400, 0), (c.pc.get_word(), c.sp.get_word(), c.get_register(6).get_word()), "Restarted from the reset vector");
+    let flags: u16 = c.devices.pmm.read_word(pmm::PMMIFG);
+    assert_eq!(pmm::PMMBORIFG | pmm::SVSHIFG, flags & (pmm::PMMBORIFG | pmm::SVSHIFG), "Reset cause is recorded");
+}
+
+#[test]
+fn gpio_stimulus() {
+    let csv = Stimulus::from_csv("cycle,pin,level\n# button press\n30,P1.3,0\n0,P1.3,1\n60,P1.3,z\n").unwrap();
+    let json = Stimulus::from_json(r#"[{"cycle": 0, "pin": "P1.3", "level": 1},
+        {"cycle": 30, "pin": "P1.3", "level": "0"}, {"cycle": 60, "pin": "P1.3", "level": null}]"#).unwrap();
+    assert!(Stimulus::from_csv("0,A0,1").is_err(), "No ADC to drive");
+
+    for stimulus in [csv, json] {
+        let c: &mut Computer = &mut Computer::new();
+        c.stimulus = Some(stimulus);
+        let assembled = assemble("
+mov.b #0x08 &0x0027 ; P1REN, pull-up on P1.3 once released
+mov.b #0x08 &0x0021
+loop:
+mov.b &0x0020 r5
+jmp loop
+");
+        let trimmed = assembled.trim();
+        println!("'{}'", trimmed);
+        execute(c, &trimmed, 3);
+        assert_eq!(0x08, c.get_register(5).get_word());
+        while c.clock.cycles() < 40 {
+            c.step();
+        }
+        assert_eq!(0x00, c.get_register(5).get_word(), "Pressed at cycle 30");
+        while c.clock.cycles() < 70 {
+            c.step();
+        }
+        assert_eq!(0x08, c.get_register(5).get_word(), "Released, the pull-up takes over");
+
+        c.reset();
+        c.step();
+        assert!(c.devices.gpio.level("P1.3".parse().unwrap()), "Replays from the start after a reset");
+    }
+}
+
+#[test]
+fn pwm_measurement() {
+    let c: &mut Computer = &mut Computer::new();
+    let pin: PinId = "P1.2".parse().unwrap();
+    c.pwm = Some(PwmAnalyzer::new(&[pin]));
+    c.attach_hooks();
+    let assembled = assemble("
+mov.b #0x04 &0x0022 ; P1DIR
+loop:
+bis.b #0x04 &0x0021 ; 4 cycles
+nop
+nop
+nop
+nop
+nop
+nop
+bic.b #0x04 &0x0021 ; high for 10 cycles
+nop
+nop
+nop
+nop
+jmp loop ; 2 cycles, a period of 20
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 4);
+    assert_eq!(None, c.pwm.as_ref().unwrap().measurement(pin), "No complete period yet");
+    for _ in 0..40 {
+        c.step();
+    }
+    let measurement = c.pwm.as_ref().unwrap().measurement(pin).unwrap();
+    assert_eq!(20, measurement.period);
+    assert_eq!(10, measurement.high);
+    assert_eq!((20, 20), (measurement.min_period, measurement.max_period));
+    assert_eq!(50_000.0, measurement.frequency_hz(1_000_000));
+    assert_eq!(0.5, measurement.duty());
+    assert!(c.pwm.as_ref().unwrap().report(1_000_000).starts_with("P1.2: 50000.00 Hz, duty 50.0%"));
+
+    c.reset();
+    assert_eq!(None, c.pwm.as_ref().unwrap().measurement(pin), "Forgotten on reset");
+}
+
+#[test]
+fn console_input() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x0001 &0x01cc ; CONSOLE_CTL, interrupt enable
+eint
+loop:
+jmp loop
+isr:
+mov &0x01c8 r5
+add r5 r6
+bit #0x0001 &0x01ca
+jnz isr
+reti
+.interrupt 0xffec isr
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 5);
+    assert_eq!(0, c.get_register(6).get_word(), "No interrupt without input");
+
+    c.devices.console.receive(b"hi");
+    for _ in 0..12 {
+        c.step();
+    }
+    assert_eq!(('h' as u16) + ('i' as u16), c.get_register(6).get_word(), "Both bytes read from the interrupt");
+    assert_eq!(None, c.devices.pending_interrupt(), "Nothing left to read");
+    assert_eq!(0xffff, c.devices.console.read_word(console::CONSOLE_IN), "Empty FIFO");
+    assert_eq!(0, c.devices.console.read_word(console::CONSOLE_STATUS));
+    c.devices.console.close();
+    assert_eq!(console::STATUS_CLOSED, c.devices.console.read_word(console::CONSOLE_STATUS));
+}
+
+#[test]
+fn port_mapping() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov.b #12 &0x01a8 ; dropped, the registers start closed
+mov #0x2d52 &0x01a0
+mov.b #12 &0x01a8 ; P1MAP0
+mov #0x1f05 &0x01b0 ; P2MAP0 = PM_ANALOG, P2MAP1 = 5
+mov #0 &0x01a0 ; closed without PMAPRECFG
+mov #0x2d52 &0x01a0
+mov.b #3 &0x01a9 ; frozen until reset
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 1);
+    assert_eq!(0, c.devices.pmap.read_byte(pmap::P1MAP));
+    assert_eq!(pmap::PMAPLOCKED, c.devices.pmap.read_word(pmap::PMAPCTL));
+    for _ in 0..3 {
+        c.step();
+    }
+    assert_eq!(0x96a5, c.devices.pmap.read_word(pmap::PMAPKEYID));
+    assert_eq!(0, c.devices.pmap.read_word(pmap::PMAPCTL), "Open");
+    assert_eq!(12, c.devices.pmap.read_byte(pmap::P1MAP));
+    assert_eq!(pmap::PM_ANALOG, c.devices.pmap.read_byte(pmap::P2MAP));
+    assert_eq!(5, c.devices.pmap.read_byte(pmap::P2MAP + 1));
+    for _ in 0..3 {
+        c.step();
+    }
+    assert_eq!(pmap::PMAPLOCKED, c.devices.pmap.read_word(pmap::PMAPCTL), "The password doesn't open it again");
+    assert_eq!(0, c.devices.pmap.read_byte(pmap::P1MAP + 1));
+
+    c.devices.reset();
+    c.devices.pmap.write_word(pmap::PMAPKEYID, pmap::PMAPPW);
+    c.devices.pmap.write_word(pmap::PMAPCTL, pmap::PMAPRECFG);
+    c.devices.pmap.write_word(pmap::PMAPKEYID, 0);
+    c.devices.pmap.write_word(pmap::PMAPKEYID, pmap::PMAPPW);
+    c.devices.pmap.write_byte(pmap::P1MAP + 7, 4);
+    assert_eq!(4, c.devices.pmap.read_byte(pmap::P1MAP + 7), "PMAPRECFG allows opening it again");
+    assert_eq!(0, c.devices.pmap.read_byte(pmap::P1MAP), "Reset clears the mapping");
+}
+
+#[test]
+fn oscillator_fault() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+start:
+mov #0x4400 sp
+mov #0xa500 &0x0160 ; CSKEY
+bic #0x0001 &0x0168 ; LFXT on
+mov.b #0 &0x0160 ; closed again
+mov #0x0002 &0x0100 ; OFIE
+loop:
+jmp loop
+
+nmi:
+mov.b #0xa5 &0x0160
+bic #0x0001 &0x016a ; LFXTOFFG
+mov.b #0 &0x0160
+bic #0x0002 &0x0102 ; OFIFG
+add #1 r5
+reti
+
+.interrupt 0xfffe start
+.interrupt 0xfffa nmi
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 10);
+    assert_eq!(0, c.get_register(5).get_word(), "The crystals work");
+    assert_eq!(0, c.devices.cs.read_word(cs::CSCTL4) & cs::LFXTOFF);
+
+    c.devices.cs.set_failed(Crystal::Lfxt, true);
+    assert_eq!(cs::OFIFG, c.devices.cs.read_word(cs::SFRIFG1));
+    for _ in 0..7 {
+        c.step();
+    }
+    assert_eq!(1, c.get_register(5).get_word());
+    assert_eq!(cs::LFXTOFFG, c.devices.cs.read_word(cs::CSCTL5), "Clearing the flag doesn't fix the crystal");
+    assert_eq!(cs::OFIFG, c.devices.cs.read_word(cs::SFRIFG1));
+    for _ in 0..7 {
+        c.step();
+    }
+    assert_eq!(2, c.get_register(5).get_word(), "The NMI is taken again after RETI");
+
+    c.devices.cs.set_failed(Crystal::Lfxt, false);
+    for _ in 0..20 {
+        c.step();
+    }
+    assert_eq!((0, 0), (c.devices.cs.read_word(cs::CSCTL5), c.devices.cs.read_word(cs::SFRIFG1)), "The handler clears the flags for good");
+    let handled: u16 = c.get_register(5).get_word();
+    for _ in 0..20 {
+        c.step();
+    }
+    assert_eq!(handled, c.get_register(5).get_word());
+
+    c.devices.cs.set_failed(Crystal::Hfxt, true);
+    assert_eq!(0, c.devices.cs.read_word(cs::SFRIFG1), "HFXT is turned off");
+
+    c.get_register(5).set_word(0);
+    c.memory.set_word(0x4500, 0x40b2); // mov #0x1234 &0x0160
+    c.memory.set_word(0x4502, 0x1234);
+    c.memory.set_word(0x4504, 0x0160);
+    c.pc.set_word(0x4500);
+    c.step();
+    assert_eq!(0x4400, c.pc.get_word(), "Wrong password is a PUC");
+}
+
+#[test]
+fn touch_pad_pin_oscillator() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov.b #0x01 &0x0042 ; P2SEL2, P2.0 oscillates
+measure:
+mov #0 &0x01d4 ; TOUCH_COUNT
+mov #100 r4
+gate:
+dec r4
+jnz gate
+mov &0x01d4 r5
+jmp measure
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 1);
+    let pad: TouchPad = "P2.0:10".parse().unwrap();
+    assert_eq!(TouchPad { pin: "P2.0".parse().unwrap(), femtofarads: 10_000 }, pad);
+    assert_eq!("P2.0:12.5", "P2.0:12.5".parse::<TouchPad>().unwrap().to_string());
+    assert!("P2.0:0".parse::<TouchPad>().is_err());
+    assert_eq!(1_500_000, c.devices.touch.frequency_hz(pad.pin));
+
+    let measure = |c: &mut Computer| {
+        for _ in 0..204 {
+            c.step();
+        }
+        return c.get_register(5).get_word();
+    };
+    let untouched: u16 = measure(c);
+    assert!((450..=465).contains(&untouched), "{} oscillations over the ~300 cycle gate", untouched);
+    c.devices.touch.set_pad("P2.0:20".parse().unwrap(), &c.devices.gpio, &c.clock);
+    let touched: u16 = measure(c);
+    assert!((225..=233).contains(&touched), "Twice the capacitance, half the frequency: {}", touched);
+
+    c.devices.gpio.write_byte(0x002e, 0x01); // P2SEL, the pin goes to its peripheral function
+    c.devices.touch.update(&c.devices.gpio, &c.clock);
+    assert_eq!(0, measure(c), "No oscillator, no count");
+
+    c.devices.reset();
+    assert_eq!(20_000, c.devices.touch.pad(pad.pin).femtofarads, "Pads keep their capacitance");
+}
+
+#[test]
+fn power_cycle_retention() {
+    let c: &mut Computer = &mut Computer::new();
+    c.ram = Profile::Fr4133.ram();
+    c.retained = Profile::Fr4133.backup_memory();
+    c.retained.push("0x2000-0x200f".parse().unwrap());
+    c.memory.set_word(0xfffe, 0x4400);
+    c.memory.set_word(0x4400, 0x4303); // FRAM
+    c.memory.set_word(0x2000, 0x1111); // retained with --retain
+    c.memory.set_word(0x2010, 0x2222); // RAM
+    c.memory.set_word(0x0660, 0x3333); // backup memory
+    c.pc.set_word(0x4400);
+    c.get_register(5).set_word(0x5555);
+    c.write_word(0x01d2, 1000); // RTC_SECONDS
+    c.devices.uart.write_word(0x01c6, 1);
+
+    c.power_cycle(5 * c.clock.mclk_hz());
+    assert_eq!((0x4400, 0), (c.pc.get_word(), c.get_register(5).get_word()), "Starts from the reset vector");
+    assert_eq!([0x4303, 0x1111, 0x0000, 0x3333],
+               [c.memory.get_word(0x4400), c.memory.get_word(0x2000), c.memory.get_word(0x2010), c.memory.get_word(0x0660)]);
+    assert_eq!(1005, c.read_word(0x01d2), "The RTC kept counting while off");
+    assert_eq!(0, c.devices.uart.read_word(0x01c6), "Other devices start over");
+}
+
+#[test]
+fn startup_state_and_resets() {
+    let c: &mut Computer = &mut Computer::new();
+    c.ram = Profile::G2553.ram();
+    c.startup = Profile::G2553.device().startup();
+    assert_eq!(0x0400, c.startup.sp, "Stack starts at the top of RAM");
+    c.startup.registers[4 - 4] = 0xffff;
+    c.startup.ram_fill = 0xcd;
+    c.reset();
+    assert_eq!([0x0400, 0xffff, 0], [c.sp.get_word(), c.get_register(4).get_word(), c.get_register(5).get_word()]);
+    assert_eq!([0xcdcd, 0xcdcd, 0x0000], [c.memory.get_word(0x0200), c.memory.get_word(0x03fe), c.memory.get_word(0x0400)]);
+    assert_eq!(Err("'r2' is not one of r4-r15 (use --reset-sp for the stack pointer)".to_string()), "r2=1".parse::<RegisterReset>());
+    assert_eq!(Ok(RegisterReset { register: 15, value: 0x1234 }), "R15=0x1234".parse());
+
+    c.memory.set_word(0xfffe, 0xc000);
+    c.memory.set_word(0x0200, 0x1111);
+    c.memory.set_word(0xc000, 0x4303);
+    c.devices.uart.write_word(0x01c6, 1);
+    c.get_register(4).set_word(7);
+    c.pc.set_word(0xc002);
+    c.reset_as(ResetKind::Puc);
+    assert_eq!([0xc000, 0x0400, 0xffff], [c.pc.get_word(), c.sp.get_word(), c.get_register(4).get_word()]);
+    assert_eq!(0x1111, c.memory.get_word(0x0200), "A PUC keeps RAM");
+    assert_eq!(1, c.devices.uart.read_word(0x01c6), "and devices");
+
+    c.reset_as(ResetKind::Bor);
+    assert_eq!(0xc000, c.pc.get_word());
+    assert_eq!([0xcdcd, 0x4303], [c.memory.get_word(0x0200), c.memory.get_word(0xc000)], "A BOR refills RAM, flash stays");
+    assert_eq!(0, c.devices.uart.read_word(0x01c6));
+    assert_eq!((2, Some(ResetCause::Command(ResetKind::Bor))), (c.resets, c.last_reset));
+}
+
+#[test]
+fn watchdog_reset_keeps_program() {
+    let assembled = assemble("
+start:
+mov #0x0400 sp
+add #1 &0x0200 ; boots
+mov #0x1234 r5
+loop:
+jmp loop
+
+.interrupt 0xfffe start
+");
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(Profile::G2553.device()).build();
+    execute(c, assembled.trim(), 3);
+    while c.clock.cycles() < 3 * 32768 + 100 {
+        c.step();
+    }
+    assert_eq!((3, 4), (c.resets, c.memory.get_word(0x0200)), "Every watchdog PUC boots the kept program again");
+
+    c.get_register(5).set_word(0);
+    c.sr.set_word(0x0008);
+    c.puc();
+    assert_eq!((0x4400, 0x0400, 0), (c.pc.get_word(), c.sp.get_word(), c.sr.get_word()), "From the reset vector, SP and SR defined");
+    c.step();
+    c.step();
+    assert_eq!(5, c.memory.get_word(0x0200), "A PUC keeps RAM");
+
+    c.power_on_reset();
+    for _ in 0..3 {
+        c.step();
+    }
+    assert_eq!((1, 0x1234), (c.memory.get_word(0x0200), c.get_register(5).get_word()), "A POR refills RAM, the program stays");
+    assert_eq!(Some(ResetCause::Command(ResetKind::Bor)), c.last_reset);
+}
+
+#[test]
+fn custom_device_profile() {
+    let profile = DeviceProfile::new("custom")
+        .with_no_execute(Region::new(0x0000, 0x0fff))
+        .with_ram(Region::new(0x1000, 0x10ff))
+        .with_backup_memory(Region::new(0x1000, 0x100f));
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(profile).ram_fill(0xaa)
+        .reset_register(RegisterReset::new(7, 0x0707).unwrap()).build();
+    assert_eq!([0x1100, 0x0707], [c.sp.get_word(), c.get_register(7).get_word()], "Stack at the top of the profile's RAM");
+    assert_eq!([0x0000, 0xaaaa], [c.memory.get_word(0x1000), c.memory.get_word(0x10fe)], "Backup memory isn't filled");
+
+    c.memory.set_word(0x1000, 0x1234);
+    c.memory.set_word(0x1010, 0x1234);
+    c.power_cycle(0);
+    assert_eq!([0x1234, 0xaaaa], [c.memory.get_word(0x1000), c.memory.get_word(0x1010)], "Backup memory is kept");
+
+    c.pc.set_word(0x0200);
+    c.step();
+    assert_eq!(Some(Fault::NoExecute { pc: 0x0200, region: Region::new(0x0000, 0x0fff) }), c.fault);
+
+    let built_in = ComputerBuilder::new().profile(Profile::G2553.device()).build();
+    assert_eq!((0x0400, Profile::G2553.no_execute()), (built_in.sp.get_word(), built_in.no_execute));
+    assert!(RegisterReset::new(3, 0).is_err(), "r3 is the constant generator");
+}
+
+#[test]
+fn custom_vectors() {
+    let profile: DeviceProfile = DeviceProfile::new("custom").with_vector(Interrupt::Port1, 0xffe8).unwrap();
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(profile).build();
+    let assembled = assemble("
+mov #0x4400 sp
+eint
+bis.b #0x01 &0x0025
+bis.b #0x01 &0x0023
+loop:
+jmp loop
+handler:
+mov #1 r5
+bic.b #0x01 &0x0023
+reti
+
+.interrupt 0xffe8 handler
+");
+    execute(c, assembled.trim(), 6);
+    assert_eq!(1, c.get_register(5).get_word(), "Port 1 taken through the profile's vector");
+    let entries: Vec<crate::interrupts::VectorEntry> = crate::interrupts::vector_map(c, &[]);
+    let port1 = |vector: u16| entries.iter().any(|entry| entry.vector == vector && entry.sources.iter().any(|s| s.name == "port 1"));
+    assert!(port1(0xffe8) && !port1(0xffe4), "Listed at the profile's vector");
+
+    assert!(DeviceProfile::new("custom").with_vector(Interrupt::Watchdog, 0xfffe).is_err(), "The reset vector");
+    assert!(DeviceProfile::new("custom").with_vector(Interrupt::Watchdog, 0xffe3).is_err());
+}
+
+#[test]
+fn comparator_waveform() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov.b #0x04 &0x005a ; CA0 on the + terminal
+mov.b #0x6a &0x0059 ; CAON, CAIE, 0.5 Vcc on the - terminal
+mov #0 r5
+eint
+loop:
+jmp loop
+
+crossing:
+add #1 r5
+bic.b #0x01 &0x0059 ; CAIFG
+reti
+
+.interrupt 0xfff6 crossing
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 5);
+    assert_eq!(0, c.devices.comparator.read_byte(comparator::CACTL2) & comparator::CAOUT, "CA0 is at 0 V");
+
+    // two periods of a sine around the 1.65 V reference, 20 samples each
+    let start: u64 = c.clock.cycles();
+    let csv: String = (0..40).map(|i| {
+        let mv: f64 = 1650.0 + 1000.0 * (i as f64 * std::f64::consts::PI / 10.0 + 0.1).sin();
+        return format!("{},{}\n", start + 10 * i as u64, mv.round() as u16);
+    }).collect();
+    c.analog.push(analog::Waveform::from_csv(0, &format!("cycle,millivolts\n{}", csv)).unwrap());
+    for _ in 0..420 {
+        c.step();
+    }
+    assert_eq!(2, c.get_register(5).get_word(), "One interrupt per rising crossing");
+
+    // 0 V, 1.65 V and 3.3 V at 1 kHz
+    let data: Vec<u8> = [i16::MIN, 0, i16::MAX].iter().flat_map(|s| s.to_le_bytes()).collect();
+    let mut wav: Vec<u8> = [b"RIFF".to_vec(), (36 + data.len() as u32).to_le_bytes().to_vec(), b"WAVEfmt ".to_vec(),
+                            16u32.to_le_bytes().to_vec(), 1u16.to_le_bytes().to_vec(), 1u16.to_le_bytes().to_vec(),
+                            1000u32.to_le_bytes().to_vec(), 2000u32.to_le_bytes().to_vec(), 2u16.to_le_bytes().to_vec(),
+                            16u16.to_le_bytes().to_vec(), b"data".to_vec(), (data.len() as u32).to_le_bytes().to_vec()].concat();
+    wav.extend_from_slice(&data);
+    let comparator: &mut comparator::ComparatorDevice = &mut comparator::ComparatorDevice::new(3300);
+    comparator.write_byte(comparator::CACTL2, 0x04);
+    comparator.write_byte(comparator::CACTL1, 0x68); // CAON, 0.5 Vcc on the - terminal
+    let mut waveform = analog::Waveform::from_wav(0, &wav, 1_000_000).unwrap();
+    waveform.apply(1999, comparator);
+    assert_eq!(0, comparator.read_byte(comparator::CACTL2) & comparator::CAOUT, "1.65 V is not above the reference");
+    waveform.apply(2000, comparator);
+    assert_eq!(comparator::CAOUT, comparator.read_byte(comparator::CACTL2) & comparator::CAOUT);
+    assert_eq!(comparator::CAIFG, comparator.read_byte(comparator::CACTL1) & comparator::CAIFG);
+
+    assert_eq!("CA3:wave.csv", "ca3:wave.csv".parse::<analog::AnalogSpec>().unwrap().to_string());
+    assert!("CA8:wave.csv".parse::<analog::AnalogSpec>().is_err());
+}
+
+#[test]
+fn host_files() {
+    let dir = std::env::temp_dir().join(format!("msp430_files_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("log"), b"x").unwrap();
+    let c: &mut Computer = &mut Computer::new();
+    c.devices.files.set_root(dir.to_str().unwrap());
+    let assembled = assemble("
+mov #0x6c &0x01e4 ; FILE_NAME 'log'
+mov #0x6f &0x01e4
+mov #0x67 &0x01e4
+mov #3 &0x01e6 ; append
+mov #0x41 &0x01e8
+mov #0x42 &0x01e8
+mov &0x01e6 r4
+mov #0x6c &0x01e4
+mov #0x6f &0x01e4
+mov #0x67 &0x01e4
+mov #1 &0x01e6 ; read
+mov &0x01e8 r5
+mov &0x01e8 r6
+mov &0x01e8 r7
+mov &0x01e8 r8
+mov &0x01e6 r9
+mov #0 &0x01e6 ; close
+");
+    execute(c, assembled.trim(), 17);
+    assert_eq!(b"xAB".to_vec(), std::fs::read(dir.join("log")).unwrap(), "Appended right away");
+    assert_eq!(files::STATUS_OPEN, c.get_register(4).get_word());
+    assert_eq!([0x78, 0x41, 0x42, 0xffff], [5, 6, 7, 8].map(|r| c.get_register(r).get_word()));
+    assert_eq!(files::STATUS_OPEN | files::STATUS_END, c.get_register(9).get_word());
+    assert_eq!(0, c.devices.files.read_word(files::FILE_CTL), "Closed");
+
+    let open = |c: &mut Computer, name: &str, command: u16| {
+        for byte in name.bytes() {
+            c.devices.files.write_word(files::FILE_NAME, byte as u16);
+        }
+        c.devices.files.write_word(files::FILE_CTL, command);
+        return c.devices.files.read_word(files::FILE_CTL);
+    };
+    assert_eq!(files::STATUS_OPEN, open(c, "new", files::CMD_WRITE));
+    c.devices.files.write_word(files::FILE_DATA, 0x0a);
+    assert_eq!(b"\n".to_vec(), std::fs::read(dir.join("new")).unwrap());
+    assert_eq!(files::STATUS_ERROR, open(c, "../escape", files::CMD_WRITE), "Nothing outside the directory");
+    assert_eq!(files::STATUS_ERROR, open(c, dir.join("log").to_str().unwrap(), files::CMD_READ), "Absolute paths are outside too");
+    assert_eq!(files::STATUS_ERROR, open(c, "missing", files::CMD_READ));
+    c.devices.files.write_word(files::FILE_DATA, 0x0a);
+    assert_eq!(files::STATUS_ERROR, c.devices.files.read_word(files::FILE_CTL), "Writing without an open file fails");
+
+    c.reset();
+    assert_eq!(files::STATUS_OPEN, open(c, "log", files::CMD_READ), "A reset keeps the directory");
+    let without: &mut Computer = &mut Computer::new();
+    assert_eq!(files::STATUS_ERROR, open(without, "log", files::CMD_READ), "No directory, no files");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sd_card_over_spi() {
+    let path = std::env::temp_dir().join(format!("msp430_sd_test_{}.img", std::process::id()));
+    let mut image: Vec<u8> = vec![0; 1024 * 512];
+    image[3 * 512..4 * 512].iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    std::fs::write(&path, &image).unwrap();
+    let spec: SdCardSpec = format!("P1.4:{}", path.display()).parse().unwrap();
+    assert_eq!("P1.4", spec.chip_select.to_string());
+
+    let c: &mut Computer = &mut Computer::new();
+    c.devices.spi.sd_card = Some(SdCard::open(&spec).unwrap());
+    let assembled = assemble("
+mov.b #0x10 &0x0022 ; P1.4 output, high: card not selected
+bis.b #0x10 &0x0021
+mov.b #0x29 &0x0068 ; UCB0CTL0, 3-pin SPI master
+bic.b #1 &0x0069 ; UCSWRST
+mov.b &0x0003 r4
+mov.b #0x40 &0x006f ; CMD0 while deselected
+mov.b &0x0003 r5
+mov.b &0x006e r6
+mov.b &0x0003 r7
+");
+    execute(c, assembled.trim(), 9);
+    assert_eq!([spi::UCB0TXIFG as u16, (spi::UCB0TXIFG | spi::UCB0RXIFG) as u16, 0xff, spi::UCB0TXIFG as u16],
+               [4, 5, 6, 7].map(|r| c.get_register(r).get_word()), "Nothing answers while CS is high, reading RXBUF clears the flag");
+
+    c.write_byte(0x0021, 0x00); // select the card
+    let exchange = |c: &mut Computer, byte: u8| {
+        c.write_byte(spi::UCB0TXBUF, byte);
+        return c.read_byte(spi::UCB0RXBUF);
+    };
+    // send a command, then clock 0xff until a response that isn't 0xff
+    let command = |c: &mut Computer, index: u8, argument: u32| {
+        for byte in [&[0x40 | index][..], &argument.to_be_bytes(), &[0x95]].concat() {
+            exchange(c, byte);
+        }
+        return (0..8).map(|_| exchange(c, 0xff)).find(|&r| r != 0xff).unwrap_or(0xff);
+    };
+    assert_eq!(0x01, command(c, 0, 0), "CMD0 puts the card in idle state");
+    assert_eq!(0x05, command(c, 17, 0), "No reads while idle");
+    assert_eq!(0x01, command(c, 8, 0x1aa));
+    assert_eq!([0, 0, 0x01, 0xaa], [0; 4].map(|_| exchange(c, 0xff)), "CMD8 echoes the voltage and check pattern");
+    assert_eq!(0x01, command(c, 55, 0));
+    assert_eq!(0x00, command(c, 41, 1 << 30), "ACMD41 finishes initialization");
+    assert_eq!(0x00, command(c, 58, 0));
+    assert_eq!(0x40, exchange(c, 0xff) & 0x40, "SDHC, block addressed");
+    (0..3).for_each(|_| { exchange(c, 0xff); });
+
+    assert_eq!(0x00, command(c, 9, 0));
+    let csd: Vec<u8> = (0..20).map(|_| exchange(c, 0xff)).skip_while(|&b| b != 0xfe).skip(1).take(16).collect();
+    let c_size: u32 = ((csd[7] as u32 & 0x3f) << 16) | ((csd[8] as u32) << 8) | csd[9] as u32;
+    assert_eq!((1, 1024), (csd[0] >> 6, (c_size + 1) << 10), "CSD version 2 with the image's 1024 blocks");
+
+    assert_eq!(0x00, command(c, 17, 3));
+    let block: Vec<u8> = (0..520).map(|_| exchange(c, 0xff)).skip_while(|&b| b != 0xfe).skip(1).take(512).collect();
+    assert_eq!(&image[3 * 512..4 * 512], &block[..], "CMD17 reads the block");
+    exchange(c, 0xff);
+    exchange(c, 0xff);
+
+    assert_eq!(0x00, command(c, 24, 5));
+    exchange(c, 0xff);
+    exchange(c, 0xfe);
+    for i in 0..514 {
+        exchange(c, (i % 7) as u8);
+    }
+    assert_eq!(0x05, exchange(c, 0xff) & 0x1f, "Data accepted");
+    assert_eq!(0x00, exchange(c, 0xff), "Busy");
+    assert_eq!(0xff, exchange(c, 0xff), "Ready again");
+    let written: Vec<u8> = std::fs::read(&path).unwrap();
+    assert_eq!((0..512).map(|i| (i % 7) as u8).collect::<Vec<u8>>(), written[5 * 512..6 * 512], "CMD24 wrote the image file");
+    assert_eq!(0x40, command(c, 17, 1024), "Past the end");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn radio_network() {
+    let pins: RadioSpec = "P2.0:P2.1:P2.2".parse().unwrap();
+    let node = || {
+        let mut c: Computer = Computer::new();
+        c.devices.spi.radio = Some(Radio::new(pins));
+        c.write_byte(0x002a, 0x03); // P2DIR: CSN and CE are outputs
+        c.write_byte(0x0029, 0x01); // CSN high
+        c.write_byte(0x002c, 0x04); // P2IES: IRQ falls
+        c.write_byte(spi::UCB0CTL1, 0);
+        return c;
+    };
+    // one command with CSN low, returns what came back
+    let transaction = |c: &mut Computer, bytes: &[u8]| {
+        let out: u8 = c.read_byte(0x0029);
+        c.write_byte(0x0029, out & !0x01);
+        let back: Vec<u8> = bytes.iter().map(|&b| {
+            c.write_byte(spi::UCB0TXBUF, b);
+            return c.read_byte(spi::UCB0RXBUF);
+        }).collect();
+        c.write_byte(0x0029, out | 0x01);
+        return back;
+    };
+    let ce = |c: &mut Computer, high: bool| c.write_byte(0x0029, if high {0x03} else {0x01});
+    let (sender, receiver, other) = (&mut node(), &mut node(), &mut node());
+    assert!(receiver.devices.gpio.level(pins.irq), "IRQ idles high");
+
+    transaction(receiver, &[radio::W_REGISTER | radio::CONFIG, radio::PWR_UP | radio::PRIM_RX]);
+    transaction(receiver, &[radio::W_REGISTER | radio::RX_PW_P0, 3]);
+    ce(receiver, true);
+    transaction(other, &[radio::W_REGISTER | radio::CONFIG, radio::PWR_UP | radio::PRIM_RX]);
+    transaction(other, &[radio::W_REGISTER | radio::RX_PW_P0, 3]);
+    transaction(other, &[radio::W_REGISTER | radio::RF_CH, 76]);
+    ce(other, true);
+
+    transaction(sender, &[radio::W_REGISTER | radio::CONFIG, radio::PWR_UP]);
+    transaction(sender, &[radio::W_TX_PAYLOAD, 1, 2, 3]);
+    assert_eq!(vec![0x0e, 0x01], transaction(sender, &[radio::FIFO_STATUS, 0xff]), "Status first, then the register");
+    ce(sender, true);
+    assert_eq!(radio::TX_DS, transaction(sender, &[0xff])[0] & radio::TX_DS, "Sent");
+    assert!(!sender.devices.gpio.level(pins.irq), "IRQ asserted for TX_DS");
+    radio_link::broadcast(&mut [&mut sender.devices, &mut receiver.devices, &mut other.devices]);
+
+    assert!(!receiver.devices.gpio.level(pins.irq) && receiver.read_byte(0x002b) & 0x04 != 0, "IRQ fell, P2.2 flagged");
+    assert_eq!(radio::RX_DR, transaction(receiver, &[0xff])[0] & 0x4e, "Data ready in pipe 0");
+    assert_eq!(vec![3], transaction(receiver, &[radio::R_RX_PL_WID, 0xff])[1..]);
+    assert_eq!(vec![1, 2, 3], transaction(receiver, &[radio::R_RX_PAYLOAD, 0xff, 0xff, 0xff])[1..]);
+    transaction(receiver, &[radio::W_REGISTER | radio::STATUS, radio::RX_DR]);
+    assert!(receiver.devices.gpio.level(pins.irq), "Clearing RX_DR releases IRQ");
+    assert_eq!(0, transaction(other, &[0xff])[0] & radio::RX_DR, "Another channel heard nothing");
+
+    let packet: Packet = Packet { channel: 2, address: vec![0xe7; 5], payload: vec![9; 32] };
+    assert_eq!(Some(packet.clone()), Packet::decode(&packet.encode()));
+    assert_eq!(None, Packet::decode(&[2, 7, 0, 0]), "Addresses are 3 to 5 bytes");
+}
+
+/// Firmware idling at 1 MHz (a cycle is a microsecond), the pins are driven from here
+fn idle_machine(sensors: &[&str]) -> Computer {
+    let mut c: Computer = Computer::new();
+    let specs: Vec<SensorSpec> = sensors.iter().map(|s| s.parse().unwrap()).collect();
+    c.sensors = Some(Sensors::new(&specs).unwrap());
+    c.attach_hooks();
+    let assembled = assemble("
+loop:
+jmp loop
+");
+    execute(&mut c, assembled.trim(), 1);
+    return c;
+}
+
+fn wait_us(c: &mut Computer, us: u64) {
+    let until: u64 = c.clock.cycles() + us;
+    while c.clock.cycles() < until {
+        c.step();
+    }
+}
+
+/// Open drain: pulling low makes the pin an output (P1OUT stays 0), releasing makes it an input
+fn pull_low(c: &mut Computer, mask: u8, low: bool) {
+    let dir: u8 = c.read_byte(0x0022);
+    c.write_byte(0x0022, if low {dir | mask} else {dir & !mask});
+}
+
+const ONE_WIRE: u8 = 0x10;
+
+fn one_wire_reset(c: &mut Computer) -> bool {
+    pull_low(c, ONE_WIRE, true);
+    wait_us(c, 480);
+    pull_low(c, ONE_WIRE, false);
+    wait_us(c, 70);
+    let present: bool = c.read_byte(0x0020) & ONE_WIRE == 0;
+    wait_us(c, 410);
+    return present;
+}
+
+fn one_wire_bit(c: &mut Computer, bit: bool) -> bool {
+    pull_low(c, ONE_WIRE, true);
+    wait_us(c, if bit {2} else {65});
+    pull_low(c, ONE_WIRE, false);
+    wait_us(c, 10);
+    let read: bool = c.read_byte(0x0020) & ONE_WIRE != 0;
+    wait_us(c, 50);
+    return read;
+}
+
+fn one_wire_write(c: &mut Computer, bytes: &[u8]) {
+    for byte in bytes {
+        (0..8).for_each(|bit| { one_wire_bit(c, (byte >> bit) & 1 != 0); });
+    }
+}
+
+fn one_wire_read(c: &mut Computer, count: usize) -> Vec<u8> {
+    return (0..count).map(|_| (0..8).fold(0, |byte, bit| byte | ((one_wire_bit(c, true) as u8) << bit))).collect();
+}
+
+/// The standard search algorithm, every ROM code on the bus
+fn one_wire_search(c: &mut Computer) -> Vec<u64> {
+    let mut found: Vec<u64> = Vec::new();
+    let mut last_discrepancy: i32 = -1;
+    loop {
+        assert!(one_wire_reset(c));
+        one_wire_write(c, &[0xf0]);
+        let (mut rom, mut discrepancy): (u64, i32) = (0, -1);
+        for index in 0..64 {
+            let (bit, complement) = (one_wire_bit(c, true), one_wire_bit(c, true));
+            assert!(!(bit && complement), "Nobody answered");
+            let direction: bool = if bit != complement {
+                bit
+            } else if index == last_discrepancy {
+                true
+            } else if index > last_discrepancy {
+                discrepancy = index;
+                false
+            } else {
+                found.last().unwrap() >> index & 1 != 0
+            };
+            if !direction && bit == complement {
+                discrepancy = index;
+            }
+            rom |= (direction as u64) << index;
+            one_wire_bit(c, direction);
+        }
+        found.push(rom);
+        if discrepancy < 0 {
+            return found;
+        }
+        last_discrepancy = discrepancy;
+    }
+}
+
+fn transactions(c: &mut Computer) -> Vec<String> {
+    wait_us(c, 1000);
+    return c.sensors.as_mut().unwrap().take().iter().map(|t| t.to_string()).collect();
+}
+
+#[test]
+fn one_wire_temperature_sensors() {
+    assert_eq!(0xa2, onewire::crc8(&[0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00]), "Maxim's example ROM");
+    let c: &mut Computer = &mut idle_machine(&["ds18b20:P1.4:21.5", "ds18b20:P1.4:-10.0625"]);
+    assert!(c.read_byte(0x0020) & ONE_WIRE != 0, "The bus idles high");
+    assert!(one_wire_reset(c), "Presence pulse");
+    one_wire_write(c, &[0xcc, 0x4e, 0x50, 0x05, 0x1f]); // 9 bit resolution
+    assert_eq!(vec!["1-Wire P1.4: reset, 2 present; SKIP ROM; WRITE SCRATCHPAD; wrote 50 05 1f"], transactions(c));
+
+    let roms: Vec<u64> = one_wire_search(c);
+    let expected: Vec<u64> = [1u8, 2].iter().map(|serial| {
+        let mut rom: [u8; 8] = [0x28, *serial, 0, 0, 0, 0, 0, 0];
+        rom[7] = onewire::crc8(&rom[..7]);
+        u64::from_le_bytes(rom)
+    }).collect();
+    assert_eq!(expected, { let mut sorted: Vec<u64> = roms.clone(); sorted.sort(); sorted });
+    let logged: Vec<String> = transactions(c);
+    assert_eq!(2, logged.len());
+    assert!(logged.iter().any(|t| t.ends_with(&format!("SEARCH ROM {:02x} {:02x} 00 00 00 00 00 {:02x}",
+                                                       0x28, 2, expected[1] >> 56))), "{:?}", logged);
+
+    // only the second sensor converts, the first one keeps its power-on 85 °C
+    assert!(one_wire_reset(c));
+    one_wire_write(c, &[0x55]);
+    one_wire_write(c, &expected[1].to_le_bytes());
+    one_wire_write(c, &[0x44]);
+    assert!(!one_wire_bit(c, true), "Converting");
+    wait_us(c, 93_750);
+    assert!(one_wire_bit(c, true), "9 bit conversion done");
+    for (rom, reading) in expected.iter().zip([85 * 16, (-10.0625f64 * 16.0) as i16 & !0x07]) {
+        assert!(one_wire_reset(c));
+        one_wire_write(c, &[0x55]);
+        one_wire_write(c, &rom.to_le_bytes());
+        one_wire_write(c, &[0xbe]);
+        let scratchpad: Vec<u8> = one_wire_read(c, 9);
+        assert_eq!(reading, i16::from_le_bytes([scratchpad[0], scratchpad[1]]));
+        assert_eq!(onewire::crc8(&scratchpad[..8]), scratchpad[8], "Scratchpad CRC");
+    }
+    assert_eq!(vec!["1-Wire P1.4: reset, 2 present; MATCH ROM 28 02 00 00 00 00 00 70; CONVERT T; read 00 (partial byte)",
+                    "1-Wire P1.4: no reset; read 01 (partial byte)",
+                    "1-Wire P1.4: reset, 2 present; MATCH ROM 28 01 00 00 00 00 00 29; READ SCRATCHPAD; read 50 05 50 05 1f ff 0c 10 88",
+                    "1-Wire P1.4: reset, 2 present; MATCH ROM 28 02 00 00 00 00 00 70; READ SCRATCHPAD; read 58 ff 50 05 1f ff 0c 10 6d"],
+               transactions(c));
+
+    c.reset();
+    assert!(one_wire_reset(c));
+    one_wire_write(c, &[0xcc, 0xbe]);
+    assert_eq!([0x50, 0x05, 0x4b, 0x46, 0x7f], one_wire_read(c, 5)[..], "Power-up values, WRITE SCRATCHPAD wasn't copied");
+}
+
+/// Durations of the levels on a pin while it is released, in µs, starting with the first low
+fn pulse_widths(c: &mut Computer, mask: u8, us: u64) -> Vec<(bool, u64)> {
+    let mut widths: Vec<(bool, u64)> = Vec::new();
+    let until: u64 = c.clock.cycles() + us;
+    while c.clock.cycles() < until {
+        let before: u64 = c.clock.cycles();
+        c.step();
+        let elapsed: u64 = c.clock.cycles() - before;
+        let high: bool = c.read_byte(0x0020) & mask != 0;
+        if widths.last().is_some_and(|(level, _)| *level == high) {
+            widths.last_mut().unwrap().1 += elapsed;
+        } else if !widths.is_empty() || !high {
+            widths.push((high, elapsed));
+        }
+    }
+    return widths;
+}
+
+#[test]
+fn dht_sensors() {
+    const DHT: u8 = 0x20;
+    let c: &mut Computer = &mut idle_machine(&["dht22:P1.5:-5.5:40.2", "dht11:P1.6:23.4:61"]);
+    pull_low(c, DHT, true);
+    wait_us(c, 300);
+    pull_low(c, DHT, false);
+    assert!(pulse_widths(c, DHT, 1000).is_empty(), "Not a start pulse");
+
+    pull_low(c, DHT, true);
+    wait_us(c, 1000);
+    pull_low(c, DHT, false);
+    let widths: Vec<(bool, u64)> = pulse_widths(c, DHT, 6000);
+    assert_eq!(84, widths.len(), "Response, 40 bits, end of transmission");
+    assert!(matches!(widths[..2], [(false, 79..=81), (true, 79..=81)]), "{:?}", &widths[..2]);
+    let bytes: Vec<u8> = widths[3..83].chunks(2).map(|bit| bit[0].1 > 40).collect::<Vec<bool>>()
+        .chunks(8).map(|bits| bits.iter().fold(0, |byte, bit| byte << 1 | *bit as u8)).collect();
+    assert_eq!(vec![0x01, 0x92, 0x80, 0x37, 0x4a], bytes, "40.2%, -5.5 °C and the checksum");
+    assert_eq!(vec!["DHT22 P1.5: start pulse too short (300 µs, needs 800 µs), ignored",
+                    "DHT22 P1.5: start (1000 µs low), answered 40.2% -5.5 °C (01 92 80 37 4a)"], transactions(c));
+
+    pull_low(c, 0x40, true);
+    wait_us(c, 18_000);
+    pull_low(c, 0x40, false);
+    wait_us(c, 1000);
+    pull_low(c, 0x40, true);
+    wait_us(c, 1000);
+    assert_eq!(vec!["DHT11 P1.6: start (18000 µs low), answered 61% 23.4 °C (3d 00 17 04 58)",
+                    "DHT11 P1.6: answer cut off after 8 of 40 bits"], transactions(c));
+    assert!(Sensors::new(&["ds18b20:P1.6".parse().unwrap(), "dht11:P1.6".parse().unwrap()]).is_err(), "One pin each");
+    assert_eq!("dht22:P1.5:-5.5:40.2", "DHT22:p1.5:-5.5:40.2".parse::<SensorSpec>().unwrap().to_string());
+    assert!("ds18b20:P1.4:1:2".parse::<SensorSpec>().is_err(), "No humidity");
+}
+
+#[test]
+fn ws2812_strip() {
+    let c: &mut Computer = &mut Computer::new();
+    let spec: LedStripSpec = "P1.7:2".parse().unwrap();
+    c.led_strips = vec![LedStrip::new(spec)];
+    c.attach_hooks();
+    c.led_strips[0].export = true;
+    // a 0 is high for 8 cycles and a 1 for 11, bits are about 20 cycles apart
+    let assembled = assemble("
+mov.b #0x80 &0x0022 ; P1DIR
+frame:
+mov #0x0200 r4
+mov #6 r5
+byte:
+mov.b @r4+ r6
+mov #8 r7
+bit:
+bis.b #0x80 &0x0021
+rla.b r6
+jc one
+bic.b #0x80 &0x0021
+nop
+nop
+jmp next
+one:
+nop
+nop
+nop
+bic.b #0x80 &0x0021
+next:
+dec r7
+jnz bit
+dec r5
+jnz byte
+mov #60 r8
+latch:
+dec r8
+jnz latch
+jmp frame
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 0);
+    for (i, byte) in [0x10, 0xff, 0x00, 0x80, 0x01, 0xaa].iter().enumerate() {
+        c.write_byte(0x0200 + i as u16, *byte);
+    }
+    for _ in 0..600 {
+        c.step();
+    }
+    let strip: &mut LedStrip = &mut c.led_strips[0];
+    assert_eq!(1, strip.frames(), "Latched once the pin stayed low");
+    assert_eq!(&[Rgb { r: 0xff, g: 0x10, b: 0x00 }, Rgb { r: 0x01, g: 0x80, b: 0xaa }], strip.leds(), "GRB on the wire");
+    let frames = strip.take_frames();
+    assert_eq!("P1.7", frames[0].pin.to_string());
+    assert!(frames[0].csv_line().ends_with(",P1.7,ff1000 0180aa"), "{}", frames[0].csv_line());
+    assert!(strip.take_frames().is_empty());
+
+    for i in 0..6 {
+        c.write_byte(0x0200 + i, 0xff);
+    }
+    // the frame being sent still had the old first bytes, the next one is all 0xff
+    for _ in 0..1200 {
+        c.step();
+    }
+    assert_eq!(3, c.led_strips[0].frames());
+    assert_eq!(&[Rgb { r: 0xff, g: 0xff, b: 0xff }; 2], c.led_strips[0].leds());
+    assert!(c.led_strips[0].report().starts_with("P1.7: 2 LEDs after 3 frames: ffffff ffffff"));
+    c.reset();
+    assert_eq!(&[Rgb { r: 0xff, g: 0xff, b: 0xff }; 2], c.led_strips[0].leds(), "The LEDs have their own supply");
+    assert_eq!("P2.0", "P2.0".parse::<LedStripSpec>().unwrap().to_string());
+    assert!("P2.0:many".parse::<LedStripSpec>().is_err());
+}
+
+#[test]
+fn frame_buffer() {
+    let dir = std::env::temp_dir().join(format!("msp430_framebuffer_test_{}", std::process::id()));
+    let c: &mut Computer = &mut Computer::new();
+    let spec: FrameBufferSpec = "16x2:mono@0x2400".parse().unwrap();
+    c.devices.framebuffer.set_spec(spec);
+    c.frame_export = Some(FrameExport::create(dir.to_str().unwrap()).unwrap());
+    let assembled = assemble("
+mov &0x01d8 r5 ; FB_WIDTH
+mov &0x01dc r6 ; FB_FORMAT
+mov #0x8001 &0x2400
+mov.b #0xff &0x2403
+mov #1 &0x01de ; FB_FRAME, present
+mov.b #0 &0x2400
+");
+    execute(c, assembled.trim(), 6);
+    assert_eq!(16, c.get_register_imut(5).get_word());
+    assert_eq!(framebuffer::PixelFormat::Mono.id() as u16, c.get_register_imut(6).get_word());
+    assert_eq!(1, c.devices.framebuffer.frames());
+    let lit: Rgb = Rgb { r: 0xff, g: 0xff, b: 0xff };
+    let pixels: Vec<Rgb> = c.frame().unwrap();
+    assert_eq!(32, pixels.len());
+    assert_eq!(Rgb::default(), pixels[0], "Rendered from memory as it is now");
+    assert_eq!(lit, pixels[15]);
+    assert_eq!(vec![lit; 8], pixels[24..].to_vec());
+
+    let image: Vec<u8> = std::fs::read(dir.join("frame-000001.ppm")).unwrap();
+    assert!(image.starts_with(b"P6\n16 2\n255\n"));
+    assert_eq!([0xff; 3], image[12..15], "Written as presented");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let color: FrameBufferSpec = "2x1:rgb565@0xfffc".parse().unwrap();
+    assert_eq!(vec![Rgb { r: 0xff, g: 0, b: 0 }, Rgb { r: 0, g: 0xff, b: 0xff }], color.render(&[0xf8, 0x00, 0x07, 0xff]));
+    assert_eq!(Rgb { r: 0xff, g: 0x24, b: 0xaa }, "1x1:rgb332@0".parse::<FrameBufferSpec>().unwrap().render(&[0xe6])[0]);
+    assert_eq!("2x1:rgb565@0xfffc", color.to_string());
+    assert!("4x1:rgb565@0xfffc".parse::<FrameBufferSpec>().is_err(), "Past the end of memory");
+    assert!("128x64:cmyk@0x2400".parse::<FrameBufferSpec>().is_err());
+
+    let none: &mut Computer = &mut Computer::new();
+    execute(none, assembled.trim(), 6);
+    assert_eq!(0, none.get_register_imut(5).get_word(), "No frame buffer, no size");
+    assert_eq!(None, none.frame());
+}
+
+#[test]
+fn input_widgets() {
+    let c: &mut Computer = &mut Computer::new();
+    let encoders: Vec<EncoderSpec> = vec!["P1.4,P1.5,P1.6".parse().unwrap()];
+    let matrices: Vec<MatrixSpec> = vec!["P2.0,P2.1:P1.0,P1.1".parse().unwrap()];
+    c.widgets = Some(Widgets::new(&encoders, &matrices).unwrap());
+    c.attach_hooks();
+    let assembled = assemble("
+loop:
+jmp loop
+");
+    execute(c, assembled.trim(), 0);
+    // pull-ups on the encoder and the columns, rows are outputs driving high
+    c.write_byte(0x0027, 0x73); // P1REN
+    c.write_byte(0x0021, 0x73); // P1OUT
+    c.write_byte(0x002a, 0x03); // P2DIR
+    c.write_byte(0x0029, 0x03); // P2OUT
+    c.write_byte(0x0024, 0x10); // P1IES, falling edge of A
+    c.step();
+    assert_eq!(0x73, c.read_byte(0x0020) & 0x73, "Everything open");
+
+    let quadrature = |c: &mut Computer, steps: u64| {
+        let mut seen: Vec<u8> = vec![(c.read_byte(0x0020) >> 4) & 3];
+        for _ in 0..steps {
+            c.step();
+            let ab: u8 = (c.read_byte(0x0020) >> 4) & 3;
+            if seen.last() != Some(&ab) {
+                seen.push(ab);
+            }
+        }
+        return seen;
+    };
+    c.widgets.as_mut().unwrap().encoders[0].turn(1);
+    assert_eq!(vec![3, 2, 0, 1, 3], quadrature(c, 1500), "Clockwise: A closes first");
+    assert_eq!(0x10, c.read_byte(0x0023) & 0x10, "Falling edge flagged");
+    c.widgets.as_mut().unwrap().encoders[0].turn(-1);
+    assert_eq!(vec![3, 1, 0, 2, 3], quadrature(c, 1500), "Counter-clockwise: B closes first");
+    c.widgets.as_mut().unwrap().encoders[0].turn(3);
+    quadrature(c, 200);
+    c.reset();
+    execute(c, assembled.trim(), 0);
+    c.write_byte(0x0027, 0x30);
+    c.write_byte(0x0021, 0x30);
+    quadrature(c, 4000);
+    assert_eq!(3, c.widgets.as_ref().unwrap().encoders[0].position, "The turn continued over the reset");
+    c.widgets.as_mut().unwrap().encoders[0].press(true);
+    c.write_byte(0x0027, 0x73);
+    c.write_byte(0x0021, 0x73);
+    c.write_byte(0x002a, 0x03);
+    c.write_byte(0x0029, 0x03);
+    c.step();
+    assert_eq!(0x00, c.read_byte(0x0020) & 0x40, "Switch pressed");
+
+    let matrix = &mut c.widgets.as_mut().unwrap().matrices[0];
+    assert!(matrix.press(1, 0, true));
+    assert!(!matrix.press(2, 0, true), "No third row");
+    c.step();
+    assert_eq!(0x03, c.read_byte(0x0020) & 0x03, "Rows driving high");
+    c.write_byte(0x0029, 0x01); // scan row 1
+    c.step();
+    assert_eq!(0x02, c.read_byte(0x0020) & 0x03, "Key 1,0 pulls column 0 low");
+    c.write_byte(0x0029, 0x02); // scan row 0
+    c.step();
+    assert_eq!(0x03, c.read_byte(0x0020) & 0x03, "Nothing pressed in row 0");
+    // scanning the other way round: columns drive, rows read
+    c.write_byte(0x002a, 0x00);
+    c.write_byte(0x002f, 0x03); // P2REN
+    c.write_byte(0x0029, 0x03); // pull-ups
+    c.write_byte(0x0022, 0x03); // P1DIR
+    c.write_byte(0x0021, 0x72);
+    c.step();
+    assert_eq!(0x01, c.read_byte(0x0028) & 0x03, "Column 0 pulls row 1 low");
+    c.widgets.as_mut().unwrap().matrices[0].press(1, 0, false);
+    c.step();
+    assert_eq!(0x03, c.read_byte(0x0028) & 0x03, "Released");
+
+    assert_eq!("P1.4,P1.5", "P1.4,P1.5".parse::<EncoderSpec>().unwrap().to_string());
+    assert!("P1.4".parse::<EncoderSpec>().is_err());
+    assert_eq!("P2.0,P2.1:P1.0", "p2.0,p2.1:p1.0".parse::<MatrixSpec>().unwrap().to_string());
+    assert_eq!("P1.4 is used twice", Widgets::new(&encoders, &["P1.4:P1.0".parse().unwrap()]).err().unwrap());
+}
+
+#[test]
+fn midi_osc_bridge() {
+    let mut parser: MidiParser = MidiParser::default();
+    // note on, running status with a clock in between, a note on at velocity 0, sysex, stray data
+    let stream: [u8; 15] = [0x91, 60, 100, 64, 0xf8, 0, 0xf0, 0x7d, 0x01, 0xf7, 0x12, 0xe0, 0x00, 0x40, 0xf6];
+    let messages: Vec<String> = stream.iter().filter_map(|b| parser.push(*b)).map(|m| m.to_string()).collect();
+    assert_eq!(vec!["note on 2 60 100", "clock", "note off 2 64 0", "sysex (2 bytes)", "pitch bend 1 0", "system 246"], messages);
+    assert_eq!(("/midi/cc", vec![OscArg::Int(16), OscArg::Int(7), OscArg::Int(127)]), MidiMessage(vec![0xbf, 7, 127]).osc());
+    assert_eq!(b"/midi/cc\0\0\0\0,ii\0\0\0\0\x01\xff\xff\xff\xfe".to_vec(),
+               midi_link::osc_message("/midi/cc", &[OscArg::Int(1), OscArg::Int(-2)]));
+    assert_eq!(b"/b\0\0,b\0\0\0\0\0\x03\x01\x02\x03\0".to_vec(), midi_link::osc_message("/b", &[OscArg::Blob(vec![1, 2, 3])]));
+
+    let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    let path = std::env::temp_dir().join(format!("msp430_midi_test_{}.mid", std::process::id()));
+    let sources: Vec<MidiSource> = vec!["uart".parse().unwrap(), "0x0200".parse().unwrap()];
+    let mappings: Vec<OscMapping> = vec!["0x0202:/synth/cutoff".parse().unwrap()];
+    let mut link = MidiLink::open(&sources, &mappings, path.to_str(), Some(&receiver.local_addr().unwrap().to_string())).unwrap();
+    let c: &mut Computer = &mut Computer::new();
+    c.write_tap = link.tap();
+    let assembled = assemble("
+mov #0x90 &0x01c0 ; UART TX
+mov #0x3c &0x01c0
+mov.b #0xc2 &0x0200
+mov #0x40 &0x01c0
+mov.b #5 &0x0200
+mov #1234 &0x0202
+");
+    execute(c, assembled.trim(), 6);
+    let writes = c.write_tap.as_mut().unwrap().take();
+    assert!(link.takes_uart());
+    let sent: Vec<String> = link.pump(&c.devices.uart.take_tx(), &writes).unwrap().iter().map(Sent::to_string).collect();
+    assert_eq!(vec!["MIDI from uart: note on 1 60 64", "MIDI from 0x0200: program 3 5", "OSC /synth/cutoff 1234"], sent);
+    assert_eq!(vec![0x90, 0x3c, 0x40, 0xc2, 5], std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+    let mut buf: [u8; 64] = [0; 64];
+    let datagrams: Vec<Vec<u8>> = (0..3).map(|_| receiver.recv(&mut buf).map(|n| buf[..n].to_vec()).unwrap()).collect();
+    assert_eq!(midi_link::osc_message("/midi/note_on", &[OscArg::Int(1), OscArg::Int(60), OscArg::Int(64)]), datagrams[0]);
+    assert_eq!(midi_link::osc_message("/midi/program", &[OscArg::Int(3), OscArg::Int(5)]), datagrams[1]);
+    assert_eq!(midi_link::osc_message("/synth/cutoff", &[OscArg::Int(1234)]), datagrams[2]);
+
+    assert_eq!("0x0200", "512".parse::<MidiSource>().unwrap().to_string());
+    assert!("0x0200:synth".parse::<OscMapping>().is_err(), "OSC addresses start with /");
+}
+
+/// Sums what it's handed, slowly, and panics on a 0
+struct SlowSum(u64);
+
+impl QuantumModel for SlowSum {
+    type Input = u64;
+    type Output = (u64, u64);
+
+    fn quantum(&mut self, cycle: u64, input: u64) -> (u64, u64) {
+        assert_ne!(0, input);
+        std::thread::sleep(Duration::from_millis(5));
+        self.0 += input;
+        return (cycle, self.0);
+    }
+}
+
+#[test]
+fn peripheral_worker() {
+    let mut inline: Hosted<SlowSum> = Hosted::new("sum", SlowSum(0), false).unwrap();
+    assert_eq!(Some((100, 1)), inline.exchange(100, 1), "Inline models answer right away");
+    assert_eq!(None, inline.finish());
+
+    let mut threaded: Hosted<SlowSum> = Hosted::new("sum", SlowSum(0), true).unwrap();
+    assert_eq!(None, threaded.exchange(100, 1), "Nothing to collect at the first boundary");
+    assert_eq!(Some((100, 1)), threaded.exchange(200, 2), "The barrier waits for the slow quantum");
+    assert_eq!(Some((200, 3)), threaded.exchange(300, 3));
+    assert_eq!(Some((300, 6)), threaded.finish(), "Finishing collects the quantum in flight");
+
+    let mut worker: Worker<SlowSum> = Worker::spawn("sum", SlowSum(0)).unwrap();
+    worker.exchange(100, 0);
+    assert_eq!(None, worker.exchange(200, 1), "The model panicked");
+    assert!(!worker.alive());
+    assert!(worker.finish().1.is_none(), "And is gone");
+}
+
+#[test]
+fn bus_map() {
+    let c: &mut Computer = &mut ComputerBuilder::new().profile(Profile::Fr4133.device()).build();
+    assert_eq!(Handler::Device(DeviceId::Wdt), c.bus.handler(wdt::WDTCTL), "Device registers win over peripheral space");
+    assert_eq!(Handler::Peripheral, c.bus.handler(0x0128));
+    assert_eq!(Handler::Ram, c.bus.handler(0x0660), "Backup memory is RAM");
+    assert_eq!(Handler::Ram, c.bus.handler(0x2000));
+    assert_eq!(Handler::Unmapped, c.bus.handler(0x3000));
+    assert_eq!((Some(DeviceId::Wdt), Some(DeviceId::Spi)), (c.bus.device(wdt::IFG1), c.bus.device(wdt::IFG1 + 1)),
+               "IFG1 and IFG2 share a word");
+    assert_eq!(Some(DeviceId::FirmwareTest), c.bus.device(firmware_test::TEST_RESULT + 1), "The rest of a word register");
+    assert_eq!(None, c.bus.device(0x0200));
+
+    // a word split between two devices is a byte from each
+    c.devices.wdt.accepted();
+    c.devices.spi.write_byte(spi::IFG2, spi::UCB0RXIFG, &c.devices.gpio.clone());
+    assert_eq!(spi::UCB0RXIFG as u16, c.read_word(wdt::IFG1), "Neither device sees the other's byte");
+    c.write_word(wdt::IFG1, 0);
+    assert_eq!(0, c.devices.spi.read_byte(spi::IFG2));
+
+    let generic: Computer = Computer::new();
+    assert_eq!(Handler::Unmapped, generic.bus.handler(0x0128), "No profile, no regions");
+    assert_eq!(Handler::Device(DeviceId::Mpu), generic.bus.handler(mpu::MPUCTL0), "Devices are always there");
+}
@@ -0,0 +1,142 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use base64::{Engine as _, engine::general_purpose};
+use crate::image::{ProgramImage, Symbol};
+use crate::test_runner::{run_tests, TestOptions, TestOutcome};
+use crate::diagnostics::EmulationMode;
+use crate::profile::DeviceProfile;
+
+/// Assemble `code` into an image, naming the labels bound to vectors 0xffa0, 0xffa2, ...
+fn image_with_symbols(code: &str, names: &[&str]) -> ProgramImage {
+    let assembled = assemble(code);
+    let bytes = general_purpose::STANDARD.decode(assembled.trim()).expect("Failed to decode memory");
+    let mut image = ProgramImage::from_segmented(&bytes).expect("Assembler output should parse");
+    let c: &mut Computer = &mut Computer::new();
+    image.load(c);
+    for (i, name) in names.iter().enumerate() {
+        let address = c.memory.get_word(0xffa0 + 2 * i as u16);
+        image.symbols.push(Symbol { name: name.to_string(), address });
+    }
+    return image;
+}
+
+#[test]
+fn firmware_test_runner() {
+    let image = image_with_symbols("
+test_pass:
+mov #5 r5
+mov #5 &0x01f2
+mov r5 &0x01f4
+ret
+
+test_fail:
+mov #4 &0x01f4
+ret
+
+test_hang:
+jmp test_hang
+
+helper:
+mov #2 &0x01f8
+
+.interrupt 0xffa0 test_pass
+.interrupt 0xffa2 test_fail
+.interrupt 0xffa4 test_hang
+.interrupt 0xffa6 helper
+", &["test_pass", "test_fail", "test_hang", "helper"]);
+
+    let options = TestOptions { prefix: "test_".to_string(), max_steps: 1000, stack_top: 0x4400, profile: DeviceProfile::default(),
+                                emulation: EmulationMode::Permissive, runaway_cycles: None, shadow_stack: false, file_dir: None, sensors: None };
+    let results = run_tests(&image, &options);
+
+    assert_eq!(3, results.len(), "Only prefixed symbols are tests");
+    assert_eq!(TestOutcome::Passed, results[0].outcome, "test_pass");
+    match &results[1].outcome {
+        TestOutcome::Failed(failures) => assert_eq!(4, failures[0].actual, "test_fail"),
+        other => panic!("test_fail should fail, got {:?}", other),
+    }
+    assert_eq!(TestOutcome::TimedOut, results[2].outcome, "test_hang");
+    assert_eq!(1000, results[2].steps);
+}
+
+/// Smallest useful MSP430 ELF: one loadable segment, a symbol table and its string table
+fn build_elf(load_address: u32, code: &[u8], symbols: &[(&str, u32)]) -> Vec<u8> {
+    let mut strtab: Vec<u8> = vec![0];
+    let mut symtab: Vec<u8> = vec![0; 16]; // null symbol
+    for (name, value) in symbols {
+        symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+        symtab.extend_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&0u32.to_le_bytes());
+        symtab.push(0x12); // GLOBAL FUNC
+        symtab.push(0);
+        symtab.extend_from_slice(&1u16.to_le_bytes());
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let code_offset: u32 = 52 + 32;
+    let symtab_offset: u32 = code_offset + code.len() as u32;
+    let strtab_offset: u32 = symtab_offset + symtab.len() as u32;
+    let sh_offset: u32 = strtab_offset + strtab.len() as u32;
+
+    let mut elf: Vec<u8> = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    elf.extend_from_slice(&2u16.to_le_bytes());   // e_type EXEC
+    elf.extend_from_slice(&105u16.to_le_bytes()); // e_machine MSP430
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&load_address.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&52u32.to_le_bytes());  // e_phoff
+    elf.extend_from_slice(&sh_offset.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());   // e_flags
+    for v in [52u16, 32, 1, 40, 3, 0] { // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in [1u32, code_offset, load_address, load_address, code.len() as u32, code.len() as u32, 5, 2] {
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    elf.extend_from_slice(code);
+    elf.extend_from_slice(&symtab);
+    elf.extend_from_slice(&strtab);
+    elf.extend_from_slice(&[0; 40]);
+    for v in [0u32, 2, 0, 0, symtab_offset, symtab.len() as u32, 2, 0, 2, 16] {
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in [0u32, 3, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0] {
+        elf.extend_from_slice(&v.to_le_bytes());
+    }
+    return elf;
+}
+
+#[test]
+fn elf_loading() {
+    // mov #0x1234 r5 ; little-endian words, as msp430-elf-gcc emits them
+    let code: [u8; 4] = [0x35, 0x40, 0x34, 0x12];
+    let elf = build_elf(0xc000, &code, &[("main", 0xc000), ("test_thing", 0xc002)]);
+    let image = elf::parse_elf(&elf).expect("ELF should parse");
+
+    assert_eq!(Some(0xc000), image.entry);
+    assert_eq!(Some(0xc002), image.symbol("test_thing").map(|s| s.address));
+
+    let c: &mut Computer = &mut Computer::new();
+    image.load(c);
+    assert_eq!(0x4035, c.memory.get_word(0xc000), "Words are converted to emulator order");
+    c.step();
+    assert_eq!(0x1234, c.get_register(5).get_word());
+
+    assert!(elf::parse_elf(&[0x7f, b'E', b'L', b'F']).is_err(), "Truncated files are rejected");
+}
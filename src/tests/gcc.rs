@@ -0,0 +1,95 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// End-to-end tests of compiled C (`cargo test --features gcc-tests`): every program in
+// src/tests/c is built with msp430-elf-gcc, loaded through the ELF loader and run until it
+// reports a result, then its UART output is compared with the program's `// expect:` line.
+// Skipped when the compiler isn't installed.
+
+use super::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::elf;
+use crate::devices::firmware_test::TestStatus;
+
+const COMPILER: &str = "msp430-elf-gcc";
+const SOURCES: &str = "src/tests/c";
+const MAX_STEPS: u64 = 2_000_000;
+
+fn compiler_available() -> bool {
+    return Command::new(COMPILER).arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status()
+        .is_ok_and(|status| status.success());
+}
+
+fn compile(source: &Path, output: &Path) -> Result<Vec<u8>, String> {
+    let result = Command::new(COMPILER)
+        .args(["-mmcu=msp430g2553", "-Os", "-std=c99", "-Wall", "-Werror", "-I", SOURCES, "-o"])
+        .arg(output).arg(source)
+        .output().map_err(|e| format!("Failed to run {}: {}", COMPILER, e))?;
+    if !result.status.success() {
+        return Err(String::from_utf8_lossy(&result.stderr).into_owned());
+    }
+    return fs::read(output).map_err(|e| e.to_string());
+}
+
+/// UART output of the program, once it reports a result
+fn run(elf: &[u8]) -> Result<String, String> {
+    let image: ProgramImage = elf::parse_elf(elf)?;
+    let c: &mut Computer = &mut Computer::new();
+    c.reset();
+    image.load(c);
+    for _ in 0..MAX_STEPS {
+        c.step();
+        if let Some(fault) = c.fault {
+            return Err(format!("halted: {}", fault));
+        }
+        if c.devices.firmware_test.status() != TestStatus::Running {
+            return Ok(String::from_utf8_lossy(&c.devices.uart.take_tx()).into_owned());
+        }
+    }
+    return Err(format!("no result after {} instructions", MAX_STEPS));
+}
+
+#[test]
+fn compiled_programs() {
+    if !compiler_available() {
+        println!("{} not found, skipping", COMPILER);
+        return;
+    }
+    let build: PathBuf = PathBuf::from("target/gcc-tests");
+    fs::create_dir_all(&build).unwrap();
+    let mut sources: Vec<PathBuf> = fs::read_dir(SOURCES).unwrap().flatten().map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "c")).collect();
+    sources.sort();
+    assert!(!sources.is_empty());
+    let mut failures: Vec<String> = vec![];
+    for source in &sources {
+        let name: String = source.file_stem().unwrap().to_string_lossy().into_owned();
+        let text: String = fs::read_to_string(source).unwrap();
+        let expected: &str = text.lines().find_map(|line| line.strip_prefix("// expect:"))
+            .unwrap_or_else(|| panic!("{} has no `// expect:` line", name)).trim();
+        let result: Result<String, String> = compile(source, &build.join(format!("{}.elf", name))).and_then(|elf| run(&elf));
+        match result {
+            Ok(output) if output == expected => println!("{}: ok", name),
+            Ok(output) => failures.push(format!("{}: printed '{}', expected '{}'", name, output, expected)),
+            Err(e) => failures.push(format!("{}: {}", name, e)),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
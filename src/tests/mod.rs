@@ -16,7 +16,11 @@
  *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+// tests pass `&trimmed` and compare flags against literal bools for readability
+#![allow(clippy::needless_borrow, clippy::bool_assert_comparison)]
+
 use super::*;
+use image::{ImageSpec, ProgramImage};
 use utils::{assemble, execute, encode_2complement, decode_2complement, wrap_2complement, execute_nr_nd};
 
 #[test]
@@ -33,6 +37,308 @@ fn decode_enum() {
     assert_eq!(DoubleOperandOpcodes::try_from(0u8), Ok(DoubleOperandOpcodes::MOV));
 }
 
+#[test]
+fn extended_memory_is_sparse() {
+    let memory: &mut MemoryMap = &mut MemoryMap::new();
+    memory.set_word(0x4400, 0x1234);
+    assert_eq!(0x1234, memory.get_word_20(0x04400), "Low 64K is shared with 16-bit access");
+    assert_eq!(0, memory.extended_allocated());
+
+    assert_eq!(0, memory.get_word_20(0x8_0000), "Untouched memory reads 0");
+    assert_eq!(0, memory.extended_allocated(), "Reading doesn't allocate");
+
+    memory.set_word_20(0x1_0fff, 0xbeef); // straddles two pages
+    memory.set_byte_20(0xf_ffff, 0x42);
+    assert_eq!(0xbeef, memory.get_word_20(0x1_0fff));
+    assert_eq!(0x42, memory.get_byte_20(0xf_ffff));
+    assert_eq!(0x42, memory.get_byte_20(0x1f_ffff), "Addresses wrap at 20 bits");
+    assert_eq!(3 * PAGE_SIZE, memory.extended_allocated());
+
+    memory.reset();
+    assert_eq!(0, memory.extended_allocated());
+    assert_eq!(0, memory.get_word_20(0x1_0fff));
+}
+
+#[test]
+fn fork_is_copy_on_write() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x1234 &0x0200
+add #1 &0x0200
+add #1 &0x0200
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 1);
+
+    let fork: &mut Computer = &mut c.fork();
+    assert_eq!(LOW_PAGES, fork.memory.shared_pages(&c.memory), "Nothing copied yet");
+
+    fork.step();
+    fork.step();
+    assert_eq!(0x1236, fork.memory.get_word(0x0200));
+    assert_eq!(0x1234, c.memory.get_word(0x0200), "Original is untouched");
+    assert_eq!(LOW_PAGES - 1, fork.memory.shared_pages(&c.memory), "Only the written page was copied");
+
+    c.step();
+    assert_eq!(0x1235, c.memory.get_word(0x0200));
+    assert_eq!((0x440a, 0x440e), (c.pc.get_word(), fork.pc.get_word()), "Registers are independent");
+}
+
+#[test]
+fn memory_changes_since() {
+    let c: &mut Computer = &mut Computer::new();
+    c.memory.set_word(0x0200, 0x1234);
+    let earlier = c.memory.clone();
+    assert_eq!(Vec::<(u16, u8, u8)>::new(), c.memory.changes_since(&earlier));
+
+    c.memory.set_word(0x0200, 0x1299);
+    c.memory.set_byte(0x8001, 0x7f);
+    c.memory.set_byte(0x8002, 0x00); // unchanged value in a copied page
+    assert_eq!(vec![(0x0201, 0x34, 0x99), (0x8001, 0x00, 0x7f)], c.memory.changes_since(&earlier));
+}
+
+#[test]
+fn overlay_keeps_state() {
+    let c: &mut Computer = &mut Computer::new();
+    execute(c, "RABDAw==", 0); // nop at 0x4400, raw format
+    c.get_register(5).set_word(7);
+    c.pc.set_word(0x4402);
+    c.memory.set_word(0x0202, 0x1234);
+
+    let image = ProgramImage::parse(&[0xff, 0xff, 0x00, 0x01, 0x02, 0x00, 0x00, 0x02, 0xbe, 0xef]).unwrap();
+    image.overlay(c);
+    assert_eq!(0xbeef, c.memory.get_word(0x0200), "Segment applied");
+    assert_eq!(0x1234, c.memory.get_word(0x0202), "Memory around it kept");
+    assert_eq!(0x4303, c.memory.get_word(0x4400), "Earlier program kept");
+    assert_eq!(7, c.get_register(5).get_word());
+    assert_eq!(0x4402, c.pc.get_word(), "PC untouched");
+
+    let raw = ProgramImage::parse(&[0x44, 0x00, 0x43, 0x03]).unwrap();
+    assert_eq!(vec![0x4400, 0xfffe], raw.segments.iter().map(|s| s.address).collect::<Vec<u16>>(),
+               "Raw format converted, with its reset vector");
+    assert!(ProgramImage::parse(&[0x44]).is_err());
+}
+
+#[test]
+fn multi_image_load() {
+    let dir = std::env::temp_dir();
+    let blob = dir.join(format!("msp430_blob_test_{}.bin", std::process::id()));
+    let app = dir.join(format!("msp430_app_test_{}.bin", std::process::id()));
+    std::fs::write(&blob, [0x12, 0x34, 0x56]).unwrap();
+    std::fs::write(&app, [0x44, 0x10, 0x43, 0x03]).unwrap(); // raw format, nop at 0x4410
+    let bsl: ImageSpec = format!("{}@0x1000", blob.to_str().unwrap()).parse().unwrap();
+    let cal: ImageSpec = format!("{}@0x1001", blob.to_str().unwrap()).parse().unwrap();
+    let application: ImageSpec = app.to_str().unwrap().parse().unwrap();
+    assert_eq!(None, application.address);
+    assert_eq!(Some(0x1001), cal.address);
+    assert_eq!("a@b@0x1000", "a@b@4096".parse::<ImageSpec>().unwrap().to_string());
+    assert_eq!(None, "user@host.bin".parse::<ImageSpec>().unwrap().address);
+
+    let images: Vec<ProgramImage> = [bsl, application, cal].iter().map(|spec| spec.read().unwrap()).collect();
+    std::fs::remove_file(&blob).unwrap();
+    std::fs::remove_file(&app).unwrap();
+    let c: &mut Computer = &mut Computer::new();
+    ProgramImage::merge(images).load(c);
+    assert_eq!(0x1212, c.memory.get_word(0x1000), "Later images overwrite earlier ones");
+    assert_eq!(0x3456, c.memory.get_word(0x1002));
+    assert_eq!(0x4303, c.memory.get_word(0x4410));
+    assert_eq!(0x4410, c.pc.get_word(), "Entry from the application's reset vector");
+    assert!("missing.bin@0xffff".parse::<ImageSpec>().unwrap().read().is_err());
+}
+
+#[test]
+fn segmented_writer() {
+    let mut image = ProgramImage::parse(&[0x44, 0x00, 0x43, 0x03]).unwrap();
+    image.entry = Some(0x4400);
+    image.segments.push(image::Segment { address: 0x0000, data: vec![0xaa; 0x10000] });
+    let packed: Vec<u8> = image.to_segmented().unwrap();
+    assert_eq!([0xff, 0xff, 0x00, 0x05, 0x44, 0x00, 0x00, 0x02, 0x43, 0x03], packed[..10], "Marker, count, first segment");
+
+    let parsed = ProgramImage::from_segmented(&packed).unwrap();
+    assert_eq!(vec![(0x4400, 2), (0xfffe, 2), (0x0000, 0xfffe), (0xfffe, 2), (0xfffe, 2)],
+               parsed.segments.iter().map(|s| (s.address, s.data.len())).collect::<Vec<(u16, usize)>>(),
+               "Oversized segment split, entry appended as the reset vector");
+    let c: &mut Computer = &mut Computer::new();
+    parsed.load(c);
+    assert_eq!(0x4400, c.pc.get_word());
+    assert_eq!(0xaaaa, c.memory.get_word(0x4400), "Segments keep their order");
+
+    image.segments.push(image::Segment { address: 0xffff, data: vec![0, 0] });
+    assert!(image.to_segmented().is_err(), "Past the end of memory");
+}
+
+#[test]
+fn v2_image_format() {
+    let mut image = ProgramImage::parse(&[0x44, 0x00, 0x43, 0x03]).unwrap();
+    image.entry = Some(0x4400);
+    image.metadata = Some(image::Metadata { name: "blinky".to_string(), build_id: vec![0xde, 0xad] });
+    let packed: Vec<u8> = image.to_v2().unwrap();
+    assert_eq!(b"MSPI\x02\x03\x44\x00", &packed[..8], "Magic, version, flags, entry");
+
+    let parsed = ProgramImage::parse(&packed).unwrap();
+    assert_eq!(image.segments, parsed.segments);
+    assert_eq!(Some(0x4400), parsed.entry, "Entry record, independent of the vector table");
+    assert_eq!("blinky (build dead)", parsed.metadata.unwrap().to_string());
+
+    let mut corrupt: Vec<u8> = packed.clone();
+    corrupt[28] ^= 0x01; // first data byte of the first segment
+    assert!(ProgramImage::parse(&corrupt).unwrap_err().starts_with("Segment 0 at 0x4400 is corrupt"));
+    let mut corrupt: Vec<u8> = packed.clone();
+    corrupt[6] ^= 0x01; // entry point
+    assert!(ProgramImage::parse(&corrupt).unwrap_err().starts_with("Image is corrupt"));
+    let mut future: Vec<u8> = packed.clone();
+    future[4] = 3;
+    assert_eq!(Err("Unsupported image version 3".to_string()), ProgramImage::parse(&future).map(|_| ()));
+    assert!(ProgramImage::parse(&packed[..packed.len() - 1]).is_err(), "Truncated");
+    assert_eq!(0xcbf43926, utils::crc32(b"123456789"));
+}
+
+#[test]
+fn image_format_conversion() {
+    use crate::formats::{self, Format};
+    let image = formats::read(Format::Raw, &[0x44, 0x00, 0x43, 0x03, 0x12, 0x34]).unwrap();
+    let ihex: Vec<u8> = formats::write(Format::Ihex, &image).unwrap();
+    assert!(String::from_utf8_lossy(&ihex).starts_with(":0444000003433412"), "Device byte order");
+
+    for format in [Format::Raw, Format::Segmented, Format::V2, Format::Ihex, Format::TiTxt, Format::Srec] {
+        let written: Vec<u8> = formats::write(format, &image).unwrap();
+        assert_eq!(format, formats::detect(&written));
+        let read = formats::read(format, &written).unwrap();
+        let c: &mut Computer = &mut Computer::new();
+        read.load(c);
+        assert_eq!(0x4303, c.memory.get_word(0x4400), "{:?}", format);
+        assert_eq!(0x1234, c.memory.get_word(0x4402), "{:?}", format);
+        assert_eq!(0x4400, c.pc.get_word(), "{:?}", format);
+    }
+    assert_eq!(vec![0x03, 0x43, 0x34, 0x12, 0xff, 0xff, 0x01, 0x00], formats::write(Format::Binary, &ProgramImage::parse(
+        &[0xff, 0xff, 0x00, 0x02, 0x44, 0x00, 0x00, 0x04, 0x43, 0x03, 0x12, 0x34, 0x44, 0x06, 0x00, 0x02, 0x00, 0x01]).unwrap()).unwrap(),
+        "Gaps filled");
+
+    let records = "@4400\n03 43\n34\n@4403\n12\nq\n";
+    let titxt = formats::read(Format::TiTxt, records.as_bytes()).unwrap();
+    assert_eq!(1, titxt.segments.len(), "Contiguous records joined");
+    assert_eq!(vec![0x43, 0x03, 0x12, 0x34], titxt.segments[0].data, "A word split across lines still swapped");
+    assert!(formats::read(Format::Ihex, b":0444000003433412FF\n").unwrap_err().contains("checksum"));
+    assert!(formats::read(Format::Srec, b"S1074400034334120F\n").unwrap_err().contains("checksum"));
+    assert!(formats::write(Format::Raw, &titxt).is_err(), "Needs an entry point");
+}
+
+#[test]
+fn assembler_macros() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+.macro delay count, reg=r15
+    mov #\\count \\reg
+wait\\@:
+    dec \\reg
+    jnz wait\\@ ; local label, unique per expansion
+.endm
+.macro twice count
+    delay \\count
+    delay \\count, r14
+.endm
+mov #0x4400 sp
+twice 3
+add #1 r13
+delay 2, r12
+add #1 r13
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 1 + 7 + 7 + 1 + 5 + 1);
+    assert_eq!(2, c.get_register(13).get_word(), "Ran through every expansion");
+    assert_eq!(0, c.get_register(15).get_word());
+    assert_eq!(0, c.get_register(14).get_word());
+    assert_eq!(0, c.get_register(12).get_word());
+
+    assert!(macros::expand(".macro m a\nmov \\a r5\n").unwrap_err().contains("missing .endm"));
+    assert!(macros::expand(".macro m a\n.endm\nm").unwrap_err().contains("missing argument 'a'"));
+    assert!(macros::expand(".macro m\nm\n.endm\nm").unwrap_err().contains("nested"));
+    assert_eq!("mov #1 r5 ; plain\n", macros::expand("mov #1 r5 ; plain").unwrap());
+}
+
+#[test]
+fn relocatable_objects() {
+    let main = object::Object::assemble("
+.extern helper, value
+.global main
+main:
+    mov #0x4400 sp
+    call #helper
+    mov value r7 ; symbolic, relative to the PC
+    mov #value r8
+    mov #here r9
+here:
+    jmp here
+").unwrap();
+    let helper = object::Object::assemble("
+.section .lib
+.global helper, value
+helper:
+    mov #7 r5
+    ret
+value:
+    mov #0x1234 r4
+").unwrap();
+    assert_eq!(".lib", helper.section);
+    assert_eq!(main, object::Object::from_json(&main.to_json()).unwrap());
+
+    let script = linker::LinkerScript::parse("
+/* library code goes high */
+ENTRY(main)
+SECTIONS {
+    .text 0x4400 : { *(.text) }
+    .lib 0xc000 : { *(.lib) }
+}").unwrap();
+    let objects: Vec<(String, object::Object)> = vec![("out/main.o".to_string(), main.clone()), ("helper.o".to_string(), helper.clone())];
+    let image: ProgramImage = linker::link(&objects, &script).unwrap();
+    assert_eq!(Some(0xc006), image.symbol("value").map(|s| s.address));
+    let c: &mut Computer = &mut Computer::new();
+    image.load(c);
+    assert_eq!(0x4400, c.pc.get_word(), "Entry symbol");
+    for _ in 0..7 {
+        c.step();
+    }
+    assert_eq!(7, c.get_register(5).get_word(), "Called into the other object");
+    assert_eq!(0x4034, c.get_register(7).get_word(), "Relative reference reaches the moved symbol");
+    assert_eq!(0xc006, c.get_register(8).get_word());
+    assert_eq!(0x4414, c.get_register(9).get_word(), "Local label");
+
+    let swapped = linker::LinkerScript::parse("SECTIONS { .all 0x8000 : { helper.o main.o(.text) } }").unwrap();
+    let image: ProgramImage = linker::link(&objects, &swapped).unwrap();
+    assert_eq!(Some(0x800a), image.symbol("main").map(|s| s.address), "Placed after helper.o");
+    assert_eq!(Some(0x800a), image.reset_vector(), "Reset vector of the first object, relocated");
+
+    assert!(linker::link(&objects[..1], &linker::LinkerScript::default()).unwrap_err().contains("Undefined symbol 'helper'"));
+    assert!(linker::link(&objects, &linker::LinkerScript::parse("SECTIONS { .text 0x4400 : { *(.text) } }").unwrap())
+        .unwrap_err().contains("not placed"));
+}
+
+#[test]
+fn decode_table() {
+    use decode::{decode, Decoded};
+    for instruction in 0..=0xffffu16 {
+        let expected: Decoded = if instruction >> 10 == 4 {
+            Decoded::Single { opcode: ((instruction >> 7) & 7) as u8, reg: (instruction & 0xf) as u8,
+                              as_: ((instruction >> 4) & 3) as u8, bw: (instruction >> 6) & 1 == 1 }
+        } else if instruction >> 13 == 1 {
+            let offset: i16 = (instruction & 0x3ff) as i16;
+            Decoded::Jump { condition: ((instruction >> 10) & 7) as u8, offset: if offset >= 512 {offset - 1024} else {offset} * 2 }
+        } else if instruction >> 12 >= 4 {
+            Decoded::Double { opcode: (instruction >> 12) as u8 - 4, src_reg: ((instruction >> 8) & 0xf) as u8,
+                              as_: ((instruction >> 4) & 3) as u8, ad: ((instruction >> 7) & 1) as u8,
+                              bw: (instruction >> 6) & 1 == 1, dst_reg: (instruction & 0xf) as u8 }
+        } else {
+            Decoded::None
+        };
+        assert_eq!(expected, decode(instruction), "{:#06x}", instruction);
+    }
+    assert_eq!(Decoded::Jump { condition: 0, offset: -1024 }, decode(0x2200), "Offset field 0x200 is the farthest back");
+    assert_eq!(Decoded::Jump { condition: 0, offset: 1022 }, decode(0x21ff), "0x1ff the farthest forward");
+    assert_eq!(0x2200, decode::encode(Decoded::Jump { condition: 0, offset: -1024 }));
+}
+
 #[test]
 fn mov_and_arg_modes() {
     let c: &mut Computer = &mut Computer::new();
@@ -88,6 +394,47 @@ mov &0xc0de r5
     assert_eq!(0xf00d, c.get_register(5).get_word(), "Absolute as source");
 }
 
+#[test]
+fn indexed_destination_off_cg() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0xbeef 0x0200(r3) ; no constants as destinations, r3 indexes from 0
+add #0x1111 0x0200(r3)
+mov #1 r5
+");
+    execute(c, assembled.trim(), 3);
+
+    assert_eq!(0xd000, c.memory.get_word(0x0200), "Indexed off r3 as target");
+    assert_eq!(1, c.get_register(5).get_word(), "Both offset words skipped");
+}
+
+#[test]
+fn destination_addressing_modes() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp
+mov #0x0210 r4
+setz ; SR's contents don't matter for absolute destinations
+mov #0x1234 &0x0200
+mov.b #0xab &0x0203
+mov &0x0200 0xc0de ; symbolic destination after an absolute source
+mov #0x5678 -2(r4)
+mov #0x9abc 2(sp)
+mov #0x00ff r3 ; writes to CG are dropped
+mov r3 r6
+mov.b #0x103 sr
+");
+    execute(c, assembled.trim(), 11);
+
+    assert_eq!(0x1234, c.memory.get_word(0x0200), "Absolute");
+    assert_eq!(0xab, c.memory.get_byte(0x0203), "Absolute byte");
+    assert_eq!(0x1234, c.memory.get_word(0xc0de), "Symbolic");
+    assert_eq!(0x5678, c.memory.get_word(0x020e), "Indexed, negative offset");
+    assert_eq!(0x9abc, c.memory.get_word(0x4402), "Indexed off SP");
+    assert_eq!(0, c.get_register(6).get_word(), "Register CG");
+    assert_eq!(0x0003, c.get_register(2).get_word(), "Register SR, byte writes clear the high byte");
+}
+
 #[test]
 fn symbolic_arg_mode() {
     let c: &mut Computer = &mut Computer::new();
@@ -163,6 +510,23 @@ pop sr  ; restore flags
     assert_eq!(false, c.sr.get_status(StatusFlags::OVERFLOW), "Flag: V");
 }
 
+#[test]
+fn flag_helpers() {
+    let c: &mut Computer = &mut Computer::new();
+    c.set_flag(StatusFlags::CARRY | StatusFlags::ZERO, true);
+    c.set_flag(StatusFlags::OVERFLOW, true);
+    c.set_flag(StatusFlags::ZERO, false);
+    assert_eq!(0x0101, c.sr.get_word());
+    assert!(c.flag(StatusFlags::CARRY));
+    assert!(!c.flag(StatusFlags::CARRY | StatusFlags::ZERO), "All of several flags");
+    let flags: Flags = c.flags();
+    assert_eq!(Flags { carry: true, overflow: true, ..Flags::default() }, flags);
+    assert_eq!("V n z C gie cpuoff oscoff scg0 scg1", flags.to_string());
+    assert_eq!(Flags { gie: true, cpuoff: true, scg1: true, ..Flags::default() }, Flags::from_sr(0x0098));
+    assert_eq!((Some(2), Some(4), None), (Flags::from_sr(0x0098).low_power_mode(), Flags::from_sr(0x00f0).low_power_mode(),
+                                          Flags::from_sr(0x00e0).low_power_mode()));
+}
+
 #[test]
 fn sub_manual() {
     let c: &mut Computer = &mut Computer::new();
@@ -251,6 +615,67 @@ and r5 r6
     assert_eq!(0x0f00, c.get_register(6).get_word());
 }
 
+#[test]
+fn dadd() { // decimal add
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x1999 r5
+mov #0x0001 r6
+clrc
+dadd r5 r6
+
+mov #0x9999 r7
+mov #0x0001 r8
+setc
+dadd r7 r8
+
+mov #0x0099 r9
+mov #0x0001 r10
+clrc
+dadd.b r9 r10
+");
+    let trimmed = assembled.trim();
+    println!("'{}'", trimmed);
+    execute(c, &trimmed, 4);
+    assert_eq!(0x2000, c.get_register(6).get_word(), "Carries across digits");
+    assert_eq!(false, c.sr.get_status(StatusFlags::CARRY), "Flags: C");
+
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!(0x0001, c.get_register(8).get_word(), "9999 + 1 + carry in");
+    assert_eq!(true, c.sr.get_status(StatusFlags::CARRY), "Flags: C");
+    assert_eq!(false, c.sr.get_status(StatusFlags::ZERO), "Flags: Z");
+
+    for _ in 0..4 {
+        c.step();
+    }
+    assert_eq!(0x0000, c.get_register(10).get_word(), "Byte mode, 99 + 1");
+    assert_eq!(true, c.sr.get_status(StatusFlags::CARRY), "Flags: C");
+    assert_eq!(true, c.sr.get_status(StatusFlags::ZERO), "Flags: Z");
+}
+
+#[test]
+fn wraps_at_the_end_of_memory() { // PC and SP arithmetic wraps around instead of overflowing
+    let c: &mut Computer = &mut Computer::new();
+    c.memory.set_word(0xfffe, 0x4305); // mov #0 r5
+    c.pc.set_word(0xfffe);
+    c.step();
+    assert_eq!(0x0000, c.pc.get_word(), "Fetching from 0xfffe");
+
+    c.memory.set_word(0x4400, 0x1300); // reti
+    c.pc.set_word(0x4400);
+    c.sp.set_word(0xfffe);
+    c.step();
+    assert_eq!(0x0002, c.sp.get_word(), "RETI popped SR from 0xfffe and the PC from 0x0000");
+
+    c.memory.set_word(0x4400, 0x1205); // push r5
+    c.pc.set_word(0x4400);
+    c.sp.set_word(0x0000);
+    c.step();
+    assert_eq!(0xfffe, c.sp.get_word(), "Pushing below 0x0000");
+}
+
 #[test]
 fn rrc_rra() {
     let c: &mut Computer = &mut Computer::new();
@@ -364,6 +789,47 @@ reti
     assert_eq!(3, c.get_register(5).get_word(), "Post-interrupt code operates properly");
 }
 
+#[test]
+fn instruction_cycles() {
+    let c: &mut Computer = &mut Computer::new();
+    let assembled = assemble("
+mov #0x4400 sp ; 2
+mov r4 r5 ; 1
+add @r5+ 0(r6) ; 5
+mov #1 r7 ; 1, constant generator
+push &0x0200 ; 5
+call #target ; 5
+jmp 0 ; 2
+
+target:
+clr 2(r4) ; 4
+ret ; 3
+
+handler:
+reti ; 5
+
+.interrupt 0xffa0 handler
+");
+    let trimmed = assembled.trim();
+    let expected: [u64; 8] = [2, 1, 5, 1, 5, 5, 4, 3];
+    execute(c, &trimmed, 0);
+    c.sr.set_status(StatusFlags::GIE, true);
+    for (i, cycles) in expected.iter().enumerate() {
+        let before = c.clock.cycles();
+        c.step();
+        assert_eq!(*cycles, c.clock.cycles() - before, "instruction {}", i);
+    }
+    let before = c.clock.cycles();
+    c.step();
+    assert_eq!(2, c.clock.cycles() - before, "jumps take 2 cycles");
+
+    let before = c.clock.cycles();
+    c.interrupt(0xffa0);
+    assert_eq!(timing::INTERRUPT_CYCLES, c.clock.cycles() - before, "accepting an interrupt");
+    c.step();
+    assert_eq!(timing::INTERRUPT_CYCLES + 5, c.clock.cycles() - before, "reti");
+}
+
 #[test]
 fn jc_jhs() { // jump if carry is set
     let c: &mut Computer = &mut Computer::new();
@@ -753,3 +1219,9 @@ fn subc_on_fuzz() {
         }
     }
 }
+
+mod devices;
+mod firmware_tests;
+mod debugging;
+#[cfg(feature = "gcc-tests")]
+mod gcc;
@@ -0,0 +1,84 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::decode::Decoded;
+
+/// Accepting an interrupt: pushing PC and SR and loading the vector
+pub(crate) const INTERRUPT_CYCLES: u64 = 6;
+
+const PUSH_OPCODE: u8 = 4;
+const CALL_OPCODE: u8 = 5;
+const RETI_OPCODE: u8 = 6;
+
+/// Operand addressing as the cycle tables tell them apart
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operand {
+    Register,
+    Indirect,
+    Autoincrement,
+    Immediate,
+    /// x(Rn), EDE and &EDE
+    Indexed,
+}
+
+impl Operand {
+    fn of(reg: u8, as_: u8) -> Operand {
+        // constants from the generators (r3, and r2 in the indirect modes) cost nothing to fetch
+        if reg == 3 || (reg == 2 && as_ >= 2) {
+            return Operand::Register;
+        }
+        return match as_ {
+            0 => Operand::Register,
+            1 => Operand::Indexed,
+            2 => Operand::Indirect,
+            _ if reg == 0 => Operand::Immediate,
+            _ => Operand::Autoincrement,
+        };
+    }
+
+    fn index(self) -> usize {
+        return self as usize;
+    }
+}
+
+/// CPU cycles an instruction takes on the MSP430 (not MSP430X) CPU, from the format I and II tables
+/// of the 2xx family user's guide. Jumps take 2 whether taken or not.
+pub(crate) fn cycles(decoded: Decoded) -> u64 {
+    return match decoded {
+        Decoded::None => 1,
+        Decoded::Jump { .. } => 2,
+        Decoded::Single { opcode: RETI_OPCODE, .. } => 5,
+        Decoded::Single { opcode, reg, as_, .. } => {
+            // Rn, @Rn, @Rn+, #N, x(Rn); immediate RRA/RRC/SWPB/SXT are undefined, timed like @Rn+
+            let table: [u64; 5] = match opcode {
+                PUSH_OPCODE => [3, 4, 5, 4, 5],
+                CALL_OPCODE => [4, 4, 5, 5, 5],
+                _ => [1, 3, 3, 3, 4],
+            };
+            table[Operand::of(reg, as_).index()]
+        },
+        Decoded::Double { src_reg, as_, ad, dst_reg, .. } => {
+            let table: [u64; 5] = match (ad, dst_reg) {
+                (0, 0) => [2, 2, 3, 3, 3], // to the PC, e.g. BR and RET
+                (0, _) => [1, 2, 2, 2, 3],
+                _ => [4, 5, 5, 5, 6],
+            };
+            table[Operand::of(src_reg, as_).index()]
+        },
+    };
+}
@@ -0,0 +1,92 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::MemoryMap;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x00000100000001b3;
+
+/// Running FNV-1a hash of everything execution changes (`trace-hash`), so two builds of the
+/// emulator can be compared on the same firmware. Every memory and device write is hashed as it
+/// happens, the registers and cycle count after every `interval` instructions.
+#[derive(Clone)]
+pub(crate) struct TraceHasher {
+    interval: u64,
+    instructions: u64,
+    hash: u64,
+}
+
+impl TraceHasher {
+    pub(crate) fn new(interval: u64) -> TraceHasher {
+        return TraceHasher { interval: interval.max(1), instructions: 0, hash: FNV_OFFSET };
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = TraceHasher::new(self.interval);
+    }
+
+    #[inline]
+    fn mix(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn write(&mut self, address: u16, value: u16, byte: bool) {
+        self.mix(&address.to_be_bytes());
+        self.mix(&value.to_be_bytes());
+        self.mix(&[byte as u8]);
+    }
+
+    /// After an instruction ran, `registers` are r0 - r15
+    #[inline]
+    pub(crate) fn retire(&mut self, registers: &[u16; 16], cycles: u64) {
+        self.instructions += 1;
+        if self.instructions.is_multiple_of(self.interval) {
+            self.mix_state(registers, cycles);
+        }
+    }
+
+    fn mix_state(&mut self, registers: &[u16; 16], cycles: u64) {
+        for register in registers {
+            self.mix(&register.to_be_bytes());
+        }
+        self.mix(&cycles.to_be_bytes());
+    }
+
+    pub(crate) fn instructions(&self) -> u64 {
+        return self.instructions;
+    }
+
+    /// The hash so far, with the final registers and the whole 64K of memory folded in
+    /// The hash so far, without the final registers and memory
+    pub(crate) fn running(&self) -> u64 {
+        return self.hash;
+    }
+
+    pub(crate) fn digest(&self, registers: &[u16; 16], cycles: u64, memory: &MemoryMap) -> u64 {
+        let mut hasher: TraceHasher = self.clone();
+        hasher.mix_state(registers, cycles);
+        for address in 0..=0xffffu16 {
+            hasher.mix(&[memory.get_byte(address)]);
+        }
+        return hasher.hash;
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, BufWriter, Write};
+
+/// Writes every byte firmware transmits on the UART as a text line, the cycle its write started
+/// at then the byte in hex, and the character when printable: `1502 48 H`. Lines are meant to be
+/// compared with a golden file, so a test catches output that comes too early or late as well as
+/// wrong output.
+pub(crate) struct UartCapture {
+    out: BufWriter<Box<dyn Write>>,
+}
+
+impl UartCapture {
+    pub(crate) fn new(out: Box<dyn Write>) -> UartCapture {
+        return UartCapture { out: BufWriter::new(out) };
+    }
+
+    pub(crate) fn create(path: &str) -> io::Result<UartCapture> {
+        return Ok(UartCapture::new(Box::new(std::fs::File::create(path)?)));
+    }
+
+    pub(crate) fn record(&mut self, cycle: u64, byte: u8) -> io::Result<()> {
+        if byte.is_ascii_graphic() {
+            return writeln!(self.out, "{} {:02x} {}", cycle, byte, byte as char);
+        }
+        return writeln!(self.out, "{} {:02x}", cycle, byte);
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+}
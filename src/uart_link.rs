@@ -0,0 +1,73 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::devices::uart::UartDevice;
+
+/// Wire two UARTs in the same process together, TX of each to RX of the other
+#[allow(dead_code)]
+pub(crate) fn cross_connect(a: &mut UartDevice, b: &mut UartDevice) {
+    let from_a: Vec<u8> = a.take_tx();
+    let from_b: Vec<u8> = b.take_tx();
+    b.receive(&from_a);
+    a.receive(&from_b);
+}
+
+/// Connects a UART to a UART in another emulator instance over TCP
+pub(crate) struct TcpUartLink {
+    stream: TcpStream,
+}
+
+impl TcpUartLink {
+    /// Wait for the other instance to connect
+    pub(crate) fn listen(address: &str) -> io::Result<TcpUartLink> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        return TcpUartLink::new(stream);
+    }
+
+    pub(crate) fn connect(address: &str) -> io::Result<TcpUartLink> {
+        return TcpUartLink::new(TcpStream::connect(address)?);
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpUartLink> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        return Ok(TcpUartLink { stream });
+    }
+
+    /// Send anything the UART transmitted and deliver anything the peer sent, without blocking
+    pub(crate) fn pump(&mut self, uart: &mut UartDevice) -> io::Result<()> {
+        if uart.has_tx() {
+            self.stream.set_nonblocking(false)?;
+            let result = self.stream.write_all(&uart.take_tx());
+            self.stream.set_nonblocking(true)?;
+            result?;
+        }
+        let mut buf: [u8; 256] = [0; 256];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "UART peer disconnected")),
+                Ok(n) => uart.receive(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
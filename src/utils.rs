@@ -44,16 +44,49 @@ pub(crate) fn wrap_2complement(v: i32) -> i32 {
     return decode_2complement(encode_2complement(v));
 }
 
+/// Parse a decimal or `0x`-prefixed hexadecimal 16-bit value (for CLI arguments)
+pub(crate) fn parse_u16(text: &str) -> Result<u16, String> {
+    let text: &str = text.trim();
+    let parsed = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse::<u16>(),
+    };
+    return parsed.map_err(|e| format!("'{}' is not a 16-bit value: {}", text, e));
+}
+
+/// Same for a byte
+pub(crate) fn parse_u8(text: &str) -> Result<u8, String> {
+    let value: u16 = parse_u16(text)?;
+    return u8::try_from(value).map_err(|_| format!("'{}' is not a byte", text.trim()));
+}
+
+/// CRC-32 (IEEE 802.3, as used by zip and PNG)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffffffff;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb88320} else {crc >> 1};
+        }
+    }
+    return !crc;
+}
+
 #[allow(dead_code)]
-pub(crate) fn assemble(code: &str) -> String {
+pub fn assemble(code: &str) -> String {
+    let expanded: String = match macros::expand(code) {
+        Ok(expanded) => expanded,
+        Err(e) => panic!("  Failed to expand macros in `{}`: {}  ", code, e),
+    };
     let mut child = Command::new("./tools/assembler")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
         .expect("Failed to run assembler");
-    child.stdin.take().unwrap().write_all(code.as_bytes()).expect("Failed to write code to assembler");
+    child.stdin.take().unwrap().write_all(expanded.as_bytes()).expect("Failed to write code to assembler");
     let mut buf: String = "".to_string();
     child.stdout.take().unwrap().read_to_string(&mut buf).expect("Failed to receive assembled bytes back");
+    child.wait().expect("Assembler did not exit");
 
     if buf.starts_with("<FAILURE>") {
         panic!("  Failed to assemble `{}`  ", code);
@@ -62,14 +95,34 @@ pub(crate) fn assemble(code: &str) -> String {
     return buf;
 }
 
+/// Assemble without panicking, for tools. Returns the segmented-format bytes, or the assembler's
+/// complaint. Macros are not expanded here.
+pub(crate) fn try_assemble(code: &str) -> Result<Vec<u8>, String> {
+    let mut child = Command::new("./tools/assembler")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run ./tools/assembler: {}", e))?;
+    child.stdin.take().unwrap().write_all(code.as_bytes()).map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).into_owned();
+    if stdout.contains("<FAILURE>") || stdout.trim().is_empty() {
+        let stderr: String = String::from_utf8_lossy(&output.stderr).into_owned();
+        let message: String = format!("{}{}", stdout.replace("<FAILURE>", ""), stderr);
+        return Err(message.trim().to_string());
+    }
+    return general_purpose::STANDARD.decode(stdout.trim()).map_err(|e| format!("Bad assembler output: {}", e));
+}
+
 #[allow(dead_code)]
-pub(crate) fn execute(computer: &mut Computer, data: &str, steps: u64) {
+pub fn execute(computer: &mut Computer, data: &str, steps: u64) {
     computer.reset();
     execute_nr(computer, data, steps);
 }
 
 #[allow(dead_code)]
-pub(crate) fn execute_nd(computer: &mut Computer, byte_data: &[u8], steps: u64) {
+pub fn execute_nd(computer: &mut Computer, byte_data: &[u8], steps: u64) {
     computer.reset();
     execute_nr_nd(computer, byte_data, steps);
 }
@@ -134,6 +187,14 @@ impl <'a>U8Stream<'a> {
     pub(crate) fn pop_word(self: &mut U8Stream<'a>) -> u16 {
         return ((self.pop_byte() as u16) << 8) + (self.pop_byte() as u16);
     }
+
+    pub(crate) fn pop_long(self: &mut U8Stream<'a>) -> u32 {
+        return ((self.pop_word() as u32) << 16) + (self.pop_word() as u32);
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        return self._data.len() - self._index;
+    }
 }
 
 pub(crate) fn convert_code_fmt(byte_data: &[u8]) -> Vec<u8> {
@@ -0,0 +1,81 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::*;
+use expr::Expr;
+use crate::notify::NOTIFICATION_IDS;
+
+/// A watch whose value changed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct WatchEvent {
+    pub(crate) id: u16,
+    pub(crate) old: u16,
+    pub(crate) new: u16,
+    /// PC after the step that changed it
+    pub(crate) pc: u16,
+}
+
+struct Watch {
+    id: u16,
+    expr: Expr,
+    last: u16,
+}
+
+/// Expressions registered by frontends, re-evaluated after every step
+pub(crate) struct WatchList {
+    watches: Vec<Watch>,
+    next_id: u16,
+}
+
+impl WatchList {
+    pub(crate) fn new() -> WatchList {
+        return WatchList { watches: Vec::new(), next_id: 0 };
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.watches.is_empty();
+    }
+
+    /// Start watching, changes are reported relative to the current value
+    pub(crate) fn add(&mut self, expr: Expr, computer: &Computer) -> u16 {
+        let id: u16 = self.next_id;
+        self.next_id = (self.next_id + 1) % NOTIFICATION_IDS; // ids above are notifications, 0xffff is the error reply over shmem
+        let last: u16 = expr.eval(computer);
+        self.watches.push(Watch { id, expr, last });
+        return id;
+    }
+
+    pub(crate) fn remove(&mut self, id: u16) -> bool {
+        let before: usize = self.watches.len();
+        self.watches.retain(|w| w.id != id);
+        return self.watches.len() != before;
+    }
+
+    pub(crate) fn check(&mut self, computer: &Computer) -> Vec<WatchEvent> {
+        let mut events: Vec<WatchEvent> = Vec::new();
+        for watch in self.watches.iter_mut() {
+            let value: u16 = watch.expr.eval(computer);
+            if value != watch.last {
+                events.push(WatchEvent { id: watch.id, old: watch.last, new: value, pc: computer.pc.get_word() });
+                watch.last = value;
+            }
+        }
+        return events;
+    }
+}
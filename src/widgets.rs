@@ -0,0 +1,261 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::sensors::cycles_for;
+
+/// Time between the quadrature steps of a turn, a detent takes four
+const STEP_US: u64 = 500;
+/// Contacts closed at each quadrature step from a detent, clockwise: A leads B
+const QUADRATURE: [(bool, bool); 4] = [(false, false), (true, false), (true, true), (false, true)];
+
+fn parse_pins(s: &str) -> Result<Vec<PinId>, String> {
+    return s.split(',').map(str::parse).collect();
+}
+
+fn join_pins(pins: &[PinId]) -> String {
+    return pins.iter().map(PinId::to_string).collect::<Vec<String>>().join(",");
+}
+
+/// `run --encoder A,B[,SWITCH]`, the pins of a rotary encoder's contacts
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct EncoderSpec {
+    pub(crate) a: PinId,
+    pub(crate) b: PinId,
+    /// push switch, if the encoder has one
+    pub(crate) switch: Option<PinId>,
+}
+
+impl FromStr for EncoderSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match parse_pins(s)?[..] {
+            [a, b] => Ok(EncoderSpec { a, b, switch: None }),
+            [a, b, switch] => Ok(EncoderSpec { a, b, switch: Some(switch) }),
+            _ => Err(format!("'{}' is not an encoder (expected A,B[,SWITCH], e.g. P1.4,P1.5)", s)),
+        };
+    }
+}
+
+impl fmt::Display for EncoderSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.switch {
+            Some(switch) => write!(f, "{},{},{}", self.a, self.b, switch),
+            None => write!(f, "{},{}", self.a, self.b),
+        };
+    }
+}
+
+/// `run --button-matrix ROWS:COLUMNS`, e.g. P1.0,P1.1,P1.2,P1.3:P2.0,P2.1,P2.2 for a 4×3 keypad
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct MatrixSpec {
+    pub(crate) rows: Vec<PinId>,
+    pub(crate) columns: Vec<PinId>,
+}
+
+impl FromStr for MatrixSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rows, columns) = s.split_once(':')
+            .ok_or(format!("'{}' is not a button matrix (expected ROWS:COLUMNS, e.g. P1.0,P1.1:P2.0,P2.1)", s))?;
+        return Ok(MatrixSpec { rows: parse_pins(rows)?, columns: parse_pins(columns)? });
+    }
+}
+
+impl fmt::Display for MatrixSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}:{}", join_pins(&self.rows), join_pins(&self.columns));
+    }
+}
+
+/// What a widget puts on one of its pins, only written to the port when it changes
+#[derive(Clone)]
+struct Contact {
+    pin: PinId,
+    applied: Option<bool>,
+}
+
+impl Contact {
+    fn new(pin: PinId) -> Contact {
+        return Contact { pin, applied: None };
+    }
+
+    fn apply(&mut self, level: Option<bool>, gpio: &mut GpioDevice) {
+        if level != self.applied {
+            gpio.set_input(self.pin, level);
+            self.applied = level;
+        }
+    }
+}
+
+/// A mechanical quadrature encoder whose contacts (and push switch) close to ground, so firmware
+/// needs the pull-ups. A detent is four quadrature steps, turned at one step per 500 µs.
+#[derive(Clone)]
+pub(crate) struct Encoder {
+    pub(crate) spec: EncoderSpec,
+    /// A, B and the switch if there is one
+    contacts: Vec<Contact>,
+    /// quadrature steps past the last detent clockwise, 0 - 3
+    phase: usize,
+    /// quadrature steps still to turn, negative counter-clockwise
+    pending: i64,
+    /// cycle of the next step, `None` to step at the next sample
+    next_step: Option<u64>,
+    /// detents turned in total, clockwise positive
+    pub(crate) position: i64,
+    pub(crate) pressed: bool,
+}
+
+impl Encoder {
+    pub(crate) fn new(spec: EncoderSpec) -> Encoder {
+        return Encoder {
+            spec,
+            contacts: [Some(spec.a), Some(spec.b), spec.switch].into_iter().flatten().map(Contact::new).collect(),
+            phase: 0,
+            pending: 0,
+            next_step: None,
+            position: 0,
+            pressed: false,
+        };
+    }
+
+    /// Queue a turn, after any turn still in progress
+    pub(crate) fn turn(&mut self, detents: i64) {
+        self.pending += detents * QUADRATURE.len() as i64;
+    }
+
+    /// Press or release the push switch (nothing happens without one)
+    pub(crate) fn press(&mut self, pressed: bool) {
+        self.pressed = pressed && self.spec.switch.is_some();
+    }
+
+    /// Turns in progress continue after a reset, from the new cycle count
+    pub(crate) fn reset(&mut self) {
+        self.next_step = None;
+    }
+
+    #[inline]
+    fn sample(&mut self, cycle: u64, mclk_hz: u64, gpio: &mut GpioDevice) {
+        if self.pending == 0 {
+            self.next_step = None;
+        } else if self.next_step.is_none_or(|next| cycle >= next) {
+            let clockwise: bool = self.pending > 0;
+            self.pending -= self.pending.signum();
+            self.phase = (self.phase + if clockwise {1} else {QUADRATURE.len() - 1}) % QUADRATURE.len();
+            if self.phase == 0 {
+                self.position += if clockwise {1} else {-1};
+            }
+            self.next_step = Some(cycle + cycles_for(STEP_US, mclk_hz));
+        }
+        let (a, b) = QUADRATURE[self.phase];
+        for (contact, closed) in self.contacts.iter_mut().zip([a, b, self.pressed]) {
+            contact.apply(if closed {Some(false)} else {None}, gpio);
+        }
+    }
+}
+
+/// Keys at the crossings of row and column pins. A pressed key connects its row and column, so
+/// whichever of the two firmware drives sets the level on the other (low wins if several keys
+/// drive the same pin). Only keys directly on a pin count, there is no ghosting through others.
+#[derive(Clone)]
+pub(crate) struct ButtonMatrix {
+    pub(crate) spec: MatrixSpec,
+    contacts: Vec<Contact>,
+    /// row-major
+    pressed: Vec<bool>,
+}
+
+impl ButtonMatrix {
+    pub(crate) fn new(spec: MatrixSpec) -> ButtonMatrix {
+        let contacts: Vec<Contact> = spec.rows.iter().chain(&spec.columns).copied().map(Contact::new).collect();
+        let pressed: Vec<bool> = vec![false; spec.rows.len() * spec.columns.len()];
+        return ButtonMatrix { spec, contacts, pressed };
+    }
+
+    /// Press or release a key, false if there's no such key
+    pub(crate) fn press(&mut self, row: usize, column: usize, pressed: bool) -> bool {
+        if row >= self.spec.rows.len() || column >= self.spec.columns.len() {
+            return false;
+        }
+        self.pressed[row * self.spec.columns.len() + column] = pressed;
+        return true;
+    }
+
+    #[inline]
+    fn sample(&mut self, gpio: &mut GpioDevice) {
+        let (rows, columns) = (self.spec.rows.len(), self.spec.columns.len());
+        for index in 0..rows + columns {
+            // the pins across pressed keys from this one
+            let across = (0..if index < rows {columns} else {rows}).filter_map(|other| {
+                let (row, column) = if index < rows {(index, other)} else {(other, index - rows)};
+                return self.pressed[row * columns + column].then_some(if index < rows {column + rows} else {row});
+            });
+            let level: Option<bool> = across.filter_map(|other| gpio.output(self.contacts[other].pin))
+                .reduce(|a, b| a && b);
+            let contact: &mut Contact = &mut self.contacts[index];
+            contact.apply(level, gpio);
+        }
+    }
+}
+
+/// Input widgets on GPIO pins (`run --encoder`, `run --button-matrix`), operated by control
+/// commands or `run --exec` scripts
+#[derive(Clone)]
+pub(crate) struct Widgets {
+    pub(crate) encoders: Vec<Encoder>,
+    pub(crate) matrices: Vec<ButtonMatrix>,
+}
+
+impl Widgets {
+    /// Each pin can belong to one widget only
+    pub(crate) fn new(encoders: &[EncoderSpec], matrices: &[MatrixSpec]) -> Result<Widgets, String> {
+        let mut pins: Vec<PinId> = Vec::new();
+        let encoder_pins = encoders.iter().flat_map(|e| [Some(e.a), Some(e.b), e.switch]).flatten();
+        let matrix_pins = matrices.iter().flat_map(|m| m.rows.iter().chain(&m.columns).copied());
+        for pin in encoder_pins.chain(matrix_pins) {
+            if pins.contains(&pin) {
+                return Err(format!("{} is used twice", pin));
+            }
+            pins.push(pin);
+        }
+        return Ok(Widgets {
+            encoders: encoders.iter().map(|spec| Encoder::new(*spec)).collect(),
+            matrices: matrices.iter().cloned().map(ButtonMatrix::new).collect(),
+        });
+    }
+
+    /// Knobs and keys stay where the user left them
+    pub(crate) fn reset(&mut self) {
+        self.encoders.iter_mut().for_each(Encoder::reset);
+    }
+
+    /// Drive the pins after an instruction ran, `cycle` is the current cycle count
+    #[inline]
+    pub(crate) fn sample(&mut self, cycle: u64, mclk_hz: u64, gpio: &mut GpioDevice) {
+        for encoder in &mut self.encoders {
+            encoder.sample(cycle, mclk_hz, gpio);
+        }
+        for matrix in &mut self.matrices {
+            matrix.sample(gpio);
+        }
+    }
+}
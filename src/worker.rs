@@ -0,0 +1,142 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// A device model that can run beside the CPU: at every quantum boundary it is handed what the
+/// CPU produced during the quantum and answers with what the CPU should see next
+pub(crate) trait QuantumModel: Send + 'static {
+    type Input: Send + 'static;
+    type Output: Send + 'static;
+    /// Work through `input`, produced by the CPU up to `cycle`
+    fn quantum(&mut self, cycle: u64, input: Self::Input) -> Self::Output;
+}
+
+/// A model on its own thread, one quantum behind the CPU. Each boundary is a barrier: the CPU waits
+/// for the model to finish the previous quantum, so however slow the model is the two never drift
+/// more than a quantum apart, and what the CPU sees arrives at the same boundaries every run.
+pub(crate) struct Worker<M: QuantumModel> {
+    inputs: Option<SyncSender<(u64, M::Input)>>,
+    outputs: Receiver<M::Output>,
+    /// a quantum was handed over and its output not collected yet
+    busy: bool,
+    thread: Option<JoinHandle<M>>,
+}
+
+impl<M: QuantumModel> Worker<M> {
+    pub(crate) fn spawn(name: &str, mut model: M) -> io::Result<Worker<M>> {
+        let (inputs, work) = mpsc::sync_channel::<(u64, M::Input)>(1);
+        let (done, outputs) = mpsc::sync_channel::<M::Output>(1);
+        let thread = thread::Builder::new().name(name.to_string()).spawn(move || {
+            for (cycle, input) in work {
+                if done.send(model.quantum(cycle, input)).is_err() {
+                    break;
+                }
+            }
+            return model;
+        })?;
+        return Ok(Worker { inputs: Some(inputs), outputs, busy: false, thread: Some(thread) });
+    }
+
+    /// The boundary at `cycle`: waits for the quantum in flight and returns its output, then starts
+    /// the model on `input` while the CPU runs the next one. `None` if nothing was in flight, or
+    /// the model panicked (the worker is gone then, see `alive`).
+    pub(crate) fn exchange(&mut self, cycle: u64, input: M::Input) -> Option<M::Output> {
+        let output: Option<M::Output> = self.collect();
+        if let Some(inputs) = &self.inputs {
+            if inputs.send((cycle, input)).is_ok() {
+                self.busy = true;
+            } else {
+                self.inputs = None;
+            }
+        }
+        return output;
+    }
+
+    fn collect(&mut self) -> Option<M::Output> {
+        if !std::mem::take(&mut self.busy) {
+            return None;
+        }
+        let output: Option<M::Output> = self.outputs.recv().ok();
+        if output.is_none() {
+            self.inputs = None;
+        }
+        return output;
+    }
+
+    pub(crate) fn alive(&self) -> bool {
+        return self.inputs.is_some();
+    }
+
+    /// Waits for the quantum in flight and ends the thread. Returns its output and the model, the
+    /// model is lost if it panicked.
+    pub(crate) fn finish(mut self) -> (Option<M::Output>, Option<M>) {
+        let output: Option<M::Output> = self.collect();
+        self.inputs = None; // the thread's loop ends with the channel
+        let model: Option<M> = self.thread.take().and_then(|thread| thread.join().ok());
+        return (output, model);
+    }
+}
+
+impl<M: QuantumModel> Drop for Worker<M> {
+    fn drop(&mut self) {
+        self.inputs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A model the run loop drives either itself between batches, or on a worker thread when run with
+/// `--peripheral-quantum`
+pub(crate) enum Hosted<M: QuantumModel> {
+    Inline(M),
+    Threaded(Worker<M>),
+}
+
+impl<M: QuantumModel> Hosted<M> {
+    pub(crate) fn new(name: &str, model: M, threaded: bool) -> io::Result<Hosted<M>> {
+        return Ok(if threaded {Hosted::Threaded(Worker::spawn(name, model)?)} else {Hosted::Inline(model)});
+    }
+
+    /// Inline models answer right away, threaded ones with the previous quantum's output.
+    /// `None` if there is nothing yet or a threaded model died.
+    pub(crate) fn exchange(&mut self, cycle: u64, input: M::Input) -> Option<M::Output> {
+        return match self {
+            Hosted::Inline(model) => Some(model.quantum(cycle, input)),
+            Hosted::Threaded(worker) => worker.exchange(cycle, input),
+        };
+    }
+
+    pub(crate) fn alive(&self) -> bool {
+        return match self {
+            Hosted::Inline(_) => true,
+            Hosted::Threaded(worker) => worker.alive(),
+        };
+    }
+
+    /// What the model still had in flight, once it has finished
+    pub(crate) fn finish(self) -> Option<M::Output> {
+        return match self {
+            Hosted::Inline(_) => None,
+            Hosted::Threaded(worker) => worker.finish().0,
+        };
+    }
+}
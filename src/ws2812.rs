@@ -0,0 +1,201 @@
+/*
+ *     MSP430 emulator
+ *     Copyright (C) 2023  Sam Wagenaar
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU General Public License as published by
+ *     the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU General Public License for more details.
+ *
+ *     You should have received a copy of the GNU General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use crate::devices::gpio::{GpioDevice, PinId};
+use crate::sensors::cycles_for;
+
+/// A pin low this long latches the frame (the shortest reset of the WS2812)
+const LATCH_US: u64 = 50;
+/// ... or this many of the frame's longest bit period, emulated timing rarely matches the real one
+const LATCH_PERIODS: u64 = 10;
+/// A lone bit has no period to compare with, it is a 1 if high longer than this
+const ONE_HIGH_NS: u64 = 625;
+
+/// `run --ws2812 PIN[:COUNT]`, without a count the strip is as long as the longest frame
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct LedStripSpec {
+    pub(crate) pin: PinId,
+    pub(crate) count: Option<usize>,
+}
+
+impl FromStr for LedStripSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pin, count) = match s.split_once(':') {
+            Some((pin, count)) => (pin, Some(count.parse().map_err(|_| format!("Invalid LED count in '{}'", s))?)),
+            None => (s, None),
+        };
+        return Ok(LedStripSpec { pin: pin.parse()?, count });
+    }
+}
+
+impl fmt::Display for LedStripSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.count {
+            Some(count) => write!(f, "{}:{}", self.pin, count),
+            None => write!(f, "{}", self.pin),
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Rgb {
+    pub(crate) r: u8,
+    pub(crate) g: u8,
+    pub(crate) b: u8,
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+    }
+}
+
+/// The colors of a strip once a frame latched
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Frame {
+    pub(crate) cycle: u64,
+    pub(crate) pin: PinId,
+    pub(crate) leds: Vec<Rgb>,
+}
+
+impl Frame {
+    /// `cycle,pin,leds` with the LEDs as space separated `rrggbb`
+    pub(crate) fn csv_line(&self) -> String {
+        return format!("{},{},{}", self.cycle, self.pin,
+                       self.leds.iter().map(|led| led.to_string()).collect::<Vec<String>>().join(" "));
+    }
+}
+
+/// A strip of WS2812 LEDs on an output pin (`run --ws2812`), decoded from the edges firmware makes.
+/// Bits are told apart by their share of the frame's typical bit period (a 0 is high for about a
+/// third, a 1 for about two thirds), so loops tuned for another clock still decode. The LEDs keep
+/// their colors when the computer is reset.
+#[derive(Clone)]
+pub(crate) struct LedStrip {
+    pub(crate) spec: LedStripSpec,
+    leds: Vec<Rgb>,
+    /// frames latched so far
+    frames: u64,
+    high: bool,
+    rise: u64,
+    fall: u64,
+    /// rising edge and high time of every bit since the last latch, in cycles
+    bits: Vec<(u64, u64)>,
+    /// longest rise to rise time among them
+    longest: u64,
+    /// keep latched frames for `take_frames`
+    pub(crate) export: bool,
+    latched: Vec<Frame>,
+}
+
+impl LedStrip {
+    pub(crate) fn new(spec: LedStripSpec) -> LedStrip {
+        return LedStrip {
+            spec,
+            leds: vec![Rgb::default(); spec.count.unwrap_or(0)],
+            frames: 0,
+            high: false,
+            rise: 0,
+            fall: 0,
+            bits: Vec::new(),
+            longest: 0,
+            export: false,
+            latched: Vec::new(),
+        };
+    }
+
+    /// Drop a frame being sent, the colors stay
+    pub(crate) fn reset(&mut self) {
+        self.high = false;
+        self.bits.clear();
+        self.longest = 0;
+    }
+
+    pub(crate) fn leds(&self) -> &[Rgb] {
+        return &self.leds;
+    }
+
+    pub(crate) fn frames(&self) -> u64 {
+        return self.frames;
+    }
+
+    /// Look at the pin after an instruction ran, `cycle` is the current cycle count
+    #[inline]
+    pub(crate) fn sample(&mut self, cycle: u64, mclk_hz: u64, gpio: &GpioDevice) {
+        let high: bool = gpio.output(self.spec.pin) == Some(true);
+        if high && !self.high {
+            if let Some((last, _)) = self.bits.last() {
+                self.longest = self.longest.max(cycle - last);
+            }
+            self.rise = cycle;
+        } else if !high && self.high {
+            self.bits.push((self.rise, cycle - self.rise));
+            self.fall = cycle;
+        } else if !high && !self.bits.is_empty() {
+            let mut latch: u64 = cycles_for(LATCH_US, mclk_hz);
+            if self.longest > 0 {
+                latch = latch.min(self.longest * LATCH_PERIODS);
+            }
+            if cycle - self.fall >= latch {
+                self.latch(cycle, mclk_hz);
+            }
+        }
+        self.high = high;
+    }
+
+    fn latch(&mut self, cycle: u64, mclk_hz: u64) {
+        let mut periods: Vec<u64> = self.bits.windows(2).map(|pair| pair[1].0 - pair[0].0).collect();
+        periods.sort_unstable();
+        let one = |high: u64| match periods.get(periods.len() / 2) {
+            Some(period) => high * 2 > *period,
+            None => high * 1_000_000_000 > ONE_HIGH_NS * mclk_hz,
+        };
+        let bits: Vec<bool> = self.bits.drain(..).map(|(_, high)| one(high)).collect();
+        self.longest = 0;
+        // each LED takes the first 24 bits it sees, green, red, blue, MSB first, and passes the rest on
+        for (index, led) in bits.chunks_exact(24).enumerate() {
+            if self.spec.count.is_some_and(|count| index >= count) {
+                break;
+            }
+            let byte = |n: usize| led[n * 8..n * 8 + 8].iter().fold(0u8, |byte, bit| byte << 1 | *bit as u8);
+            if index == self.leds.len() {
+                self.leds.push(Rgb::default());
+            }
+            self.leds[index] = Rgb { r: byte(1), g: byte(0), b: byte(2) };
+        }
+        self.frames += 1;
+        if self.export {
+            self.latched.push(Frame { cycle, pin: self.spec.pin, leds: self.leds.clone() });
+        }
+    }
+
+    /// Frames latched since the last call, if exporting
+    pub(crate) fn take_frames(&mut self) -> Vec<Frame> {
+        return std::mem::take(&mut self.latched);
+    }
+
+    /// e.g. `P1.7: 3 LEDs after 12 frames: ff0000 00ff00 0000ff`
+    pub(crate) fn report(&self) -> String {
+        return format!("{}: {} LEDs after {} frames: {}\n", self.spec.pin, self.leds.len(), self.frames,
+                       self.leds.iter().map(|led| led.to_string()).collect::<Vec<String>>().join(" "));
+    }
+}